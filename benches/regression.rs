@@ -0,0 +1,11 @@
+use mesh::node::bench;
+use std::path::Path;
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(bench::BASELINES);
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = bench::regressions(&path, &args) {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,83 @@
+//! Requests and responses of the control socket.
+//!
+//! Clients send one JSON [`Request`] per line and read one JSON
+//! [`Response`] per line back.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// File name of the control socket inside the data directory.
+pub const FILE_NAME: &str = "control.sock";
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Status,
+    /// Retrieve the debug bundle of another node.
+    Bundle { peer_id: String },
+    /// Connect to a peer at a multiaddress.
+    Dial { address: String },
+    /// Speak [`crate::daemon::Request`] from now on.
+    Attach,
+    /// Use the permission of `token` for the following requests, on nodes
+    /// with access control.
+    Authenticate { token: String },
+    Shutdown,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Status(Status),
+    /// A gzipped tarball, hex encoded.
+    Bundle(String),
+    Attached,
+    Dialing,
+    /// The permission granted: `status`, `publish` or `admin`.
+    Authenticated(String),
+    ShuttingDown,
+    Error(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub peer_id:       String,
+    pub connected:     bool,
+    pub ping_ms:       Option<u64>,
+    /// Average of the recent pings.
+    #[serde(default)]
+    pub ping_avg_ms:   Option<u64>,
+    /// Pings failed in a row.
+    #[serde(default)]
+    pub ping_failures: u32,
+    pub agent:         Option<String>,
+    #[serde(default)]
+    pub protocols:     Vec<String>,
+}
+
+/// An address not dialed until its backoff runs out.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Backoff {
+    pub address:  String,
+    pub peer_id:  Option<String>,
+    pub failures: u32,
+    /// Of the last failure.
+    pub outcome:  String,
+    pub retry_ms: u64,
+}
+
+/// A snapshot of the node.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    pub peer_id:  String,
+    pub peers:    Vec<PeerStatus>,
+    pub inbound:  u64,
+    pub outbound: u64,
+    pub topics:   Vec<String>,
+    /// Oldest first.
+    pub events:   Vec<String>,
+    /// Addresses backing off after failed dials.
+    #[serde(default)]
+    pub backoffs: Vec<Backoff>,
+    /// Fingerprints of the current keys of encrypted topics, by topic.
+    #[serde(default)]
+    pub keys:     BTreeMap<String, String>,
+}
@@ -0,0 +1,32 @@
+//! Requests and responses of a client attached to a daemon.
+//!
+//! After [`crate::control::Request::Attach`] the connection speaks one JSON
+//! [`Request`] per line and reads one JSON [`Response`] per line back, with
+//! a [`Response::Message`] pushed in between for every message on a topic
+//! the client subscribed to.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    Publish { topic: String, data: Vec<u8> },
+    Peers,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok,
+    Peers(Vec<String>),
+    Message(Message),
+    Error(String),
+}
+
+/// A message on a topic an attached client subscribed to.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub source: String,
+    pub topic:  String,
+    pub data:   Vec<u8>,
+}
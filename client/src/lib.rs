@@ -0,0 +1,275 @@
+//! Client for the control socket of a mesh-rs node.
+//!
+//! A node with a data directory listens on the Unix socket
+//! [`control::FILE_NAME`] in it. This crate speaks its protocol without
+//! depending on libp2p or the node itself, so programs can ask a node for
+//! its [`Status`](control::Status), or [`Client::attach`] to a node running
+//! in daemon mode to publish and subscribe through it. Nodes running with
+//! `--access` need [`Client::authenticate`] first:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use futures::StreamExt;
+//! use mesh_client::Client;
+//!
+//! let path = std::path::Path::new("/var/lib/mesh").join(mesh_client::control::FILE_NAME);
+//! let mut attached = Client::connect(&path).await?.attach().await?;
+//! attached.subscribe("chat").await?;
+//! attached.publish("chat", b"hello").await?;
+//! let mut messages = Box::pin(attached.messages());
+//! while let Some(message) = messages.next().await {
+//!     let message = message?;
+//!     println!("{}: {}", message.source, String::from_utf8_lossy(&message.data));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod control;
+pub mod daemon;
+
+use anyhow::{anyhow, Context as _, Result};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, path::Path};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+    net::UnixStream,
+};
+
+/// JSON lines over the socket.
+struct Connection {
+    lines:  Lines<BufReader<ReadHalf<UnixStream>>>,
+    writer: WriteHalf<UnixStream>,
+}
+
+impl Connection {
+    async fn send<T: Serialize>(&mut self, request: &T) -> Result<()> {
+        let mut json = serde_json::to_vec(request)?;
+        json.push(b'\n');
+        self.writer.write_all(&json).await?;
+        Ok(())
+    }
+
+    async fn read<T: for<'a> Deserialize<'a>>(&mut self) -> Result<T> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .context("Node closed the control connection")?;
+        serde_json::from_str(&line).context("Parsing control response")
+    }
+}
+
+/// A connection to the control socket of a running node.
+pub struct Client {
+    connection: Connection,
+}
+
+impl Client {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Connecting to node at {}", path.display()))?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self {
+            connection: Connection {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            },
+        })
+    }
+
+    pub async fn call(&mut self, request: &control::Request) -> Result<control::Response> {
+        self.connection.send(request).await?;
+        self.connection.read().await
+    }
+
+    pub async fn status(&mut self) -> Result<control::Status> {
+        match self.call(&control::Request::Status).await? {
+            control::Response::Status(status) => Ok(status),
+            control::Response::Error(err) => Err(anyhow!("Node refused: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Retrieve the debug bundle of `peer_id` through the node, a gzipped
+    /// tarball.
+    pub async fn bundle(&mut self, peer_id: &str) -> Result<Vec<u8>> {
+        let request = control::Request::Bundle {
+            peer_id: peer_id.into(),
+        };
+        match self.call(&request).await? {
+            control::Response::Bundle(bundle) => {
+                hex::decode(bundle).context("Invalid bundle encoding")
+            }
+            control::Response::Error(err) => Err(anyhow!("Could not retrieve bundle: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Authorize the following requests with `token`, returning the
+    /// permission it grants.
+    pub async fn authenticate(&mut self, token: &str) -> Result<String> {
+        let request = control::Request::Authenticate {
+            token: token.into(),
+        };
+        match self.call(&request).await? {
+            control::Response::Authenticated(permission) => Ok(permission),
+            control::Response::Error(err) => Err(anyhow!("Could not authenticate: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Have the node connect to a peer at the multiaddress `address`.
+    pub async fn dial(&mut self, address: &str) -> Result<()> {
+        let request = control::Request::Dial {
+            address: address.into(),
+        };
+        match self.call(&request).await? {
+            control::Response::Dialing => Ok(()),
+            control::Response::Error(err) => Err(anyhow!("Could not dial: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Shut the node down gracefully.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        match self.call(&control::Request::Shutdown).await? {
+            control::Response::ShuttingDown => Ok(()),
+            control::Response::Error(err) => Err(anyhow!("Node refused: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// Attach to a node in daemon mode, to share its connections and
+    /// subscriptions.
+    pub async fn attach(mut self) -> Result<Attached> {
+        match self.call(&control::Request::Attach).await? {
+            control::Response::Attached => {
+                Ok(Attached {
+                    connection: self.connection,
+                    messages:   VecDeque::new(),
+                })
+            }
+            control::Response::Error(err) => Err(anyhow!("Could not attach: {}", err)),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+}
+
+/// A client attached to a daemon, see [`Client::attach`].
+pub struct Attached {
+    connection: Connection,
+    /// Messages read while waiting for a response.
+    messages:   VecDeque<daemon::Message>,
+}
+
+impl Attached {
+    async fn call(&mut self, request: &daemon::Request) -> Result<daemon::Response> {
+        self.connection.send(request).await?;
+        loop {
+            match self.connection.read().await? {
+                daemon::Response::Message(message) => self.messages.push_back(message),
+                daemon::Response::Error(err) => return Err(anyhow!("Node refused: {}", err)),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Receive the messages on `topic`, subscribing the daemon if no other
+    /// client did.
+    pub async fn subscribe(&mut self, topic: &str) -> Result<()> {
+        let topic = topic.to_owned();
+        self.call(&daemon::Request::Subscribe { topic }).await.map(drop)
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        let topic = topic.to_owned();
+        self.call(&daemon::Request::Unsubscribe { topic }).await.map(drop)
+    }
+
+    /// Publish to the network and the other clients subscribed to `topic`.
+    pub async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<()> {
+        let request = daemon::Request::Publish {
+            topic: topic.to_owned(),
+            data:  data.to_vec(),
+        };
+        self.call(&request).await.map(drop)
+    }
+
+    /// The peers the daemon is connected to.
+    pub async fn peers(&mut self) -> Result<Vec<String>> {
+        match self.call(&daemon::Request::Peers).await? {
+            daemon::Response::Peers(peers) => Ok(peers),
+            response => Err(anyhow!("Unexpected response {:?}", response)),
+        }
+    }
+
+    /// The next message on the subscribed topics, skipping responses to
+    /// requests nobody waits for.
+    pub async fn next_message(&mut self) -> Result<daemon::Message> {
+        if let Some(message) = self.messages.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            if let daemon::Response::Message(message) = self.connection.read().await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// The messages on the subscribed topics, until the daemon closes the
+    /// connection.
+    pub fn messages(self) -> impl Stream<Item = Result<daemon::Message>> {
+        futures::stream::unfold(Some(self), |attached| {
+            async move {
+                let mut attached = attached?;
+                match attached.next_message().await {
+                    Ok(message) => Some((Ok(message), Some(attached))),
+                    Err(err) => Some((Err(err), None)),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_keeps_messages_pushed_before_responses() {
+        let dir = std::env::temp_dir().join(format!("mesh-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(control::FILE_NAME);
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let node = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, r#""Attach""#);
+            writer.write_all(b"\"Attached\"\n").await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, r#"{"Subscribe":{"topic":"chat"}}"#);
+            let message = r#"{"Message":{"source":"peer","topic":"chat","data":[104,105]}}"#;
+            writer.write_all(format!("{}\n\"Ok\"\n", message).as_bytes()).await.unwrap();
+        });
+        let mut attached = Client::connect(&path).await.unwrap().attach().await.unwrap();
+        attached.subscribe("chat").await.unwrap();
+        node.await.unwrap();
+        let messages: Vec<_> = attached.messages().collect().await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].as_ref().unwrap(), &daemon::Message {
+            source: "peer".into(),
+            topic:  "chat".into(),
+            data:   b"hi".to_vec(),
+        });
+        assert!(messages[1].is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
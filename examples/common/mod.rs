@@ -0,0 +1,79 @@
+//! Helpers shared by the examples.
+#![allow(dead_code)]
+
+use anyhow::Result;
+use libp2p::{Multiaddr, PeerId};
+use mesh::node::{Node, NodeHandle};
+use std::time::Duration;
+
+/// Log level used when `RUST_LOG` is not set.
+const DEFAULT_LOG: &str = "info,libp2p_gossipsub=warn";
+
+pub fn init_logging() {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", DEFAULT_LOG);
+    }
+    env_logger::init();
+}
+
+/// An example node running on the current `LocalSet`.
+pub struct ExampleNode {
+    pub peer_id: PeerId,
+    /// Loopback address the node listens on.
+    pub address: Multiaddr,
+    pub handle:  NodeHandle,
+}
+
+/// Create a node in application namespace `namespace` and drive it on the
+/// current `LocalSet`.
+pub async fn spawn_node(namespace: &str) -> Result<ExampleNode> {
+    let mut node = Node::builder().with_namespace(namespace).build().await?;
+    let peer_id = node.local_peer_id().clone();
+    let handle = node.handle();
+
+    // Drive the node until it reports its listening addresses
+    let address = loop {
+        node.step().await?;
+        let loopback = node
+            .listeners()
+            .find(|address| address.to_string().starts_with("/ip4/127.0.0.1/"))
+            .cloned();
+        if let Some(address) = loopback {
+            break address;
+        }
+    };
+
+    tokio::task::spawn_local(async move {
+        if let Err(err) = node.run().await {
+            log::error!("Node stopped: {:?}", err);
+        }
+    });
+    Ok(ExampleNode {
+        peer_id,
+        address,
+        handle,
+    })
+}
+
+/// Create `count` nodes, all connected to the first one.
+pub async fn spawn_nodes(namespace: &str, count: usize) -> Result<Vec<ExampleNode>> {
+    let mut nodes: Vec<ExampleNode> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut node = spawn_node(namespace).await?;
+        if let Some(first) = nodes.first() {
+            node.handle.dial(first.address.clone()).await?;
+        }
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+/// Fail if `future` does not finish within `timeout`.
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| anyhow::anyhow!("Example did not finish within {:?}", timeout))?
+}
@@ -0,0 +1,89 @@
+//! Transferring a file between two nodes using a named service.
+//!
+//! The sending node advertises a `file/<name>` service that returns the file
+//! in chunks. The receiving node finds it through service discovery, fetches
+//! all chunks, writes them to `<path>.copy` and checks the result.
+//!
+//! ```text
+//! cargo run --example file_transfer -- Cargo.toml
+//! ```
+
+mod common;
+
+use anyhow::{ensure, Context, Result};
+use futures::prelude::*;
+use mesh::node::NodeHandle;
+use std::{convert::TryInto, path::PathBuf, time::Duration};
+use tokio::{task::LocalSet, time::sleep};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+async fn send(mut handle: NodeHandle, service: String, contents: Vec<u8>) -> Result<()> {
+    let mut requests = handle.advertise_service(&service).await?;
+    while let Some(request) = requests.next().await {
+        let response = request
+            .data
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Expected a chunk index".to_owned())
+            .map(|index| {
+                let start = (u64::from_be_bytes(index) as usize * CHUNK_SIZE).min(contents.len());
+                let end = (start + CHUNK_SIZE).min(contents.len());
+                contents[start..end].to_vec()
+            });
+        request.respond(response);
+    }
+    Ok(())
+}
+
+async fn receive(mut handle: NodeHandle, service: &str) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    let mut index = 0_u64;
+    loop {
+        let chunk = match handle.call_service(service, &index.to_be_bytes()).await {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Providers are discovered periodically
+                println!("Waiting for the sender: {}", err);
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if chunk.is_empty() {
+            return Ok(contents);
+        }
+        contents.extend(chunk);
+        index += 1;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    common::init_logging();
+    let path: PathBuf = std::env::args()
+        .nth(1)
+        .context("Usage: file_transfer <path>")?
+        .into();
+    let contents = std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+    let name = path
+        .file_name()
+        .context("Path has no file name")?
+        .to_string_lossy();
+    let service = format!("file/{}", name);
+
+    LocalSet::new()
+        .run_until(common::with_timeout(Duration::from_secs(180), async {
+            let mut nodes = common::spawn_nodes("file-transfer-example", 2).await?;
+            let receiver = nodes.pop().unwrap().handle;
+            let sender = nodes.pop().unwrap().handle;
+            tokio::task::spawn_local(send(sender, service.clone(), contents.clone()));
+
+            let received = receive(receiver, &service).await?;
+            ensure!(received == contents, "Received file differs from the original");
+            let copy = PathBuf::from(format!("{}.copy", path.display()));
+            std::fs::write(&copy, &received)?;
+            println!("Transferred {} bytes to {}", received.len(), copy.display());
+            Ok(())
+        }))
+        .await
+}
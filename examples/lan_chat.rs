@@ -0,0 +1,70 @@
+//! Three nodes on the local network chatting on a shared topic.
+//!
+//! The nodes connect to the first one over localhost; on a real LAN they also
+//! find each other through mDNS. Each one says hello every few seconds and
+//! prints what it hears. The example exits once every node heard from both
+//! others.
+//!
+//! ```text
+//! cargo run --example lan_chat
+//! ```
+
+mod common;
+
+use anyhow::Result;
+use futures::prelude::*;
+use libp2p::PeerId;
+use mesh::node::{ready::Criteria, NodeHandle};
+use std::{cell::Cell, collections::HashSet, rc::Rc, time::Duration};
+use tokio::{task::LocalSet, time::interval};
+
+const NODES: usize = 3;
+const TOPIC: &str = "chat";
+
+/// Say hello until every node heard from all others, counted in `done`.
+async fn chat(peer_id: PeerId, mut handle: NodeHandle, done: Rc<Cell<usize>>) -> Result<()> {
+    let (mut sender, mut messages) = handle.typed_topic::<String>(TOPIC).await?;
+    handle.wait_ready(Criteria::default().topic(TOPIC, 1)).await?;
+    let mut tick = interval(Duration::from_secs(2));
+    let mut heard = HashSet::new();
+    while done.get() < NODES {
+        tokio::select! {
+            _ = tick.tick() => {
+                let text = format!("Hello from {}", peer_id);
+                // Repeated for nodes that join the mesh later
+                sender.send(&text).await?;
+            }
+            Some(message) = messages.next() => {
+                if heard.insert(message.sender) {
+                    println!("{} heard: {}", peer_id, message.payload);
+                    if heard.len() == NODES - 1 {
+                        done.set(done.get() + 1);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    common::init_logging();
+    LocalSet::new()
+        .run_until(common::with_timeout(Duration::from_secs(120), async {
+            let done = Rc::new(Cell::new(0));
+            let chats = common::spawn_nodes("lan-chat-example", NODES)
+                .await?
+                .into_iter()
+                .map(|node| {
+                    tokio::task::spawn_local(chat(node.peer_id, node.handle, done.clone()))
+                })
+                .collect::<Vec<_>>();
+            for result in future::join_all(chats).await {
+                result??;
+            }
+            println!("Every node heard from every other node");
+            Ok(())
+        }))
+        .await
+}
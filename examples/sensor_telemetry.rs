@@ -0,0 +1,100 @@
+//! Sensors publishing telemetry to a collector.
+//!
+//! Each sensor node publishes its readings as delta-encoded state and counts
+//! itself in the mesh-wide `sensors_online` gauge. The collector subscribes to
+//! the state topic, prints the reconstructed readings with their hybrid
+//! logical clock timestamps, and the mesh-wide gauge.
+//!
+//! ```text
+//! cargo run --example sensor_telemetry
+//! ```
+
+mod common;
+
+use anyhow::Result;
+use futures::prelude::*;
+use mesh::node::{subscriptions::TopicOptions, Event, NodeHandle};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::{task::LocalSet, time::interval};
+
+const SENSORS: usize = 2;
+const TOPIC: &str = "telemetry";
+const READINGS: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Reading {
+    sensor:  String,
+    celsius: f64,
+    history: Vec<f64>,
+}
+
+async fn sensor(name: String, mut handle: NodeHandle) -> Result<()> {
+    handle.set_gauge("sensors_online", 1).await?;
+    let mut reading = Reading {
+        sensor:  name,
+        celsius: 20.0,
+        history: Vec::new(),
+    };
+    let mut tick = interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        reading.history.push(reading.celsius);
+        reading.celsius += 0.1;
+        let data = serde_json::to_vec(&reading)?;
+        // Fails until the gossip mesh is formed
+        let _ = handle.publish_state(TOPIC, &data).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    common::init_logging();
+    LocalSet::new()
+        .run_until(common::with_timeout(Duration::from_secs(120), async {
+            let mut nodes = common::spawn_nodes("sensor-telemetry-example", SENSORS + 1).await?;
+            let mut collector = nodes.remove(0).handle;
+            let options = TopicOptions {
+                delta: true,
+                ..TopicOptions::default()
+            };
+            collector.subscribe(TOPIC, options).await?;
+            let mut events = collector.events().await?;
+
+            for (index, node) in nodes.into_iter().enumerate() {
+                tokio::task::spawn_local(sensor(format!("sensor-{}", index), node.handle));
+            }
+
+            let mut readings = 0;
+            while readings < READINGS {
+                let (topic, data, timestamp) = match events.next().await {
+                    Some(Event::Message {
+                        topic,
+                        data,
+                        timestamp,
+                        ..
+                    }) => (topic, data, timestamp),
+                    Some(_) => continue,
+                    None => break,
+                };
+                if topic != TOPIC {
+                    continue;
+                }
+                let reading: Reading = serde_json::from_slice(&data)?;
+                println!(
+                    "{:?} {}: {:.1}°C ({} earlier readings)",
+                    timestamp,
+                    reading.sensor,
+                    reading.celsius,
+                    reading.history.len()
+                );
+                readings += 1;
+            }
+            println!(
+                "Sensors online: {:?}",
+                collector.metric("sensors_online").await?
+            );
+            Ok(())
+        }))
+        .await
+}
@@ -0,0 +1,64 @@
+//! A node joining a wide area mesh through bootstrap peers.
+//!
+//! Pass the multiaddresses of one or more bootstrap peers, including their
+//! `/p2p/` peer id. Without arguments the node only waits for others to
+//! connect; run a second instance with one of the printed addresses to form a
+//! mesh.
+//!
+//! ```text
+//! cargo run --example wan_mesh -- /ip4/203.0.113.7/tcp/4001/p2p/16Uiu2...
+//! ```
+
+mod common;
+
+use anyhow::{Context, Result};
+use libp2p::{identity::Keypair, Multiaddr};
+use mesh::node::Node;
+use std::time::Duration;
+use tokio::{task::LocalSet, time::interval};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    common::init_logging();
+    let bootstrap = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse::<Multiaddr>())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Parsing bootstrap addresses")?;
+
+    let mut node = Node::builder()
+        .with_keypair(Keypair::generate_ed25519())
+        .with_namespace("wan-mesh-example")
+        .build()
+        .await?;
+    let peer_id = node.local_peer_id().clone();
+    let known_peers = node.known_peers();
+    let mut handle = node.handle();
+
+    LocalSet::new()
+        .run_until(async move {
+            tokio::task::spawn_local(async move {
+                // Let the node report its listening addresses
+                for _ in 0..10 {
+                    node.step().await?;
+                }
+                for address in node.listeners() {
+                    println!("Listening on {}/p2p/{}", address, peer_id);
+                }
+                node.run().await
+            });
+
+            for address in bootstrap {
+                handle.dial(address).await?;
+            }
+
+            let mut tick = interval(Duration::from_secs(10));
+            loop {
+                tick.tick().await;
+                let peers = known_peers.read().unwrap();
+                let reachable = peers.values().filter(|peer| peer.ping.is_some()).count();
+                println!("Known peers: {}, reachable: {}", peers.len(), reachable);
+            }
+        })
+        .await
+}
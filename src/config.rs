@@ -0,0 +1,199 @@
+//! Settings from a TOML file.
+//!
+//! `--config mesh.toml` reads every command line option from the file, by
+//! its long name:
+//!
+//! ```toml
+//! data-dir = "/var/lib/mesh"
+//! verbose = 2
+//! topic = ["orders", "chat"]
+//! listen = ["/ip4/0.0.0.0/udp/4002"]
+//! bootstrap = ["/ip4/10.0.0.1/tcp/4001/p2p/<peer id>"]
+//!
+//! [pubsub]
+//! mesh = 8
+//! heartbeat = "700ms"
+//!
+//! [discovery]
+//! mdns = false
+//!
+//! [[heartbeat]]
+//! topic = "sensors"
+//! interval = "10s"
+//! ```
+//!
+//! Arrays repeat the option, tables become its `key=value` list, arrays of
+//! tables repeat an option taking such a list, `true` sets a flag and
+//! `verbose` counts like `-v`. Environment variables named `MESH_` and the
+//! option, like `MESH_DATA_DIR`, override the file, and the command line
+//! overrides both. The merged options are parsed like the command line, so
+//! values are checked the same way.
+//!
+//! `SIGHUP` reads the file again, see [`crate::node::reload`].
+
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use std::{ffi::OsString, fs, path::Path};
+use toml::Value;
+
+/// The environment variable overriding the option `key`.
+pub fn env_var(key: &str) -> String {
+    format!("MESH_{}", key.to_uppercase().replace('-', "_"))
+}
+
+/// Whether `args` set the option `key`.
+fn given(args: &[OsString], key: &str) -> bool {
+    let long = format!("--{}", key);
+    let prefix = format!("{}=", long);
+    args.iter().any(|arg| {
+        arg.to_str()
+            .map_or(false, |arg| arg == long || arg.starts_with(&prefix))
+    }) || (key == "verbose"
+        && args.iter().any(|arg| {
+            arg.to_str().map_or(false, |arg| {
+                arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v')
+            })
+        }))
+}
+
+/// A scalar as an option value.
+fn scalar(key: &str, value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::String(value) => value.clone(),
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        Value::Boolean(value) => value.to_string(),
+        Value::Datetime(value) => value.to_string(),
+        Value::Array(_) | Value::Table(_) => bail!("{} must be a single value", key),
+    })
+}
+
+/// A table as the `key=value` list of an option.
+fn pairs(key: &str, table: &toml::value::Table) -> Result<String> {
+    let pairs = table
+        .iter()
+        .map(|(name, value)| {
+            let name = name.replace('_', "-");
+            scalar(&format!("{}.{}", key, name), value).map(|value| format!("{}={}", name, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(pairs.join(" "))
+}
+
+/// The command line arguments for `key = value`.
+fn option_args(key: &str, value: &Value) -> Result<Vec<String>> {
+    let long = format!("--{}", key);
+    Ok(match value {
+        Value::Boolean(true) => vec![long],
+        Value::Boolean(false) => Vec::new(),
+        Value::Integer(count) if key == "verbose" => {
+            (0..*count).map(|_| long.clone()).collect()
+        }
+        Value::Array(values) => {
+            let mut args = Vec::new();
+            for value in values {
+                args.push(long.clone());
+                args.push(match value {
+                    Value::Table(table) => pairs(key, table)?,
+                    value => scalar(key, value)?,
+                });
+            }
+            args
+        }
+        Value::Table(table) => vec![long, pairs(key, table)?],
+        value => vec![long, scalar(key, value)?],
+    })
+}
+
+/// The options of the file at `path` that neither `args`, the command line
+/// with the program name first, nor the environment set, followed by
+/// `args`.
+pub fn merge(path: &Path, args: &[OsString]) -> Result<Vec<OsString>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Reading config {}", path.display()))?;
+    let table = match text.parse::<Value>() {
+        Ok(Value::Table(table)) => table,
+        Ok(_) => bail!("Config {} is not a table", path.display()),
+        Err(err) => return Err(anyhow!(err).context(format!("Parsing config {}", path.display()))),
+    };
+    let mut merged: Vec<OsString> = args.iter().take(1).cloned().collect();
+    for (key, value) in &table {
+        let key = key.replace('_', "-");
+        if key == "config" {
+            bail!("Config {} can not include another config", path.display());
+        }
+        if given(args, &key) || std::env::var_os(env_var(&key)).is_some() {
+            debug!("Config {} overridden", key);
+            continue;
+        }
+        let option = option_args(&key, value)
+            .with_context(|| format!("Invalid {} in config {}", key, path.display()))?;
+        merged.extend(option.into_iter().map(OsString::from));
+    }
+    merged.extend(args.iter().skip(1).cloned());
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_merges_file_under_command_line() {
+        let path = std::env::temp_dir().join(format!("mesh-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+                verbose = 2
+                namespace = "test"
+                power_save = true
+                topic = ["orders", "chat"]
+                bootstrap-quorum = 2
+                [pubsub]
+                mesh = 8
+                heartbeat = "700ms"
+                [[heartbeat]]
+                topic = "a"
+                interval = "1s"
+                [[heartbeat]]
+                topic = "b"
+                interval = "2s"
+            "#,
+        )
+        .unwrap();
+        let args: Vec<OsString> = vec!["mesh".into(), "--namespace=prod".into(), "top".into()];
+        let merged = merge(&path, &args).unwrap();
+        let merged: Vec<_> = merged.iter().map(|arg| arg.to_str().unwrap()).collect();
+        assert_eq!(merged, vec![
+            "mesh",
+            "--bootstrap-quorum",
+            "2",
+            "--heartbeat",
+            "interval=1s topic=a",
+            "--heartbeat",
+            "interval=2s topic=b",
+            "--power-save",
+            "--pubsub",
+            "heartbeat=700ms mesh=8",
+            "--topic",
+            "orders",
+            "--topic",
+            "chat",
+            "--verbose",
+            "--verbose",
+            "--namespace=prod",
+            "top",
+        ]);
+        assert!(merge(&path, &["mesh".into(), "-vvv".into()])
+            .unwrap()
+            .iter()
+            .all(|arg| arg != "--verbose"));
+
+        fs::write(&path, "topic = [[\"nested\"]]").unwrap();
+        assert!(merge(&path, &args).is_err());
+        fs::write(&path, "topic = ").unwrap();
+        assert!(merge(&path, &args).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
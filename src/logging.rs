@@ -0,0 +1,426 @@
+//! Log output.
+//!
+//! The node logs through `tracing`, and records of the `log` macros, its own
+//! and those of libp2p, are bridged in. Records go to stderr, or with
+//! `--log-file "path=<file>"` to a [`node::rolling::RollingFile`].
+//! `RUST_LOG` filters them the same way.
+//!
+//! Records show the spans they happened in, see [`node::spans`]: text lines
+//! like `[time level target] swarm{peer_id=..}:connection{peer=..}: message`,
+//! with the fields of the record after the message. Both outputs show peer
+//! ids as set with `--peer-names`, see [`node::names`]. With `--log-format
+//! json` they write one JSON object per record instead, with `time`,
+//! `level`, `target` and `message`, the fields of the record, and the
+//! `spans`, outermost first, each with its `name` and fields, for log
+//! aggregation.
+//!
+//! The returned [`LogFilter`] replaces the `RUST_LOG` filter of the logger
+//! while it runs, for config reloads.
+
+use crate::{
+    node::{names, rolling},
+    prelude::*,
+};
+use anyhow::bail;
+use env_logger::filter::{self, Filter};
+use std::{
+    fmt::{self, Write as _},
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+use tracing::{
+    field::{Field, Visit},
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_log::{AsLog, LogTracer, NormalizeEvent};
+use tracing_subscriber::{
+    layer::{self, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+/// How log records are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// `[time level target] spans: message fields` lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => Self::Text,
+            "json" => Self::Json,
+            _ => bail!("Unknown log format {}, expected text or json", s),
+        })
+    }
+}
+
+/// The fields of a span or record, peer ids shown as set with
+/// `--peer-names`.
+#[derive(Clone, Default, Debug)]
+struct Fields {
+    message: Option<String>,
+    values:  Vec<(&'static str, String)>,
+}
+
+impl Fields {
+    fn push(&mut self, field: &Field, value: &str) {
+        let value = names::rewrite(value).into_owned();
+        match field.name() {
+            "message" => self.message = Some(value),
+            // The bridge repeats the metadata of `log` records as fields
+            name if name.starts_with("log.") => {}
+            name => self.values.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, &format!("{:?}", value));
+    }
+}
+
+/// A record with the spans it happened in, outermost first.
+struct Line<'a> {
+    level:  Level,
+    target: &'a str,
+    spans:  Vec<(&'static str, Fields)>,
+    fields: Fields,
+}
+
+impl Line<'_> {
+    fn text(&self, colored: bool) -> String {
+        let level = format!("{:<5}", self.level);
+        let level = if colored {
+            let color = match self.level {
+                Level::ERROR => 31,
+                Level::WARN => 33,
+                Level::INFO => 32,
+                Level::DEBUG => 34,
+                Level::TRACE => 36,
+            };
+            format!("\x1b[{}m{}\x1b[0m", color, level)
+        } else {
+            level
+        };
+        let mut line = format!(
+            "[{} {} {}] ",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            level,
+            self.target
+        );
+        for (name, fields) in &self.spans {
+            line.push_str(name);
+            if !fields.values.is_empty() {
+                let values = fields
+                    .values
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value));
+                let _ = write!(line, "{{{}}}", values.collect::<Vec<_>>().join(" "));
+            }
+            line.push(':');
+        }
+        if !self.spans.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(self.fields.message.as_deref().unwrap_or_default());
+        for (key, value) in &self.fields.values {
+            let _ = write!(line, " {}={}", key, value);
+        }
+        line
+    }
+
+    fn json(&self) -> String {
+        let mut object = serde_json::Map::new();
+        let time = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        object.insert("time".into(), time.into());
+        object.insert("level".into(), self.level.to_string().into());
+        object.insert("target".into(), self.target.into());
+        let message = self.fields.message.clone().unwrap_or_default();
+        object.insert("message".into(), message.into());
+        for (key, value) in &self.fields.values {
+            object.entry(*key).or_insert_with(|| value.clone().into());
+        }
+        if !self.spans.is_empty() {
+            let spans = self.spans.iter().map(|(name, fields)| {
+                let mut span = serde_json::Map::new();
+                span.insert("name".into(), (*name).into());
+                for (key, value) in &fields.values {
+                    span.entry(*key).or_insert_with(|| value.clone().into());
+                }
+                serde_json::Value::Object(span)
+            });
+            object.insert("spans".into(), spans.collect());
+        }
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+/// The filter of the installed logger, in the syntax of `RUST_LOG`.
+#[derive(Clone)]
+pub struct LogFilter(Arc<RwLock<Filter>>);
+
+impl LogFilter {
+    fn new(spec: &str) -> Self {
+        let filter = filter::Builder::new().parse(spec).build();
+        log::set_max_level(filter.filter());
+        Self(Arc::new(RwLock::new(filter)))
+    }
+
+    /// Filter by `spec` from now on.
+    pub fn set(&self, spec: &str) {
+        let filter = filter::Builder::new().parse(spec).build();
+        log::set_max_level(filter.filter());
+        if let Ok(mut current) = self.0.write() {
+            *current = filter;
+        }
+    }
+
+    /// Whether records of `metadata` may pass, before their message is
+    /// known.
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0
+            .read()
+            .map_or(false, |filter| filter.enabled(&metadata.as_log()))
+    }
+
+    /// Whether a record of `metadata` with `message` passes, which the
+    /// `/regex` of a spec matches.
+    fn matches(&self, metadata: &Metadata, message: &str) -> bool {
+        self.0.read().map_or(false, |filter| {
+            filter.matches(
+                &log::Record::builder()
+                    .metadata(metadata.as_log())
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        })
+    }
+}
+
+/// The spec of `RUST_LOG`, the empty default if unset.
+fn env_spec() -> String {
+    std::env::var("RUST_LOG").unwrap_or_default()
+}
+
+/// Where lines go.
+enum Sink {
+    Stderr {
+        colored: bool,
+    },
+    File(Mutex<rolling::RollingFile>),
+    #[cfg(test)]
+    Memory(Arc<Mutex<Vec<u8>>>),
+}
+
+impl Sink {
+    /// Stderr, with colored levels if it is a terminal or `RUST_LOG_STYLE`
+    /// is `always`, and not if it is `never`.
+    fn stderr() -> Self {
+        let colored = match std::env::var("RUST_LOG_STYLE").as_deref() {
+            Ok("always") => true,
+            Ok("never") => false,
+            _ => unsafe { libc::isatty(libc::STDERR_FILENO) == 1 },
+        };
+        Self::Stderr { colored }
+    }
+
+    fn write(&self, line: &str) {
+        match self {
+            Self::Stderr { .. } => {
+                let _ = std::io::stderr().lock().write_all(line.as_bytes());
+            }
+            Self::File(file) => {
+                if let Ok(mut file) = file.lock() {
+                    let _ = file.write_all(line.as_bytes());
+                }
+            }
+            #[cfg(test)]
+            Self::Memory(buffer) => {
+                if let Ok(mut buffer) = buffer.lock() {
+                    buffer.extend_from_slice(line.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Writes the records [`LogFilter`] passes to a [`Sink`] in a [`Format`].
+struct Output {
+    filter: LogFilter,
+    format: Format,
+    sink:   Sink,
+}
+
+impl<S> Layer<S> for Output
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // Spans always give records their context, and records are checked
+        // on every call since the filter changes on reloads
+        if metadata.is_span() {
+            Interest::always()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: layer::Context<'_, S>) -> bool {
+        metadata.is_span() || self.filter.enabled(metadata)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: layer::Context<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        if !self
+            .filter
+            .matches(metadata, fields.message.as_deref().unwrap_or_default())
+        {
+            return;
+        }
+        let spans = ctx.event_scope(event).map_or_else(Vec::new, |scope| {
+            scope
+                .from_root()
+                .map(|span| {
+                    let fields = span.extensions().get::<Fields>().cloned();
+                    (span.name(), fields.unwrap_or_default())
+                })
+                .collect()
+        });
+        let line = Line {
+            level: *metadata.level(),
+            target: metadata.target(),
+            spans,
+            fields,
+        };
+        // Format first so the line is written, and rotated, in one piece
+        let line = match (self.format, &self.sink) {
+            (Format::Json, _) => line.json(),
+            (Format::Text, Sink::Stderr { colored }) => line.text(*colored),
+            (Format::Text, _) => line.text(false),
+        };
+        self.sink.write(&(line + "\n"));
+    }
+}
+
+/// Install `sink` as the logger, for records of both `tracing` and `log`.
+fn install(format: Format, sink: Sink) -> Result<LogFilter> {
+    LogTracer::init().context("Logger already initialized")?;
+    let filter = LogFilter::new(&env_spec());
+    let output = Output {
+        filter: filter.clone(),
+        format,
+        sink,
+    };
+    tracing::subscriber::set_global_default(Registry::default().with(output))
+        .context("Logger already initialized")?;
+    Ok(filter)
+}
+
+/// Install a logger writing to stderr in `format`, filtered by `RUST_LOG`.
+pub fn init_stderr(format: Format) -> Result<LogFilter> {
+    install(format, Sink::stderr())
+}
+
+/// Install a logger writing to the file described by `config` in `format`,
+/// filtered by `RUST_LOG`.
+pub fn init(config: rolling::Config, format: Format) -> Result<LogFilter> {
+    let file = Mutex::new(rolling::RollingFile::open(config)?);
+    install(format, Sink::File(file))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    /// The lines logged in `format` with `spec` while running `log`.
+    fn capture(format: Format, spec: &str, log: impl FnOnce()) -> Vec<String> {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let output = Output {
+            filter: LogFilter(Arc::new(RwLock::new(
+                filter::Builder::new().parse(spec).build(),
+            ))),
+            format,
+            sink: Sink::Memory(buffer.clone()),
+        };
+        tracing::subscriber::with_default(Registry::default().with(output), log);
+        let buffer = buffer.lock().unwrap();
+        String::from_utf8_lossy(&buffer)
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn log_in_spans() {
+        let connection = tracing::info_span!("connection", peer = "QmPeer");
+        let _connection = connection.enter();
+        let message = tracing::debug_span!("receive", message_id = "00ff", topic = "chat");
+        let _message = message.enter();
+        tracing::warn!(bytes = 3, "Dropping {}", "message");
+        tracing::debug!("Hidden");
+    }
+
+    #[test]
+    fn test_formats_text_records_in_spans() {
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("yaml".parse::<Format>().is_err());
+        let lines = capture(Format::Text, "info", log_in_spans);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with('['));
+        assert!(lines[0].ends_with(
+            "WARN  mesh::logging::test] connection{peer=QmPeer}:receive{message_id=00ff \
+             topic=chat}: Dropping message bytes=3"
+        ));
+        assert!(capture(Format::Text, "info/Passing", log_in_spans).is_empty());
+    }
+
+    #[test]
+    fn test_formats_json_records_in_spans() {
+        let lines = capture(Format::Json, "debug", log_in_spans);
+        assert_eq!(lines.len(), 2);
+        let value: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "mesh::logging::test");
+        assert_eq!(value["message"], "Dropping message");
+        assert_eq!(value["bytes"], "3");
+        assert_eq!(value["spans"][0]["name"], "connection");
+        assert_eq!(value["spans"][0]["peer"], "QmPeer");
+        assert_eq!(value["spans"][1]["message_id"], "00ff");
+        assert_eq!(value["spans"][1]["topic"], "chat");
+        assert!(value["time"].as_str().unwrap().ends_with('Z'));
+    }
+}
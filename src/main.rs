@@ -1,6 +1,8 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
-mod node;
+mod config;
+mod logging;
+pub mod node;
 mod utils;
 
 mod prelude {
@@ -15,6 +17,7 @@ mod prelude {
 }
 
 use prelude::*;
+use std::{ffi::OsString, path::PathBuf, time::Duration};
 use structopt::StructOpt;
 
 // Gossipsub is very noisy, so limit it to warn by default even if
@@ -27,6 +30,363 @@ struct Options {
     #[structopt(short, long, parse(from_occurrences))]
     verbose: usize,
 
+    /// Read options from this TOML file, by their long names. `MESH_`
+    /// environment variables like `MESH_DATA_DIR` and the command line
+    /// override it
+    #[structopt(long, parse(from_os_str), env = "MESH_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Directory for persistent node state
+    #[structopt(long, parse(from_os_str), env = "MESH_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// How the data directory keeps state: `files` or `sled`
+    #[structopt(long, default_value = "files", env = "MESH_STORAGE")]
+    storage: node::storage::Backend,
+
+    /// File keeping the node identity, by default in the storage of the data
+    /// directory or `~/.mesh-rs/identity.key`. Encrypted with
+    /// `MESH_IDENTITY_PASSPHRASE`, if set
+    #[structopt(long, parse(from_os_str), env = "MESH_IDENTITY")]
+    identity: Option<PathBuf>,
+
+    /// Application namespace, keeping topics separate from other applications
+    #[structopt(long, env = "MESH_NAMESPACE")]
+    namespace: Option<String>,
+
+    /// Generate synthetic traffic and check delivery, e.g.
+    /// `--soak "rate=1000/s size=512B topics=10"`
+    #[structopt(long, env = "MESH_SOAK")]
+    soak: Option<node::soak::Config>,
+
+    /// Push metrics to StatsD, e.g.
+    /// `--statsd "address=127.0.0.1:8125 interval=10s prefix=mesh"`
+    #[structopt(long, env = "MESH_STATSD")]
+    statsd: Option<node::statsd::Config>,
+
+    /// Log to a file instead of stderr, rotating it, e.g.
+    /// `--log-file "path=mesh.log size=10MiB age=1d keep=5 compress=true"`
+    #[structopt(long, env = "MESH_LOG_FILE")]
+    log_file: Option<node::rolling::Config>,
+
+    /// How to write log records: `text`, or `json` for one object per line
+    #[structopt(long, default_value = "text", env = "MESH_LOG_FORMAT")]
+    log_format: logging::Format,
+
+    /// Record delivered messages in a binary journal, rotated like the log
+    /// file, e.g. `--journal "path=journal size=100MiB"`
+    #[structopt(long, env = "MESH_JOURNAL")]
+    journal: Option<node::rolling::Config>,
+
+    /// Record connections, dials, discoveries, messages by hash and errors
+    /// as JSON lines, rotated like the log file, e.g.
+    /// `--event-journal "path=events.jsonl size=100MiB"`
+    #[structopt(long, env = "MESH_EVENT_JOURNAL")]
+    event_journal: Option<node::rolling::Config>,
+
+    /// How to detect and handle wall clock jumps, e.g.
+    /// `--clock-jumps "threshold=10s action=rebaseline"` or `action=report`
+    #[structopt(long, default_value = "", env = "MESH_CLOCK_JUMPS")]
+    clock_jumps: node::clock::Config,
+
+    /// Probe connections to keep carrier NAT mappings open, e.g.
+    /// `--keepalive "interval=25s failures=2 peers=critical"` or `peers=all`
+    #[structopt(long, env = "MESH_KEEPALIVE")]
+    keepalive: Option<node::keepalive::Config>,
+
+    /// How to reconnect to bootstrap and supervised peers that dropped, e.g.
+    /// `--reconnect "first=1s max=5m jitter=0.2 retries=10"` or
+    /// `retries=forever`
+    #[structopt(long, default_value = "", env = "MESH_RECONNECT")]
+    reconnect: node::supervisor::Config,
+
+    /// Time out dials and close connections without traffic, e.g.
+    /// `--connection-health "dial=20s idle=10m"` or `idle=never`
+    #[structopt(long, default_value = "", env = "MESH_CONNECTION_HEALTH")]
+    connection_health: node::health::Config,
+
+    /// Rate limit the pubsub messages of each peer, and disconnect and ban
+    /// peers that flood or send invalid messages, e.g.
+    /// `--scoring "rate=100 burst=200 ban_for=1h"`
+    #[structopt(long, env = "MESH_SCORING")]
+    scoring: Option<node::scoring::Config>,
+
+    /// Gossip a heartbeat with our nickname and capabilities, and keep a
+    /// roster of the peers online on each topic, e.g.
+    /// `--presence "nickname=alice capability=chat interval=30s timeout=90s"`
+    #[structopt(long, env = "MESH_PRESENCE")]
+    presence: Option<node::presence::Config>,
+
+    /// Publish a signed profile so peers show and address us by nickname,
+    /// e.g. `--nickname "name=alice avatar=<sha256 hex> meta=team:ops"`
+    #[structopt(long, env = "MESH_NICKNAME")]
+    nickname: Option<node::nickname::Config>,
+
+    /// Refuse to publish payloads over `max` and split messages over `frame`
+    /// into fragments, e.g. `--message-size "max=4MiB frame=128KiB"`
+    #[structopt(long, default_value = "", env = "MESH_MESSAGE_SIZE")]
+    message_size: node::fragment::Config,
+
+    /// Let our messages expire and limit how many relays republish them,
+    /// e.g. `--message-ttl "ttl=5m hops=4"`
+    #[structopt(long, default_value = "", env = "MESH_MESSAGE_TTL")]
+    message_ttl: node::expiry::Config,
+
+    /// Compress payloads for peers built with the same codec, e.g.
+    /// `--compression "threshold=1KiB codecs=zstd,lz4"` or `codecs=none`
+    #[structopt(long, default_value = "", env = "MESH_COMPRESSION")]
+    compression: node::codec::Config,
+
+    /// Resend messages on topics subscribed with acknowledgements until
+    /// their subscribers acknowledge them, e.g.
+    /// `--reliable "retry=5s retries=5 window=1024"`
+    #[structopt(long, default_value = "", env = "MESH_RELIABLE")]
+    reliable: node::reliable::Config,
+
+    /// Carry messages for disconnected peers and exchange them when peers
+    /// meet, e.g. `--dtn "capacity=10000 quota=256MiB evict=priority lifetime=1d copies=8"`
+    #[structopt(long, env = "MESH_DTN")]
+    dtn: Option<node::dtn::Config>,
+
+    /// Let this peer retrieve debug bundles of logs, status and peers with
+    /// `mesh bundle`. May be repeated.
+    #[structopt(long, env = "MESH_DEBUG_ADMIN")]
+    debug_admin: Vec<libp2p::PeerId>,
+
+    /// Keep redundant connections to this peer, e.g.
+    /// `--critical /ip4/10.0.0.1/tcp/4001/p2p/<peer id>`. May be repeated.
+    #[structopt(long, env = "MESH_CRITICAL")]
+    critical: Vec<libp2p::Multiaddr>,
+
+    /// Always keep a connection to this peer, even over `--max-peers`, e.g.
+    /// `--pin /ip4/10.0.0.1/tcp/4001/p2p/<peer id>`. May be repeated.
+    #[structopt(long, env = "MESH_PIN")]
+    pin: Vec<libp2p::Multiaddr>,
+
+    /// Also listen on this address, e.g. `--listen /ip4/0.0.0.0/udp/4002` for
+    /// the UDP transport on lossy networks. May be repeated.
+    #[structopt(long, env = "MESH_LISTEN")]
+    listen: Vec<libp2p::Multiaddr>,
+
+    /// Listen on and dial IPv4, IPv6 or both, e.g.
+    /// `--address-family "listen=both dial=prefer-ip6"` or `dial=ip4`
+    #[structopt(long, default_value = "", env = "MESH_ADDRESS_FAMILY")]
+    address_family: node::family::Config,
+
+    /// Dial TCP addresses through a SOCKS5 proxy, e.g.
+    /// `--proxy socks5h://127.0.0.1:9050` to resolve names through it too,
+    /// or `--proxy "socks5://10.0.0.2:1080 peer=<peer id>"` for the addresses
+    /// of one bootstrap peer. May be repeated.
+    #[structopt(long, env = "MESH_PROXY")]
+    proxy: Vec<node::proxy::Route>,
+
+    /// Also listen on a local link, e.g. `--link ble:any` for Bluetooth LE or
+    /// `--link serial:ttyUSB0@115200`. May be repeated.
+    #[structopt(long = "link", env = "MESH_LINK")]
+    links: Vec<String>,
+
+    /// Bandwidth caps, e.g.
+    /// `--bandwidth "upload=1MiB/s download=4MiB/s peer-upload=256KiB/s peer-download=1MiB/s"`
+    #[structopt(long, default_value = "", env = "MESH_BANDWIDTH")]
+    bandwidth: node::shaping::Config,
+
+    /// Connection caps, e.g.
+    /// `--connection-limits "incoming=256 outgoing=128 pending_dials=32 per_peer=4 per_ip=16"`
+    #[structopt(long = "connection-limits", default_value = "", env = "MESH_CONNECTION_LIMITS")]
+    limits: node::admission::Config,
+
+    /// Start in power-save mode. `SIGUSR1` enters and `SIGUSR2` leaves it.
+    #[structopt(long)]
+    power_save: bool,
+
+    /// Daily windows, in UTC, in which the node goes dormant, e.g.
+    /// `--quiet-hours "22:00-06:00,12:00-12:30"`
+    #[structopt(long, default_value = "", env = "MESH_QUIET_HOURS")]
+    quiet_hours: node::quiet::Schedule,
+
+    /// How peer ids are shown in logs and `mesh top`: `full`, `short` for
+    /// the last characters or `words` for names like `brave-otter-7f3a`
+    #[structopt(long, default_value = "full", env = "MESH_PEER_NAMES")]
+    peer_names: node::names::Format,
+
+    /// What to do when a dialed address answers with another peer id, as
+    /// after a reinstall: `reject`, `update` the address book and dial the
+    /// new peer, or `prompt` the application with an event
+    #[structopt(long, default_value = "reject", env = "MESH_IDENTITY_MISMATCH")]
+    identity_mismatch: node::mismatch::Policy,
+
+    /// What to do when two connections to the same peer open, as when both
+    /// dial at once: keep the one dialed by the lower peer id
+    /// (`deterministic`), or `keep` both
+    #[structopt(
+        long = "duplicate-connections",
+        default_value = "deterministic",
+        env = "MESH_DUPLICATE_CONNECTIONS"
+    )]
+    duplicates: node::duplicate::Policy,
+
+    /// Whether to `flag` or `drop` received messages that are `unsigned`, or
+    /// signed by another peer than their source, like
+    /// `--verification "unsigned=drop invalid=drop"`. Flags unsigned and
+    /// drops invalid messages by default
+    #[structopt(long, default_value = "", env = "MESH_VERIFICATION")]
+    verification: node::verification::Policy,
+
+    /// Also bootstrap through this peer, e.g.
+    /// `--bootstrap /ip4/10.0.0.1/tcp/4001/p2p/<peer id>`, or the peers
+    /// listed in DNS with `--bootstrap /dnsaddr/bootstrap.example.com`. May
+    /// be repeated.
+    #[structopt(long, env = "MESH_BOOTSTRAP")]
+    bootstrap: Vec<libp2p::Multiaddr>,
+
+    /// Number of bootstrap peers that must answer for the bootstrap to be
+    /// complete
+    #[structopt(long, default_value = "1", env = "MESH_BOOTSTRAP_QUORUM")]
+    bootstrap_quorum: usize,
+
+    /// Keep the registrations of other peers as a rendezvous point
+    #[structopt(long, env = "MESH_RENDEZVOUS_SERVER")]
+    rendezvous_server: bool,
+
+    /// Register our topics at this rendezvous point and find their peers
+    /// there, e.g. `--rendezvous /ip4/10.0.0.1/tcp/4001/p2p/<peer id>`. May
+    /// be repeated.
+    #[structopt(long, env = "MESH_RENDEZVOUS")]
+    rendezvous: Vec<libp2p::Multiaddr>,
+
+    /// Receive files offered by peers, e.g.
+    /// `--files "dir=/var/lib/mesh/downloads max_size=1GiB"`. Without a
+    /// `dir` files are refused.
+    #[structopt(long, default_value = "", env = "MESH_FILES")]
+    files: node::file::Config,
+
+    /// Discovery mechanisms to turn off and their tuning, e.g.
+    /// `--discovery "mdns=false bootnodes=false"` or
+    /// `--discovery "mdns_service=_orders._udp.local network=prod"`
+    #[structopt(long, default_value = "", env = "MESH_DISCOVERY")]
+    discovery: node::discovery::Config,
+
+    /// Only connect to this peer id or IP network, e.g. `--allow 10.0.0.0/8`.
+    /// May be repeated.
+    #[structopt(long, env = "MESH_ALLOW")]
+    allow: Vec<node::gate::Rule>,
+
+    /// Never connect to this peer id or IP network. May be repeated.
+    #[structopt(long, env = "MESH_DENY")]
+    deny: Vec<node::gate::Rule>,
+
+    /// Protocols securing connections, `noise` once no peer needs secio
+    #[structopt(long, default_value = "noise,secio", env = "MESH_SECURITY")]
+    security: node::security::Config,
+
+    /// Pin protocols and pubsub parameters to go-libp2p and js-libp2p
+    /// defaults with `libp2p`, to mesh with their daemons
+    #[structopt(long, default_value = "native", env = "MESH_COMPAT")]
+    compat: node::compat::Compat,
+
+    /// Only connect to nodes with the pre-shared key in this file, see
+    /// `keygen`
+    #[structopt(long, parse(from_os_str), env = "MESH_SWARM_KEY")]
+    swarm_key: Option<PathBuf>,
+
+    /// Memory limits, `constrained` for 64 MB-class devices
+    #[structopt(long, default_value = "default", env = "MESH_PROFILE")]
+    profile: node::profile::Profile,
+
+    /// Keep at most this many connected peers besides critical, hot and
+    /// pinned peers, overriding the limit of `--profile`
+    #[structopt(long, env = "MESH_MAX_PEERS")]
+    max_peers: Option<usize>,
+
+    /// How long shutting down may take, sending the last publishes and
+    /// closing connections
+    #[structopt(
+        long,
+        default_value = "5s",
+        parse(try_from_str = humantime::parse_duration),
+        env = "MESH_SHUTDOWN_TIMEOUT"
+    )]
+    shutdown_timeout: std::time::Duration,
+
+    /// Subscribe to this topic. May be repeated.
+    #[structopt(long, env = "MESH_TOPIC")]
+    topic: Vec<String>,
+
+    /// Encrypt a topic with the pre-shared key in a file, like
+    /// `chat=chat.key`, see `keygen --topic`. May be repeated.
+    #[structopt(long, env = "MESH_TOPIC_KEY")]
+    topic_key: Vec<node::keyring::KeyFile>,
+
+    /// Pubsub protocol and tuning, e.g. `--pubsub "protocol=floodsub"` for
+    /// small LANs or `--pubsub "mesh=6 mesh-low=5 mesh-high=12 heartbeat=1s"`
+    #[structopt(long, default_value = "", env = "MESH_PUBSUB")]
+    pubsub: node::pubsub::Config,
+
+    /// Persist publishes on this topic and resend them after a restart until
+    /// sent, needs --data-dir. May be repeated.
+    #[structopt(long, env = "MESH_OUTBOX")]
+    outbox: Vec<String>,
+
+    /// Publish on a timer, e.g.
+    /// `--heartbeat "topic=sensors interval=10s data=alive"`. May be repeated.
+    #[structopt(long, env = "MESH_HEARTBEAT")]
+    heartbeat: Vec<node::publisher::Heartbeat>,
+
+    /// Publishes waiting to be sent at most, beyond which publishes fail
+    #[structopt(long, default_value = "1024", env = "MESH_PUBLISH_QUEUE")]
+    publish_queue: usize,
+
+    /// Keep this many past messages per topic and answer backfills of
+    /// subscribers with them
+    #[structopt(long, env = "MESH_ARCHIVE")]
+    archive: Option<usize>,
+
+    /// Keep the archive in the data directory, so it survives restarts
+    #[structopt(long)]
+    persist_archive: bool,
+
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9090
+    #[structopt(long, env = "MESH_METRICS")]
+    metrics: Option<std::net::SocketAddr>,
+
+    /// Answer `/healthz` and `/readyz` probes, e.g.
+    /// `--probes "address=0.0.0.0:8081 peers=2 stall=30s"`
+    #[structopt(long, env = "MESH_PROBES")]
+    probes: Option<node::probe::Config>,
+
+    /// Serve the HTTP control API, e.g.
+    /// `--api "address=127.0.0.1:8080 token-file=/run/secrets/mesh-api"`
+    #[structopt(long, env = "MESH_API")]
+    api: Option<node::api::Config>,
+
+    /// Require one of the tokens in this file on the control socket, and
+    /// accept them on the API, one `<permission> <token>` per line with
+    /// `status`, `publish` or `admin`
+    #[structopt(long, parse(from_os_str), env = "MESH_ACCESS")]
+    access: Option<PathBuf>,
+
+    /// Token `top`, `bundle` and `attach` authenticate with, for a node
+    /// running with `--access`
+    #[structopt(long, env = "MESH_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// What to do when mDNS, the metrics endpoint or the StatsD exporter
+    /// fails to start: `fail`, or `degrade` to run without it
+    #[structopt(long, default_value = "fail", env = "MESH_SUBSYSTEM_FAILURES")]
+    subsystem_failures: node::degrade::Policy,
+
+    /// Read commands from stdin: plain lines are published on the first
+    /// `--topic`, and `/peers`, `/subscribe`, `/dial`, `/msg` and `/quit`
+    /// perform actions
+    #[structopt(long)]
+    interactive: bool,
+
+    /// `full`, `bootstrap` to only help other nodes join: identify, the DHT
+    /// and dial-backs, without pubsub, or `daemon` to share the node with the
+    /// processes of the host that `attach` to it
+    #[structopt(long, default_value = "full", env = "MESH_MODE")]
+    mode: node::mode::Mode,
+
     #[structopt(subcommand)]
     command: Option<Command>,
 }
@@ -35,10 +395,395 @@ struct Options {
 enum Command {
     /// Show version information
     Test,
+    /// Show live peers, bandwidth, topics and events of the node running on
+    /// the data directory
+    Top,
+    /// Print the entries of a message journal, or read an event journal
+    Journal {
+        #[structopt(parse(from_os_str))]
+        path:    Option<PathBuf>,
+        #[structopt(subcommand)]
+        command: Option<JournalCommand>,
+    },
+    /// Print the archived messages matching a full-text query, newest first,
+    /// from the index of the node on the data directory
+    Search {
+        /// Words to look for, in the SQLite FTS5 query syntax
+        query: String,
+        /// Only messages on this topic
+        #[structopt(long)]
+        topic: Option<String>,
+        /// Only messages at or after this time, e.g. `2020-12-01T10:00:00Z`
+        #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        since: Option<std::time::SystemTime>,
+        /// Only messages before this time
+        #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        until: Option<std::time::SystemTime>,
+        /// How many messages to print at most
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: usize,
+    },
+    /// Retrieve the debug bundle of another node, through the node running
+    /// on the data directory
+    Bundle {
+        peer_id: String,
+        /// Where to write the gzipped tarball
+        #[structopt(parse(from_os_str))]
+        output:  PathBuf,
+    },
+    /// Print a new pre-shared key for a private network, to save as the
+    /// `--swarm-key` file of its nodes
+    Keygen {
+        /// Print a key for `--topic-key` instead
+        #[structopt(long)]
+        topic: bool,
+    },
+    /// Attach to the daemon running on the data directory, print the
+    /// messages on the topics and publish the lines of stdin on the first
+    Attach {
+        #[structopt(long)]
+        topic: Vec<String>,
+    },
+    /// Manage the node identity at `--identity`
+    Identity(IdentityCommand),
+    /// Control the node running on the data directory, one request at a time
+    Ctl(CtlCommand),
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+enum CtlCommand {
+    /// Publish a message through the daemon
+    Publish { topic: String, message: String },
+    /// Print the messages on the topics, through the daemon
+    Subscribe {
+        #[structopt(required = true)]
+        topics: Vec<String>,
+    },
+    /// List the connected peers
+    Peers,
+    /// Connect to a peer
+    Dial { address: libp2p::Multiaddr },
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+enum JournalCommand {
+    /// Print the last records of an event journal
+    Tail {
+        #[structopt(parse(from_os_str))]
+        path:   PathBuf,
+        /// How many records to print
+        #[structopt(short = "n", long, default_value = "20")]
+        lines:  usize,
+        /// Keep printing records as they are appended
+        #[structopt(short, long)]
+        follow: bool,
+    },
+    /// Print the records of event journals, oldest file first
+    Replay {
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+        /// Only records of this event, e.g. `connected` or `message`
+        #[structopt(long)]
+        event: Option<String>,
+        /// Only records about this peer
+        #[structopt(long)]
+        peer:  Option<libp2p::PeerId>,
+        /// Only records on this topic
+        #[structopt(long)]
+        topic: Option<String>,
+        /// Only records at or after this time, e.g. `2020-12-01T10:00:00Z`
+        #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        since: Option<std::time::SystemTime>,
+    },
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+enum IdentityCommand {
+    /// Replace the identity key and print the new peer id. Until the grace
+    /// period ends the node keeps the old key, vouches for the new peer id
+    /// with it and republishes its name records, so peers migrate their
+    /// address books. Takes effect when the node starts next.
+    Rotate {
+        /// Type of the new key: `ed25519`, `secp256k1`, or `rsa` with
+        /// `--import`
+        #[structopt(long, default_value = "ed25519")]
+        key_type: node::keystore::KeyType,
+        /// Use the key in this file instead of generating one: an Ed25519
+        /// secret key or keypair, a raw or DER Secp256k1 secret key, or an
+        /// RSA key in PKCS#8 DER
+        #[structopt(long, parse(from_os_str))]
+        import:   Option<PathBuf>,
+        /// How long to keep the old key
+        #[structopt(long, default_value = "7d", parse(try_from_str = humantime::parse_duration))]
+        grace:    std::time::Duration,
+    },
+}
+
+/// The options of `args`, merged with their `--config` file.
+fn parse(app: &structopt::clap::App<'_, '_>, args: &[OsString]) -> Result<Options> {
+    let options = Options::from_clap(&app.clone().get_matches_from(args));
+    match options.config.clone() {
+        Some(path) => {
+            let matches = app
+                .clone()
+                .get_matches_from_safe(config::merge(&path, args)?)
+                .map_err(|err| anyhow::anyhow!("{}", err.message))
+                .with_context(|| format!("Applying config {}", path.display()))?;
+            Ok(Options::from_clap(&matches))
+        }
+        None => Ok(options),
+    }
+}
+
+/// The `RUST_LOG` filter for `verbose`, followed by the user's `rust_log`.
+fn log_spec(verbose: usize, rust_log: Option<&str>) -> String {
+    let spec = match verbose {
+        0 => "error",
+        1 => "warn",
+        2 => "info",
+        3 => "info,mesh=debug",
+        3 => "debug",
+        _ => "trace,libp2p_gossipsub::behaviour=trace",
+    };
+    match rust_log {
+        Some(rust_log) => format!("{},{},{}", spec, DEFAULT_LOG, rust_log),
+        None => format!("{},{}", spec, DEFAULT_LOG),
+    }
 }
 
-async fn async_main(_options: Options) -> Result<()> {
-    node::run().await
+/// Read the config of `args` again and set the log levels it asks for,
+/// with the user's `rust_log`.
+fn reload_source(
+    args: Vec<OsString>,
+    rust_log: Option<String>,
+    log_filter: logging::LogFilter,
+) -> node::reload::Source {
+    node::reload::Source::new(move || {
+        let options = parse(&Options::clap(), &args)?;
+        log_filter.set(&log_spec(options.verbose, rust_log.as_deref()));
+        Ok(node::reload::Config {
+            topics:     options.topic,
+            listen:     options.listen,
+            bootstrap:  options.bootstrap,
+            critical:   options.critical,
+            pinned:     options.pin,
+            rendezvous: options.rendezvous,
+        })
+    })
+}
+
+async fn async_main(options: Options, reload: Option<node::reload::Source>) -> Result<()> {
+    match options.command {
+        Some(Command::Top) => {
+            let data_dir = options.data_dir.context("`top` needs --data-dir")?;
+            let path = data_dir.join(node::control::FILE_NAME);
+            return node::control::top(&path, options.token.as_deref()).await;
+        }
+        Some(Command::Journal { command, path }) => {
+            return match (command, path) {
+                (Some(JournalCommand::Tail { path, lines, follow }), _) => {
+                    node::audit::tail(&path, lines, follow)
+                }
+                (Some(JournalCommand::Replay {
+                    paths,
+                    event,
+                    peer,
+                    topic,
+                    since,
+                }), _) => {
+                    let filter = node::audit::Filter {
+                        event,
+                        peer,
+                        topic,
+                        since,
+                    };
+                    node::audit::replay(&paths, &filter)
+                }
+                (None, Some(path)) => node::journal::print(&path),
+                (None, None) => anyhow::bail!("`journal` needs a file, `tail` or `replay`"),
+            };
+        }
+        Some(Command::Search {
+            query,
+            topic,
+            since,
+            until,
+            limit,
+        }) => {
+            let data_dir = options.data_dir.context("`search` needs --data-dir")?;
+            #[cfg(feature = "search")]
+            {
+                let query = node::search::Query {
+                    text: query,
+                    topic,
+                    since,
+                    until,
+                    limit,
+                };
+                return node::search::print(&data_dir.join(node::search::FILE_NAME), &query);
+            }
+            #[cfg(not(feature = "search"))]
+            {
+                let _ = (data_dir, query, topic, since, until, limit);
+                anyhow::bail!("Built without full-text search, enable the search feature");
+            }
+        }
+        Some(Command::Attach { topic }) => {
+            let data_dir = options.data_dir.context("`attach` needs --data-dir")?;
+            let path = data_dir.join(node::control::FILE_NAME);
+            return node::daemon::attach(&path, options.token.as_deref(), &topic).await;
+        }
+        Some(Command::Ctl(command)) => {
+            let data_dir = options.data_dir.context("`ctl` needs --data-dir")?;
+            let path = data_dir.join(node::control::FILE_NAME);
+            let token = options.token.as_deref();
+            if let CtlCommand::Subscribe { topics } = &command {
+                return node::daemon::subscribe(&path, token, topics).await;
+            }
+            let mut client = node::control::connect(&path, token).await?;
+            match command {
+                CtlCommand::Publish { topic, message } => {
+                    let mut attached = client.attach().await?;
+                    attached.publish(&topic, message.as_bytes()).await?;
+                }
+                CtlCommand::Peers => {
+                    for peer in client.status().await?.peers {
+                        if peer.connected {
+                            println!("{}", peer.peer_id);
+                        }
+                    }
+                }
+                CtlCommand::Dial { address } => client.dial(&address.to_string()).await?,
+                CtlCommand::Subscribe { .. } => unreachable!("Handled above"),
+            }
+            return Ok(());
+        }
+        Some(Command::Keygen { topic: true }) => {
+            println!("{}", hex::encode(rand::random::<node::keyring::Key>()));
+            return Ok(());
+        }
+        Some(Command::Keygen { topic: false }) => {
+            print!("{}", node::pnet::SwarmKey::generate());
+            return Ok(());
+        }
+        Some(Command::Identity(IdentityCommand::Rotate {
+            key_type,
+            import,
+            grace,
+        })) => {
+            let backend = options.storage;
+            let storage = options
+                .data_dir
+                .as_deref()
+                .map(|data_dir| node::storage::open(backend, data_dir))
+                .transpose()?;
+            let moved = options.data_dir.as_ref().map(|dir| dir.join(node::keystore::KEY));
+            let slot = options
+                .identity
+                .filter(|path| Some(path) != moved.as_ref())
+                .map(node::storage::Slot::File)
+                .or_else(|| node::keystore::default_slot(storage.as_ref()))
+                .context("`identity rotate` needs --identity")?;
+            let import = import
+                .map(|path| {
+                    std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))
+                })
+                .transpose()?;
+            let passphrase = node::keystore::passphrase();
+            let identity = node::keystore::rotate(&slot, key_type, import, grace, &passphrase)?;
+            if let Some(previous) = &identity.previous {
+                info!(
+                    "Keeping {} until {}",
+                    libp2p::PeerId::from(previous.keypair.public()),
+                    humantime::format_rfc3339_seconds(previous.until)
+                );
+            }
+            println!("{}", identity.peer_id());
+            return Ok(());
+        }
+        Some(Command::Bundle { peer_id, output }) => {
+            let data_dir = options.data_dir.context("`bundle` needs --data-dir")?;
+            let path = data_dir.join(node::control::FILE_NAME);
+            let bundle = node::control::connect(&path, options.token.as_deref())
+                .await?
+                .bundle(&peer_id)
+                .await?;
+            std::fs::write(&output, bundle)
+                .with_context(|| format!("Writing {}", output.display()))?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    node::run(node::RunOptions {
+        data_dir:           options.data_dir,
+        storage:            options.storage,
+        identity:           options.identity,
+        namespace:          options.namespace,
+        soak:               options.soak,
+        statsd:             options.statsd,
+        journal:            options.journal,
+        event_journal:      options.event_journal,
+        clock_jumps:        options.clock_jumps,
+        keepalive:          options.keepalive,
+        reconnect:          options.reconnect,
+        connection_health:  options.connection_health,
+        scoring:            options.scoring,
+        presence:           options.presence,
+        nickname:           options.nickname,
+        message_size:       options.message_size,
+        message_ttl:        options.message_ttl,
+        compression:        options.compression,
+        reliable:           options.reliable,
+        publish_queue:      options.publish_queue,
+        dtn:                options.dtn,
+        log_file:           options.log_file.map(|config| config.path),
+        debug_admin:        options.debug_admin,
+        critical:           options.critical,
+        pinned:             options.pin,
+        listen:             options.listen,
+        address_family:     options.address_family,
+        proxy:              options.proxy,
+        links:              options.links,
+        bandwidth:          options.bandwidth,
+        limits:             options.limits,
+        power_save:         options.power_save,
+        quiet_hours:        options.quiet_hours,
+        identity_mismatch:  options.identity_mismatch,
+        duplicates:         options.duplicates,
+        verification:       options.verification,
+        bootstrap:          options.bootstrap,
+        bootstrap_quorum:   options.bootstrap_quorum,
+        rendezvous_server:  options.rendezvous_server,
+        rendezvous:         options.rendezvous,
+        files:              options.files,
+        pubsub:             options.pubsub,
+        outbox:             options.outbox,
+        heartbeats:         options.heartbeat,
+        topics:             options.topic,
+        topic_keys:         options.topic_key,
+        discovery:          options.discovery,
+        gate:               node::gate::Config {
+            allow: options.allow,
+            deny:  options.deny,
+        },
+        security:           options.security,
+        compat:             options.compat,
+        swarm_key:          options.swarm_key,
+        profile:            options.profile,
+        max_peers:          options.max_peers,
+        shutdown_timeout:   options.shutdown_timeout,
+        archive:            options.archive,
+        persist_archive:    options.persist_archive,
+        metrics:            options.metrics,
+        probes:             options.probes,
+        reload,
+        api:                options.api,
+        access:             options.access,
+        subsystem_failures: options.subsystem_failures,
+        interactive:        options.interactive,
+        mode:               options.mode,
+    })
+    .await
 }
 
 pub fn main() -> Result<()> {
@@ -59,24 +804,20 @@ pub fn main() -> Result<()> {
         target      = env!("TARGET"),
         build_date  = env!("BUILD_DATE"),
     );
-    let matches = Options::clap().long_version(version.as_str()).get_matches();
-    let options = Options::from_clap(&matches);
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let app = Options::clap().long_version(version.as_str());
+    let options = parse(&app, &args)?;
 
     // Initialize log output (prepend verbosity to RUST_LOG)
-    let rust_log = match options.verbose {
-        0 => "error",
-        1 => "warn",
-        2 => "info",
-        3 => "info,mesh=debug",
-        3 => "debug",
-        _ => "trace,libp2p_gossipsub::behaviour=trace",
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let reload = options.config.is_some().then(|| args.clone());
+    std::env::set_var("RUST_LOG", log_spec(options.verbose, rust_log.as_deref()));
+    node::names::set_format(options.peer_names);
+    let log_filter = match options.log_file.clone() {
+        Some(config) => logging::init(config, options.log_format)?,
+        None => logging::init_stderr(options.log_format)?,
     };
-    let rust_log_env = std::env::var("RUST_LOG").map_or_else(
-        |_| format!("{},{}", rust_log, DEFAULT_LOG),
-        |arg| format!("{},{},{}", rust_log, DEFAULT_LOG, arg),
-    );
-    std::env::set_var("RUST_LOG", rust_log_env);
-    env_logger::init();
+    let reload = reload.map(|args| reload_source(args, rust_log, log_filter));
 
     // Log version
     info!(
@@ -87,12 +828,15 @@ pub fn main() -> Result<()> {
     );
 
     // Launch Tokio runtime
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .context("Error creating Tokio runtime")?
-        .block_on(async_main(options))
-        .context("Error in main thread")?;
+        .context("Error creating Tokio runtime")?;
+    let result = runtime.block_on(async_main(options, reload));
+
+    // Do not wait on blocking tasks, like a read of stdin, to exit
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    result.context("Error in main thread")?;
 
     // Terminate successfully
     info!("program stopping normally");
@@ -110,13 +854,202 @@ mod test {
         pub use proptest::prelude::*;
     }
 
+    /// Swarms over loopback TCP, for tests of behaviours exchanging
+    /// messages.
+    pub mod swarm {
+        use libp2p::{
+            core::upgrade,
+            identity::Keypair,
+            mplex::MplexConfig,
+            plaintext::PlainText2Config,
+            swarm::{
+                ExpandedSwarm, IntoProtocolsHandler, NetworkBehaviour, ProtocolsHandler,
+                SwarmBuilder, SwarmEvent,
+            },
+            tcp::TokioTcpConfig,
+            Multiaddr, PeerId, Swarm, Transport,
+        };
+        use std::{error, time::Duration};
+        use tokio::time::{sleep, timeout};
+
+        /// A swarm of `behaviour` with a new peer id, without encryption,
+        /// running its connections on the tokio runtime.
+        pub fn new<B, I, O, H, E>(behaviour: B) -> (PeerId, ExpandedSwarm<B, I, O, H>)
+        where
+            B: NetworkBehaviour<ProtocolsHandler = H>,
+            I: Clone + Send + 'static,
+            O: Send + 'static,
+            H: IntoProtocolsHandler + Send + 'static,
+            H::Handler: ProtocolsHandler<InEvent = I, OutEvent = O, Error = E>,
+            E: error::Error + Send + 'static,
+        {
+            with_keypair(Keypair::generate_ed25519(), behaviour)
+        }
+
+        /// A swarm of `behaviour` with the peer id of `keypair`, like
+        /// [`new`].
+        pub fn with_keypair<B, I, O, H, E>(
+            keypair: Keypair,
+            behaviour: B,
+        ) -> (PeerId, ExpandedSwarm<B, I, O, H>)
+        where
+            B: NetworkBehaviour<ProtocolsHandler = H>,
+            I: Clone + Send + 'static,
+            O: Send + 'static,
+            H: IntoProtocolsHandler + Send + 'static,
+            H::Handler: ProtocolsHandler<InEvent = I, OutEvent = O, Error = E>,
+            E: error::Error + Send + 'static,
+        {
+            let peer_id = keypair.public().into_peer_id();
+            let transport = TokioTcpConfig::new()
+                .nodelay(true)
+                .upgrade(upgrade::Version::V1)
+                .authenticate(PlainText2Config {
+                    local_public_key: keypair.public(),
+                })
+                .multiplex(MplexConfig::new())
+                .boxed();
+            let swarm = SwarmBuilder::new(transport, behaviour, peer_id.clone())
+                .executor(Box::new(|future| {
+                    tokio::spawn(future);
+                }))
+                .build();
+            (peer_id, swarm)
+        }
+
+        /// Listen on a port of `127.0.0.1` and return its address.
+        pub async fn listen<B, I, O, H, E>(swarm: &mut ExpandedSwarm<B, I, O, H>) -> Multiaddr
+        where
+            B: NetworkBehaviour<ProtocolsHandler = H>,
+            I: Clone + Send + 'static,
+            O: Send + 'static,
+            H: IntoProtocolsHandler + Send + 'static,
+            H::Handler: ProtocolsHandler<InEvent = I, OutEvent = O, Error = E>,
+            E: error::Error + Send + 'static,
+        {
+            Swarm::listen_on(swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+            loop {
+                if let SwarmEvent::NewListenAddr(address) = swarm.next_event().await {
+                    return address;
+                }
+            }
+        }
+
+        /// Poll `swarm` and `other` until `check` finds what it waits for in
+        /// the behaviour events of `swarm` or in its state, which it is also
+        /// given without an event every few milliseconds. Panics after ten
+        /// seconds.
+        pub async fn run_until<B, I, O, H, E, T>(
+            swarm: &mut ExpandedSwarm<B, I, O, H>,
+            other: &mut ExpandedSwarm<B, I, O, H>,
+            mut check: impl FnMut(&mut ExpandedSwarm<B, I, O, H>, Option<B::OutEvent>) -> Option<T>,
+        ) -> T
+        where
+            B: NetworkBehaviour<ProtocolsHandler = H>,
+            I: Clone + Send + 'static,
+            O: Send + 'static,
+            H: IntoProtocolsHandler + Send + 'static,
+            H::Handler: ProtocolsHandler<InEvent = I, OutEvent = O, Error = E>,
+            E: error::Error + Send + 'static,
+        {
+            let run = async {
+                loop {
+                    let event = tokio::select! {
+                        event = swarm.next_event() => {
+                            match event {
+                                SwarmEvent::Behaviour(event) => Some(event),
+                                _ => None,
+                            }
+                        }
+                        _ = other.next_event() => None,
+                        _ = sleep(Duration::from_millis(10)) => None,
+                    };
+                    if let Some(found) = check(swarm, event) {
+                        return found;
+                    }
+                }
+            };
+            timeout(Duration::from_secs(10), run)
+                .await
+                .expect("Swarms did not get there in time")
+        }
+    }
+
     #[test]
     fn parse_args() {
         let cmd = "hello -vvv";
         let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
         assert_eq!(options, Options {
-            verbose: 3,
-            command: None,
+            verbose:            3,
+            config:             None,
+            data_dir:           None,
+            storage:            node::storage::Backend::Files,
+            identity:           None,
+            namespace:          None,
+            soak:               None,
+            statsd:             None,
+            log_file:           None,
+            log_format:         logging::Format::Text,
+            journal:            None,
+            event_journal:      None,
+            clock_jumps:        node::clock::Config::default(),
+            keepalive:          None,
+            reconnect:          node::supervisor::Config::default(),
+            connection_health:  node::health::Config::default(),
+            scoring:            None,
+            presence:           None,
+            nickname:           None,
+            message_size:       node::fragment::Config::default(),
+            message_ttl:        node::expiry::Config::default(),
+            compression:        node::codec::Config::default(),
+            reliable:           node::reliable::Config::default(),
+            publish_queue:      1024,
+            dtn:                None,
+            debug_admin:        Vec::new(),
+            critical:           Vec::new(),
+            pin:                Vec::new(),
+            listen:             Vec::new(),
+            address_family:     node::family::Config::default(),
+            proxy:              Vec::new(),
+            links:              Vec::new(),
+            bandwidth:          node::shaping::Config::default(),
+            limits:             node::admission::Config::default(),
+            power_save:         false,
+            quiet_hours:        node::quiet::Schedule::default(),
+            peer_names:         node::names::Format::Full,
+            identity_mismatch:  node::mismatch::Policy::Reject,
+            duplicates:         node::duplicate::Policy::Deterministic,
+            verification:       node::verification::Policy::default(),
+            bootstrap:          Vec::new(),
+            bootstrap_quorum:   1,
+            rendezvous_server:  false,
+            rendezvous:         Vec::new(),
+            files:              node::file::Config::default(),
+            pubsub:             node::pubsub::Config::default(),
+            outbox:             Vec::new(),
+            heartbeat:          Vec::new(),
+            discovery:          node::discovery::Config::default(),
+            allow:              Vec::new(),
+            deny:               Vec::new(),
+            security:           node::security::Config::default(),
+            compat:             node::compat::Compat::Native,
+            swarm_key:          None,
+            profile:            node::profile::Profile::Default,
+            max_peers:          None,
+            topic:              Vec::new(),
+            topic_key:          Vec::new(),
+            shutdown_timeout:   std::time::Duration::from_secs(5),
+            archive:            None,
+            persist_archive:    false,
+            metrics:            None,
+            probes:             None,
+            api:                None,
+            access:             None,
+            token:              None,
+            subsystem_failures: node::degrade::Policy::Fail,
+            interactive:        false,
+            mode:               node::mode::Mode::Full,
+            command:            None,
         });
     }
 
@@ -131,5 +1064,5 @@ mod test {
 
 #[cfg(feature = "bench")]
 pub fn bench_main(c: &mut criterion::Criterion) {
-    server::bench::group(c);
+    node::bench::group(c);
 }
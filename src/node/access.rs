@@ -0,0 +1,155 @@
+//! Tokens and permissions of the control planes.
+//!
+//! Without `--access`, anything that can open the [`control`] socket has
+//! full control, and the HTTP [`api`] knows its one token only. With
+//! `--access <file>`, the file lists one token per line with the
+//! [`Permission`] it grants before it, like `status 3b1c...`, and both
+//! planes let each token do what its permission allows and no more:
+//!
+//! * `status` reads the status, peers and topics,
+//! * `publish` also publishes and attaches to a daemon,
+//! * `admin` also dials, retrieves debug bundles and shuts the node down.
+//!
+//! Empty lines and lines starting with `#` are skipped. Socket clients send
+//! `{"Authenticate": {"token": "..."}}` first, HTTP clients an
+//! `Authorization: Bearer <token>` header.
+//!
+//! [`control`]: super::control
+//! [`api`]: super::api
+
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use std::{fmt, fs, path::Path, str::FromStr};
+
+/// What a token allows, each including the ones before.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum Permission {
+    /// Read-only status.
+    Status,
+    Publish,
+    /// Dial, retrieve bundles and shut down.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Status => "status",
+            Self::Publish => "publish",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "status" => Ok(Self::Status),
+            "publish" => Ok(Self::Publish),
+            "admin" => Ok(Self::Admin),
+            _ => Err(anyhow!("Expected status, publish or admin, got {}", s)),
+        }
+    }
+}
+
+/// Compare tokens in time independent of where they differ.
+fn matches(token: &str, given: &str) -> bool {
+    let (token, given) = (token.as_bytes(), given.as_bytes());
+    given.len() == token.len()
+        && given.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The tokens clients authorize with.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Tokens(Vec<(String, Permission)>);
+
+impl fmt::Debug for Tokens {
+    // Keep the tokens out of logs and crash reports
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(_, permission)| permission))
+            .finish()
+    }
+}
+
+impl FromStr for Tokens {
+    type Err = anyhow::Error;
+
+    /// Parse lines of `<permission> <token>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(permission), Some(token), None) => {
+                    let permission = permission
+                        .parse()
+                        .with_context(|| format!("Line {}", number + 1))?;
+                    tokens.insert(token, permission);
+                }
+                _ => bail!("Line {}: expected <permission> <token>", number + 1),
+            }
+        }
+        Ok(tokens)
+    }
+}
+
+impl Tokens {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading access tokens {}", path.display()))?;
+        contents
+            .parse()
+            .with_context(|| format!("Parsing access tokens {}", path.display()))
+    }
+
+    pub fn insert(&mut self, token: &str, permission: Permission) {
+        self.0.push((token.to_owned(), permission));
+    }
+
+    pub fn extend(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// What `given` allows, if it is one of the tokens.
+    pub fn permission(&self, given: Option<&str>) -> Option<Permission> {
+        let given = given?;
+        // Check every token, so timing does not tell which one matched
+        self.0
+            .iter()
+            .filter(|(token, _)| matches(token, given))
+            .map(|(_, permission)| Some(*permission))
+            .fold(None, Option::max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_grants_permissions() {
+        let tokens: Tokens = "# Dashboards\nstatus reader\n\npublish writer\nadmin root\n"
+            .parse()
+            .unwrap();
+        assert_eq!(format!("{:?}", tokens), "[Status, Publish, Admin]");
+        assert_eq!(tokens.permission(Some("reader")), Some(Permission::Status));
+        assert_eq!(tokens.permission(Some("root")), Some(Permission::Admin));
+        assert_eq!(tokens.permission(Some("Root")), None);
+        assert_eq!(tokens.permission(None), None);
+        assert!(Permission::Publish > Permission::Status);
+        assert!("owner root".parse::<Tokens>().is_err());
+        assert!("admin".parse::<Tokens>().is_err());
+    }
+}
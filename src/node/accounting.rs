@@ -0,0 +1,499 @@
+//! Bandwidth per peer and per protocol.
+//!
+//! The transport wraps the multiplexer of every connection in [`Metered`],
+//! which counts the bytes read and written on each substream for the peer
+//! and for the protocol the substream negotiated. The protocol is read off
+//! the multistream-select handshake at the start of the substream: the
+//! first protocol proposed by one side and echoed by the other. Bytes of the
+//! handshake count towards that protocol too, and substreams whose
+//! handshake could not be followed count as `(unknown)`.
+//!
+//! Counts are taken inside the encryption and multiplexing, so unlike
+//! [`super::Node::total_inbound`] they leave out their overhead. Every
+//! [`super::Node`] tick updates the rates, smoothed over about
+//! [`SMOOTHING`] ticks. [`super::NodeHandle::bandwidth_usage`] reports
+//! both, and so does the [`super::metrics`] endpoint.
+//!
+//! At most [`MAX_PEERS`] peers are kept, dropping the one with the least
+//! traffic, and [`MAX_PROTOCOLS`] protocols, the others counting as
+//! `(other)`.
+
+use crate::prelude::*;
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerEvent},
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// Most peers whose traffic is kept.
+pub const MAX_PEERS: usize = 1024;
+
+/// Most protocols counted by name.
+pub const MAX_PROTOCOLS: usize = 64;
+
+/// Ticks rates are smoothed over.
+pub const SMOOTHING: f64 = 5.0;
+
+/// Label of substreams whose protocol is not known.
+pub const UNKNOWN: &str = "(unknown)";
+
+/// Label of the protocols beyond [`MAX_PROTOCOLS`].
+pub const OTHER: &str = "(other)";
+
+/// The multistream-select header, which names no protocol.
+const MULTISTREAM: &str = "/multistream/1.0.0";
+
+/// Most bytes of handshake followed in each direction.
+const MAX_HANDSHAKE: usize = 1024;
+
+/// Most handshake messages followed in each direction.
+const MAX_MESSAGES: usize = 16;
+
+/// Traffic of a peer or protocol.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Usage {
+    /// Bytes received.
+    pub inbound:       u64,
+    /// Bytes sent.
+    pub outbound:      u64,
+    /// Bytes received per second, recently.
+    pub inbound_rate:  f64,
+    /// Bytes sent per second, recently.
+    pub outbound_rate: f64,
+}
+
+impl Usage {
+    fn total(&self) -> u64 {
+        self.inbound + self.outbound
+    }
+}
+
+/// Traffic by peer and by protocol, most first.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Report {
+    pub peers:     Vec<(PeerId, Usage)>,
+    pub protocols: Vec<(String, Usage)>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counter {
+    usage:    Usage,
+    /// Totals at the last tick.
+    inbound:  u64,
+    outbound: u64,
+}
+
+impl Counter {
+    fn add(&mut self, inbound: u64, outbound: u64) {
+        self.usage.inbound += inbound;
+        self.usage.outbound += outbound;
+    }
+
+    fn tick(&mut self, seconds: f64) {
+        let smooth = |rate: f64, sample: f64| rate + (sample - rate) / SMOOTHING;
+        let inbound = (self.usage.inbound - self.inbound) as f64 / seconds;
+        let outbound = (self.usage.outbound - self.outbound) as f64 / seconds;
+        self.usage.inbound_rate = smooth(self.usage.inbound_rate, inbound);
+        self.usage.outbound_rate = smooth(self.usage.outbound_rate, outbound);
+        self.inbound = self.usage.inbound;
+        self.outbound = self.usage.outbound;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    peers:     HashMap<PeerId, Counter>,
+    protocols: HashMap<String, Counter>,
+    ticked:    Option<Instant>,
+}
+
+/// The counts, shared by the connections.
+#[derive(Clone, Debug, Default)]
+pub struct Meter(Arc<Mutex<Counters>>);
+
+impl Meter {
+    /// Count traffic of `peer_id` on `protocol`.
+    pub fn record(&self, peer_id: &PeerId, protocol: &str, inbound: u64, outbound: u64) {
+        let mut counters = self.0.lock().unwrap();
+        if !counters.peers.contains_key(peer_id) && counters.peers.len() >= MAX_PEERS {
+            let least = counters
+                .peers
+                .iter()
+                .min_by_key(|(_, counter)| counter.usage.total())
+                .map(|(peer_id, _)| peer_id.clone());
+            if let Some(least) = least {
+                counters.peers.remove(&least);
+            }
+        }
+        counters
+            .peers
+            .entry(peer_id.clone())
+            .or_default()
+            .add(inbound, outbound);
+        let protocol = if counters.protocols.contains_key(protocol)
+            || counters.protocols.len() < MAX_PROTOCOLS
+        {
+            protocol
+        } else {
+            OTHER
+        };
+        counters
+            .protocols
+            .entry(protocol.to_owned())
+            .or_default()
+            .add(inbound, outbound);
+    }
+
+    /// Update the rates at `now`.
+    pub fn tick(&self, now: Instant) {
+        let mut counters = self.0.lock().unwrap();
+        let seconds = match counters.ticked.replace(now) {
+            Some(last) => now.saturating_duration_since(last).as_secs_f64(),
+            None => return,
+        };
+        if seconds <= 0.0 {
+            return;
+        }
+        for counter in counters.peers.values_mut() {
+            counter.tick(seconds);
+        }
+        for counter in counters.protocols.values_mut() {
+            counter.tick(seconds);
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let counters = self.0.lock().unwrap();
+        let mut peers: Vec<_> = counters
+            .peers
+            .iter()
+            .map(|(peer_id, counter)| (peer_id.clone(), counter.usage))
+            .collect();
+        peers.sort_by(|(a, a_usage), (b, b_usage)| {
+            b_usage
+                .total()
+                .cmp(&a_usage.total())
+                .then_with(|| a.as_bytes().cmp(b.as_bytes()))
+        });
+        let mut protocols: Vec<_> = counters
+            .protocols
+            .iter()
+            .map(|(protocol, counter)| (protocol.clone(), counter.usage))
+            .collect();
+        protocols.sort_by(|(a, a_usage), (b, b_usage)| {
+            b_usage.total().cmp(&a_usage.total()).then_with(|| a.cmp(b))
+        });
+        Report { peers, protocols }
+    }
+}
+
+/// The multistream-select messages seen in one direction of a substream.
+#[derive(Clone, Debug, Default)]
+struct Messages {
+    buffer:   Vec<u8>,
+    messages: Vec<String>,
+    /// Not a handshake we can follow.
+    broken:   bool,
+}
+
+impl Messages {
+    fn feed(&mut self, data: &[u8]) {
+        if self.broken {
+            return;
+        }
+        let room = MAX_HANDSHAKE.saturating_sub(self.buffer.len());
+        self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+        loop {
+            // A message is its length as an unsigned varint, then the
+            // message ending in a newline
+            let mut length = 0_usize;
+            let mut header = None;
+            for (index, byte) in self.buffer.iter().enumerate().take(2) {
+                length |= usize::from(byte & 0x7f) << (7 * index);
+                if byte & 0x80 == 0 {
+                    header = Some(index + 1);
+                    break;
+                }
+            }
+            let header = match header {
+                Some(header) => header,
+                None if self.buffer.len() >= 2 => {
+                    self.broken = true;
+                    return;
+                }
+                None => break,
+            };
+            if header + length > MAX_HANDSHAKE || self.messages.len() >= MAX_MESSAGES {
+                self.broken = true;
+                return;
+            }
+            if self.buffer.len() < header + length {
+                break;
+            }
+            let message: Vec<u8> = self.buffer.drain(..header + length).skip(header).collect();
+            match String::from_utf8(message) {
+                Ok(mut message) if message.ends_with('\n') => {
+                    message.pop();
+                    self.messages.push(message);
+                }
+                _ => {
+                    self.broken = true;
+                    return;
+                }
+            }
+        }
+        if self.buffer.len() >= MAX_HANDSHAKE {
+            self.broken = true;
+        }
+    }
+}
+
+/// Follows the handshake of a substream to learn its protocol, counting the
+/// bytes until then.
+#[derive(Clone, Debug, Default)]
+struct Sniffer {
+    sent:     Messages,
+    received: Messages,
+    protocol: Option<String>,
+    inbound:  u64,
+    outbound: u64,
+}
+
+impl Sniffer {
+    fn count(&mut self, peer_id: &PeerId, meter: &Meter, inbound: &[u8], outbound: &[u8]) {
+        if let Some(protocol) = &self.protocol {
+            meter.record(peer_id, protocol, inbound.len() as u64, outbound.len() as u64);
+            return;
+        }
+        self.inbound += inbound.len() as u64;
+        self.outbound += outbound.len() as u64;
+        self.received.feed(inbound);
+        self.sent.feed(outbound);
+        let agreed = self.sent.messages.iter().find(|message| {
+            message.as_str() != MULTISTREAM
+                && message.as_str() != "na"
+                && self.received.messages.contains(message)
+        });
+        let protocol = match agreed {
+            Some(protocol) => protocol.clone(),
+            None if self.sent.broken || self.received.broken => UNKNOWN.to_owned(),
+            None => return,
+        };
+        meter.record(peer_id, &protocol, self.inbound, self.outbound);
+        self.protocol = Some(protocol);
+        self.sent = Messages::default();
+        self.received = Messages::default();
+    }
+
+    /// Count what is left when the substream closes.
+    fn finish(&mut self, peer_id: &PeerId, meter: &Meter) {
+        if self.protocol.is_none() && self.inbound + self.outbound > 0 {
+            meter.record(peer_id, UNKNOWN, self.inbound, self.outbound);
+        }
+    }
+}
+
+/// A substream of a [`Metered`] connection.
+pub struct MeteredSubstream<S> {
+    inner:   S,
+    sniffer: Sniffer,
+}
+
+impl<S> MeteredSubstream<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            sniffer: Sniffer::default(),
+        }
+    }
+}
+
+/// Multiplexer counting the traffic of its substreams into a [`Meter`].
+pub struct Metered<M> {
+    inner:   M,
+    peer_id: PeerId,
+    meter:   Meter,
+}
+
+impl<M> Metered<M> {
+    pub fn new(inner: M, peer_id: PeerId, meter: Meter) -> Self {
+        Self {
+            inner,
+            peer_id,
+            meter,
+        }
+    }
+}
+
+impl<M: StreamMuxer> StreamMuxer for Metered<M> {
+    type Error = M::Error;
+    type OutboundSubstream = M::OutboundSubstream;
+    type Substream = MeteredSubstream<M::Substream>;
+
+    fn poll_event(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent<Self::Substream>, Self::Error>> {
+        let event = futures::ready!(self.inner.poll_event(cx))?;
+        Poll::Ready(Ok(match event {
+            StreamMuxerEvent::InboundSubstream(substream) => {
+                StreamMuxerEvent::InboundSubstream(MeteredSubstream::new(substream))
+            }
+            StreamMuxerEvent::AddressChange(address) => StreamMuxerEvent::AddressChange(address),
+        }))
+    }
+
+    fn open_outbound(&self) -> Self::OutboundSubstream {
+        self.inner.open_outbound()
+    }
+
+    fn poll_outbound(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Self::OutboundSubstream,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        self.inner
+            .poll_outbound(cx, substream)
+            .map_ok(MeteredSubstream::new)
+    }
+
+    fn destroy_outbound(&self, substream: Self::OutboundSubstream) {
+        self.inner.destroy_outbound(substream);
+    }
+
+    fn read_substream(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let read = futures::ready!(self.inner.read_substream(cx, &mut substream.inner, buf))?;
+        substream
+            .sniffer
+            .count(&self.peer_id, &self.meter, &buf[..read], &[]);
+        Poll::Ready(Ok(read))
+    }
+
+    fn write_substream(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let written = futures::ready!(self.inner.write_substream(cx, &mut substream.inner, buf))?;
+        substream
+            .sniffer
+            .count(&self.peer_id, &self.meter, &[], &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn flush_substream(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_substream(cx, &mut substream.inner)
+    }
+
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.shutdown_substream(cx, &mut substream.inner)
+    }
+
+    fn destroy_substream(&self, mut substream: Self::Substream) {
+        substream.sniffer.finish(&self.peer_id, &self.meter);
+        self.inner.destroy_substream(substream.inner);
+    }
+
+    fn close(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.close(cx)
+    }
+
+    fn flush_all(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_all(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::time::Duration;
+
+    fn message(text: &str) -> Vec<u8> {
+        let mut encoded = vec![text.len() as u8 + 1];
+        encoded.extend_from_slice(text.as_bytes());
+        encoded.push(b'\n');
+        encoded
+    }
+
+    #[test]
+    fn test_learns_protocol_from_handshake() {
+        let (meter, peer) = (Meter::default(), PeerId::random());
+        let mut sniffer = Sniffer::default();
+        let mut proposal = message(MULTISTREAM);
+        proposal.extend(message("/ipfs/kad/1.0.0"));
+        sniffer.count(&peer, &meter, &[], &proposal);
+        sniffer.count(&peer, &meter, &message(MULTISTREAM), &[]);
+        sniffer.count(&peer, &meter, &message("na"), &[]);
+        assert!(meter.report().protocols.is_empty());
+
+        // Another proposal, echoed in two pieces
+        let accepted = message("/meshsub/1.1.0");
+        sniffer.count(&peer, &meter, &[], &accepted);
+        sniffer.count(&peer, &meter, &accepted[..4], &[]);
+        sniffer.count(&peer, &meter, &accepted[4..], &[0; 100]);
+        sniffer.count(&peer, &meter, &[0; 50], &[]);
+        let report = meter.report();
+        assert_eq!(report.protocols.len(), 1);
+        let (protocol, usage) = &report.protocols[0];
+        assert_eq!(protocol, "/meshsub/1.1.0");
+        assert_eq!(usage.inbound, 20 + 4 + 16 + 50);
+        assert_eq!(usage.outbound, 20 + 17 + 16 + 100);
+        assert_eq!(report.peers, vec![(peer.clone(), *usage)]);
+
+        // Garbage is unknown
+        let mut sniffer = Sniffer::default();
+        sniffer.count(&peer, &meter, &[0xff, 0xff, 0xff], &[]);
+        assert_eq!(meter.report().protocols[1].0, UNKNOWN);
+        let mut sniffer = Sniffer::default();
+        sniffer.count(&peer, &meter, &message(MULTISTREAM), &[]);
+        sniffer.finish(&peer, &meter);
+        assert_eq!(meter.report().protocols[1].1.inbound, 3 + 20);
+    }
+
+    #[test]
+    fn test_smooths_rates_and_bounds_peers() {
+        let meter = Meter::default();
+        let peer = PeerId::random();
+        let start = Instant::now();
+        meter.tick(start);
+        for second in 1..=50 {
+            meter.record(&peer, "/ipfs/ping/1.0.0", 1000, 0);
+            meter.tick(start + Duration::from_secs(second));
+        }
+        let usage = meter.report().peers[0].1;
+        assert_eq!(usage.inbound, 50_000);
+        assert!(usage.inbound_rate > 990.0 && usage.inbound_rate <= 1000.0);
+        assert_eq!(usage.outbound_rate, 0.0);
+
+        for _ in 0..MAX_PEERS {
+            meter.record(&PeerId::random(), "/ipfs/ping/1.0.0", 1, 0);
+        }
+        let report = meter.report();
+        assert_eq!(report.peers.len(), MAX_PEERS);
+        assert_eq!(report.peers[0].0, peer);
+        for index in 0..MAX_PROTOCOLS {
+            meter.record(&peer, &format!("/test/{}", index), 1, 0);
+        }
+        assert!(meter.report().protocols.iter().any(|(protocol, _)| protocol == OTHER));
+    }
+}
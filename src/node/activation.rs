@@ -0,0 +1,293 @@
+//! Systemd socket activation.
+//!
+//! When started by a systemd `.socket` unit, the listening sockets are passed
+//! as file descriptors starting at 3, announced through the `LISTEN_PID` and
+//! `LISTEN_FDS` environment variables. The node then accepts connections on
+//! those sockets instead of binding its own, so it can be started on demand
+//! and restarted without refusing connections in between.
+//!
+//! [`Activated`] is a transport that only listens on the inherited sockets.
+//! It sits in front of the regular TCP transport, which handles dialing and
+//! any other listening address.
+
+use crate::prelude::*;
+use libp2p::{
+    core::{
+        multiaddr::Protocol,
+        transport::{ListenerEvent, TransportError},
+    },
+    Multiaddr, Transport,
+};
+use std::{
+    env,
+    io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    os::unix::io::{FromRawFd, IntoRawFd, RawFd},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the TCP listeners passed by systemd, if any. The environment
+/// variables are removed so child processes do not pick them up.
+pub fn listen_fds() -> Vec<TcpListener> {
+    let pid: Option<u32> = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count: Option<RawFd> = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count,
+        (None, None) => return Vec::new(),
+        _ => {
+            warn!("Ignoring socket activation variables meant for another process");
+            return Vec::new();
+        }
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            // SAFETY: systemd passes these descriptors to us and nothing else
+            // in the process owns them.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(address) => {
+                    info!("Inherited listening socket {} (fd {})", address, fd);
+                    Some(listener)
+                }
+                Err(err) => {
+                    warn!("Ignoring inherited fd {}, not a TCP socket: {}", fd, err);
+                    // Leave the descriptor open; it is not ours to close
+                    let _ = listener.into_raw_fd();
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn to_multiaddr(address: SocketAddr) -> Multiaddr {
+    Multiaddr::empty()
+        .with(address.ip().into())
+        .with(Protocol::Tcp(address.port()))
+}
+
+fn to_socket_addr(address: &Multiaddr) -> Option<SocketAddr> {
+    let mut protocols = address.iter();
+    let ip: IpAddr = match protocols.next()? {
+        Protocol::Ip4(ip) => ip.into(),
+        Protocol::Ip6(ip) => ip.into(),
+        _ => return None,
+    };
+    match (protocols.next()?, protocols.next()) {
+        (Protocol::Tcp(port), None) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
+/// Addresses to announce for a listener bound to `address`, expanding
+/// wildcard addresses to those of all interfaces.
+fn announced_addresses(address: SocketAddr) -> Vec<Multiaddr> {
+    if !address.ip().is_unspecified() {
+        return vec![to_multiaddr(address)];
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            interfaces
+                .into_iter()
+                .map(|interface| interface.ip())
+                // A dual-stack IPv6 socket also accepts IPv4
+                .filter(|ip| address.is_ipv6() || ip.is_ipv4())
+                .map(|ip| to_multiaddr(SocketAddr::new(ip, address.port())))
+                .collect()
+        }
+        Err(err) => {
+            warn!("Could not list interface addresses: {}", err);
+            vec![to_multiaddr(address)]
+        }
+    }
+}
+
+/// Connection accepted on an inherited socket, or dialed through a
+/// [`super::proxy`].
+#[derive(Debug)]
+pub struct Stream(tokio::net::TcpStream);
+
+impl From<tokio::net::TcpStream> for Stream {
+    fn from(stream: tokio::net::TcpStream) -> Self {
+        Self(stream)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut read_buf = tokio::io::ReadBuf::new(buf);
+        futures::ready!(tokio::io::AsyncRead::poll_read(
+            Pin::new(&mut self.0),
+            cx,
+            &mut read_buf
+        ))?;
+        Poll::Ready(Ok(read_buf.filled().len()))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+    }
+}
+
+type Upgrade = future::Ready<io::Result<Stream>>;
+type Listener = stream::BoxStream<'static, io::Result<ListenerEvent<Upgrade, io::Error>>>;
+
+/// Transport listening on sockets inherited through socket activation or
+/// [`super::handoff`].
+#[derive(Clone, Debug, Default)]
+pub struct Activated {
+    listeners: Arc<Mutex<Vec<TcpListener>>>,
+    /// Copies of the sockets listened on, to hand off to a successor.
+    listening: Arc<Mutex<Vec<TcpListener>>>,
+}
+
+impl Activated {
+    pub fn new(listeners: Vec<TcpListener>) -> Self {
+        Self {
+            listeners: Arc::new(Mutex::new(listeners)),
+            listening: Arc::default(),
+        }
+    }
+
+    /// Copies of the sockets listened on.
+    pub fn listening(&self) -> Vec<TcpListener> {
+        self.listening
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|listener| listener.try_clone().ok())
+            .collect()
+    }
+
+    /// Listening addresses of the sockets not yet listened on.
+    pub fn addresses(&self) -> Vec<Multiaddr> {
+        self.listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(to_multiaddr)
+            .collect()
+    }
+
+    fn take(&self, address: SocketAddr) -> Option<TcpListener> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let index = listeners
+            .iter()
+            .position(|listener| listener.local_addr().ok() == Some(address))?;
+        let listener = listeners.remove(index);
+        match listener.try_clone() {
+            Ok(copy) => self.listening.lock().unwrap().push(copy),
+            Err(err) => warn!("Socket {} can not be handed off: {}", address, err),
+        }
+        Some(listener)
+    }
+}
+
+fn listen(listener: TcpListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let local = listener.local_addr()?;
+    let announced = announced_addresses(local)
+        .into_iter()
+        .map(|address| Ok(ListenerEvent::NewAddress(address)));
+    let incoming = stream::unfold(listener, move |listener| {
+        async move {
+            let event = match listener.accept().await {
+                Ok((stream, remote)) => {
+                    let local = stream.local_addr().unwrap_or(local);
+                    if let Err(err) = stream.set_nodelay(true) {
+                        debug!("Could not set TCP_NODELAY for {}: {}", remote, err);
+                    }
+                    ListenerEvent::Upgrade {
+                        upgrade:     future::ok(Stream(stream)),
+                        local_addr:  to_multiaddr(local),
+                        remote_addr: to_multiaddr(remote),
+                    }
+                }
+                Err(err) => ListenerEvent::Error(err),
+            };
+            Some((Ok(event), listener))
+        }
+    });
+    Ok(stream::iter(announced).chain(incoming).boxed())
+}
+
+impl Transport for Activated {
+    type Dial = future::Ready<io::Result<Stream>>;
+    type Error = io::Error;
+    type Listener = Listener;
+    type ListenerUpgrade = Upgrade;
+    type Output = Stream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let listener = match to_socket_addr(&address).and_then(|address| self.take(address)) {
+            Some(listener) => listener,
+            None => return Err(TransportError::MultiaddrNotSupported(address)),
+        };
+        listen(listener).map_err(TransportError::Other)
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_accepts_on_inherited_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = to_multiaddr(listener.local_addr().unwrap());
+        let transport = Activated::new(vec![listener]);
+        assert_eq!(transport.addresses(), vec![address.clone()]);
+
+        // Other addresses are left to the regular TCP transport
+        let other = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        assert!(matches!(
+            transport.clone().listen_on(other),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+
+        let mut events = transport.clone().listen_on(address.clone()).unwrap();
+        assert!(transport.addresses().is_empty());
+        match events.next().await {
+            Some(Ok(ListenerEvent::NewAddress(announced))) => assert_eq!(announced, address),
+            _ => panic!("Expected the listening address"),
+        }
+        let _client = std::net::TcpStream::connect(to_socket_addr(&address).unwrap()).unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(ListenerEvent::Upgrade { .. }))
+        ));
+    }
+}
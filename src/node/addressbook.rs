@@ -0,0 +1,267 @@
+//! Peers remembered across restarts.
+//!
+//! With a data directory the node records the peers it connects to in its
+//! [`storage`], with the addresses it dialed them at and those they
+//! announce in identify, and when it last saw them. On start it dials the
+//! peers seen within [`RECENT`], again after [`FIRST_BACKOFF`] if they did
+//! not connect and twice as long after each dial that failed, giving up
+//! after [`DIALS`] dials. The book is written at most every
+//! [`SAVE_INTERVAL`] and on shutdown, and keeps the [`REMEMBERED`] peers
+//! seen last.
+//!
+//! [`storage`]: super::storage
+
+use super::storage::Slot;
+use crate::prelude::*;
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Storage namespace of the address book.
+pub const NAMESPACE: &str = "addressbook";
+
+/// Storage key of the address book, once its file name.
+pub const KEY: &str = "peers.json";
+
+/// Peers seen this long before the start are dialed again.
+pub const RECENT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Wait before the second dial of a peer that did not reconnect.
+pub const FIRST_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Dials of a peer before giving up on reconnecting to it.
+pub const DIALS: u32 = 6;
+
+/// Longest time changes wait to be written.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Peers kept, the ones seen last.
+pub const REMEMBERED: usize = 256;
+
+/// Addresses kept per peer.
+const ADDRESSES: usize = 8;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Entry {
+    addresses: Vec<Multiaddr>,
+    /// Seconds since the Unix epoch.
+    last_seen: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Reconnect {
+    dials: u32,
+    /// No dial before then.
+    retry: Instant,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AddressBook {
+    slot:      Option<Slot>,
+    peers:     HashMap<PeerId, Entry>,
+    reconnect: HashMap<PeerId, Reconnect>,
+    /// When unsaved changes were first made.
+    dirty:     Option<Instant>,
+}
+
+impl AddressBook {
+    /// Load the peers kept in `slot`, or start empty if there are none.
+    /// Changes are written back to `slot`.
+    pub fn load(slot: Slot) -> Result<Self> {
+        let mut peers = HashMap::new();
+        if let Some(json) = slot.read()? {
+            let stored: BTreeMap<String, Entry> = serde_json::from_slice(&json)
+                .with_context(|| format!("Parsing address book from {}", slot))?;
+            for (peer_id, entry) in stored {
+                let peer_id = peer_id
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid peer {} in {}", peer_id, slot))?;
+                peers.insert(peer_id, entry);
+            }
+        }
+        Ok(Self {
+            slot: Some(slot),
+            peers,
+            ..Self::default()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Start reconnecting to the peers seen within [`RECENT`] of `now`, and
+    /// return them with their addresses.
+    pub fn reconnect(&mut self, now: SystemTime, at: Instant) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let since = seconds(now).saturating_sub(RECENT.as_secs());
+        let mut recent = Vec::new();
+        for (peer_id, entry) in &self.peers {
+            if entry.last_seen < since || entry.addresses.is_empty() {
+                continue;
+            }
+            self.reconnect.insert(peer_id.clone(), Reconnect {
+                dials: 0,
+                retry: at,
+            });
+            recent.push((peer_id.clone(), entry.addresses.clone()));
+        }
+        recent
+    }
+
+    /// The peers being reconnected, not `connected`, to dial `now`.
+    pub fn due(&mut self, now: Instant, connected: impl Fn(&PeerId) -> bool) -> Vec<PeerId> {
+        self.reconnect
+            .retain(|peer_id, reconnect| !connected(peer_id) && reconnect.dials < DIALS);
+        let mut due = Vec::new();
+        for (peer_id, reconnect) in &mut self.reconnect {
+            if now < reconnect.retry {
+                continue;
+            }
+            reconnect.retry = now + FIRST_BACKOFF * (1 << reconnect.dials.min(16));
+            reconnect.dials += 1;
+            due.push(peer_id.clone());
+        }
+        due
+    }
+
+    /// Record that `peer_id` is connected `now`, at `addresses` besides
+    /// those already known.
+    pub fn seen(
+        &mut self,
+        peer_id: &PeerId,
+        addresses: impl IntoIterator<Item = Multiaddr>,
+        now: SystemTime,
+        at: Instant,
+    ) {
+        self.reconnect.remove(peer_id);
+        let entry = self.peers.entry(peer_id.clone()).or_insert(Entry {
+            addresses: Vec::new(),
+            last_seen: 0,
+        });
+        let mut changed = entry.last_seen != seconds(now);
+        entry.last_seen = seconds(now);
+        for address in addresses {
+            if !entry.addresses.contains(&address) {
+                // Newest first, so the oldest are dropped
+                entry.addresses.insert(0, address);
+                entry.addresses.truncate(ADDRESSES);
+                changed = true;
+            }
+        }
+        if changed && self.dirty.is_none() {
+            self.dirty = Some(at);
+        }
+    }
+
+    /// Move the addresses of `previous` to `current`, which it rotated to,
+    /// and return them.
+    pub fn migrate(&mut self, previous: &PeerId, current: &PeerId, at: Instant) -> Vec<Multiaddr> {
+        self.reconnect.remove(previous);
+        let moved = match self.peers.remove(previous) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let entry = self.peers.entry(current.clone()).or_insert(Entry {
+            addresses: Vec::new(),
+            last_seen: 0,
+        });
+        entry.last_seen = entry.last_seen.max(moved.last_seen);
+        for address in moved.addresses.iter().rev() {
+            if !entry.addresses.contains(address) {
+                entry.addresses.insert(0, address.clone());
+                entry.addresses.truncate(ADDRESSES);
+            }
+        }
+        if self.dirty.is_none() {
+            self.dirty = Some(at);
+        }
+        moved.addresses
+    }
+
+    /// Write the changes if they waited [`SAVE_INTERVAL`] by `now`.
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        match self.dirty {
+            Some(dirty) if now.saturating_duration_since(dirty) >= SAVE_INTERVAL => self.save(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the changes, if any.
+    pub fn save(&mut self) -> Result<()> {
+        if self.dirty.take().is_none() {
+            return Ok(());
+        }
+        let slot = match &self.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        if self.peers.len() > REMEMBERED {
+            let mut last_seen: Vec<_> = self.peers.values().map(|entry| entry.last_seen).collect();
+            last_seen.sort_unstable_by(|a, b| b.cmp(a));
+            let oldest = last_seen[REMEMBERED - 1];
+            self.peers.retain(|_, entry| entry.last_seen >= oldest);
+        }
+        let peers: BTreeMap<_, _> = self
+            .peers
+            .iter()
+            .map(|(peer_id, entry)| (peer_id.to_base58(), entry))
+            .collect();
+        slot.write(&serde_json::to_vec_pretty(&peers)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reconnects_recent_peers() {
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+        let (recent, old) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let now = SystemTime::now();
+        let at = Instant::now();
+
+        let mut book = AddressBook::load(slot.clone()).unwrap();
+        book.seen(&recent, vec![address.clone()], now, at);
+        book.seen(&old, vec![address.clone()], now - RECENT * 2, at);
+        book.tick(at).unwrap();
+        assert_eq!(slot.read().unwrap(), None);
+        book.tick(at + SAVE_INTERVAL).unwrap();
+
+        let mut book = AddressBook::load(slot).unwrap();
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.reconnect(now, at), vec![(recent.clone(), vec![address])]);
+        assert_eq!(book.due(at, |_| false), vec![recent.clone()]);
+        assert!(book.due(at + FIRST_BACKOFF / 2, |_| false).is_empty());
+        assert_eq!(book.due(at + FIRST_BACKOFF, |_| false).len(), 1);
+        // The second failed dial doubles the wait
+        assert!(book.due(at + FIRST_BACKOFF * 2, |_| false).is_empty());
+        let mut last = at + FIRST_BACKOFF;
+        for dials in 2..DIALS {
+            last += FIRST_BACKOFF * (1 << (dials - 1));
+            assert_eq!(book.due(last, |_| false).len(), 1);
+        }
+        assert!(book.due(last + RECENT, |_| false).is_empty());
+
+        // A peer that rotated its identity keeps its addresses
+        let rotated = PeerId::random();
+        let moved = book.migrate(&recent, &rotated, at);
+        assert_eq!(moved, vec!["/ip4/10.0.0.1/tcp/4001".parse::<Multiaddr>().unwrap()]);
+        assert!(book.migrate(&recent, &rotated, at).is_empty());
+        assert_eq!(book.reconnect(now, at), vec![(rotated, moved)]);
+    }
+}
@@ -0,0 +1,236 @@
+//! Caps on connections.
+//!
+//! `--connection-limits "incoming=256 per_ip=16"` bounds what a busy network
+//! can make the node hold. The swarm refuses connections beyond
+//!
+//! * `incoming` and `outgoing` established connections,
+//! * `pending_dials` dials in progress at once,
+//! * `per_peer` established connections to one peer,
+//!
+//! and the transport refuses connections from an IP address that already
+//! has `per_ip` established, before any handshake. Each takes `none` to
+//! lift it. Refused connections are counted by the limit that refused
+//! them, see [`Rejected`]; dials beyond `pending_dials` fail at once
+//! instead.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::bail;
+use libp2p::{
+    core::{network::ConnectionLimits, ConnectedPoint},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// Established connections peers dialed.
+    pub incoming:      Option<u32>,
+    /// Established connections we dialed.
+    pub outgoing:      Option<u32>,
+    pub pending_dials: Option<u32>,
+    pub per_peer:      Option<u32>,
+    /// Established incoming connections from one IP address.
+    pub per_ip:        Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            incoming:      Some(256),
+            outgoing:      Some(128),
+            pending_dials: Some(32),
+            per_peer:      Some(4),
+            per_ip:        Some(16),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let limit = match value {
+                "none" => None,
+                _ => {
+                    Some(value.parse::<u32>().ok().filter(|limit| *limit > 0).with_context(
+                        || format!("Invalid {} {}, expected a positive count or none", key, value),
+                    )?)
+                }
+            };
+            match key {
+                "incoming" => config.incoming = limit,
+                "outgoing" => config.outgoing = limit,
+                "pending_dials" => config.pending_dials = limit,
+                "per_peer" => config.per_peer = limit,
+                "per_ip" => config.per_ip = limit,
+                _ => bail!("Unknown connection limit {}", key),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// The limits the swarm enforces.
+    pub fn swarm_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_established_incoming(self.incoming)
+            .with_max_established_outgoing(self.outgoing)
+            .with_max_pending_outgoing(self.pending_dials)
+            .with_max_established_per_peer(self.per_peer)
+    }
+}
+
+/// Connections refused so far, by the limit that refused them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Rejected {
+    /// By the `incoming`, `outgoing` or `per_peer` limits of the swarm.
+    pub swarm:  u64,
+    pub per_ip: u64,
+}
+
+/// Why the transport refused a connection.
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+#[error("Address {0} has {1} connections already")]
+pub struct OverLimit(IpAddr, u32);
+
+impl From<OverLimit> for io::Error {
+    fn from(over: OverLimit) -> Self {
+        Self::new(io::ErrorKind::ConnectionRefused, over)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    per_ip:      Option<u32>,
+    /// Established incoming connections by remote IP address.
+    established: HashMap<IpAddr, u32>,
+    rejected:    Rejected,
+}
+
+fn ip(address: &Multiaddr) -> Option<IpAddr> {
+    address.iter().find_map(|protocol| {
+        match protocol {
+            Protocol::Ip4(ip) => Some(IpAddr::from(ip)),
+            Protocol::Ip6(ip) => Some(IpAddr::from(ip)),
+            _ => None,
+        }
+    })
+}
+
+/// The per-IP counts, shared with the transport.
+#[derive(Clone, Debug, Default)]
+pub struct Admission(Arc<Mutex<State>>);
+
+impl Admission {
+    pub fn new(config: &Config) -> Self {
+        let admission = Self::default();
+        admission.0.lock().unwrap().per_ip = config.per_ip;
+        admission
+    }
+
+    /// Check a connection before its handshake. Only incoming connections
+    /// are limited by their address.
+    pub fn check(&self, endpoint: &ConnectedPoint) -> Result<(), OverLimit> {
+        let ip = match endpoint {
+            ConnectedPoint::Listener { send_back_addr, .. } => ip(send_back_addr),
+            ConnectedPoint::Dialer { .. } => None,
+        };
+        let mut state = self.0.lock().unwrap();
+        match (ip, state.per_ip) {
+            (Some(ip), Some(limit)) => {
+                let count = state.established.get(&ip).copied().unwrap_or_default();
+                if count >= limit {
+                    state.rejected.per_ip += 1;
+                    return Err(OverLimit(ip, count));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn established(&self, endpoint: &ConnectedPoint) {
+        if let ConnectedPoint::Listener { send_back_addr, .. } = endpoint {
+            if let Some(ip) = ip(send_back_addr) {
+                *self.0.lock().unwrap().established.entry(ip).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn closed(&self, endpoint: &ConnectedPoint) {
+        if let ConnectedPoint::Listener { send_back_addr, .. } = endpoint {
+            if let Some(ip) = ip(send_back_addr) {
+                let established = &mut self.0.lock().unwrap().established;
+                if let Some(count) = established.get_mut(&ip) {
+                    *count -= 1;
+                    if *count == 0 {
+                        established.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Count a connection the swarm refused.
+    pub fn rejected_by_swarm(&self) {
+        self.0.lock().unwrap().rejected.swarm += 1;
+    }
+
+    pub fn rejected(&self) -> Rejected {
+        self.0.lock().unwrap().rejected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_limits_connections_per_ip() {
+        let config = "per_ip=2 incoming=none".parse::<Config>().unwrap();
+        assert_eq!(config.incoming, None);
+        assert_eq!(config.per_peer, Config::default().per_peer);
+        assert!("per_ip=0".parse::<Config>().is_err());
+        assert!("per_host=1".parse::<Config>().is_err());
+
+        let admission = Admission::new(&config);
+        let from = |address: &str| {
+            ConnectedPoint::Listener {
+                local_addr:     "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+                send_back_addr: address.parse().unwrap(),
+            }
+        };
+        let (first, second) = (from("/ip4/10.0.0.1/tcp/5001"), from("/ip4/10.0.0.1/tcp/5002"));
+        for endpoint in &[&first, &second] {
+            admission.check(endpoint).unwrap();
+            admission.established(endpoint);
+        }
+        assert!(admission.check(&from("/ip4/10.0.0.1/tcp/5003")).is_err());
+        admission.check(&from("/ip4/10.0.0.2/tcp/5001")).unwrap();
+        let dialed = ConnectedPoint::Dialer {
+            address: "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+        };
+        admission.check(&dialed).unwrap();
+        admission.closed(&first);
+        admission.check(&from("/ip4/10.0.0.1/tcp/5003")).unwrap();
+        admission.rejected_by_swarm();
+        assert_eq!(admission.rejected(), Rejected {
+            swarm:  1,
+            per_ip: 1,
+        });
+    }
+}
@@ -0,0 +1,242 @@
+//! Mesh-wide counters and gauges.
+//!
+//! Every node keeps its own contribution to each metric and periodically
+//! publishes it on [`TOPIC`]. Contributions merge as CRDTs, so all nodes
+//! converge on the same value regardless of message order or duplication:
+//!
+//! * Counters are PN-counters: separate grow-only totals of increments and
+//!   decrements per node, merged by taking the maximum.
+//! * Gauges are last-writer-wins registers per node, ordered by hybrid logical
+//!   clock timestamp. A node's gauges expire when we stop hearing from it,
+//!   so the mesh value of "active users" drops when a node goes away.
+//!
+//! The value of a metric is the sum of all nodes' contributions.
+
+use super::hlc::Timestamp;
+use crate::prelude::*;
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Topic on which nodes publish their contributions.
+pub const TOPIC: &str = "/mesh-rs/aggregate/version/1";
+
+/// How often to publish our contributions.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long gauges of a node that went silent keep counting.
+pub const GAUGE_EXPIRY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct PnCounter {
+    pub increments: u64,
+    pub decrements: u64,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, delta: i64) {
+        if delta >= 0 {
+            self.increments = self.increments.saturating_add(delta as u64);
+        } else {
+            self.decrements = self.decrements.saturating_add(delta.unsigned_abs());
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.increments = self.increments.max(other.increments);
+        self.decrements = self.decrements.max(other.decrements);
+    }
+
+    /// The count, saturating at the bounds of `i64`.
+    pub fn value(&self) -> i64 {
+        let value = i128::from(self.increments) - i128::from(self.decrements);
+        value.max(i128::from(i64::MIN)).min(i128::from(i64::MAX)) as i64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Gauge {
+    pub value:     i64,
+    pub timestamp: Timestamp,
+}
+
+impl Gauge {
+    pub fn merge(&mut self, other: &Self) {
+        if other.timestamp > self.timestamp {
+            *self = *other;
+        }
+    }
+}
+
+/// The contributions of one node, as published on [`TOPIC`].
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Contribution {
+    pub counters: HashMap<String, PnCounter>,
+    pub gauges:   HashMap<String, Gauge>,
+}
+
+impl Contribution {
+    pub fn merge(&mut self, other: &Self) {
+        for (name, counter) in &other.counters {
+            self.counters.entry(name.clone()).or_default().merge(counter);
+        }
+        for (name, gauge) in &other.gauges {
+            self.gauges
+                .entry(name.clone())
+                .and_modify(|existing| existing.merge(gauge))
+                .or_insert(*gauge);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Remote {
+    contribution: Contribution,
+    last_seen:    Instant,
+}
+
+#[derive(Debug)]
+pub struct Aggregates {
+    local:        Contribution,
+    remotes:      HashMap<PeerId, Remote>,
+    next_publish: Instant,
+}
+
+impl Aggregates {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            local:        Contribution::default(),
+            remotes:      HashMap::new(),
+            next_publish: now,
+        }
+    }
+
+    /// Add `delta` to our contribution to counter `name`.
+    pub fn increment(&mut self, name: &str, delta: i64) {
+        self.local.counters.entry(name.into()).or_default().add(delta);
+    }
+
+    /// Set our contribution to gauge `name`.
+    pub fn set_gauge(&mut self, name: &str, value: i64, timestamp: Timestamp) {
+        self.local.gauges.insert(name.into(), Gauge { value, timestamp });
+    }
+
+    /// Merge a contribution received from `peer_id`.
+    pub fn receive(&mut self, peer_id: PeerId, contribution: &Contribution, now: Instant) {
+        let remote = self.remotes.entry(peer_id).or_insert_with(|| {
+            Remote {
+                contribution: Contribution::default(),
+                last_seen:    now,
+            }
+        });
+        remote.contribution.merge(contribution);
+        remote.last_seen = now;
+    }
+
+    /// The mesh-wide value of metric `name`, if any node reported it.
+    pub fn value(&self, name: &str, now: Instant) -> Option<i64> {
+        let live = |remote: &&Remote| now.duration_since(remote.last_seen) < GAUGE_EXPIRY;
+        let counters = std::iter::once(&self.local)
+            .chain(self.remotes.values().map(|remote| &remote.contribution))
+            .filter_map(|contribution| contribution.counters.get(name))
+            .map(PnCounter::value);
+        let gauges = std::iter::once(&self.local)
+            .chain(
+                self.remotes
+                    .values()
+                    .filter(live)
+                    .map(|remote| &remote.contribution),
+            )
+            .filter_map(|contribution| contribution.gauges.get(name))
+            .map(|gauge| gauge.value);
+        counters
+            .chain(gauges)
+            .fold(None, |sum, value| Some(sum.unwrap_or(0_i64).saturating_add(value)))
+    }
+
+    /// Names of all metrics reported by any node.
+    pub fn names(&self) -> BTreeSet<&String> {
+        std::iter::once(&self.local)
+            .chain(self.remotes.values().map(|remote| &remote.contribution))
+            .flat_map(|contribution| {
+                contribution
+                    .counters
+                    .keys()
+                    .chain(contribution.gauges.keys())
+            })
+            .collect()
+    }
+
+    /// Returns our contribution if it is due for publishing.
+    pub fn tick(&mut self, now: Instant) -> Option<&Contribution> {
+        if now < self.next_publish {
+            return None;
+        }
+        self.next_publish = now + PUBLISH_INTERVAL;
+        if self.local.counters.is_empty() && self.local.gauges.is_empty() {
+            return None;
+        }
+        Some(&self.local)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn ts(wall_ms: u64) -> Timestamp {
+        Timestamp {
+            wall_ms,
+            logical: 0,
+        }
+    }
+
+    #[test]
+    fn test_counter_merge_is_idempotent() {
+        let now = Instant::now();
+        let remote = PeerId::random();
+        let mut sent = Contribution::default();
+        sent.counters.entry("users".into()).or_default().add(5);
+        sent.counters.entry("users".into()).or_default().add(-2);
+
+        let mut aggregates = Aggregates::new(now);
+        aggregates.increment("users", 1);
+        aggregates.receive(remote.clone(), &sent, now);
+        aggregates.receive(remote, &sent, now);
+        assert_eq!(aggregates.value("users", now), Some(4));
+        assert_eq!(aggregates.value("other", now), None);
+    }
+
+    #[test]
+    fn test_counter_saturates() {
+        let counter = PnCounter {
+            increments: u64::MAX,
+            decrements: 0,
+        };
+        assert_eq!(counter.value(), i64::MAX);
+    }
+
+    #[test]
+    fn test_gauge_last_writer_wins_and_expires() {
+        let now = Instant::now();
+        let remote = PeerId::random();
+        let gauge = |value, wall_ms| {
+            let mut contribution = Contribution::default();
+            contribution.gauges.insert("load".into(), Gauge {
+                value,
+                timestamp: ts(wall_ms),
+            });
+            contribution
+        };
+
+        let mut aggregates = Aggregates::new(now);
+        aggregates.set_gauge("load", 1, ts(1));
+        aggregates.receive(remote.clone(), &gauge(7, 20), now);
+        aggregates.receive(remote, &gauge(3, 10), now);
+        assert_eq!(aggregates.value("load", now), Some(8));
+        assert_eq!(aggregates.value("load", now + GAUGE_EXPIRY), Some(1));
+    }
+}
@@ -0,0 +1,322 @@
+//! HTTP control API.
+//!
+//! Started with `--api "address=127.0.0.1:8080 token-file=/run/secrets/api"`
+//! the node answers JSON requests on that address, for deployments without
+//! a terminal or a data directory to put the control socket in:
+//!
+//! * `GET /health` returns `{"status": "ok", "peers": <connected>}`.
+//! * `GET /peers` returns the connected and pinned peers, as
+//!   `{"peer_id": "...", "connected": true, "pinned": false}`.
+//! * `GET /topics` returns the subscribed topics with their options and,
+//!   for encrypted topics, the generation and fingerprint of the key, as
+//!   `{"topic": "chat", "options": {...}, "key": {"generation": 0,
+//!   "fingerprint": "..."}}`.
+//! * `POST /publish` with `{"topic": "chat", "data": "hello"}` publishes.
+//! * `POST /dial` with `{"address": "/ip4/.../tcp/4001"}` connects.
+//! * `POST /shutdown` shuts the node down gracefully.
+//!
+//! Requests but health checks need an `Authorization: Bearer <token>`
+//! header, with the `token` option or the contents of `token-file`, which
+//! may do anything, or a token of `--access` with the permission the
+//! request needs, see [`access`]. Errors are answered as
+//! `{"error": "..."}`. There is no TLS, so bind to loopback or a private
+//! interface.
+//!
+//! Clients get [`http::TIMEOUT`] to send their request, and at most
+//! [`MAX_CONNECTIONS`] are served at once; others wait to be accepted.
+
+use super::{
+    access::{self, Permission},
+    http, options, NodeHandle, TopicOptions,
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::Multiaddr;
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+/// Largest request body read.
+const MAX_BODY: usize = 1 << 20;
+
+/// Most connections served at once.
+pub const MAX_CONNECTIONS: usize = 64;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    pub address: SocketAddr,
+    /// Bearer tokens clients authorize with.
+    pub tokens:  access::Tokens,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut address = None;
+        let mut token = None;
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "address" => {
+                    address = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid address {}", value))?,
+                    );
+                }
+                "token" => token = Some(value.to_owned()),
+                "token-file" => {
+                    let path = PathBuf::from(value);
+                    let contents = std::fs::read_to_string(&path)
+                        .with_context(|| format!("Reading API token {}", path.display()))?;
+                    token = Some(contents.trim().to_owned());
+                }
+                _ => bail!("Unknown API option {}", key),
+            }
+        }
+        let address = address.ok_or_else(|| anyhow!("The API needs an address"))?;
+        let token = token.ok_or_else(|| anyhow!("The API needs a token or token-file"))?;
+        ensure!(!token.is_empty(), "The API token is empty");
+        let mut tokens = access::Tokens::default();
+        tokens.insert(&token, Permission::Admin);
+        Ok(Self { address, tokens })
+    }
+}
+
+#[derive(Deserialize)]
+struct Publish {
+    topic: String,
+    data:  String,
+}
+
+#[derive(Deserialize)]
+struct Dial {
+    address: Multiaddr,
+}
+
+#[derive(Serialize)]
+struct Peer {
+    peer_id:   String,
+    connected: bool,
+    pinned:    bool,
+}
+
+#[derive(Serialize)]
+struct Topic {
+    topic:   String,
+    options: TopicOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key:     Option<Key>,
+}
+
+/// The current key of an encrypted topic, see [`super::keyring`].
+#[derive(Serialize)]
+struct Key {
+    generation:  u32,
+    fingerprint: String,
+}
+
+/// An answer, with its status line.
+type Response = (&'static str, serde_json::Value);
+
+fn error(status: &'static str, message: impl fmt::Display) -> Response {
+    (status, serde_json::json!({ "error": message.to_string() }))
+}
+
+/// The permission a request for `path` needs, if any.
+fn needs(path: &str) -> Option<Permission> {
+    match path {
+        "/health" => None,
+        "/publish" => Some(Permission::Publish),
+        "/dial" | "/shutdown" => Some(Permission::Admin),
+        _ => Some(Permission::Status),
+    }
+}
+
+/// The bearer token `request` is authorized with.
+fn bearer(request: &http::Request) -> Option<&str> {
+    request.authorization.as_deref()?.strip_prefix("Bearer ")
+}
+
+fn body<'a, T: Deserialize<'a>>(request: &'a http::Request) -> std::result::Result<T, Response> {
+    serde_json::from_slice(&request.body).map_err(|err| error("400 Bad Request", err))
+}
+
+/// Answer an authorized `request` through `handle`.
+async fn route(handle: &mut NodeHandle, request: &http::Request) -> Result<Response> {
+    let ok = |value| ("200 OK", value);
+    Ok(match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => {
+            let peers = handle.peers().await?;
+            ok(serde_json::json!({ "status": "ok", "peers": peers.len() }))
+        }
+        ("GET", "/peers") => {
+            let connected = handle.peers().await?;
+            let pinned = handle.pinned_peers().await?;
+            let mut peers = connected
+                .iter()
+                .map(|peer_id| {
+                    Peer {
+                        peer_id:   peer_id.to_string(),
+                        connected: true,
+                        pinned:    pinned.iter().any(|pin| pin.peer_id == *peer_id),
+                    }
+                })
+                .collect::<Vec<_>>();
+            peers.extend(pinned.iter().filter(|pin| !pin.connected).map(|pin| {
+                Peer {
+                    peer_id:   pin.peer_id.to_string(),
+                    connected: false,
+                    pinned:    true,
+                }
+            }));
+            ok(serde_json::to_value(peers)?)
+        }
+        ("GET", "/topics") => {
+            let mut topics = Vec::new();
+            for (topic, options) in handle.topics().await? {
+                let key = handle
+                    .topic_fingerprint(&topic)
+                    .await?
+                    .map(|(generation, fingerprint)| Key { generation, fingerprint });
+                topics.push(Topic {
+                    topic,
+                    options,
+                    key,
+                });
+            }
+            ok(serde_json::to_value(topics)?)
+        }
+        ("POST", "/publish") => {
+            let publish: Publish = match body(request) {
+                Ok(publish) => publish,
+                Err(response) => return Ok(response),
+            };
+            match handle.publish(&publish.topic, publish.data.as_bytes()).await {
+                Ok(()) => ok(serde_json::json!({})),
+                Err(err) => error("422 Unprocessable Entity", format!("{:#}", err)),
+            }
+        }
+        ("POST", "/dial") => {
+            let dial: Dial = match body(request) {
+                Ok(dial) => dial,
+                Err(response) => return Ok(response),
+            };
+            match handle.dial(dial.address).await {
+                Ok(()) => ok(serde_json::json!({})),
+                Err(err) => error("422 Unprocessable Entity", format!("{:#}", err)),
+            }
+        }
+        ("POST", "/shutdown") => {
+            handle.shutdown().await?;
+            ok(serde_json::json!({}))
+        }
+        (_, "/health" | "/peers" | "/topics" | "/publish" | "/dial" | "/shutdown") => {
+            error("405 Method Not Allowed", "Method not allowed")
+        }
+        _ => error("404 Not Found", "Not found"),
+    })
+}
+
+/// Answer one request on `stream`.
+async fn serve_client(
+    mut stream: TcpStream,
+    mut handle: NodeHandle,
+    tokens: &access::Tokens,
+) -> Result<()> {
+    let (status, value) = match http::read(&mut stream, MAX_BODY).await {
+        Ok(request) => {
+            let permission = tokens.permission(bearer(&request));
+            match (needs(&request.path), permission) {
+                (Some(_), None) => error("401 Unauthorized", "Missing or wrong bearer token"),
+                (Some(needed), Some(permission)) if permission < needed => {
+                    error("403 Forbidden", format!("Needs the {} permission", needed))
+                }
+                _ => {
+                    match route(&mut handle, &request).await {
+                        Ok(response) => response,
+                        Err(err) => error("503 Service Unavailable", format!("{:#}", err)),
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            match err.downcast_ref::<http::Refused>() {
+                Some(refused) => error(refused.status, refused.message),
+                None => return Err(err),
+            }
+        }
+    };
+    http::write_response(&mut stream, status, "application/json", &value.to_string()).await
+}
+
+/// Listen for API requests on the configured address.
+pub async fn bind(config: &Config) -> Result<TcpListener> {
+    TcpListener::bind(config.address)
+        .await
+        .with_context(|| format!("Listening for API requests on {}", config.address))
+}
+
+/// Accept requests on `listener`, answering them through `handle`.
+pub async fn serve(listener: TcpListener, handle: NodeHandle, config: Config) -> Result<()> {
+    info!("Serving the control API on http://{}", listener.local_addr()?);
+    let tokens = Arc::new(config.tokens);
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let permit = connections.clone().acquire_owned().await;
+        let (stream, _) = listener.accept().await.context("Accepting API request")?;
+        let handle = handle.clone();
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, handle, &tokens).await {
+                debug!("API request failed: {:#}", err);
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_reads_authorized_requests() {
+        let config: Config = "address=127.0.0.1:0 token=secret".parse().unwrap();
+        assert_eq!(format!("{:?}", config).contains("secret"), false);
+        assert!("address=127.0.0.1:0".parse::<Config>().is_err());
+
+        let listener = TcpListener::bind(config.address).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let body = r#"{"topic":"chat","data":"hi"}"#;
+            let request = format!(
+                "POST /publish HTTP/1.1\r\nauthorization: Bearer secret\r\nContent-Length: \
+                 {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = http::read(&mut stream, MAX_BODY).await.unwrap();
+        client.await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/publish");
+        let publish: Publish = body(&request).unwrap();
+        assert_eq!((publish.topic.as_str(), publish.data.as_str()), ("chat", "hi"));
+        let permission = config.tokens.permission(bearer(&request));
+        assert_eq!(permission, Some(Permission::Admin));
+        assert_eq!(config.tokens.permission(Some("secreT")), None);
+        assert_eq!(config.tokens.permission(None), None);
+        assert_eq!(needs("/publish"), Some(Permission::Publish));
+        assert_eq!(needs("/health"), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Recent messages from archiver peers for new subscribers.
+//!
+//! A node started with `--archive 1000` takes the archiver role: it keeps
+//! the last 1000 messages of each topic it is subscribed to in memory and
+//! provides them through the [`SERVICE`] service. Subscribing with
+//! `TopicOptions { backfill: Some(n), .. }` asks the nearest connected
+//! archiver for the last `n` messages of the topic and delivers them as
+//! [`Event::Historical`], oldest first, before any live message on the
+//! topic. `backfill_since: Some(time)` asks for the messages from `time`
+//! on instead, or for the last `n` of those together with `backfill`. A
+//! message's time is its timestamp, or when the archiver received it if it
+//! has none. Live messages arriving meanwhile are held until the archiver
+//! answered or the call failed, for instance because no archiver is
+//! connected; subscribe once the mesh is ready to find one.
+//!
+//! With `--persist-archive` and a data directory, archivers keep the
+//! messages in the [`storage`] too, written at most [`SAVE_INTERVAL`] after
+//! they arrived and on shutdown, and answer with them after a restart.
+//!
+//! In builds with the `search` feature, persisted archives are indexed for
+//! full-text [`search`] too.
+//!
+//! Archivers do not keep messages of encrypted topics, since anyone may ask
+//! for them.
+//!
+//! [`Event::Historical`]: crate::node::Event::Historical
+//! [`search`]: super::search
+//! [`storage`]: super::storage
+
+#[cfg(feature = "search")]
+use super::search;
+use super::{behaviour::service, hlc::Timestamp, route, storage::Slot};
+use crate::prelude::*;
+use anyhow::anyhow;
+use futures::{
+    channel::oneshot, future::BoxFuture, stream::FuturesUnordered, task::Context as TaskContext,
+};
+use libp2p::PeerId;
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The service archivers provide.
+pub const SERVICE: &str = "mesh-archive";
+
+/// Storage namespace of the archive.
+pub const NAMESPACE: &str = "archive";
+
+/// Storage key of the archive, once its file name.
+pub const KEY: &str = "archive.cbor";
+
+/// Longest time new messages wait to be written.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub topic:    String,
+    pub count:    usize,
+    /// Only messages from this time on, in milliseconds since the Unix
+    /// epoch. Archivers from before ignore it.
+    #[serde(default)]
+    pub since_ms: Option<u64>,
+}
+
+/// An archived message.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    source:      String,
+    data:        ByteBuf,
+    timestamp:   Option<Timestamp>,
+    /// When the archiver received it, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    received_ms: u64,
+}
+
+pub fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+impl Entry {
+    pub fn decode(&self) -> Result<(PeerId, Vec<u8>, Option<Timestamp>)> {
+        let source = self
+            .source
+            .parse()
+            .map_err(|_| anyhow!("Invalid archived source {}", self.source))?;
+        Ok((source, self.data.to_vec(), self.timestamp))
+    }
+
+    /// Milliseconds since the Unix epoch the message was sent, or received
+    /// if it has no timestamp.
+    fn time_ms(&self) -> u64 {
+        self.timestamp
+            .map_or(self.received_ms, |timestamp| timestamp.wall_ms)
+    }
+}
+
+/// The last messages by topic.
+#[derive(Debug)]
+pub struct Archive {
+    capacity: usize,
+    topics:   HashMap<String, VecDeque<Entry>>,
+    slot:     Option<Slot>,
+    /// When unsaved messages first arrived.
+    dirty:    Option<Instant>,
+    #[cfg(feature = "search")]
+    index:    Option<search::Index>,
+}
+
+impl Archive {
+    /// Keep `capacity` messages per topic.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            topics: HashMap::new(),
+            slot: None,
+            dirty: None,
+            #[cfg(feature = "search")]
+            index: None,
+        }
+    }
+
+    /// Load the messages kept in `slot`, or start empty if there are none,
+    /// keeping `capacity` per topic. Changes are written back to `slot`.
+    pub fn load(slot: Slot, capacity: usize) -> Result<Self> {
+        let mut archive = Self::new(capacity);
+        if let Some(cbor) = slot.read()? {
+            archive.topics = serde_cbor::from_slice(&cbor)
+                .with_context(|| format!("Parsing archive from {}", slot))?;
+            for entries in archive.topics.values_mut() {
+                let excess = entries.len().saturating_sub(capacity);
+                entries.drain(..excess);
+            }
+        }
+        archive.slot = Some(slot);
+        Ok(archive)
+    }
+
+    /// Index the text of the messages in `index` too, replacing what it
+    /// held with the messages kept now.
+    #[cfg(feature = "search")]
+    pub fn set_index(&mut self, mut index: search::Index) -> Result<()> {
+        let (topics, capacity) = (&self.topics, self.capacity);
+        index.rebuild(|index| {
+            for (topic, entries) in topics {
+                for entry in entries {
+                    index.insert(topic, &entry.source, entry.time_ms(), &entry.data, capacity)?;
+                }
+            }
+            Ok(())
+        })?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of messages kept, of all topics.
+    pub fn len(&self) -> usize {
+        self.topics.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn record(
+        &mut self,
+        topic: &str,
+        source: &PeerId,
+        data: &[u8],
+        timestamp: Option<Timestamp>,
+    ) {
+        let entries = self.topics.entry(topic.to_owned()).or_default();
+        entries.push_back(Entry {
+            source: source.to_base58(),
+            data: ByteBuf::from(data.to_vec()),
+            timestamp,
+            received_ms: epoch_ms(SystemTime::now()),
+        });
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+        #[cfg(feature = "search")]
+        {
+            if let (Some(index), Some(entry)) = (&self.index, entries.back()) {
+                let indexed =
+                    index.insert(topic, &entry.source, entry.time_ms(), &entry.data, self.capacity);
+                if let Err(err) = indexed {
+                    warn!("Could not index a message on {}: {:#}", topic, err);
+                }
+            }
+        }
+        self.dirty.get_or_insert_with(Instant::now);
+    }
+
+    /// Forget `topic`, after unsubscribing.
+    pub fn remove(&mut self, topic: &str) {
+        if self.topics.remove(topic).is_some() {
+            self.dirty.get_or_insert_with(Instant::now);
+        }
+        #[cfg(feature = "search")]
+        {
+            if let Some(Err(err)) = self.index.as_ref().map(|index| index.remove(topic)) {
+                warn!("Could not remove {} from the index: {:#}", topic, err);
+            }
+        }
+    }
+
+    /// The last `count` messages on `topic`, oldest first.
+    pub fn latest(&self, topic: &str, count: usize) -> Vec<Entry> {
+        self.topics.get(topic).map_or_else(Vec::new, |entries| {
+            let skip = entries.len().saturating_sub(count);
+            entries.iter().skip(skip).cloned().collect()
+        })
+    }
+
+    /// The last `count` messages on `topic` from `since_ms` on, oldest
+    /// first.
+    pub fn since(&self, topic: &str, count: usize, since_ms: u64) -> Vec<Entry> {
+        self.topics.get(topic).map_or_else(Vec::new, |entries| {
+            let mut since = entries
+                .iter()
+                .rev()
+                .filter(|entry| entry.time_ms() >= since_ms)
+                .take(count)
+                .cloned()
+                .collect::<Vec<_>>();
+            since.reverse();
+            since
+        })
+    }
+
+    /// Answer an encoded [`Request`] with the encoded entries.
+    pub fn answer(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let request: Request = serde_cbor::from_slice(request).context("Decoding request")?;
+        let entries = match request.since_ms {
+            Some(since_ms) => self.since(&request.topic, request.count, since_ms),
+            None => self.latest(&request.topic, request.count),
+        };
+        Ok(serde_cbor::to_vec(&entries)?)
+    }
+
+    /// Write the messages if they waited [`SAVE_INTERVAL`] by `now`.
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        match self.dirty {
+            Some(dirty) if now.saturating_duration_since(dirty) >= SAVE_INTERVAL => self.save(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the changes, if any and if the archive is stored.
+    pub fn save(&mut self) -> Result<()> {
+        let slot = match &self.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        if self.dirty.take().is_none() {
+            return Ok(());
+        }
+        slot.write(&serde_cbor::to_vec(&self.topics)?)
+    }
+}
+
+type Pending = BoxFuture<'static, (String, service::Result)>;
+
+/// Backfills waiting for an archiver, with the live messages held meanwhile.
+#[derive(Default)]
+pub struct Backfills {
+    pending: FuturesUnordered<Pending>,
+    held:    HashMap<String, Vec<route::Message>>,
+}
+
+impl Backfills {
+    /// Hold live messages on `topic` until the call answered by `receiver`
+    /// finished. Returns false if a backfill of `topic` is already running.
+    pub fn start(&mut self, topic: &str, receiver: oneshot::Receiver<service::Result>) -> bool {
+        if self.held.contains_key(topic) {
+            return false;
+        }
+        self.held.insert(topic.to_owned(), Vec::new());
+        let topic = topic.to_owned();
+        self.pending.push(Box::pin(async move {
+            let result = receiver
+                .await
+                .unwrap_or_else(|_| Err(service::Error::Failed("Call dropped".into())));
+            (topic, result)
+        }));
+        true
+    }
+
+    /// Hold `message` if its topic is backfilling, or give it back. Beyond
+    /// `limit` held messages on the topic, further ones are dropped.
+    pub fn hold(&mut self, message: route::Message, limit: usize) -> Option<route::Message> {
+        match self.held.get_mut(&message.topic) {
+            Some(held) if held.len() >= limit => {
+                warn!("Backfill of {} is taking long, dropping message", message.topic);
+                None
+            }
+            Some(held) => {
+                held.push(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    /// Stop holding live messages on `topic` and return them, except those
+    /// the archiver already answered with in `entries`.
+    pub fn finish(&mut self, topic: &str, entries: &[Entry]) -> Vec<route::Message> {
+        let mut held = self.held.remove(topic).unwrap_or_default();
+        held.retain(|message| {
+            !entries.iter().any(|entry| {
+                entry.source == message.source.to_base58()
+                    && entry.timestamp == message.timestamp
+                    && entry.data.as_slice() == message.data.as_slice()
+            })
+        });
+        held
+    }
+}
+
+impl Stream for Backfills {
+    type Item = (String, service::Result);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.pending.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_answers_with_latest() {
+        let mut archive = Archive::new(3);
+        let source = PeerId::random();
+        for i in 0..5_u8 {
+            archive.record("chat", &source, &[i], None);
+        }
+        let request = serde_cbor::to_vec(&Request {
+            topic:    "chat".into(),
+            count:    2,
+            since_ms: None,
+        })
+        .unwrap();
+        let answer = archive.answer(&request).unwrap();
+        let entries: Vec<Entry> = serde_cbor::from_slice(&answer).unwrap();
+        let decoded: Vec<_> = entries.iter().map(|entry| entry.decode().unwrap()).collect();
+        assert_eq!(decoded, vec![
+            (source.clone(), vec![3], None),
+            (source.clone(), vec![4], None)
+        ]);
+        assert_eq!(archive.latest("chat", 10).len(), 3);
+        assert!(archive.latest("news", 10).is_empty());
+
+        let sent = |wall_ms| {
+            Some(Timestamp {
+                wall_ms,
+                logical: 0,
+            })
+        };
+        archive.record("news", &source, b"old", sent(1000));
+        archive.record("news", &source, b"new", sent(2000));
+        archive.record("news", &source, b"newer", sent(3000));
+        let since = archive.since("news", 10, 2000);
+        assert_eq!(since.len(), 2);
+        assert_eq!(since[0].data.as_slice(), b"new");
+        assert_eq!(archive.since("news", 1, 2000)[0].data.as_slice(), b"newer");
+        // Without a timestamp, the time it arrived counts
+        assert_eq!(archive.since("chat", 10, 1000).len(), 3);
+    }
+
+    #[test]
+    fn test_keeps_archive_across_restarts() {
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+        let source = PeerId::random();
+        let mut archive = Archive::load(slot.clone(), 3).unwrap();
+        for i in 0..3_u8 {
+            archive.record("chat", &source, &[i], None);
+        }
+        archive.tick(Instant::now()).unwrap();
+        assert_eq!(slot.read().unwrap(), None);
+        archive.save().unwrap();
+        let loaded = Archive::load(slot, 2).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.latest("chat", 2), archive.latest("chat", 2));
+    }
+
+    #[cfg(feature = "search")]
+    #[test]
+    fn test_indexes_archived_text() {
+        let mut archive = Archive::new(2);
+        let source = PeerId::random();
+        archive.record("chat", &source, b"before the index", None);
+        archive.set_index(search::Index::memory().unwrap()).unwrap();
+        archive.record("chat", &source, b"after the index", None);
+        let found = |archive: &Archive, text| {
+            let index = archive.index.as_ref().unwrap();
+            index.search(&search::Query::new(text)).unwrap().len()
+        };
+        assert_eq!(found(&archive, "index"), 2);
+        archive.record("chat", &source, b"third", None);
+        assert_eq!(found(&archive, "before"), 0);
+        archive.remove("chat");
+        assert_eq!(found(&archive, "third"), 0);
+    }
+}
@@ -0,0 +1,572 @@
+//! JSON lines journal of swarm events.
+//!
+//! Started with `--event-journal "path=events.jsonl"`, the node appends a
+//! [`Record`] for every connection opened, closed or refused, dial, listener
+//! change, discovery, delivered message and error, one JSON object per
+//! line, in a [`rolling`] file with the same options as the log file.
+//! Messages are recorded by the SHA-256 of their payload and its size, not
+//! the payload itself, which the message [`super::journal`] keeps.
+//!
+//! `mesh journal tail <file>` prints the last records and, with `--follow`,
+//! those appended after. `mesh journal replay <files>` prints all records of
+//! the files in order, compressed or not, filtered by event, peer, topic or
+//! time.
+
+use super::{names, rolling, Event};
+use crate::prelude::*;
+use flate2::read::GzDecoder;
+use libp2p::{core::ConnectedPoint, swarm::SwarmEvent, Multiaddr, PeerId};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How often `tail --follow` looks for new records.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Record {
+    /// RFC 3339 time in UTC, with milliseconds.
+    pub time:    String,
+    pub event:   String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer:    Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic:   Option<String>,
+    /// Hex SHA-256 of the payload of a message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256:  Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes:   Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error:   Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail:  Option<String>,
+}
+
+impl Record {
+    pub fn new(event: &str, time: SystemTime) -> Self {
+        Self {
+            time: humantime::format_rfc3339_millis(time).to_string(),
+            event: event.to_owned(),
+            ..Self::default()
+        }
+    }
+
+    fn peer(mut self, peer: &PeerId) -> Self {
+        self.peer = Some(peer.to_base58());
+        self
+    }
+
+    fn address(mut self, address: &Multiaddr) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    fn topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_owned());
+        self
+    }
+
+    fn payload(mut self, data: &[u8]) -> Self {
+        self.sha256 = Some(hex::encode(Sha256::digest(data)));
+        self.bytes = Some(data.len());
+        self
+    }
+
+    fn error(mut self, error: impl std::fmt::Display) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    fn detail(mut self, detail: impl std::fmt::Display) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// The record of a behaviour `event`, if it is worth one.
+    pub fn of_event(event: &Event, time: SystemTime) -> Option<Self> {
+        Some(match event {
+            Event::Message {
+                source,
+                topic,
+                data,
+                direct,
+                signed,
+                ..
+            } => {
+                Self::new("message", time)
+                    .peer(source)
+                    .topic(topic)
+                    .payload(data)
+                    .detail(format!("direct={} signed={}", direct, signed))
+            }
+            Event::DirectMessage { source, data } => {
+                Self::new("direct_message", time).peer(source).payload(data)
+            }
+            Event::Historical {
+                source,
+                topic,
+                data,
+                ..
+            } => {
+                Self::new("historical", time)
+                    .peer(source)
+                    .topic(topic)
+                    .payload(data)
+            }
+            Event::Scheduled {
+                issuer, name, data, ..
+            } => {
+                Self::new("scheduled", time)
+                    .peer(issuer)
+                    .payload(data)
+                    .detail(name)
+            }
+            Event::Announced { peer, kind, agent } => {
+                Self::new("announced", time)
+                    .peer(peer)
+                    .detail(format!("{} {}", kind, agent))
+            }
+            Event::ClockJump { offset_ms } => {
+                Self::new("clock_jump", time).detail(format!("{}ms", offset_ms))
+            }
+            Event::SubsystemDegraded { subsystem, error } => {
+                Self::new("subsystem_degraded", time)
+                    .detail(subsystem)
+                    .error(error)
+            }
+            Event::DuplicateClosed { peer, endpoint } => {
+                Self::new("duplicate_closed", time)
+                    .peer(peer)
+                    .address(endpoint.get_remote_address())
+            }
+            Event::NegotiationFailed {
+                peer,
+                address,
+                reason,
+                error,
+            } => {
+                let mut record = Self::new("negotiation_failed", time)
+                    .detail(reason)
+                    .error(error);
+                if let Some(peer) = peer {
+                    record = record.peer(peer);
+                }
+                if let Some(address) = address {
+                    record = record.address(address);
+                }
+                record
+            }
+            Event::DialFailed { peer, attempts } => {
+                let mut record = Self::new("dial_failed", time);
+                if let Some(peer) = peer {
+                    record = record.peer(peer);
+                }
+                let attempts: Vec<_> = attempts.iter().map(ToString::to_string).collect();
+                record.error(attempts.join("; "))
+            }
+            Event::IdentityMismatch {
+                expected,
+                actual,
+                address,
+                ..
+            } => {
+                Self::new("identity_mismatch", time)
+                    .peer(expected)
+                    .address(address)
+                    .detail(format!("answered by {}", actual))
+            }
+            Event::IdentityRotated { previous, current } => {
+                Self::new("identity_rotated", time)
+                    .peer(previous)
+                    .detail(format!("rotated to {}", current))
+            }
+            Event::PresenceJoined { peer_id, topic, .. } => {
+                Self::new("presence_joined", time).peer(peer_id).topic(topic)
+            }
+            Event::PresenceLeft { peer_id, topic } => {
+                Self::new("presence_left", time).peer(peer_id).topic(topic)
+            }
+            Event::Bootstrapped { peers, elapsed } => {
+                Self::new("bootstrapped", time).detail(format!(
+                    "{} peers in {}",
+                    peers.len(),
+                    humantime::format_duration(*elapsed)
+                ))
+            }
+            Event::PeerDiscovered { peer, address } => {
+                Self::new("peer_discovered", time).peer(peer).address(address)
+            }
+            Event::PeerExpired { peer, address } => {
+                Self::new("peer_expired", time).peer(peer).address(address)
+            }
+            Event::RendezvousDiscovered {
+                namespace, peer, ..
+            } => {
+                Self::new("rendezvous_discovered", time)
+                    .peer(peer)
+                    .detail(namespace)
+            }
+            Event::PeerUnresponsive { peer, failures } => {
+                Self::new("peer_unresponsive", time)
+                    .peer(peer)
+                    .detail(format!("{} failed pings", failures))
+            }
+            Event::ConnectionReaped { peer, reason } => {
+                Self::new("connection_reaped", time).peer(peer).detail(reason)
+            }
+            Event::NatStatusChanged { reachability, .. } => {
+                Self::new("nat_status_changed", time).detail(reachability)
+            }
+            Event::Subscribed { peer, topic } => {
+                Self::new("subscribed", time).peer(peer).topic(topic)
+            }
+            Event::Unsubscribed { peer, topic } => {
+                Self::new("unsubscribed", time).peer(peer).topic(topic)
+            }
+            Event::ListenAddr { address } => Self::new("listen_addr", time).address(address),
+            Event::ListenAddrExpired { address } => {
+                Self::new("listen_addr_expired", time).address(address)
+            }
+            Event::BundleEvicted(_)
+            | Event::StateChanged { .. }
+            | Event::FileTransfer(_)
+            | Event::DhtQuery { .. } => return None,
+        })
+    }
+
+    /// The record of a swarm `event`. Behaviour events and new listen
+    /// addresses are recorded when the node emits them.
+    pub fn of_swarm<E: std::error::Error>(
+        event: &SwarmEvent<Event, E>,
+        time: SystemTime,
+    ) -> Option<Self> {
+        Some(match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+            } => {
+                Self::new("connected", time)
+                    .peer(peer_id)
+                    .address(endpoint.get_remote_address())
+                    .detail(format!("{}, {} open", direction(endpoint), num_established))
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+            } => {
+                let record = Self::new("disconnected", time)
+                    .peer(peer_id)
+                    .address(endpoint.get_remote_address())
+                    .detail(format!("{}, {} open", direction(endpoint), num_established));
+                match cause {
+                    Some(cause) => record.error(cause),
+                    None => record,
+                }
+            }
+            SwarmEvent::IncomingConnection { send_back_addr, .. } => {
+                Self::new("incoming", time).address(send_back_addr)
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => {
+                Self::new("incoming_failed", time)
+                    .address(send_back_addr)
+                    .error(error)
+            }
+            SwarmEvent::BannedPeer { peer_id, endpoint } => {
+                Self::new("banned", time)
+                    .peer(peer_id)
+                    .address(endpoint.get_remote_address())
+            }
+            SwarmEvent::UnreachableAddr {
+                peer_id,
+                address,
+                error,
+                ..
+            } => {
+                Self::new("unreachable", time)
+                    .peer(peer_id)
+                    .address(address)
+                    .error(error)
+            }
+            SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
+                Self::new("unreachable", time).address(address).error(error)
+            }
+            SwarmEvent::ListenerClosed { addresses, reason } => {
+                let addresses: Vec<_> = addresses.iter().map(ToString::to_string).collect();
+                let record = Self::new("listener_closed", time).detail(addresses.join(" "));
+                match reason {
+                    Err(err) => record.error(err),
+                    Ok(()) => record,
+                }
+            }
+            SwarmEvent::ListenerError { error } => Self::new("listener_error", time).error(error),
+            SwarmEvent::Dialing(peer_id) => Self::new("dialing", time).peer(peer_id),
+            _ => return None,
+        })
+    }
+
+    /// The record on one line, with peer names if `--peer-names` asks.
+    pub fn format(&self) -> String {
+        let mut line = format!("{} {}", self.time, self.event);
+        if let Some(peer) = &self.peer {
+            let _ = write!(line, " peer={}", names::rewrite(peer));
+        }
+        for (key, value) in &[
+            ("address", &self.address),
+            ("topic", &self.topic),
+            ("sha256", &self.sha256),
+        ] {
+            if let Some(value) = value {
+                let _ = write!(line, " {}={}", key, value);
+            }
+        }
+        if let Some(bytes) = self.bytes {
+            let _ = write!(line, " bytes={}", bytes);
+        }
+        if let Some(detail) = &self.detail {
+            let _ = write!(line, " ({})", detail);
+        }
+        if let Some(error) = &self.error {
+            let _ = write!(line, " error: {}", error);
+        }
+        line
+    }
+}
+
+fn direction(endpoint: &ConnectedPoint) -> &'static str {
+    match endpoint {
+        ConnectedPoint::Dialer { .. } => "outbound",
+        ConnectedPoint::Listener { .. } => "inbound",
+    }
+}
+
+pub struct Journal {
+    file: rolling::RollingFile,
+}
+
+impl Journal {
+    pub fn open(config: rolling::Config) -> Result<Self> {
+        info!("Journaling swarm events to {}", config.path.display());
+        Ok(Self {
+            file: rolling::RollingFile::open(config)?,
+        })
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line).context("Writing event journal")?;
+        Ok(())
+    }
+
+    /// Append `record`, logging failures: a full disk should not stop the
+    /// node.
+    pub fn record(&mut self, record: &Record) {
+        if let Err(err) = self.append(record) {
+            warn!("Could not journal event: {:#}", err);
+        }
+    }
+}
+
+/// Which records [`replay`] prints.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Filter {
+    pub event: Option<String>,
+    pub peer:  Option<PeerId>,
+    pub topic: Option<String>,
+    /// Records at or after this time.
+    pub since: Option<SystemTime>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        let since = self
+            .since
+            .map(|since| humantime::format_rfc3339_millis(since).to_string());
+        self.event.as_ref().map_or(true, |event| *event == record.event)
+            && self.peer.as_ref().map_or(true, |peer| {
+                record.peer.as_deref() == Some(peer.to_base58().as_str())
+            })
+            && self.topic.as_ref().map_or(true, |topic| record.topic.as_ref() == Some(topic))
+            && since.map_or(true, |since| record.time >= since)
+    }
+}
+
+/// Read the records of `reader`. A truncated last line, as left by a crash or
+/// a write in progress, is ignored.
+pub fn read_from(reader: impl Read) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).context("Invalid event journal record")?);
+    }
+    Ok(records)
+}
+
+/// Read the records of the journal at `path`, decompressing `.gz` files.
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    if matches!(path.extension(), Some(extension) if extension == "gz") {
+        read_from(GzDecoder::new(file))
+    } else {
+        read_from(file)
+    }
+}
+
+/// Print the records `filter` matches in the journals at `paths`, in order.
+/// Pass rotated files oldest first, like `events.jsonl.2.gz events.jsonl.1.gz
+/// events.jsonl`.
+pub fn replay(paths: &[impl AsRef<Path>], filter: &Filter) -> Result<()> {
+    for path in paths {
+        for record in read(path.as_ref())? {
+            if filter.matches(&record) {
+                println!("{}", record.format());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the last `lines` records of the journal at `path`. With `follow`,
+/// keep printing records as they are appended, starting over when the file
+/// is rotated.
+pub fn tail(path: &Path, lines: usize, follow: bool) -> Result<()> {
+    let records = read(path)?;
+    for record in &records[records.len().saturating_sub(lines)..] {
+        println!("{}", record.format());
+    }
+    if !follow {
+        return Ok(());
+    }
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut offset = file.seek(SeekFrom::End(0))?;
+    let mut pending = String::new();
+    loop {
+        thread::sleep(FOLLOW_INTERVAL);
+        let len = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            // Between the rename of a rotation and the new file
+            Err(_) => continue,
+        };
+        if len < offset {
+            file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+            offset = 0;
+            pending.clear();
+        }
+        let read = file.read_to_string(&mut pending)?;
+        offset += read as u64;
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => println!("{}", record.format()),
+                Err(err) => warn!("Skipping invalid record: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::Provenance, test::prelude::assert_eq};
+
+    #[test]
+    fn test_records_events() {
+        let dir = std::env::temp_dir().join(format!("mesh-events-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("events.jsonl");
+        let mut journal = Journal::open(format!("path={}", path.display()).parse().unwrap())
+            .unwrap();
+        let (source, time) = (PeerId::random(), SystemTime::now());
+        let message = Event::Message {
+            source:     source.clone(),
+            topic:      "chat".into(),
+            data:       b"hello".to_vec(),
+            direct:     false,
+            timestamp:  None,
+            provenance: Provenance::default(),
+            signed:     true,
+        };
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let discovered = Event::PeerDiscovered {
+            peer:    source.clone(),
+            address: address.clone(),
+        };
+        for event in &[message, discovered] {
+            journal
+                .append(&Record::of_event(event, time).unwrap())
+                .unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(b"{\"time\":");
+        let records = read_from(&bytes[..]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].event, "message");
+        assert_eq!(records[0].peer, Some(source.to_base58()));
+        assert_eq!(
+            records[0].sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        assert_eq!(records[0].bytes, Some(5));
+        assert_eq!(records[1].address, Some(address.to_string()));
+        assert_eq!(read(&path).unwrap(), records);
+        assert!(records[1]
+            .format()
+            .ends_with(&format!("peer_discovered peer={} address={}", source, address)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_filters_records() {
+        let peer = PeerId::random();
+        let time = humantime::parse_rfc3339("2020-12-01T10:00:00Z").unwrap();
+        let record = Record::new("subscribed", time).peer(&peer).topic("chat");
+        assert_eq!(record.time, "2020-12-01T10:00:00.000Z");
+        assert!(Filter::default().matches(&record));
+        assert!(Filter {
+            event: Some("subscribed".into()),
+            peer: Some(peer),
+            since: Some(time),
+            ..Filter::default()
+        }
+        .matches(&record));
+        assert!(!Filter {
+            topic: Some("news".into()),
+            ..Filter::default()
+        }
+        .matches(&record));
+        assert!(!Filter {
+            since: Some(time + Duration::from_millis(1)),
+            ..Filter::default()
+        }
+        .matches(&record));
+        assert!(!Filter {
+            peer: Some(PeerId::random()),
+            ..Filter::default()
+        }
+        .matches(&record));
+    }
+}
@@ -0,0 +1,187 @@
+//! Learning whether peers can reach us, and at which addresses.
+//!
+//! A node listening on `0.0.0.0` knows its interface addresses, and
+//! identify tells it the addresses peers saw its connections come from, but
+//! behind a NAT neither needs to be reachable. As soon as it has candidate
+//! addresses and every [`PROBE_INTERVAL`] after, the node asks up to
+//! [`PROBE_PEERS`] connected peers to dial it back at its listen and
+//! observed addresses. A peer only dials addresses on the IP address our
+//! connection to it comes from, so nodes can not make peers dial someone
+//! else, and answers with the first address it reached us at.
+//!
+//! Addresses dialed back are confirmed, and the node advertises them ahead
+//! of the others in identify, where peers learn the addresses they put in
+//! their DHT routing table, and in its provider records. Once every peer
+//! that answered a round of probes failed to reach us, the node is private
+//! and stops advertising them. [`NodeHandle::nat_status`] returns the
+//! current [`NatStatus`] and the node emits [`Event::NatStatusChanged`]
+//! when it changes.
+//!
+//! [`NodeHandle::nat_status`]: crate::node::NodeHandle::nat_status
+//! [`Event::NatStatusChanged`]: crate::node::Event::NatStatusChanged
+
+use crate::prelude::*;
+use libp2p::Multiaddr;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Time between rounds of probes.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Peers probed in a round.
+pub const PROBE_PEERS: usize = 3;
+
+/// Whether peers can dial us.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Reachability {
+    /// Not probed yet.
+    #[default]
+    Unknown,
+    Public,
+    Private,
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "unknown",
+            Self::Public => "public",
+            Self::Private => "private",
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct NatStatus {
+    pub reachability: Reachability,
+    /// Addresses peers dialed us at, sorted.
+    pub addresses:    Vec<Multiaddr>,
+}
+
+/// A peer's answer to a probe.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Answer {
+    /// Dialed us back at this address.
+    Reachable(Multiaddr),
+    /// Dialed every address and failed.
+    Unreachable,
+    /// Did not try, being busy or having no address to dial.
+    Refused,
+}
+
+/// Rounds of probes and what they found.
+#[derive(Clone, Debug, Default)]
+pub struct Prober {
+    status:      NatStatus,
+    next_round:  Option<Instant>,
+    /// Answers still expected in this round.
+    pending:     usize,
+    reachable:   bool,
+    unreachable: bool,
+    changed:     bool,
+}
+
+impl Prober {
+    /// Whether to start a round of probes now, with candidate addresses and
+    /// peers to probe.
+    pub fn due(&mut self, now: Instant, candidates: bool, peers: bool) -> bool {
+        if self.pending > 0 || !candidates || !peers {
+            return false;
+        }
+        if matches!(self.next_round, Some(next) if now < next) {
+            return false;
+        }
+        self.next_round = Some(now + PROBE_INTERVAL);
+        true
+    }
+
+    /// A round of `probes` was sent.
+    pub fn sent(&mut self, probes: usize) {
+        self.pending = probes;
+        self.reachable = false;
+        self.unreachable = false;
+    }
+
+    /// The answer to a probe of this round, `None` when the probe failed.
+    pub fn answer(&mut self, answer: Option<Answer>) {
+        match answer {
+            Some(Answer::Reachable(address)) => {
+                self.reachable = true;
+                if let Err(index) = self.status.addresses.binary_search(&address) {
+                    self.status.addresses.insert(index, address);
+                    self.changed = true;
+                }
+                self.set_reachability(Reachability::Public);
+            }
+            Some(Answer::Unreachable) => self.unreachable = true,
+            Some(Answer::Refused) | None => {}
+        }
+        self.pending = self.pending.saturating_sub(1);
+        if self.pending == 0 && self.unreachable && !self.reachable {
+            if !self.status.addresses.is_empty() {
+                self.status.addresses.clear();
+                self.changed = true;
+            }
+            self.set_reachability(Reachability::Private);
+        }
+    }
+
+    fn set_reachability(&mut self, reachability: Reachability) {
+        if self.status.reachability != reachability {
+            self.status.reachability = reachability;
+            self.changed = true;
+        }
+    }
+
+    pub fn status(&self) -> &NatStatus {
+        &self.status
+    }
+
+    /// The status if it changed since the last call.
+    pub fn take_change(&mut self) -> Option<NatStatus> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        Some(self.status.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_rounds_confirm_addresses() {
+        let address: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+        let now = Instant::now();
+        let mut prober = Prober::default();
+        assert!(!prober.due(now, false, true));
+        assert!(prober.due(now, true, true));
+        prober.sent(2);
+        assert!(!prober.due(now + PROBE_INTERVAL, true, true));
+
+        prober.answer(Some(Answer::Unreachable));
+        assert_eq!(prober.take_change(), None);
+        prober.answer(Some(Answer::Reachable(address.clone())));
+        assert_eq!(prober.take_change(), Some(NatStatus {
+            reachability: Reachability::Public,
+            addresses:    vec![address],
+        }));
+        assert_eq!(prober.take_change(), None);
+
+        // A round where no peer reached us
+        assert!(!prober.due(now, true, true));
+        assert!(prober.due(now + PROBE_INTERVAL, true, true));
+        prober.sent(2);
+        prober.answer(None);
+        prober.answer(Some(Answer::Unreachable));
+        assert_eq!(prober.take_change(), Some(NatStatus {
+            reachability: Reachability::Private,
+            addresses:    vec![],
+        }));
+    }
+}
@@ -0,0 +1,128 @@
+//! Recorded performance baselines, for the benchmarks of the `bench` feature.
+//!
+//! A run measures the throughput and latency of each scenario and compares
+//! them with the [`Baselines`] recorded in a JSON file. Throughput below
+//! the baseline, or latency above it, by more than the threshold is a
+//! [`Regression`]. Recording replaces the baselines with the run.
+
+use crate::prelude::*;
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+/// Default allowed slowdown, as a fraction of the baseline.
+pub const THRESHOLD: f64 = 0.25;
+
+/// What one run of a scenario measured.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Sample {
+    /// Messages per second.
+    pub throughput: f64,
+    /// Median time for one message to arrive, in milliseconds.
+    pub latency_ms: f64,
+}
+
+/// A scenario that got slower than its baseline.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Regression {
+    pub scenario: String,
+    pub baseline: Sample,
+    pub sample:   Sample,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.0} messages/s and {:.3} ms, baseline {:.0} messages/s and {:.3} ms",
+            self.scenario,
+            self.sample.throughput,
+            self.sample.latency_ms,
+            self.baseline.throughput,
+            self.baseline.latency_ms
+        )
+    }
+}
+
+/// Samples by scenario.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct Baselines(pub BTreeMap<String, Sample>);
+
+impl Baselines {
+    /// The baselines at `path`, none if there is no file yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let json = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        serde_json::from_slice(&json).with_context(|| format!("Parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, json).with_context(|| format!("Writing {}", temp.display()))?;
+        fs::rename(&temp, path).with_context(|| format!("Writing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The scenarios of `run` slower than their baseline by more than
+    /// `threshold`. Scenarios without a baseline pass.
+    pub fn regressions(&self, run: &Self, threshold: f64) -> Vec<Regression> {
+        run.0
+            .iter()
+            .filter_map(|(scenario, sample)| {
+                let baseline = *self.0.get(scenario)?;
+                let slower = sample.throughput < baseline.throughput * (1.0 - threshold)
+                    || sample.latency_ms > baseline.latency_ms * (1.0 + threshold);
+                slower.then(|| {
+                    Regression {
+                        scenario: scenario.clone(),
+                        baseline,
+                        sample: *sample,
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_finds_regressions() {
+        let sample = |throughput, latency_ms| {
+            Sample {
+                throughput,
+                latency_ms,
+            }
+        };
+        let baselines = Baselines(
+            vec![
+                ("small".to_owned(), sample(1000.0, 1.0)),
+                ("large".to_owned(), sample(100.0, 10.0)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let run = Baselines(
+            vec![
+                ("small".to_owned(), sample(800.0, 1.2)),
+                ("large".to_owned(), sample(100.0, 13.0)),
+                ("new".to_owned(), sample(1.0, 100.0)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let regressions = baselines.regressions(&run, THRESHOLD);
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].scenario, "large");
+
+        let path = std::env::temp_dir().join(format!("mesh-baselines-{}.json", std::process::id()));
+        assert_eq!(Baselines::load(&path).unwrap(), Baselines::default());
+        run.save(&path).unwrap();
+        assert_eq!(Baselines::load(&path).unwrap(), run);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+//! Dial-back probes, see [`crate::node::autonat`].
+//!
+//! A probe asks a peer to dial us at a list of addresses. The peer dials
+//! them one after the other from [`DialBack`] until one connects to us,
+//! and answers with that address. Connections it already dialed to us count
+//! without a new dial, so later rounds do not open more connections.
+
+use super::cbor_codec::CborCodec;
+use crate::{
+    node::autonat::{Answer, NatStatus, Prober, PROBE_PEERS},
+    prelude::*,
+};
+use libp2p::{
+    core::{connection::ConnectionId, multiaddr::Protocol, ConnectedPoint, ProtocolName},
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        NetworkBehaviourEventProcess, PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, NetworkBehaviour, PeerId,
+};
+use rand::seq::SliceRandom;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error, iter,
+    net::IpAddr,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/// How long a probe waits for its answer.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A dial back that has not connected after this long has failed.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Addresses of a probe dialed at most.
+const MAX_ADDRESSES: usize = 8;
+
+/// Probes dialed back at the same time at most, others are refused.
+const MAX_JOBS: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/autonat/version/1"
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Probe {
+    pub addresses: Vec<Multiaddr>,
+}
+
+pub type Codec = CborCodec<Version, Probe, Answer>;
+
+fn ip(address: &Multiaddr) -> Option<IpAddr> {
+    address.iter().find_map(|protocol| {
+        match protocol {
+            Protocol::Ip4(ip) => Some(ip.into()),
+            Protocol::Ip6(ip) => Some(ip.into()),
+            _ => None,
+        }
+    })
+}
+
+/// A probe being dialed back.
+struct Job {
+    peer:      PeerId,
+    addresses: VecDeque<Multiaddr>,
+    /// The address dialed and when.
+    dialing:   Option<(Multiaddr, Instant)>,
+    channel:   ResponseChannel<Answer>,
+}
+
+/// Dials peers back at the addresses they asked for.
+#[derive(Default)]
+pub struct DialBack {
+    /// Remote addresses of our connections, by peer, with whether we dialed.
+    connections: HashMap<PeerId, Vec<(Multiaddr, bool)>>,
+    jobs:        Vec<Job>,
+    dials:       VecDeque<Multiaddr>,
+    answers:     VecDeque<(ResponseChannel<Answer>, Answer)>,
+    waker:       Option<Waker>,
+}
+
+impl DialBack {
+    /// Dial `peer` back at `addresses` on the IP addresses it is connected
+    /// from, or return the channel to refuse the probe.
+    pub fn start(
+        &mut self,
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+        channel: ResponseChannel<Answer>,
+    ) -> Result<(), ResponseChannel<Answer>> {
+        if self.jobs.len() >= MAX_JOBS {
+            return Err(channel);
+        }
+        let connections = self.connections.get(&peer).cloned().unwrap_or_default();
+        let ips = connections
+            .iter()
+            .filter_map(|(address, _)| ip(address))
+            .collect::<HashSet<_>>();
+        let addresses = addresses
+            .into_iter()
+            .map(|mut address| {
+                if let Some(Protocol::P2p(_)) = address.iter().last() {
+                    address.pop();
+                }
+                address
+            })
+            .filter(|address| ip(address).map_or(false, |ip| ips.contains(&ip)))
+            .take(MAX_ADDRESSES)
+            .collect::<VecDeque<_>>();
+        if addresses.is_empty() {
+            return Err(channel);
+        }
+        let dialed = addresses.iter().find(|address| {
+            connections
+                .iter()
+                .any(|(connected, dialer)| *dialer && connected == *address)
+        });
+        if let Some(address) = dialed {
+            trace!("Already connected to {} at {}", peer, address);
+            self.answer(channel, Answer::Reachable(address.clone()));
+            return Ok(());
+        }
+        debug!("Dialing {} back at {} addresses", peer, addresses.len());
+        let mut job = Job {
+            peer,
+            addresses,
+            dialing: None,
+            channel,
+        };
+        if self.next(&mut job) {
+            self.jobs.push(job);
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Dial the next address of `job`, returning false when none is left.
+    fn next(&mut self, job: &mut Job) -> bool {
+        match job.addresses.pop_front() {
+            Some(address) => {
+                self.dials.push_back(address.clone());
+                job.dialing = Some((address, Instant::now()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn answer(&mut self, channel: ResponseChannel<Answer>, answer: Answer) {
+        self.answers.push_back((channel, answer));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Move the jobs dialing `address` on, `connected` to `peer` or not.
+    fn dialed(&mut self, address: &Multiaddr, connected: Option<&PeerId>) {
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let job = &self.jobs[index];
+            if !matches!(&job.dialing, Some((dialing, _)) if dialing == address) {
+                index += 1;
+                continue;
+            }
+            let mut job = self.jobs.swap_remove(index);
+            if connected == Some(&job.peer) {
+                debug!("Dialed {} back at {}", job.peer, address);
+                self.answer(job.channel, Answer::Reachable(address.clone()));
+            } else if self.next(&mut job) {
+                self.jobs.push(job);
+            } else {
+                debug!("Could not dial {} back", job.peer);
+                self.answer(job.channel, Answer::Unreachable);
+            }
+        }
+    }
+
+    /// Fail the dials that took too long.
+    pub fn tick(&mut self, now: Instant) {
+        let expired = self
+            .jobs
+            .iter()
+            .filter_map(|job| job.dialing.as_ref())
+            .filter(|(_, started)| now.saturating_duration_since(*started) >= DIAL_TIMEOUT)
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<_>>();
+        for address in expired {
+            self.dialed(&address, None);
+        }
+    }
+}
+
+impl NetworkBehaviour for DialBack {
+    type OutEvent = (ResponseChannel<Answer>, Answer);
+    type ProtocolsHandler = DummyProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let dialer = endpoint.is_dialer();
+        let address = endpoint.get_remote_address();
+        self.connections
+            .entry(peer_id.clone())
+            .or_default()
+            .push((address.clone(), dialer));
+        if dialer {
+            self.dialed(address, Some(peer_id));
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            let closed = (endpoint.get_remote_address().clone(), endpoint.is_dialer());
+            if let Some(index) = connections.iter().position(|connection| *connection == closed) {
+                connections.swap_remove(index);
+            }
+            if connections.is_empty() {
+                self.connections.remove(peer_id);
+            }
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection: ConnectionId,
+        _event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        _peer_id: Option<&PeerId>,
+        address: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        trace!("Dial back to {} failed: {}", address, error);
+        self.dialed(address, None);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>,
+    > {
+        if let Some(answer) = self.answers.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(answer));
+        }
+        if let Some(address) = self.dials.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll_events")]
+pub struct AutoNat {
+    dial_back:        DialBack,
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    prober: Prober,
+
+    /// Peers without the protocol.
+    #[behaviour(ignore)]
+    unsupported: HashSet<PeerId>,
+}
+
+impl AutoNat {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(PROBE_TIMEOUT);
+        Self {
+            dial_back:        DialBack::default(),
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            prober:           Prober::default(),
+            unsupported:      HashSet::new(),
+        }
+    }
+
+    /// Probe some of `peers` at `candidates` when due. Returns the status if
+    /// it changed since the last tick.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        candidates: Vec<Multiaddr>,
+        peers: impl Iterator<Item = PeerId>,
+    ) -> Option<NatStatus> {
+        self.dial_back.tick(now);
+        let request_response = &self.request_response;
+        self.unsupported
+            .retain(|peer_id| request_response.is_connected(peer_id));
+        let connected = peers
+            .filter(|peer_id| {
+                request_response.is_connected(peer_id) && !self.unsupported.contains(peer_id)
+            })
+            .collect::<Vec<_>>();
+        if self
+            .prober
+            .due(now, !candidates.is_empty(), !connected.is_empty())
+        {
+            let probed = connected
+                .choose_multiple(&mut rand::thread_rng(), PROBE_PEERS)
+                .collect::<Vec<_>>();
+            debug!("Probing reachability of {} addresses", candidates.len());
+            for peer_id in &probed {
+                self.request_response.send_request(peer_id, Probe {
+                    addresses: candidates.clone(),
+                });
+            }
+            self.prober.sent(probed.len());
+        }
+        self.prober.take_change()
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviourEventProcess<(ResponseChannel<Answer>, Answer)> for AutoNat {
+    fn inject_event(&mut self, (channel, answer): (ResponseChannel<Answer>, Answer)) {
+        if self.request_response.send_response(channel, answer).is_err() {
+            debug!("Could not answer reachability probe");
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Probe, Answer>> for AutoNat {
+    fn inject_event(&mut self, event: RequestResponseEvent<Probe, Answer>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                if let Err(channel) = self.dial_back.start(peer.clone(), request.addresses, channel)
+                {
+                    debug!("Refused reachability probe from {}", peer);
+                    let _ = self.request_response.send_response(channel, Answer::Refused);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { response, .. },
+            } => {
+                debug!("Reachability probe answered by {}: {:?}", peer, response);
+                self.prober.answer(Some(response));
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    debug!("Peer {} does not support reachability probes", peer);
+                    self.unsupported.insert(peer);
+                } else {
+                    debug!("Reachability probe to {} failed: {:?}", peer, error);
+                }
+                self.prober.answer(None);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("Reachability probe from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::autonat::{Reachability, PROBE_INTERVAL},
+        test::{prelude::assert_eq, swarm},
+    };
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::{
+        core::upgrade::write_with_len_prefix, request_response::RequestResponseCodec, Swarm,
+    };
+
+    #[test]
+    fn test_refuses_malformed_messages() {
+        let probe = Probe {
+            addresses: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+        };
+        let mut bytes = Vec::new();
+        block_on(Codec::default().write_request(&Version(), &mut bytes, probe.clone())).unwrap();
+        let read = |bytes: &[u8]| {
+            block_on(Codec::default().read_request(&Version(), &mut Cursor::new(bytes)))
+        };
+        assert_eq!(read(&bytes).unwrap(), probe);
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+
+        // Addresses that are not multiaddrs
+        let mut invalid = Vec::new();
+        let addresses = serde_cbor::to_vec(&serde_cbor::Value::Map(
+            iter::once((
+                serde_cbor::Value::Text("addresses".into()),
+                serde_cbor::Value::Array(vec![serde_cbor::Value::Bytes(vec![0xff; 4])]),
+            ))
+            .collect(),
+        ))
+        .unwrap();
+        block_on(write_with_len_prefix(&mut invalid, addresses)).unwrap();
+        assert_eq!(
+            read(&invalid).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_confirms_addresses_dialed_back() {
+        let (_, mut alice) = swarm::new(AutoNat::new());
+        let (bob_id, mut bob) = swarm::new(AutoNat::new());
+        let alice_address = swarm::listen(&mut alice).await;
+        let bob_address = swarm::listen(&mut bob).await;
+        Swarm::dial_addr(&mut alice, bob_address).unwrap();
+
+        // Bob only dials the IP address Alice is connected from
+        let elsewhere: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let candidates = vec![elsewhere.clone(), alice_address.clone()];
+        let now = Instant::now();
+        let status = swarm::run_until(&mut alice, &mut bob, |alice, _| {
+            alice.tick(now, candidates.clone(), iter::once(bob_id.clone()))
+        })
+        .await;
+        assert_eq!(status, NatStatus {
+            reachability: Reachability::Public,
+            addresses:    vec![alice_address],
+        });
+
+        // A refused probe does not change the status
+        let later = now + PROBE_INTERVAL;
+        let change = alice.tick(later, vec![elsewhere], iter::once(bob_id.clone()));
+        assert_eq!(change, None);
+        // Another round is due only once the probe was answered
+        let next_round = later + PROBE_INTERVAL;
+        assert!(!alice.prober.due(next_round, true, true));
+        swarm::run_until(&mut alice, &mut bob, |alice, _| {
+            alice.prober.due(next_round, true, true).then(|| ())
+        })
+        .await;
+        assert_eq!(alice.prober.take_change(), None);
+        assert_eq!(alice.prober.status().reachability, Reachability::Public);
+    }
+}
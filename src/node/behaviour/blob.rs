@@ -0,0 +1,482 @@
+//! Deduplication of large payloads.
+//!
+//! Payloads of at least [`THRESHOLD`] bytes are stored once by their SHA-256
+//! hash. When the same payload is published again, peers known to have the
+//! blob only receive a reference. A peer is known to have a blob if it sent it
+//! to us, received it from us, or fetched it from us. Receivers of a reference
+//! to a blob they do not have fetch it from the sender over
+//! `/mesh-rs/blob/version/1` before the message is delivered. Blobs are
+//! fetched in chunks of at most [`CHUNK_SIZE`] bytes, so no stream carries
+//! more, and a fetch whose stream was reset goes on from the last chunk
+//! received, see [`super::reopen`]. Peers that do not send chunks answer
+//! with the whole blob.
+//!
+//! Blobs are reference counted by the messages that carried them. When the
+//! store exceeds its capacity, the least referenced blobs are evicted first.
+
+use super::{
+    cbor_codec::CborCodec,
+    reopen::Reopens,
+    Event,
+};
+use crate::prelude::*;
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter, mem,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Payloads at least this large are deduplicated.
+pub const THRESHOLD: usize = 16 * 1024;
+
+/// Default capacity of the blob store in bytes.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// Largest chunk of a blob sent at once.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// SHA-256 hash identifying a blob.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct BlobId(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+impl BlobId {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).to_vec())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id:     BlobId,
+    /// Ask for the chunk at this offset, instead of the whole blob.
+    #[serde(default)]
+    pub offset: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Blob(#[serde(with = "serde_bytes")] Vec<u8>),
+    NotFound,
+    Chunk {
+        offset: u64,
+        /// Size of the whole blob.
+        total:  u64,
+        #[serde(with = "serde_bytes")]
+        data:   Vec<u8>,
+    },
+}
+
+/// The chunk of `blob` at `offset`.
+fn chunk(blob: &[u8], offset: u64) -> Response {
+    let start = (offset.min(blob.len() as u64)) as usize;
+    let end = (start + CHUNK_SIZE).min(blob.len());
+    Response::Chunk {
+        offset: start as u64,
+        total:  blob.len() as u64,
+        data:   blob[start..end].to_vec(),
+    }
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/blob/version/1"
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    data:    Arc<Vec<u8>>,
+    refs:    usize,
+    holders: HashSet<PeerId>,
+    /// Order of storing, so older blobs are evicted first.
+    stored:  u64,
+}
+
+/// Content addressed, reference counted blob storage.
+#[derive(Debug)]
+pub struct BlobStore {
+    blobs:    HashMap<BlobId, Entry>,
+    size:     usize,
+    capacity: usize,
+    stored:   u64,
+}
+
+impl BlobStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blobs: HashMap::new(),
+            size: 0,
+            capacity,
+            stored: 0,
+        }
+    }
+
+    /// Keep at most `capacity` bytes, evicting blobs if there are more.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(&BlobId(Vec::new()));
+    }
+
+    pub fn get(&self, id: &BlobId) -> Option<Arc<Vec<u8>>> {
+        self.blobs.get(id).map(|entry| entry.data.clone())
+    }
+
+    /// Add a reference to `data`, storing it if it is new.
+    pub fn retain(&mut self, id: BlobId, data: &[u8]) {
+        match self.blobs.get_mut(&id) {
+            Some(entry) => entry.refs += 1,
+            None => {
+                self.size += data.len();
+                self.stored += 1;
+                self.blobs.insert(id.clone(), Entry {
+                    data:    Arc::new(data.to_vec()),
+                    refs:    1,
+                    holders: HashSet::new(),
+                    stored:  self.stored,
+                });
+                self.evict(&id);
+            }
+        }
+    }
+
+    /// Drop a reference. Blobs without references are removed.
+    pub fn release(&mut self, id: &BlobId) {
+        if let Some(entry) = self.blobs.get_mut(id) {
+            entry.refs -= 1;
+            if entry.refs == 0 {
+                self.remove(id);
+            }
+        }
+    }
+
+    /// Record that `peer_id` has blob `id`.
+    pub fn add_holder(&mut self, id: &BlobId, peer_id: PeerId) {
+        if let Some(entry) = self.blobs.get_mut(id) {
+            entry.holders.insert(peer_id);
+        }
+    }
+
+    pub fn has_holder(&self, id: &BlobId, peer_id: &PeerId) -> bool {
+        self.blobs
+            .get(id)
+            .map_or(false, |entry| entry.holders.contains(peer_id))
+    }
+
+    fn remove(&mut self, id: &BlobId) {
+        if let Some(entry) = self.blobs.remove(id) {
+            self.size -= entry.data.len();
+        }
+    }
+
+    /// Evict the least referenced blobs, oldest first, except `keep`, until
+    /// within capacity.
+    fn evict(&mut self, keep: &BlobId) {
+        while self.size > self.capacity {
+            let victim = self
+                .blobs
+                .iter()
+                .filter(|(id, _)| *id != keep)
+                .min_by_key(|(_, entry)| (entry.refs, entry.stored))
+                .map(|(id, _)| id.clone());
+            match victim {
+                Some(victim) => self.remove(&victim),
+                None => break,
+            }
+        }
+    }
+}
+
+/// A blob being fetched, for a message.
+#[derive(Debug)]
+struct Fetch {
+    id:       BlobId,
+    event:    Event,
+    /// The chunks received so far.
+    received: Vec<u8>,
+    reopens:  Reopens,
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
+pub struct Blobs {
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    store: BlobStore,
+
+    /// Messages waiting for their blob to be fetched.
+    #[behaviour(ignore)]
+    pending: HashMap<RequestId, Fetch>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+}
+
+impl Blobs {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(60));
+        Self {
+            request_response: RequestResponse::new(
+                Codec::new(DEFAULT_CAPACITY),
+                protocols,
+                config,
+            ),
+            store:            BlobStore::new(DEFAULT_CAPACITY),
+            pending:          HashMap::new(),
+            events:           VecDeque::new(),
+        }
+    }
+
+    pub fn store(&mut self) -> &mut BlobStore {
+        &mut self.store
+    }
+
+    /// Fetch blob `id` from `peer_id`, then emit `event` with the blob as its
+    /// data.
+    pub fn fetch(&mut self, peer_id: &PeerId, id: BlobId, event: Event) {
+        self.request(peer_id, Fetch {
+            id,
+            event,
+            received: Vec::new(),
+            reopens: Reopens::default(),
+        });
+    }
+
+    /// Ask `peer_id` for the next chunk of `fetch`.
+    fn request(&mut self, peer_id: &PeerId, fetch: Fetch) {
+        let request = Request {
+            id:     fetch.id.clone(),
+            offset: Some(fetch.received.len() as u64),
+        };
+        let request_id = self.request_response.send_request(peer_id, request);
+        self.pending.insert(request_id, fetch);
+    }
+
+    /// Emit the message of `fetch` with `data`, if it is the blob.
+    fn complete(&mut self, peer: PeerId, fetch: Fetch, data: Vec<u8>) {
+        let Fetch { id, mut event, .. } = fetch;
+        if BlobId::of(&data) != id {
+            warn!("Peer {} sent a blob with the wrong hash", peer);
+            return;
+        }
+        self.store.retain(id.clone(), &data);
+        self.store.add_holder(&id, peer);
+        if let Event::Message { data: event_data, .. } = &mut event {
+            *event_data = data;
+        }
+        self.events.push_back(event);
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Blobs {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                let response = match (self.store.get(&request.id), request.offset) {
+                    (Some(data), Some(offset)) => {
+                        let response = chunk(&data, offset);
+                        if matches!(&response, Response::Chunk { offset, data: chunk, .. }
+                            if *offset as usize + chunk.len() == data.len())
+                        {
+                            self.store.add_holder(&request.id, peer.clone());
+                        }
+                        response
+                    }
+                    (Some(data), None) => {
+                        self.store.add_holder(&request.id, peer.clone());
+                        Response::Blob(data.to_vec())
+                    }
+                    (None, _) => Response::NotFound,
+                };
+                if self
+                    .request_response
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Could not send blob to {}", peer);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                },
+            } => {
+                let mut fetch = match self.pending.remove(&request_id) {
+                    Some(fetch) => fetch,
+                    None => return,
+                };
+                match response {
+                    Response::Blob(data) => self.complete(peer, fetch, data),
+                    Response::Chunk {
+                        offset,
+                        total,
+                        data,
+                    } => {
+                        let received = fetch.received.len() as u64;
+                        if offset != received || (data.is_empty() && received < total) {
+                            warn!("Peer {} sent chunk at {} instead of {}", peer, offset, received);
+                            return;
+                        }
+                        fetch.received.extend(data);
+                        if fetch.received.len() as u64 >= total {
+                            let data = mem::take(&mut fetch.received);
+                            self.complete(peer, fetch, data);
+                        } else {
+                            self.request(&peer, fetch);
+                        }
+                    }
+                    Response::NotFound => warn!("Peer {} no longer has blob {:?}", peer, fetch.id),
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                let mut fetch = match self.pending.remove(&request_id) {
+                    Some(fetch) => fetch,
+                    None => return,
+                };
+                if fetch.reopens.reopen(&error) {
+                    debug!(
+                        "Blob stream from {} reset, resuming at {} bytes",
+                        peer,
+                        fetch.received.len()
+                    );
+                    self.request(&peer, fetch);
+                    return;
+                }
+                warn!("Fetching blob from {} failed: {:?}", peer, error);
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    let event = Event::unsupported(peer, Version().protocol_name());
+                    self.events.push_back(event);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("Serving blob to {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, *};
+
+    #[test]
+    fn test_reference_counting() {
+        let mut store = BlobStore::new(100);
+        let data = vec![1_u8; 40];
+        let id = BlobId::of(&data);
+        store.retain(id.clone(), &data);
+        store.retain(id.clone(), &data);
+        store.release(&id);
+        assert_eq!(store.get(&id).as_deref(), Some(&data));
+        store.release(&id);
+        assert_eq!(store.get(&id), None);
+    }
+
+    #[test]
+    fn test_evicts_least_referenced() {
+        let mut store = BlobStore::new(100);
+        let (a, b, c) = (vec![1_u8; 40], vec![2_u8; 40], vec![3_u8; 40]);
+        store.retain(BlobId::of(&a), &a);
+        store.retain(BlobId::of(&a), &a);
+        store.retain(BlobId::of(&b), &b);
+        store.retain(BlobId::of(&c), &c);
+        assert!(store.get(&BlobId::of(&a)).is_some());
+        assert!(store.get(&BlobId::of(&b)).is_none());
+        assert!(store.get(&BlobId::of(&c)).is_some());
+        // Of equally referenced blobs the oldest goes first
+        let d = vec![4_u8; 40];
+        store.release(&BlobId::of(&a));
+        store.retain(BlobId::of(&d), &d);
+        assert!(store.get(&BlobId::of(&a)).is_none());
+        assert!(store.get(&BlobId::of(&c)).is_some());
+        store.set_capacity(40);
+        assert!(store.get(&BlobId::of(&c)).is_none());
+        assert!(store.get(&BlobId::of(&d)).is_some());
+    }
+
+    #[test]
+    fn test_chunks_cover_blob() {
+        let blob = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        let mut received = Vec::new();
+        while let Response::Chunk { offset, total, data } = chunk(&blob, received.len() as u64) {
+            assert_eq!((offset, total), (received.len() as u64, blob.len() as u64));
+            assert!(data.len() <= CHUNK_SIZE);
+            if data.is_empty() {
+                break;
+            }
+            received.extend(data);
+        }
+        assert_eq!(received, blob);
+    }
+
+    proptest! {
+        #[test]
+        fn test_each_payload_is_stored_once(ops: Vec<(bool, u8)>) {
+            // Retain or release one of a few payloads and compare with a
+            // model of the reference counts.
+            let payloads = (0..4_u8).map(|i| vec![i; 10 + usize::from(i)]).collect::<Vec<_>>();
+            let mut store = BlobStore::new(usize::MAX);
+            let mut refs = [0_usize; 4];
+            for (retain, index) in ops {
+                let index = usize::from(index % 4);
+                let id = BlobId::of(&payloads[index]);
+                if retain {
+                    store.retain(id, &payloads[index]);
+                    refs[index] += 1;
+                } else if refs[index] > 0 {
+                    store.release(&id);
+                    refs[index] -= 1;
+                }
+                let live = (0..4).filter(|&i| refs[i] > 0).collect::<Vec<_>>();
+                prop_assert_eq!(store.blobs.len(), live.len());
+                prop_assert_eq!(store.size, live.iter().map(|&i| payloads[i].len()).sum::<usize>());
+                for (i, payload) in payloads.iter().enumerate() {
+                    let stored = store.get(&BlobId::of(payload));
+                    prop_assert_eq!(stored.as_deref(), if refs[i] > 0 { Some(payload) } else { None });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Generic `RequestResponseCodec` for Serde types using length-prefixed CBOR.
+//!
+//! Unlike the raw [`JsonCodec`][json] used by `OrderSync`, every message is
+//! prefixed with an unsigned varint length, so framing is trivial and the
+//! maximum message size can be enforced before allocating.
+//!
+//! [json]: super::order_sync
+//!
+//! Use this for new protocols.
+
+use crate::prelude::*;
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_with_len_prefix, ReadOneError},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+use std::{
+    io::{Error, ErrorKind},
+    marker::PhantomData,
+};
+
+/// Default maximum size of a single encoded message.
+pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct CborCodec<Protocol, Request, Response>
+where
+    Protocol: Clone + Send + Sync + ProtocolName,
+    Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+    Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    max_size: usize,
+    protocol: PhantomData<Protocol>,
+    request:  PhantomData<Request>,
+    response: PhantomData<Response>,
+}
+
+impl<Protocol, Request, Response> CborCodec<Protocol, Request, Response>
+where
+    Protocol: Clone + Send + Sync + ProtocolName,
+    Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+    Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    pub const fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            protocol: PhantomData,
+            request: PhantomData,
+            response: PhantomData,
+        }
+    }
+}
+
+impl<Protocol, Request, Response> Default for CborCodec<Protocol, Request, Response>
+where
+    Protocol: Clone + Send + Sync + ProtocolName,
+    Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+    Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SIZE)
+    }
+}
+
+/// Encode a value as CBOR.
+pub fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_cbor::to_vec(value).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Decode a value from CBOR.
+pub fn decode<T: for<'a> Deserialize<'a>>(bytes: &[u8]) -> io::Result<T> {
+    serde_cbor::from_slice(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+async fn read_framed<R, T>(io: &mut R, max_size: usize) -> io::Result<T>
+where
+    R: AsyncRead + Unpin + Send,
+    T: for<'a> Deserialize<'a>,
+{
+    let bytes = read_one(io, max_size).await.map_err(|err| {
+        match err {
+            ReadOneError::Io(err) => err,
+            err @ ReadOneError::TooLarge { .. } => Error::new(ErrorKind::InvalidData, err),
+        }
+    })?;
+    decode(&bytes)
+}
+
+#[async_trait]
+impl<Protocol, Request, Response> RequestResponseCodec for CborCodec<Protocol, Request, Response>
+where
+    Protocol: Clone + Send + Sync + ProtocolName,
+    Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+    Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    type Protocol = Protocol;
+    type Request = Request;
+    type Response = Response;
+
+    async fn read_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_framed(io, self.max_size).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_framed(io, self.max_size).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_with_len_prefix(io, encode(&req)?).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_with_len_prefix(io, encode(&res)?).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::*;
+    use futures::executor::block_on;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// Reader that hands out its bytes in the given chunk sizes, like a
+    /// stream delivering a message over several packets.
+    struct Chunked {
+        bytes:  Vec<u8>,
+        chunks: Vec<usize>,
+    }
+
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let chunk = self.chunks.pop().unwrap_or(usize::MAX).max(1);
+            let len = chunk.min(buf.len()).min(self.bytes.len());
+            buf[..len].copy_from_slice(&self.bytes[..len]);
+            self.bytes.drain(..len);
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        block_on(write_with_len_prefix(
+            &mut bytes,
+            encode(&serde_bytes::Bytes::new(payload)).unwrap(),
+        ))
+        .unwrap();
+        bytes
+    }
+
+    proptest! {
+        #[test]
+        fn test_reassembles_chunked_messages(payload: Vec<u8>, chunks: Vec<usize>) {
+            let mut reader = Chunked { bytes: framed(&payload), chunks };
+            let read: serde_bytes::ByteBuf =
+                block_on(read_framed(&mut reader, DEFAULT_MAX_SIZE)).unwrap();
+            prop_assert_eq!(read.into_vec(), payload);
+        }
+
+        #[test]
+        fn test_rejects_oversized(payload in proptest::collection::vec(any::<u8>(), 65..200)) {
+            let mut reader = Chunked { bytes: framed(&payload), chunks: vec![] };
+            let read = block_on(read_framed::<_, serde_bytes::ByteBuf>(&mut reader, 64));
+            prop_assert_eq!(read.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
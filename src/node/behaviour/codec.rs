@@ -0,0 +1,262 @@
+//! Compression of message payloads.
+//!
+//! Built with the `zstd` or `lz4` features the node compresses envelope
+//! payloads of at least `threshold` bytes, and appends the codecs it decodes
+//! to the agent version it sends in identify, as in
+//! `mesh-rs/0.1.0 codecs/zstd,lz4`. A payload is compressed with the first
+//! codec of the config that every recipient advertised: the subscribers of
+//! the topic we know of, or the peers of a direct message. Payloads that do
+//! not shrink are sent as they are. Tune it with
+//! `--compression "threshold=1KiB codecs=zstd,lz4"`, or turn it off with
+//! `codecs=none`.
+//!
+//! The codec is part of the signed envelope, so the signature covers the
+//! compressed payload. Gossip forwards messages beyond the peers we know, so
+//! topics mixing nodes built with and without a codec should leave it off.
+
+use crate::{
+    node::{behaviour::discovery::AGENT_VERSION, options},
+    prelude::*,
+};
+use anyhow::{anyhow, bail};
+use std::{fmt, str::FromStr};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// Most bytes a compressed payload may expand to.
+pub const MAX_DECOMPRESSED: usize = 16 << 20;
+
+/// Marks the codecs in the agent version.
+const ADVERTISEMENT: &str = "codecs/";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    /// Whether this build can compress and decompress with it.
+    pub fn is_supported(self) -> bool {
+        supported().contains(&self)
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Ok(zstd::stream::encode_all(data, 0)?),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[allow(unreachable_patterns)]
+            codec => bail!("Built without {} to compress {} bytes", codec, data.len()),
+        }
+    }
+
+    /// Decompress `data`, refusing payloads over [`MAX_DECOMPRESSED`].
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                use std::io::Read;
+                let mut plain = Vec::new();
+                zstd::stream::read::Decoder::new(data)?
+                    .take(MAX_DECOMPRESSED as u64 + 1)
+                    .read_to_end(&mut plain)
+                    .context("Decompressing")?;
+                anyhow::ensure!(
+                    plain.len() <= MAX_DECOMPRESSED,
+                    "Decompressed payload over {}",
+                    MAX_DECOMPRESSED.bytes()
+                );
+                Ok(plain)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                anyhow::ensure!(data.len() >= 4, "Truncated lz4 payload");
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                anyhow::ensure!(
+                    size <= MAX_DECOMPRESSED,
+                    "Decompressed payload over {}",
+                    MAX_DECOMPRESSED.bytes()
+                );
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|err| anyhow!("Decompressing: {:?}", err))
+            }
+            #[allow(unreachable_patterns)]
+            codec => bail!("Built without {} to decompress {} bytes", codec, data.len()),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => bail!("Unknown codec {}, expected zstd or lz4", s),
+        }
+    }
+}
+
+/// The codecs of this build, best first.
+pub fn supported() -> &'static [Codec] {
+    &[
+        #[cfg(feature = "zstd")]
+        Codec::Zstd,
+        #[cfg(feature = "lz4")]
+        Codec::Lz4,
+    ]
+}
+
+/// Our agent version, advertising the codecs we decode.
+pub fn agent_version() -> String {
+    if supported().is_empty() {
+        return AGENT_VERSION.to_owned();
+    }
+    let codecs: Vec<_> = supported().iter().map(|codec| codec.name()).collect();
+    format!("{} {}{}", AGENT_VERSION, ADVERTISEMENT, codecs.join(","))
+}
+
+/// The codecs a peer with `agent_version` advertised. Unknown ones are
+/// left out.
+pub fn advertised(agent_version: &str) -> Vec<Codec> {
+    agent_version
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(ADVERTISEMENT))
+        .flat_map(|codecs| codecs.split(','))
+        .filter_map(|codec| codec.parse().ok())
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Smallest payload compressed.
+    pub threshold: ByteUnit,
+    /// Codecs to compress with, preferred first.
+    pub codecs:    Vec<Codec>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            threshold: 1.kibibytes(),
+            codecs:    supported().to_vec(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in s.split_whitespace().map(options::pair) {
+            let (key, value) = pair?;
+            match key {
+                "threshold" => {
+                    config.threshold = value
+                        .parse()
+                        .map_err(|err| anyhow!("Invalid threshold {}: {}", value, err))?;
+                }
+                "codecs" if value == "none" => config.codecs.clear(),
+                "codecs" => {
+                    config.codecs = value
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<_>>()?;
+                }
+                _ => bail!("Unknown compression option {}", key),
+            }
+        }
+        if let Some(codec) = config.codecs.iter().find(|codec| !codec.is_supported()) {
+            bail!("Built without codec {}", codec);
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// The codec to compress `len` bytes for peers advertising `recipients`,
+    /// if any.
+    pub fn choose(&self, len: usize, recipients: &[Vec<Codec>]) -> Option<Codec> {
+        if (len as u64) < self.threshold.as_u64() || recipients.is_empty() {
+            return None;
+        }
+        self.codecs
+            .iter()
+            .copied()
+            .find(|codec| recipients.iter().all(|codecs| codecs.contains(codec)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_negotiates_codec() {
+        assert_eq!(advertised("mesh-rs/0.1.0 codecs/lz4,brotli,zstd"), vec![
+            Codec::Lz4,
+            Codec::Zstd
+        ]);
+        assert_eq!(advertised("go-mesh/11.0"), vec![]);
+        assert_eq!(advertised(&agent_version()), supported().to_vec());
+
+        let config = Config {
+            threshold: 100.bytes(),
+            codecs:    vec![Codec::Zstd, Codec::Lz4],
+        };
+        let both = vec![Codec::Zstd, Codec::Lz4];
+        assert_eq!(config.choose(1000, &[both.clone(), both.clone()]), Some(Codec::Zstd));
+        assert_eq!(config.choose(1000, &[both.clone(), vec![Codec::Lz4]]), Some(Codec::Lz4));
+        assert_eq!(config.choose(1000, &[both.clone(), vec![]]), None);
+        assert_eq!(config.choose(99, &[both]), None);
+        assert_eq!(config.choose(1000, &[]), None);
+    }
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "threshold=4KiB codecs=none".parse().unwrap();
+        assert_eq!(config.threshold, 4.kibibytes());
+        assert_eq!(config.codecs, vec![]);
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("codecs=brotli".parse::<Config>().is_err());
+        assert!("level=3".parse::<Config>().is_err());
+        for codec in supported() {
+            let config: Config = format!("codecs={}", codec).parse().unwrap();
+            assert_eq!(config.codecs, vec![*codec]);
+        }
+    }
+
+    #[test]
+    fn test_round_trips_supported_codecs() {
+        let data = b"order order order order order order order order ".repeat(100);
+        for codec in supported() {
+            let compressed = codec.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(codec.decompress(&compressed).unwrap(), data);
+            assert!(codec.decompress(&compressed[..compressed.len() / 2]).is_err());
+        }
+        for codec in &[Codec::Zstd, Codec::Lz4] {
+            if !codec.is_supported() {
+                assert!(codec.compress(&data).is_err());
+            }
+        }
+    }
+}
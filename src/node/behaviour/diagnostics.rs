@@ -0,0 +1,229 @@
+//! Retrieval of debug bundles from remote nodes.
+//!
+//! A node only answers peers it was told to trust with
+//! [`Diagnostics::authorize`]. The peer id of a connection is authenticated
+//! by the transport handshake, so no further credentials are needed. Accepted
+//! requests are handed to the node, which packages the bundle.
+
+use super::cbor_codec::CborCodec;
+use crate::prelude::*;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    stream::FuturesUnordered,
+};
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+pub const PROTOCOL_NAME: &str = "/mesh-rs/diagnostics/version/1";
+
+/// Largest bundle accepted.
+pub const MAX_BUNDLE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        PROTOCOL_NAME.as_bytes()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Bundle,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Bundle(#[serde(with = "serde_bytes")] Vec<u8>),
+    Denied,
+    Error(String),
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+/// A bundle, or why there is none.
+pub type Result = std::result::Result<Vec<u8>, String>;
+
+/// An authorized request for a bundle.
+#[derive(Debug)]
+pub struct BundleRequest {
+    pub peer_id: PeerId,
+
+    responder: oneshot::Sender<Result>,
+}
+
+impl BundleRequest {
+    pub fn respond(self, result: Result) {
+        if self.responder.send(result).is_err() {
+            warn!("Debug bundle for {} dropped, request expired", self.peer_id);
+        }
+    }
+}
+
+type PendingResponse = BoxFuture<'static, (ResponseChannel<Response>, Response)>;
+
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll_events")]
+pub struct Diagnostics {
+    request_response: RequestResponse<Codec>,
+
+    /// Peers allowed to retrieve bundles.
+    #[behaviour(ignore)]
+    admins: HashSet<PeerId>,
+
+    /// Where authorized requests go.
+    #[behaviour(ignore)]
+    handler: Option<mpsc::Sender<BundleRequest>>,
+
+    #[behaviour(ignore)]
+    pending_fetches: HashMap<RequestId, oneshot::Sender<Result>>,
+
+    #[behaviour(ignore)]
+    pending_responses: FuturesUnordered<PendingResponse>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(60));
+        Self {
+            request_response: RequestResponse::new(Codec::new(MAX_BUNDLE), protocols, config),
+            admins: HashSet::new(),
+            handler: None,
+            pending_fetches: HashMap::new(),
+            pending_responses: FuturesUnordered::new(),
+        }
+    }
+
+    /// Send authorized requests to `handler`.
+    pub fn serve(&mut self, handler: mpsc::Sender<BundleRequest>) {
+        self.handler = Some(handler);
+    }
+
+    /// Let `peer_id` retrieve bundles.
+    pub fn authorize(&mut self, peer_id: PeerId) {
+        self.admins.insert(peer_id);
+    }
+
+    /// Ask `peer_id` for its bundle.
+    pub fn fetch(&mut self, peer_id: &PeerId, sender: oneshot::Sender<Result>) {
+        let request_id = self.request_response.send_request(peer_id, Request::Bundle);
+        self.pending_fetches.insert(request_id, sender);
+    }
+
+    fn handle_request(&mut self, peer_id: PeerId, channel: ResponseChannel<Response>) {
+        if !self.admins.contains(&peer_id) {
+            warn!("Refusing debug bundle to unauthorized peer {}", peer_id);
+            if self
+                .request_response
+                .send_response(channel, Response::Denied)
+                .is_err()
+            {
+                warn!("Could not refuse debug bundle to {}", peer_id);
+            }
+            return;
+        }
+        info!("Sending debug bundle to {}", peer_id);
+        let (responder, receiver) = oneshot::channel();
+        let request = BundleRequest { peer_id, responder };
+        let accepted = match &mut self.handler {
+            Some(handler) => handler.try_send(request).is_ok(),
+            None => false,
+        };
+        if !accepted {
+            let response = Response::Error("Debug bundles unavailable".into());
+            if self.request_response.send_response(channel, response).is_err() {
+                warn!("Could not send debug bundle error");
+            }
+            return;
+        }
+        self.pending_responses.push(Box::pin(async move {
+            let response = match receiver.await {
+                Ok(Ok(bundle)) => Response::Bundle(bundle),
+                Ok(Err(message)) => Response::Error(message),
+                Err(_) => Response::Error("Node dropped the request".into()),
+            };
+            (channel, response)
+        }));
+    }
+
+    fn finish_fetch(&mut self, request_id: RequestId, result: Result) {
+        if let Some(sender) = self.pending_fetches.remove(&request_id) {
+            let _ = sender.send(result);
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        let mut progress = false;
+        while let Poll::Ready(Some((channel, response))) =
+            self.pending_responses.poll_next_unpin(cx)
+        {
+            if self.request_response.send_response(channel, response).is_err() {
+                warn!("Debug bundle request expired before the bundle was ready");
+            }
+            progress = true;
+        }
+        // Let the request-response behaviour send what we queued
+        if progress {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Diagnostics {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { channel, .. },
+            } => self.handle_request(peer, channel),
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let result = match response {
+                    Response::Bundle(bundle) => Ok(bundle),
+                    Response::Denied => Err(format!("Peer {} refused, not authorized", peer)),
+                    Response::Error(message) => Err(message),
+                };
+                self.finish_fetch(request_id, result);
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.finish_fetch(request_id, Err(format!("{:?} from {}", error, peer)));
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("Sending debug bundle to {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
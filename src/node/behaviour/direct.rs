@@ -0,0 +1,372 @@
+//! Direct delivery of pubsub payloads to selected peers.
+//!
+//! Gossip fans out to the whole mesh, which is wasteful when only a handful of
+//! known peers are interested. This behaviour hands a `(topic, payload)` pair
+//! straight to each of the given connected peers over a dedicated
+//! request-response protocol. The receiver surfaces it exactly like a gossiped
+//! message, but flagged as `direct`.
+//!
+//! Deliveries are acknowledged. A message whose delivery failed for a reason
+//! other than the peer not speaking the protocol, typically because the
+//! connection died when our address changed, is kept and sent again on the
+//! next [`Direct::resend_unacked`] after we reconnected to the peer, at most
+//! [`MAX_ATTEMPTS`] times. A peer may then receive a message twice if only
+//! its acknowledgement got lost.
+//!
+//! [`Direct::send_to`] sends a payload of no topic to one peer, dialing it
+//! if needed, and reports whether the peer acknowledged it. It travels with
+//! [`MESSAGE_TOPIC`] and surfaces as [`Event::DirectMessage`], not as a
+//! message of a topic, and is not sent again. The connection it takes is
+//! encrypted to the peer, so no relay sees the payload.
+
+use super::{cbor_codec::CborCodec, envelope::Provenance, Event};
+use crate::prelude::*;
+use anyhow::anyhow;
+use futures::channel::oneshot;
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Most unacknowledged messages kept for resending.
+pub const MAX_UNACKED: usize = 1024;
+
+/// Most times a message is sent.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Topic of one-to-one messages. Peers from before one-to-one messages
+/// deliver them as messages on this topic.
+pub const MESSAGE_TOPIC: &str = "/mesh-rs/direct-message/version/1";
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub topic: String,
+    #[serde(with = "serde_bytes")]
+    pub data:  Vec<u8>,
+}
+
+/// Empty acknowledgement.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Response {}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/direct/version/1"
+    }
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
+pub struct Direct {
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+
+    /// Messages waiting for their acknowledgement, with their attempts.
+    #[behaviour(ignore)]
+    pending: HashMap<RequestId, (PeerId, Request, u32)>,
+
+    /// Failed messages waiting for their peer to reconnect.
+    #[behaviour(ignore)]
+    unacked: VecDeque<(PeerId, Request, u32)>,
+
+    /// One-to-one messages waiting for their acknowledgement.
+    #[behaviour(ignore)]
+    sent_to: HashMap<RequestId, oneshot::Sender<Result<()>>>,
+}
+
+impl Direct {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(10));
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            events:           VecDeque::new(),
+            pending:          HashMap::new(),
+            unacked:          VecDeque::new(),
+            sent_to:          HashMap::new(),
+        }
+    }
+
+    /// Send `data` to `peer_id` alone, and tell `sender` once it
+    /// acknowledged the message.
+    pub fn send_to(
+        &mut self,
+        peer_id: &PeerId,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    ) {
+        let request = Request {
+            topic: MESSAGE_TOPIC.into(),
+            data,
+        };
+        let request_id = self.request_response.send_request(peer_id, request);
+        trace!("Direct message {} to {}", request_id, peer_id);
+        self.sent_to.insert(request_id, sender);
+    }
+
+    /// Send `data` on `topic` to each of `peers` we are currently connected
+    /// to. Returns the peers the message was sent to.
+    pub fn publish_to(&mut self, peers: &[PeerId], topic: &str, data: &[u8]) -> Vec<PeerId> {
+        let mut sent = Vec::with_capacity(peers.len());
+        for peer_id in peers {
+            if !self.request_response.is_connected(peer_id) {
+                debug!("Not connected to {}, skipping direct publish", peer_id);
+                continue;
+            }
+            let request = Request {
+                topic: topic.into(),
+                data:  data.to_vec(),
+            };
+            self.send(peer_id.clone(), request, 1);
+            sent.push(peer_id.clone());
+        }
+        sent
+    }
+
+    fn send(&mut self, peer_id: PeerId, request: Request, attempts: u32) {
+        let request_id = self.request_response.send_request(&peer_id, request.clone());
+        trace!("Direct publish {} to {} on {}", request_id, peer_id, request.topic);
+        self.pending.insert(request_id, (peer_id, request, attempts));
+    }
+
+    /// Send the failed messages to the peers we are connected to again.
+    pub fn resend_unacked(&mut self) {
+        let mut waiting = VecDeque::with_capacity(self.unacked.len());
+        for (peer_id, request, attempts) in std::mem::take(&mut self.unacked) {
+            if self.request_response.is_connected(&peer_id) {
+                debug!("Resending direct message on {} to {}", request.topic, peer_id);
+                self.send(peer_id, request, attempts + 1);
+            } else {
+                waiting.push_back((peer_id, request, attempts));
+            }
+        }
+        self.unacked = waiting;
+    }
+
+    fn retain_unacked(&mut self, request_id: RequestId, error: &OutboundFailure) {
+        let (peer_id, request, attempts) = match self.pending.remove(&request_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if matches!(error, OutboundFailure::UnsupportedProtocols) || attempts >= MAX_ATTEMPTS {
+            warn!("Giving up direct message on {} to {}", request.topic, peer_id);
+            return;
+        }
+        if self.unacked.len() >= MAX_UNACKED {
+            if let Some((peer_id, request, _)) = self.unacked.pop_front() {
+                warn!(
+                    "Too many unacknowledged messages, dropping one on {} to {}",
+                    request.topic, peer_id
+                );
+            }
+        }
+        self.unacked.push_back((peer_id, request, attempts));
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Direct {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                if self
+                    .request_response
+                    .send_response(channel, Response {})
+                    .is_err()
+                {
+                    warn!("Could not acknowledge direct message from {}", peer);
+                }
+                if request.topic == MESSAGE_TOPIC {
+                    self.events.push_back(Event::DirectMessage {
+                        source: peer,
+                        data:   request.data,
+                    });
+                    return;
+                }
+                self.events.push_back(Event::Message {
+                    source: peer,
+                    topic:  request.topic,
+                    data:   request.data,
+                    direct: true,
+                    timestamp: None,
+                    provenance: Provenance::default(),
+                    signed: false,
+                });
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { request_id, .. },
+            } => {
+                trace!("Direct publish {} acknowledged by {}", request_id, peer);
+                self.pending.remove(&request_id);
+                if let Some(sender) = self.sent_to.remove(&request_id) {
+                    let _ = sender.send(Ok(()));
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Direct publish {} to {} failed: {:?}",
+                    request_id, peer, error
+                );
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    let event = Event::unsupported(peer.clone(), Version().protocol_name());
+                    self.events.push_back(event);
+                }
+                if let Some(sender) = self.sent_to.remove(&request_id) {
+                    let _ = sender.send(Err(anyhow!("Sending to {} failed: {:?}", peer, error)));
+                    return;
+                }
+                self.retain_unacked(request_id, &error);
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Inbound direct message {} from {} failed: {:?}",
+                    request_id, peer, error
+                );
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{prelude::assert_eq, swarm};
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::{
+        core::upgrade::write_with_len_prefix, request_response::RequestResponseCodec, Swarm,
+    };
+
+    fn read(bytes: Vec<u8>) -> io::Result<Request> {
+        block_on(Codec::default().read_request(&Version(), &mut Cursor::new(bytes)))
+    }
+
+    #[test]
+    fn test_refuses_malformed_requests() {
+        let request = Request {
+            topic: "chat".into(),
+            data:  b"hello".to_vec(),
+        };
+        let mut bytes = Vec::new();
+        block_on(Codec::default().write_request(&Version(), &mut bytes, request.clone())).unwrap();
+        assert_eq!(read(bytes.clone()).unwrap(), request);
+
+        bytes.truncate(bytes.len() - 1);
+        assert!(read(bytes).is_err());
+        let mut acknowledgement = Vec::new();
+        block_on(write_with_len_prefix(
+            &mut acknowledgement,
+            serde_cbor::to_vec(&Response {}).unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(
+            read(acknowledgement).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delivers_and_acknowledges() {
+        let (alice_id, mut alice) = swarm::new(Direct::new());
+        let (bob_id, mut bob) = swarm::new(Direct::new());
+        let address = swarm::listen(&mut bob).await;
+        Swarm::dial_addr(&mut alice, address).unwrap();
+
+        // Peers we are not connected to are skipped
+        let stranger = PeerId::random();
+        let peers = [stranger.clone(), bob_id.clone()];
+        let sent = swarm::run_until(&mut alice, &mut bob, |alice, _| {
+            let sent = alice.publish_to(&peers, "chat", b"hello");
+            (!sent.is_empty()).then(|| sent)
+        })
+        .await;
+        assert_eq!(sent, vec![bob_id.clone()]);
+        let received = swarm::run_until(&mut bob, &mut alice, |_, event| {
+            match event {
+                Some(Event::Message {
+                    source,
+                    topic,
+                    data,
+                    direct,
+                    ..
+                }) => Some((source, topic, data, direct)),
+                _ => None,
+            }
+        })
+        .await;
+        assert_eq!(
+            received,
+            (alice_id.clone(), "chat".into(), b"hello".to_vec(), true)
+        );
+        swarm::run_until(&mut alice, &mut bob, |alice, _| {
+            alice.pending.is_empty().then(|| ())
+        })
+        .await;
+        assert!(alice.unacked.is_empty());
+
+        let (sender, mut acknowledged) = oneshot::channel();
+        alice.send_to(&bob_id, b"hi".to_vec(), sender);
+        let received = swarm::run_until(&mut bob, &mut alice, |_, event| {
+            match event {
+                Some(Event::DirectMessage { source, data }) => Some((source, data)),
+                _ => None,
+            }
+        })
+        .await;
+        assert_eq!(received, (alice_id, b"hi".to_vec()));
+        let result = swarm::run_until(&mut alice, &mut bob, |_, _| {
+            acknowledged.try_recv().unwrap()
+        })
+        .await;
+        assert!(result.is_ok());
+
+        // Without an address the peer can not be dialed
+        let (sender, mut failed) = oneshot::channel();
+        alice.send_to(&stranger, b"hi".to_vec(), sender);
+        let result =
+            swarm::run_until(&mut alice, &mut bob, |_, _| failed.try_recv().unwrap()).await;
+        assert!(result.is_err());
+    }
+}
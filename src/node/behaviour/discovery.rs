@@ -9,30 +9,68 @@
 //! ## To do
 //!
 //! * Accessor methods for known peers.
-//! * Periodically initiate random Kademlia searches.
 //! * Persistently store known peers for quick restart.
 //! * Distinguish between local and global addresses, only feed global ones to
 //!   DHT.
 //! * Observed addresses protocol: https://docs.rs/libp2p-observed-address/0.12.0/libp2p_observed_address/
 
-use crate::prelude::*;
+use super::{
+    codec,
+    mdns::{self, Mdns},
+    multipath::PathHealth,
+    service::ServiceDescriptor,
+    Event,
+};
+use crate::{
+    node::{
+        discovery::{Dht, Lan, Provided, QueryKind},
+        family,
+        latency::{self, Latency},
+        scoring::Score,
+    },
+    prelude::*,
+};
+use anyhow::anyhow;
+use futures::channel::oneshot;
 use humantime::Duration as HumanDuration;
 use libp2p::{
     identify::{Identify, IdentifyEvent, IdentifyInfo},
     identity::Keypair,
     kad::{
-        record::store::MemoryStore, Kademlia, KademliaBucketInserts, KademliaConfig, KademliaEvent,
-        QueryId, QueryResult,
+        record::{
+            self,
+            store::{MemoryStore, MemoryStoreConfig},
+            Record,
+        },
+        AddProviderError, AddProviderOk, GetClosestPeersError, GetClosestPeersOk, GetRecordError,
+        GetRecordOk, Kademlia, KademliaBucketInserts, KademliaConfig, KademliaEvent, PeerRecord,
+        PutRecordError, PutRecordOk, QueryId, QueryResult, QueryStats, Quorum,
     },
-    mdns::{Mdns, MdnsEvent},
     ping::{Ping, PingConfig, PingEvent},
-    swarm::NetworkBehaviourEventProcess,
+    swarm::{
+        toggle::Toggle, NetworkBehaviour as _, NetworkBehaviourAction,
+        NetworkBehaviourEventProcess, PollParameters,
+    },
     Multiaddr, NetworkBehaviour, PeerId,
 };
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    num::{NonZeroU32, NonZeroUsize},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
 use std::sync::{Arc, RwLock};
 
 const DHT_PROTOCOL_ID: &[u8] = b"/0x-mesh-dht/version/1";
+
+/// Time between bootstraps, which refresh the routing table.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long joining the DHT waits for the bootstrap peers at most.
+pub const DHT_DELAY: Duration = Duration::from_secs(5);
+
+/// Copies of a record a lookup looks for, to find the latest.
+const RECORD_QUORUM: usize = 3;
 const BOOTNODES: &[(&str, &str)] = &[
     (
         "16Uiu2HAmGx8Z6gdq5T5AQE54GMtqDhDFhizywTy1o28NJbAMMumF",
@@ -48,20 +86,53 @@ const BOOTNODES: &[(&str, &str)] = &[
     ),
 ];
 
+/// The 0x Mesh bootstrap peers.
+pub fn bootnodes() -> Result<Vec<(PeerId, Multiaddr)>> {
+    BOOTNODES
+        .iter()
+        .map(|(peer_id, multiaddr)| {
+            let peer_id = peer_id.parse().context("Parsing bootnode peer id")?;
+            let multiaddr = multiaddr.parse().context("Parsing bootnode address")?;
+            Ok((peer_id, multiaddr))
+        })
+        .collect()
+}
+
 pub struct DiscoveryConfig {
     peer_key:          Keypair,
     dht_protocol_name: String,
     bootnodes:         Vec<(PeerId, Multiaddr)>,
 }
+/// What the node knows about its peers, shared by the behaviours.
+pub type PeerStore = Arc<RwLock<HashMap<PeerId, PeerInfo>>>;
+
+/// Agent version of this node in the identify protocol.
+pub const AGENT_VERSION: &str = concat!("mesh-rs/", env!("CARGO_PKG_VERSION"));
+
 #[derive(Clone, Debug)]
 pub struct PeerInfo {
     pub peer_id: PeerId,
 
-    /// Latest Identify info
+    /// Latest Identify info, with the agent version and supported protocols
     pub identify: Option<IdentifyInfo>,
 
+    /// Address this node last saw us at.
+    pub observed_addr: Option<Multiaddr>,
+
     /// Latest ping time with this node.
     pub ping: Option<Duration>,
+
+    /// Recent ping times and failures, see [`crate::node::latency`].
+    pub latency: Latency,
+
+    /// Application services advertised by this node.
+    pub services: Vec<ServiceDescriptor>,
+
+    /// Health of the redundant paths, if this is a critical peer.
+    pub paths: Vec<PathHealth>,
+
+    /// How it behaved, with scoring on, see [`crate::node::scoring`].
+    pub score: Option<Score>,
 }
 
 impl PeerInfo {
@@ -69,24 +140,80 @@ impl PeerInfo {
         Self {
             peer_id,
             identify: None,
+            observed_addr: None,
             ping: None,
+            latency: Latency::default(),
+            services: Vec::new(),
+            paths: Vec::new(),
+            score: None,
         }
     }
 }
 
+/// A lookup in the DHT, waiting for its query to finish.
+enum Lookup {
+    /// Addresses of the peer.
+    Peer(PeerId, oneshot::Sender<Result<Vec<Multiaddr>>>),
+    /// Peers closest to a key.
+    Closest(oneshot::Sender<Result<Vec<PeerId>>>),
+    /// Storing a record.
+    Put(oneshot::Sender<Result<()>>),
+    /// The values stored under a key.
+    Get(oneshot::Sender<Result<Vec<Vec<u8>>>>),
+}
+
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
 pub struct Discovery {
-    mdns:     Mdns,
+    mdns:     Toggle<Mdns>,
     kademlia: Kademlia<MemoryStore>,
     identify: Identify,
     ping:     Ping,
 
+    #[behaviour(ignore)]
+    peer_id: PeerId,
+
     #[behaviour(ignore)]
     bootstrap_query_id: Option<QueryId>,
 
+    /// When the routing table was last refreshed.
+    #[behaviour(ignore)]
+    bootstrapped_at: Option<Instant>,
+
+    /// When to join the DHT, once started.
+    #[behaviour(ignore)]
+    dht_from: Option<Instant>,
+
+    #[behaviour(ignore)]
+    lookups: HashMap<QueryId, Lookup>,
+
+    #[behaviour(ignore)]
+    dht: Dht,
+
+    #[behaviour(ignore)]
+    ping_config: latency::Config,
+
+    /// Which addresses of a peer to try first.
+    #[behaviour(ignore)]
+    dial: family::Dial,
+
+    /// Keys we provide, see [`Self::start_providing`].
+    #[behaviour(ignore)]
+    provided: HashMap<Vec<u8>, Provided>,
+
     /// Information that we know about all nodes.
     #[behaviour(ignore)]
-    peer_info: Arc<RwLock<HashMap<PeerId, PeerInfo>>>,
+    peer_info: PeerStore,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+
+    /// Why mDNS did not start, until the node takes it.
+    #[behaviour(ignore)]
+    mdns_error: Option<anyhow::Error>,
+
+    #[behaviour(ignore)]
+    lan: Lan,
 }
 
 impl Discovery {
@@ -94,47 +221,56 @@ impl Discovery {
         let public_key = peer_key.public();
         let peer_id = PeerId::from_public_key(public_key.clone());
 
-        // Mdns LAN node discovery
-        let mdns = Mdns::new()
-            .await
-            .context("Creating mDNS node discovery behaviour")?;
+        // Mdns LAN node discovery, the node decides whether it can go without
+        let (mdns, mdns_error) = match Mdns::new(Lan::default()) {
+            Ok(mdns) => (Some(mdns), None),
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                (None, Some(err.context("Creating mDNS node discovery behaviour")))
+            }
+        };
 
         // Kademlia for 0x Mesh peer discovery
-        let mut kad_config = KademliaConfig::default();
-        kad_config.set_protocol_name(DHT_PROTOCOL_ID);
-        kad_config.set_kbucket_inserts(KademliaBucketInserts::OnConnected);
-        debug!("Kademlia config: {:?}", &kad_config);
-        let kad_store = MemoryStore::new(peer_id.clone());
-        let mut kademlia = Kademlia::with_config(peer_id.clone(), kad_store, kad_config);
+        let mut kademlia = kademlia(&peer_id, Dht::default());
 
         // Add bootnodes
-        for (peer_id, multiaddr) in BOOTNODES {
-            let peer_id = peer_id.parse().context("Parsing bootnode peer id")?;
-            let multiaddr = multiaddr.parse().context("Parsing bootnode address")?;
+        for (peer_id, multiaddr) in bootnodes()? {
             kademlia.add_address(&peer_id, multiaddr);
         }
 
         // Identify protocol
-        let identify = Identify::new("/ipfs/0.1.0".into(), "mesh-rs".into(), public_key);
+        // The agent version advertises our codecs, see [`super::codec`]
+        let identify = Identify::new("/ipfs/0.1.0".into(), codec::agent_version(), public_key);
 
         // Ping protocol
-        let ping = Ping::new(PingConfig::new());
+        let ping = Ping::new(ping_config(latency::Config::default()));
 
         Ok(Self {
-            mdns,
+            mdns: mdns.into(),
             kademlia,
             identify,
             ping,
+            peer_id,
             bootstrap_query_id: None,
+            bootstrapped_at: None,
+            dht_from: None,
+            lookups: HashMap::new(),
+            dht: Dht::default(),
+            ping_config: latency::Config::default(),
+            dial: family::Dial::default(),
+            provided: HashMap::new(),
             peer_info: Arc::new(RwLock::new(HashMap::new())),
+            events: VecDeque::new(),
+            mdns_error,
+            lan: Lan::default(),
         })
     }
 
+    /// Join the DHT after [`DHT_DELAY`], or earlier with
+    /// [`Self::start_dht`]. The first connections then go to the bootstrap
+    /// peers rather than to the DHT queries.
     pub fn start(&mut self) -> Result<()> {
-        // Join DHT
-        let query_id = self.kademlia.bootstrap().context("Joining Kademlia DHT")?;
-        info!("Kademlia Bootstrap started {:?}", &query_id);
-        self.bootstrap_query_id = Some(query_id);
+        self.dht_from = Some(Instant::now() + DHT_DELAY);
 
         // Start searching for random nodes
         // TODO: self.swarm.search_random_peer();
@@ -142,19 +278,364 @@ impl Discovery {
         Ok(())
     }
 
-    pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
+    /// Join the DHT now, once the bootstrap peers answered.
+    pub fn start_dht(&mut self, now: Instant) {
+        if self.bootstrapped_at.is_none() {
+            self.dht_from = Some(now);
+            self.tick(now);
+        }
+    }
+
+    /// Run DHT queries with the settings of `dht`. Call before
+    /// [`Self::start`]: the routing table is kept, but queries in progress
+    /// are dropped.
+    pub fn set_dht(&mut self, dht: Dht) {
+        let addresses: Vec<(PeerId, Vec<Multiaddr>)> = self
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| {
+                        let addresses = entry.node.value.iter().cloned().collect();
+                        (entry.node.key.preimage().clone(), addresses)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        self.kademlia = kademlia(&self.peer_id, dht);
+        for (peer_id, addresses) in addresses {
+            for address in addresses {
+                self.kademlia.add_address(&peer_id, address);
+            }
+        }
+        // The provider records were in the old store
+        self.dht = dht;
+        let now = Instant::now();
+        for provided in self.provided.values_mut() {
+            provided.republish = now;
+        }
+    }
+
+    /// Ping peers with the settings of `config`. Call before connecting to
+    /// peers, the connections open keep their settings.
+    pub fn set_ping(&mut self, config: latency::Config) {
+        self.ping = Ping::new(ping_config(config));
+        self.ping_config = config;
+    }
+
+    /// Learn the addresses of the family `dial` prefers first, see
+    /// [`family`](crate::node::family).
+    pub fn set_dial_preference(&mut self, dial: family::Dial) {
+        self.dial = dial;
+    }
+
+    pub fn known_peers(&self) -> PeerStore {
         self.peer_info.clone()
     }
+
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.kademlia.add_address(peer_id, address);
+    }
+
+    /// Bootstrap again every [`REFRESH_INTERVAL`], to find peers that
+    /// joined since and drop those that left.
+    pub fn tick(&mut self, now: Instant) {
+        let started = self.dht_from.map_or(false, |from| now >= from);
+        if started {
+            self.republish(now);
+        }
+        let due = self.bootstrapped_at.map_or(true, |last| {
+            now.saturating_duration_since(last) >= REFRESH_INTERVAL
+        });
+        if !started || !due || self.bootstrap_query_id.is_some() {
+            return;
+        }
+        // Without peers, retry every tick until there are some
+        match self.kademlia.bootstrap() {
+            Ok(query_id) => {
+                if self.bootstrapped_at.is_none() {
+                    info!("Kademlia Bootstrap started {:?}", &query_id);
+                } else {
+                    debug!("Refreshing Kademlia routing table {:?}", query_id);
+                }
+                self.bootstrap_query_id = Some(query_id);
+                self.bootstrapped_at = Some(now);
+            }
+            Err(err) => trace!("Not refreshing Kademlia routing table: {:?}", err),
+        }
+    }
+
+    /// Look up the addresses of `peer_id` in the DHT.
+    pub fn find_peer(&mut self, peer_id: PeerId, sender: oneshot::Sender<Result<Vec<Multiaddr>>>) {
+        let query_id = self.kademlia.get_closest_peers(peer_id.as_bytes().to_vec());
+        self.lookups.insert(query_id, Lookup::Peer(peer_id, sender));
+    }
+
+    /// Look up the peers closest to `key` in the DHT.
+    pub fn closest_peers(&mut self, key: Vec<u8>, sender: oneshot::Sender<Result<Vec<PeerId>>>) {
+        let query_id = self.kademlia.get_closest_peers(key);
+        self.lookups.insert(query_id, Lookup::Closest(sender));
+    }
+
+    /// Store `value` under `key` in the DHT, locally and on at least
+    /// [`Dht::record_quorum`] of the closest peers.
+    pub fn put_record(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    ) {
+        match self
+            .kademlia
+            .put_record(Record::new(key, value), Quorum::N(self.dht.record_quorum))
+        {
+            Ok(query_id) => {
+                self.lookups.insert(query_id, Lookup::Put(sender));
+            }
+            Err(record::store::Error::ValueTooLarge) => {
+                let limit = self.dht.max_record_size;
+                let _ = sender.send(Err(anyhow!("Record over the limit of {}", limit)));
+            }
+            Err(record::store::Error::MaxRecords) => {
+                let limit = self.dht.max_records;
+                let _ = sender.send(Err(anyhow!("Record store full with {} records", limit)));
+            }
+            Err(err) => {
+                let _ = sender.send(Err(anyhow!("Could not store record: {:?}", err)));
+            }
+        }
+    }
+
+    /// Look up the values stored under `key` in the DHT, up to
+    /// [`RECORD_QUORUM`] of them.
+    pub fn get_record(&mut self, key: Vec<u8>, sender: oneshot::Sender<Result<Vec<Vec<u8>>>>) {
+        let quorum = Quorum::N(NonZeroUsize::new(RECORD_QUORUM).expect("3 != 0"));
+        let query_id = self.kademlia.get_record(&record::Key::from(key), quorum);
+        self.lookups.insert(query_id, Lookup::Get(sender));
+    }
+
+    /// Announce that we provide `key`, and again every
+    /// [`Dht::republish_interval`] until [`Self::stop_providing`].
+    pub fn start_providing(&mut self, key: Vec<u8>, now: Instant) -> Result<()> {
+        self.kademlia
+            .start_providing(record::Key::from(key.clone()))
+            .map_err(|err| anyhow!("Could not provide key: {:?}", err))?;
+        let republish = now + self.dht.republish_interval();
+        self.provided
+            .entry(key.clone())
+            .and_modify(|provided| provided.republish = republish)
+            .or_insert(Provided {
+                key,
+                republish,
+                expires: None,
+            });
+        Ok(())
+    }
+
+    /// Stop announcing `key`. Peers still know us as a provider until the
+    /// records we announced expire.
+    pub fn stop_providing(&mut self, key: &[u8]) {
+        self.kademlia.stop_providing(&record::Key::new(&key));
+        self.provided.remove(key);
+    }
+
+    /// The keys we provide, next to be announced first.
+    pub fn provided(&self) -> Vec<Provided> {
+        let mut provided: Vec<_> = self.provided.values().cloned().collect();
+        provided.sort_by_key(|provided| provided.republish);
+        provided
+    }
+
+    /// Announce the provided keys that are due again.
+    fn republish(&mut self, now: Instant) {
+        let due: Vec<Vec<u8>> = self
+            .provided
+            .values()
+            .filter(|provided| provided.republish <= now)
+            .map(|provided| provided.key.clone())
+            .collect();
+        for key in due {
+            debug!("Announcing provided key {} again", hex::encode(&key));
+            if let Err(err) = self.start_providing(key.clone(), now) {
+                warn!("Not providing {} anymore: {:#}", hex::encode(&key), err);
+                self.provided.remove(&key);
+            }
+        }
+    }
+
+    fn finish_lookup(&mut self, lookup: Lookup, peers: Vec<PeerId>, timed_out: bool) {
+        match lookup {
+            Lookup::Peer(peer_id, sender) => {
+                let addresses = if peers.contains(&peer_id) {
+                    self.kademlia.addresses_of_peer(&peer_id)
+                } else {
+                    Vec::new()
+                };
+                let result = if !addresses.is_empty() {
+                    Ok(addresses)
+                } else if timed_out {
+                    Err(anyhow!("Looking up {} timed out", peer_id))
+                } else {
+                    Err(anyhow!("Peer {} not found in the DHT", peer_id))
+                };
+                let _ = sender.send(result);
+            }
+            Lookup::Closest(sender) => {
+                let result = if timed_out && peers.is_empty() {
+                    Err(anyhow!("Looking up closest peers timed out"))
+                } else {
+                    Ok(peers)
+                };
+                let _ = sender.send(result);
+            }
+            Lookup::Put(_) | Lookup::Get(_) => {
+                error!("Received closest peers for a record lookup");
+            }
+        }
+    }
+
+    /// Move `address` from `old` to `new`, the peer now found there.
+    pub fn move_address(&mut self, old: &PeerId, new: &PeerId, address: Multiaddr) {
+        self.kademlia.remove_address(old, &address);
+        self.kademlia.add_address(new, address);
+    }
+
+    /// Look for peers again: rejoin the DHT and, unless suspended, restart
+    /// mDNS so it queries the network we are on now.
+    pub async fn refresh(&mut self) -> Result<()> {
+        match self.kademlia.bootstrap() {
+            Ok(query_id) => {
+                self.bootstrap_query_id = Some(query_id);
+                self.bootstrapped_at = Some(Instant::now());
+            }
+            Err(err) => debug!("Not refreshing Kademlia routing table: {:?}", err),
+        }
+        if self.mdns.is_enabled() {
+            self.suspend_mdns();
+            self.resume_mdns().await?;
+        }
+        Ok(())
+    }
+
+    /// Forget the 0x Mesh bootnodes, so the DHT does not reach the public
+    /// mesh through them.
+    pub fn remove_bootnodes(&mut self) -> Result<()> {
+        for (peer_id, _) in bootnodes()? {
+            self.kademlia.remove_peer(&peer_id);
+        }
+        Ok(())
+    }
+
+    /// Stop sending and answering mDNS queries.
+    pub fn suspend_mdns(&mut self) {
+        self.mdns = None.into();
+        self.mdns_error = None;
+    }
+
+    /// Why mDNS failed to start, if it did and was not suspended since.
+    pub fn take_mdns_error(&mut self) -> Option<anyhow::Error> {
+        self.mdns_error.take()
+    }
+
+    /// Restart mDNS after [`Self::suspend_mdns`].
+    pub async fn resume_mdns(&mut self) -> Result<()> {
+        if !self.mdns.is_enabled() {
+            self.start_mdns()?;
+        }
+        Ok(())
+    }
+
+    fn start_mdns(&mut self) -> Result<()> {
+        let mdns = Mdns::new(self.lan.clone()).context("Creating mDNS node discovery behaviour")?;
+        self.mdns = Some(mdns).into();
+        Ok(())
+    }
+
+    /// Query and answer mDNS with the settings of `lan`, restarting it if
+    /// it runs.
+    pub fn set_lan(&mut self, lan: Lan) -> Result<()> {
+        self.lan = lan;
+        if self.mdns.is_enabled() {
+            self.suspend_mdns();
+            self.start_mdns()?;
+        }
+        Ok(())
+    }
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for Discovery {
-    fn inject_event(&mut self, event: MdnsEvent) {
+fn ping_config(config: latency::Config) -> PingConfig {
+    // One failure more than we evict after, as the handler closes the
+    // connection on its last failure without reporting it
+    let failures = NonZeroU32::new(config.failures.get().saturating_add(1))
+        .expect("failures + 1 != 0");
+    PingConfig::new()
+        .with_interval(config.interval)
+        .with_timeout(config.timeout)
+        .with_max_failures(failures)
+}
+
+fn kademlia(peer_id: &PeerId, dht: Dht) -> Kademlia<MemoryStore> {
+    let mut kad_config = KademliaConfig::default();
+    kad_config.set_protocol_name(DHT_PROTOCOL_ID);
+    kad_config.set_kbucket_inserts(KademliaBucketInserts::OnConnected);
+    kad_config.set_parallelism(dht.parallelism);
+    kad_config.set_replication_factor(dht.replication);
+    kad_config.set_query_timeout(dht.query_timeout);
+    // Discovery republishes provided keys itself, to know when it does
+    kad_config.set_provider_record_ttl(Some(dht.provider_ttl));
+    kad_config.set_provider_publication_interval(None);
+    kad_config.set_record_ttl(Some(dht.record_ttl));
+    debug!("Kademlia config: {:?}", &kad_config);
+    let kad_store = MemoryStore::with_config(peer_id.clone(), MemoryStoreConfig {
+        max_records: dht.max_records,
+        // The store refuses values of its limit and over
+        max_value_bytes: dht.max_record_size.as_u64() as usize + 1,
+        ..MemoryStoreConfig::default()
+    });
+    Kademlia::with_config(peer_id.clone(), kad_store, kad_config)
+}
+
+impl Discovery {
+    /// Report the progress of a query for `kind`.
+    fn query_progress(&mut self, kind: QueryKind, stats: &QueryStats, finished: bool) {
+        self.events.push_back(Event::DhtQuery {
+            kind,
+            requests: stats.num_requests(),
+            successes: stats.num_successes(),
+            failures: stats.num_failures(),
+            elapsed: stats.duration().unwrap_or_default(),
+            finished,
+        });
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut TaskContext<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<mdns::Event> for Discovery {
+    fn inject_event(&mut self, event: mdns::Event) {
         match event {
-            MdnsEvent::Discovered(iter) => for (peer_id, multiaddr) in iter {
+            mdns::Event::Discovered(found) => for (peer_id, multiaddr) in found {
                 debug!("Discovered {} at {} on LAN.", peer_id, multiaddr);
+                self.events.push_back(Event::PeerDiscovered {
+                    peer:    peer_id,
+                    address: multiaddr,
+                });
             },
-            MdnsEvent::Expired(iter) => for (peer_id, multiaddr) in iter {
+            mdns::Event::Expired(expired) => for (peer_id, multiaddr) in expired {
                 debug!("Expired {} at {} from LAN.", peer_id, multiaddr);
+                self.events.push_back(Event::PeerExpired {
+                    peer:    peer_id,
+                    address: multiaddr,
+                });
             },
         }
     }
@@ -165,7 +646,7 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Discovery {
         match event {
             // A query has produced a result.
             KademliaEvent::QueryResult { id, stats, result } => {
-                info!("Query {:?} finished with {:?}", &id, stats);
+                debug!("Query {:?} finished with {:?}", &id, stats);
                 match result {
                     QueryResult::Bootstrap(result) => {
                         if Some(id) != self.bootstrap_query_id {
@@ -184,17 +665,79 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Discovery {
                         if Some(id) == self.bootstrap_query_id && done {
                             self.bootstrap_query_id = None;
                         }
+                        self.query_progress(QueryKind::Bootstrap, &stats, done);
                     }
                     QueryResult::GetClosestPeers(result) => {
-                        // TODO: track query_id
+                        let (peers, timed_out) = match result {
+                            Ok(GetClosestPeersOk { peers, .. }) => {
+                                debug!("Peer query found {} peers", peers.len());
+                                (peers, false)
+                            }
+                            Err(GetClosestPeersError::Timeout { peers, .. }) => {
+                                debug!("Peer query timed out with {} peers", peers.len());
+                                (peers, true)
+                            }
+                        };
+                        let lookup = self.lookups.remove(&id);
+                        let kind = match &lookup {
+                            Some(Lookup::Peer(..)) => QueryKind::FindPeer,
+                            _ => QueryKind::ClosestPeers,
+                        };
+                        self.query_progress(kind, &stats, true);
+                        if let Some(lookup) = lookup {
+                            self.finish_lookup(lookup, peers, timed_out);
+                        }
+                    }
+                    QueryResult::PutRecord(result) => {
+                        let result = match result {
+                            Ok(PutRecordOk { .. }) => Ok(()),
+                            Err(PutRecordError::QuorumFailed { .. }) => {
+                                Err(anyhow!("No peer stored the record"))
+                            }
+                            Err(PutRecordError::Timeout { .. }) => {
+                                Err(anyhow!("Storing the record timed out"))
+                            }
+                        };
+                        self.query_progress(QueryKind::PutRecord, &stats, true);
+                        if let Some(Lookup::Put(sender)) = self.lookups.remove(&id) {
+                            let _ = sender.send(result);
+                        }
+                    }
+                    QueryResult::GetRecord(result) => {
+                        let records = match result {
+                            Ok(GetRecordOk { records })
+                            | Err(GetRecordError::QuorumFailed { records, .. })
+                            | Err(GetRecordError::Timeout { records, .. }) => records,
+                            Err(GetRecordError::NotFound { .. }) => Vec::new(),
+                        };
+                        debug!("Record query found {} records", records.len());
+                        self.query_progress(QueryKind::GetRecord, &stats, true);
+                        if let Some(Lookup::Get(sender)) = self.lookups.remove(&id) {
+                            let result = if records.is_empty() {
+                                Err(anyhow!("Record not found in the DHT"))
+                            } else {
+                                Ok(records
+                                    .into_iter()
+                                    .map(|PeerRecord { record, .. }| record.value)
+                                    .collect())
+                            };
+                            let _ = sender.send(result);
+                        }
+                    }
+                    QueryResult::StartProviding(result) => {
                         match result {
-                            Ok(ok) => {
-                                info!("Peer query succeeded with {:?}", ok);
+                            Ok(AddProviderOk { key }) => {
+                                debug!("Announced provided key {}", hex::encode(&key));
+                                let expires = Instant::now() + self.dht.provider_ttl;
+                                if let Some(provided) = self.provided.get_mut(key.as_ref()) {
+                                    provided.expires = Some(expires);
+                                }
                             }
-                            Err(err) => {
-                                error!("Peer query failed with {:?}", err);
+                            Err(AddProviderError::Timeout { key }) => {
+                                debug!("Announcing {} timed out", hex::encode(&key));
                             }
                         }
+                        self.query_progress(QueryKind::Provide, &stats, true);
                     }
                     result => {
                         error!("Received query result for unsupported query: {:?}", result);
@@ -247,15 +790,29 @@ impl NetworkBehaviourEventProcess<IdentifyEvent> for Discovery {
             IdentifyEvent::Received {
                 peer_id,
                 info,
-                observed_addr: _,
+                observed_addr,
             } => {
                 debug!(
-                    "Learned about {}",
-                    &peer_id
+                    "Learned about {}, running {} with {} protocols",
+                    &peer_id,
+                    info.agent_version,
+                    info.protocols.len()
                 );
+                // Kademlia only learns addresses of peers it dialed, so
+                // feed it those of DHT peers that connected to us, those
+                // of the preferred family first
+                let dht = DHT_PROTOCOL_ID;
+                if info.protocols.iter().any(|protocol| protocol.as_bytes() == dht) {
+                    let mut addresses = info.listen_addrs.clone();
+                    self.dial.sort(&mut addresses);
+                    for address in addresses {
+                        self.kademlia.add_address(&peer_id, address);
+                    }
+                }
                 let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
                 let entry = lock.entry(peer_id.clone()).or_insert(PeerInfo::new(peer_id));
                 entry.identify = Some(info);
+                entry.observed_addr = Some(observed_addr);
             }
             IdentifyEvent::Sent { peer_id } => {
                 debug!("Sent identify info to {}", peer_id);
@@ -282,13 +839,108 @@ impl NetworkBehaviourEventProcess<PingEvent> for Discovery {
                 let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
                 let entry = lock.entry(event.peer.clone()).or_insert(PeerInfo::new(event.peer));
                 entry.ping = Some(rtt);
+                entry.latency.record(rtt);
             }
             Ok(libp2p::ping::PingSuccess::Pong) => {
                 debug!("Sent pong to {}", event.peer);
             }
             Err(err) => {
-                error!("Ping failed for {}: {:?}", event.peer, err);
+                let peer = event.peer;
+                let failures = {
+                    let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
+                    let entry = lock
+                        .entry(peer.clone())
+                        .or_insert_with(|| PeerInfo::new(peer.clone()));
+                    entry.latency.fail()
+                };
+                debug!("Ping {} of {} failed: {:?}", failures, peer, err);
+                if failures >= self.ping_config.failures.get() {
+                    warn!("Evicting {} after {} failed pings", peer, failures);
+                    self.kademlia.remove_peer(&peer);
+                    self.events.push_back(Event::PeerUnresponsive { peer, failures });
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{prelude::assert_eq, swarm};
+    use libp2p::Swarm;
+    use ubyte::ToByteUnit;
+
+    async fn discovery() -> (PeerId, Swarm<Discovery>) {
+        let keypair = Keypair::generate_ed25519();
+        let mut discovery = Discovery::new(keypair.clone()).await.unwrap();
+        discovery.remove_bootnodes().unwrap();
+        discovery.suspend_mdns();
+        swarm::with_keypair(keypair, discovery)
+    }
+
+    #[tokio::test]
+    async fn test_refuses_records_over_limits() {
+        let (_, mut alice) = discovery().await;
+        alice.set_dht(Dht {
+            max_records: 1,
+            max_record_size: 16.bytes(),
+            ..Dht::default()
+        });
+
+        let (sender, mut result) = oneshot::channel();
+        alice.put_record(b"big".to_vec(), vec![0; 17], sender);
+        let err = result.try_recv().unwrap().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Record over the limit of 16B");
+
+        // Stored locally, the query then waits for peers
+        let (sender, mut result) = oneshot::channel();
+        alice.put_record(b"small".to_vec(), vec![0; 16], sender);
+        assert!(result.try_recv().unwrap().is_none());
+        let (sender, mut result) = oneshot::channel();
+        alice.put_record(b"other".to_vec(), vec![0; 16], sender);
+        let err = result.try_recv().unwrap().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Record store full with 1 records");
+    }
+
+    #[tokio::test]
+    async fn test_finds_peers() {
+        let (alice_id, mut alice) = discovery().await;
+        let (bob_id, mut bob) = discovery().await;
+        let address = swarm::listen(&mut bob).await;
+        alice.add_address(&bob_id, address.clone());
+
+        let (sender, mut found) = oneshot::channel();
+        alice.find_peer(bob_id.clone(), sender);
+        let addresses =
+            swarm::run_until(&mut alice, &mut bob, |_, _| found.try_recv().unwrap()).await;
+        assert_eq!(addresses.unwrap(), vec![address]);
+
+        let (sender, mut found) = oneshot::channel();
+        alice.closest_peers(b"key".to_vec(), sender);
+        let peers = swarm::run_until(&mut alice, &mut bob, |_, _| found.try_recv().unwrap()).await;
+        assert_eq!(peers.unwrap(), vec![bob_id.clone()]);
+
+        // Bob only knows alice, who is not the peer looked for
+        let stranger = PeerId::random();
+        let (sender, mut found) = oneshot::channel();
+        alice.find_peer(stranger.clone(), sender);
+        let err = swarm::run_until(&mut alice, &mut bob, |_, _| found.try_recv().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Peer {} not found in the DHT", stranger)
+        );
+
+        // Identify told bob what alice runs
+        let peers = bob.known_peers();
+        let agent_version = swarm::run_until(&mut bob, &mut alice, |_, _| {
+            let peers = peers.read().unwrap();
+            let identify = peers.get(&alice_id)?.identify.as_ref()?;
+            Some(identify.agent_version.clone())
+        })
+        .await;
+        assert_eq!(agent_version, codec::agent_version());
+    }
+}
@@ -0,0 +1,368 @@
+//! Bundle exchange between peers, see [`crate::node::dtn`].
+//!
+//! When peers meet, each offers the bundles the other may take, the other
+//! answers with those it wants, and each wanted bundle is then transferred in
+//! a request of its own, answered with whether the peer took custody.
+//! Offers are repeated every [`EXCHANGE_INTERVAL`] for bundles that arrived
+//! since. Reports on bundles are exchanged the same way, as bundles of their
+//! own.
+
+use super::{cbor_codec::CborCodec, envelope::Provenance, Event};
+use crate::{
+    node::dtn::{now_ms, Bundle, BundleId, Receipt, Report, Status, Store, Summary, Trace},
+    prelude::*,
+};
+use anyhow::Context as _;
+use libp2p::{
+    core::ProtocolName,
+    identity::Keypair,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Time between offers to a connected peer.
+pub const EXCHANGE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Most bundles in one offer.
+const MAX_OFFER: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/dtn/version/1"
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Offer(Vec<Summary>),
+    Transfer { bundle: Bundle, copies: u32 },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Want(Vec<BundleId>),
+    Custody(bool),
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+/// What an outbound request was about.
+#[derive(Clone, Debug)]
+enum Pending {
+    Offer(Vec<BundleId>),
+    Transfer(BundleId, u32),
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
+pub struct Dtn {
+    request_response: RequestResponse<Codec>,
+
+    /// Signs our bundles.
+    #[behaviour(ignore)]
+    key: Keypair,
+
+    #[behaviour(ignore)]
+    local: PeerId,
+
+    /// Disabled without a store.
+    #[behaviour(ignore)]
+    store: Option<Store>,
+
+    #[behaviour(ignore)]
+    pending: HashMap<RequestId, (PeerId, Pending)>,
+
+    /// When we last offered bundles to each connected peer.
+    #[behaviour(ignore)]
+    offered: HashMap<PeerId, Instant>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+}
+
+impl Dtn {
+    pub fn new(key: Keypair) -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(30));
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            local: PeerId::from(key.public()),
+            key,
+            store: None,
+            pending: HashMap::new(),
+            offered: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn configure(&mut self, store: Store) {
+        self.store = Some(store);
+    }
+
+    /// Number of bundles carried.
+    pub fn len(&self) -> usize {
+        self.store.as_ref().map_or(0, Store::len)
+    }
+
+    /// Store a bundle with `data` on `topic` for `destination`.
+    pub fn send(
+        &mut self,
+        destination: &PeerId,
+        topic: &str,
+        data: Vec<u8>,
+        priority: u8,
+    ) -> Result<BundleId> {
+        let store = self
+            .store
+            .as_mut()
+            .context("Delay-tolerant networking is off, see --dtn")?;
+        let lifetime = store.config().lifetime;
+        let bundle = Bundle::new(&self.key, destination, topic, data, lifetime, priority)?;
+        let id = store.send(bundle)?;
+        debug!("Sending bundle {:?} on {} to {}", id, topic, destination);
+        Ok(id)
+    }
+
+    /// The reports received on our bundle `id`.
+    pub fn trace(&self, id: &BundleId) -> Option<Trace> {
+        self.store.as_ref()?.trace(id).cloned()
+    }
+
+    /// Report `status` of `bundle` back to its `source`.
+    fn report(&mut self, source: &PeerId, bundle: &Bundle, status: Status) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let report = Report {
+            bundle: bundle.id(),
+            status,
+            at_ms: now_ms(),
+        };
+        let result = report
+            .to_bundle(&self.key, source, bundle.expires_ms, bundle.priority)
+            .and_then(|bundle| store.send(bundle));
+        if let Err(err) = result {
+            debug!("Could not report bundle {:?} to {}: {:#}", report.bundle, source, err);
+        }
+    }
+
+    /// Add a report delivered to us to the trace of its bundle.
+    fn record(&mut self, reporter: &PeerId, data: &[u8]) {
+        let report: Report = match serde_cbor::from_slice(data) {
+            Ok(report) => report,
+            Err(err) => {
+                debug!("Ignoring invalid bundle report from {}: {}", reporter, err);
+                return;
+            }
+        };
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        if store.record(reporter, &report) {
+            let id = report.bundle;
+            match report.status {
+                Status::Custody => debug!("Bundle {:?} is carried by {}", id, reporter),
+                Status::Delivered => info!("Bundle {:?} was delivered to {}", id, reporter),
+            }
+        }
+    }
+
+    /// Drop expired bundles, offer bundles to the `peers` that are connected
+    /// and due, and persist the store.
+    pub fn tick(&mut self, now: Instant, peers: impl Iterator<Item = PeerId>) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let expired = store.expire(now_ms());
+        if expired > 0 {
+            debug!("Dropped {} expired bundles", expired);
+        }
+        let request_response = &mut self.request_response;
+        self.offered
+            .retain(|peer_id, _| request_response.is_connected(peer_id));
+        for peer_id in peers {
+            if !request_response.is_connected(&peer_id) {
+                continue;
+            }
+            let due = self.offered.get(&peer_id).map_or(true, |last| {
+                now.saturating_duration_since(*last) >= EXCHANGE_INTERVAL
+            });
+            if !due {
+                continue;
+            }
+            self.offered.insert(peer_id.clone(), now);
+            let offer = store.offer(&peer_id, MAX_OFFER);
+            if offer.is_empty() {
+                continue;
+            }
+            trace!("Offering {} bundles to {}", offer.len(), peer_id);
+            let ids = offer.iter().map(|summary| summary.id.clone()).collect();
+            let request_id = request_response.send_request(&peer_id, Request::Offer(offer));
+            self.pending
+                .insert(request_id, (peer_id, Pending::Offer(ids)));
+        }
+        if let Err(err) = store.flush() {
+            error!("Could not save bundles: {:#}", err);
+        }
+    }
+
+    fn respond(&mut self, peer: &PeerId, request: Request) -> Response {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => {
+                return match request {
+                    Request::Offer(_) => Response::Want(Vec::new()),
+                    Request::Transfer { .. } => Response::Custody(false),
+                }
+            }
+        };
+        match request {
+            Request::Offer(offer) => Response::Want(store.wanted(&offer, &self.local)),
+            Request::Transfer { bundle, copies } => {
+                let receipt = store.receive(peer, bundle.clone(), copies, &self.local, now_ms());
+                match &receipt {
+                    Receipt::Deliver(source) if bundle.is_report() => {
+                        self.record(source, &bundle.data);
+                    }
+                    Receipt::Deliver(source) => {
+                        debug!(
+                            "Received bundle on {} from {} through {}",
+                            bundle.topic, source, peer
+                        );
+                        self.report(source, &bundle, Status::Delivered);
+                        self.events.push_back(Event::Message {
+                            source:     source.clone(),
+                            topic:      bundle.topic,
+                            data:       bundle.data,
+                            direct:     true,
+                            timestamp:  None,
+                            provenance: Provenance::default(),
+                            signed:     false,
+                        });
+                    }
+                    Receipt::Carry(source) => {
+                        trace!("Carrying bundle on {} from {}", bundle.topic, peer);
+                        if !bundle.is_report() {
+                            self.report(source, &bundle, Status::Custody);
+                        }
+                    }
+                    Receipt::Duplicate => {}
+                    Receipt::Refuse(reason) => {
+                        debug!("Refusing bundle on {} from {}: {}", bundle.topic, peer, reason);
+                    }
+                }
+                Response::Custody(receipt.is_custody())
+            }
+        }
+    }
+
+    fn on_response(&mut self, peer: PeerId, pending: Pending, response: Response) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        match (pending, response) {
+            (Pending::Offer(_), Response::Want(wanted)) => {
+                for id in wanted {
+                    let (bundle, copies) = match store.transfer(&peer, &id) {
+                        Some(transfer) => transfer,
+                        None => continue,
+                    };
+                    let request = Request::Transfer { bundle, copies };
+                    let request_id = self.request_response.send_request(&peer, request);
+                    self.pending
+                        .insert(request_id, (peer.clone(), Pending::Transfer(id, copies)));
+                }
+            }
+            (Pending::Transfer(id, copies), Response::Custody(true)) => {
+                trace!("Peer {} took custody of bundle {:?}", peer, id);
+                store.handed_over(&peer, &id, copies);
+            }
+            (Pending::Transfer(..), Response::Custody(false)) => {}
+            (_, response) => warn!("Unexpected bundle response from {}: {:?}", peer, response),
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        if let Some(store) = &mut self.store {
+            self.events
+                .extend(store.take_evicted().into_iter().map(Event::BundleEvicted));
+        }
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Dtn {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                let response = self.respond(&peer, request);
+                if self.request_response.send_response(channel, response).is_err() {
+                    debug!("Could not answer bundle request from {}", peer);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                },
+            } => {
+                if let Some((_, pending)) = self.pending.remove(&request_id) {
+                    self.on_response(peer, pending, response);
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!("Bundle exchange with {} failed: {:?}", peer, error);
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    let event = Event::unsupported(peer.clone(), Version().protocol_name());
+                    self.events.push_back(event);
+                }
+                let ids = match self.pending.remove(&request_id) {
+                    Some((_, Pending::Offer(ids))) => ids,
+                    Some((_, Pending::Transfer(id, _))) => vec![id],
+                    None => return,
+                };
+                if let Some(store) = &mut self.store {
+                    store.reoffer(&peer, &ids);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("Bundle exchange from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Closing duplicate connections, see [`crate::node::duplicate`].
+//!
+//! The swarm can only close all connections to a peer, so every connection
+//! gets a [`Closer`] handler that closes its connection when told to.
+
+use super::Event;
+use crate::{node::duplicate::Policy, prelude::*};
+use libp2p::{
+    core::{
+        connection::ConnectionId,
+        upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade},
+        ConnectedPoint, Endpoint,
+    },
+    swarm::{
+        KeepAlive, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+        PollParameters, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+        SubstreamProtocol,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+/// Why a [`Closer`] closed its connection.
+#[derive(Debug, Error)]
+#[error("Duplicate connection")]
+pub struct Duplicate;
+
+/// What a denied upgrade yields, which never happens.
+type Never = <DeniedUpgrade as InboundUpgrade<NegotiatedSubstream>>::Output;
+
+/// Tells a [`Closer`] to close its connection.
+#[derive(Clone, Debug)]
+pub struct Close;
+
+/// Closes its connection on [`Close`], and otherwise leaves it to the other
+/// handlers.
+#[derive(Debug, Default)]
+pub struct Closer {
+    closing: bool,
+}
+
+impl ProtocolsHandler for Closer {
+    type Error = Duplicate;
+    type InEvent = Close;
+    type InboundOpenInfo = ();
+    type InboundProtocol = DeniedUpgrade;
+    type OutEvent = Infallible;
+    type OutboundOpenInfo = Infallible;
+    type OutboundProtocol = DeniedUpgrade;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<DeniedUpgrade, ()> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(&mut self, output: Never, _info: ()) {
+        match output {}
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        output: <DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        _info: Infallible,
+    ) {
+        match output {}
+    }
+
+    fn inject_event(&mut self, _event: Close) {
+        self.closing = true;
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Infallible,
+        _error: ProtocolsHandlerUpgrErr<Never>,
+    ) {
+        match info {}
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, Infallible, Infallible, Duplicate>> {
+        if self.closing {
+            Poll::Ready(ProtocolsHandlerEvent::Close(Duplicate))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub struct Duplicates {
+    local:       PeerId,
+    policy:      Policy,
+    /// Peers we keep several connections to on purpose.
+    exempt:      HashSet<PeerId>,
+    /// Open connections by peer, oldest first.
+    connections: HashMap<PeerId, Vec<(ConnectionId, ConnectedPoint)>>,
+    /// Connections told to close, until they did.
+    closing:     HashSet<(PeerId, ConnectionId)>,
+    to_close:    VecDeque<(PeerId, ConnectionId)>,
+    events:      VecDeque<Event>,
+}
+
+impl Duplicates {
+    pub fn new(local: PeerId) -> Self {
+        Self {
+            local,
+            policy: Policy::default(),
+            exempt: HashSet::new(),
+            connections: HashMap::new(),
+            closing: HashSet::new(),
+            to_close: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Keep all connections to `peer_id`.
+    pub fn exempt(&mut self, peer_id: PeerId) {
+        self.exempt.insert(peer_id);
+    }
+}
+
+impl NetworkBehaviour for Duplicates {
+    type OutEvent = Event;
+    type ProtocolsHandler = Closer;
+
+    fn new_handler(&mut self) -> Closer {
+        Closer::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let connections = self.connections.entry(peer_id.clone()).or_default();
+        connections.push((*connection, endpoint.clone()));
+        if self.exempt.contains(peer_id) {
+            return;
+        }
+        let endpoints = connections
+            .iter()
+            .map(|(id, endpoint)| {
+                let side = if endpoint.is_dialer() {
+                    Endpoint::Dialer
+                } else {
+                    Endpoint::Listener
+                };
+                (*id, side)
+            })
+            .collect::<Vec<_>>();
+        for id in self.policy.duplicates(&self.local, peer_id, &endpoints) {
+            if self.closing.insert((peer_id.clone(), id)) {
+                debug!("Closing duplicate connection {:?} to {}", id, peer_id);
+                self.to_close.push_back((peer_id.clone(), id));
+            }
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        _endpoint: &ConnectedPoint,
+    ) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            if let Some(index) = connections.iter().position(|(id, _)| id == connection) {
+                let (_, endpoint) = connections.remove(index);
+                if self.closing.remove(&(peer_id.clone(), *connection)) {
+                    self.events.push_back(Event::DuplicateClosed {
+                        peer: peer_id.clone(),
+                        endpoint,
+                    });
+                }
+            }
+            if connections.is_empty() {
+                self.connections.remove(peer_id);
+            }
+        }
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: Infallible) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Close, Event>> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        self.to_close
+            .pop_front()
+            .map_or(Poll::Pending, |(peer_id, connection)| {
+                Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(connection),
+                    event: Close,
+                })
+            })
+    }
+}
@@ -0,0 +1,342 @@
+//! Envelope wrapped around pubsub payloads published by this node.
+//!
+//! Payloads from peers that do not use envelopes (like the Go 0x Mesh nodes on
+//! the orders topic) are passed through as-is, without a timestamp.
+//!
+//! Large payloads carry the id of their blob. If the sender knows the
+//! receiver already has the blob, `data` is left empty. Payloads may be
+//! compressed with a [`Codec`] the receivers advertised.
+//!
+//! Messages republished by a bridge or relay carry their [`Provenance`]: the
+//! hash of the payload as the origin published it, and a hop for every relay,
+//! signed by the relay and naming the peer it received the message from.
+//! Receivers see the origin as the source of the message, with the relays in
+//! between. The topic is not signed, as bridges may republish on another one.
+//!
+//! Envelopes are signed by their sender over the topic they are sent on, see
+//! [`crate::node::verification`]. Envelopes on restricted topics carry the
+//! sender's [`Capability`], which names the sender itself and so needs no
+//! signature of the envelope. Envelopes on acknowledged topics carry a signed
+//! sequence number, see [`super::reliable`], and those with an expiry or hop
+//! limit carry them signed too, see [`crate::node::expiry`].
+
+use super::{
+    blob::BlobId,
+    cbor_codec::{decode, encode},
+    codec::Codec,
+};
+use crate::{
+    node::{
+        capability::Capability,
+        expiry::Limits,
+        hlc::Timestamp,
+        signing::{Domain, Layout},
+        verification::Verification,
+    },
+    prelude::*,
+};
+use anyhow::{anyhow, ensure};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub timestamp:  Timestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob:       Option<BlobId>,
+    /// Compression of `data`, see [`super::codec`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec:      Option<Codec>,
+    #[serde(with = "serde_bytes")]
+    pub data:       Vec<u8>,
+    #[serde(default, skip_serializing_if = "Provenance::is_empty")]
+    pub provenance: Provenance,
+    /// Right to publish on a restricted topic, see
+    /// [`crate::node::capability`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<Capability>,
+    /// Sequence number on an acknowledged topic, see [`super::reliable`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq:        Option<u64>,
+    /// Milliseconds since the Unix epoch after which the message is
+    /// dropped, see [`crate::node::expiry`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires:    Option<u64>,
+    /// Relays that may still republish the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hops:       Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature:  Option<Signature>,
+}
+
+/// The sender's signature of an envelope.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Signature {
+    /// Protobuf encoding of the sender's public key.
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    signature:  Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Hop {
+    /// Protobuf encoding of the relay's public key.
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+    /// Base58 peer id of the peer the relay received the message from.
+    from:       String,
+    #[serde(with = "serde_bytes")]
+    signature:  Vec<u8>,
+}
+
+/// The relays that republished a message, empty for messages received from
+/// their origin.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Provenance {
+    /// SHA-256 of the payload as published by the origin.
+    #[serde(with = "serde_bytes")]
+    digest: Vec<u8>,
+    hops:   Vec<Hop>,
+    /// The relays of the hops, once verified.
+    #[serde(skip)]
+    relays: Vec<PeerId>,
+    /// The expiry and hop limit of the envelope it came in.
+    #[serde(skip)]
+    limits: Limits,
+}
+
+fn signed_bytes(digest: &[u8], from: &str, previous: &[u8]) -> Vec<u8> {
+    Layout::new(Domain::Provenance)
+        .header("from", from.as_bytes())
+        .header("previous", previous)
+        .payload(digest)
+        .to_bytes()
+}
+
+impl Provenance {
+    pub fn is_empty(&self) -> bool {
+        self.hops.is_empty()
+    }
+
+    /// The relays in the order they republished the message, see
+    /// [`Provenance::verify`].
+    pub fn relays(&self) -> &[PeerId] {
+        &self.relays
+    }
+
+    /// The expiry and hop limit the message was received with.
+    pub const fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Add a hop for us relaying `payload`, as received from `from`.
+    pub fn extend(&self, key: &Keypair, payload: &[u8], from: &PeerId) -> Result<Self> {
+        let local = PeerId::from(key.public());
+        ensure!(
+            !self.relays.contains(&local) && from != &local,
+            "Message was relayed by us before"
+        );
+        let mut provenance = self.clone();
+        if provenance.digest.is_empty() {
+            provenance.digest = Sha256::digest(payload).to_vec();
+        }
+        let from = from.to_base58();
+        let previous = self.hops.last().map_or(&[][..], |hop| &hop.signature);
+        let signature = key
+            .sign(&signed_bytes(&provenance.digest, &from, previous))
+            .context("Signing provenance")?;
+        provenance.hops.push(Hop {
+            public_key: key.public().into_protobuf_encoding(),
+            from,
+            signature,
+        });
+        provenance.relays.push(local);
+        Ok(provenance)
+    }
+
+    /// Check the hops of a message received from `sender`, the last relay.
+    /// Returns the origin.
+    pub fn verify(&mut self, sender: &PeerId) -> Result<PeerId> {
+        let first = self.hops.first().context("No hops")?;
+        let origin = first
+            .from
+            .parse::<PeerId>()
+            .map_err(|_| anyhow!("Invalid origin {}", first.from))?;
+        let mut relays = Vec::with_capacity(self.hops.len());
+        let mut previous = &[][..];
+        for hop in &self.hops {
+            let public_key = PublicKey::from_protobuf_encoding(&hop.public_key)
+                .map_err(|_| anyhow!("Invalid relay key"))?;
+            let relay = PeerId::from(public_key.clone());
+            let from = relays.last().unwrap_or(&origin);
+            ensure!(hop.from == from.to_base58(), "Hop from {} does not follow {}", hop.from, from);
+            ensure!(
+                public_key.verify(&signed_bytes(&self.digest, &hop.from, previous), &hop.signature),
+                "Bad signature of relay {}",
+                relay
+            );
+            ensure!(relay != origin && !relays.contains(&relay), "Relay {} loops", relay);
+            relays.push(relay);
+            previous = &hop.signature;
+        }
+        ensure!(relays.last() == Some(sender), "Last relay is not the sender {}", sender);
+        self.relays = relays;
+        Ok(origin)
+    }
+
+    /// Whether `payload` is what the origin published.
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        self.is_empty() || Sha256::digest(payload).as_slice() == self.digest.as_slice()
+    }
+}
+
+impl Envelope {
+    /// What the sender signs. Payloads stored as blobs are covered by their
+    /// id, as the data may be left out.
+    fn signed_bytes(&self, topic: &str) -> Vec<u8> {
+        let mut layout = Layout::new(Domain::Message)
+            .topic(topic)
+            .timestamp(self.timestamp.wall_ms)
+            .number("logical", self.timestamp.logical.into());
+        if let Some(codec) = self.codec {
+            layout = layout.header("codec", codec.name().as_bytes());
+        }
+        if let Some(seq) = self.seq {
+            layout = layout.number("seq", seq);
+        }
+        if let Some(expires) = self.expires {
+            layout = layout.number("expires", expires);
+        }
+        if let Some(hops) = self.hops {
+            layout = layout.number("hops", hops.into());
+        }
+        match &self.blob {
+            Some(id) => layout.header("blob", &id.0[..]).to_bytes(),
+            None => layout.payload(&self.data).to_bytes(),
+        }
+    }
+
+    /// Sign the envelope for sending on `topic`.
+    pub fn sign(&mut self, key: &Keypair, topic: &str) -> Result<()> {
+        let signature = key
+            .sign(&self.signed_bytes(topic))
+            .context("Signing envelope")?;
+        self.signature = Some(Signature {
+            public_key: key.public().into_protobuf_encoding(),
+            signature,
+        });
+        Ok(())
+    }
+
+    /// Check that `source` signed the envelope received on `topic`.
+    pub fn verify(&self, topic: &str, source: &PeerId) -> Verification {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Verification::Unsigned,
+        };
+        match PublicKey::from_protobuf_encoding(&signature.public_key) {
+            Ok(public_key)
+                if PeerId::from(public_key.clone()) == *source
+                    && public_key.verify(&self.signed_bytes(topic), &signature.signature) =>
+            {
+                Verification::Valid
+            }
+            _ => Verification::Invalid,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self).expect("Envelopes always encode")
+    }
+
+    /// The expiry and hop limit of the envelope.
+    pub const fn limits(&self) -> Limits {
+        Limits {
+            expires: self.expires,
+            hops:    self.hops,
+        }
+    }
+
+    /// Decode an envelope. Returns `None` for payloads without one.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        decode(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_verifies_provenance_chain() {
+        let keys = (0..3).map(|_| Keypair::generate_ed25519()).collect::<Vec<_>>();
+        let peers = keys.iter().map(|key| PeerId::from(key.public())).collect::<Vec<_>>();
+        let (origin, bridge, relay) = (&peers[0], &peers[1], &peers[2]);
+
+        let once = Provenance::default().extend(&keys[1], b"data", origin).unwrap();
+        let twice = once.extend(&keys[2], b"data", bridge).unwrap();
+        assert_eq!(twice.relays(), &[bridge.clone(), relay.clone()]);
+        assert!(twice.extend(&keys[1], b"data", relay).is_err());
+
+        // Receivers check the whole chain
+        let mut received: Provenance = decode(&encode(&twice).unwrap()).unwrap();
+        assert_eq!(received.relays(), &[]);
+        assert_eq!(received.verify(relay).unwrap(), origin.clone());
+        assert_eq!(received.relays(), twice.relays());
+        assert!(received.matches(b"data"));
+        assert!(!received.matches(b"other"));
+        assert!(received.clone().verify(bridge).is_err());
+
+        let mut forged = twice.clone();
+        forged.hops[0].from = relay.to_base58();
+        assert!(forged.verify(relay).is_err());
+        let mut truncated = twice;
+        truncated.hops.remove(0);
+        assert!(truncated.verify(relay).is_err());
+    }
+
+    #[test]
+    fn test_verifies_sender() {
+        let key = Keypair::generate_ed25519();
+        let sender = PeerId::from(key.public());
+        let mut envelope = Envelope {
+            timestamp:  Timestamp::default(),
+            blob:       None,
+            codec:      None,
+            data:       b"hello".to_vec(),
+            provenance: Provenance::default(),
+            capability: None,
+            seq:        None,
+            expires:    None,
+            hops:       None,
+            signature:  None,
+        };
+        assert_eq!(envelope.verify("chat", &sender), Verification::Unsigned);
+        envelope.sign(&key, "chat").unwrap();
+        let received = Envelope::decode(&envelope.to_bytes()).unwrap();
+        assert_eq!(received.verify("chat", &sender), Verification::Valid);
+        assert_eq!(received.verify("news", &sender), Verification::Invalid);
+        assert_eq!(received.verify("chat", &PeerId::random()), Verification::Invalid);
+        let mut altered = received.clone();
+        altered.data = b"hellO".to_vec();
+        assert_eq!(altered.verify("chat", &sender), Verification::Invalid);
+        let mut recoded = received;
+        recoded.codec = Some(Codec::Lz4);
+        assert_eq!(recoded.verify("chat", &sender), Verification::Invalid);
+        let mut renumbered = envelope.clone();
+        renumbered.seq = Some(1);
+        assert_eq!(renumbered.verify("chat", &sender), Verification::Invalid);
+        let mut extended = envelope;
+        extended.hops = Some(8);
+        assert_eq!(extended.verify("chat", &sender), Verification::Invalid);
+    }
+}
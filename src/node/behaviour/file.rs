@@ -0,0 +1,433 @@
+//! File transfers, see [`crate::node::file`].
+//!
+//! The sender offers a manifest, and the receiver that accepted it asks
+//! for the chunks it is missing one request each.
+
+use super::{cbor_codec::CborCodec, Event};
+use crate::{
+    node::file::{
+        self, Config, Direction, Download, FileId, Manifest, Progress, State, MAX_INCOMING,
+        STALL_TIMEOUT,
+    },
+    prelude::*,
+};
+use anyhow::anyhow;
+use futures::channel::oneshot;
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    path::PathBuf,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/file/version/1"
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Offer(Manifest),
+    Chunk { id: FileId, index: u32 },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Accepted,
+    Refused(String),
+    Chunk(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The file is not offered to the peer.
+    NotOffered,
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+/// A file offered to a peer.
+struct Offer {
+    path:     PathBuf,
+    manifest: Manifest,
+    /// Chunks the peer fetched.
+    sent:     HashSet<usize>,
+    accepted: Option<oneshot::Sender<Result<FileId>>>,
+    active:   Instant,
+}
+
+struct Incoming {
+    download: Download,
+    active:   Instant,
+}
+
+enum Pending {
+    Offer(PeerId, FileId),
+    Chunk(PeerId, FileId, usize),
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
+pub struct Files {
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+
+    #[behaviour(ignore)]
+    config: Config,
+
+    #[behaviour(ignore)]
+    offers: HashMap<(PeerId, FileId), Offer>,
+
+    #[behaviour(ignore)]
+    incoming: HashMap<(PeerId, FileId), Incoming>,
+
+    #[behaviour(ignore)]
+    pending: HashMap<RequestId, Pending>,
+}
+
+impl Files {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(60));
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            events:           VecDeque::new(),
+            config:           Config::default(),
+            offers:           HashMap::new(),
+            incoming:         HashMap::new(),
+            pending:          HashMap::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Offer `peer_id` the file at `path` made of `manifest`, and tell
+    /// `accepted` whether it took it.
+    pub fn offer(
+        &mut self,
+        peer_id: &PeerId,
+        path: PathBuf,
+        manifest: Manifest,
+        accepted: Option<oneshot::Sender<Result<FileId>>>,
+    ) -> FileId {
+        let id = manifest.id();
+        info!("Offering {} ({} bytes) to {}", manifest.name, manifest.size, peer_id);
+        let request_id = self
+            .request_response
+            .send_request(peer_id, Request::Offer(manifest.clone()));
+        self.pending
+            .insert(request_id, Pending::Offer(peer_id.clone(), id.clone()));
+        self.offers.insert((peer_id.clone(), id.clone()), Offer {
+            path,
+            manifest,
+            sent: HashSet::new(),
+            accepted,
+            active: Instant::now(),
+        });
+        id
+    }
+
+    fn progress(&mut self, peer: &PeerId, manifest: &Manifest, direction: Direction, sent: u64) {
+        let state = if sent == manifest.size {
+            State::Done(None)
+        } else {
+            State::Active
+        };
+        self.events.push_back(Event::FileTransfer(Progress {
+            peer: peer.clone(),
+            id: manifest.id(),
+            name: manifest.name.clone(),
+            direction,
+            transferred: sent,
+            size: manifest.size,
+            state,
+        }));
+    }
+
+    fn failed(&mut self, peer: &PeerId, manifest: &Manifest, direction: Direction, error: String) {
+        self.events.push_back(Event::FileTransfer(Progress {
+            peer: peer.clone(),
+            id: manifest.id(),
+            name: manifest.name.clone(),
+            direction,
+            transferred: 0,
+            size: manifest.size,
+            state: State::Failed(error),
+        }));
+    }
+
+    /// Answer an offer of `manifest` from `peer`.
+    fn accept(&mut self, peer: &PeerId, manifest: Manifest) -> Response {
+        let dir = match &self.config.dir {
+            Some(dir) => dir.clone(),
+            None => return Response::Refused("Not accepting files".into()),
+        };
+        if let Err(err) = manifest.validate() {
+            return Response::Refused(err.to_string());
+        }
+        if manifest.size > self.config.max_size.as_u64() {
+            return Response::Refused(format!("Files over {} are refused", self.config.max_size));
+        }
+        let key = (peer.clone(), manifest.id());
+        if !self.incoming.contains_key(&key) && self.incoming.len() >= MAX_INCOMING {
+            return Response::Refused("Receiving too many files".into());
+        }
+        let download = match Download::open(&dir, manifest) {
+            Ok(download) => download,
+            Err(err) => {
+                error!("Could not receive a file: {:#}", err);
+                return Response::Refused("Could not store the file".into());
+            }
+        };
+        info!(
+            "Receiving {} ({} bytes) from {}",
+            download.manifest.name, download.manifest.size, peer
+        );
+        self.incoming.insert(key.clone(), Incoming {
+            download,
+            active: Instant::now(),
+        });
+        self.request_chunks(&key);
+        Response::Accepted
+    }
+
+    /// Ask for the next chunks of the file of `key`, or finish it.
+    fn request_chunks(&mut self, key: &(PeerId, FileId)) {
+        let incoming = match self.incoming.get_mut(key) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        if incoming.download.is_done() {
+            let incoming = self.incoming.remove(key).expect("Checked above");
+            self.finish(&key.0, incoming.download);
+            return;
+        }
+        if !self.request_response.is_connected(&key.0) {
+            return;
+        }
+        for index in incoming.download.next() {
+            let request = Request::Chunk {
+                id:    key.1.clone(),
+                index: index as u32,
+            };
+            let request_id = self.request_response.send_request(&key.0, request);
+            self.pending
+                .insert(request_id, Pending::Chunk(key.0.clone(), key.1.clone(), index));
+        }
+    }
+
+    fn finish(&mut self, peer: &PeerId, download: Download) {
+        let manifest = download.manifest.clone();
+        let dir = self.config.dir.clone().unwrap_or_default();
+        match download.finish(&dir) {
+            Ok(path) => {
+                info!("Received {} from {} into {}", manifest.name, peer, path.display());
+                self.events.push_back(Event::FileTransfer(Progress {
+                    peer:        peer.clone(),
+                    id:          manifest.id(),
+                    name:        manifest.name,
+                    direction:   Direction::Receiving,
+                    transferred: manifest.size,
+                    size:        manifest.size,
+                    state:       State::Done(Some(path)),
+                }));
+            }
+            Err(err) => {
+                error!("Could not store {}: {:#}", manifest.name, err);
+                self.failed(peer, &manifest, Direction::Receiving, format!("{:#}", err));
+            }
+        }
+    }
+
+    /// Serve chunk `index` of the file `id` to `peer`.
+    fn serve(&mut self, peer: &PeerId, id: FileId, index: usize) -> Response {
+        let offer = match self.offers.get_mut(&(peer.clone(), id)) {
+            Some(offer) => offer,
+            None => return Response::NotOffered,
+        };
+        offer.active = Instant::now();
+        let data = match file::read(&offer.path, &offer.manifest, index) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Could not read {}: {:#}", offer.path.display(), err);
+                return Response::NotOffered;
+            }
+        };
+        offer.sent.insert(index);
+        let sent = offer
+            .sent
+            .iter()
+            .map(|index| offer.manifest.chunk_len(*index))
+            .sum();
+        let manifest = offer.manifest.clone();
+        if sent == manifest.size {
+            info!("Sent {} to {}", manifest.name, peer);
+        }
+        self.progress(peer, &manifest, Direction::Sending, sent);
+        Response::Chunk(data)
+    }
+
+    /// Go on with the transfers of reconnected peers, and fail the stalled.
+    pub fn tick(&mut self, now: Instant) {
+        let stalled = |active: &Instant| now.saturating_duration_since(*active) >= STALL_TIMEOUT;
+        let expired = self
+            .offers
+            .iter()
+            .filter(|(_, offer)| stalled(&offer.active))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            if let Some(offer) = self.offers.remove(&key) {
+                if offer.sent.len() < offer.manifest.chunks.len() {
+                    let error = "Stalled".to_owned();
+                    self.failed(&key.0, &offer.manifest, Direction::Sending, error);
+                }
+            }
+        }
+        let keys = self.incoming.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            if self.incoming.get(&key).map_or(false, |incoming| stalled(&incoming.active)) {
+                if let Some(incoming) = self.incoming.remove(&key) {
+                    warn!("Receiving {} from {} stalled", incoming.download.manifest.name, key.0);
+                    let manifest = incoming.download.manifest;
+                    self.failed(&key.0, &manifest, Direction::Receiving, "Stalled".into());
+                }
+                continue;
+            }
+            self.request_chunks(&key);
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Files {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                let response = match request {
+                    Request::Offer(manifest) => self.accept(&peer, manifest),
+                    Request::Chunk { id, index } => self.serve(&peer, id, index as usize),
+                };
+                if self.request_response.send_response(channel, response).is_err() {
+                    debug!("Could not answer file request from {}", peer);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                },
+            } => {
+                match (self.pending.remove(&request_id), response) {
+                    (Some(Pending::Offer(peer, id)), Response::Accepted) => {
+                        let key = (peer, id.clone());
+                        if let Some(sender) =
+                            self.offers.get_mut(&key).and_then(|offer| offer.accepted.take())
+                        {
+                            let _ = sender.send(Ok(id));
+                        }
+                    }
+                    (Some(Pending::Offer(peer, id)), Response::Refused(reason)) => {
+                        warn!("{} refused the file: {}", peer, reason);
+                        if let Some(mut offer) = self.offers.remove(&(peer.clone(), id)) {
+                            if let Some(sender) = offer.accepted.take() {
+                                let _ = sender.send(Err(anyhow!("Refused: {}", reason)));
+                            }
+                            self.failed(&peer, &offer.manifest, Direction::Sending, reason);
+                        }
+                    }
+                    (Some(Pending::Chunk(peer, id, index)), Response::Chunk(data)) => {
+                        let key = (peer, id);
+                        let incoming = match self.incoming.get_mut(&key) {
+                            Some(incoming) => incoming,
+                            None => return,
+                        };
+                        incoming.active = Instant::now();
+                        if let Err(err) = incoming.download.write(index, &data) {
+                            warn!("Chunk from {}: {:#}", key.0, err);
+                        }
+                        let received = incoming.download.received();
+                        let manifest = incoming.download.manifest.clone();
+                        if received < manifest.size {
+                            self.progress(&key.0, &manifest, Direction::Receiving, received);
+                        }
+                        self.request_chunks(&key);
+                    }
+                    (Some(Pending::Chunk(peer, id, _)), Response::NotOffered) => {
+                        if let Some(incoming) = self.incoming.remove(&(peer.clone(), id)) {
+                            let manifest = incoming.download.manifest;
+                            let error = "No longer offered".to_owned();
+                            self.failed(&peer, &manifest, Direction::Receiving, error);
+                        }
+                    }
+                    (_, response) => {
+                        debug!("Unexpected file response from {}: {:?}", peer, response);
+                    }
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!("File request to {} failed: {:?}", peer, error);
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    let event = Event::unsupported(peer, Version().protocol_name());
+                    self.events.push_back(event);
+                }
+                match self.pending.remove(&request_id) {
+                    Some(Pending::Offer(peer, id)) => {
+                        if let Some(mut offer) = self.offers.remove(&(peer.clone(), id)) {
+                            let reason = format!("{:?}", error);
+                            if let Some(sender) = offer.accepted.take() {
+                                let _ = sender.send(Err(anyhow!("Offer failed: {}", reason)));
+                            }
+                            self.failed(&peer, &offer.manifest, Direction::Sending, reason);
+                        }
+                    }
+                    // Asked for again once reconnected
+                    Some(Pending::Chunk(peer, id, index)) => {
+                        if let Some(incoming) = self.incoming.get_mut(&(peer, id)) {
+                            incoming.download.lost(index);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("File request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
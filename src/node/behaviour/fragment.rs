@@ -0,0 +1,404 @@
+//! Message size limits and fragmentation.
+//!
+//! Started with `--message-size "max=4MiB frame=128KiB"` the node refuses to
+//! publish payloads over `max` bytes, and splits envelopes over `frame`
+//! bytes into fragments of at most `frame` bytes each, published as separate
+//! pubsub messages on the same topic. A fragment carries a random message
+//! id, its index and the number of fragments, so receivers put them back in
+//! order however they arrive. The reassembled envelope is then verified and
+//! delivered like any other.
+//!
+//! Fragments of a message are kept for [`TIMEOUT`] waiting for the others.
+//! Receivers drop messages that would reassemble to more than their own
+//! `max` bytes, plus the envelope, and hold at most [`MAX_PENDING`] bytes and
+//! [`MAX_PARTIAL`] incomplete messages, [`MAX_PARTIAL_PER_SOURCE`] of them
+//! from any one source, dropping the oldest first. Peers without
+//! fragmentation deliver the fragments as undecodable payloads.
+
+use super::cbor_codec::{decode, encode};
+use crate::{node::options, prelude::*};
+use anyhow::{anyhow, bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// How long fragments wait for the rest of their message.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most bytes of incomplete messages kept.
+pub const MAX_PENDING: usize = 64 * 1024 * 1024;
+
+/// Most incomplete messages kept.
+pub const MAX_PARTIAL: usize = 1024;
+
+/// Most incomplete messages kept from one source.
+pub const MAX_PARTIAL_PER_SOURCE: usize = 64;
+
+/// Largest frame, leaving room below the gossipsub transmit size for the
+/// fragment header and the pubsub signature.
+pub const MAX_FRAME: usize = 240 * 1024;
+
+/// Room for the envelope around a payload of the largest size.
+const ENVELOPE_OVERHEAD: usize = 4096;
+
+/// Most fragments of a message.
+const MAX_FRAGMENTS: u32 = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Largest payload published.
+    pub max:   ByteUnit,
+    /// Largest pubsub message, above which envelopes are fragmented.
+    pub frame: ByteUnit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max:   4.mebibytes(),
+            frame: 128.kibibytes(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let size = value
+                .parse::<ByteUnit>()
+                .map_err(|err| anyhow!("Invalid {} {}: {}", key, value, err))?;
+            match key {
+                "max" => config.max = size,
+                "frame" => config.frame = size,
+                _ => bail!("Unknown message size option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.max.as_u64() > 0, "Message size max must be positive");
+        let frame = self.frame.as_u64() as usize;
+        ensure!(
+            (1024..=MAX_FRAME).contains(&frame),
+            "Message size frame must be between 1KiB and {}",
+            MAX_FRAME.bytes()
+        );
+        ensure!(
+            self.fragments(self.max.as_u64() as usize + ENVELOPE_OVERHEAD)
+                <= MAX_FRAGMENTS as usize,
+            "Message size max must be at most {} frames with its envelope",
+            MAX_FRAGMENTS
+        );
+        Ok(())
+    }
+
+    fn fragments(&self, len: usize) -> usize {
+        let frame = self.frame.as_u64() as usize;
+        (len + frame - 1) / frame
+    }
+}
+
+/// A piece of a message too large for one frame.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Fragment {
+    /// Id of the message, shared by its fragments.
+    pub fragment: u64,
+    pub index:    u32,
+    pub count:    u32,
+    #[serde(with = "serde_bytes")]
+    pub data:     Vec<u8>,
+}
+
+impl Fragment {
+    /// Decode a fragment. Returns `None` for other payloads.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        decode(bytes).ok()
+    }
+}
+
+/// Split `message` into encoded fragments of at most `config.frame` bytes
+/// of it, or return it whole if it fits.
+pub fn split(config: &Config, message: Vec<u8>) -> Vec<Vec<u8>> {
+    let frame = config.frame.as_u64() as usize;
+    if message.len() <= frame {
+        return vec![message];
+    }
+    let fragment = rand::random();
+    let count = config.fragments(message.len()) as u32;
+    message
+        .chunks(frame)
+        .enumerate()
+        .map(|(index, data)| {
+            encode(&Fragment {
+                fragment,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            })
+            .expect("Fragments always encode")
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Partial {
+    count:   u32,
+    pieces:  BTreeMap<u32, Vec<u8>>,
+    bytes:   usize,
+    /// When the first fragment arrived.
+    started: Instant,
+}
+
+/// Messages being reassembled, by source, topic and id.
+#[derive(Debug, Default)]
+pub struct Fragments {
+    config:  Config,
+    partial: HashMap<(PeerId, String, u64), Partial>,
+    bytes:   usize,
+}
+
+impl Fragments {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Add a `fragment` from `source` on `topic`, returning the message once
+    /// all of its fragments arrived.
+    pub fn receive(
+        &mut self,
+        source: &PeerId,
+        topic: &str,
+        fragment: Fragment,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        self.expire(now);
+        let limit = self.config.max.as_u64() as usize + ENVELOPE_OVERHEAD;
+        ensure!(
+            fragment.count >= 2 && fragment.count <= MAX_FRAGMENTS,
+            "Message of {} fragments",
+            fragment.count
+        );
+        ensure!(
+            fragment.index < fragment.count,
+            "Fragment {} of {}",
+            fragment.index,
+            fragment.count
+        );
+        ensure!(
+            !fragment.data.is_empty() || fragment.index + 1 == fragment.count,
+            "Empty fragment {} of {}",
+            fragment.index,
+            fragment.count
+        );
+        let key = (source.clone(), topic.to_owned(), fragment.fragment);
+        if !self.partial.contains_key(&key) {
+            self.make_room(source);
+        }
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| {
+            Partial {
+                count:   fragment.count,
+                pieces:  BTreeMap::new(),
+                bytes:   0,
+                started: now,
+            }
+        });
+        if partial.count != fragment.count {
+            self.remove(&key);
+            bail!("Fragments disagree on their count");
+        }
+        if partial.pieces.contains_key(&fragment.index) {
+            return Ok(None);
+        }
+        if partial.bytes + fragment.data.len() > limit {
+            self.remove(&key);
+            bail!("Message over {}", limit.bytes());
+        }
+        partial.bytes += fragment.data.len();
+        self.bytes += fragment.data.len();
+        partial.pieces.insert(fragment.index, fragment.data);
+        if partial.pieces.len() == partial.count as usize {
+            let partial = self.remove(&key).expect("Just inserted");
+            return Ok(Some(partial.pieces.into_iter().flat_map(|(_, data)| data).collect()));
+        }
+        while self.bytes > MAX_PENDING {
+            match self.oldest(|_| true) {
+                Some(oldest) => {
+                    debug!("Dropping fragments of {:?} over the pending limit", oldest);
+                    self.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop the oldest incomplete messages to make room for another from
+    /// `source`.
+    fn make_room(&mut self, source: &PeerId) {
+        let from_source = self.partial.keys().filter(|(peer, ..)| peer == source).count();
+        if from_source >= MAX_PARTIAL_PER_SOURCE {
+            if let Some(oldest) = self.oldest(|peer| peer == source) {
+                debug!("Dropping fragments of {:?} over the per source limit", oldest);
+                self.remove(&oldest);
+            }
+        }
+        if self.partial.len() >= MAX_PARTIAL {
+            if let Some(oldest) = self.oldest(|_| true) {
+                debug!("Dropping fragments of {:?} over the message limit", oldest);
+                self.remove(&oldest);
+            }
+        }
+    }
+
+    /// The oldest incomplete message from a source matching `filter`.
+    fn oldest(&self, filter: impl Fn(&PeerId) -> bool) -> Option<(PeerId, String, u64)> {
+        self.partial
+            .iter()
+            .filter(|((peer, ..), _)| filter(peer))
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(key, _)| key.clone())
+    }
+
+    fn remove(&mut self, key: &(PeerId, String, u64)) -> Option<Partial> {
+        let partial = self.partial.remove(key)?;
+        self.bytes -= partial.bytes;
+        Some(partial)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now.saturating_duration_since(partial.started) > TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            trace!("Fragments of {:?} timed out", key);
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "max=1MiB frame=64KiB".parse().unwrap();
+        assert_eq!(config.max, 1.mebibytes());
+        assert_eq!(config.frame, 64.kibibytes());
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("frame=1MiB".parse::<Config>().is_err());
+        assert!("max=1GiB frame=1KiB".parse::<Config>().is_err());
+
+        // The largest envelope has to fit in the most fragments
+        let config: Config = "max=4092KiB frame=1KiB".parse().unwrap();
+        let envelope = vec![0; config.max.as_u64() as usize + ENVELOPE_OVERHEAD];
+        assert_eq!(split(&config, envelope).len(), MAX_FRAGMENTS as usize);
+        assert!("max=4093KiB frame=1KiB".parse::<Config>().is_err());
+        assert!("max=4MiB frame=1KiB".parse::<Config>().is_err());
+        assert!("size=1".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order() {
+        let config: Config = "max=16KiB frame=1KiB".parse().unwrap();
+        let message: Vec<u8> = (0..5000_u32).map(|i| i as u8).collect();
+        assert_eq!(split(&config, vec![1, 2, 3]), vec![vec![1, 2, 3]]);
+        let mut fragments: Vec<_> = split(&config, message.clone())
+            .iter()
+            .map(|bytes| Fragment::decode(bytes).unwrap())
+            .collect();
+        assert_eq!(fragments.len(), 5);
+        fragments.reverse();
+        fragments.insert(1, fragments[0].clone());
+
+        let (source, now) = (PeerId::random(), Instant::now());
+        let mut receiver = Fragments::new(config);
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert_eq!(receiver.receive(&source, "chat", fragment, now).unwrap(), None);
+        }
+        let other = PeerId::random();
+        assert_eq!(receiver.receive(&other, "chat", last.clone(), now).unwrap(), None);
+        assert_eq!(receiver.receive(&source, "chat", last, now).unwrap(), Some(message));
+        assert_eq!(receiver.bytes, 1024);
+        receiver.expire(now + TIMEOUT * 2);
+        assert_eq!(receiver.bytes, 0);
+    }
+
+    #[test]
+    fn test_drops_oversized_messages() {
+        let config: Config = "max=2KiB frame=1KiB".parse().unwrap();
+        let mut receiver = Fragments::new(config);
+        let (source, now) = (PeerId::random(), Instant::now());
+        let mut fragment = Fragment {
+            fragment: 1,
+            index:    0,
+            count:    8,
+            data:     vec![0; 1024],
+        };
+        for index in 0..6 {
+            fragment.index = index;
+            assert_eq!(receiver.receive(&source, "chat", fragment.clone(), now).unwrap(), None);
+        }
+        fragment.index = 6;
+        assert!(receiver.receive(&source, "chat", fragment.clone(), now).is_err());
+        assert_eq!(receiver.bytes, 0);
+        fragment.index = 8;
+        assert!(receiver.receive(&source, "chat", fragment, now).is_err());
+    }
+
+    #[test]
+    fn test_bounds_incomplete_messages() {
+        let config: Config = "max=2KiB frame=1KiB".parse().unwrap();
+        let mut receiver = Fragments::new(config);
+        let (source, now) = (PeerId::random(), Instant::now());
+        let empty = |id, index| {
+            Fragment {
+                fragment: id,
+                index,
+                count:    MAX_FRAGMENTS,
+                data:     Vec::new(),
+            }
+        };
+        assert!(receiver.receive(&source, "chat", empty(0, 0), now).is_err());
+        assert!(receiver.partial.is_empty());
+
+        for id in 0..MAX_PARTIAL_PER_SOURCE as u64 * 2 {
+            let at = now + Duration::from_millis(id);
+            let last = empty(id, MAX_FRAGMENTS - 1);
+            assert_eq!(receiver.receive(&source, "chat", last, at).unwrap(), None);
+        }
+        assert_eq!(receiver.partial.len(), MAX_PARTIAL_PER_SOURCE);
+        assert!(receiver.partial.keys().all(|(_, _, id)| *id >= MAX_PARTIAL_PER_SOURCE as u64));
+
+        for id in 0..MAX_PARTIAL as u64 {
+            let last = empty(id, MAX_FRAGMENTS - 1);
+            let other = PeerId::random();
+            assert_eq!(receiver.receive(&other, "chat", last, now).unwrap(), None);
+        }
+        assert_eq!(receiver.partial.len(), MAX_PARTIAL);
+    }
+}
@@ -0,0 +1,150 @@
+//! Keepalive probes, see [`crate::node::keepalive`].
+//!
+//! A probe is an empty request answered with an empty response. Peers that do
+//! not speak the protocol are not probed again until they reconnect.
+
+use super::cbor_codec::CborCodec;
+use crate::{
+    node::keepalive::{Config, Schedule},
+    prelude::*,
+};
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::HashSet,
+    iter, mem,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// How long a probe waits for its answer.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/keepalive/version/1"
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Probe {}
+
+pub type Codec = CborCodec<Version, Probe, Probe>;
+
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll_events")]
+pub struct Keepalive {
+    request_response: RequestResponse<Codec>,
+
+    /// Disabled without a schedule.
+    #[behaviour(ignore)]
+    schedule: Option<Schedule>,
+
+    /// Peers without the protocol.
+    #[behaviour(ignore)]
+    unsupported: HashSet<PeerId>,
+
+    /// Peers whose connections are considered dead.
+    #[behaviour(ignore)]
+    dead: Vec<PeerId>,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(PROBE_TIMEOUT);
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            schedule:         None,
+            unsupported:      HashSet::new(),
+            dead:             Vec::new(),
+        }
+    }
+
+    pub fn configure(&mut self, config: Config) {
+        self.schedule = Some(Schedule::new(config));
+    }
+
+    pub fn config(&self) -> Option<&Config> {
+        self.schedule.as_ref().map(Schedule::config)
+    }
+
+    /// Probe those of `peers` that are connected and due. Returns the peers
+    /// whose connections died since the last tick.
+    pub fn tick(&mut self, now: Instant, peers: impl Iterator<Item = PeerId>) -> Vec<PeerId> {
+        let request_response = &self.request_response;
+        self.unsupported
+            .retain(|peer_id| request_response.is_connected(peer_id));
+        let connected = peers
+            .filter(|peer_id| {
+                request_response.is_connected(peer_id) && !self.unsupported.contains(peer_id)
+            })
+            .collect::<Vec<_>>();
+        if let Some(schedule) = &mut self.schedule {
+            for peer_id in schedule.due(now, &connected) {
+                trace!("Sending keepalive probe to {}", peer_id);
+                self.request_response.send_request(&peer_id, Probe {});
+            }
+        }
+        mem::take(&mut self.dead)
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Probe, Probe>> for Keepalive {
+    fn inject_event(&mut self, event: RequestResponseEvent<Probe, Probe>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { channel, .. },
+            } => {
+                if self.request_response.send_response(channel, Probe {}).is_err() {
+                    debug!("Could not answer keepalive probe from {}", peer);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { .. },
+            } => {
+                if let Some(schedule) = &mut self.schedule {
+                    schedule.answered(&peer);
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    debug!("Peer {} does not support keepalive probes", peer);
+                    self.unsupported.insert(peer);
+                    return;
+                }
+                debug!("Keepalive probe to {} failed: {:?}", peer, error);
+                if let Some(schedule) = &mut self.schedule {
+                    if schedule.failed(&peer) {
+                        self.dead.push(peer);
+                    }
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("Keepalive probe from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! mDNS discovery of peers on the local network, see [`Lan`].
+//!
+//! Speaks the libp2p mDNS protocol: every `interval` the node asks
+//! [`GROUP`] for the PTR records of its service name, and answers such
+//! queries with a PTR record naming its peer id and one TXT record
+//! `dnsaddr=<address>/p2p/<peer id>` per listen address, valid for `ttl`.
+//! With the default service name `_p2p._udp.local` it finds every libp2p
+//! node on the network. With a `network` id the answers also carry a TXT
+//! record `network=<id>`, and only peers advertising the same id are
+//! reported.
+
+use crate::{node::discovery::Lan, prelude::*};
+use data_encoding::BASE32_DNSCURVE;
+use dns_parser::{Packet, RData};
+use libp2p::{
+    core::{address_translation, connection::ConnectionId, multiaddr::Protocol},
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+use socket2::{Domain, Socket, Type};
+use std::{
+    convert::TryFrom,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::ReadBuf,
+    net::UdpSocket,
+    time::{sleep_until, Sleep},
+};
+
+/// The mDNS multicast group.
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+pub const PORT: u16 = 5353;
+
+/// Largest packet sent, below the jumbo frames mDNS allows.
+const MAX_PACKET: usize = 9000;
+
+/// Longest value of a TXT record.
+const MAX_TXT: usize = 255;
+
+/// TXT records per answer, leaving room for the header and PTR record.
+const MAX_RECORDS: usize = (MAX_PACKET - 300) / (MAX_TXT + 100);
+
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const IN: u16 = 1;
+/// Class of unique records, which replace cached ones.
+const IN_FLUSH: u16 = 0x8001;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    Discovered(Vec<(PeerId, Multiaddr)>),
+    Expired(Vec<(PeerId, Multiaddr)>),
+}
+
+/// A peer found in an answer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Found {
+    peer:      PeerId,
+    addresses: Vec<Multiaddr>,
+    ttl:       Duration,
+}
+
+/// What a received packet is about.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Received {
+    /// A query for our service, answered with its id.
+    Query(u16),
+    Answer(Vec<Found>),
+}
+
+fn append_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Append `name`, whose labels [`Lan::validate`] checked.
+fn append_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16, additional: u16) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAX_PACKET);
+    for value in &[id, flags, questions, answers, 0, additional] {
+        append_u16(&mut out, *value);
+    }
+    out
+}
+
+/// The query for the PTR records of `service`.
+fn query(service: &str) -> Vec<u8> {
+    let mut out = header(rand::random(), 0, 1, 0, 0);
+    append_name(&mut out, service);
+    append_u16(&mut out, PTR);
+    append_u16(&mut out, IN);
+    out
+}
+
+/// The name of `peer` under `service`, in labels of at most 63 characters.
+fn peer_name(peer: &PeerId, service: &str) -> String {
+    let encoded = BASE32_DNSCURVE.encode(peer.as_bytes());
+    let mut labels = encoded
+        .as_bytes()
+        .chunks(63)
+        .map(|label| String::from_utf8_lossy(label).into_owned())
+        .collect::<Vec<_>>();
+    labels.push(service.to_owned());
+    labels.join(".")
+}
+
+fn txt_record(name: &str, ttl: u32, value: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    append_name(&mut out, name);
+    append_u16(&mut out, TXT);
+    append_u16(&mut out, IN_FLUSH);
+    out.extend_from_slice(&ttl.to_be_bytes());
+    append_u16(&mut out, value.len() as u16 + 1);
+    out.push(value.len() as u8);
+    out.extend_from_slice(value.as_bytes());
+    out
+}
+
+/// The answers to query `id`, with the `addresses` of `peer`.
+fn answers(
+    id: u16,
+    peer: &PeerId,
+    addresses: impl Iterator<Item = Multiaddr>,
+    config: &Lan,
+) -> Vec<Vec<u8>> {
+    let name = peer_name(peer, &config.service);
+    let ttl = u32::try_from(config.ttl.as_secs()).unwrap_or(u32::MAX);
+    let records = addresses
+        .map(|address| format!("dnsaddr={}/p2p/{}", address, peer))
+        .filter(|value| value.len() <= MAX_TXT)
+        .map(|value| txt_record(&name, ttl, &value))
+        .collect::<Vec<_>>();
+    let network = config
+        .network
+        .as_ref()
+        .map(|network| txt_record(&name, ttl, &format!("network={}", network)));
+    let mut chunks = records.chunks(MAX_RECORDS - 1).collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| {
+            let additional = chunk.iter().chain(network.as_ref());
+            let mut out = header(id, 0x8400, 0, 1, additional.clone().count() as u16);
+            append_name(&mut out, &config.service);
+            append_u16(&mut out, PTR);
+            append_u16(&mut out, IN);
+            out.extend_from_slice(&ttl.to_be_bytes());
+            let mut data = Vec::new();
+            append_name(&mut data, &name);
+            append_u16(&mut out, data.len() as u16);
+            out.extend_from_slice(&data);
+            for record in additional {
+                out.extend_from_slice(record);
+            }
+            out
+        })
+        .collect()
+}
+
+/// The peer id in `name`, a PTR record under `service`.
+fn parse_peer_name(name: &str, service: &str) -> Option<PeerId> {
+    let split = name.len().checked_sub(service.len() + 1)?;
+    if !name.is_char_boundary(split)
+        || !name[split + 1..].eq_ignore_ascii_case(service)
+        || !name[split..].starts_with('.')
+    {
+        return None;
+    }
+    let encoded = name[..split].replace('.', "");
+    let bytes = BASE32_DNSCURVE.decode(encoded.as_bytes()).ok()?;
+    PeerId::from_bytes(bytes).ok()
+}
+
+fn parse(bytes: &[u8], config: &Lan) -> Option<Received> {
+    let packet = Packet::parse(bytes).ok()?;
+    let ours = |name: String| name.eq_ignore_ascii_case(&config.service);
+    if packet.header.query {
+        return if packet.questions.iter().any(|q| ours(q.qname.to_string())) {
+            Some(Received::Query(packet.header.id))
+        } else {
+            None
+        };
+    }
+    let mut found = Vec::new();
+    for answer in &packet.answers {
+        let name = match &answer.data {
+            RData::PTR(ptr) if ours(answer.name.to_string()) => ptr.0.to_string(),
+            _ => continue,
+        };
+        let peer = match parse_peer_name(&name, &config.service) {
+            Some(peer) => peer,
+            None => continue,
+        };
+        let mut addresses = Vec::new();
+        let mut network = None;
+        let values = packet
+            .additional
+            .iter()
+            .filter(|record| record.name.to_string() == name)
+            .filter_map(|record| {
+                match &record.data {
+                    RData::TXT(txt) => Some(txt.iter()),
+                    _ => None,
+                }
+            })
+            .flatten()
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .map(|value| value.trim_matches('"'));
+        for value in values {
+            if let Some(address) = value.strip_prefix("dnsaddr=") {
+                let mut address = match address.parse::<Multiaddr>() {
+                    Ok(address) => address,
+                    Err(_) => continue,
+                };
+                if let Some(Protocol::P2p(hash)) = address.pop() {
+                    if PeerId::from_multihash(hash).ok().as_ref() == Some(&peer) {
+                        addresses.push(address);
+                    }
+                }
+            } else if let Some(id) = value.strip_prefix("network=") {
+                network = Some(id.to_owned());
+            }
+        }
+        if config.network.is_some() && network != config.network {
+            trace!("Ignoring {} of network {:?} on LAN", peer, network);
+            continue;
+        }
+        found.push(Found {
+            peer,
+            addresses,
+            ttl: Duration::from_secs(answer.ttl.into()),
+        });
+    }
+    Some(Received::Answer(found))
+}
+
+/// Open the socket on [`PORT`], shared with the other mDNS responders of
+/// this host, received from through tokio and sent to directly.
+fn open() -> io::Result<(UdpSocket, std::net::UdpSocket)> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(socket2::Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    let socket = socket.into_udp_socket();
+    let sender = socket.try_clone()?;
+    Ok((UdpSocket::from_std(socket)?, sender))
+}
+
+pub struct Mdns {
+    config:     Lan,
+    socket:     UdpSocket,
+    sender:     std::net::UdpSocket,
+    next_query: Pin<Box<Sleep>>,
+    /// Addresses of peers found, until they expire.
+    discovered: Vec<(PeerId, Multiaddr, Instant)>,
+    buffer:     Vec<u8>,
+}
+
+impl Mdns {
+    /// Join the mDNS group and query it right away.
+    pub fn new(config: Lan) -> io::Result<Self> {
+        let (socket, sender) = open()?;
+        Ok(Self {
+            config,
+            socket,
+            sender,
+            next_query: Box::pin(sleep_until(tokio::time::Instant::now())),
+            discovered: Vec::new(),
+            buffer: vec![0; MAX_PACKET],
+        })
+    }
+
+    fn send(&self, packet: &[u8]) {
+        if let Err(err) = self.sender.send_to(packet, (GROUP, PORT)) {
+            debug!("Could not send mDNS packet: {}", err);
+        }
+    }
+
+    /// Forget the addresses that expired at `now`.
+    fn expire(&mut self, now: Instant) -> Vec<(PeerId, Multiaddr)> {
+        let mut expired = Vec::new();
+        self.discovered.retain(|(peer, address, expires)| {
+            if *expires > now {
+                return true;
+            }
+            expired.push((peer.clone(), address.clone()));
+            false
+        });
+        expired
+    }
+
+    /// Remember the peers `found` in an answer from `from`, returning the
+    /// addresses new to us.
+    fn discovered(
+        &mut self,
+        found: Vec<Found>,
+        from: SocketAddr,
+        local: &PeerId,
+        now: Instant,
+    ) -> Vec<(PeerId, Multiaddr)> {
+        let observed = Multiaddr::empty()
+            .with(from.ip().into())
+            .with(Protocol::Udp(from.port()));
+        let mut new = Vec::new();
+        for Found {
+            peer,
+            addresses,
+            ttl,
+        } in found
+        {
+            if peer == *local {
+                continue;
+            }
+            let mut all = addresses
+                .iter()
+                .filter_map(|address| address_translation(address, &observed))
+                .collect::<Vec<_>>();
+            all.extend(addresses);
+            for address in all {
+                let known = self
+                    .discovered
+                    .iter_mut()
+                    .find(|(known, known_address, _)| *known == peer && *known_address == address);
+                match known {
+                    Some((_, _, expires)) => *expires = now + ttl,
+                    None => {
+                        self.discovered.push((peer.clone(), address.clone(), now + ttl));
+                        new.push((peer.clone(), address));
+                    }
+                }
+            }
+        }
+        new
+    }
+}
+
+impl NetworkBehaviour for Mdns {
+    type OutEvent = Event;
+    type ProtocolsHandler = DummyProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let now = Instant::now();
+        self.discovered
+            .iter()
+            .filter(|(peer, _, expires)| peer == peer_id && *expires > now)
+            .map(|(_, address, _)| address.clone())
+            .collect()
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection: ConnectionId,
+        _event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>,
+    > {
+        let now = Instant::now();
+        if self.next_query.as_mut().poll(cx).is_ready() {
+            self.send(&query(&self.config.service));
+            let next = tokio::time::Instant::now() + self.config.interval;
+            self.next_query = Box::pin(sleep_until(next));
+            let _ = self.next_query.as_mut().poll(cx);
+            let expired = self.expire(now);
+            if !expired.is_empty() {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(Event::Expired(expired)));
+            }
+        }
+        loop {
+            let mut read = ReadBuf::new(&mut self.buffer);
+            let from = match self.socket.poll_recv_from(cx, &mut read) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(from)) => from,
+                Poll::Ready(Err(err)) => {
+                    debug!("Could not receive mDNS packet: {}", err);
+                    continue;
+                }
+            };
+            let bytes = read.filled().to_vec();
+            match parse(&bytes, &self.config) {
+                Some(Received::Query(id)) => {
+                    let local = params.local_peer_id().clone();
+                    for packet in answers(id, &local, params.listened_addresses(), &self.config) {
+                        self.send(&packet);
+                    }
+                }
+                Some(Received::Answer(found)) => {
+                    let local = params.local_peer_id().clone();
+                    let new = self.discovered(found, from, &local, now);
+                    if !new.is_empty() {
+                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                            Event::Discovered(new),
+                        ));
+                    }
+                }
+                None => trace!("Ignoring mDNS packet from {}", from),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_answers_queries_of_the_same_network() {
+        let config = Lan {
+            service: "_mesh._udp.local".into(),
+            network: Some("prod".into()),
+            ..Lan::default()
+        };
+        let packet = query(&config.service);
+        let id = u16::from_be_bytes([packet[0], packet[1]]);
+        assert_eq!(parse(&packet, &config), Some(Received::Query(id)));
+        assert_eq!(parse(&packet, &Lan::default()), None);
+
+        let peer = PeerId::random();
+        let address: Multiaddr = "/ip4/192.168.1.2/tcp/4001".parse().unwrap();
+        let addresses = vec![address; MAX_RECORDS];
+        let packets = answers(id, &peer, addresses.into_iter(), &config);
+        assert_eq!(packets.len(), 2);
+        let found = |config: &Lan| {
+            packets
+                .iter()
+                .filter_map(|packet| {
+                    match parse(packet, config) {
+                        Some(Received::Answer(found)) => Some(found),
+                        _ => None,
+                    }
+                })
+                .flatten()
+                .map(|found| (found.peer, found.addresses.len(), found.ttl))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(found(&config), vec![
+            (peer.clone(), MAX_RECORDS - 1, config.ttl),
+            (peer, 1, config.ttl),
+        ]);
+        let other = Lan {
+            network: Some("staging".into()),
+            ..config
+        };
+        assert_eq!(found(&other), vec![]);
+        assert_eq!(found(&Lan::default()), vec![]);
+    }
+}
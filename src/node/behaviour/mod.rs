@@ -6,6 +6,16 @@
 //! * `/meshsub/1.0.0` (aka gossipsub)
 //! * `/0x-mesh-dht/version/1` (aka kademlia)
 //! * `/0x-mesh/order-sync/version/0`
+//! * `/mesh-rs/direct/version/1`
+//! * `/mesh-rs/service/version/1`
+//! * `/mesh-rs/blob/version/1`
+//! * `/mesh-rs/diagnostics/version/1`
+//! * `/mesh-rs/keepalive/version/1`
+//! * `/mesh-rs/dtn/version/1`
+//! * `/mesh-rs/rpc/version/1`
+//! * `/mesh-rs/autonat/version/1`
+//! * `/mesh-rs/rendezvous/version/1`
+//! * `/mesh-rs/file/version/1`
 //!
 //! Missing protocols:
 //!
@@ -14,37 +24,405 @@
 //! * `/libp2p/circuit/relay/0.1.0
 //! * `/floodsub/1.0.0`
 
+pub mod autonat;
+pub mod blob;
+mod cbor_codec;
+pub mod codec;
+pub mod diagnostics;
+pub mod direct;
+pub mod duplicate;
 pub mod discovery;
+pub mod dtn;
+pub mod envelope;
+pub mod file;
+pub mod fragment;
+pub mod keepalive;
+pub mod mdns;
+pub mod multicast;
+pub mod multipath;
+mod namespace;
 pub mod order_sync;
 pub mod pubsub;
+pub mod reliable;
+mod reopen;
+pub mod rendezvous;
+pub mod rpc;
+pub mod service;
 
-use self::{discovery::{Discovery, PeerInfo}, order_sync::OrderSync, pubsub::PubSub};
-use crate::prelude::*;
-use futures::channel::oneshot;
+use self::{
+    autonat::AutoNat,
+    blob::{BlobId, Blobs},
+    codec::Codec,
+    diagnostics::{BundleRequest, Diagnostics},
+    direct::Direct,
+    discovery::{Discovery, PeerStore},
+    duplicate::Duplicates,
+    dtn::Dtn,
+    envelope::{Envelope, Provenance},
+    file::Files,
+    fragment::{Fragment, Fragments},
+    keepalive::Keepalive,
+    multicast::Multicast,
+    multipath::Multipath,
+    namespace::Namespace,
+    order_sync::OrderSync,
+    pubsub::PubSub,
+    reliable::{Ack, Reliable},
+    rendezvous::Rendezvous,
+    rpc::{Rpc, RpcRequest},
+    service::{Service, ServiceDescriptor, ServiceRequest},
+};
+use crate::{
+    node::{
+        autonat::{NatStatus, Reachability},
+        capability::{Capabilities, Capability},
+        degrade::Subsystem,
+        dial::Attempt,
+        duplicate::Policy as DuplicatePolicy,
+        discovery::{record_key, Dht, Lan, Provided, QueryKind},
+        dtn::{BundleId, Evicted, Store, Trace},
+        expiry::{self, Drops, Limits},
+        file::{Config as FileConfig, FileId, Manifest, Progress},
+        hlc::{Hlc, Timestamp},
+        keepalive::{Config as KeepaliveConfig, Peers},
+        latency,
+        lifecycle::Kind,
+        mismatch::Policy,
+        naming,
+        negotiation::Reason,
+        rotation::Migration,
+        scoring, spans,
+        verification::{self, Verification},
+    },
+    prelude::*,
+};
+use futures::channel::{mpsc, oneshot};
 use libp2p::{
-    identity::Keypair, request_response, swarm::NetworkBehaviourEventProcess, NetworkBehaviour,
-    PeerId,
+    core::ConnectedPoint,
+    gossipsub::error::PublishError,
+    identity::Keypair,
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    Multiaddr, NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+
+/// Events emitted by the node behaviour.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A pubsub payload arrived, either through the gossip mesh or delivered
+    /// directly by the sender (`direct`). The `timestamp` is the sender's
+    /// hybrid logical clock, if the payload came in an envelope. Republished
+    /// messages come from their origin, through the relays in `provenance`.
+    Message {
+        source:     PeerId,
+        topic:      String,
+        data:       Vec<u8>,
+        direct:     bool,
+        timestamp:  Option<Timestamp>,
+        provenance: Provenance,
+        /// Whether `source` signed the message, or the last relay if it was
+        /// republished, see [`crate::node::verification`].
+        signed:     bool,
+    },
+
+    /// A payload of no topic `source` sent to us alone, see
+    /// [`direct`].
+    DirectMessage { source: PeerId, data: Vec<u8> },
+
+    /// A payload published before we subscribed, as an archiver kept it, see
+    /// [`crate::node::archive`]. Backfills arrive before live messages.
+    Historical {
+        source:    PeerId,
+        topic:     String,
+        data:      Vec<u8>,
+        timestamp: Option<Timestamp>,
+    },
+
+    /// The action `name` scheduled by `issuer` is due, `late` by this
+    /// much, see [`crate::node::schedule`].
+    Scheduled {
+        issuer: PeerId,
+        name:   String,
+        data:   Vec<u8>,
+        late:   Duration,
+    },
+
+    /// `peer`, running `agent`, announced it joined, is leaving or was
+    /// upgraded, see [`crate::node::lifecycle`].
+    Announced {
+        peer:  PeerId,
+        kind:  Kind,
+        agent: String,
+    },
+
+    /// The wall clock moved `offset_ms` more than the monotonic clock since
+    /// the previous tick, see [`crate::node::clock`].
+    ClockJump { offset_ms: i64 },
+
+    /// The node went on without `subsystem`, which failed with `error`, see
+    /// [`crate::node::degrade`].
+    SubsystemDegraded {
+        subsystem: Subsystem,
+        error:     String,
+    },
+
+    /// A second connection to `peer` was closed, see
+    /// [`crate::node::duplicate`]. `endpoint` is the closed one.
+    DuplicateClosed {
+        peer:     PeerId,
+        endpoint: ConnectedPoint,
+    },
+
+    /// A full store evicted a bundle to make room, see
+    /// [`crate::node::dtn`].
+    BundleEvicted(Evicted),
+
+    /// A connection, or a substream of a behaviour, could not be upgraded,
+    /// see [`crate::node::negotiation`].
+    NegotiationFailed {
+        peer:    Option<PeerId>,
+        address: Option<Multiaddr>,
+        reason:  Reason,
+        error:   String,
+    },
+
+    /// Dialing `peer`, or an address of an unknown peer, failed at every
+    /// address tried, see [`crate::node::dial`].
+    DialFailed {
+        peer:     Option<PeerId>,
+        attempts: Vec<Attempt>,
+    },
+
+    /// Dialing `address` of `expected` reached `actual` instead, see
+    /// [`crate::node::mismatch`].
+    IdentityMismatch {
+        expected: PeerId,
+        actual:   PeerId,
+        address:  Multiaddr,
+        policy:   Policy,
+    },
+
+    /// The `previous` peer rotated its identity to `current`, see
+    /// [`crate::node::rotation`].
+    IdentityRotated { previous: PeerId, current: PeerId },
+
+    /// `peer_id`, going by `nickname`, came online on `topic`, see
+    /// [`crate::node::presence`].
+    PresenceJoined {
+        peer_id:  PeerId,
+        topic:    String,
+        nickname: String,
+    },
+
+    /// `peer_id` left `topic` or went silent, see [`crate::node::presence`].
+    PresenceLeft { peer_id: PeerId, topic: String },
+
+    /// A peer set `key` of the replicated `map` to `value`, or removed it
+    /// without one, see [`crate::node::replica`].
+    StateChanged {
+        map:   String,
+        key:   String,
+        value: Option<Vec<u8>>,
+    },
+
+    /// Enough bootstrap `peers` answered, `elapsed` after the node started,
+    /// see [`crate::node::bootstrap`].
+    Bootstrapped {
+        peers:   Vec<PeerId>,
+        elapsed: Duration,
+    },
+
+    /// mDNS found `peer` at `address` on the local network.
+    PeerDiscovered { peer: PeerId, address: Multiaddr },
+
+    /// A rendezvous point told us `peer` registered under `namespace` at
+    /// `addresses`, see [`crate::node::rendezvous`].
+    RendezvousDiscovered {
+        namespace: String,
+        peer:      PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+
+    /// A file transfer progressed, see [`crate::node::file`].
+    FileTransfer(Progress),
+
+    /// The mDNS record of `peer` at `address` expired.
+    PeerExpired { peer: PeerId, address: Multiaddr },
+
+    /// `peer` failed `failures` pings in a row and was evicted, see
+    /// [`crate::node::latency`].
+    PeerUnresponsive { peer: PeerId, failures: u32 },
+
+    /// The connections to `peer` were closed for `reason`, see
+    /// [`crate::node::health`].
+    ConnectionReaped {
+        peer:   PeerId,
+        reason: crate::node::health::Reason,
+    },
+
+    /// Peers dialing us back found us `reachability` at `addresses`, see
+    /// [`crate::node::autonat`].
+    NatStatusChanged {
+        reachability: Reachability,
+        addresses:    Vec<Multiaddr>,
+    },
+
+    /// A connected `peer` subscribed to `topic`.
+    Subscribed { peer: PeerId, topic: String },
+
+    /// A connected `peer` unsubscribed from `topic`.
+    Unsubscribed { peer: PeerId, topic: String },
+
+    /// We listen on `address`, a new one of a listener.
+    ListenAddr { address: Multiaddr },
+
+    /// We stopped listening on `address`.
+    ListenAddrExpired { address: Multiaddr },
+
+    /// A DHT query for `kind` sent `requests`, of which `successes` were
+    /// answered and `failures` were not, in `elapsed`. Bootstrap queries
+    /// report once per bucket they refresh, until `finished`.
+    DhtQuery {
+        kind:      QueryKind,
+        requests:  u32,
+        successes: u32,
+        failures:  u32,
+        elapsed:   Duration,
+        finished:  bool,
+    },
+}
+
+impl Event {
+    /// `peer` does not speak one of our request-response `protocol`s.
+    pub(crate) fn unsupported(peer: PeerId, protocol: &[u8]) -> Self {
+        Self::NegotiationFailed {
+            peer:    Some(peer),
+            address: None,
+            reason:  Reason::UnsupportedProtocol,
+            error:   format!("Unsupported protocol {}", String::from_utf8_lossy(protocol)),
+        }
+    }
+}
 
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
 pub struct Behaviour {
-    discovery:  Discovery,
-    pubsub:     PubSub,
-    order_sync: OrderSync,
+    discovery:   Discovery,
+    pubsub:      PubSub,
+    order_sync:  OrderSync,
+    direct:      Direct,
+    service:     Service,
+    blobs:       Blobs,
+    multipath:   Multipath,
+    diagnostics: Diagnostics,
+    keepalive:   Keepalive,
+    dtn:         Dtn,
+    rpc:         Rpc,
+    autonat:     AutoNat,
+    rendezvous:  Rendezvous,
+    files:       Files,
+    duplicates:  Duplicates,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+
+    #[behaviour(ignore)]
+    clock: Hlc,
+
+    #[behaviour(ignore)]
+    namespace: Option<Namespace>,
+
+    #[behaviour(ignore)]
+    multicast: Multicast,
+
+    /// Messages split into frames being put back together.
+    #[behaviour(ignore)]
+    fragments: Fragments,
+
+    #[behaviour(ignore)]
+    compression: codec::Config,
+
+    /// Signs envelopes and provenance hops.
+    #[behaviour(ignore)]
+    key: Keypair,
+
+    #[behaviour(ignore)]
+    verification: verification::Policy,
+
+    /// Restricted topics and our capabilities, by wire topic.
+    #[behaviour(ignore)]
+    capabilities: Capabilities,
+
+    /// Sequence numbers and acknowledgements of acked topics, by wire topic.
+    #[behaviour(ignore)]
+    reliable: Reliable,
+
+    /// Expiry and hop limit of our messages, see [`crate::node::expiry`].
+    #[behaviour(ignore)]
+    expiry: expiry::Config,
+
+    /// Messages dropped for their expiry or hop limit.
+    #[behaviour(ignore)]
+    drops: Drops,
+
+    /// Publish payloads without an envelope, see [`crate::node::compat`].
+    #[behaviour(ignore)]
+    bare: bool,
 }
 
 impl Behaviour {
     pub async fn new(peer_key: Keypair) -> Result<Self> {
         let discovery = Discovery::new(peer_key.clone()).await?;
-        let pubsub = PubSub::new(peer_key);
+        let multicast = Multicast::new(peer_key.clone());
+        let dtn = Dtn::new(peer_key.clone());
+        let pubsub = PubSub::new(peer_key.clone());
         let order_sync = OrderSync::new();
+        let direct = Direct::new();
+        let service = Service::new(discovery.known_peers());
+        let blobs = Blobs::new();
+        let multipath = Multipath::new(discovery.known_peers());
+        let diagnostics = Diagnostics::new();
+        let keepalive = Keepalive::new();
+        let rpc = Rpc::new();
+        let autonat = AutoNat::new();
+        let rendezvous = Rendezvous::new();
+        let files = Files::new();
+        let duplicates = Duplicates::new(PeerId::from(peer_key.public()));
 
         Ok(Self {
             discovery,
             pubsub,
             order_sync,
+            direct,
+            service,
+            blobs,
+            multipath,
+            diagnostics,
+            keepalive,
+            dtn,
+            rpc,
+            autonat,
+            rendezvous,
+            files,
+            duplicates,
+            events: VecDeque::new(),
+            clock: Hlc::default(),
+            namespace: None,
+            multicast,
+            fragments: Fragments::default(),
+            compression: codec::Config::default(),
+            key: peer_key,
+            verification: verification::Policy::default(),
+            capabilities: Capabilities::default(),
+            reliable: Reliable::default(),
+            expiry: expiry::Config::default(),
+            drops: Drops::default(),
+            bare: false,
         })
     }
 
@@ -63,12 +441,956 @@ impl Behaviour {
         self.order_sync.send(peer_id, request, sender);
     }
 
+    /// Put all topics used from here on in the application namespace `name`.
+    pub fn set_namespace(&mut self, name: &str) {
+        self.namespace = Some(Namespace::new(name));
+    }
+
+    fn wire_topic(&self, topic: &str) -> String {
+        self.namespace
+            .as_ref()
+            .map_or_else(|| topic.to_owned(), |namespace| namespace.wire(topic))
+    }
+
+    pub fn subscribe(&mut self, topic: &str) -> bool {
+        let topic = self.wire_topic(topic);
+        self.pubsub.subscribe(&topic)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> bool {
+        let topic = self.wire_topic(topic);
+        self.pubsub.unsubscribe(&topic)
+    }
+
+    /// Publish and receive `topic` by LAN [`multicast`], or stop doing so.
+    pub fn set_multicast(&mut self, topic: &str, enabled: bool) {
+        let topic = self.wire_topic(topic);
+        self.multicast.set_topic(&topic, enabled);
+    }
+
+    /// Number our messages on `topic` and send them again until the
+    /// subscribers acknowledge them, or stop doing so, see [`reliable`].
+    pub fn set_acked(&mut self, topic: &str, enabled: bool) {
+        let topic = self.wire_topic(topic);
+        self.reliable.set_topic(&topic, enabled);
+    }
+
+    /// Retry and deduplicate acknowledged messages as configured, see
+    /// [`reliable`].
+    pub fn set_reliable(&mut self, config: reliable::Config) {
+        self.reliable.set_config(config);
+    }
+
+    /// Send the messages due again to the peers that did not acknowledge
+    /// them, see [`reliable`].
+    pub fn tick_reliable(&mut self, now: Instant) {
+        for retransmit in self.reliable.due(now) {
+            let sent = self
+                .direct
+                .publish_to(&retransmit.peers, &retransmit.topic, &retransmit.data);
+            debug!(
+                "Resent message {} on {} to {} of {} peers",
+                retransmit.seq,
+                retransmit.topic,
+                sent.len(),
+                retransmit.peers.len()
+            );
+        }
+    }
+
+    /// See [`PubSub::duplicates`].
+    pub fn pubsub_duplicates(&self) -> u64 {
+        self.pubsub.duplicates()
+    }
+
+    pub fn mesh_peer_count(&self, topic: &str) -> usize {
+        self.pubsub.mesh_peer_count(&self.wire_topic(topic))
+    }
+
+    /// Use the pubsub protocol of `config`, see [`crate::node::pubsub`].
+    /// Call before [`Self::start`].
+    pub fn configure_pubsub(&mut self, config: &crate::node::pubsub::Config) {
+        self.pubsub.configure(config);
+        self.bare = config.bare;
+    }
+
+    /// Rate limit and score the peers messages come from, see
+    /// [`crate::node::scoring`].
+    pub fn set_scoring(&mut self, config: scoring::Config) {
+        self.pubsub.set_scoring(config);
+    }
+
+    pub fn peer_score(&self, peer_id: &PeerId) -> Option<scoring::Score> {
+        self.pubsub.score(peer_id)
+    }
+
+    /// `peer_id` sent an invalid message.
+    pub fn penalize(&mut self, peer_id: &PeerId) {
+        self.pubsub.penalize(peer_id);
+    }
+
+    /// The peers to disconnect or ban for their scores.
+    pub fn tick_scores(&mut self, now: Instant) -> Vec<(PeerId, scoring::Verdict)> {
+        self.pubsub.verdicts(now)
+    }
+
+    pub fn pubsub_connected(&mut self, peer_id: &PeerId) {
+        self.pubsub.connected(peer_id);
+    }
+
+    pub fn pubsub_disconnected(&mut self, peer_id: &PeerId) {
+        self.pubsub.disconnected(peer_id);
+    }
+
+    /// Compress payloads for the peers that decode them, see [`codec`].
+    pub fn set_compression(&mut self, config: codec::Config) {
+        self.compression = config;
+    }
+
+    /// Compress `data` for all of `recipients`, if they share a codec and it
+    /// makes `data` smaller.
+    fn compress(&self, data: &[u8], recipients: &[PeerId]) -> (Option<Codec>, Vec<u8>) {
+        let known_peers = self.known_peers();
+        let advertised: Vec<_> = {
+            let known_peers = known_peers.read().unwrap(); // FIXME: Can block
+            recipients
+                .iter()
+                .map(|peer_id| {
+                    let identify = known_peers.get(peer_id).and_then(|info| info.identify.as_ref());
+                    identify.map_or_else(Vec::new, |identify| {
+                        codec::advertised(&identify.agent_version)
+                    })
+                })
+                .collect()
+        };
+        let codec = match self.compression.choose(data.len(), &advertised) {
+            Some(codec) => codec,
+            None => return (None, data.to_vec()),
+        };
+        match codec.compress(data) {
+            Ok(compressed) if compressed.len() < data.len() => (Some(codec), compressed),
+            Ok(_) => (None, data.to_vec()),
+            Err(err) => {
+                debug!("Sending {} bytes uncompressed: {:#}", data.len(), err);
+                (None, data.to_vec())
+            }
+        }
+    }
+
+    /// Wrap `data` in an envelope with `limits` signed for `topic`, storing
+    /// it as a blob if it is large and compressing it for `recipients`.
+    /// Returns the envelope and whether the blob was stored before.
+    fn envelope(
+        &mut self,
+        topic: &str,
+        data: &[u8],
+        recipients: &[PeerId],
+        seq: Option<u64>,
+        limits: Limits,
+    ) -> (Envelope, bool) {
+        let (blob, known) = if data.len() < blob::THRESHOLD {
+            (None, false)
+        } else {
+            let id = BlobId::of(data);
+            let known = self.blobs.store().get(&id).is_some();
+            self.blobs.store().retain(id.clone(), data);
+            (Some(id), known)
+        };
+        let (codec, data) = self.compress(data, recipients);
+        let mut envelope = Envelope {
+            timestamp: self.clock.now(),
+            blob,
+            codec,
+            data,
+            provenance: Provenance::default(),
+            capability: self.capabilities.token(topic).cloned(),
+            seq,
+            expires: limits.expires,
+            hops: limits.hops,
+            signature: None,
+        };
+        if let Err(err) = envelope.sign(&self.key, topic) {
+            warn!("Sending unsigned message on {}: {:#}", topic, err);
+        }
+        (envelope, known)
+    }
+
+    /// Publish to the gossip mesh, or by [`multicast`] for multicast topics.
+    ///
+    /// A large payload that was published before is sent by reference only.
+    /// Envelopes over the frame size go out in [`fragment`]s. Bare payloads,
+    /// see [`crate::node::compat`], go out as they are.
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), PublishError> {
+        self.republish(topic, data, Provenance::default())
+    }
+
+    /// Add our hop to the `provenance` of `payload`, received from `from`,
+    /// to republish it. Fails for messages past their expiry or hop limit,
+    /// see [`crate::node::expiry`].
+    pub fn relay_hop(
+        &mut self,
+        provenance: &Provenance,
+        payload: &[u8],
+        from: &PeerId,
+    ) -> Result<Provenance> {
+        let now_ms = expiry::epoch_ms(SystemTime::now());
+        if let Err(exceeded) = provenance.limits().forward(now_ms) {
+            self.drops.count(exceeded);
+            return Err(exceeded.into());
+        }
+        provenance.extend(&self.key, payload, from)
+    }
+
+    /// Publish like [`Behaviour::publish`], with the `provenance` of a
+    /// relayed message.
+    pub fn republish(
+        &mut self,
+        topic: &str,
+        data: &[u8],
+        provenance: Provenance,
+    ) -> Result<(), PublishError> {
+        let friendly = topic;
+        let topic = self.wire_topic(topic);
+        if self.bare {
+            return self.pubsub.publish(&topic, data);
+        }
+        let subscribers = self.pubsub.subscribers(&topic);
+        let seq = self.reliable.next_seq(&topic);
+        // Relays pass on the limits of the origin, less their hop
+        let limits = if provenance.is_empty() {
+            self.expiry.limits(SystemTime::now())
+        } else {
+            let now_ms = expiry::epoch_ms(SystemTime::now());
+            provenance.limits().forward(now_ms).unwrap_or_default()
+        };
+        let (mut envelope, known) = self.envelope(&topic, data, &subscribers, seq, limits);
+        let local = PeerId::from(self.key.public());
+        let span = spans::publish(&local, envelope.timestamp, friendly);
+        let _entered = span.enter();
+        envelope.provenance = provenance;
+        let bytes = envelope.to_bytes();
+        if let Some(seq) = seq {
+            self.reliable.sent(&topic, seq, bytes.clone(), &subscribers, Instant::now());
+        }
+        if self.multicast.is_multicast(&topic, &bytes) {
+            match self.multicast.publish(&topic, &bytes) {
+                Ok(()) => return Ok(()),
+                Err(err) => debug!("Publishing on {} through gossip: {:#}", topic, err),
+            }
+        }
+        if known {
+            envelope.data.clear();
+        }
+        for frame in fragment::split(self.fragments.config(), envelope.to_bytes()) {
+            self.pubsub.publish(&topic, &frame)?;
+        }
+        Ok(())
+    }
+
+    /// Put an expiry and hop limit in our messages, see
+    /// [`crate::node::expiry`].
+    pub fn set_expiry(&mut self, config: expiry::Config) {
+        self.expiry = config;
+    }
+
+    /// Messages dropped for their expiry or hop limit so far.
+    pub const fn expiry_drops(&self) -> Drops {
+        self.drops
+    }
+
+    /// Limit payloads and split large envelopes into frames, see
+    /// [`fragment`].
+    pub fn set_message_size(&mut self, config: fragment::Config) {
+        self.fragments = Fragments::new(config);
+    }
+
+    /// Largest payload published, see [`fragment`].
+    pub fn max_message_size(&self) -> usize {
+        self.fragments.config().max.as_u64() as usize
+    }
+
+    /// Send `data` to `peer_id` alone, see [`Direct::send_to`].
+    pub fn send_to(
+        &mut self,
+        peer_id: &PeerId,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    ) {
+        self.direct.send_to(peer_id, data, sender);
+    }
+
+    /// Publish directly to a subset of connected peers, bypassing gossip.
+    ///
+    /// Peers known to have a large payload receive it by reference only.
+    pub fn publish_to(&mut self, peers: &[PeerId], topic: &str, data: &[u8]) -> Vec<PeerId> {
+        let topic = self.wire_topic(topic);
+        let limits = self.expiry.limits(SystemTime::now());
+        let (mut envelope, _) = self.envelope(&topic, data, peers, None, limits);
+        let id = match envelope.blob.clone() {
+            Some(id) => id,
+            None => return self.direct.publish_to(peers, &topic, &envelope.to_bytes()),
+        };
+        let store = self.blobs.store();
+        let (holders, others): (Vec<_>, Vec<_>) = peers
+            .iter()
+            .cloned()
+            .partition(|peer_id| store.has_holder(&id, peer_id));
+        let mut sent = self.direct.publish_to(&others, &topic, &envelope.to_bytes());
+        for peer_id in &sent {
+            self.blobs.store().add_holder(&id, peer_id.clone());
+        }
+        envelope.data.clear();
+        sent.extend(self.direct.publish_to(&holders, &topic, &envelope.to_bytes()));
+        sent
+    }
+
+    /// Resend failed direct messages to the peers we reconnected to, see
+    /// [`direct`].
+    pub fn resend_unacked(&mut self) {
+        self.direct.resend_unacked();
+    }
+
+    /// Keep at most `capacity` bytes of large payloads, see [`blob`].
+    pub fn set_blob_capacity(&mut self, capacity: usize) {
+        self.blobs.store().set_capacity(capacity);
+    }
+
+    /// Timestamp a local event with the node's hybrid logical clock.
+    pub fn timestamp(&mut self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// Widen the clock drift window after our wall clock jumped back by
+    /// `jump_ms`, see [`Hlc::rebaseline`].
+    pub fn rebaseline_clock(&mut self, jump_ms: u64, grace: Duration) {
+        self.clock.rebaseline(jump_ms, grace);
+    }
+
+    /// Start providing the service of `descriptor`, with calls sent to
+    /// `handler`.
+    pub fn advertise_service(
+        &mut self,
+        descriptor: ServiceDescriptor,
+        handler: mpsc::Sender<ServiceRequest>,
+    ) {
+        self.service.advertise(descriptor, handler);
+    }
+
+    /// See [`Service::find`].
+    pub fn find_services(&self, service: &str) -> Vec<(PeerId, ServiceDescriptor)> {
+        self.service.find(service)
+    }
+
+    pub fn withdraw_service(&mut self, service: &str) {
+        self.service.withdraw(service);
+    }
+
+    /// Route a call to the nearest provider of `service`.
+    pub fn call_service(
+        &mut self,
+        service: String,
+        data: Vec<u8>,
+        sender: oneshot::Sender<service::Result>,
+    ) {
+        self.service.call(service, data, sender);
+    }
 
-    pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
+    /// Hand a job to one consumer of a work queue.
+    pub fn push_job(
+        &mut self,
+        queue: &str,
+        key: &str,
+        data: Vec<u8>,
+        visibility_timeout: Duration,
+        sender: oneshot::Sender<service::Result>,
+    ) {
+        self.service
+            .push_job(queue, key, data, visibility_timeout, sender);
+    }
+
+    /// Send `data` to `peer_id` and wait for its reply.
+    pub fn request(
+        &mut self,
+        peer_id: &PeerId,
+        data: Vec<u8>,
+        sender: oneshot::Sender<rpc::Result>,
+    ) {
+        self.rpc.request(peer_id, data, sender);
+    }
+
+    /// Send inbound requests to `handler`.
+    pub fn serve_requests(&mut self, handler: mpsc::Sender<RpcRequest>) {
+        self.rpc.serve(handler);
+    }
+
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.rpc.set_timeout(timeout);
+    }
+
+    /// Send debug bundle requests from authorized peers to `handler`.
+    pub fn serve_diagnostics(&mut self, handler: mpsc::Sender<BundleRequest>) {
+        self.diagnostics.serve(handler);
+    }
+
+    /// Let `peer_id` retrieve our debug bundle.
+    pub fn authorize_diagnostics(&mut self, peer_id: PeerId) {
+        self.diagnostics.authorize(peer_id);
+    }
+
+    /// Retrieve the debug bundle of `peer_id`.
+    pub fn fetch_bundle(&mut self, peer_id: &PeerId, sender: oneshot::Sender<diagnostics::Result>) {
+        self.diagnostics.fetch(peer_id, sender);
+    }
+
+    pub fn known_peers(&self) -> PeerStore {
         self.discovery.known_peers()
     }
+
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.discovery.add_address(peer_id, address);
+    }
+
+    pub fn move_address(&mut self, old: &PeerId, new: &PeerId, address: Multiaddr) {
+        self.discovery.move_address(old, new, address);
+    }
+
+    /// Keep redundant connections to `peer_id`, see [`multipath`].
+    pub fn add_critical_peer(&mut self, peer_id: PeerId, address: Multiaddr) {
+        self.discovery.add_address(&peer_id, address.clone());
+        self.duplicates.exempt(peer_id.clone());
+        self.multipath.add_critical(peer_id, address);
+    }
+
+    pub fn tick_multipath(&mut self, now: Instant) {
+        self.multipath.tick(now);
+    }
+
+    /// What to do with messages that are not validly signed.
+    pub fn set_verification(&mut self, policy: verification::Policy) {
+        self.verification = policy;
+    }
+
+    /// Only deliver messages on `topic` from `owner` and the holders of its
+    /// capabilities, see [`crate::node::capability`].
+    pub fn restrict_topic(&mut self, topic: &str, owner: PeerId) -> bool {
+        let topic = self.wire_topic(topic);
+        self.capabilities.restrict(&topic, owner)
+    }
+
+    pub fn unrestrict_topic(&mut self, topic: &str) -> bool {
+        let topic = self.wire_topic(topic);
+        self.capabilities.unrestrict(&topic)
+    }
+
+    /// Sign a capability for `holder` to publish on `topic`.
+    pub fn issue_capability(
+        &self,
+        topic: &str,
+        holder: &PeerId,
+        expires: Option<SystemTime>,
+    ) -> Result<Capability> {
+        Capability::issue(&self.key, &self.wire_topic(topic), holder, expires)
+    }
+
+    /// Attach `capability` to our messages on its topic.
+    pub fn add_capability(&mut self, capability: Capability) -> Result<()> {
+        let local = PeerId::from(self.key.public());
+        self.capabilities.hold(capability, &local, SystemTime::now())
+    }
+
+    /// Whether we may publish on `topic`, owning it if it is restricted or
+    /// holding an unexpired capability.
+    pub fn may_publish(&self, topic: &str) -> Result<()> {
+        let local = PeerId::from(self.key.public());
+        self.capabilities
+            .may_publish(&self.wire_topic(topic), &local, SystemTime::now())
+    }
+
+    /// Check the capability of a message signed as `verification` by
+    /// `source` on the wire `topic`, if it is restricted.
+    fn authorize(
+        &self,
+        topic: &str,
+        source: &PeerId,
+        envelope: Option<&Envelope>,
+        verification: Verification,
+    ) -> Result<()> {
+        if self.capabilities.owner(topic).is_none() {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            verification == Verification::Valid,
+            "{:?} message on a restricted topic",
+            verification
+        );
+        let capability = envelope.and_then(|envelope| envelope.capability.as_ref());
+        self.capabilities
+            .authorize(topic, source, capability, SystemTime::now())
+    }
+
+    /// See [`Discovery::set_dht`].
+    pub fn set_dht(&mut self, dht: Dht) {
+        self.discovery.set_dht(dht);
+    }
+
+    /// See [`Discovery::set_dial_preference`].
+    pub fn set_dial_preference(&mut self, dial: crate::node::family::Dial) {
+        self.discovery.set_dial_preference(dial);
+    }
+
+    /// See [`Discovery::set_ping`].
+    pub fn set_ping(&mut self, config: latency::Config) {
+        self.discovery.set_ping(config);
+    }
+
+    /// Refresh the DHT routing table and republish provided keys when due.
+    pub fn tick_discovery(&mut self, now: Instant) {
+        self.discovery.tick(now);
+    }
+
+    pub fn start_dht(&mut self, now: Instant) {
+        self.discovery.start_dht(now);
+    }
+
+    pub fn find_peer(&mut self, peer_id: PeerId, sender: oneshot::Sender<Result<Vec<Multiaddr>>>) {
+        self.discovery.find_peer(peer_id, sender);
+    }
+
+    pub fn closest_peers(&mut self, key: Vec<u8>, sender: oneshot::Sender<Result<Vec<PeerId>>>) {
+        self.discovery.closest_peers(key, sender);
+    }
+
+    /// Sign a record of `name` pointing at us and store it in the DHT, see
+    /// [`naming`].
+    pub fn publish_name(
+        &mut self,
+        name: &str,
+        addresses: &[Multiaddr],
+        metadata: BTreeMap<String, String>,
+        migration: Option<&Migration>,
+        sender: oneshot::Sender<Result<()>>,
+    ) {
+        let now = SystemTime::now();
+        match naming::sign(&self.key, name, addresses, metadata, migration, now) {
+            Ok(value) => self.discovery.put_record(naming::key(name), value, sender),
+            Err(err) => {
+                let _ = sender.send(Err(err));
+            }
+        }
+    }
+
+    /// Look up the records stored under `name`, to be checked with
+    /// [`naming::latest`].
+    pub fn name_records(&mut self, name: &str, sender: oneshot::Sender<Result<Vec<Vec<u8>>>>) {
+        self.discovery.get_record(naming::key(name), sender);
+    }
+
+    /// Store `value` under the application record `key` in the DHT.
+    pub fn dht_put(&mut self, key: &[u8], value: Vec<u8>, sender: oneshot::Sender<Result<()>>) {
+        self.discovery.put_record(record_key(key), value, sender);
+    }
+
+    /// Look up the values stored under the application record `key`.
+    pub fn dht_get(&mut self, key: &[u8], sender: oneshot::Sender<Result<Vec<Vec<u8>>>>) {
+        self.discovery.get_record(record_key(key), sender);
+    }
+
+    pub fn start_providing(&mut self, key: Vec<u8>) -> Result<()> {
+        self.discovery.start_providing(key, Instant::now())
+    }
+
+    pub fn stop_providing(&mut self, key: &[u8]) {
+        self.discovery.stop_providing(key);
+    }
+
+    pub fn provided(&self) -> Vec<Provided> {
+        self.discovery.provided()
+    }
+
+    pub fn is_critical_peer(&self, peer_id: &PeerId) -> bool {
+        self.multipath.is_critical(peer_id)
+    }
+
+    /// Probe connections to keep them open, see [`keepalive`].
+    pub fn set_keepalive(&mut self, config: KeepaliveConfig) {
+        self.keepalive.configure(config);
+    }
+
+    /// What to do with a second connection to a peer, see
+    /// [`crate::node::duplicate`].
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicates.set_policy(policy);
+    }
+
+    /// Send due keepalive probes. Returns the peers whose connections died.
+    pub fn tick_keepalive(&mut self, now: Instant) -> Vec<PeerId> {
+        let peers = match self.keepalive.config().map(|config| config.peers) {
+            Some(Peers::Critical) => self.multipath.critical_peers(),
+            // FIXME: Can block
+            Some(Peers::All) => self.known_peers().read().unwrap().keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        self.keepalive.tick(now, peers.into_iter())
+    }
+
+    /// Carry bundles for disconnected peers, see [`crate::node::dtn`].
+    pub fn set_dtn(&mut self, store: Store) {
+        self.dtn.configure(store);
+    }
+
+    /// Send `data` on `topic` to `destination` as a bundle, delivered when
+    /// the destination or a carrier of the bundle meet.
+    pub fn send_bundle(
+        &mut self,
+        destination: &PeerId,
+        topic: &str,
+        data: &[u8],
+        priority: u8,
+    ) -> Result<BundleId> {
+        let topic = self.wire_topic(topic);
+        let mut envelope = Envelope {
+            timestamp:  self.clock.now(),
+            blob:       None,
+            codec:      None,
+            data:       data.to_vec(),
+            provenance: Provenance::default(),
+            capability: self.capabilities.token(&topic).cloned(),
+            seq:        None,
+            expires:    None,
+            hops:       None,
+            signature:  None,
+        };
+        envelope.sign(&self.key, &topic)?;
+        self.dtn.send(destination, &topic, envelope.to_bytes(), priority)
+    }
+
+    /// Ask connected peers to dial us back at `candidates` when due, see
+    /// [`crate::node::autonat`]. Returns the status if it changed.
+    pub fn tick_autonat(&mut self, now: Instant, candidates: Vec<Multiaddr>) -> Option<NatStatus> {
+        // FIXME: Can block
+        let peers = self.known_peers().read().unwrap().keys().cloned().collect::<Vec<_>>();
+        self.autonat.tick(now, candidates, peers.into_iter())
+    }
+
+    /// Keep the registrations of other peers, see [`crate::node::rendezvous`].
+    pub fn serve_rendezvous(&mut self) {
+        self.rendezvous.serve();
+    }
+
+    pub fn add_rendezvous_point(&mut self, peer_id: PeerId) {
+        self.rendezvous.add_point(peer_id);
+    }
+
+    pub fn rendezvous_points(&self) -> &[PeerId] {
+        self.rendezvous.points()
+    }
+
+    /// The rendezvous points not `connected` to dial again.
+    pub fn rendezvous_dials(
+        &mut self,
+        now: Instant,
+        connected: impl Fn(&PeerId) -> bool,
+    ) -> Vec<PeerId> {
+        self.rendezvous.dials(now, connected)
+    }
+
+    /// Register under `topics` at `addresses`, and discover other peers of
+    /// them, when due.
+    pub fn tick_rendezvous(&mut self, now: Instant, topics: &[&str], addresses: &[Multiaddr]) {
+        let namespaces = topics.iter().map(|topic| self.wire_topic(topic)).collect();
+        self.rendezvous.tick(now, &namespaces, addresses);
+    }
+
+    /// Where to receive files and how large, see [`crate::node::file`].
+    pub fn set_file_config(&mut self, config: FileConfig) {
+        self.files.set_config(config);
+    }
+
+    /// Offer `peer_id` the file at `path`, telling `accepted` whether it
+    /// took it.
+    pub fn offer_file(
+        &mut self,
+        peer_id: &PeerId,
+        path: PathBuf,
+        manifest: Manifest,
+        accepted: Option<oneshot::Sender<Result<FileId>>>,
+    ) -> FileId {
+        self.files.offer(peer_id, path, manifest, accepted)
+    }
+
+    /// Go on with interrupted file transfers and fail the stalled.
+    pub fn tick_files(&mut self, now: Instant) {
+        self.files.tick(now);
+    }
+
+    /// Exchange bundles with connected peers.
+    pub fn tick_dtn(&mut self, now: Instant) {
+        // FIXME: Can block
+        let peers = self.known_peers().read().unwrap().keys().cloned().collect::<Vec<_>>();
+        self.dtn.tick(now, peers.into_iter());
+    }
+
+    /// Number of bundles carried.
+    pub fn bundle_count(&self) -> usize {
+        self.dtn.len()
+    }
+
+    /// The reports received on our bundle `id`.
+    pub fn bundle_trace(&self, id: &BundleId) -> Option<Trace> {
+        self.dtn.trace(id)
+    }
+
+    /// Look for peers again, see [`Discovery::refresh`].
+    pub async fn refresh_discovery(&mut self) -> Result<()> {
+        self.discovery.refresh().await
+    }
+
+    pub fn remove_bootnodes(&mut self) -> Result<()> {
+        self.discovery.remove_bootnodes()
+    }
+
+    pub fn suspend_mdns(&mut self) {
+        self.discovery.suspend_mdns();
+    }
+
+    pub async fn resume_mdns(&mut self) -> Result<()> {
+        self.discovery.resume_mdns().await
+    }
+
+    pub fn set_lan(&mut self, lan: Lan) -> Result<()> {
+        self.discovery.set_lan(lan)
+    }
+
+    pub fn take_mdns_error(&mut self) -> Option<anyhow::Error> {
+        self.discovery.take_mdns_error()
+    }
+
+    /// The name of a pubsub `topic` inside our namespace, or None for
+    /// topics of other namespaces.
+    fn friendly(&self, topic: String) -> Option<String> {
+        match &self.namespace {
+            None => Some(topic),
+            Some(namespace) => {
+                match namespace.friendly(&topic) {
+                    Some(friendly) => Some(friendly.to_owned()),
+                    None => {
+                        trace!("Dropping event on foreign namespace topic {}", topic);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        while let Poll::Ready(message) = self.multicast.poll_message(cx) {
+            self.inject_event(Event::Message {
+                source:     message.source,
+                topic:      message.topic,
+                data:       message.data,
+                direct:     false,
+                timestamp:  None,
+                provenance: Provenance::default(),
+                signed:     false,
+            });
+        }
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
 }
 
 impl NetworkBehaviourEventProcess<()> for Behaviour {
     fn inject_event(&mut self, _event: ()) {}
 }
+
+impl NetworkBehaviourEventProcess<Event> for Behaviour {
+    fn inject_event(&mut self, event: Event) {
+        let event = match event {
+            Event::Message {
+                source,
+                topic,
+                data,
+                direct,
+                timestamp: None,
+                ..
+            } => {
+                if direct && topic == reliable::ACK_TOPIC {
+                    match Ack::decode(&data) {
+                        Some(ack) => {
+                            if !self.reliable.acked(&source, &ack) {
+                                trace!("Late acknowledgement on {} from {}", ack.topic, source);
+                            }
+                        }
+                        None => debug!("Invalid acknowledgement from {}", source),
+                    }
+                    return;
+                }
+                let wire_topic = topic.clone();
+                let topic = match self.friendly(topic) {
+                    Some(topic) => topic,
+                    None => return,
+                };
+                let data = match Fragment::decode(&data) {
+                    Some(fragment) => {
+                        let now = Instant::now();
+                        match self.fragments.receive(&source, &wire_topic, fragment, now) {
+                            Ok(Some(data)) => data,
+                            Ok(None) => return,
+                            Err(err) => {
+                                debug!("Dropping fragment on {} from {}: {:#}", topic, source, err);
+                                return;
+                            }
+                        }
+                    }
+                    None => data,
+                };
+                let envelope = Envelope::decode(&data);
+                let verification = envelope.as_ref().map_or(Verification::Unsigned, |envelope| {
+                    envelope.verify(&wire_topic, &source)
+                });
+                if !self.verification.delivers(verification) {
+                    debug!("Dropping {:?} message on {} from {}", verification, topic, source);
+                    if verification == Verification::Invalid {
+                        self.pubsub.penalize(&source);
+                    }
+                    return;
+                }
+                if let Err(err) =
+                    self.authorize(&wire_topic, &source, envelope.as_ref(), verification)
+                {
+                    debug!("Dropping message on {} from {}: {:#}", topic, source, err);
+                    return;
+                }
+                let signed = verification == Verification::Valid;
+                match envelope {
+                    Some(mut envelope) => {
+                        let limits = envelope.limits();
+                        if limits.is_expired(expiry::epoch_ms(SystemTime::now())) {
+                            debug!("Dropping expired message on {} from {}", topic, source);
+                            self.drops.count(expiry::Exceeded::Expired);
+                            return;
+                        }
+                        if let Err(err) = self.clock.receive(envelope.timestamp) {
+                            warn!("Not advancing clock for message from {}: {}", source, err);
+                        }
+                        // The signer numbered the message, so it gets the ack
+                        if let Some(seq) = envelope.seq {
+                            let ack = Ack {
+                                topic: wire_topic.clone(),
+                                seq,
+                            };
+                            self.direct.publish_to(
+                                &[source.clone()],
+                                reliable::ACK_TOPIC,
+                                &ack.to_bytes(),
+                            );
+                            if !self.reliable.receive(&source, &wire_topic, seq) {
+                                trace!("Dropping duplicate {} on {} from {}", seq, topic, source);
+                                return;
+                            }
+                        }
+                        if let Some(codec) = envelope.codec {
+                            if !envelope.data.is_empty() {
+                                match codec.decompress(&envelope.data) {
+                                    Ok(data) => envelope.data = data,
+                                    Err(err) => {
+                                        warn!("Dropping message from {}: {:#}", source, err);
+                                        self.pubsub.penalize(&source);
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                        // The sender is the last relay of republished messages
+                        envelope.provenance.set_limits(limits);
+                        let origin = if envelope.provenance.is_empty() {
+                            source.clone()
+                        } else {
+                            match envelope.provenance.verify(&source) {
+                                Ok(origin) => origin,
+                                Err(err) => {
+                                    warn!("Dropping message relayed by {}: {:#}", source, err);
+                                    self.pubsub.penalize(&source);
+                                    return;
+                                }
+                            }
+                        };
+                        let mut event = Event::Message {
+                            source: origin,
+                            topic,
+                            data: envelope.data,
+                            direct,
+                            timestamp: Some(envelope.timestamp),
+                            provenance: envelope.provenance,
+                            signed,
+                        };
+                        if let (Some(id), Event::Message { data, .. }) =
+                            (envelope.blob, &mut event)
+                        {
+                            let store = self.blobs.store();
+                            if !data.is_empty() && BlobId::of(data) != id {
+                                warn!("Dropping message from {} with the wrong blob hash", source);
+                                self.pubsub.penalize(&source);
+                                return;
+                            }
+                            if data.is_empty() {
+                                match store.get(&id) {
+                                    Some(blob) => *data = blob.to_vec(),
+                                    None => {
+                                        trace!("Fetching blob {:?} from {}", id, source);
+                                        self.blobs.fetch(&source, id, event);
+                                        return;
+                                    }
+                                }
+                            }
+                            store.retain(id.clone(), data);
+                            store.add_holder(&id, source);
+                        }
+                        event
+                    }
+                    None => {
+                        Event::Message {
+                            source,
+                            topic,
+                            data,
+                            direct,
+                            timestamp: None,
+                            provenance: Provenance::default(),
+                            signed,
+                        }
+                    }
+                }
+            }
+            Event::Subscribed { peer, topic } => {
+                match self.friendly(topic) {
+                    Some(topic) => Event::Subscribed { peer, topic },
+                    None => return,
+                }
+            }
+            Event::Unsubscribed { peer, topic } => {
+                self.reliable.forget(&peer, &topic);
+                match self.friendly(topic) {
+                    Some(topic) => Event::Unsubscribed { peer, topic },
+                    None => return,
+                }
+            }
+            Event::BundleEvicted(mut evicted) => {
+                if let Some(friendly) = self
+                    .namespace
+                    .as_ref()
+                    .and_then(|namespace| namespace.friendly(&evicted.topic))
+                {
+                    evicted.topic = friendly.to_owned();
+                }
+                Event::BundleEvicted(evicted)
+            }
+            event => event,
+        };
+        self.events.push_back(event);
+    }
+}
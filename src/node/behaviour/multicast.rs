@@ -0,0 +1,280 @@
+//! LAN multicast for topics whose subscribers are all on the local network.
+//!
+//! Topics subscribed with [`TopicOptions::multicast`] are published as a
+//! single UDP datagram to [`GROUP`] instead of through the gossip mesh, where
+//! every message is sent to each mesh peer separately. Datagrams carry the
+//! envelope signed with the publisher's key, so receivers check who sent it
+//! just like gossipsub does. Datagrams are sent with a TTL of one, so they do
+//! not leave the local network, and receivers drop those they saw before.
+//!
+//! Multicast is unreliable and limited to payloads of [`MAX_DATA`] bytes.
+//! Larger payloads, and all payloads if the socket could not be opened, go
+//! through the gossip mesh as usual.
+//!
+//! [`TopicOptions::multicast`]: crate::node::subscriptions::TopicOptions::multicast
+
+use super::cbor_codec::{decode, encode};
+use crate::{
+    node::signing::{self, Layout},
+    prelude::*,
+};
+use anyhow::Context as _;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    task::{Context, Poll},
+};
+use tokio::{io::ReadBuf, net::UdpSocket};
+
+/// Administratively scoped multicast group the datagrams are sent to.
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 83);
+
+pub const PORT: u16 = 4767;
+
+/// Largest payload sent by multicast, leaving room for the signature.
+pub const MAX_DATA: usize = 60 * 1024;
+
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Number of recently received messages remembered to drop duplicates.
+const SEEN: usize = 4096;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Datagram {
+    /// Protobuf encoding of the publisher's public key.
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+    topic:      String,
+    seqno:      u64,
+    #[serde(with = "serde_bytes")]
+    data:       Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    signature:  Vec<u8>,
+}
+
+fn signed_bytes(topic: &str, seqno: u64, data: &[u8]) -> Vec<u8> {
+    Layout::new(signing::Domain::Multicast)
+        .topic(topic)
+        .number("seqno", seqno)
+        .payload(data)
+        .to_bytes()
+}
+
+impl Datagram {
+    fn sign(key: &Keypair, topic: &str, seqno: u64, data: &[u8]) -> Result<Self> {
+        let signature = key
+            .sign(&signed_bytes(topic, seqno, data))
+            .context("Signing multicast datagram")?;
+        Ok(Self {
+            public_key: key.public().into_protobuf_encoding(),
+            topic: topic.to_owned(),
+            seqno,
+            data: data.to_vec(),
+            signature,
+        })
+    }
+
+    /// The publisher, if the signature is valid.
+    fn verify(&self) -> Option<PeerId> {
+        let public_key = PublicKey::from_protobuf_encoding(&self.public_key).ok()?;
+        let signed = signed_bytes(&self.topic, self.seqno, &self.data);
+        if public_key.verify(&signed, &self.signature) {
+            Some(PeerId::from(public_key))
+        } else {
+            None
+        }
+    }
+}
+
+/// Open the socket, received from through tokio and sent to directly, as
+/// sends must not wait.
+fn open() -> io::Result<(UdpSocket, std::net::UdpSocket)> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    // Other nodes on this host listen on the same port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.set_nonblocking(true)?;
+    let socket = socket.into_udp_socket();
+    let sender = socket.try_clone()?;
+    Ok((UdpSocket::from_std(socket)?, sender))
+}
+
+/// A message received by multicast, with its publisher.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Message {
+    pub source: PeerId,
+    pub topic:  String,
+    pub data:   Vec<u8>,
+}
+
+pub struct Multicast {
+    key:     Keypair,
+    peer_id: PeerId,
+    /// Topics sent and received by multicast, on the wire.
+    topics:  HashSet<String>,
+    /// Open while there are topics.
+    socket:  Option<(UdpSocket, std::net::UdpSocket)>,
+    seqno:   u64,
+    seen:    VecDeque<(PeerId, u64)>,
+    buffer:  Vec<u8>,
+}
+
+impl Multicast {
+    pub fn new(key: Keypair) -> Self {
+        Self {
+            peer_id: PeerId::from(key.public()),
+            key,
+            topics: HashSet::new(),
+            socket: None,
+            seqno: rand::random(),
+            seen: VecDeque::new(),
+            buffer: vec![0; MAX_DATAGRAM],
+        }
+    }
+
+    /// Send and receive `topic` by multicast, or stop doing so.
+    pub fn set_topic(&mut self, topic: &str, enabled: bool) {
+        if enabled {
+            self.topics.insert(topic.to_owned());
+        } else {
+            self.topics.remove(topic);
+        }
+        if self.topics.is_empty() {
+            self.socket = None;
+        } else if self.socket.is_none() {
+            match open() {
+                Ok(socket) => {
+                    info!("Joined multicast group {}:{}", GROUP, PORT);
+                    self.socket = Some(socket);
+                }
+                Err(err) => warn!("Could not join multicast group {}: {}", GROUP, err),
+            }
+        }
+    }
+
+    /// Whether `data` on `topic` is published by multicast.
+    pub fn is_multicast(&self, topic: &str, data: &[u8]) -> bool {
+        self.socket.is_some() && data.len() <= MAX_DATA && self.topics.contains(topic)
+    }
+
+    /// Publish `data` on `topic` to the multicast group.
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<()> {
+        let (_, socket) = self.socket.as_ref().context("Not in the multicast group")?;
+        self.seqno = self.seqno.wrapping_add(1);
+        let datagram = Datagram::sign(&self.key, topic, self.seqno, data)?;
+        let bytes = encode(&datagram)?;
+        let group = SocketAddr::V4(SocketAddrV4::new(GROUP, PORT));
+        socket
+            .send_to(&bytes, group)
+            .context("Sending multicast datagram")?;
+        Ok(())
+    }
+
+    /// Remember the message `seqno` of `source`. Returns false if it was
+    /// seen before.
+    fn first_seen(&mut self, source: &PeerId, seqno: u64) -> bool {
+        let key = (source.clone(), seqno);
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.seen.len() >= SEEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key);
+        true
+    }
+
+    /// Check a received datagram. Returns the message if it is new, signed
+    /// and on one of our topics.
+    fn receive(&mut self, bytes: &[u8], from: SocketAddr) -> Option<Message> {
+        let datagram: Datagram = match decode(bytes) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                trace!("Ignoring invalid multicast datagram from {}: {}", from, err);
+                return None;
+            }
+        };
+        if !self.topics.contains(&datagram.topic) {
+            return None;
+        }
+        let source = match datagram.verify() {
+            Some(source) => source,
+            None => {
+                warn!("Dropping multicast datagram from {} with a bad signature", from);
+                return None;
+            }
+        };
+        if source == self.peer_id || !self.first_seen(&source, datagram.seqno) {
+            return None;
+        }
+        Some(Message {
+            source,
+            topic: datagram.topic,
+            data: datagram.data,
+        })
+    }
+
+    pub fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<Message> {
+        loop {
+            let socket = match &self.socket {
+                Some((socket, _)) => socket,
+                None => return Poll::Pending,
+            };
+            let mut read = ReadBuf::new(&mut self.buffer);
+            let from = match socket.poll_recv_from(cx, &mut read) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(from)) => from,
+                Poll::Ready(Err(err)) => {
+                    debug!("Could not receive multicast datagram: {}", err);
+                    continue;
+                }
+            };
+            let bytes = read.filled().to_vec();
+            if let Some(message) = self.receive(&bytes, from) {
+                return Poll::Ready(message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_accepts_signed_datagrams_once() {
+        let publisher = Keypair::generate_ed25519();
+        let mut receiver = Multicast::new(Keypair::generate_ed25519());
+        receiver.topics.insert("lan".into());
+        let from = SocketAddr::from((Ipv4Addr::LOCALHOST, PORT));
+
+        let datagram = Datagram::sign(&publisher, "lan", 1, b"data").unwrap();
+        let bytes = encode(&datagram).unwrap();
+        assert_eq!(
+            receiver.receive(&bytes, from),
+            Some(Message {
+                source: PeerId::from(publisher.public()),
+                topic:  "lan".into(),
+                data:   b"data".to_vec(),
+            })
+        );
+        assert_eq!(receiver.receive(&bytes, from), None);
+
+        let mut forged = Datagram::sign(&publisher, "lan", 2, b"data").unwrap();
+        forged.data = b"forged".to_vec();
+        assert_eq!(receiver.receive(&encode(&forged).unwrap(), from), None);
+        let other = Datagram::sign(&publisher, "other", 3, b"data").unwrap();
+        assert_eq!(receiver.receive(&encode(&other).unwrap(), from), None);
+        assert_eq!(receiver.receive(b"garbage", from), None);
+    }
+}
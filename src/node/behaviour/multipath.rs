@@ -0,0 +1,440 @@
+//! Redundant connections to critical peers.
+//!
+//! For every peer marked critical (`--critical <address>/p2p/<peer id>`) we
+//! keep [`REDUNDANCY`] connections open, each over a different address, and
+//! preferably over a different network or transport. Protocols use whichever
+//! connection is open, so when one path breaks traffic continues on the other
+//! while we redial the broken one with exponential backoff.
+//!
+//! The health of every path is recorded in [`PeerInfo::paths`].
+
+use super::discovery::{PeerInfo, PeerStore};
+use crate::prelude::*;
+use libp2p::{
+    core::{connection::ConnectionId, multiaddr::Protocol, ConnectedPoint},
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    error,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Number of connections kept open to a critical peer.
+pub const REDUNDANCY: usize = 2;
+
+/// Longest wait before redialing a failed path.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A dial that has not connected after this long has failed.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Health of one path to a critical peer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PathHealth {
+    pub address:   Multiaddr,
+    /// Number of open connections over this path.
+    pub connected: usize,
+    /// Dial failures since the path was last connected.
+    pub failures:  u32,
+}
+
+#[derive(Clone, Debug)]
+struct Path {
+    health:   PathHealth,
+    /// When the current dial started.
+    dialing:  Option<Instant>,
+    /// Do not dial before this time.
+    retry:    Option<Instant>,
+    /// False for the ephemeral address of a connection the peer opened.
+    dialable: bool,
+}
+
+impl Path {
+    fn new(address: Multiaddr, dialable: bool) -> Self {
+        Self {
+            health: PathHealth {
+                address,
+                connected: 0,
+                failures: 0,
+            },
+            dialing: None,
+            retry: None,
+            dialable,
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        self.health.connected > 0
+    }
+
+    fn is_active(&self) -> bool {
+        self.is_up() || self.dialing.is_some()
+    }
+
+    fn fail(&mut self, now: Instant) {
+        self.dialing = None;
+        self.health.failures = self.health.failures.saturating_add(1);
+        let backoff = Duration::from_secs(1 << self.health.failures.min(6)).min(MAX_BACKOFF);
+        self.retry = Some(now + backoff);
+    }
+}
+
+/// The network a path goes over: its transport protocols and host, without
+/// ports or peer id. Paths over different routes are less likely to break
+/// together.
+fn route(address: &Multiaddr) -> Vec<Protocol<'_>> {
+    address
+        .iter()
+        .filter_map(|protocol| {
+            match protocol {
+                Protocol::Tcp(_) => Some(Protocol::Tcp(0)),
+                Protocol::Udp(_) => Some(Protocol::Udp(0)),
+                Protocol::P2p(_) => None,
+                other => Some(other),
+            }
+        })
+        .collect()
+}
+
+/// Strip a trailing `/p2p/<peer id>`, returning it separately.
+pub fn split_peer_id(address: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut address = address.clone();
+    match address.pop()? {
+        Protocol::P2p(hash) => Some((PeerId::from_multihash(hash).ok()?, address)),
+        _ => None,
+    }
+}
+
+pub struct Multipath {
+    critical:  HashMap<PeerId, Vec<Path>>,
+    actions:   VecDeque<Multiaddr>,
+    peer_info: PeerStore,
+}
+
+impl Multipath {
+    pub fn new(peer_info: PeerStore) -> Self {
+        Self {
+            critical: HashMap::new(),
+            actions: VecDeque::new(),
+            peer_info,
+        }
+    }
+
+    /// Keep redundant connections to `peer_id`, starting with `address`.
+    pub fn add_critical(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let paths = self.critical.entry(peer_id).or_default();
+        if !paths.iter().any(|path| path.health.address == address) {
+            paths.push(Path::new(address, true));
+        }
+    }
+
+    pub fn is_critical(&self, peer_id: &PeerId) -> bool {
+        self.critical.contains_key(peer_id)
+    }
+
+    pub fn critical_peers(&self) -> Vec<PeerId> {
+        self.critical.keys().cloned().collect()
+    }
+
+    /// Path health of a critical peer.
+    pub fn paths(&self, peer_id: &PeerId) -> Vec<PathHealth> {
+        self.critical
+            .get(peer_id)
+            .map(|paths| paths.iter().map(|path| path.health.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Dial paths to critical peers that have fewer than [`REDUNDANCY`] open,
+    /// or whose open paths all share a route while another is known.
+    pub fn tick(&mut self, now: Instant) {
+        let peer_ids = self.critical.keys().cloned().collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            // Learn addresses the peer announced
+            let announced = self
+                .peer_info
+                .read()
+                .unwrap() // FIXME: Can block
+                .get(&peer_id)
+                .and_then(|info| info.identify.as_ref())
+                .map(|identify| identify.listen_addrs.clone())
+                .unwrap_or_default();
+            for address in announced {
+                self.add_critical(peer_id.clone(), address);
+            }
+
+            let paths = self.critical.get_mut(&peer_id).unwrap();
+            for path in paths.iter_mut() {
+                if matches!(path.dialing, Some(start) if now >= start + DIAL_TIMEOUT) {
+                    debug!("Dialing {} timed out", path.health.address);
+                    path.fail(now);
+                }
+            }
+            loop {
+                let mut active = 0;
+                let mut used = Vec::new();
+                for path in paths.iter().filter(|path| path.is_active()) {
+                    active += 1;
+                    let route = route(&path.health.address);
+                    if !used.contains(&route) {
+                        used.push(route);
+                    }
+                }
+                let candidate = paths
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, path)| path.dialable && !path.is_active())
+                    .filter(|(_, path)| !matches!(path.retry, Some(retry) if now < retry))
+                    .map(|(index, path)| {
+                        let diverse = !used.contains(&route(&path.health.address));
+                        (!diverse, path.health.failures, index)
+                    })
+                    .min();
+                let (shared, index) = match candidate {
+                    Some((shared, _, index)) => (shared, index),
+                    None => break,
+                };
+                // Stop with enough paths, unless they share a route and a
+                // path over another route is known
+                if active >= REDUNDANCY && (used.len() >= REDUNDANCY || shared) {
+                    break;
+                }
+                let path = &mut paths[index];
+                debug!("Dialing path {} to critical peer {}", path.health.address, peer_id);
+                path.dialing = Some(now);
+                self.actions.push_back(path.health.address.clone());
+            }
+        }
+    }
+
+    /// Find the path to `peer_id` of a connection, adding it if it is new.
+    fn path(&mut self, peer_id: &PeerId, endpoint: &ConnectedPoint) -> Option<&mut Path> {
+        let paths = self.critical.get_mut(peer_id)?;
+        let (address, dialable) = match endpoint {
+            ConnectedPoint::Dialer { address } => (address, true),
+            ConnectedPoint::Listener { send_back_addr, .. } => (send_back_addr, false),
+        };
+        let index = match paths.iter().position(|path| &path.health.address == address) {
+            Some(index) => index,
+            None => {
+                paths.push(Path::new(address.clone(), dialable));
+                paths.len() - 1
+            }
+        };
+        let path = &mut paths[index];
+        path.dialable |= dialable;
+        Some(path)
+    }
+
+    /// Record the current path health in the peer info.
+    fn update_peer_info(&self, peer_id: &PeerId) {
+        let paths = self.paths(peer_id);
+        let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
+        let entry = lock
+            .entry(peer_id.clone())
+            .or_insert_with(|| PeerInfo::new(peer_id.clone()));
+        entry.paths = paths;
+    }
+}
+
+impl NetworkBehaviour for Multipath {
+    type OutEvent = ();
+    type ProtocolsHandler = DummyProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.critical
+            .get(peer_id)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter(|path| path.dialable)
+                    .map(|path| path.health.address.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        if let Some(path) = self.path(peer_id, endpoint) {
+            path.health.connected += 1;
+            path.health.failures = 0;
+            path.dialing = None;
+            path.retry = None;
+            self.update_peer_info(peer_id);
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        if let Some(path) = self.path(peer_id, endpoint) {
+            path.health.connected = path.health.connected.saturating_sub(1);
+            if !path.is_up() {
+                info!("Path {} to critical peer {} is down", path.health.address, peer_id);
+            }
+            // Forget connections the peer opened from ephemeral ports
+            if let Some(paths) = self.critical.get_mut(peer_id) {
+                paths.retain(|path| path.dialable || path.is_up());
+            }
+            self.update_peer_info(peer_id);
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection: ConnectionId,
+        _event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        address: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        let now = Instant::now();
+        let mut failed = Vec::new();
+        for (critical, paths) in &mut self.critical {
+            if matches!(peer_id, Some(peer_id) if peer_id != critical) {
+                continue;
+            }
+            for path in paths.iter_mut() {
+                if &path.health.address == address && path.dialing.is_some() {
+                    debug!("Path {} to {} failed: {}", address, critical, error);
+                    path.fail(now);
+                    failed.push(critical.clone());
+                }
+            }
+        }
+        for peer_id in failed {
+            self.update_peer_info(&peer_id);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>,
+    > {
+        self.actions.pop_front().map_or(Poll::Pending, |address| {
+            Poll::Ready(NetworkBehaviourAction::DialAddress { address })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_fail_over_to_second_path() {
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+        let first: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let second: Multiaddr = "/ip4/10.0.0.1/tcp/4002".parse().unwrap();
+        let other_route: Multiaddr = "/ip6/fd00::1/tcp/4001".parse().unwrap();
+        let mut multipath = Multipath::new(PeerStore::default());
+        multipath.add_critical(peer_id.clone(), first.clone());
+        multipath.add_critical(peer_id.clone(), second);
+        multipath.add_critical(peer_id.clone(), other_route.clone());
+
+        // Dials two paths, preferring different routes
+        multipath.tick(now);
+        assert_eq!(multipath.actions, vec![first.clone(), other_route.clone()]);
+        multipath.actions.clear();
+        let dialer = |address: &Multiaddr| {
+            ConnectedPoint::Dialer {
+                address: address.clone(),
+            }
+        };
+        let connection = ConnectionId::new(1);
+        multipath.inject_connection_established(&peer_id, &connection, &dialer(&first));
+        multipath.inject_connection_established(&peer_id, &connection, &dialer(&other_route));
+        multipath.tick(now);
+        assert!(multipath.actions.is_empty());
+
+        // Breaking a path redials the remaining address
+        multipath.inject_connection_closed(&peer_id, &connection, &dialer(&other_route));
+        let info = multipath.peer_info.read().unwrap()[&peer_id].paths.clone();
+        assert_eq!(info.iter().filter(|path| path.connected > 0).count(), 1);
+        multipath.tick(now);
+        assert_eq!(multipath.actions.len(), 1);
+        let redial = multipath.actions.pop_front().unwrap();
+        assert!(redial != first);
+
+        // Failed dials back off
+        let error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        multipath.inject_addr_reach_failure(Some(&peer_id), &redial, &error);
+        let failed = multipath.paths(&peer_id).into_iter().find(|path| path.address == redial);
+        assert_eq!(failed.unwrap().failures, 1);
+    }
+
+    #[test]
+    fn test_adds_path_over_other_route() {
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+        let configured: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let mut multipath = Multipath::new(PeerStore::default());
+        multipath.add_critical(peer_id.clone(), configured.clone());
+        let connection = ConnectionId::new(1);
+        let dialer = ConnectedPoint::Dialer {
+            address: configured,
+        };
+        let listener = ConnectedPoint::Listener {
+            local_addr:     "/ip4/127.0.0.1/tcp/4002".parse().unwrap(),
+            send_back_addr: "/ip4/127.0.0.1/tcp/50123".parse().unwrap(),
+        };
+        multipath.inject_connection_established(&peer_id, &connection, &dialer);
+        multipath.inject_connection_established(&peer_id, &connection, &listener);
+        multipath.tick(now);
+        assert!(multipath.actions.is_empty());
+
+        // Both connections go over loopback, so another route is worth a dial
+        let announced: Multiaddr = "/ip4/192.0.2.2/tcp/4001".parse().unwrap();
+        multipath.add_critical(peer_id.clone(), announced.clone());
+        multipath.tick(now);
+        assert_eq!(multipath.actions, vec![announced]);
+
+        // The peer's ephemeral port is never dialed
+        multipath.inject_connection_closed(&peer_id, &connection, &listener);
+        assert_eq!(multipath.paths(&peer_id).len(), 2);
+        assert_eq!(multipath.addresses_of_peer(&peer_id).len(), 2);
+    }
+
+    #[test]
+    fn test_split_peer_id() {
+        let peer_id = PeerId::random();
+        let address: Multiaddr = format!("/ip4/1.2.3.4/tcp/5/p2p/{}", peer_id).parse().unwrap();
+        assert_eq!(
+            split_peer_id(&address),
+            Some((peer_id, "/ip4/1.2.3.4/tcp/5".parse().unwrap()))
+        );
+        assert_eq!(split_peer_id(&"/ip4/1.2.3.4/tcp/5".parse().unwrap()), None);
+    }
+}
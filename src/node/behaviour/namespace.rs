@@ -0,0 +1,58 @@
+//! Application namespaces for pubsub topics.
+//!
+//! Unrelated applications on the same network may well pick the same topic
+//! names. A node configured with a namespace prefixes every topic it uses with
+//! a hash of the namespace, so the applications never see each other's
+//! messages. The API keeps using the friendly names.
+
+use crate::utils::fnv1a;
+
+const PREFIX: &str = "/mesh-rs/ns/";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Namespace {
+    /// Topic prefix including the trailing slash.
+    prefix: String,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Self {
+        Self {
+            prefix: format!("{}{:016x}/", PREFIX, fnv1a(name.bytes())),
+        }
+    }
+
+    /// The topic name on the wire.
+    pub fn wire(&self, topic: &str) -> String {
+        format!("{}{}", self.prefix, topic)
+    }
+
+    /// The friendly name of a wire topic. Topics outside of any namespace are
+    /// passed through, topics of other namespaces give `None`.
+    pub fn friendly<'a>(&self, wire: &'a str) -> Option<&'a str> {
+        if let Some(topic) = wire.strip_prefix(&self.prefix) {
+            Some(topic)
+        } else if wire.starts_with(PREFIX) {
+            None
+        } else {
+            Some(wire)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_namespaces_are_separate() {
+        let chat = Namespace::new("chat-app");
+        let game = Namespace::new("game");
+        let wire = chat.wire("chat");
+        assert_ne!(wire, game.wire("chat"));
+        assert_eq!(chat.friendly(&wire), Some("chat"));
+        assert_eq!(game.friendly(&wire), None);
+        assert_eq!(game.friendly("/0x-orders/version/3"), Some("/0x-orders/version/3"));
+    }
+}
@@ -1,38 +1,361 @@
 //! Pub sub behaviour for order sharing.
+//!
+//! Runs gossipsub or floodsub, see [`crate::node::pubsub`], drops messages
+//! seen before, see [`crate::node::seen`], and scores the peers they come
+//! from, see [`crate::node::scoring`].
 
+use super::{envelope::Provenance, Event};
+use crate::{
+    node::{
+        pubsub::{Config, Protocol},
+        scoring::{self, Scores, Verdict},
+        seen::{self, MessageId},
+    },
+    prelude::*,
+};
 use libp2p::{
-    gossipsub::{Gossipsub, GossipsubConfigBuilder, GossipsubEvent, MessageAuthenticity, Topic},
+    floodsub::{self, Floodsub, FloodsubEvent},
+    gossipsub::{
+        error::PublishError, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
+        MessageAuthenticity, MessageId as GossipId, Topic,
+    },
     identity::Keypair,
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour,
+    swarm::{
+        toggle::Toggle, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
+    },
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    task::{Context, Poll},
+    time::Instant,
 };
 
 /// Topic for all mainnet v3 orders (unfiltered)
 const TOPIC: &str = "/0x-orders/version/3/chain/1/schema/e30=";
 
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
 pub struct PubSub {
-    gossipsub: Gossipsub,
+    /// Exactly one of gossipsub and floodsub is enabled.
+    gossipsub: Toggle<Gossipsub>,
+    floodsub:  Toggle<Floodsub>,
+
+    #[behaviour(ignore)]
+    key: Keypair,
+
+    /// Connected peers subscribed to each topic, for floodsub, which does
+    /// not keep track itself.
+    #[behaviour(ignore)]
+    subscribers: HashMap<String, HashSet<PeerId>>,
+
+    /// Messages delivered recently.
+    #[behaviour(ignore)]
+    seen: seen::Cache,
+
+    /// Set to rate limit and score the peers messages come from.
+    #[behaviour(ignore)]
+    scores: Option<Scores>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+}
+
+/// The id go-libp2p gives a message: its source followed by its sequence
+/// number.
+fn go_message_id(message: &GossipsubMessage) -> GossipId {
+    let mut id = message
+        .source
+        .as_ref()
+        .map_or_else(Vec::new, |source| source.as_bytes().to_vec());
+    if let Some(seqno) = message.sequence_number {
+        id.extend_from_slice(&seqno.to_be_bytes());
+    }
+    GossipId::from(id)
+}
+
+fn gossipsub(peer_key: Keypair, config: &Config) -> Gossipsub {
+    let mut builder = GossipsubConfigBuilder::new();
+    builder
+        // go-libp2p still accepts /meshsub/1.0.0 next to /meshsub/1.1.0
+        .protocol_id(&b"/meshsub/1.0.0"[..])
+        .max_transmit_size(config.max_transmit)
+        .mesh_n(config.mesh)
+        .mesh_n_low(config.mesh_low)
+        .mesh_n_high(config.mesh_high)
+        .gossip_lazy(config.mesh)
+        .heartbeat_interval(config.heartbeat);
+    if config.bare {
+        builder
+            .duplicate_cache_time(config.seen_ttl)
+            .message_id_fn(go_message_id);
+    }
+    Gossipsub::new(MessageAuthenticity::Signed(peer_key), builder.build())
 }
 
 impl PubSub {
     pub(crate) fn new(peer_key: Keypair) -> Self {
-        // GossipSub
-        let gossipsub_config = GossipsubConfigBuilder::new()
-            .max_transmit_size(262_144)
-            .build();
-        let gossipsub = Gossipsub::new(MessageAuthenticity::Signed(peer_key), gossipsub_config);
+        let gossipsub = gossipsub(peer_key.clone(), &Config::default());
+        Self {
+            gossipsub: Some(gossipsub).into(),
+            floodsub: None.into(),
+            key: peer_key,
+            subscribers: HashMap::new(),
+            seen: seen::Cache::default(),
+            scores: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Switch to the protocol and tuning of `config`. Call before
+    /// [`Self::start`] and any subscription, which are not carried over.
+    pub fn configure(&mut self, config: &Config) {
+        match config.protocol {
+            Protocol::Gossipsub => {
+                self.gossipsub = Some(gossipsub(self.key.clone(), config)).into();
+                self.floodsub = None.into();
+            }
+            Protocol::Floodsub => {
+                let local = PeerId::from(self.key.public());
+                self.gossipsub = None.into();
+                self.floodsub = Some(Floodsub::new(local)).into();
+            }
+            Protocol::Off => {
+                self.gossipsub = None.into();
+                self.floodsub = None.into();
+            }
+        }
+        self.subscribers.clear();
+        self.seen = seen::Cache::new(config.seen_ttl, config.seen_capacity);
+    }
+
+    /// Messages dropped as seen before.
+    pub fn duplicates(&self) -> u64 {
+        self.seen.duplicates()
+    }
+
+    pub fn set_scoring(&mut self, config: scoring::Config) {
+        self.scores = Some(Scores::new(config));
+    }
+
+    pub fn score(&self, peer: &PeerId) -> Option<scoring::Score> {
+        self.scores.as_ref().and_then(|scores| scores.score(peer))
+    }
+
+    /// `peer` sent an invalid message.
+    pub fn penalize(&mut self, peer: &PeerId) {
+        if let Some(scores) = &mut self.scores {
+            scores.invalid(peer, Instant::now());
+        }
+    }
+
+    /// The peers to disconnect or ban for their scores.
+    pub fn verdicts(&mut self, now: Instant) -> Vec<(PeerId, Verdict)> {
+        self.scores
+            .as_mut()
+            .map_or_else(Vec::new, |scores| scores.verdicts(now))
+    }
 
-        Self { gossipsub }
+    /// Whether the message `sequence_number` of `source`, which came from
+    /// `peer`, is new and within the rate of `peer`.
+    fn is_new(&mut self, peer: &PeerId, source: &PeerId, sequence_number: &[u8]) -> bool {
+        let now = Instant::now();
+        if let Some(scores) = &mut self.scores {
+            if !scores.message(peer, now) {
+                trace!("Dropping message of {} from {}, over the rate", source, peer);
+                return false;
+            }
+        }
+        let id = MessageId::new(source, sequence_number);
+        if self.seen.insert(id, now) {
+            return true;
+        }
+        trace!("Dropping message {:?} of {}, seen before", id, source);
+        if let Some(scores) = &mut self.scores {
+            scores.duplicate(peer, now);
+        }
+        false
     }
 
     pub fn start(&mut self) {
         // Subscribe to orders
-        let topic = Topic::new(TOPIC.into());
-        self.gossipsub.subscribe(topic);
+        self.subscribe(TOPIC);
+    }
+
+    /// Subscribe to `topic`. Returns false if already subscribed.
+    pub fn subscribe(&mut self, topic: &str) -> bool {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            return floodsub.subscribe(floodsub::Topic::new(topic));
+        }
+        self.gossipsub
+            .as_mut()
+            .map_or(false, |gossipsub| gossipsub.subscribe(Topic::new(topic.into())))
+    }
+
+    /// Unsubscribe from `topic`. Returns false if not subscribed.
+    pub fn unsubscribe(&mut self, topic: &str) -> bool {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            return floodsub.unsubscribe(floodsub::Topic::new(topic));
+        }
+        self.gossipsub
+            .as_mut()
+            .map_or(false, |gossipsub| gossipsub.unsubscribe(Topic::new(topic.into())))
+    }
+
+    /// Number of peers in our mesh for `topic`. With floodsub, every
+    /// connected peer subscribed to it.
+    pub fn mesh_peer_count(&self, topic: &str) -> usize {
+        if self.floodsub.is_enabled() {
+            return self.subscribers.get(topic).map_or(0, HashSet::len);
+        }
+        let topic = Topic::new(topic.into());
+        self.gossipsub
+            .as_ref()
+            .map_or(0, |gossipsub| gossipsub.peers(&topic.no_hash()).count())
+    }
+
+    /// The peers we know to be subscribed to `topic`: every connected one
+    /// with floodsub, those of our mesh with gossipsub.
+    pub fn subscribers(&self, topic: &str) -> Vec<PeerId> {
+        if self.floodsub.is_enabled() {
+            return self
+                .subscribers
+                .get(topic)
+                .map_or_else(Vec::new, |peers| peers.iter().cloned().collect());
+        }
+        let topic = Topic::new(topic.into());
+        self.gossipsub.as_ref().map_or_else(Vec::new, |gossipsub| {
+            gossipsub.peers(&topic.no_hash()).cloned().collect()
+        })
+    }
+
+    /// Publish `data` on `topic` to the gossip mesh, or flood it to all
+    /// peers.
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), PublishError> {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.publish(floodsub::Topic::new(topic), data);
+            return Ok(());
+        }
+        let topic = Topic::new(topic.into());
+        self.gossipsub
+            .as_mut()
+            .map_or(Err(PublishError::InsufficientPeers), |gossipsub| {
+                gossipsub.publish(&topic, data)
+            })
+    }
+
+    /// Floodsub only sends to peers we add, so add every connected one.
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.add_node_to_partial_view(peer_id.clone());
+        }
+    }
+
+    /// Stop flooding to `peer_id` after its last connection closed.
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.remove_node_from_partial_view(peer_id);
+        }
+        for subscribers in self.subscribers.values_mut() {
+            subscribers.remove(peer_id);
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
     }
 }
 
 impl NetworkBehaviourEventProcess<GossipsubEvent> for PubSub {
-    fn inject_event(&mut self, _event: GossipsubEvent) {}
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Message(propagation_source, _message_id, message) => {
+                let source = message.source.unwrap_or_else(|| propagation_source.clone());
+                // Unsigned messages have no sequence number, their data stands in
+                let sequence_number = match message.sequence_number {
+                    Some(seqno) => seqno.to_be_bytes().to_vec(),
+                    None => message.data.clone(),
+                };
+                if !self.is_new(&propagation_source, &source, &sequence_number) {
+                    return;
+                }
+                for topic in message.topics {
+                    self.events.push_back(Event::Message {
+                        source: source.clone(),
+                        topic:  topic.as_str().to_owned(),
+                        data:   message.data.clone(),
+                        direct: false,
+                        timestamp: None,
+                        provenance: Provenance::default(),
+                        signed: false,
+                    });
+                }
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                trace!("Peer {} subscribed to {}", peer_id, topic);
+                self.events.push_back(Event::Subscribed {
+                    peer:  peer_id,
+                    topic: topic.as_str().to_owned(),
+                });
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                trace!("Peer {} unsubscribed from {}", peer_id, topic);
+                self.events.push_back(Event::Unsubscribed {
+                    peer:  peer_id,
+                    topic: topic.as_str().to_owned(),
+                });
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<FloodsubEvent> for PubSub {
+    fn inject_event(&mut self, event: FloodsubEvent) {
+        match event {
+            FloodsubEvent::Message(message) => {
+                // Floodsub does not tell who forwarded the message
+                if !self.is_new(&message.source, &message.source, &message.sequence_number) {
+                    return;
+                }
+                for topic in message.topics {
+                    self.events.push_back(Event::Message {
+                        source:     message.source.clone(),
+                        topic:      topic.id().to_owned(),
+                        data:       message.data.clone(),
+                        direct:     false,
+                        timestamp:  None,
+                        provenance: Provenance::default(),
+                        signed:     false,
+                    });
+                }
+            }
+            FloodsubEvent::Subscribed { peer_id, topic } => {
+                trace!("Peer {} subscribed to {}", peer_id, topic.id());
+                self.subscribers
+                    .entry(topic.id().to_owned())
+                    .or_default()
+                    .insert(peer_id.clone());
+                self.events.push_back(Event::Subscribed {
+                    peer:  peer_id,
+                    topic: topic.id().to_owned(),
+                });
+            }
+            FloodsubEvent::Unsubscribed { peer_id, topic } => {
+                trace!("Peer {} unsubscribed from {}", peer_id, topic.id());
+                if let Some(subscribers) = self.subscribers.get_mut(topic.id()) {
+                    subscribers.remove(&peer_id);
+                }
+                self.events.push_back(Event::Unsubscribed {
+                    peer:  peer_id,
+                    topic: topic.id().to_owned(),
+                });
+            }
+        }
+    }
 }
@@ -0,0 +1,371 @@
+//! At-least-once delivery on acknowledged topics.
+//!
+//! Envelopes we publish on topics subscribed with
+//! [`TopicOptions::acked`] carry a sequence number, counting up per topic
+//! from a random start so a restarted node does not reuse recent numbers.
+//! Receivers acknowledge each of them with an [`Ack`] sent back to the
+//! publisher over the [`super::direct`] protocol, on [`ACK_TOPIC`]. The
+//! publisher expects an acknowledgement from every subscriber of the topic
+//! it knew of when publishing, and after `retry` sends the message again,
+//! directly, to those that did not acknowledge it, up to `retries` times.
+//! Receivers acknowledge every copy but deliver a sequence number of a
+//! publisher once, remembering the last `window` of them per publisher and
+//! topic. Tune it with `--reliable "retry=5s retries=5 window=1024"`.
+//!
+//! The sequence number is signed with the envelope. At most [`MAX_UNACKED`]
+//! messages wait for acknowledgements, dropping the oldest first. Peers that
+//! subscribe after a message was published do not get it, see
+//! [`crate::node::archive`] for that.
+//!
+//! [`TopicOptions::acked`]: crate::node::subscriptions::TopicOptions::acked
+
+use super::cbor_codec::{decode, encode};
+use crate::{node::options, prelude::*};
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Topic of acknowledgements, sent as direct messages.
+pub const ACK_TOPIC: &str = "/mesh-rs/ack/version/1";
+
+/// Most messages waiting for acknowledgements.
+pub const MAX_UNACKED: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Wait for acknowledgements before sending again.
+    pub retry:   Duration,
+    /// Times a message is sent again before giving up.
+    pub retries: u32,
+    /// Sequence numbers remembered per publisher and topic.
+    pub window:  usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            retry:   Duration::from_secs(5),
+            retries: 5,
+            window:  1024,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "retry" => {
+                    config.retry = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid retry {}", value))?;
+                }
+                "retries" => {
+                    config.retries = value
+                        .parse()
+                        .with_context(|| format!("Invalid retries {}", value))?;
+                }
+                "window" => {
+                    config.window = value
+                        .parse()
+                        .with_context(|| format!("Invalid window {}", value))?;
+                }
+                _ => bail!("Unknown reliable delivery option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.retry > Duration::from_secs(0), "Reliable retry must be positive");
+        ensure!(self.window > 0, "Reliable window must be positive");
+        Ok(())
+    }
+}
+
+/// Acknowledgement of the message numbered `seq` on `topic`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Ack {
+    pub topic: String,
+    pub seq:   u64,
+}
+
+impl Ack {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self).expect("Acks always encode")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        decode(bytes).ok()
+    }
+}
+
+/// A message to send again to the `peers` that did not acknowledge it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Retransmit {
+    pub topic: String,
+    pub seq:   u64,
+    /// The encoded envelope.
+    pub data:  Vec<u8>,
+    pub peers: Vec<PeerId>,
+}
+
+#[derive(Debug)]
+struct Outgoing {
+    topic:    String,
+    seq:      u64,
+    data:     Vec<u8>,
+    waiting:  HashSet<PeerId>,
+    attempts: u32,
+    /// Not sent again before then.
+    retry:    Instant,
+}
+
+/// The sequence numbers recently received from a publisher on a topic.
+#[derive(Debug, Default)]
+struct Window {
+    order: VecDeque<u64>,
+    seen:  HashSet<u64>,
+}
+
+/// Sequence numbers, acknowledgements and retransmissions, by wire topic.
+#[derive(Debug, Default)]
+pub struct Reliable {
+    config:   Config,
+    /// Next sequence number of each acknowledged topic.
+    topics:   HashMap<String, u64>,
+    outgoing: VecDeque<Outgoing>,
+    received: HashMap<(PeerId, String), Window>,
+}
+
+impl Reliable {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Number and acknowledge our messages on `topic`, or stop doing so.
+    pub fn set_topic(&mut self, topic: &str, enabled: bool) {
+        if enabled {
+            self.topics
+                .entry(topic.to_owned())
+                .or_insert_with(|| u64::from(rand::random::<u32>()));
+        } else if self.topics.remove(topic).is_some() {
+            self.outgoing.retain(|outgoing| outgoing.topic != topic);
+        }
+    }
+
+    /// The sequence number of our next message on `topic`, if it is
+    /// acknowledged.
+    pub fn next_seq(&mut self, topic: &str) -> Option<u64> {
+        let next = self.topics.get_mut(topic)?;
+        let seq = *next;
+        *next = next.wrapping_add(1);
+        Some(seq)
+    }
+
+    /// Wait for the `peers` we published message `seq` on `topic` to.
+    pub fn sent(&mut self, topic: &str, seq: u64, data: Vec<u8>, peers: &[PeerId], now: Instant) {
+        if peers.is_empty() {
+            return;
+        }
+        if self.outgoing.len() >= MAX_UNACKED {
+            if let Some(outgoing) = self.outgoing.pop_front() {
+                warn!(
+                    "Too many unacknowledged messages, dropping {} on {}",
+                    outgoing.seq, outgoing.topic
+                );
+            }
+        }
+        self.outgoing.push_back(Outgoing {
+            topic: topic.to_owned(),
+            seq,
+            data,
+            waiting: peers.iter().cloned().collect(),
+            attempts: 0,
+            retry: now + self.config.retry,
+        });
+    }
+
+    /// `peer_id` acknowledged a message. Returns false if we were not
+    /// waiting for it.
+    pub fn acked(&mut self, peer_id: &PeerId, ack: &Ack) -> bool {
+        let index = match self
+            .outgoing
+            .iter()
+            .position(|outgoing| outgoing.seq == ack.seq && outgoing.topic == ack.topic)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+        let outgoing = &mut self.outgoing[index];
+        let acked = outgoing.waiting.remove(peer_id);
+        if outgoing.waiting.is_empty() {
+            trace!("Message {} on {} acknowledged by all", ack.seq, ack.topic);
+            self.outgoing.remove(index);
+        }
+        acked
+    }
+
+    /// Record message `seq` from `source` on `topic`. Returns false if it
+    /// was received before.
+    pub fn receive(&mut self, source: &PeerId, topic: &str, seq: u64) -> bool {
+        let window = self
+            .received
+            .entry((source.clone(), topic.to_owned()))
+            .or_default();
+        if !window.seen.insert(seq) {
+            return false;
+        }
+        window.order.push_back(seq);
+        while window.order.len() > self.config.window {
+            if let Some(oldest) = window.order.pop_front() {
+                window.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Forget the messages received from `peer_id` on `topic`.
+    pub fn forget(&mut self, peer_id: &PeerId, topic: &str) {
+        self.received.remove(&(peer_id.clone(), topic.to_owned()));
+    }
+
+    /// The messages to send again `now`. Messages sent `retries` times
+    /// are given up on.
+    pub fn due(&mut self, now: Instant) -> Vec<Retransmit> {
+        let mut due = Vec::new();
+        let retries = self.config.retries;
+        let retry = self.config.retry;
+        self.outgoing.retain(|outgoing| {
+            if now < outgoing.retry {
+                return true;
+            }
+            if outgoing.attempts >= retries {
+                warn!(
+                    "Giving up message {} on {}, unacknowledged by {} peers",
+                    outgoing.seq,
+                    outgoing.topic,
+                    outgoing.waiting.len()
+                );
+                return false;
+            }
+            true
+        });
+        for outgoing in &mut self.outgoing {
+            if now < outgoing.retry {
+                continue;
+            }
+            outgoing.attempts += 1;
+            outgoing.retry = now + retry;
+            let mut peers: Vec<_> = outgoing.waiting.iter().cloned().collect();
+            peers.sort();
+            due.push(Retransmit {
+                topic: outgoing.topic.clone(),
+                seq: outgoing.seq,
+                data: outgoing.data.clone(),
+                peers,
+            });
+        }
+        due
+    }
+
+    /// Messages waiting for acknowledgements.
+    pub fn unacked(&self) -> usize {
+        self.outgoing.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "retry=2s retries=3 window=16".parse().unwrap();
+        assert_eq!(config.retry, Duration::from_secs(2));
+        assert_eq!(config.retries, 3);
+        assert_eq!(config.window, 16);
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("retry=0s".parse::<Config>().is_err());
+        assert!("window=0".parse::<Config>().is_err());
+        assert!("timeout=1s".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_retransmits_until_acked() {
+        let config: Config = "retry=1s retries=2".parse().unwrap();
+        let mut reliable = Reliable::new(config);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+
+        assert_eq!(reliable.next_seq("chat"), None);
+        reliable.set_topic("chat", true);
+        let seq = reliable.next_seq("chat").unwrap();
+        assert_eq!(reliable.next_seq("chat"), Some(seq + 1));
+        reliable.sent("chat", seq, b"hello".to_vec(), &[alice.clone(), bob.clone()], now);
+        assert_eq!(reliable.due(now), vec![]);
+
+        let ack = Ack {
+            topic: "chat".into(),
+            seq,
+        };
+        assert_eq!(Ack::decode(&ack.to_bytes()), Some(ack.clone()));
+        assert!(reliable.acked(&alice, &ack));
+        assert!(!reliable.acked(&alice, &ack));
+        assert_eq!(reliable.due(at(1)), vec![Retransmit {
+            topic: "chat".into(),
+            seq,
+            data: b"hello".to_vec(),
+            peers: vec![bob.clone()],
+        }]);
+        assert_eq!(reliable.due(at(2)).len(), 1);
+        assert_eq!(reliable.due(at(3)), vec![]);
+        assert_eq!(reliable.unacked(), 0);
+
+        reliable.sent("chat", seq + 1, b"again".to_vec(), &[bob.clone()], now);
+        assert!(reliable.acked(&bob, &Ack {
+            topic: "chat".into(),
+            seq:   seq + 1,
+        }));
+        assert_eq!(reliable.unacked(), 0);
+    }
+
+    #[test]
+    fn test_drops_duplicates() {
+        let config: Config = "window=2".parse().unwrap();
+        let mut reliable = Reliable::new(config);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        assert!(reliable.receive(&alice, "chat", 7));
+        assert!(!reliable.receive(&alice, "chat", 7));
+        assert!(reliable.receive(&bob, "chat", 7));
+        assert!(reliable.receive(&alice, "news", 7));
+        assert!(reliable.receive(&alice, "chat", 8));
+        assert!(reliable.receive(&alice, "chat", 9));
+        assert!(reliable.receive(&alice, "chat", 7));
+        reliable.forget(&alice, "chat");
+        assert!(reliable.receive(&alice, "chat", 9));
+    }
+}
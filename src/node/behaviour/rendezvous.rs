@@ -0,0 +1,295 @@
+//! Registration and discovery at rendezvous points, see
+//! [`crate::node::rendezvous`].
+
+use super::{cbor_codec::CborCodec, Event};
+use crate::{
+    node::rendezvous::{Client, Registry, Request, Response},
+    prelude::*,
+};
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    Multiaddr, NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    iter,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mesh-rs/rendezvous/version/1"
+    }
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event", poll_method = "poll_events")]
+pub struct Rendezvous {
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<Event>,
+
+    /// Set when we are a rendezvous point.
+    #[behaviour(ignore)]
+    registry: Option<Registry>,
+
+    #[behaviour(ignore)]
+    client: Client,
+
+    /// Discoveries waiting for their answer, by namespace.
+    #[behaviour(ignore)]
+    discovering: HashMap<RequestId, String>,
+}
+
+impl Rendezvous {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(30));
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            events:           VecDeque::new(),
+            registry:         None,
+            client:           Client::default(),
+            discovering:      HashMap::new(),
+        }
+    }
+
+    /// Keep the registrations of other peers.
+    pub fn serve(&mut self) {
+        self.registry.get_or_insert_with(Registry::default);
+    }
+
+    /// Register and discover at `peer_id`.
+    pub fn add_point(&mut self, peer_id: PeerId) {
+        self.client.add_point(peer_id);
+    }
+
+    pub fn points(&self) -> &[PeerId] {
+        self.client.points()
+    }
+
+    pub fn dials(&mut self, now: Instant, connected: impl Fn(&PeerId) -> bool) -> Vec<PeerId> {
+        self.client.dials(connected, now)
+    }
+
+    /// Register under `namespaces` at `addresses`, and discover their
+    /// registrants, when due.
+    pub fn tick(&mut self, now: Instant, namespaces: &BTreeSet<String>, addresses: &[Multiaddr]) {
+        let request_response = &self.request_response;
+        let requests = self.client.due(
+            namespaces,
+            addresses,
+            |point| request_response.is_connected(point),
+            now,
+        );
+        for (point, request) in requests {
+            let namespace = match &request {
+                Request::Discover { namespace } => Some(namespace.clone()),
+                _ => None,
+            };
+            trace!("Rendezvous {:?} at {}", request, point);
+            let request_id = self.request_response.send_request(&point, request);
+            if let Some(namespace) = namespace {
+                self.discovering.insert(request_id, namespace);
+            }
+        }
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, Event>> {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Rendezvous {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => {
+                let response = match &mut self.registry {
+                    Some(registry) => registry.handle(&peer, request, Instant::now()),
+                    None => Response::Refused("Not a rendezvous point".into()),
+                };
+                if self.request_response.send_response(channel, response).is_err() {
+                    debug!("Could not answer rendezvous request from {}", peer);
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                },
+            } => {
+                let namespace = self.discovering.remove(&request_id);
+                match response {
+                    Response::Registered(ttl_secs) => {
+                        debug!("Registered at {} for {}s", peer, ttl_secs);
+                    }
+                    Response::Unregistered => {}
+                    Response::Refused(reason) => {
+                        warn!("Rendezvous point {} refused us: {}", peer, reason);
+                    }
+                    Response::Registrations(registrations) => {
+                        let namespace = namespace.unwrap_or_default();
+                        for registration in registrations {
+                            match registration.peer_id() {
+                                Ok(registered) => {
+                                    self.events.push_back(Event::RendezvousDiscovered {
+                                        namespace: namespace.clone(),
+                                        peer:      registered,
+                                        addresses: registration.addresses,
+                                    });
+                                }
+                                Err(err) => debug!("Registration from {}: {}", peer, err),
+                            }
+                        }
+                    }
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!("Rendezvous request to {} failed: {:?}", peer, error);
+                self.discovering.remove(&request_id);
+                if matches!(error, OutboundFailure::UnsupportedProtocols) {
+                    let event = Event::unsupported(peer, Version().protocol_name());
+                    self.events.push_back(event);
+                } else {
+                    self.client.failed(&peer);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("Rendezvous request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::rendezvous::Registration,
+        test::{prelude::assert_eq, swarm},
+    };
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::{
+        core::upgrade::write_with_len_prefix, request_response::RequestResponseCodec, Swarm,
+    };
+
+    #[test]
+    fn test_refuses_malformed_messages() {
+        let mut bytes = Vec::new();
+        let request = Request::Discover {
+            namespace: "chat".into(),
+        };
+        block_on(Codec::default().write_request(&Version(), &mut bytes, request.clone())).unwrap();
+        let read = |bytes: &[u8]| {
+            block_on(Codec::default().read_request(&Version(), &mut Cursor::new(bytes)))
+        };
+        assert_eq!(read(&bytes).unwrap(), request);
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut unknown = Vec::new();
+        block_on(write_with_len_prefix(
+            &mut unknown,
+            serde_cbor::to_vec(&"Delete").unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(
+            read(&unknown).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let registration = Registration {
+            peer:      b"not a peer id".to_vec(),
+            addresses: Vec::new(),
+        };
+        assert!(registration.peer_id().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registers_and_discovers_at_point() {
+        let (alice_id, mut alice) = swarm::new(Rendezvous::new());
+        let (bob_id, mut bob) = swarm::new(Rendezvous::new());
+        bob.serve();
+        let carol = PeerId::random();
+        let carol_address: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+        let register = Request::Register {
+            namespace: "chat".into(),
+            addresses: vec![carol_address.clone()],
+            ttl_secs:  60,
+        };
+        let registry = bob.registry.as_mut().unwrap();
+        registry.handle(&carol, register, Instant::now());
+        let address = swarm::listen(&mut bob).await;
+        Swarm::dial_addr(&mut alice, address).unwrap();
+        alice.add_point(bob_id);
+
+        let namespaces = iter::once("chat".to_owned()).collect();
+        let alice_address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let addresses = [alice_address.clone()];
+        let discovered = swarm::run_until(&mut alice, &mut bob, |alice, event| {
+            alice.tick(Instant::now(), &namespaces, &addresses);
+            match event {
+                Some(Event::RendezvousDiscovered {
+                    namespace,
+                    peer,
+                    addresses,
+                }) => Some((namespace, peer, addresses)),
+                _ => None,
+            }
+        })
+        .await;
+        assert_eq!(
+            discovered,
+            ("chat".into(), carol.clone(), vec![carol_address])
+        );
+
+        let discover = || {
+            Request::Discover {
+                namespace: "chat".into(),
+            }
+        };
+        let registrations = swarm::run_until(&mut bob, &mut alice, |bob, _| {
+            let registry = bob.registry.as_mut().unwrap();
+            match registry.handle(&carol, discover(), Instant::now()) {
+                Response::Registrations(registrations) if !registrations.is_empty() => {
+                    Some(registrations)
+                }
+                _ => None,
+            }
+        })
+        .await;
+        assert_eq!(registrations, vec![Registration {
+            peer:      alice_id.as_bytes().to_vec(),
+            addresses: vec![alice_address],
+        }]);
+    }
+}
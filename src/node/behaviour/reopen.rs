@@ -0,0 +1,73 @@
+//! Sending requests again after their stream was reset.
+//!
+//! The request-response handler of libp2p 0.32 closes the whole connection
+//! on any stream error but an unsupported protocol, like a reset stream or
+//! a frame over the limit of the multiplexer, and every request on the
+//! connection fails with `ConnectionClosed`. [`Failure`] classifies
+//! outbound failures, and the blob, rpc and service behaviours send a
+//! request whose stream was [`Failure::Reset`] again, over a new connection,
+//! up to [`MAX_REOPENS`] times before failing it. Blob fetches go on from
+//! the offset they got to.
+
+use libp2p::request_response::OutboundFailure;
+
+/// Times a request is sent again after its stream was reset.
+pub const MAX_REOPENS: u32 = 3;
+
+/// Why a request failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Failure {
+    /// The stream went down with its connection.
+    Reset,
+    /// No response in time.
+    Timeout,
+    /// The peer could not be dialed.
+    Unreachable,
+    /// The peer does not speak the protocol.
+    Unsupported,
+}
+
+impl From<&OutboundFailure> for Failure {
+    fn from(error: &OutboundFailure) -> Self {
+        match error {
+            OutboundFailure::ConnectionClosed => Self::Reset,
+            OutboundFailure::Timeout => Self::Timeout,
+            OutboundFailure::DialFailure => Self::Unreachable,
+            OutboundFailure::UnsupportedProtocols => Self::Unsupported,
+        }
+    }
+}
+
+/// The reopens of one request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Reopens(u32);
+
+impl Reopens {
+    /// Count sending the request again after `error`, or return false if it
+    /// should fail instead.
+    pub fn reopen(&mut self, error: &OutboundFailure) -> bool {
+        if Failure::from(error) != Failure::Reset || self.0 >= MAX_REOPENS {
+            return false;
+        }
+        self.0 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_reopens_reset_streams_only() {
+        assert_eq!(Failure::from(&OutboundFailure::DialFailure), Failure::Unreachable);
+        let mut reopens = Reopens::default();
+        assert!(!reopens.reopen(&OutboundFailure::Timeout));
+        assert!(!reopens.reopen(&OutboundFailure::UnsupportedProtocols));
+        for _ in 0..MAX_REOPENS {
+            assert!(reopens.reopen(&OutboundFailure::ConnectionClosed));
+        }
+        assert!(!reopens.reopen(&OutboundFailure::ConnectionClosed));
+    }
+}
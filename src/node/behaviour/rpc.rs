@@ -0,0 +1,354 @@
+//! Requests to a specific peer, answered by its application.
+//!
+//! Pubsub broadcasts, and a [`service`](super::service) call goes to any
+//! provider. [`Rpc::request`] sends bytes to the one peer given and waits
+//! for the bytes its handler replies with, or fails after the request
+//! timeout. Peers without a handler answer every request with an error.
+//! Requests and replies larger than [`MAX_SIZE`] are refused. A request
+//! whose stream was reset is sent again, see [`super::reopen`], so a handler
+//! may see a request more than once.
+
+use super::{cbor_codec::CborCodec, reopen::Reopens};
+use crate::prelude::*;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    stream::FuturesUnordered,
+};
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::HashMap,
+    iter,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+pub const PROTOCOL_NAME: &str = "/mesh-rs/rpc/version/1";
+
+/// Largest request or reply.
+pub const MAX_SIZE: usize = 1024 * 1024;
+
+/// How long a request waits for its reply by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request timeout.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        PROTOCOL_NAME.as_bytes()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Request(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Reply(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The peer has no handler for requests.
+    Unavailable,
+    Error(String),
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+pub type Result = std::result::Result<Vec<u8>, Error>;
+
+/// Outcome of handling a request, as produced by the application.
+pub type HandlerResult = std::result::Result<Vec<u8>, String>;
+
+#[derive(Error, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    #[error("Peer {0} did not reply in time")]
+    Timeout(PeerId),
+
+    #[error("Peer {0} does not answer requests")]
+    Unavailable(PeerId),
+
+    #[error("Peer {0} failed the request: {1}")]
+    Failed(PeerId, String),
+
+    #[error("Request to {0} not delivered: {1}")]
+    Network(PeerId, String),
+}
+
+/// An inbound request for the application.
+#[derive(Debug)]
+pub struct RpcRequest {
+    pub peer_id: PeerId,
+    pub data:    Vec<u8>,
+
+    responder: oneshot::Sender<HandlerResult>,
+}
+
+impl RpcRequest {
+    /// Send the reply back to the peer.
+    pub fn respond(self, result: HandlerResult) {
+        if self.responder.send(result).is_err() {
+            warn!("Reply to {} dropped, request expired", self.peer_id);
+        }
+    }
+}
+
+type PendingResponse = BoxFuture<'static, (ResponseChannel<Response>, Response)>;
+
+/// A request waiting for its reply.
+struct Call {
+    peer_id: PeerId,
+    /// Kept to send the request again.
+    data:    Vec<u8>,
+    reopens: Reopens,
+    sender:  oneshot::Sender<Result>,
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll_events")]
+pub struct Rpc {
+    request_response: RequestResponse<Codec>,
+
+    /// Where inbound requests go.
+    #[behaviour(ignore)]
+    handler: Option<mpsc::Sender<RpcRequest>>,
+
+    #[behaviour(ignore)]
+    timeout: Duration,
+
+    /// Requests by the id of the call, kept when a request is reopened.
+    #[behaviour(ignore)]
+    calls: HashMap<u64, Call>,
+
+    #[behaviour(ignore)]
+    pending_requests: HashMap<RequestId, u64>,
+
+    #[behaviour(ignore)]
+    next_call: u64,
+
+    #[behaviour(ignore)]
+    pending_responses: FuturesUnordered<PendingResponse>,
+
+    #[behaviour(ignore)]
+    timeouts: FuturesUnordered<BoxFuture<'static, u64>>,
+}
+
+impl Rpc {
+    pub fn new() -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        // Requests time out by our own timers, which may be changed later
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(MAX_TIMEOUT);
+        Self {
+            request_response: RequestResponse::new(Codec::new(MAX_SIZE), protocols, config),
+            handler: None,
+            timeout: DEFAULT_TIMEOUT,
+            calls: HashMap::new(),
+            pending_requests: HashMap::new(),
+            next_call: 0,
+            pending_responses: FuturesUnordered::new(),
+            timeouts: FuturesUnordered::new(),
+        }
+    }
+
+    /// Send inbound requests to `handler`, instead of refusing them.
+    pub fn serve(&mut self, handler: mpsc::Sender<RpcRequest>) {
+        self.handler = Some(handler);
+    }
+
+    /// Fail requests that are not answered within `timeout`, at most
+    /// [`MAX_TIMEOUT`].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout.min(MAX_TIMEOUT);
+    }
+
+    /// Send `data` to `peer_id`, dialing it if needed.
+    pub fn request(&mut self, peer_id: &PeerId, data: Vec<u8>, sender: oneshot::Sender<Result>) {
+        let call = self.next_call;
+        self.next_call += 1;
+        self.timeouts
+            .push(Box::pin(sleep(self.timeout).map(move |()| call)));
+        self.calls.insert(call, Call {
+            peer_id: peer_id.clone(),
+            data,
+            reopens: Reopens::default(),
+            sender,
+        });
+        self.send(call);
+    }
+
+    fn send(&mut self, call: u64) {
+        if let Some(Call { peer_id, data, .. }) = self.calls.get(&call) {
+            let request_id = self
+                .request_response
+                .send_request(peer_id, Request(data.clone()));
+            trace!("Request {} sent to {}", request_id, peer_id);
+            self.pending_requests.insert(request_id, call);
+        }
+    }
+
+    fn finish(&mut self, call: u64, result: impl FnOnce(PeerId) -> Result) {
+        if let Some(Call {
+            peer_id, sender, ..
+        }) = self.calls.remove(&call)
+        {
+            let _ = sender.send(result(peer_id));
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        data: Vec<u8>,
+        channel: ResponseChannel<Response>,
+    ) {
+        let (responder, receiver) = oneshot::channel();
+        let request = RpcRequest {
+            peer_id: peer_id.clone(),
+            data,
+            responder,
+        };
+        let accepted = match &mut self.handler {
+            Some(handler) => handler.try_send(request).is_ok(),
+            None => false,
+        };
+        if !accepted {
+            debug!("Refusing request from {}, no handler available", peer_id);
+            if self
+                .request_response
+                .send_response(channel, Response::Unavailable)
+                .is_err()
+            {
+                warn!("Could not refuse request from {}", peer_id);
+            }
+            return;
+        }
+        self.pending_responses.push(Box::pin(async move {
+            let response = match receiver.await {
+                Ok(Ok(data)) => Response::Reply(data),
+                Ok(Err(message)) => Response::Error(message),
+                Err(_) => Response::Error("Handler dropped the request".into()),
+            };
+            (channel, response)
+        }));
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        let mut progress = false;
+        while let Poll::Ready(Some(call)) = self.timeouts.poll_next_unpin(cx) {
+            self.finish(call, |peer_id| Err(Error::Timeout(peer_id)));
+        }
+        while let Poll::Ready(Some((channel, response))) =
+            self.pending_responses.poll_next_unpin(cx)
+        {
+            if self.request_response.send_response(channel, response).is_err() {
+                warn!("Request expired before the handler replied");
+            }
+            progress = true;
+        }
+        // Let the request-response behaviour send what we queued
+        if progress {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Rpc {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => self.handle_request(peer, request.0, channel),
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let call = match self.pending_requests.remove(&request_id) {
+                    Some(call) => call,
+                    None => return,
+                };
+                self.finish(call, |peer_id| {
+                    match response {
+                        Response::Reply(data) => Ok(data),
+                        Response::Unavailable => Err(Error::Unavailable(peer_id)),
+                        Response::Error(message) => Err(Error::Failed(peer_id, message)),
+                    }
+                });
+            }
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                let call = match self.pending_requests.remove(&request_id) {
+                    Some(call) => call,
+                    None => return,
+                };
+                let reopen = self
+                    .calls
+                    .get_mut(&call)
+                    .map_or(false, |call| call.reopens.reopen(&error));
+                if reopen {
+                    debug!("Request stream reset, sending request {} again", request_id);
+                    self.send(call);
+                    return;
+                }
+                self.finish(call, |peer_id| {
+                    match error {
+                        OutboundFailure::Timeout => Err(Error::Timeout(peer_id)),
+                        error => Err(Error::Network(peer_id, format!("{:?}", error))),
+                    }
+                });
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("Replying to request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let request = Request(b"ping".to_vec());
+        let encoded = serde_cbor::to_vec(&request).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Request>(&encoded).unwrap(), request);
+        for response in vec![
+            Response::Reply(b"pong".to_vec()),
+            Response::Unavailable,
+            Response::Error("busy".into()),
+        ] {
+            let encoded = serde_cbor::to_vec(&response).unwrap();
+            assert_eq!(serde_cbor::from_slice::<Response>(&encoded).unwrap(), response);
+        }
+        let peer_id = PeerId::random();
+        assert_eq!(
+            Error::Timeout(peer_id.clone()).to_string(),
+            format!("Peer {} did not reply in time", peer_id)
+        );
+    }
+}
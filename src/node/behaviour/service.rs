@@ -0,0 +1,660 @@
+//! Anycast routing of requests to named application services.
+//!
+//! Nodes advertise the services they provide as [`ServiceDescriptor`]s: a
+//! name, a version and metadata. Every [`REFRESH_INTERVAL`] we ask connected
+//! peers that speak this protocol for their descriptors and record them in
+//! the [`PeerInfo`] database, where [`Service::find`] looks them up; peers
+//! too old to describe their services are asked for their names only. A call
+//! is routed to the provider with the lowest ping round trip time and fails
+//! over to the next best provider when a request fails. A call whose stream
+//! was reset goes to the same provider again first, see [`super::reopen`].
+//!
+//! Work queues are built on the same mechanism: consumers of queue `q`
+//! advertise the service named by [`job_service`] and a job is routed to the
+//! consumer that wins rendezvous hashing on the job key. If the consumer does
+//! not finish the job within its visibility timeout, the job is re-dispatched
+//! to the next consumer in hash order.
+//!
+//! ## To do
+//!
+//! * Push service list changes instead of relying on periodic refresh.
+
+use super::{
+    cbor_codec::CborCodec,
+    discovery::{PeerInfo, PeerStore},
+    reopen::Reopens,
+};
+use crate::{prelude::*, utils::fnv1a};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    stream::FuturesUnordered,
+};
+use libp2p::{
+    core::ProtocolName,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    iter,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{interval, sleep, Interval};
+
+pub const PROTOCOL_NAME: &str = "/mesh-rs/service/version/1";
+
+/// How often to ask connected peers for the services they provide.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct Version();
+
+impl ProtocolName for Version {
+    fn protocol_name(&self) -> &[u8] {
+        PROTOCOL_NAME.as_bytes()
+    }
+}
+
+/// A service a node provides.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct ServiceDescriptor {
+    pub name:     String,
+    /// Version of the service's interface, empty if not given.
+    pub version:  String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ServiceDescriptor {
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_owned(), value.to_owned());
+        self
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Ask for the names of the provided services.
+    List,
+
+    /// Call a service.
+    Call {
+        service: String,
+        #[serde(with = "serde_bytes")]
+        data:    Vec<u8>,
+    },
+
+    /// Ask for the descriptors of the provided services.
+    Describe,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Services(Vec<String>),
+    Descriptors(Vec<ServiceDescriptor>),
+    Reply {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub type Codec = CborCodec<Version, Request, Response>;
+pub type Result = std::result::Result<Vec<u8>, Error>;
+
+/// Outcome of handling a call, as produced by the application.
+pub type HandlerResult = std::result::Result<Vec<u8>, String>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No connected peer provides service {0}.")]
+    NoProviders(String),
+
+    #[error("All providers failed, last error: {0}")]
+    Failed(String),
+}
+
+/// An inbound call to a locally advertised service.
+#[derive(Debug)]
+pub struct ServiceRequest {
+    pub peer_id: PeerId,
+    pub service: String,
+    pub data:    Vec<u8>,
+
+    responder: oneshot::Sender<HandlerResult>,
+}
+
+impl ServiceRequest {
+    /// Send the result back to the caller.
+    pub fn respond(self, result: HandlerResult) {
+        if self.responder.send(result).is_err() {
+            warn!("Service {} response dropped, call expired", self.service);
+        }
+    }
+}
+
+/// An outbound call that has not been answered yet.
+struct PendingCall {
+    service:    String,
+    data:       Vec<u8>,
+    candidates: VecDeque<PeerId>,
+    last_error: Option<String>,
+    timeout:    Option<Duration>,
+    /// Reopens of the call to the current candidate.
+    reopens:    Reopens,
+    sender:     oneshot::Sender<Result>,
+}
+
+type PendingResponse = BoxFuture<'static, (ResponseChannel<Response>, Response)>;
+
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll_events")]
+pub struct Service {
+    request_response: RequestResponse<Codec>,
+
+    #[behaviour(ignore)]
+    peer_info: PeerStore,
+
+    /// Locally provided services and the channel to their handler.
+    #[behaviour(ignore)]
+    local_services: HashMap<String, (ServiceDescriptor, mpsc::Sender<ServiceRequest>)>,
+
+    /// Peers asked to describe their services, to ask for the list instead
+    /// if they can not.
+    #[behaviour(ignore)]
+    describing: HashMap<RequestId, PeerId>,
+
+    #[behaviour(ignore)]
+    pending_calls: HashMap<RequestId, PendingCall>,
+
+    #[behaviour(ignore)]
+    pending_responses: FuturesUnordered<PendingResponse>,
+
+    /// Visibility timeouts of pending calls.
+    #[behaviour(ignore)]
+    timeouts: FuturesUnordered<BoxFuture<'static, RequestId>>,
+
+    #[behaviour(ignore)]
+    refresh: Pin<Box<Interval>>,
+}
+
+/// Name of the service consumers of work queue `queue` advertise.
+pub fn job_service(queue: &str) -> String {
+    format!("job/{}", queue)
+}
+
+/// Order `peers` by rendezvous (highest random weight) hashing on `key`.
+///
+/// Every producer computes the same order for the same key and consumer set,
+/// and adding or removing a consumer only moves the keys it wins or loses.
+fn rendezvous_order(key: &str, peers: impl IntoIterator<Item = PeerId>) -> VecDeque<PeerId> {
+    let mut weighted = peers
+        .into_iter()
+        .map(|peer_id| {
+            let weight = fnv1a(key.bytes().chain(peer_id.as_bytes().iter().copied()));
+            (weight, peer_id)
+        })
+        .collect::<Vec<_>>();
+    weighted.sort_by_key(|(weight, _)| Reverse(*weight));
+    weighted.into_iter().map(|(_, peer_id)| peer_id).collect()
+}
+
+impl Service {
+    pub fn new(peer_info: PeerStore) -> Self {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(Duration::from_secs(30));
+        Self {
+            request_response: RequestResponse::new(Codec::default(), protocols, config),
+            peer_info,
+            local_services: HashMap::new(),
+            describing: HashMap::new(),
+            pending_calls: HashMap::new(),
+            pending_responses: FuturesUnordered::new(),
+            timeouts: FuturesUnordered::new(),
+            refresh: Box::pin(interval(REFRESH_INTERVAL)),
+        }
+    }
+
+    /// Start providing the service of `descriptor`. Inbound calls are sent
+    /// to `handler`.
+    pub fn advertise(
+        &mut self,
+        descriptor: ServiceDescriptor,
+        handler: mpsc::Sender<ServiceRequest>,
+    ) {
+        let name = descriptor.name.clone();
+        if self
+            .local_services
+            .insert(name.clone(), (descriptor, handler))
+            .is_some()
+        {
+            warn!("Replacing existing handler for service {}", name);
+        }
+    }
+
+    /// Known providers of `service`, connected and nearest first.
+    pub fn find(&self, service: &str) -> Vec<(PeerId, ServiceDescriptor)> {
+        let lock = self.peer_info.read().unwrap(); // FIXME: Can block
+        let mut providers = lock
+            .values()
+            .filter_map(|info| {
+                let descriptor = info.services.iter().find(|s| s.name == service)?;
+                let connected = self.request_response.is_connected(&info.peer_id);
+                Some(((!connected, info.ping.is_none(), info.ping), info, descriptor))
+            })
+            .collect::<Vec<_>>();
+        // Connected first, then nearest, providers without a ping last
+        providers.sort_by_key(|(order, ..)| *order);
+        providers
+            .into_iter()
+            .map(|(_, info, descriptor)| (info.peer_id.clone(), descriptor.clone()))
+            .collect()
+    }
+
+    /// Stop providing `service`.
+    pub fn withdraw(&mut self, service: &str) {
+        self.local_services.remove(service);
+    }
+
+    /// Call the nearest provider of `service`.
+    pub fn call(&mut self, service: String, data: Vec<u8>, sender: oneshot::Sender<Result>) {
+        let candidates = self.providers(&service);
+        debug!(
+            "Calling service {} with {} candidate providers",
+            service,
+            candidates.len()
+        );
+        self.dispatch(PendingCall {
+            service,
+            data,
+            candidates,
+            last_error: None,
+            timeout: None,
+            reopens: Reopens::default(),
+            sender,
+        });
+    }
+
+    /// Hand a job to exactly one consumer of `queue`, selected by hashing
+    /// `key`. The job is re-dispatched if the consumer fails or does not
+    /// respond within `visibility_timeout`.
+    pub fn push_job(
+        &mut self,
+        queue: &str,
+        key: &str,
+        data: Vec<u8>,
+        visibility_timeout: Duration,
+        sender: oneshot::Sender<Result>,
+    ) {
+        let service = job_service(queue);
+        let candidates = rendezvous_order(key, self.providers(&service));
+        debug!(
+            "Pushing job {} to queue {} with {} consumers",
+            key,
+            queue,
+            candidates.len()
+        );
+        self.dispatch(PendingCall {
+            service,
+            data,
+            candidates,
+            last_error: None,
+            timeout: Some(visibility_timeout),
+            reopens: Reopens::default(),
+            sender,
+        });
+    }
+
+    /// Connected providers of `service`, nearest first.
+    fn providers(&self, service: &str) -> VecDeque<PeerId> {
+        let lock = self.peer_info.read().unwrap(); // FIXME: Can block
+        let mut providers = lock
+            .values()
+            .filter(|info| info.services.iter().any(|s| s.name == service))
+            .filter(|info| self.request_response.is_connected(&info.peer_id))
+            .map(|info| (info.ping, info.peer_id.clone()))
+            .collect::<Vec<_>>();
+        drop(lock);
+        // Nearest first, providers without a ping measurement last.
+        providers.sort_by_key(|(rtt, _)| (rtt.is_none(), *rtt));
+        providers.into_iter().map(|(_, peer_id)| peer_id).collect()
+    }
+
+    /// Send the call to the next candidate, or fail it if none are left.
+    fn dispatch(&mut self, mut call: PendingCall) {
+        if let Some(peer_id) = call.candidates.pop_front() {
+            let request = Request::Call {
+                service: call.service.clone(),
+                data:    call.data.clone(),
+            };
+            let request_id = self.request_response.send_request(&peer_id, request);
+            trace!(
+                "Service call {} for {} sent to {}",
+                request_id,
+                call.service,
+                peer_id
+            );
+            if let Some(timeout) = call.timeout {
+                self.timeouts
+                    .push(Box::pin(sleep(timeout).map(move |()| request_id)));
+            }
+            self.pending_calls.insert(request_id, call);
+        } else {
+            let error = match call.last_error {
+                Some(message) => Error::Failed(message),
+                None => Error::NoProviders(call.service),
+            };
+            if call.sender.send(Err(error)).is_err() {
+                warn!("Service call failed for dropped handler");
+            }
+        }
+    }
+
+    /// Fail over to the next provider after an error.
+    fn retry(&mut self, request_id: RequestId, error: String) {
+        if let Some(mut call) = self.pending_calls.remove(&request_id) {
+            debug!("Service call {} failed: {}", request_id, error);
+            call.last_error = Some(error);
+            call.reopens = Reopens::default();
+            self.dispatch(call);
+        }
+    }
+
+    /// Ask all connected peers speaking this protocol for their services.
+    fn refresh_providers(&mut self) {
+        let lock = self.peer_info.read().unwrap(); // FIXME: Can block
+        let peers = lock
+            .values()
+            .filter(|info| {
+                info.identify
+                    .as_ref()
+                    .map_or(false, |identify| {
+                        identify.protocols.iter().any(|p| p == PROTOCOL_NAME)
+                    })
+            })
+            .map(|info| info.peer_id.clone())
+            .filter(|peer_id| self.request_response.is_connected(peer_id))
+            .collect::<Vec<_>>();
+        drop(lock);
+        for peer_id in peers {
+            let request_id = self.request_response.send_request(&peer_id, Request::Describe);
+            self.describing.insert(request_id, peer_id);
+        }
+    }
+
+    fn set_services(&mut self, peer: PeerId, services: Vec<ServiceDescriptor>) {
+        trace!("Peer {} provides services {:?}", peer, services);
+        let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
+        let entry = lock
+            .entry(peer.clone())
+            .or_insert_with(|| PeerInfo::new(peer));
+        entry.services = services;
+    }
+
+    /// Answer an inbound request, possibly asynchronously.
+    fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        request: Request,
+        channel: ResponseChannel<Response>,
+    ) {
+        let (service, data) = match request {
+            Request::List => {
+                let services = self.local_services.keys().cloned().collect();
+                let response = Response::Services(services);
+                if self.request_response.send_response(channel, response).is_err() {
+                    warn!("Could not send service list to {}", peer_id);
+                }
+                return;
+            }
+            Request::Describe => {
+                let descriptors = self
+                    .local_services
+                    .values()
+                    .map(|(descriptor, _)| descriptor.clone())
+                    .collect();
+                let response = Response::Descriptors(descriptors);
+                if self.request_response.send_response(channel, response).is_err() {
+                    warn!("Could not send service descriptors to {}", peer_id);
+                }
+                return;
+            }
+            Request::Call { service, data } => (service, data),
+        };
+
+        // Hand the call to the application handler
+        let (responder, receiver) = oneshot::channel();
+        let request = ServiceRequest {
+            peer_id: peer_id.clone(),
+            service: service.clone(),
+            data,
+            responder,
+        };
+        let accepted = self
+            .local_services
+            .get_mut(&service)
+            .map_or(false, |(_, handler)| handler.try_send(request).is_ok());
+        if !accepted {
+            debug!(
+                "Rejecting call from {} for unavailable service {}",
+                peer_id, service
+            );
+            let response = Response::Error {
+                message: format!("Service {} unavailable", service),
+            };
+            if self.request_response.send_response(channel, response).is_err() {
+                warn!("Could not send service error to {}", peer_id);
+            }
+            return;
+        }
+
+        // Respond once the handler is done
+        self.pending_responses.push(Box::pin(async move {
+            let response = match receiver.await {
+                Ok(Ok(data)) => Response::Reply { data },
+                Ok(Err(message)) => Response::Error { message },
+                Err(_) => {
+                    Response::Error {
+                        message: format!("Service {} handler dropped the call", service),
+                    }
+                }
+            };
+            (channel, response)
+        }));
+    }
+
+    fn poll_events<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        let mut progress = false;
+
+        // Periodically refresh the service lists of connected peers
+        while self.refresh.poll_next_unpin(cx).is_ready() {
+            self.refresh_providers();
+            progress = true;
+        }
+
+        // Re-dispatch jobs whose consumer did not respond in time
+        while let Poll::Ready(Some(request_id)) = self.timeouts.poll_next_unpin(cx) {
+            if self.pending_calls.contains_key(&request_id) {
+                self.retry(request_id, "Visibility timeout expired".into());
+                progress = true;
+            }
+        }
+
+        // Send responses as application handlers complete
+        while let Poll::Ready(Some((channel, response))) =
+            self.pending_responses.poll_next_unpin(cx)
+        {
+            if self.request_response.send_response(channel, response).is_err() {
+                warn!("Service call expired before the handler responded");
+            }
+            progress = true;
+        }
+
+        // The request-response behaviour was already polled this round, make
+        // sure it gets to process what we queued.
+        if progress {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>> for Service {
+    fn inject_event(&mut self, event: RequestResponseEvent<Request, Response>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            } => self.handle_request(peer, request, channel),
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                self.describing.remove(&request_id);
+                match response {
+                    Response::Services(services) => {
+                        let descriptors = services
+                            .iter()
+                            .map(|name| ServiceDescriptor::new(name, ""))
+                            .collect();
+                        self.set_services(peer, descriptors);
+                    }
+                    Response::Descriptors(descriptors) => self.set_services(peer, descriptors),
+                    Response::Reply { data } => {
+                        if let Some(call) = self.pending_calls.remove(&request_id) {
+                            if call.sender.send(Ok(data)).is_err() {
+                                warn!("Received service reply for dropped handler");
+                            }
+                        }
+                    }
+                    Response::Error { message } => self.retry(request_id, message),
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if self.describing.remove(&request_id).is_some() {
+                    debug!("Peer {} did not describe its services: {:?}", peer, error);
+                    self.request_response.send_request(&peer, Request::List);
+                    return;
+                }
+                let reopen = self
+                    .pending_calls
+                    .get_mut(&request_id)
+                    .map_or(false, |call| call.reopens.reopen(&error));
+                if let (true, Some(mut call)) = (reopen, self.pending_calls.remove(&request_id)) {
+                    debug!("Service call stream to {} reset, calling it again", peer);
+                    call.candidates.push_front(peer);
+                    self.dispatch(call);
+                    return;
+                }
+                self.retry(request_id, format!("{:?} from {}", error, peer));
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Inbound service request {} from {} failed: {:?}",
+                    request_id, peer, error
+                );
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, *};
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::random()).collect()
+    }
+
+    #[test]
+    fn test_fnv1a_reference() {
+        assert_eq!(fnv1a(b"".iter().copied()), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a".iter().copied()), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_rendezvous_order_is_input_order_independent() {
+        let peers = peers(5);
+        let mut reversed = peers.clone();
+        reversed.reverse();
+        assert_eq!(
+            rendezvous_order("job-1", peers),
+            rendezvous_order("job-1", reversed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_finds_described_providers() {
+        let descriptor = ServiceDescriptor::new("db", "2.1").with_metadata("region", "eu");
+        let response = Response::Descriptors(vec![descriptor.clone()]);
+        let encoded = serde_cbor::to_vec(&response).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Response>(&encoded).unwrap(), response);
+
+        let store = PeerStore::default();
+        let (near, far, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        for (peer_id, ping, name) in &[(&far, 20, "db"), (&near, 5, "db"), (&other, 1, "cache")] {
+            let mut info = PeerInfo::new((*peer_id).clone());
+            info.ping = Some(Duration::from_millis(*ping));
+            info.services = vec![ServiceDescriptor::new(name, "2.1")];
+            store.write().unwrap().insert((*peer_id).clone(), info);
+        }
+        store.write().unwrap().get_mut(&near).unwrap().services = vec![descriptor.clone()];
+        let service = Service::new(store);
+        assert_eq!(service.find("db"), vec![
+            (near, descriptor),
+            (far, ServiceDescriptor::new("db", "2.1")),
+        ]);
+        assert!(service.find("queue").is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn test_rendezvous_removal_is_stable(key in "[a-z0-9]{1,16}", n in 2_usize..8) {
+            // Removing a consumer that did not win a key does not move it.
+            let peers = peers(n);
+            let order = rendezvous_order(&key, peers.clone());
+            let winner = order[0].clone();
+            let loser = order[n - 1].clone();
+            let remaining = peers.into_iter().filter(|p| p != &loser);
+            prop_assert_eq!(&rendezvous_order(&key, remaining)[0], &winner);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Benchmarks, built with the `bench` feature.
+//!
+//! `cargo bench --features bench --bench criterion` runs the criterion
+//! benchmarks of [`group`]. `cargo bench --features bench --bench
+//! regression` measures pubsub between the two nodes of a [`Harness`] in
+//! each of [`SCENARIOS`] and compares the run with the [`Baselines`] in
+//! [`BASELINES`]. Regressions are warned about, or fail the run with
+//! `-- --fail`. `-- --record` stores the run as the new baselines, and
+//! `-- --threshold 0.2` allows 20% instead of [`THRESHOLD`].
+//!
+//! The criterion benchmarks include [`simulation`] runs of many nodes,
+//! configured with `MESH_SIMULATION="nodes=100 degree=4 pattern=burst"`.
+//! The report of the first run is printed before timing the others.
+
+use super::{
+    baseline::{Baselines, Sample, THRESHOLD},
+    harness::Harness,
+    message::{self, Format},
+    simulation,
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use criterion::{black_box, Criterion};
+use std::{env, path::Path, time::Instant};
+
+/// Baselines file, relative to the package.
+pub const BASELINES: &str = "bench-baselines.json";
+
+/// Messages published before waiting for them, so none is dropped from
+/// the event stream.
+const WINDOW: usize = 32;
+
+/// Messages timed one at a time for the latency.
+const ROUNDS: usize = 200;
+
+/// Throughput runs of a scenario, of which the fastest counts.
+const RUNS: usize = 3;
+
+/// Messages of `size` bytes, `count` of them timed for the throughput.
+pub struct Scenario {
+    pub name:  &'static str,
+    pub size:  usize,
+    pub count: usize,
+}
+
+pub const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name:  "pubsub-1KiB",
+        size:  1024,
+        count: 2048,
+    },
+    Scenario {
+        name:  "pubsub-16KiB",
+        size:  16 * 1024,
+        count: 512,
+    },
+];
+
+/// Criterion benchmarks.
+pub fn group(c: &mut Criterion) {
+    let payload = vec![7_u8; 1024];
+    c.bench_function("encode 1KiB", |b| {
+        b.iter(|| message::encode(black_box(&payload), Format::Cbor))
+    });
+
+    let config = env::var("MESH_SIMULATION").unwrap_or_else(|_| simulation::BENCH.to_owned());
+    let config = config
+        .parse::<simulation::Config>()
+        .expect("Invalid MESH_SIMULATION");
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Building the runtime");
+    let simulate = || {
+        runtime
+            .block_on(tokio::task::LocalSet::new().run_until(simulation::run(&config)))
+            .expect("Simulation failed")
+    };
+    println!("{}", simulate());
+    let mut group = c.benchmark_group("simulation");
+    group.sample_size(10);
+    group.bench_function(format!("{} nodes", config.nodes), |b| b.iter(simulate));
+    group.finish();
+}
+
+/// Payload `index` of `size` bytes, distinct so pubsub does not drop it as
+/// a duplicate.
+fn payload(size: usize, index: usize) -> Vec<u8> {
+    let mut data = vec![0_u8; size.max(8)];
+    data[..8].copy_from_slice(&(index as u64).to_be_bytes());
+    data
+}
+
+async fn measure(harness: &mut Harness, scenario: &Scenario) -> Result<Sample> {
+    let topic = scenario.name;
+    harness.subscribe(topic).await?;
+    let mut sender = harness.handle(0);
+
+    let mut latencies = Vec::with_capacity(ROUNDS);
+    for index in 0..ROUNDS {
+        let start = Instant::now();
+        sender.publish(topic, &payload(scenario.size, index)).await?;
+        harness.wait_for_message(1, topic).await?;
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+
+    let mut throughput = 0.0_f64;
+    let mut index = ROUNDS;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        for window in (0..scenario.count).step_by(WINDOW) {
+            let end = (window + WINDOW).min(scenario.count);
+            for _ in window..end {
+                sender.publish(topic, &payload(scenario.size, index)).await?;
+                index += 1;
+            }
+            for _ in window..end {
+                harness.wait_for_message(1, topic).await?;
+            }
+        }
+        throughput = throughput.max(scenario.count as f64 / start.elapsed().as_secs_f64());
+    }
+    Ok(Sample {
+        throughput,
+        latency_ms: latencies[ROUNDS / 2].as_secs_f64() * 1000.0,
+    })
+}
+
+/// Measure every scenario.
+pub async fn run() -> Result<Baselines> {
+    let mut harness = Harness::spawn(2).await?;
+    let mut samples = Baselines::default();
+    for scenario in SCENARIOS {
+        let sample = measure(&mut harness, scenario).await?;
+        info!(
+            "{}: {:.0} messages/s, {:.3} ms",
+            scenario.name, sample.throughput, sample.latency_ms
+        );
+        samples.0.insert(scenario.name.to_owned(), sample);
+    }
+    harness.shutdown().await?;
+    Ok(samples)
+}
+
+/// Run the scenarios and compare them with the baselines at `path` as the
+/// command line `args` say.
+pub fn regressions(path: &Path, args: &[String]) -> Result<()> {
+    let (mut record, mut fail, mut threshold) = (false, false, THRESHOLD);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record = true,
+            "--fail" => fail = true,
+            "--threshold" => {
+                let value = args.next().ok_or_else(|| anyhow!("--threshold needs a value"))?;
+                threshold = value
+                    .parse()
+                    .with_context(|| format!("Invalid threshold {}", value))?;
+            }
+            // Passed by cargo bench
+            "--bench" => {}
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let run = runtime.block_on(tokio::task::LocalSet::new().run_until(run()))?;
+    for (scenario, sample) in &run.0 {
+        println!(
+            "{:<16} {:>10.0} messages/s {:>10.3} ms",
+            scenario, sample.throughput, sample.latency_ms
+        );
+    }
+    if record {
+        run.save(path)?;
+        println!("Recorded baselines in {}", path.display());
+        return Ok(());
+    }
+    let baselines = Baselines::load(path)?;
+    let regressions = baselines.regressions(&run, threshold);
+    for regression in &regressions {
+        eprintln!("Regression of {}", regression);
+    }
+    if fail && !regressions.is_empty() {
+        bail!("{} scenarios regressed", regressions.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! Experimental Bluetooth LE link.
+//!
+//! Connections are L2CAP connection-oriented channels (LE credit based flow
+//! control) opened through the Bluetooth sockets of the Linux kernel, so this
+//! only works on Linux with a Bluetooth 4.1 or later controller. Link
+//! addresses are `[random-]<device address>[@<PSM>]`, like
+//! `ble:00:1A:7D:DA:71:13@133`. The `random-` prefix dials devices with a
+//! random address, as phones use, and the PSM defaults to [`DEFAULT_PSM`].
+//! Listen on all controllers with `--link ble:any`.
+//!
+//! Advertising and scanning are left to the platform. LE links carry tens of
+//! kilobytes per second at best, so they suit sensors and messaging rather
+//! than bulk transfers.
+
+use super::link::{check, Fd, FdStream, Incoming, LinkTransport};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use futures::future::BoxFuture;
+use std::{
+    fmt, io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    str::FromStr,
+};
+use tokio::io::unix::AsyncFd;
+
+/// Protocol/service multiplexer listened on by default, in the range of
+/// dynamically assigned LE PSMs.
+pub const DEFAULT_PSM: u16 = 133;
+
+const SCHEME: &str = "ble";
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_L2CAP: libc::c_int = 0;
+const BDADDR_LE_PUBLIC: u8 = 1;
+const BDADDR_LE_RANDOM: u8 = 2;
+
+/// A device and channel on the link.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Address {
+    /// Device address, most significant byte first. All zeroes for any.
+    pub device: [u8; 6],
+    pub random: bool,
+    pub psm:    u16,
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (s, random) = match s.strip_prefix("random-") {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let (device, psm) = match s.find('@') {
+            Some(index) => {
+                let psm = &s[index + 1..];
+                let psm = psm
+                    .parse::<u16>()
+                    .with_context(|| format!("Invalid PSM {}", psm))?;
+                (&s[..index], psm)
+            }
+            None => (s, DEFAULT_PSM),
+        };
+        let mut address = Self {
+            device: [0; 6],
+            random,
+            psm,
+        };
+        if device == "any" {
+            return Ok(address);
+        }
+        let bytes = device
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("Invalid device address {}", device))?;
+        if bytes.len() != address.device.len() {
+            bail!("Expected six bytes in device address {}", device);
+        }
+        address.device.copy_from_slice(&bytes);
+        Ok(address)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.random {
+            write!(f, "random-")?;
+        }
+        let device = self
+            .device
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>();
+        write!(f, "{}@{}", device.join(":"), self.psm)
+    }
+}
+
+/// `struct sockaddr_l2` of `<bluetooth/l2cap.h>`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SockaddrL2 {
+    l2_family:      libc::sa_family_t,
+    l2_psm:         u16,
+    /// Least significant byte first.
+    l2_bdaddr:      [u8; 6],
+    l2_cid:         u16,
+    l2_bdaddr_type: u8,
+}
+
+impl From<&Address> for SockaddrL2 {
+    fn from(address: &Address) -> Self {
+        let mut bdaddr = address.device;
+        bdaddr.reverse();
+        Self {
+            l2_family:      AF_BLUETOOTH as libc::sa_family_t,
+            l2_psm:         address.psm.to_le(),
+            l2_bdaddr:      bdaddr,
+            l2_cid:         0,
+            l2_bdaddr_type: if address.random {
+                BDADDR_LE_RANDOM
+            } else {
+                BDADDR_LE_PUBLIC
+            },
+        }
+    }
+}
+
+impl From<&SockaddrL2> for Address {
+    fn from(sockaddr: &SockaddrL2) -> Self {
+        let mut device = sockaddr.l2_bdaddr;
+        device.reverse();
+        Self {
+            device,
+            random: sockaddr.l2_bdaddr_type == BDADDR_LE_RANDOM,
+            psm: u16::from_le(sockaddr.l2_psm),
+        }
+    }
+}
+
+/// A non-blocking L2CAP socket.
+#[derive(Debug)]
+struct Socket(Fd);
+
+impl Socket {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(AF_BLUETOOTH, libc::SOCK_STREAM, BTPROTO_L2CAP) };
+        Ok(Self(Fd::new(check(fd)?)?))
+    }
+
+    fn bind(&self, address: &Address) -> io::Result<()> {
+        let sockaddr = SockaddrL2::from(address);
+        check(unsafe {
+            libc::bind(
+                self.as_raw_fd(),
+                (&sockaddr as *const SockaddrL2).cast(),
+                mem::size_of::<SockaddrL2>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn connect(&self, address: &Address) -> io::Result<()> {
+        let sockaddr = SockaddrL2::from(address);
+        check(unsafe {
+            libc::connect(
+                self.as_raw_fd(),
+                (&sockaddr as *const SockaddrL2).cast(),
+                mem::size_of::<SockaddrL2>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn accept(&self) -> io::Result<(Self, Address)> {
+        let mut sockaddr = SockaddrL2::from(&Address {
+            device: [0; 6],
+            random: false,
+            psm:    0,
+        });
+        let mut len = mem::size_of::<SockaddrL2>() as libc::socklen_t;
+        let fd = check(unsafe {
+            libc::accept(
+                self.as_raw_fd(),
+                (&mut sockaddr as *mut SockaddrL2).cast(),
+                &mut len,
+            )
+        })?;
+        Ok((Self(Fd::new(fd)?), Address::from(&sockaddr)))
+    }
+
+    /// The error of a finished non-blocking connect, if it failed.
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut error: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                (&mut error as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        })?;
+        Ok(if error == 0 {
+            None
+        } else {
+            Some(io::Error::from_raw_os_error(error))
+        })
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+fn invalid(err: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:#}", err))
+}
+
+/// The Bluetooth LE [`LinkTransport`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ble;
+
+impl LinkTransport for Ble {
+    type Stream = FdStream;
+
+    fn scheme(&self) -> &'static str {
+        SCHEME
+    }
+
+    fn listen(&self, address: &str) -> io::Result<Incoming<Self::Stream>> {
+        let address: Address = address.parse().map_err(invalid)?;
+        let socket = Socket::new()?;
+        socket.bind(&address)?;
+        check(unsafe { libc::listen(socket.as_raw_fd(), 16) })?;
+        info!("Listening for Bluetooth LE connections on PSM {}", address.psm);
+        let listener = AsyncFd::new(socket)?;
+        Ok(stream::unfold(listener, |listener| {
+            async move {
+                let accepted = loop {
+                    let mut guard = match listener.readable().await {
+                        Ok(guard) => guard,
+                        Err(err) => break Err(err),
+                    };
+                    match guard.with_io(|| listener.get_ref().accept()) {
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                        Err(err) => break Err(err),
+                        Ok((socket, remote)) => {
+                            break FdStream::new(socket.0).map(|stream| (stream, remote.to_string()));
+                        }
+                    }
+                };
+                Some((accepted, listener))
+            }
+        })
+        .boxed())
+    }
+
+    fn dial(&self, address: &str) -> BoxFuture<'static, io::Result<Self::Stream>> {
+        let address = address.parse::<Address>().map_err(invalid);
+        async move {
+            let address = address?;
+            let socket = Socket::new()?;
+            match socket.connect(&address) {
+                Ok(()) => {}
+                Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+                Err(err) => return Err(err),
+            }
+            let socket = AsyncFd::new(socket)?;
+            socket.writable().await?.retain_ready();
+            if let Some(err) = socket.get_ref().take_error()? {
+                return Err(err);
+            }
+            debug!("Connected to Bluetooth LE device {}", address);
+            FdStream::new(socket.into_inner().0)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_addresses() {
+        let address: Address = "00:1A:7D:DA:71:13@129".parse().unwrap();
+        assert_eq!(address.device, [0x00, 0x1a, 0x7d, 0xda, 0x71, 0x13]);
+        assert_eq!(address.psm, 129);
+        assert_eq!(address.to_string(), "00:1A:7D:DA:71:13@129");
+        let random: Address = "random-c0:ff:ee:00:00:01".parse().unwrap();
+        assert_eq!(random.to_string(), "random-C0:FF:EE:00:00:01@133");
+        let any: Address = "any".parse().unwrap();
+        assert_eq!(any.device, [0; 6]);
+        assert!("00:1A:7D".parse::<Address>().is_err());
+        assert!("00:1A:7D:DA:71:13@x".parse::<Address>().is_err());
+
+        // The kernel wants the device address backwards
+        let sockaddr = SockaddrL2::from(&random);
+        assert_eq!(sockaddr.l2_bdaddr, [0x01, 0x00, 0x00, 0xee, 0xff, 0xc0]);
+        assert_eq!(sockaddr.l2_bdaddr_type, BDADDR_LE_RANDOM);
+        assert_eq!(Address::from(&sockaddr), random);
+    }
+}
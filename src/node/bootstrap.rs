@@ -0,0 +1,258 @@
+//! Joining the mesh through bootstrap peers.
+//!
+//! On start the node dials all bootstrap peers at once, the 0x Mesh
+//! bootnodes and those given with `--bootstrap`, instead of waiting for the
+//! Kademlia bootstrap query to reach them one by one. Bootstrap is complete
+//! once `--bootstrap-quorum` of them answered, at which point the node emits
+//! [`Event::Bootstrapped`]. If so many dials fail that the quorum can no
+//! longer be met, the node warns once and keeps running on whatever peers it
+//! finds otherwise.
+//!
+//! Until the quorum is met, bootstrap peers that could not be dialed are
+//! dialed again with exponential backoff, up to [`MAX_BACKOFF`] apart.
+//! Peers listed by `/dnsaddr/` entries join once resolved, see
+//! [`crate::node::dnsaddr`].
+//!
+//! [`Event::Bootstrapped`]: crate::node::Event::Bootstrapped
+
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Longest wait before dialing a bootstrap peer again.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Progress of a bootstrap towards its quorum.
+#[derive(Debug)]
+pub struct Progress {
+    quorum:    usize,
+    /// The quorum asked for, met by fewer peers if there are not so many.
+    wanted:    usize,
+    /// `/dnsaddr/` entries not resolved yet.
+    names:     usize,
+    started:   Instant,
+    /// Peers being dialed, with how often dialing them failed before.
+    pending:   HashMap<PeerId, u32>,
+    /// Peers to dial again at a time, with how often dialing them failed.
+    retries:   HashMap<PeerId, (Instant, u32)>,
+    connected: Vec<PeerId>,
+    /// Whether we warned that the quorum can not be met.
+    warned:    bool,
+    /// Whether the quorum was met, or there was nothing to wait for.
+    complete:  bool,
+}
+
+/// A change in the state of a bootstrap.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Update {
+    /// The quorum was met by `peers` after `elapsed`.
+    Bootstrapped {
+        peers:   Vec<PeerId>,
+        elapsed: Duration,
+    },
+    /// Too many dials failed to meet the quorum, only `connected` answered.
+    Failed { connected: usize, quorum: usize },
+}
+
+impl Progress {
+    /// Wait for `quorum` of `peers`, at least one and at most all of them.
+    pub fn new(peers: impl IntoIterator<Item = PeerId>, quorum: usize, now: Instant) -> Self {
+        let pending: HashMap<_, _> = peers.into_iter().map(|peer| (peer, 0)).collect();
+        Self {
+            quorum: quorum.clamp(1, pending.len().max(1)),
+            wanted: quorum,
+            names: 0,
+            started: now,
+            complete: pending.is_empty(),
+            pending,
+            retries: HashMap::new(),
+            connected: Vec::new(),
+            warned: false,
+        }
+    }
+
+    /// Also wait for the peers of `names` `/dnsaddr/` entries.
+    pub fn with_names(mut self, names: usize) -> Self {
+        self.names = names;
+        if names > 0 {
+            self.quorum = self.wanted.max(1);
+            self.complete = false;
+        }
+        self
+    }
+
+    /// A `/dnsaddr/` entry was resolved to `peers`, or none if it failed.
+    pub fn resolved(&mut self, peers: Vec<PeerId>) -> Option<Update> {
+        self.names = self.names.saturating_sub(1);
+        for peer in peers {
+            self.add(peer);
+        }
+        if self.complete || self.warned || self.names > 0 {
+            return None;
+        }
+        let total = self.connected.len() + self.pending.len() + self.retries.len();
+        self.quorum = self.wanted.clamp(1, total.max(1));
+        if self.connected.len() + self.pending.len() >= self.quorum {
+            return None;
+        }
+        self.warned = true;
+        Some(Update::Failed {
+            connected: self.connected.len(),
+            quorum:    self.quorum,
+        })
+    }
+
+    /// Also wait for `peer`, newly found.
+    pub fn add(&mut self, peer: PeerId) {
+        if !self.complete
+            && !self.connected.contains(&peer)
+            && !self.retries.contains_key(&peer)
+        {
+            self.pending.entry(peer).or_insert(0);
+        }
+    }
+
+    /// Whether the quorum was met or can not be met by the first dials.
+    pub const fn is_done(&self) -> bool {
+        self.complete || self.warned
+    }
+
+    pub const fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// A connection to `peer` was established.
+    pub fn connected(&mut self, peer: &PeerId, now: Instant) -> Option<Update> {
+        if self.complete
+            || (self.pending.remove(peer).is_none() && self.retries.remove(peer).is_none())
+        {
+            return None;
+        }
+        self.connected.push(peer.clone());
+        if self.connected.len() < self.quorum {
+            return None;
+        }
+        self.complete = true;
+        self.retries.clear();
+        Some(Update::Bootstrapped {
+            peers:   self.connected.clone(),
+            elapsed: now.saturating_duration_since(self.started),
+        })
+    }
+
+    /// Dialing `peer` failed at every address.
+    pub fn failed(&mut self, peer: &PeerId, now: Instant) -> Option<Update> {
+        if self.complete {
+            return None;
+        }
+        let failures = match self.pending.remove(peer) {
+            Some(failures) => failures + 1,
+            None => return None,
+        };
+        let backoff = Duration::from_secs(1 << failures.min(6)).min(MAX_BACKOFF);
+        self.retries.insert(peer.clone(), (now + backoff, failures));
+        if self.warned
+            || self.names > 0
+            || self.connected.len() + self.pending.len() >= self.quorum
+        {
+            return None;
+        }
+        self.warned = true;
+        Some(Update::Failed {
+            connected: self.connected.len(),
+            quorum:    self.quorum,
+        })
+    }
+
+    /// The peers to dial again at `now`.
+    pub fn due(&mut self, now: Instant) -> Vec<PeerId> {
+        let due: Vec<_> = self
+            .retries
+            .iter()
+            .filter(|(_, (at, _))| *at <= now)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in &due {
+            if let Some((_, failures)) = self.retries.remove(peer) {
+                self.pending.insert(peer.clone(), failures);
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_completes_at_quorum() {
+        let now = Instant::now();
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let mut progress = Progress::new(peers.clone(), 2, now);
+        assert_eq!(progress.failed(&peers[0], now), None);
+        assert_eq!(progress.connected(&PeerId::random(), now), None);
+        assert_eq!(progress.connected(&peers[1], now), None);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            progress.connected(&peers[2], later),
+            Some(Update::Bootstrapped {
+                peers:   vec![peers[1].clone(), peers[2].clone()],
+                elapsed: Duration::from_secs(2),
+            })
+        );
+        assert!(progress.is_done() && progress.is_complete());
+        assert!(progress.due(later + MAX_BACKOFF).is_empty());
+
+        let mut progress = Progress::new(peers.clone(), 5, now);
+        assert_eq!(progress.connected(&peers[0], now), None);
+        assert_eq!(
+            progress.failed(&peers[1], now),
+            Some(Update::Failed {
+                connected: 1,
+                quorum:    3,
+            })
+        );
+        assert_eq!(progress.failed(&peers[2], now), None);
+        assert!(progress.is_done() && !progress.is_complete());
+        assert!(Progress::new(Vec::new(), 1, now).is_complete());
+
+        // Failed peers are dialed again, backing off
+        assert!(progress.due(now + Duration::from_millis(1999)).is_empty());
+        let mut due = progress.due(now + Duration::from_secs(2));
+        due.sort();
+        let mut expected = vec![peers[1].clone(), peers[2].clone()];
+        expected.sort();
+        assert_eq!(due, expected);
+        let now = now + Duration::from_secs(2);
+        assert_eq!(progress.failed(&peers[1], now), None);
+        assert!(progress.due(now + Duration::from_secs(3)).is_empty());
+        assert_eq!(progress.due(now + Duration::from_secs(4)), vec![peers[1].clone()]);
+        assert_eq!(progress.connected(&peers[2], now), None);
+        assert!(matches!(
+            progress.connected(&peers[1], now),
+            Some(Update::Bootstrapped { .. })
+        ));
+
+        // Peers of /dnsaddr/ entries count once resolved
+        let mut named = Progress::new(peers[..1].to_vec(), 2, now).with_names(2);
+        assert!(!named.is_done());
+        assert_eq!(named.failed(&peers[0], now), None);
+        assert_eq!(named.resolved(vec![peers[1].clone()]), None);
+        assert_eq!(named.connected(&peers[1], now), None);
+        assert_eq!(
+            named.resolved(Vec::new()),
+            Some(Update::Failed {
+                connected: 1,
+                quorum:    2,
+            })
+        );
+        assert!(matches!(
+            named.connected(&peers[0], now),
+            Some(Update::Bootstrapped { .. })
+        ));
+    }
+}
@@ -0,0 +1,417 @@
+//! Set up a [`Node`] to embed in an application.
+//!
+//! [`run`](super::run) is the whole `mesh` binary: it also serves the control
+//! socket, hands off to successors, pushes metrics and catches signals. An
+//! application running its own tokio runtime builds a node instead, keeps a
+//! [`NodeHandle`](super::NodeHandle) to publish and subscribe from its own
+//! tasks, and drives the node with [`Node::run`] until it calls
+//! [`NodeHandle::shutdown`](super::NodeHandle::shutdown).
+//!
+//! The config types the builder takes are `#[non_exhaustive]`, so options
+//! can be added without breaking applications: start from their `Default`
+//! or `FromStr` and set the public fields. [`NodeBuilder::build`] validates
+//! them before creating anything, like their `FromStr` does.
+
+use super::{
+    admission, compat, degrade, discovery, family, file, gate, health, middleware, presence,
+    profile, proxy, pubsub, scoring, security, shaping, storage, Node,
+};
+use crate::prelude::*;
+use anyhow::ensure;
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr};
+use std::{
+    net::TcpListener,
+    time::{Duration, SystemTime},
+};
+
+/// Options of a [`Node`], see [`Node::builder`].
+#[derive(Default)]
+pub struct NodeBuilder {
+    keypair:   Option<Keypair>,
+    previous:  Option<(Keypair, SystemTime)>,
+    listen:    Vec<Multiaddr>,
+    listeners: Vec<TcpListener>,
+    bandwidth: shaping::Config,
+    limits:    admission::Config,
+    health:    health::Config,
+    families:  family::Config,
+    proxies:   Vec<proxy::Route>,
+    namespace: Option<String>,
+    critical:  Vec<Multiaddr>,
+    bootstrap: Vec<Multiaddr>,
+    quorum:    Option<usize>,
+    points:    Vec<Multiaddr>,
+    serve:     bool,
+    files:     file::Config,
+    scoring:   Option<scoring::Config>,
+    presence:  Option<presence::Config>,
+    queue:     Option<usize>,
+    pubsub:    pubsub::Config,
+    discovery: discovery::Config,
+    gate:      gate::Config,
+    security:  security::Config,
+    profile:   profile::Profile,
+    max_peers: Option<usize>,
+    shutdown:  Option<Duration>,
+    layers:    Vec<(String, Box<dyn middleware::Middleware>)>,
+    failures:  degrade::Policy,
+    storage:   Option<storage::Shared>,
+    compat:    compat::Compat,
+}
+
+impl NodeBuilder {
+    /// Identify as the peer of `keypair`, instead of a new random one.
+    pub fn with_keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Vouch for our peer id with the `previous` key until `until`, after
+    /// rotating away from it. See [`crate::node::rotation`].
+    pub fn with_previous_keypair(mut self, previous: Keypair, until: SystemTime) -> Self {
+        self.previous = Some((previous, until));
+        self
+    }
+
+    /// Listen on `address`, like `/ip4/0.0.0.0/tcp/4001`. May be repeated.
+    /// Without addresses or listeners the node listens on a port the OS
+    /// assigns on all interfaces.
+    pub fn with_listen_addr(mut self, address: Multiaddr) -> Self {
+        self.listen.push(address);
+        self
+    }
+
+    /// Accept connections on an already listening socket, like one passed
+    /// by systemd.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: shaping::Config) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Cap connections, see [`crate::node::admission`].
+    pub fn with_connection_limits(mut self, limits: admission::Config) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Time out dials and close idle connections, see
+    /// [`crate::node::health`].
+    pub fn with_connection_health(mut self, config: health::Config) -> Self {
+        self.health = config;
+        self
+    }
+
+    /// Listen on and dial IPv4, IPv6 or both, see [`crate::node::family`].
+    pub fn with_address_family(mut self, config: family::Config) -> Self {
+        self.families = config;
+        self
+    }
+
+    /// Dial through the SOCKS5 proxy of `route`, for all peers or for a
+    /// bootstrap peer, see [`crate::node::proxy`]. May be repeated.
+    pub fn with_proxy(mut self, route: proxy::Route) -> Self {
+        self.proxies.push(route);
+        self
+    }
+
+    /// See [`Node::set_namespace`].
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_owned());
+        self
+    }
+
+    /// Keep redundant connections to the peer at `address`, which ends in
+    /// `/p2p/<peer id>`. May be repeated.
+    pub fn with_critical_peer(mut self, address: Multiaddr) -> Self {
+        self.critical.push(address);
+        self
+    }
+
+    /// Also bootstrap through the peer at `address`, which ends in
+    /// `/p2p/<peer id>`. May be repeated.
+    pub fn with_bootstrap_peer(mut self, address: Multiaddr) -> Self {
+        self.bootstrap.push(address);
+        self
+    }
+
+    /// Complete the bootstrap once `quorum` bootstrap peers answered, one by
+    /// default. See [`crate::node::bootstrap`].
+    pub fn with_bootstrap_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = Some(quorum);
+        self
+    }
+
+    /// Serve as a rendezvous point, see [`crate::node::rendezvous`].
+    pub fn with_rendezvous_server(mut self) -> Self {
+        self.serve = true;
+        self
+    }
+
+    /// Register our topics at the rendezvous point at `address`, which ends
+    /// in `/p2p/<peer id>`, and find their other peers there. May be
+    /// repeated.
+    pub fn with_rendezvous_point(mut self, address: Multiaddr) -> Self {
+        self.points.push(address);
+        self
+    }
+
+    /// Receive files, see [`crate::node::file`].
+    pub fn with_files(mut self, config: file::Config) -> Self {
+        self.files = config;
+        self
+    }
+
+    /// Rate limit the messages of each peer and disconnect or ban the
+    /// peers that misbehave, see [`crate::node::scoring`].
+    pub fn with_scoring(mut self, config: scoring::Config) -> Self {
+        self.scoring = Some(config);
+        self
+    }
+
+    /// Gossip our heartbeat and keep a roster of the peers online, see
+    /// [`crate::node::presence`].
+    pub fn with_presence(mut self, config: presence::Config) -> Self {
+        self.presence = Some(config);
+        self
+    }
+
+    /// Let at most `capacity` publishes of handles wait, see
+    /// [`crate::node::outbound`].
+    pub fn with_publish_queue(mut self, capacity: usize) -> Self {
+        self.queue = Some(capacity);
+        self
+    }
+
+    /// Use floodsub instead of gossipsub, or tune gossipsub. See
+    /// [`crate::node::pubsub`].
+    pub fn with_pubsub(mut self, config: pubsub::Config) -> Self {
+        self.pubsub = config;
+        self
+    }
+
+    /// Turn off mDNS or the 0x Mesh bootnodes. See
+    /// [`crate::node::discovery`].
+    pub fn with_discovery(mut self, config: discovery::Config) -> Self {
+        self.discovery = config;
+        self
+    }
+
+    /// Refuse connections to and from the peers and networks `config`
+    /// denies or does not allow. See [`crate::node::gate`].
+    pub fn with_gate(mut self, config: gate::Config) -> Self {
+        self.gate = config;
+        self
+    }
+
+    /// Accept only Noise, or only secio, or only the nodes of a private
+    /// network. See [`crate::node::security`].
+    pub fn with_security(mut self, config: security::Config) -> Self {
+        self.security = config;
+        self
+    }
+
+    /// Bound memory use by the limits of `profile`. See
+    /// [`crate::node::profile`].
+    pub fn with_profile(mut self, profile: profile::Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Keep at most `max` connected peers besides critical, hot and pinned
+    /// peers, instead of the limit of the profile.
+    pub fn with_max_peers(mut self, max: usize) -> Self {
+        self.max_peers = Some(max);
+        self
+    }
+
+    /// Start without mDNS if it fails, instead of failing to build. See
+    /// [`crate::node::degrade`].
+    pub fn with_subsystem_failures(mut self, policy: degrade::Policy) -> Self {
+        self.failures = policy;
+        self
+    }
+
+    /// Speak to go-libp2p and js-libp2p peers, overriding the pubsub and
+    /// security configs. See [`crate::node::compat`].
+    pub fn with_compat(mut self, compat: compat::Compat) -> Self {
+        self.compat = compat;
+        self
+    }
+
+    /// Keep bans, the address book and subscriptions in `storage`, and
+    /// restore them from it. See [`crate::node::storage`].
+    pub fn with_storage(mut self, storage: storage::Shared) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Give [`Node::run`] `timeout` to shut down gracefully, see
+    /// [`Node::close`].
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown = Some(timeout);
+        self
+    }
+
+    /// Pass messages through `layer`, after the layers added before. See
+    /// [`crate::node::middleware`].
+    pub fn with_middleware(
+        mut self,
+        name: &str,
+        layer: impl middleware::Middleware + 'static,
+    ) -> Self {
+        self.layers.push((name.to_owned(), Box::new(layer)));
+        self
+    }
+
+    /// Check the options without building, as [`NodeBuilder::build`] does.
+    pub fn validate(&self) -> Result<()> {
+        self.bandwidth.validate().context("Invalid bandwidth caps")?;
+        self.pubsub.validate().context("Invalid pubsub config")?;
+        self.discovery.validate().context("Invalid discovery config")?;
+        self.security.validate().context("Invalid security config")?;
+        self.health.validate().context("Invalid connection health config")?;
+        self.compat.security(self.security)?;
+        ensure!(self.quorum != Some(0), "The bootstrap quorum must be positive");
+        let peers = self.critical.iter().chain(&self.bootstrap).chain(&self.points);
+        for address in peers {
+            ensure!(
+                matches!(address.iter().last(), Some(Protocol::P2p(_))),
+                "Peer address {} does not end in /p2p/<peer id>",
+                address
+            );
+        }
+        Ok(())
+    }
+
+    /// Create the node and start listening and discovering peers.
+    pub async fn build(self) -> Result<Node> {
+        self.validate()?;
+        let keypair = self.keypair.unwrap_or_else(Keypair::generate_ed25519);
+        let default_listener = self.listen.is_empty() && self.listeners.is_empty();
+        let pubsub = self.compat.pubsub(&self.pubsub);
+        let mut node = Node::with_listeners(
+            keypair,
+            self.listeners,
+            self.bandwidth,
+            self.compat.security(self.security)?,
+            self.limits,
+            self.health,
+        )
+        .await
+        .context("Creating node")?;
+        if let Some((previous, until)) = &self.previous {
+            node.set_previous_identity(previous, *until)?;
+        }
+        node.set_pubsub(&pubsub);
+        let mut limits = self.profile.limits();
+        if let Some(max) = self.max_peers {
+            limits.max_peers = Some(max);
+        }
+        node.set_limits(limits);
+        node.set_discovery(self.discovery)?;
+        node.set_gate(self.gate);
+        node.set_address_family(self.families);
+        node.set_proxy(self.proxies)?;
+        node.set_subsystem_failures(self.failures);
+        if let Some(timeout) = self.shutdown {
+            node.set_shutdown_timeout(timeout);
+        }
+        for (name, layer) in self.layers {
+            node.add_middleware(&name, layer);
+        }
+        if let Some(namespace) = &self.namespace {
+            node.set_namespace(namespace);
+        }
+        for address in &self.bootstrap {
+            node.add_bootstrap_peer(address)?;
+        }
+        if let Some(quorum) = self.quorum {
+            node.set_bootstrap_quorum(quorum);
+        }
+        if default_listener {
+            node.start()?;
+        } else {
+            node.start_behaviours()?;
+        }
+        for address in self.listen {
+            node.listen_on(address)?;
+        }
+        for address in &self.critical {
+            node.add_critical_peer(address)?;
+        }
+        if self.serve {
+            node.set_rendezvous_server();
+        }
+        for address in &self.points {
+            node.add_rendezvous_point(address)?;
+        }
+        node.set_files(self.files);
+        if let Some(config) = self.scoring {
+            node.set_scoring(config);
+        }
+        if let Some(capacity) = self.queue {
+            node.set_publish_queue(capacity);
+        }
+        if let Some(config) = self.presence {
+            node.set_presence(config);
+        }
+        if let Some(storage) = self.storage {
+            node.set_storage(storage).context("Loading from storage")?;
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::subscriptions::TopicOptions, test::prelude::assert_eq};
+    use libp2p::PeerId;
+
+    #[tokio::test]
+    async fn test_runs_until_shutdown() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut node = Node::builder()
+            .with_keypair(keypair)
+            .with_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(node.local_peer_id(), &peer_id);
+        node.subscribe("test", TopicOptions::default()).unwrap();
+        node.subscribe("other", TopicOptions::default()).unwrap();
+        assert!(node.unsubscribe("other").unwrap());
+        assert!(!node.unsubscribe("other").unwrap());
+        assert!(node.peers().is_empty());
+
+        let mut handle = node.handle();
+        let topics = async {
+            let topics = handle.topics().await?;
+            handle.shutdown().await.map(|()| topics)
+        };
+        let (result, topics) = future::join(node.run(), topics).await;
+        result.unwrap();
+        assert_eq!(topics.unwrap(), vec![("test".to_owned(), TopicOptions::default())]);
+    }
+
+    #[test]
+    fn test_validates_options() {
+        assert!(Node::builder().validate().is_ok());
+        let mut pubsub = pubsub::Config::default();
+        pubsub.mesh_low = pubsub.mesh + 1;
+        assert!(Node::builder().with_pubsub(pubsub).validate().is_err());
+        let mut security = security::Config::default();
+        security.noise = false;
+        security.secio = false;
+        assert!(Node::builder().with_security(security).validate().is_err());
+        assert!(Node::builder().with_bootstrap_quorum(0).validate().is_err());
+        let address = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert!(Node::builder().with_critical_peer(address).validate().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Debug bundles.
+//!
+//! A bundle is a gzipped tarball of
+//!
+//! * `status.json`, the node's [`control::Status`] with its recent events,
+//! * `topology.json`, the known peers with their addresses, protocols and
+//!   the address they saw us at,
+//! * `mesh.log`, the last [`MAX_LOG`] of the log file, if logging to one,
+//! * the [`crash`] reports in the data directory.
+//!
+//! A node started with `--debug-admin <peer id>` sends its bundle to that
+//! peer over [`super::behaviour::diagnostics`]. An operator retrieves it
+//! through their own node with `mesh --data-dir <dir> bundle <peer id> <file>`.
+
+use super::{control, crash};
+use crate::prelude::*;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How much of the end of the log file is included.
+pub const MAX_LOG: u64 = 4 * 1024 * 1024;
+
+const BLOCK: usize = 512;
+
+/// Where the files in a bundle come from.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Sources {
+    pub data_dir: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+}
+
+/// A peer as it appears in `topology.json`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Peer {
+    pub peer_id:   String,
+    pub connected: bool,
+    pub ping_ms:   Option<u64>,
+    pub agent:     Option<String>,
+    pub addresses: Vec<String>,
+    pub protocols: Vec<String>,
+    /// Where the peer saw us.
+    #[serde(default)]
+    pub observed:  Option<String>,
+}
+
+/// A tarball being written.
+pub struct Archive {
+    encoder: GzEncoder<Vec<u8>>,
+    mtime:   u64,
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+            mtime:   since_epoch.as_secs(),
+        }
+    }
+
+    /// Add a regular file. Names are truncated to 100 bytes.
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = [0_u8; BLOCK];
+        let name = &name.as_bytes()[..name.len().min(100)];
+        header[..name.len()].copy_from_slice(name);
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(format!("{:011o}\0", self.mtime).as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // The checksum is computed with its own field set to spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum = header.iter().map(|&byte| u32::from(byte)).sum::<u32>();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        self.encoder.write_all(&header)?;
+        self.encoder.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.encoder.write_all(&[0; BLOCK][..padding])?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        self.encoder.write_all(&[0; 2 * BLOCK])?;
+        Ok(self.encoder.finish()?)
+    }
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The last `max` bytes of the file at `path`.
+fn tail(path: &Path, max: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max)))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Package a bundle.
+pub fn build(sources: &Sources, status: &control::Status, topology: &[Peer]) -> Result<Vec<u8>> {
+    let mut archive = Archive::new();
+    archive.add("status.json", &serde_json::to_vec_pretty(status)?)?;
+    archive.add("topology.json", &serde_json::to_vec_pretty(topology)?)?;
+    if let Some(path) = &sources.log_file {
+        archive.add("mesh.log", &tail(path, MAX_LOG)?)?;
+    }
+    if let Some(data_dir) = &sources.data_dir {
+        for entry in fs::read_dir(data_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(crash::FILE_PREFIX) && name.ends_with(".json") {
+                archive.add(&name, &fs::read(data_dir.join(&name))?)?;
+            }
+        }
+    }
+    archive.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn test_packages_tarball() {
+        let dir = std::env::temp_dir().join(format!("mesh-bundle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("crash-1.json"), b"{}").unwrap();
+        fs::write(dir.join("control.sock"), b"").unwrap();
+        let log_file = dir.join("mesh.log");
+        fs::write(&log_file, vec![b'x'; MAX_LOG as usize + 10]).unwrap();
+        let sources = Sources {
+            data_dir: Some(dir.clone()),
+            log_file: Some(log_file),
+        };
+        let bundle = build(&sources, &control::Status::default(), &[]).unwrap();
+
+        let mut tar = Vec::new();
+        GzDecoder::new(&bundle[..]).read_to_end(&mut tar).unwrap();
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while tar[offset] != 0 {
+            let header = &tar[offset..offset + BLOCK];
+            let name = &header[..header.iter().position(|&byte| byte == 0).unwrap()];
+            names.push(String::from_utf8(name.to_vec()).unwrap());
+            let size = std::str::from_utf8(&header[124..135]).unwrap();
+            let size = usize::from_str_radix(size, 8).unwrap();
+            let stored = header[148..154].iter().map(|&byte| char::from(byte)).collect::<String>();
+            let mut blank = header.to_vec();
+            blank[148..156].copy_from_slice(b"        ");
+            let checksum = blank.iter().map(|&byte| u32::from(byte)).sum::<u32>();
+            assert_eq!(u32::from_str_radix(&stored, 8).unwrap(), checksum);
+            if names.last().unwrap() == "mesh.log" {
+                assert_eq!(size as u64, MAX_LOG);
+            }
+            offset += BLOCK + (size + BLOCK - 1) / BLOCK * BLOCK;
+        }
+        assert_eq!(names, vec!["status.json", "topology.json", "mesh.log", "crash-1.json"]);
+        assert_eq!(tar.len(), offset + 2 * BLOCK);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
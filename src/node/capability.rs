@@ -0,0 +1,266 @@
+//! Topics only holders of a capability may publish on.
+//!
+//! Anyone who knows a topic can publish to it, unless nodes restrict it with
+//! [`super::NodeHandle::restrict_topic`], naming the peer id of its owner.
+//! The owner issues a [`Capability`] to each peer allowed to publish with
+//! [`super::NodeHandle::issue_capability`]: a token signed with its identity
+//! key that names the topic, the holder and when it expires. The holder adds
+//! it with [`super::NodeHandle::add_capability`] and from then on every
+//! envelope it publishes on the topic carries the token. Receivers drop
+//! messages on a restricted topic that are not validly signed by their
+//! sender, or whose token is missing, expired, for another topic or peer, or
+//! not signed by the owner. The owner needs no token.
+//!
+//! Tokens are exchanged out of band as hex strings. There is no revocation,
+//! so give them an expiry and issue new ones as they run out. Messages
+//! republished by a bridge carry the token of the bridge, which signs them.
+//! Gossip still relays dropped messages, so restriction only works as far
+//! as the receiving nodes honor it.
+
+use super::{
+    keyring,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::{identity::Keypair, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// The right of `holder` to publish on `topic`, signed by the topic owner.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Capability {
+    pub topic:     String,
+    /// Base58 peer id.
+    pub holder:    String,
+    /// Milliseconds since the Unix epoch, 0 for never.
+    pub expires:   u64,
+    pub signature: ByteBuf,
+}
+
+impl Capability {
+    fn signed_bytes(topic: &str, holder: &str, expires: u64) -> Vec<u8> {
+        Layout::new(Domain::Capability)
+            .topic(topic)
+            .timestamp(expires)
+            .header("holder", holder.as_bytes())
+            .to_bytes()
+    }
+
+    /// Sign a capability for `holder` on `topic` with the owner `key`.
+    pub fn issue(
+        key: &Keypair,
+        topic: &str,
+        holder: &PeerId,
+        expires: Option<SystemTime>,
+    ) -> Result<Self> {
+        let holder = holder.to_base58();
+        let expires = expires.map_or(0, millis);
+        let signature = key
+            .sign(&Self::signed_bytes(topic, &holder, expires))
+            .map_err(|err| anyhow!("Signing capability: {:?}", err))?;
+        Ok(Self {
+            topic: topic.into(),
+            holder,
+            expires,
+            signature: ByteBuf::from(signature),
+        })
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires != 0 && self.expires <= millis(now)
+    }
+
+    /// Check that the capability lets `source` publish on `topic`, signed by
+    /// its `owner`.
+    pub fn verify(
+        &self,
+        topic: &str,
+        owner: &PeerId,
+        source: &PeerId,
+        now: SystemTime,
+    ) -> Result<()> {
+        ensure!(self.topic == topic, "Capability for {} used on {}", self.topic, topic);
+        ensure!(
+            self.holder == source.to_base58(),
+            "Capability of {} used by {}",
+            self.holder,
+            source
+        );
+        ensure!(!self.is_expired(now), "Capability of {} on {} expired", self.holder, topic);
+        let signed = match keyring::public_key(owner) {
+            Some(public) => {
+                public.verify(
+                    &Self::signed_bytes(&self.topic, &self.holder, self.expires),
+                    &self.signature,
+                )
+            }
+            None => false,
+        };
+        ensure!(signed, "Capability on {} not signed by owner {}", topic, owner);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Capability {
+    /// Hex of the CBOR encoding, to hand to the holder.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = serde_cbor::to_vec(self).expect("Capabilities always encode");
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim()).context("Capability is not hex")?;
+        serde_cbor::from_slice(&bytes).context("Invalid capability")
+    }
+}
+
+/// The restricted topics and the capabilities we hold for them.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Owners by topic.
+    owners: HashMap<String, PeerId>,
+    held:   HashMap<String, Capability>,
+}
+
+impl Capabilities {
+    /// Only accept messages on `topic` from `owner` and the holders of its
+    /// capabilities. Returns false if it already was.
+    pub fn restrict(&mut self, topic: &str, owner: PeerId) -> bool {
+        self.owners.insert(topic.into(), owner.clone()) != Some(owner)
+    }
+
+    /// Accept messages on `topic` from anyone again.
+    pub fn unrestrict(&mut self, topic: &str) -> bool {
+        self.held.remove(topic);
+        self.owners.remove(topic).is_some()
+    }
+
+    pub fn owner(&self, topic: &str) -> Option<&PeerId> {
+        self.owners.get(topic)
+    }
+
+    /// Attach `capability` to what `local` publishes on its topic, once
+    /// checked against the owner.
+    pub fn hold(&mut self, capability: Capability, local: &PeerId, now: SystemTime) -> Result<()> {
+        let owner = match self.owners.get(&capability.topic) {
+            Some(owner) => owner,
+            None => bail!("Topic {} is not restricted", capability.topic),
+        };
+        capability.verify(&capability.topic, owner, local, now)?;
+        self.held.insert(capability.topic.clone(), capability);
+        Ok(())
+    }
+
+    /// The capability to attach to messages on `topic`, if any.
+    pub fn token(&self, topic: &str) -> Option<&Capability> {
+        self.held.get(topic)
+    }
+
+    /// Whether `local` may publish on `topic`.
+    pub fn may_publish(&self, topic: &str, local: &PeerId, now: SystemTime) -> Result<()> {
+        match self.owners.get(topic) {
+            Some(owner) if owner != local => {
+                match self.held.get(topic) {
+                    Some(capability) => capability.verify(topic, owner, local, now),
+                    None => bail!("No capability to publish on restricted topic {}", topic),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a message on `topic` signed by `source`, with the `capability`
+    /// it carried.
+    pub fn authorize(
+        &self,
+        topic: &str,
+        source: &PeerId,
+        capability: Option<&Capability>,
+        now: SystemTime,
+    ) -> Result<()> {
+        match self.owners.get(topic) {
+            Some(owner) if owner != source => {
+                match capability {
+                    Some(capability) => capability.verify(topic, owner, source, now),
+                    None => bail!("No capability from {} on {}", source, topic),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_authorizes_holders() {
+        let owner_key = Keypair::generate_ed25519();
+        let owner = PeerId::from(owner_key.public());
+        let (writer, stranger) = (PeerId::random(), PeerId::random());
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+
+        let mut capabilities = Capabilities::default();
+        assert!(capabilities.authorize("news", &stranger, None, now).is_ok());
+        assert!(capabilities.restrict("news", owner.clone()));
+        assert!(!capabilities.restrict("news", owner.clone()));
+        assert!(capabilities.authorize("news", &owner, None, now).is_ok());
+        assert!(capabilities.authorize("news", &stranger, None, now).is_err());
+
+        let capability = Capability::issue(&owner_key, "news", &writer, Some(later)).unwrap();
+        let received: Capability = capability.to_string().parse().unwrap();
+        assert_eq!(received, capability);
+        assert!(capabilities.authorize("news", &writer, Some(&received), now).is_ok());
+        assert!(capabilities.authorize("news", &stranger, Some(&received), now).is_err());
+        assert!(capabilities.authorize("news", &writer, Some(&received), later).is_err());
+        capabilities.restrict("chat", owner.clone());
+        assert!(capabilities.authorize("chat", &writer, Some(&received), now).is_err());
+
+        let mut forged = received.clone();
+        forged.expires = 0;
+        assert!(capabilities.authorize("news", &writer, Some(&forged), later).is_err());
+        let other_key = Keypair::generate_ed25519();
+        let foreign = Capability::issue(&other_key, "news", &writer, None).unwrap();
+        assert!(capabilities.authorize("news", &writer, Some(&foreign), now).is_err());
+    }
+
+    #[test]
+    fn test_holds_capabilities() {
+        let owner_key = Keypair::generate_ed25519();
+        let owner = PeerId::from(owner_key.public());
+        let writer = PeerId::random();
+        let now = SystemTime::now();
+        let capability = Capability::issue(&owner_key, "news", &writer, None).unwrap();
+
+        let mut capabilities = Capabilities::default();
+        assert!(capabilities.hold(capability.clone(), &writer, now).is_err());
+        capabilities.restrict("news", owner.clone());
+        assert!(capabilities.may_publish("news", &writer, now).is_err());
+        assert!(capabilities.may_publish("news", &owner, now).is_ok());
+        assert!(capabilities.hold(capability.clone(), &PeerId::random(), now).is_err());
+        capabilities.hold(capability.clone(), &writer, now).unwrap();
+        assert!(capabilities.may_publish("news", &writer, now).is_ok());
+        assert_eq!(capabilities.token("news"), Some(&capability));
+        assert!(capabilities.unrestrict("news"));
+        assert_eq!(capabilities.token("news"), None);
+    }
+}
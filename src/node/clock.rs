@@ -0,0 +1,188 @@
+//! Detecting wall clock jumps.
+//!
+//! Every tick the node compares how far the wall clock moved with how far the
+//! monotonic clock moved. When they disagree by more than the `threshold` of
+//! `--clock-jumps "threshold=10s action=rebaseline"`, the wall clock jumped:
+//! an NTP correction, someone setting the time, or a suspend, during which
+//! the monotonic clock stands still. The node logs the jump and emits an
+//! [`super::Event::ClockJump`].
+//!
+//! With `action=rebaseline`, the default, a backward jump also widens the
+//! [`super::hlc`] drift window by the size of the jump for [`GRACE_PERIOD`],
+//! so messages from peers whose clocks did not jump are not all rejected as
+//! coming from the future. Timers, heartbeats and expiry all run on the
+//! monotonic clock and need no adjustment. With `action=report` the jump is
+//! only reported.
+//!
+//! A forward jump is what a suspend looks like, and a tick arriving more than
+//! `threshold` late means the process was paused. Either way the node
+//! [resumed](Observation::resumed): it closes and redials its connections,
+//! which may have died in the meantime, so peers learn its subscriptions
+//! again, and restarts discovery, instead of waiting for timeouts.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long a widened drift window lasts.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Rebaseline,
+    Report,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Smallest disagreement between the clocks counted as a jump.
+    pub threshold: Duration,
+    pub action:    Action,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(10),
+            action:    Action::Rebaseline,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "threshold" => {
+                    config.threshold = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid threshold {}", value))?;
+                }
+                "action" => {
+                    config.action = match value {
+                        "rebaseline" => Action::Rebaseline,
+                        "report" => Action::Report,
+                        _ => bail!("Unknown clock jump action {}", value),
+                    };
+                }
+                _ => bail!("Unknown clock jump option {}", key),
+            }
+        }
+        ensure!(
+            config.threshold > Duration::from_secs(0),
+            "Clock jump threshold must be positive"
+        );
+        Ok(config)
+    }
+}
+
+/// Milliseconds the wall clock moved beyond the monotonic clock, negative for
+/// backward jumps.
+fn offset_ms(
+    (wall_before, monotonic_before): (SystemTime, Instant),
+    (wall, monotonic): (SystemTime, Instant),
+) -> i64 {
+    let wall_ms = match wall.duration_since(wall_before) {
+        Ok(forward) => forward.as_millis() as i64,
+        Err(err) => -(err.duration().as_millis() as i64),
+    };
+    let monotonic_ms = monotonic.saturating_duration_since(monotonic_before).as_millis() as i64;
+    wall_ms - monotonic_ms
+}
+
+/// What happened since the previous check.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Observation {
+    /// Milliseconds the wall clock jumped, if it did.
+    pub jump_ms: Option<i64>,
+    /// Whether the process was suspended or paused.
+    pub resumed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Detector {
+    config: Config,
+    last:   (SystemTime, Instant),
+}
+
+impl Detector {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            last: (SystemTime::now(), Instant::now()),
+        }
+    }
+
+    pub const fn action(&self) -> Action {
+        self.config.action
+    }
+
+    /// Check now, expecting to be called every `interval`.
+    pub fn check(&mut self, interval: Duration) -> Observation {
+        self.check_at(SystemTime::now(), Instant::now(), interval)
+    }
+
+    fn check_at(
+        &mut self,
+        wall: SystemTime,
+        monotonic: Instant,
+        interval: Duration,
+    ) -> Observation {
+        let offset_ms = offset_ms(self.last, (wall, monotonic));
+        let gap = monotonic.saturating_duration_since(self.last.1);
+        self.last = (wall, monotonic);
+        let threshold_ms = self.config.threshold.as_millis();
+        let jump_ms = if u128::from(offset_ms.unsigned_abs()) >= threshold_ms {
+            Some(offset_ms)
+        } else {
+            None
+        };
+        Observation {
+            jump_ms,
+            resumed: matches!(jump_ms, Some(jump_ms) if jump_ms > 0)
+                || gap > interval + self.config.threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_detects_jumps() {
+        let config: Config = "threshold=5s action=report".parse().unwrap();
+        assert_eq!(config.action, Action::Report);
+        assert!("action=panic".parse::<Config>().is_err());
+
+        let (wall, monotonic) = (SystemTime::now(), Instant::now());
+        let mut detector = Detector::new(config);
+        detector.last = (wall, monotonic);
+        let second = Duration::from_secs(1);
+        let check = |detector: &mut Detector, wall, monotonic| {
+            let observation = detector.check_at(wall, monotonic, second);
+            (observation.jump_ms, observation.resumed)
+        };
+        // Both clocks advance together
+        assert_eq!(check(&mut detector, wall + second, monotonic + second), (None, false));
+        // NTP steps the clock back a minute
+        let wall = wall + second - Duration::from_secs(60);
+        let monotonic = monotonic + 2 * second;
+        assert_eq!(check(&mut detector, wall, monotonic), (Some(-61_000), false));
+        // Suspended for an hour, the monotonic clock stood still
+        let wall = wall + Duration::from_secs(3600);
+        let monotonic = monotonic + second;
+        assert_eq!(check(&mut detector, wall, monotonic), (Some(3_599_000), true));
+        // Paused for ten seconds, both clocks advanced
+        let gap = Duration::from_secs(10);
+        assert_eq!(check(&mut detector, wall + gap, monotonic + gap), (None, true));
+    }
+}
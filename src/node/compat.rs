@@ -0,0 +1,140 @@
+//! Wire compatibility with go-libp2p and js-libp2p peers.
+//!
+//! Nodes of this crate mesh with each other as they are, but wrap every
+//! payload they publish in a signed [envelope], and a go-libp2p or
+//! js-libp2p daemon on the same topic would hand that envelope to its
+//! application instead of the payload. `--compat libp2p`, or `go-libp2p` or
+//! `js-libp2p`, pins the wire to the defaults of those implementations:
+//!
+//! * Noise only, as they dropped secio, with yamux or mplex.
+//! * Gossipsub on `/meshsub/1.0.0`, which they still speak, with a mesh of 6
+//!   peers between 5 and 12, a heartbeat every second, messages of up to
+//!   [`MAX_TRANSMIT`] bytes remembered for [`SEEN_TTL`], and message ids
+//!   made of the source and sequence number.
+//! * Bare payloads: published data goes to gossipsub as it is, signed by
+//!   gossipsub only.
+//!
+//! What the envelope carries is then not sent: timestamps, compression,
+//! blobs and fragments, acknowledgements, expiry and hop limits, and the
+//! provenance of relayed messages. Envelopes from nodes not in the mode are
+//! still read, and bare payloads from any peer are delivered like those of
+//! the Go 0x Mesh nodes always were, see [`verification`]. `--pubsub` is
+//! overridden, with a warning if it asked for something else, and
+//! `--security` without Noise fails. A `--namespace` prefixes topics in this
+//! mode too, so leave it out to share topics with other daemons.
+//!
+//! `cargo test --features interop --test interop` checks the mode against
+//! the go-libp2p reference peer in `test/interop`, run in Docker.
+//!
+//! [envelope]: super::behaviour::envelope
+//! [`verification`]: super::verification
+
+use super::{pubsub, security};
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Largest message go-libp2p takes by default.
+pub const MAX_TRANSMIT: usize = 1 << 20;
+
+/// How long go-libp2p remembers messages by default.
+pub const SEEN_TTL: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compat {
+    /// The wire of this crate.
+    Native,
+    /// The wire of go-libp2p and js-libp2p.
+    Libp2p,
+}
+
+impl Default for Compat {
+    fn default() -> Self {
+        Self::Native
+    }
+}
+
+impl FromStr for Compat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "native" => Self::Native,
+            "libp2p" | "go-libp2p" | "js-libp2p" => Self::Libp2p,
+            _ => bail!("Unknown compat mode {}, expected native or libp2p", s),
+        })
+    }
+}
+
+impl fmt::Display for Compat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Native => "native",
+            Self::Libp2p => "libp2p",
+        })
+    }
+}
+
+impl Compat {
+    /// The pubsub config to run with instead of `config`. Pubsub stays off
+    /// if `config` turns it off.
+    pub fn pubsub(self, config: &pubsub::Config) -> pubsub::Config {
+        if self == Self::Native {
+            return config.clone();
+        }
+        let mut pinned = pubsub::Config::default();
+        if config.protocol == pubsub::Protocol::Off {
+            pinned.protocol = pubsub::Protocol::Off;
+        } else if *config != pinned {
+            warn!("Pubsub config {:?} overridden for {} peers", config, self);
+        }
+        pinned.mesh = 6;
+        pinned.mesh_low = 5;
+        pinned.mesh_high = 12;
+        pinned.heartbeat = Duration::from_secs(1);
+        pinned.seen_ttl = SEEN_TTL;
+        pinned.max_transmit = MAX_TRANSMIT;
+        pinned.bare = true;
+        pinned
+    }
+
+    /// The security config to run with instead of `config`, keeping its
+    /// private network.
+    pub fn security(self, config: security::Config) -> Result<security::Config> {
+        if self == Self::Native {
+            return Ok(config);
+        }
+        ensure!(config.noise, "{} peers need noise", self);
+        let mut pinned = config;
+        pinned.secio = false;
+        Ok(pinned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_pins_libp2p_defaults() {
+        assert_eq!("go-libp2p".parse::<Compat>().unwrap(), Compat::Libp2p);
+        assert_eq!("native".parse::<Compat>().unwrap(), Compat::default());
+        assert!("rust-libp2p".parse::<Compat>().is_err());
+
+        let tuned: pubsub::Config = "protocol=floodsub mesh=8 mesh-high=16".parse().unwrap();
+        assert_eq!(Compat::Native.pubsub(&tuned), tuned);
+        let pinned = Compat::Libp2p.pubsub(&tuned);
+        assert_eq!(pinned.protocol, pubsub::Protocol::Gossipsub);
+        assert_eq!((pinned.mesh, pinned.mesh_low, pinned.mesh_high), (6, 5, 12));
+        assert_eq!((pinned.seen_ttl, pinned.max_transmit), (SEEN_TTL, MAX_TRANSMIT));
+        assert!(pinned.bare);
+        let mut off = pubsub::Config::default();
+        off.protocol = pubsub::Protocol::Off;
+        assert_eq!(Compat::Libp2p.pubsub(&off).protocol, pubsub::Protocol::Off);
+
+        let security = Compat::Libp2p.security(security::Config::default()).unwrap();
+        assert_eq!(security, "noise".parse().unwrap());
+        assert!(Compat::Libp2p.security("secio".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Commands typed on stdin.
+//!
+//! Started with `--interactive`, the node reads lines from stdin. A plain
+//! line is published on the current topic, the first `--topic` or
+//! [`DEFAULT_TOPIC`], and a line starting with `/` is a [`Line`] command:
+//!
+//! * `/peers` lists the connected peers, marking pinned ones, and the
+//!   pinned peers not connected.
+//! * `/subscribe <topic>` subscribes to `topic` and makes it the current one.
+//! * `/dial <address>` connects to the peer at `address`.
+//! * `/msg <peer> <text>` sends `text` on the current topic to that peer
+//!   only, if we are connected to it. The peer is a peer id, a suffix of one
+//!   or a [`crate::node::nickname`].
+//! * `/send <peer> <path>` offers that peer the file at `path`, see
+//!   [`crate::node::file`].
+//! * `/quit` stops the node, like the end of the input.
+//!
+//! Messages received on subscribed topics are printed with their topic and
+//! source, by nickname if it published a profile, as are finished or failed
+//! file transfers. Logs go to stderr, so they do not mix with the
+//! conversation.
+//!
+//! Input may be piped, as in `cat notes.txt | mesh --interactive`: stdin is
+//! read on a thread of its own, see [`crate::node::input`], so the node
+//! stops at the end of the input or on `/quit` without waiting for another
+//! line. A line published while the outbound queue is full, as a pipe can
+//! outpace the mesh, is retried with a backoff from [`RETRY_MIN`] up to
+//! [`RETRY_MAX`] between attempts, for [`RETRY_FOR`] at most.
+
+use super::{
+    file::{Direction, State},
+    input, outbound, Event, NodeHandle, TopicOptions,
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use futures::StreamExt;
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// The topic plain lines are published on without a `--topic`.
+pub const DEFAULT_TOPIC: &str = "chat";
+
+/// The first wait before publishing a line again on a full queue.
+pub const RETRY_MIN: Duration = Duration::from_millis(100);
+
+/// The longest wait between two attempts to publish a line.
+pub const RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// How long a line is retried before it is given up.
+pub const RETRY_FOR: Duration = Duration::from_secs(30);
+
+/// A line of input.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Line {
+    Publish(String),
+    Peers,
+    Subscribe(String),
+    Dial(Multiaddr),
+    /// A message to the peer named, see [`NodeHandle::resolve_peer`].
+    Msg(String, String),
+    Send(String, PathBuf),
+    Quit,
+}
+
+impl FromStr for Line {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let command = match s.strip_prefix('/') {
+            Some(command) => command,
+            None => return Ok(Self::Publish(s.to_owned())),
+        };
+        let (command, argument) = match command.find(' ') {
+            Some(index) => (&command[..index], command[index + 1..].trim()),
+            None => (command, ""),
+        };
+        Ok(match command {
+            "peers" | "quit" => {
+                ensure!(argument.is_empty(), "/{} takes no argument", command);
+                if command == "peers" {
+                    Self::Peers
+                } else {
+                    Self::Quit
+                }
+            }
+            "subscribe" => {
+                ensure!(!argument.is_empty(), "Usage: /subscribe <topic>");
+                Self::Subscribe(argument.to_owned())
+            }
+            "dial" => {
+                ensure!(!argument.is_empty(), "Usage: /dial <address>");
+                let address = argument
+                    .parse()
+                    .map_err(|err| anyhow!("Invalid address {}: {}", argument, err))?;
+                Self::Dial(address)
+            }
+            "msg" | "send" => {
+                let (peer, rest) = match argument.find(' ') {
+                    Some(index) => (&argument[..index], argument[index + 1..].trim()),
+                    None if command == "msg" => bail!("Usage: /msg <peer> <text>"),
+                    None => bail!("Usage: /send <peer> <path>"),
+                };
+                if command == "msg" {
+                    Self::Msg(peer.to_owned(), rest.to_owned())
+                } else {
+                    Self::Send(peer.to_owned(), PathBuf::from(rest))
+                }
+            }
+            _ => {
+                bail!(
+                    "Unknown command /{}, expected /peers, /subscribe, /dial, /msg, /send or \
+                     /quit",
+                    command
+                )
+            }
+        })
+    }
+}
+
+/// Run the commands read from stdin on `topic` until `/quit` or the end of
+/// the input.
+pub async fn run(mut handle: NodeHandle, mut topic: String) -> Result<()> {
+    let subscribed = handle.topics().await?;
+    if !subscribed.iter().any(|(subscribed, _)| *subscribed == topic) {
+        handle.subscribe(&topic, TopicOptions::default()).await?;
+    }
+    let mut events = handle.events().await?;
+    let mut lines = input::Lines::stdin().context("Reading stdin")?;
+    println!("Publishing on {}, /quit to stop", topic);
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line.context("Reading stdin")? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let result = match line.parse() {
+                    Ok(Line::Quit) => return Ok(()),
+                    Ok(line) => execute(&mut handle, &mut topic, line).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    println!("{:#}", err);
+                }
+            }
+            Some(event) = events.next() => {
+                match event {
+                    Event::Message { source, topic, data, .. } => {
+                        let source = name(&mut handle, &source).await?;
+                        println!("[{}] {}: {}", topic, source, String::from_utf8_lossy(&data));
+                    }
+                    Event::FileTransfer(progress) => {
+                        let verb = match progress.direction {
+                            Direction::Sending => "Sending",
+                            Direction::Receiving => "Receiving",
+                        };
+                        match progress.state {
+                            State::Active => {}
+                            State::Done(Some(path)) => {
+                                println!("Received {} into {}", progress.name, path.display());
+                            }
+                            State::Done(None) => println!("Sent {}", progress.name),
+                            State::Failed(error) => {
+                                println!("{} {} failed: {}", verb, progress.name, error);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// The nickname of `peer_id`, or its id without a verified profile.
+async fn name(handle: &mut NodeHandle, peer_id: &PeerId) -> Result<String> {
+    Ok(match handle.profile(peer_id).await? {
+        Some(profile) => profile.nickname,
+        None => peer_id.to_string(),
+    })
+}
+
+/// Publish `data` on `topic`, backing off while the outbound queue is full.
+async fn publish(handle: &mut NodeHandle, topic: &str, data: &[u8]) -> Result<()> {
+    let started = Instant::now();
+    let mut delay = RETRY_MIN;
+    loop {
+        match handle.publish(topic, data).await {
+            Err(err)
+                if err.downcast_ref::<outbound::Full>().is_some()
+                    && started.elapsed() + delay <= RETRY_FOR =>
+            {
+                debug!("{}, retrying in {:?}", err, delay);
+                sleep(delay).await;
+                delay = (delay * 2).min(RETRY_MAX);
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn execute(handle: &mut NodeHandle, topic: &mut String, line: Line) -> Result<()> {
+    match line {
+        Line::Publish(text) => publish(handle, topic, text.as_bytes()).await?,
+        Line::Peers => {
+            let peers = handle.peers().await?;
+            let pinned = handle.pinned_peers().await?;
+            println!("{} connected peers", peers.len());
+            for peer_id in peers {
+                if pinned.iter().any(|pin| pin.peer_id == peer_id) {
+                    println!("  {} (pinned)", peer_id);
+                } else {
+                    println!("  {}", peer_id);
+                }
+            }
+            for pin in pinned.iter().filter(|pin| !pin.connected) {
+                println!("  {} (pinned, not connected, {} dials)", pin.peer_id, pin.dials);
+            }
+        }
+        Line::Subscribe(new) => {
+            handle.subscribe(&new, TopicOptions::default()).await?;
+            println!("Publishing on {}", new);
+            *topic = new;
+        }
+        Line::Dial(address) => handle.dial(address).await?,
+        Line::Msg(peer, text) => {
+            let peer_id = handle.resolve_peer(&peer).await?;
+            let sent = handle
+                .publish_to(&[peer_id.clone()], topic, text.as_bytes())
+                .await?;
+            ensure!(!sent.is_empty(), "Not connected to {}", peer);
+        }
+        Line::Send(peer, path) => {
+            let peer_id = handle.resolve_peer(&peer).await?;
+            let id = handle.send_file(&peer_id, &path).await?;
+            println!("{} accepted {} ({})", peer, path.display(), id);
+        }
+        Line::Quit => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_commands() {
+        let peer_id = PeerId::random();
+        assert_eq!("hello /all".parse::<Line>().unwrap(), Line::Publish("hello /all".into()));
+        assert_eq!("/peers".parse::<Line>().unwrap(), Line::Peers);
+        assert_eq!("/subscribe news".parse::<Line>().unwrap(), Line::Subscribe("news".into()));
+        assert_eq!(
+            "/dial /ip4/10.0.0.1/tcp/4001".parse::<Line>().unwrap(),
+            Line::Dial("/ip4/10.0.0.1/tcp/4001".parse().unwrap())
+        );
+        assert_eq!(
+            format!("/msg {} hi there", peer_id).parse::<Line>().unwrap(),
+            Line::Msg(peer_id.to_string(), "hi there".into())
+        );
+        assert_eq!(
+            format!("/send {} notes/a b.txt", peer_id).parse::<Line>().unwrap(),
+            Line::Send(peer_id.to_string(), "notes/a b.txt".into())
+        );
+        assert_eq!("/quit".parse::<Line>().unwrap(), Line::Quit);
+        assert!("/quit now".parse::<Line>().is_err());
+        assert!("/subscribe".parse::<Line>().is_err());
+        assert_eq!(
+            "/msg alice hello".parse::<Line>().unwrap(),
+            Line::Msg("alice".into(), "hello".into())
+        );
+        assert!("/msg alice".parse::<Line>().is_err());
+        assert!("/join chat".parse::<Line>().is_err());
+    }
+}
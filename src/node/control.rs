@@ -0,0 +1,323 @@
+//! Local control socket and `mesh top`.
+//!
+//! A node with a data directory listens on the Unix socket [`FILE_NAME`] in
+//! it. Clients send one JSON [`Request`] per line and read one JSON
+//! [`Response`] per line back. On a node in daemon mode, clients may
+//! [`Request::Attach`] to share it, see [`super::daemon`]. With `--access`,
+//! clients [`Request::Authenticate`] first and may only send the requests
+//! their token permits, see [`super::access`].
+//!
+//! `mesh --data-dir <dir> top` polls the node's [`Status`] every
+//! [`REFRESH_INTERVAL`] and redraws it in the terminal: peers by round trip
+//! time, bandwidth, topics and the most recent events.
+//!
+//! The protocol and [`Client`] live in the `mesh-client` crate, for programs
+//! that talk to a node without depending on libp2p.
+
+pub use mesh_client::{
+    control::{Backoff, PeerStatus, Request, Response, Status, FILE_NAME},
+    Client,
+};
+
+use super::{
+    access::{Permission, Tokens},
+    daemon::Daemon,
+    names,
+};
+use crate::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    time::sleep,
+};
+
+/// Number of recent events kept for [`Status::events`].
+pub const RECENT_EVENTS: usize = 32;
+
+/// How often `mesh top` redraws.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most recent events, for [`Status::events`].
+#[derive(Clone, Debug, Default)]
+pub struct Recent {
+    events: VecDeque<String>,
+}
+
+impl Recent {
+    pub fn record(&mut self, event: String) {
+        if self.events.len() >= RECENT_EVENTS {
+            self.events.pop_front();
+        }
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+        self.events.push_back(format!("{} {}", now, event));
+    }
+
+    pub fn to_vec(&self) -> Vec<String> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+/// A request from a control client and where to send the response.
+pub type Call = (Request, oneshot::Sender<Response>);
+
+/// The permission `request` needs.
+fn needs(request: &Request) -> Permission {
+    match request {
+        Request::Status | Request::Authenticate { .. } => Permission::Status,
+        Request::Attach => Permission::Publish,
+        Request::Bundle { .. } | Request::Dial { .. } | Request::Shutdown => Permission::Admin,
+    }
+}
+
+async fn serve_client(
+    stream: UnixStream,
+    mut calls: mpsc::Sender<Call>,
+    mut daemon: Option<Daemon>,
+    access: Option<Tokens>,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    // Without access control, the file permissions of the socket decide
+    let mut permission = access.is_none().then(|| Permission::Admin);
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Authenticate { token }) => {
+                if let Some(access) = &access {
+                    permission = access.permission(Some(&token));
+                }
+                match permission {
+                    Some(permission) => Response::Authenticated(permission.to_string()),
+                    None => Response::Error("Invalid token".into()),
+                }
+            }
+            Ok(request) if permission.map_or(true, |permission| permission < needs(&request)) => {
+                match permission {
+                    Some(_) => {
+                        Response::Error(format!("Needs the {} permission", needs(&request)))
+                    }
+                    None => Response::Error("Authenticate first".into()),
+                }
+            }
+            Ok(Request::Attach) => {
+                match daemon.take() {
+                    Some(daemon) => {
+                        let mut json = serde_json::to_vec(&Response::Attached)?;
+                        json.push(b'\n');
+                        writer.write_all(&json).await?;
+                        return daemon.serve(lines, writer).await;
+                    }
+                    None => Response::Error("Not a daemon, see --mode daemon".into()),
+                }
+            }
+            Ok(request) => {
+                let (sender, receiver) = oneshot::channel();
+                calls.send((request, sender)).await.context("Node stopped")?;
+                receiver.await.context("Node stopped")?
+            }
+            Err(err) => Response::Error(format!("Invalid request: {}", err)),
+        };
+        let mut json = serde_json::to_vec(&response)?;
+        json.push(b'\n');
+        writer.write_all(&json).await?;
+    }
+    Ok(())
+}
+
+/// Listen on the control socket at `path`, passing requests to `calls`, and
+/// attaching clients to `daemon` if any. With `access`, clients need one of
+/// its tokens.
+pub async fn serve(
+    path: PathBuf,
+    calls: mpsc::Sender<Call>,
+    daemon: Option<Daemon>,
+    access: Option<Tokens>,
+) -> Result<()> {
+    // Left behind by a previous instance
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Removing stale control socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Listening for control clients on {}", path.display()))?;
+    loop {
+        let (stream, _) = listener.accept().await.context("Accepting control client")?;
+        let calls = calls.clone();
+        let daemon = daemon.clone();
+        let access = access.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, calls, daemon, access).await {
+                debug!("Control client failed: {:#}", err);
+            }
+        });
+    }
+}
+
+/// Connect to the control socket at `path`, authenticating with `token` if
+/// given.
+pub async fn connect(path: &Path, token: Option<&str>) -> Result<Client> {
+    let mut client = Client::connect(path).await?;
+    if let Some(token) = token {
+        client.authenticate(token).await?;
+    }
+    Ok(client)
+}
+
+/// Render `status` for the terminal. Rates are computed against `previous`,
+/// taken `elapsed` earlier.
+pub fn render(status: &Status, previous: Option<(&Status, Duration)>) -> String {
+    let rate = |now: u64, before: u64, elapsed: Duration| {
+        now.saturating_sub(before) as f64 / elapsed.as_secs_f64().max(0.001)
+    };
+    let (inbound_rate, outbound_rate) = match previous {
+        Some((previous, elapsed)) => {
+            (
+                rate(status.inbound, previous.inbound, elapsed),
+                rate(status.outbound, previous.outbound, elapsed),
+            )
+        }
+        None => (0.0, 0.0),
+    };
+    let mut peers = status
+        .peers
+        .iter()
+        .filter(|peer| peer.connected)
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|peer| peer.ping_ms.unwrap_or(u64::MAX));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "mesh {}", names::rewrite(&status.peer_id));
+    let _ = writeln!(
+        out,
+        "peers {} connected, {} known   in {} ({:.0} B/s)   out {} ({:.0} B/s)",
+        peers.len(),
+        status.peers.len(),
+        status.inbound,
+        inbound_rate,
+        status.outbound,
+        outbound_rate
+    );
+    let _ = writeln!(out, "\n{:<54}  {:>8}  {:>6}  AGENT", "PEER", "RTT", "PROTOS");
+    for peer in peers {
+        let ping = peer
+            .ping_ms
+            .map_or_else(|| "-".to_owned(), |ping| format!("{}ms", ping));
+        let agent = peer.agent.as_deref().unwrap_or("");
+        let peer_id = names::rewrite(&peer.peer_id);
+        let protocols = peer.protocols.len();
+        let _ = writeln!(out, "{:<54}  {:>8}  {:>6}  {}", peer_id, ping, protocols, agent);
+    }
+    let _ = writeln!(out, "\nTOPICS");
+    for topic in &status.topics {
+        match status.keys.get(topic) {
+            Some(fingerprint) => {
+                let _ = writeln!(out, "{}  key {}", topic, fingerprint);
+            }
+            None => {
+                let _ = writeln!(out, "{}", topic);
+            }
+        }
+    }
+    if !status.backoffs.is_empty() {
+        let _ = writeln!(out, "\nBACKING OFF");
+        for backoff in &status.backoffs {
+            let _ = writeln!(
+                out,
+                "{}  {} failures, {}, retry in {}s",
+                backoff.address,
+                backoff.failures,
+                backoff.outcome,
+                backoff.retry_ms / 1000
+            );
+        }
+    }
+    let _ = writeln!(out, "\nEVENTS");
+    for event in &status.events {
+        let _ = writeln!(out, "{}", names::rewrite(event));
+    }
+    out
+}
+
+/// Show the status of the node listening at `path` until interrupted.
+pub async fn top(path: &Path, token: Option<&str>) -> Result<()> {
+    let mut client = connect(path, token).await?;
+    let mut previous: Option<(Status, Instant)> = None;
+    loop {
+        let status = client.status().await?;
+        let now = Instant::now();
+        let screen = render(
+            &status,
+            previous
+                .as_ref()
+                .map(|(status, at)| (status, now.duration_since(*at))),
+        );
+        // Clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H{}", screen);
+        previous = Some((status, now));
+        sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_status_over_control_socket() {
+        let dir = std::env::temp_dir().join(format!("mesh-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE_NAME);
+        let (sender, mut calls) = mpsc::channel(1);
+        let access = "status reader".parse().unwrap();
+        tokio::spawn(serve(path.clone(), sender, None, Some(access)));
+        let status = Status {
+            peer_id: "local".into(),
+            peers: vec![PeerStatus {
+                peer_id:       "remote".into(),
+                connected:     true,
+                ping_ms:       Some(12),
+                ping_avg_ms:   Some(15),
+                ping_failures: 1,
+                agent:         Some("mesh-rs/0.1.0".into()),
+                protocols:     vec!["/ipfs/ping/1.0.0".into()],
+            }],
+            inbound: 2000,
+            ..Status::default()
+        };
+        let node = {
+            let status = status.clone();
+            tokio::spawn(async move {
+                let (request, sender): Call = calls.next().await.unwrap();
+                assert_eq!(request, Request::Status);
+                sender.send(Response::Status(status)).unwrap();
+            })
+        };
+        while !path.exists() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let mut client = Client::connect(&path).await.unwrap();
+        assert!(client.status().await.is_err());
+        assert!(client.authenticate("writer").await.is_err());
+        assert_eq!(client.authenticate("reader").await.unwrap(), "status");
+        let refused = client.bundle("remote").await.unwrap_err();
+        assert!(refused.to_string().contains("Needs the admin permission"));
+        assert_eq!(client.status().await.unwrap(), status);
+        node.await.unwrap();
+
+        let before = Status {
+            inbound: 1000,
+            ..status.clone()
+        };
+        let screen = render(&status, Some((&before, Duration::from_secs(2))));
+        assert!(screen.contains("peers 1 connected, 1 known   in 2000 (500 B/s)"));
+        assert!(screen.contains("12ms       1  mesh-rs/0.1.0"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Diagnostic bundles on panic.
+//!
+//! A node with a data directory installs a panic hook that writes a
+//! [`Bundle`] to `crash-<unix time>.json` in it and then aborts, so crashes
+//! on remote devices can be diagnosed afterwards and a supervisor restarts
+//! the node instead of leaving it half-running. The bundle holds the panic
+//! message and backtrace, a hash of the configuration and the node's
+//! [`control::Status`] as of the last [`SNAPSHOT_INTERVAL`]: peers, topics
+//! and recent events.
+
+use super::control;
+use crate::prelude::*;
+use sha2::{Digest, Sha256};
+use std::{
+    backtrace::Backtrace,
+    fs,
+    panic,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Prefix of bundle file names in the data directory.
+pub const FILE_PREFIX: &str = "crash-";
+
+/// How often the status in the bundle is refreshed.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    /// Seconds since the Unix epoch.
+    pub time:        u64,
+    pub version:     String,
+    pub message:     String,
+    /// Source location of the panic.
+    pub location:    Option<String>,
+    pub thread:      Option<String>,
+    pub backtrace:   String,
+    /// Identifies the configuration, see [`config_hash`].
+    pub config_hash: String,
+    pub status:      control::Status,
+}
+
+/// A short hash of `config`'s debug representation, to tell whether crashes
+/// share a configuration without including it.
+pub fn config_hash(config: &impl std::fmt::Debug) -> String {
+    let digest = Sha256::digest(format!("{:?}", config).as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// Write `bundle` to the data directory `dir`.
+pub fn write(dir: &Path, bundle: &Bundle) -> Result<PathBuf> {
+    let path = dir.join(format!("{}{}.json", FILE_PREFIX, bundle.time));
+    let json = serde_json::to_vec_pretty(bundle)?;
+    fs::write(&path, json).with_context(|| format!("Writing {}", path.display()))?;
+    Ok(path)
+}
+
+/// The status kept for the bundle.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    status: Arc<Mutex<control::Status>>,
+}
+
+impl Snapshot {
+    pub fn update(&self, status: control::Status) {
+        if let Ok(mut snapshot) = self.status.lock() {
+            *snapshot = status;
+        }
+    }
+}
+
+/// Write a bundle to `dir` and abort on panic, after the previous hook
+/// printed the panic.
+pub fn install(dir: PathBuf, config_hash: String) -> Snapshot {
+    let snapshot = Snapshot {
+        status: Arc::new(Mutex::new(control::Status::default())),
+    };
+    let status = snapshot.status.clone();
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let payload = info.payload();
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).to_owned()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".to_owned()
+        };
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let bundle = Bundle {
+            time: since_epoch.as_secs(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            message,
+            location: info.location().map(ToString::to_string),
+            thread: std::thread::current().name().map(Into::into),
+            backtrace: Backtrace::force_capture().to_string(),
+            config_hash: config_hash.clone(),
+            // The panic may have happened while updating it
+            status: status
+                .try_lock()
+                .map(|status| status.clone())
+                .unwrap_or_default(),
+        };
+        match write(&dir, &bundle) {
+            Ok(path) => eprintln!("Wrote crash report to {}", path.display()),
+            Err(err) => eprintln!("Could not write crash report: {:#}", err),
+        }
+        std::process::abort();
+    }));
+    snapshot
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_writes_bundle() {
+        let dir = std::env::temp_dir().join(format!("mesh-crash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(config_hash(&("a", 1)), config_hash(&("a", 1)));
+        assert!(config_hash(&("a", 1)) != config_hash(&("a", 2)));
+        let bundle = Bundle {
+            time:        1_600_000_000,
+            version:     "0.1.0".into(),
+            message:     "index out of bounds".into(),
+            location:    Some("src/node/mod.rs:1:1".into()),
+            thread:      Some("main".into()),
+            backtrace:   String::new(),
+            config_hash: config_hash(&()),
+            status:      control::Status {
+                events: vec!["Published 3 bytes on chat".into()],
+                ..control::Status::default()
+            },
+        };
+        let path = write(&dir, &bundle).unwrap();
+        assert_eq!(path, dir.join("crash-1600000000.json"));
+        let read: Bundle = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(read, bundle);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! One node per host, shared by local processes.
+//!
+//! Started with `--mode daemon` and a data directory, the node lets the
+//! processes of the host attach to it over the [`control`] socket instead
+//! of each running its own swarm. A client sends the `"Attach"` request,
+//! and from then on the connection speaks one JSON [`Request`] per line and
+//! reads one JSON [`Response`] per line back, with a [`Response::Message`]
+//! pushed in between for every message on a topic the client subscribed to.
+//! The `mesh-client` crate does that for Rust processes, and `mesh attach` for the shell.
+//! `mesh ctl publish`, `ctl subscribe`, `ctl peers` and `ctl dial` do one
+//! thing each, for scripts; the last two work on any node with a control
+//! socket.
+//!
+//! Subscriptions are shared: the daemon subscribes to a topic when the
+//! first client does, and unsubscribes when the last one unsubscribes or
+//! detaches, unless the node was subscribed before, like with `--topic`.
+//! Messages published by one client go to the network and to the other
+//! clients subscribed on the host, and only fail to publish if they reach
+//! neither. Client subscriptions are not stored in
+//! the data directory, as clients subscribe again when they reattach.
+//!
+//! [`control`]: super::control
+
+pub use mesh_client::daemon::{Message, Request, Response};
+
+use super::{control, input, Event, NodeHandle, TopicOptions};
+use crate::prelude::*;
+use anyhow::anyhow;
+use futures::channel::{mpsc, oneshot};
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+    net::UnixStream,
+};
+
+/// Messages held for a client that does not read them, before dropping.
+const BUFFER: usize = 256;
+
+/// What a client asks of the broker.
+enum Op {
+    Attach(mpsc::Sender<Response>),
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish(String, Vec<u8>),
+    Detach,
+}
+
+type Call = (u64, Op, oneshot::Sender<Result<()>>);
+
+/// The clients attached to the node, see the [module docs](self).
+#[derive(Clone)]
+pub struct Daemon {
+    handle: NodeHandle,
+    calls:  mpsc::Sender<Call>,
+    next:   Arc<AtomicU64>,
+}
+
+impl Daemon {
+    /// Share the node of `handle`. The returned future routes the messages
+    /// of the attached clients, until the node stops.
+    pub fn new(handle: NodeHandle) -> (Self, impl Future<Output = Result<()>>) {
+        let (calls, receiver) = mpsc::channel(16);
+        let broker = Broker {
+            handle:   handle.clone(),
+            clients:  HashMap::new(),
+            borrowed: HashSet::new(),
+        };
+        let daemon = Self {
+            handle,
+            calls,
+            next: Arc::default(),
+        };
+        (daemon, broker.run(receiver))
+    }
+
+    async fn call(&mut self, client: u64, op: Op) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.calls
+            .send((client, op, sender))
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Serve an attached client on the rest of its control connection.
+    pub async fn serve(
+        mut self,
+        mut lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+        mut writer: WriteHalf<UnixStream>,
+    ) -> Result<()> {
+        let client = self.next.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut pushed) = mpsc::channel(BUFFER);
+        self.call(client, Op::Attach(sender)).await?;
+        debug!("Control client {} attached", client);
+        let result = loop {
+            let response = tokio::select! {
+                line = lines.next_line() => {
+                    match line {
+                        Ok(Some(line)) => self.answer(client, &line).await,
+                        Ok(None) => break Ok(()),
+                        Err(err) => break Err(err.into()),
+                    }
+                }
+                Some(response) = pushed.next() => response,
+            };
+            let mut json = serde_json::to_vec(&response)?;
+            json.push(b'\n');
+            if let Err(err) = writer.write_all(&json).await {
+                break Err(err.into());
+            }
+        };
+        debug!("Control client {} detached", client);
+        self.call(client, Op::Detach).await?;
+        result
+    }
+
+    async fn answer(&mut self, client: u64, line: &str) -> Response {
+        let request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => return Response::Error(format!("Invalid request: {}", err)),
+        };
+        let result = match request {
+            Request::Subscribe { topic } => self.call(client, Op::Subscribe(topic)).await,
+            Request::Unsubscribe { topic } => self.call(client, Op::Unsubscribe(topic)).await,
+            Request::Publish { topic, data } => self.call(client, Op::Publish(topic, data)).await,
+            Request::Peers => {
+                return match self.handle.peers().await {
+                    Ok(peers) => Response::Peers(peers.iter().map(PeerId::to_string).collect()),
+                    Err(err) => Response::Error(format!("{:#}", err)),
+                };
+            }
+        };
+        match result {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::Error(format!("{:#}", err)),
+        }
+    }
+}
+
+struct Attached {
+    sender: mpsc::Sender<Response>,
+    topics: HashSet<String>,
+}
+
+/// Owns the shared subscriptions, so clients subscribing and leaving at
+/// once are applied in order.
+struct Broker {
+    handle:   NodeHandle,
+    clients:  HashMap<u64, Attached>,
+    /// Topics the node was subscribed to before the clients.
+    borrowed: HashSet<String>,
+}
+
+impl Broker {
+    async fn run(mut self, mut calls: mpsc::Receiver<Call>) -> Result<()> {
+        let mut events = self.handle.events().await?;
+        loop {
+            tokio::select! {
+                Some((client, op, sender)) = calls.next() => {
+                    let _ = sender.send(self.apply(client, op).await);
+                }
+                event = events.next() => {
+                    match event {
+                        Some(Event::Message { source, topic, data, .. }) => {
+                            let message = Message {
+                                source: source.to_string(),
+                                topic,
+                                data,
+                            };
+                            self.push(None, &message);
+                        }
+                        Some(_) => {}
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn subscribers(&self, topic: &str) -> usize {
+        self.clients
+            .values()
+            .filter(|client| client.topics.contains(topic))
+            .count()
+    }
+
+    async fn apply(&mut self, client: u64, op: Op) -> Result<()> {
+        match op {
+            Op::Attach(sender) => {
+                let topics = HashSet::new();
+                self.clients.insert(client, Attached { sender, topics });
+            }
+            Op::Subscribe(topic) => {
+                if self.subscribers(&topic) == 0 {
+                    let topics = self.handle.topics().await?;
+                    if topics.iter().any(|(subscribed, _)| *subscribed == topic) {
+                        self.borrowed.insert(topic.clone());
+                    } else {
+                        self.handle.subscribe(&topic, TopicOptions::default()).await?;
+                    }
+                }
+                self.attached(client)?.topics.insert(topic);
+            }
+            Op::Unsubscribe(topic) => {
+                if self.attached(client)?.topics.remove(&topic) {
+                    self.release(&topic).await?;
+                }
+            }
+            Op::Publish(topic, data) => {
+                let result = self.handle.publish(&topic, &data).await;
+                let message = Message {
+                    source: self.handle.local_peer_id().to_string(),
+                    topic,
+                    data,
+                };
+                let pushed = self.push(Some(client), &message);
+                match result {
+                    Err(err) if pushed > 0 => {
+                        debug!("Message on {} only reached the host: {:#}", message.topic, err);
+                    }
+                    result => result?,
+                }
+            }
+            Op::Detach => {
+                let topics = self.attached(client)?.topics.clone();
+                self.clients.remove(&client);
+                for topic in topics {
+                    self.release(&topic).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn attached(&mut self, client: u64) -> Result<&mut Attached> {
+        self.clients
+            .get_mut(&client)
+            .ok_or_else(|| anyhow!("Client {} is not attached", client))
+    }
+
+    /// Unsubscribe the node from `topic` once no client is subscribed.
+    async fn release(&mut self, topic: &str) -> Result<()> {
+        if self.subscribers(topic) > 0 || self.borrowed.remove(topic) {
+            return Ok(());
+        }
+        self.handle.unsubscribe(topic).await.map(drop)
+    }
+
+    /// Pass `message` to the clients subscribed to its topic, but `from`.
+    /// Returns the number of clients it reached.
+    fn push(&mut self, from: Option<u64>, message: &Message) -> usize {
+        let mut pushed = 0;
+        for (id, client) in &mut self.clients {
+            if Some(*id) == from || !client.topics.contains(&message.topic) {
+                continue;
+            }
+            match client.sender.try_send(Response::Message(message.clone())) {
+                Ok(()) => pushed += 1,
+                Err(_) => debug!("Dropping message on {} for slow client {}", message.topic, id),
+            }
+        }
+        pushed
+    }
+}
+
+fn print(message: &Message) {
+    println!(
+        "[{}] {}: {}",
+        message.topic,
+        message.source,
+        String::from_utf8_lossy(&message.data)
+    );
+}
+
+/// `mesh ctl subscribe`: print the messages on `topics` until the daemon
+/// stops.
+pub async fn subscribe(path: &Path, token: Option<&str>, topics: &[String]) -> Result<()> {
+    let mut client = control::connect(path, token).await?.attach().await?;
+    for topic in topics {
+        client.subscribe(topic).await?;
+    }
+    loop {
+        print(&client.next_message().await?);
+    }
+}
+
+/// `mesh attach`: print the messages on `topics` and publish the lines of
+/// stdin on the first of them, authenticating with `token` if given.
+pub async fn attach(path: &Path, token: Option<&str>, topics: &[String]) -> Result<()> {
+    let mut client = control::connect(path, token).await?.attach().await?;
+    for topic in topics {
+        client.subscribe(topic).await?;
+    }
+    let mut stdin = input::Lines::stdin().context("Reading stdin")?;
+    loop {
+        tokio::select! {
+            line = stdin.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                let topic = topics.first().context("Publishing needs a --topic")?;
+                client.publish(topic, line.as_bytes()).await?;
+            }
+            message = client.next_message() => print(&message?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::Node, test::prelude::assert_eq};
+    use mesh_client::Client;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_shares_subscriptions() {
+        let dir = std::env::temp_dir().join(format!("mesh-daemon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(control::FILE_NAME);
+        let mut node = Node::builder()
+            .with_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let mut handle = node.handle();
+        let (daemon, broker) = Daemon::new(node.handle());
+        let (calls, _) = mpsc::channel(1);
+        tokio::spawn(control::serve(path.clone(), calls, Some(daemon), None));
+        tokio::spawn(broker);
+        let clients = async {
+            while !path.exists() {
+                sleep(Duration::from_millis(10)).await;
+            }
+            let mut first = Client::connect(&path).await.unwrap().attach().await.unwrap();
+            let mut second = Client::connect(&path).await.unwrap().attach().await.unwrap();
+            first.subscribe("chat").await.unwrap();
+            second.subscribe("chat").await.unwrap();
+            first.publish("chat", b"hello").await.unwrap();
+            let message = second.next_message().await.unwrap();
+            assert_eq!(message.data, b"hello");
+            assert_eq!(message.source, handle.local_peer_id().to_string());
+
+            first.unsubscribe("chat").await.unwrap();
+            assert_eq!(handle.topics().await.unwrap().len(), 1);
+            drop(second);
+            while !handle.topics().await.unwrap().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+            handle.shutdown().await.unwrap();
+        };
+        let (result, ()) = future::join(node.run(), clients).await;
+        result.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
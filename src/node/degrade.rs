@@ -0,0 +1,99 @@
+//! Starting without the subsystems that failed.
+//!
+//! mDNS needs multicast, which containers and some networks do not allow,
+//! and the metrics endpoint and the StatsD exporter need their address. By
+//! default, `--subsystem-failures fail`, any of them failing stops the node
+//! from starting. With `degrade` the node starts without that subsystem
+//! instead, logs a warning and emits [`Event::SubsystemDegraded`]. Event
+//! consumers that subscribe later get the event of every subsystem disabled
+//! before.
+//!
+//! [`Event::SubsystemDegraded`]: crate::node::Event::SubsystemDegraded
+
+use crate::prelude::*;
+use anyhow::bail;
+use std::{fmt, str::FromStr};
+
+/// What to do when an optional subsystem fails to start.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Policy {
+    /// Fail starting the node.
+    #[default]
+    Fail,
+    /// Start the node without the subsystem.
+    Degrade,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "fail" => Self::Fail,
+            "degrade" => Self::Degrade,
+            _ => bail!("Unknown subsystem failure policy {}, expected fail or degrade", s),
+        })
+    }
+}
+
+/// A subsystem the node can go without.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    Mdns,
+    Metrics,
+    Statsd,
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mdns => "mDNS discovery",
+            Self::Metrics => "metrics endpoint",
+            Self::Statsd => "StatsD exporter",
+        })
+    }
+}
+
+/// A subsystem that failed, with its error.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Degraded {
+    pub subsystem: Subsystem,
+    pub error:     String,
+}
+
+impl Policy {
+    /// Go on without `subsystem`, which failed with `error`, or return the
+    /// error.
+    pub fn apply(self, subsystem: Subsystem, error: anyhow::Error) -> Result<Degraded> {
+        if self == Self::Fail {
+            return Err(error);
+        }
+        warn!("Continuing without the {}: {:#}", subsystem, error);
+        Ok(Degraded {
+            subsystem,
+            error: format!("{:#}", error),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_degrades_by_policy() {
+        assert_eq!("degrade".parse::<Policy>().unwrap(), Policy::Degrade);
+        assert!("ignore".parse::<Policy>().is_err());
+
+        let error = || anyhow!("Permission denied").context("Creating mDNS");
+        let failed = Policy::Fail.apply(Subsystem::Mdns, error()).unwrap_err();
+        assert_eq!(format!("{:#}", failed), "Creating mDNS: Permission denied");
+        assert_eq!(Policy::Degrade.apply(Subsystem::Mdns, error()).unwrap(), Degraded {
+            subsystem: Subsystem::Mdns,
+            error: "Creating mDNS: Permission denied".into(),
+        });
+    }
+}
@@ -0,0 +1,247 @@
+//! Delta encoding for frequently republished state.
+//!
+//! A publisher on a state topic sends each new version of its state as a diff
+//! against the previous version it published. Receivers keep the last version
+//! per publisher and apply the diff. Every [`SNAPSHOT_INTERVAL`] versions the
+//! publisher sends the full state instead, so receivers that missed an update
+//! or joined late resynchronize.
+//!
+//! Diffs are computed by matching fixed-size blocks of the previous version,
+//! which works well for large documents with local changes.
+
+use crate::prelude::*;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// Send a full snapshot every this many versions.
+pub const SNAPSHOT_INTERVAL: u64 = 16;
+
+/// Largest state a delta may reconstruct. Without a limit, a small delta
+/// copying the base over and over would exhaust memory.
+pub const MAX_STATE_SIZE: usize = 16 * 1024 * 1024;
+
+const BLOCK_SIZE: usize = 32;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Op {
+    /// Copy a range of the base version.
+    Copy { offset: usize, len: usize },
+    /// Insert new bytes.
+    Insert(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// Message published on a state topic.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Update {
+    Snapshot {
+        version: u64,
+        #[serde(with = "serde_bytes")]
+        data:    Vec<u8>,
+    },
+    Delta {
+        version: u64,
+        base:    u64,
+        ops:     Vec<Op>,
+    },
+}
+
+/// Compute the operations that turn `base` into `target`.
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<Op> {
+    let mut blocks = HashMap::new();
+    for (index, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+        blocks.entry(block).or_insert(index * BLOCK_SIZE);
+    }
+    let mut ops = Vec::new();
+    let mut insert = Vec::new();
+    let mut position = 0;
+    while position < target.len() {
+        let matched = target
+            .get(position..position + BLOCK_SIZE)
+            .and_then(|block| blocks.get(block));
+        if let Some(&offset) = matched {
+            // Extend the match as far as it goes
+            let len = base[offset..]
+                .iter()
+                .zip(&target[position..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if !insert.is_empty() {
+                ops.push(Op::Insert(std::mem::take(&mut insert)));
+            }
+            ops.push(Op::Copy { offset, len });
+            position += len;
+        } else {
+            insert.push(target[position]);
+            position += 1;
+        }
+    }
+    if !insert.is_empty() {
+        ops.push(Op::Insert(insert));
+    }
+    ops
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum DeltaError {
+    #[error("Delta copies {offset}..{end} out of a base of {len} bytes")]
+    OutOfBounds {
+        offset: usize,
+        end:    usize,
+        len:    usize,
+    },
+    #[error("Delta reconstructs more than {} bytes", MAX_STATE_SIZE)]
+    TooLarge,
+}
+
+/// Apply the operations of [`diff`] to `base`.
+pub fn apply(base: &[u8], ops: &[Op]) -> Result<Vec<u8>, DeltaError> {
+    let mut result = Vec::with_capacity(base.len());
+    for op in ops {
+        let bytes = match op {
+            Op::Copy { offset, len } => {
+                let end = offset.saturating_add(*len);
+                base.get(*offset..end).ok_or_else(|| {
+                    DeltaError::OutOfBounds {
+                        offset: *offset,
+                        end,
+                        len: base.len(),
+                    }
+                })?
+            }
+            Op::Insert(data) => data.as_slice(),
+        };
+        if result.len() + bytes.len() > MAX_STATE_SIZE {
+            return Err(DeltaError::TooLarge);
+        }
+        result.extend_from_slice(bytes);
+    }
+    Ok(result)
+}
+
+/// Publisher side of a state topic.
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    version: u64,
+    last:    Vec<u8>,
+}
+
+impl Encoder {
+    /// Encode the next version of the state.
+    pub fn update(&mut self, data: &[u8]) -> Update {
+        self.version += 1;
+        let update = if self.version % SNAPSHOT_INTERVAL == 1 {
+            Update::Snapshot {
+                version: self.version,
+                data:    data.to_vec(),
+            }
+        } else {
+            Update::Delta {
+                version: self.version,
+                base:    self.version - 1,
+                ops:     diff(&self.last, data),
+            }
+        };
+        self.last = data.to_vec();
+        update
+    }
+}
+
+/// Receiver side of a state topic, tracking every publisher.
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    states: HashMap<PeerId, (u64, Vec<u8>)>,
+}
+
+impl Decoder {
+    /// Reconstruct the state sent by `source`. Returns `None` if the update
+    /// is based on a version we do not have; we then wait for the next
+    /// snapshot.
+    pub fn receive(&mut self, source: &PeerId, update: Update) -> Option<&[u8]> {
+        match update {
+            Update::Snapshot { version, data } => {
+                self.states.insert(source.clone(), (version, data));
+            }
+            Update::Delta { version, base, ops } => {
+                let (current, state) = self.states.get_mut(source)?;
+                if *current != base {
+                    debug!(
+                        "Missed state update from {} (have {}, need {})",
+                        source, current, base
+                    );
+                    return None;
+                }
+                match apply(state, &ops) {
+                    Ok(next) => {
+                        *current = version;
+                        *state = next;
+                    }
+                    Err(err) => {
+                        warn!("Invalid state delta from {}: {}", source, err);
+                        self.states.remove(source);
+                        return None;
+                    }
+                }
+            }
+        }
+        self.states.get(source).map(|(_, state)| state.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, *};
+
+    #[test]
+    fn test_small_change_gives_small_delta() {
+        let base = (0..4096_u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut target = base.clone();
+        target[2000] ^= 0xff;
+        let ops = diff(&base, &target);
+        let encoded = serde_cbor::to_vec(&ops).unwrap();
+        assert!(encoded.len() < 100);
+        assert_eq!(apply(&base, &ops).unwrap(), target);
+    }
+
+    #[test]
+    fn test_decoder_resyncs_on_snapshot() {
+        let source = PeerId::random();
+        let mut encoder = Encoder::default();
+        let mut decoder = Decoder::default();
+        let updates = (0..SNAPSHOT_INTERVAL + 2)
+            .map(|i| encoder.update(format!("state {}", i).as_bytes()))
+            .collect::<Vec<_>>();
+
+        // Joining late, deltas are ignored until the next snapshot
+        assert_eq!(decoder.receive(&source, updates[1].clone()), None);
+        let snapshot = updates[SNAPSHOT_INTERVAL as usize].clone();
+        assert!(matches!(snapshot, Update::Snapshot { .. }));
+        decoder.receive(&source, snapshot);
+        let last = updates[SNAPSHOT_INTERVAL as usize + 1].clone();
+        assert_eq!(
+            decoder.receive(&source, last),
+            Some(format!("state {}", SNAPSHOT_INTERVAL + 1).as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_amplification_is_bounded() {
+        let base = vec![0_u8; 1024 * 1024];
+        let ops = vec![
+            Op::Copy {
+                offset: 0,
+                len:    base.len(),
+            };
+            17
+        ];
+        assert_eq!(apply(&base, &ops), Err(DeltaError::TooLarge));
+    }
+
+    proptest! {
+        #[test]
+        fn test_diff_roundtrip(base: Vec<u8>, target: Vec<u8>) {
+            let ops = diff(&base, &target);
+            prop_assert_eq!(apply(&base, &ops).unwrap(), target);
+        }
+    }
+}
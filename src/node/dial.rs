@@ -0,0 +1,391 @@
+//! Why dials fail.
+//!
+//! Dialing a peer tries its known addresses one after the other. Once the
+//! last of them failed, the node logs what happened at each address at info
+//! level, adds it to the recent events of `mesh top` and emits
+//! [`Event::DialFailed`] with an [`Attempt`] per address, so an unreachable
+//! bootstrap node can be told apart from one with a different peer id
+//! without trace logs.
+//!
+//! Addresses that failed are kept in [`Backoffs`] and not dialed again until
+//! their backoff ran out, one second after the first failure and doubling
+//! up to [`MAX_BACKOFF`], so peers rediscovered over and over at a dead
+//! address do not have it dialed each time. The transport refuses them with
+//! [`Outcome::BackingOff`] before trying. A connection made to the address
+//! or an explicit [`crate::node::Node::dial`] clears it, and the control
+//! [`Status`](crate::node::control::Status) lists the addresses backing off.
+//!
+//! [`Event::DialFailed`]: crate::node::Event::DialFailed
+
+use super::{
+    activation::Stream,
+    control, family::WrongFamily, gate,
+    negotiation::{self, Reason},
+};
+use crate::prelude::*;
+use libp2p::{
+    core::{
+        connection::PendingConnectionError,
+        transport::{ListenerEvent, TransportError},
+    },
+    Multiaddr, PeerId, Transport,
+};
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt, io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Longest wait before dialing a failed address again.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Failed addresses remembered at most.
+const REMEMBERED: usize = 1024;
+
+/// How long after its backoff ran out a failed address is forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+/// What became of dialing one address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    /// Nothing listens on the address.
+    Refused,
+    /// The address did not answer, or the upgrade did not finish, in time.
+    Timeout,
+    /// There is no route to the address.
+    Unreachable,
+    /// The name in the address did not resolve, or the device is missing.
+    NotFound,
+    /// The peer at the address is not the one we dialed.
+    WrongPeerId,
+    /// None of our transports can dial the address.
+    UnsupportedAddress,
+    /// The connection was made, but could not be upgraded.
+    Negotiation(Reason),
+    /// The address failed recently and was not dialed.
+    BackingOff,
+    /// The [`gate`](super::gate) refused the address or peer.
+    Denied,
+    Other,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Refused => f.write_str("refused"),
+            Self::Timeout => f.write_str("timed out"),
+            Self::Unreachable => f.write_str("unreachable"),
+            Self::NotFound => f.write_str("not found"),
+            Self::WrongPeerId => f.write_str("wrong peer id"),
+            Self::UnsupportedAddress => f.write_str("unsupported address"),
+            Self::Negotiation(reason) => write!(f, "negotiation failed ({})", reason),
+            Self::BackingOff => f.write_str("backing off"),
+            Self::Denied => f.write_str("denied"),
+            Self::Other => f.write_str("failed"),
+        }
+    }
+}
+
+/// The outcome of an I/O error of the transport.
+fn io_outcome(error: &io::Error) -> Outcome {
+    if error.get_ref().map_or(false, |inner| inner.is::<BackingOff>()) {
+        return Outcome::BackingOff;
+    }
+    if error.get_ref().map_or(false, |inner| inner.is::<WrongFamily>()) {
+        return Outcome::UnsupportedAddress;
+    }
+    if gate::is_denied(error) {
+        return Outcome::Denied;
+    }
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => Outcome::Refused,
+        io::ErrorKind::TimedOut => Outcome::Timeout,
+        io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable
+        | io::ErrorKind::AddrNotAvailable => Outcome::Unreachable,
+        io::ErrorKind::NotFound => Outcome::NotFound,
+        _ => Outcome::Other,
+    }
+}
+
+pub fn outcome(error: &PendingConnectionError<io::Error>) -> Outcome {
+    match error {
+        PendingConnectionError::InvalidPeerId => Outcome::WrongPeerId,
+        PendingConnectionError::Transport(TransportError::MultiaddrNotSupported(_)) => {
+            Outcome::UnsupportedAddress
+        }
+        PendingConnectionError::ConnectionLimit(_) => Outcome::Other,
+        PendingConnectionError::IO(error) => io_outcome(error),
+        PendingConnectionError::Transport(TransportError::Other(error)) => {
+            match negotiation::reason(error) {
+                Some(Reason::Timeout) => Outcome::Timeout,
+                Some(Reason::Transport) | None => {
+                    // The transport keeps the I/O error of the dial, see
+                    // `negotiation::classify`
+                    let dial = error
+                        .get_ref()
+                        .and_then(StdError::source)
+                        .and_then(|error| error.downcast_ref::<io::Error>());
+                    io_outcome(dial.unwrap_or(error))
+                }
+                Some(reason) => Outcome::Negotiation(reason),
+            }
+        }
+    }
+}
+
+/// A failed dial of one address.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Attempt {
+    pub address: Multiaddr,
+    pub outcome: Outcome,
+    pub error:   String,
+}
+
+impl Attempt {
+    pub fn new(address: Multiaddr, error: &PendingConnectionError<io::Error>) -> Self {
+        Self {
+            address,
+            outcome: outcome(error),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Attempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.address, self.outcome)
+    }
+}
+
+/// The failed attempts of dials in progress, by peer.
+#[derive(Default, Debug)]
+pub struct Dials(HashMap<PeerId, Vec<Attempt>>);
+
+impl Dials {
+    /// Record a failed `attempt` with `remaining` addresses left to try.
+    /// Returns the attempts of the dial after the last one failed.
+    pub fn failed(
+        &mut self,
+        peer: &PeerId,
+        attempt: Attempt,
+        remaining: u32,
+    ) -> Option<Vec<Attempt>> {
+        self.0.entry(peer.clone()).or_default().push(attempt);
+        if remaining > 0 {
+            return None;
+        }
+        self.0.remove(peer)
+    }
+
+    /// Forget the failed attempts of a dial that got through.
+    pub fn connected(&mut self, peer: &PeerId) {
+        self.0.remove(peer);
+    }
+}
+
+/// The error of dials refused by [`Backoffs`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Backing off after failed dials")]
+pub struct BackingOff;
+
+#[derive(Clone, Debug)]
+struct Backoff {
+    peer:     Option<PeerId>,
+    failures: u32,
+    outcome:  Outcome,
+    until:    Instant,
+}
+
+/// Addresses that recently failed to dial, with when to try them again.
+/// Shared with the transport.
+#[derive(Clone, Default, Debug)]
+pub struct Backoffs(Arc<Mutex<HashMap<Multiaddr, Backoff>>>);
+
+impl Backoffs {
+    /// Record that dialing `address`, of `peer` if known, failed at `now`.
+    pub fn failed(
+        &self,
+        address: &Multiaddr,
+        peer: Option<&PeerId>,
+        outcome: Outcome,
+        now: Instant,
+    ) {
+        let mut backoffs = self.0.lock().unwrap();
+        backoffs.retain(|_, backoff| backoff.until + FORGET_AFTER > now);
+        if backoffs.len() >= REMEMBERED && !backoffs.contains_key(address) {
+            let oldest = backoffs
+                .iter()
+                .min_by_key(|(_, backoff)| backoff.until)
+                .map(|(address, _)| address.clone());
+            if let Some(oldest) = oldest {
+                backoffs.remove(&oldest);
+            }
+        }
+        let backoff = backoffs.entry(address.clone()).or_insert(Backoff {
+            peer: None,
+            failures: 0,
+            outcome,
+            until: now,
+        });
+        backoff.failures += 1;
+        backoff.outcome = outcome;
+        if let Some(peer) = peer {
+            backoff.peer = Some(peer.clone());
+        }
+        let delay = Duration::from_secs(1 << (backoff.failures - 1).min(6)).min(MAX_BACKOFF);
+        backoff.until = now + delay;
+        debug!("Not dialing {} again for {:?}", address, delay);
+    }
+
+    /// Forget the failures of `address`, after connecting to it or to dial
+    /// it regardless.
+    pub fn clear(&self, address: &Multiaddr) {
+        self.0.lock().unwrap().remove(address);
+    }
+
+    pub fn is_backing_off(&self, address: &Multiaddr, now: Instant) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(address)
+            .map_or(false, |backoff| backoff.until > now)
+    }
+
+    /// The addresses backing off at `now`, soonest retried first.
+    pub fn status(&self, now: Instant) -> Vec<control::Backoff> {
+        let backoffs = self.0.lock().unwrap();
+        let mut status: Vec<_> = backoffs
+            .iter()
+            .filter(|(_, backoff)| backoff.until > now)
+            .map(|(address, backoff)| {
+                control::Backoff {
+                    address:  address.to_string(),
+                    peer_id:  backoff.peer.as_ref().map(PeerId::to_base58),
+                    failures: backoff.failures,
+                    outcome:  backoff.outcome.to_string(),
+                    retry_ms: backoff.until.duration_since(now).as_millis() as u64,
+                }
+            })
+            .collect();
+        status.sort_by_key(|backoff| backoff.retry_ms);
+        status
+    }
+}
+
+type Refused = future::Ready<io::Result<Stream>>;
+
+/// Refuses to dial the addresses backing off and leaves the others to the
+/// transports behind it. It never listens, nor makes a connection.
+impl Transport for Backoffs {
+    type Dial = Refused;
+    type Error = io::Error;
+    type Listener = stream::Pending<io::Result<ListenerEvent<Refused, io::Error>>>;
+    type ListenerUpgrade = Refused;
+    type Output = Stream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(address))
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if self.is_backing_off(&address, Instant::now()) {
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::Other,
+                BackingOff,
+            )));
+        }
+        Err(TransportError::MultiaddrNotSupported(address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::core::{
+        either::EitherError, transport::timeout::TransportTimeoutError, upgrade::UpgradeError,
+    };
+
+    type Upgraded = TransportTimeoutError<
+        EitherError<EitherError<io::Error, UpgradeError<io::Error>>, UpgradeError<io::Error>>,
+    >;
+
+    fn dial_error(error: Upgraded) -> PendingConnectionError<io::Error> {
+        let error = io::Error::new(io::ErrorKind::Other, negotiation::classify(error));
+        PendingConnectionError::Transport(TransportError::Other(error))
+    }
+
+    fn io_error(kind: io::ErrorKind) -> PendingConnectionError<io::Error> {
+        dial_error(TransportTimeoutError::Other(EitherError::A(EitherError::A(
+            io::Error::from(kind),
+        ))))
+    }
+
+    #[test]
+    fn test_reports_each_address() {
+        let peer = PeerId::random();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let mut dials = Dials::default();
+
+        let refused = Attempt::new(address.clone(), &io_error(io::ErrorKind::ConnectionRefused));
+        assert_eq!(refused.outcome, Outcome::Refused);
+        assert_eq!(dials.failed(&peer, refused.clone(), 2), None);
+        let unreachable = Attempt::new(address.clone(), &io_error(io::ErrorKind::HostUnreachable));
+        assert_eq!(unreachable.outcome, Outcome::Unreachable);
+        assert_eq!(dials.failed(&peer, unreachable.clone(), 1), None);
+        let wrong = Attempt::new(address, &PendingConnectionError::InvalidPeerId);
+        assert_eq!(wrong.to_string(), "/ip4/127.0.0.1/tcp/4001 wrong peer id");
+        assert_eq!(
+            dials.failed(&peer, wrong.clone(), 0),
+            Some(vec![refused.clone(), unreachable, wrong])
+        );
+
+        assert_eq!(outcome(&dial_error(TransportTimeoutError::Timeout)), Outcome::Timeout);
+        assert_eq!(dials.failed(&peer, refused, 1), None);
+        dials.connected(&peer);
+        assert!(dials.0.is_empty());
+    }
+
+    #[test]
+    fn test_backs_off_failed_addresses() {
+        let peer = PeerId::random();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let backoffs = Backoffs::default();
+        let now = Instant::now();
+        assert!(!backoffs.is_backing_off(&address, now));
+        backoffs.failed(&address, Some(&peer), Outcome::Refused, now);
+        backoffs.failed(&address, None, Outcome::Timeout, now);
+        backoffs.failed(&address, None, Outcome::Timeout, now);
+        assert!(backoffs.is_backing_off(&address, now + Duration::from_secs(3)));
+        assert!(!backoffs.is_backing_off(&address, now + Duration::from_secs(4)));
+        assert_eq!(backoffs.status(now), vec![control::Backoff {
+            address:  "/ip4/127.0.0.1/tcp/4001".into(),
+            peer_id:  Some(peer.to_base58()),
+            failures: 3,
+            outcome:  "timed out".into(),
+            retry_ms: 4000,
+        }]);
+
+        // The transport refuses it before dialing
+        let error = match backoffs.clone().dial(address.clone()) {
+            Err(TransportError::Other(error)) => error,
+            _ => panic!("Dialed an address backing off"),
+        };
+        let error = dial_error(TransportTimeoutError::Other(EitherError::A(EitherError::A(error))));
+        assert_eq!(outcome(&error), Outcome::BackingOff);
+
+        for _ in 0..10 {
+            backoffs.failed(&address, None, Outcome::Timeout, now);
+        }
+        assert_eq!(backoffs.status(now)[0].retry_ms, MAX_BACKOFF.as_millis() as u64);
+        backoffs.clear(&address);
+        assert!(!backoffs.is_backing_off(&address, now));
+        assert!(matches!(
+            backoffs.dial(address),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+//! Which peer discovery mechanisms run.
+//!
+//! By default the node finds peers on the local network through mDNS and
+//! joins the 0x Mesh DHT through its bootnodes. `--discovery "mdns=false"`
+//! keeps quiet on networks where multicast is unwanted, and
+//! `bootnodes=false` keeps a private deployment from reaching the public
+//! mesh; peers are then found through `--bootstrap` and `--critical` peers
+//! and the DHT among them.
+//!
+//! DHT queries send `parallelism=3` requests at a time, look for the
+//! `replication=20` closest peers and give up after `query_timeout=60s`.
+//! Small deployments answer faster with a shorter timeout; large ones may
+//! send fewer requests at a time to be less chatty.
+//!
+//! Keys announced with [`NodeHandle::start_providing`] are stored by other
+//! peers for `provider_ttl=24h`. The node announces them again halfway
+//! through, so they do not expire while it provides them, and
+//! [`NodeHandle::provided`] lists them with the next announcement.
+//!
+//! [`NodeHandle::dht_put`] stores small records under keys of the
+//! application, apart from the keys of [`naming`], on the `replication`
+//! closest peers, and succeeds once `record_quorum=1` of them stored it.
+//! Peers keep records for `record_ttl=36h`, and the node stores them
+//! again while it runs. Its own store keeps at most `max_records=1024`
+//! records of at most `max_record_size=64KiB` each, for itself and its
+//! peers, and refuses more. [`NodeHandle::dht_get`] returns the values
+//! found under a key.
+//!
+//! `ping_interval`, `ping_timeout` and `ping_failures` set how peers are
+//! pinged and when they are evicted, see [`latency`].
+//!
+//! mDNS queries for `mdns_service=_p2p._udp.local`, the service of all
+//! libp2p nodes, every `mdns_interval=20s`, and answers with addresses
+//! valid for `mdns_ttl=5m`. Unrelated deployments on one LAN keep apart
+//! with their own service name, or with `network=<id>`, which the node
+//! advertises and requires of the peers it reports, see [`Lan`].
+//!
+//! [`NodeHandle::start_providing`]: crate::node::NodeHandle::start_providing
+//! [`NodeHandle::provided`]: crate::node::NodeHandle::provided
+//! [`NodeHandle::dht_put`]: crate::node::NodeHandle::dht_put
+//! [`NodeHandle::dht_get`]: crate::node::NodeHandle::dht_get
+//! [`naming`]: crate::node::naming
+//! [`Event::DhtQuery`]: crate::node::Event::DhtQuery
+//! [`latency`]: crate::node::latency
+
+use crate::{
+    node::{latency, options},
+    prelude::*,
+};
+use anyhow::{anyhow, bail};
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// Prefix of the DHT keys of application records.
+pub const RECORD_PREFIX: &str = "/mesh-rs/record/";
+
+/// The DHT key of the application record `key`.
+pub fn record_key(key: &[u8]) -> Vec<u8> {
+    let mut prefixed = RECORD_PREFIX.as_bytes().to_vec();
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+/// What a DHT query is for, in [`Event::DhtQuery`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueryKind {
+    /// Refreshing the routing table.
+    Bootstrap,
+    FindPeer,
+    ClosestPeers,
+    /// Announcing a provided key.
+    Provide,
+    PutRecord,
+    GetRecord,
+}
+
+/// A key the node provides.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Provided {
+    pub key:       Vec<u8>,
+    /// When the key is announced next.
+    pub republish: Instant,
+    /// When the records of the last successful announcement expire.
+    pub expires:   Option<Instant>,
+}
+
+/// Settings of Kademlia queries.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Dht {
+    /// Requests in flight per query, Kademlia's alpha.
+    pub parallelism:     NonZeroUsize,
+    /// Closest peers a query looks for, Kademlia's k.
+    pub replication:     NonZeroUsize,
+    pub query_timeout:   Duration,
+    /// How long peers keep the provider records we announce.
+    pub provider_ttl:    Duration,
+    /// How long peers keep the records we store.
+    pub record_ttl:      Duration,
+    /// Peers that must store a record for a put to succeed.
+    pub record_quorum:   NonZeroUsize,
+    /// Records the local store keeps at most.
+    pub max_records:     usize,
+    /// Largest record value the local store keeps.
+    pub max_record_size: ByteUnit,
+}
+
+impl Dht {
+    /// How long after announcing a provided key to announce it again.
+    pub fn republish_interval(&self) -> Duration {
+        self.provider_ttl / 2
+    }
+}
+
+impl Default for Dht {
+    fn default() -> Self {
+        Self {
+            parallelism:     NonZeroUsize::new(3).expect("3 != 0"),
+            replication:     NonZeroUsize::new(20).expect("20 != 0"),
+            query_timeout:   Duration::from_secs(60),
+            provider_ttl:    Duration::from_secs(24 * 3600),
+            record_ttl:      Duration::from_secs(36 * 3600),
+            record_quorum:   NonZeroUsize::new(1).expect("1 != 0"),
+            max_records:     1024,
+            max_record_size: 64.kibibytes(),
+        }
+    }
+}
+
+/// Settings of mDNS on the local network.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Lan {
+    /// Service name queried and answered, ending in `.local`.
+    pub service:  String,
+    pub interval: Duration,
+    /// How long peers keep the addresses we answer with.
+    pub ttl:      Duration,
+    /// Only report peers advertising the same id.
+    pub network:  Option<String>,
+}
+
+impl Default for Lan {
+    fn default() -> Self {
+        Self {
+            service:  "_p2p._udp.local".into(),
+            interval: Duration::from_secs(20),
+            ttl:      Duration::from_secs(300),
+            network:  None,
+        }
+    }
+}
+
+impl Lan {
+    pub fn validate(&self) -> Result<()> {
+        let labels = self.service.split('.').collect::<Vec<_>>();
+        if !self.service.is_ascii()
+            || labels.len() < 2
+            || labels.last() != Some(&"local")
+            || labels.iter().any(|label| label.is_empty() || label.len() > 63)
+        {
+            bail!("Invalid mdns_service {}, expected a name like _mesh._udp.local", self.service);
+        }
+        if self.interval == Duration::from_secs(0) {
+            bail!("mdns_interval must be positive");
+        }
+        if let Some(network) = &self.network {
+            if network.is_empty()
+                || network.len() > 64
+                || !network.bytes().all(|byte| byte.is_ascii_graphic())
+            {
+                bail!("Invalid network {}, expected up to 64 printable characters", network);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub mdns:      bool,
+    /// Whether to bootstrap through the 0x Mesh bootnodes.
+    pub bootnodes: bool,
+    pub dht:       Dht,
+    pub ping:      latency::Config,
+    pub lan:       Lan,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mdns:      true,
+            bootnodes: true,
+            dht:       Dht::default(),
+            ping:      latency::Config::default(),
+            lan:       Lan::default(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let toggle = || {
+                value
+                    .parse::<bool>()
+                    .with_context(|| format!("Invalid {} {}, expected true or false", key, value))
+            };
+            let count = || {
+                value.parse::<NonZeroUsize>().with_context(|| {
+                    format!("Invalid {} {}, expected a positive count", key, value)
+                })
+            };
+            match key {
+                "mdns" => config.mdns = toggle()?,
+                "bootnodes" => config.bootnodes = toggle()?,
+                "parallelism" => config.dht.parallelism = count()?,
+                "replication" => config.dht.replication = count()?,
+                "query_timeout" => {
+                    config.dht.query_timeout = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid query_timeout {}", value))?;
+                }
+                "provider_ttl" => {
+                    config.dht.provider_ttl = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid provider_ttl {}", value))?;
+                }
+                "record_ttl" => {
+                    config.dht.record_ttl = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid record_ttl {}", value))?;
+                }
+                "record_quorum" => config.dht.record_quorum = count()?,
+                "max_records" => config.dht.max_records = count()?.get(),
+                "max_record_size" => {
+                    config.dht.max_record_size = value
+                        .parse()
+                        .map_err(|err| anyhow!("Invalid max_record_size {}: {}", value, err))?;
+                }
+                "ping_interval" => {
+                    config.ping.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid ping_interval {}", value))?;
+                }
+                "ping_timeout" => {
+                    config.ping.timeout = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid ping_timeout {}", value))?;
+                }
+                "ping_failures" => {
+                    config.ping.failures = value.parse::<NonZeroU32>().with_context(|| {
+                        format!("Invalid {} {}, expected a positive count", key, value)
+                    })?;
+                }
+                "mdns_service" => config.lan.service = value.to_owned(),
+                "mdns_interval" => {
+                    config.lan.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid mdns_interval {}", value))?;
+                }
+                "mdns_ttl" => {
+                    config.lan.ttl = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid mdns_ttl {}", value))?;
+                }
+                "network" => config.lan.network = Some(value.to_owned()),
+                _ => bail!("Unknown discovery option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if self.dht.provider_ttl < Duration::from_secs(2) {
+            bail!(
+                "provider_ttl {} is too short, expected at least 2s",
+                humantime::format_duration(self.dht.provider_ttl)
+            );
+        }
+        if self.dht.record_ttl < Duration::from_secs(2) {
+            bail!(
+                "record_ttl {} is too short, expected at least 2s",
+                humantime::format_duration(self.dht.record_ttl)
+            );
+        }
+        if self.dht.record_quorum > self.dht.replication {
+            bail!("record_quorum must be at most replication");
+        }
+        if self.ping.interval == Duration::from_secs(0) {
+            bail!("ping_interval must be positive");
+        }
+        self.lan.validate()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert_eq!("mdns=false,bootnodes=false".parse::<Config>().unwrap(), Config {
+            mdns: false,
+            bootnodes: false,
+            ..Config::default()
+        });
+        let config: Config = "parallelism=1 query_timeout=10s".parse().unwrap();
+        assert_eq!(config.dht.parallelism.get(), 1);
+        assert_eq!(config.dht.replication, Dht::default().replication);
+        assert_eq!(config.dht.query_timeout, Duration::from_secs(10));
+        let config: Config = "provider_ttl=1h".parse().unwrap();
+        assert_eq!(config.dht.republish_interval(), Duration::from_secs(1800));
+        assert!("provider_ttl=1s".parse::<Config>().is_err());
+        assert!("parallelism=0".parse::<Config>().is_err());
+        let config: Config = "ping_interval=5s ping_failures=1".parse().unwrap();
+        assert_eq!(config.ping.interval, Duration::from_secs(5));
+        assert_eq!(config.ping.failures.get(), 1);
+        assert_eq!(config.ping.timeout, latency::Config::default().timeout);
+        assert!("ping_failures=0".parse::<Config>().is_err());
+        assert!("mdns=off".parse::<Config>().is_err());
+        let config: Config = "mdns_service=_mesh._udp.local network=prod".parse().unwrap();
+        assert_eq!(config.lan.service, "_mesh._udp.local");
+        assert_eq!(config.lan.network.as_deref(), Some("prod"));
+        assert_eq!(config.lan.interval, Lan::default().interval);
+        assert!("mdns_service=mesh".parse::<Config>().is_err());
+        assert!("mdns_interval=0s".parse::<Config>().is_err());
+        assert!("dht=false".parse::<Config>().is_err());
+        let config: Config = "record_ttl=1h record_quorum=2 max_records=10 max_record_size=1KiB"
+            .parse()
+            .unwrap();
+        assert_eq!(config.dht.record_ttl, Duration::from_secs(3600));
+        assert_eq!(config.dht.record_quorum.get(), 2);
+        assert_eq!(config.dht.max_records, 10);
+        assert_eq!(config.dht.max_record_size, 1.kibibytes());
+        assert!("record_quorum=21".parse::<Config>().is_err());
+        assert!("max_records=0".parse::<Config>().is_err());
+        assert_eq!(record_key(b"k"), b"/mesh-rs/record/k".to_vec());
+    }
+}
@@ -0,0 +1,357 @@
+//! Bootstrap peers found through DNS.
+//!
+//! Bootstrap entries may name hosts instead of IP addresses. `/dns4/`,
+//! `/dns6/` and `/dns/` addresses go to the DNS transport, which resolves
+//! them again at every dial. A `/dnsaddr/<domain>` entry stands for the
+//! peers listed in the TXT records of `_dnsaddr.<domain>`, one
+//! `dnsaddr=<address>/p2p/<peer id>` each, which may name further
+//! `/dnsaddr/` domains, up to [`MAX_DEPTH`] deep. With a trailing
+//! `/p2p/<peer id>` only the records of that peer are used.
+//!
+//! The node resolves these entries when it starts, dials the peers found
+//! and counts them towards the bootstrap quorum. A domain that does not
+//! resolve, or whose peers can not be dialed, is resolved again with
+//! exponential backoff, up to [`bootstrap::MAX_BACKOFF`] apart. TXT records
+//! are looked up at the nameservers of `/etc/resolv.conf`.
+//!
+//! [`bootstrap::MAX_BACKOFF`]: super::bootstrap::MAX_BACKOFF
+
+use super::bootstrap::MAX_BACKOFF;
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData};
+use futures::future::BoxFuture;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+/// Nested `/dnsaddr/` lookups followed at most.
+pub const MAX_DEPTH: usize = 4;
+
+/// Addresses one entry resolves to at most.
+pub const MAX_ADDRESSES: usize = 32;
+
+/// How long a nameserver has to answer.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Whether `address` is resolved here rather than by the transport.
+pub fn is_dnsaddr(address: &Multiaddr) -> bool {
+    matches!(address.iter().next(), Some(Protocol::Dnsaddr(_)))
+}
+
+/// The addresses of the TXT `records` of a `_dnsaddr` domain, of `peer` if
+/// given.
+fn parse_records(records: &[String], peer: Option<&PeerId>) -> Vec<Multiaddr> {
+    records
+        .iter()
+        .filter_map(|record| record.strip_prefix("dnsaddr="))
+        .filter_map(|address| address.parse::<Multiaddr>().ok())
+        .filter(|address| {
+            match (peer, address.iter().last()) {
+                (None, _) => true,
+                (Some(peer), Some(Protocol::P2p(hash))) => {
+                    PeerId::from_multihash(hash).ok().as_ref() == Some(peer)
+                }
+                (Some(_), _) => false,
+            }
+        })
+        .collect()
+}
+
+/// The peer ids and addresses `address`, a `/dnsaddr/` entry, stands for.
+pub async fn resolve(address: Multiaddr) -> Result<Vec<(PeerId, Multiaddr)>> {
+    let nameservers = nameservers()?;
+    let resolved = resolve_in(&nameservers, address.clone(), 0).await?;
+    let peers = resolved
+        .iter()
+        .filter_map(super::behaviour::multipath::split_peer_id)
+        .take(MAX_ADDRESSES)
+        .collect::<Vec<_>>();
+    ensure!(!peers.is_empty(), "{} lists no peers", address);
+    Ok(peers)
+}
+
+fn resolve_in(
+    nameservers: &[SocketAddr],
+    address: Multiaddr,
+    depth: usize,
+) -> BoxFuture<'_, Result<Vec<Multiaddr>>> {
+    Box::pin(async move {
+        let mut protocols = address.iter();
+        let domain = match protocols.next() {
+            Some(Protocol::Dnsaddr(domain)) => domain.to_string(),
+            _ => return Ok(vec![address.clone()]),
+        };
+        ensure!(depth < MAX_DEPTH, "{} nests /dnsaddr/ too deep", address);
+        let peer = match protocols.last() {
+            Some(Protocol::P2p(hash)) => {
+                Some(PeerId::from_multihash(hash).map_err(|_| anyhow!("Invalid peer id"))?)
+            }
+            _ => None,
+        };
+        let records = lookup_txt(nameservers, &format!("_dnsaddr.{}", domain)).await?;
+        let mut addresses = Vec::new();
+        for record in parse_records(&records, peer.as_ref()) {
+            if addresses.len() >= MAX_ADDRESSES {
+                break;
+            }
+            if is_dnsaddr(&record) {
+                match resolve_in(nameservers, record.clone(), depth + 1).await {
+                    Ok(nested) => addresses.extend(nested),
+                    Err(err) => debug!("Could not resolve {}: {:#}", record, err),
+                }
+            } else {
+                addresses.push(record);
+            }
+        }
+        Ok(addresses)
+    })
+}
+
+/// The nameservers of `/etc/resolv.conf`.
+fn nameservers() -> Result<Vec<SocketAddr>> {
+    let conf = fs::read_to_string(RESOLV_CONF)
+        .with_context(|| format!("Reading {}", RESOLV_CONF))?;
+    let nameservers = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect::<Vec<_>>();
+    ensure!(!nameservers.is_empty(), "No nameservers in {}", RESOLV_CONF);
+    Ok(nameservers)
+}
+
+/// The TXT records of `name`, from the first nameserver that answers.
+async fn lookup_txt(nameservers: &[SocketAddr], name: &str) -> Result<Vec<String>> {
+    ensure!(
+        name.len() <= 253 && name.split('.').all(|label| !label.is_empty() && label.len() <= 63),
+        "Invalid domain name {}",
+        name
+    );
+    let id = rand::random::<u16>();
+    let mut builder = Builder::new_query(id, true);
+    builder.add_question(name, false, QueryType::TXT, QueryClass::IN);
+    let query = builder.build().map_err(|_| anyhow!("Query for {} too long", name))?;
+    let mut last_error = anyhow!("No nameservers");
+    for nameserver in nameservers {
+        let answer = tokio::time::timeout(TIMEOUT, query_udp(*nameserver, &query))
+            .await
+            .map_err(|_| anyhow!("{} did not answer", nameserver))
+            .and_then(|answer| answer);
+        let answer = match answer {
+            Ok(answer) if truncated(&answer) => {
+                tokio::time::timeout(TIMEOUT, query_tcp(*nameserver, &query))
+                    .await
+                    .map_err(|_| anyhow!("{} did not answer", nameserver))
+                    .and_then(|answer| answer)
+            }
+            answer => answer,
+        };
+        match answer.and_then(|answer| parse_answer(&answer, id)) {
+            Ok(records) => return Ok(records),
+            Err(err) => last_error = err.context(format!("Asking {} for {}", nameserver, name)),
+        }
+    }
+    Err(last_error)
+}
+
+fn truncated(answer: &[u8]) -> bool {
+    Packet::parse(answer).map_or(false, |packet| packet.header.truncated)
+}
+
+async fn query_udp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let local: IpAddr = match nameserver {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.send_to(query, nameserver).await?;
+    let mut buffer = vec![0; 4096];
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await?;
+        if from == nameserver {
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+    }
+}
+
+async fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    stream.write_all(query).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+fn parse_answer(answer: &[u8], id: u16) -> Result<Vec<String>> {
+    let packet = Packet::parse(answer).map_err(|err| anyhow!("Invalid answer: {}", err))?;
+    ensure!(packet.header.id == id && !packet.header.query, "Unexpected answer");
+    if packet.header.response_code != dns_parser::ResponseCode::NoError {
+        bail!("Lookup failed: {:?}", packet.header.response_code);
+    }
+    Ok(packet
+        .answers
+        .iter()
+        .filter_map(|answer| {
+            match &answer.data {
+                RData::TXT(txt) => {
+                    let bytes = txt.iter().flatten().copied().collect::<Vec<_>>();
+                    String::from_utf8(bytes).ok()
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+struct Name {
+    address:  Multiaddr,
+    /// When to resolve it, none while resolving or resolved.
+    next:     Option<Instant>,
+    resolved: bool,
+    failures: u32,
+    peers:    Vec<PeerId>,
+}
+
+impl Name {
+    fn back_off(&mut self, now: Instant) {
+        self.failures += 1;
+        let backoff = Duration::from_secs(1 << self.failures.min(6)).min(MAX_BACKOFF);
+        self.next = Some(now + backoff);
+    }
+}
+
+/// When to resolve each `/dnsaddr/` bootstrap entry.
+#[derive(Clone, Debug, Default)]
+pub struct Names {
+    names: Vec<Name>,
+}
+
+impl Names {
+    /// Resolve `address` from `now`.
+    pub fn add(&mut self, address: Multiaddr, now: Instant) {
+        if self.names.iter().any(|name| name.address == address) {
+            return;
+        }
+        self.names.push(Name {
+            address,
+            next: Some(now),
+            resolved: false,
+            failures: 0,
+            peers: Vec::new(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The entries to resolve at `now`, which are then being resolved.
+    pub fn due(&mut self, now: Instant) -> Vec<Multiaddr> {
+        let mut due = Vec::new();
+        for name in &mut self.names {
+            if matches!(name.next, Some(next) if next <= now) {
+                name.next = None;
+                due.push(name.address.clone());
+            }
+        }
+        due
+    }
+
+    /// Resolving `address` gave `result`. Returns whether it was the first
+    /// attempt, and the peers to dial.
+    pub fn resolved(
+        &mut self,
+        address: &Multiaddr,
+        result: Result<Vec<(PeerId, Multiaddr)>>,
+        now: Instant,
+    ) -> (bool, Vec<(PeerId, Multiaddr)>) {
+        let name = match self.names.iter_mut().find(|name| name.address == *address) {
+            Some(name) => name,
+            None => return (false, Vec::new()),
+        };
+        let first = !name.resolved;
+        name.resolved = true;
+        match result {
+            Ok(peers) => {
+                info!("{} lists {} bootstrap addresses", address, peers.len());
+                name.failures = 0;
+                name.peers = peers.iter().map(|(peer, _)| peer.clone()).collect();
+                (first, peers)
+            }
+            Err(err) => {
+                warn!("Could not resolve bootstrap peers {}: {:#}", address, err);
+                name.back_off(now);
+                (first, Vec::new())
+            }
+        }
+    }
+
+    /// Dialing `peer` failed, so resolve the entries listing it again.
+    pub fn dial_failed(&mut self, peer: &PeerId, now: Instant) {
+        for name in &mut self.names {
+            if name.next.is_none() && name.resolved && name.peers.contains(peer) {
+                name.back_off(now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_resolves_again_after_failures() {
+        let peer = PeerId::random();
+        let listed = format!("/ip4/198.51.100.1/tcp/4001/p2p/{}", peer)
+            .parse::<Multiaddr>()
+            .unwrap();
+        let records = vec![
+            format!("dnsaddr={}", listed),
+            format!("dnsaddr=/ip4/198.51.100.2/tcp/4001/p2p/{}", PeerId::random()),
+            "v=spf1 -all".into(),
+        ];
+        assert_eq!(parse_records(&records, None).len(), 2);
+        assert_eq!(parse_records(&records, Some(&peer)), vec![listed.clone()]);
+
+        let entry = "/dnsaddr/bootstrap.example.com".parse::<Multiaddr>().unwrap();
+        assert!(is_dnsaddr(&entry) && !is_dnsaddr(&listed));
+        let now = Instant::now();
+        let mut names = Names::default();
+        names.add(entry.clone(), now);
+        names.add(entry.clone(), now);
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.due(now), vec![entry.clone()]);
+        assert!(names.due(now).is_empty());
+        let (first, peers) = names.resolved(&entry, Err(anyhow!("Timed out")), now);
+        assert!(first && peers.is_empty());
+        assert!(names.due(now + Duration::from_millis(1999)).is_empty());
+        let later = now + Duration::from_secs(2);
+        assert_eq!(names.due(later), vec![entry.clone()]);
+        let found = vec![(peer.clone(), listed)];
+        assert_eq!(names.resolved(&entry, Ok(found.clone()), later), (false, found));
+
+        // Unreachable peers are looked up again
+        names.dial_failed(&PeerId::random(), later);
+        assert!(names.due(later + MAX_BACKOFF).is_empty());
+        names.dial_failed(&peer, later);
+        assert_eq!(names.due(later + Duration::from_secs(2)), vec![entry]);
+    }
+}
@@ -0,0 +1,904 @@
+//! Store-and-forward delay-tolerant networking.
+//!
+//! Where the network is split in islands that only meet now and then — a
+//! phone carried between villages, a vehicle visiting field stations — a
+//! message for a peer that is not reachable now can still get there by being
+//! carried. With `--dtn "capacity=10000 lifetime=1d copies=8"` the node keeps
+//! the bundles it sends and those it carries for others in a store, persisted
+//! in the [`storage`], and exchanges them with every peer it meets.
+//!
+//! A bundle is a message for one destination peer, signed by its source and
+//! dropped by everyone once its `lifetime` is over. Bundles spread by binary
+//! spray and wait: the source starts with `copies` copies, and a custodian
+//! meeting a peer without the bundle hands it half of its copies. Custody
+//! passes when the peer confirmed it stored the bundle; only then does the
+//! sender count those copies as gone. A custodian with a single copy left
+//! waits to meet the destination itself. Bundles handed to their destination
+//! are removed, and the destination remembers what it received so copies
+//! still carried around are not delivered twice.
+//!
+//! Both ends of an exchange need the mode enabled. Stores hold at most
+//! `capacity` bundles taking up at most `quota` bytes. A full store makes
+//! room by evicting bundles in the order of its [`Eviction`] policy, and
+//! refuses custody of a bundle that would be evicted first itself.
+//!
+//! Every peer taking custody of a bundle, and its destination on delivery,
+//! sends a signed [`Report`] back to the source, itself as a bundle on
+//! [`REPORT_TOPIC`]. The source collects the reports in the [`Trace`] of the
+//! bundle, showing how far it travelled and whether it arrived.
+//!
+//! [`storage`]: super::storage
+
+use super::{
+    options,
+    signing::{Domain, Layout},
+    storage::Slot,
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// Storage namespace of the bundle store.
+pub const NAMESPACE: &str = "dtn";
+
+/// Storage key of the bundle store, once its file name.
+pub const KEY: &str = "bundles.cbor";
+
+/// Largest payload of a bundle.
+pub const MAX_DATA: usize = 256 * 1024;
+
+/// Topic of the bundles carrying reports, handled by the node itself.
+pub const REPORT_TOPIC: &str = "/mesh-rs/dtn/report";
+
+/// Which bundles a full store evicts first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Eviction {
+    /// The lowest priority, and of those the nearest expiry.
+    Priority,
+    /// The nearest expiry.
+    Expiry,
+    /// The largest.
+    Largest,
+}
+
+impl Eviction {
+    /// `Ordering::Greater` if `a` is evicted before `b`.
+    fn compare(self, a: &Rank, b: &Rank) -> Ordering {
+        match self {
+            Self::Priority => {
+                b.priority
+                    .cmp(&a.priority)
+                    .then(b.expires_ms.cmp(&a.expires_ms))
+            }
+            Self::Expiry => b.expires_ms.cmp(&a.expires_ms),
+            Self::Largest => a.size.cmp(&b.size),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Most bundles stored, our own and those carried for others.
+    pub capacity: usize,
+    /// Most bytes stored.
+    pub quota:    ByteUnit,
+    pub evict:    Eviction,
+    /// Time after which a bundle is dropped.
+    pub lifetime: Duration,
+    /// Copies of a bundle spread through the network.
+    pub copies:   u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            quota:    256.mebibytes(),
+            evict:    Eviction::Priority,
+            lifetime: Duration::from_secs(24 * 60 * 60),
+            copies:   8,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "capacity" => {
+                    config.capacity = value
+                        .parse()
+                        .with_context(|| format!("Invalid capacity {}", value))?;
+                }
+                "quota" => {
+                    config.quota = value
+                        .parse()
+                        .map_err(|err| anyhow!("Invalid quota {}: {}", value, err))?;
+                }
+                "evict" => {
+                    config.evict = match value {
+                        "priority" => Eviction::Priority,
+                        "expiry" => Eviction::Expiry,
+                        "largest" => Eviction::Largest,
+                        _ => bail!("Unknown DTN eviction policy {}", value),
+                    };
+                }
+                "lifetime" => {
+                    config.lifetime = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid lifetime {}", value))?;
+                }
+                "copies" => {
+                    config.copies = value
+                        .parse()
+                        .with_context(|| format!("Invalid copies {}", value))?;
+                }
+                _ => bail!("Unknown DTN option {}", key),
+            }
+        }
+        ensure!(config.capacity > 0, "DTN capacity must be positive");
+        ensure!(config.copies > 0, "DTN copies must be positive");
+        Ok(config)
+    }
+}
+
+/// Milliseconds since the Unix epoch. Bundles expire by the wall clock, as
+/// they outlive restarts and travel between nodes.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// SHA-256 hash of the signed part of a bundle.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BundleId(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+impl fmt::Debug for BundleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.0[..self.0.len().min(8)]))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    /// Protobuf encoding of the source's public key.
+    #[serde(with = "serde_bytes")]
+    public_key:      Vec<u8>,
+    /// Base58 peer id of the destination.
+    pub destination: String,
+    pub topic:       String,
+    pub expires_ms:  u64,
+    /// Bundles of lower priority are evicted first from full stores.
+    pub priority:    u8,
+    #[serde(with = "serde_bytes")]
+    pub data:        Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    signature:       Vec<u8>,
+}
+
+impl Bundle {
+    /// Sign a bundle for `destination`, expiring after `lifetime`.
+    pub fn new(
+        key: &Keypair,
+        destination: &PeerId,
+        topic: &str,
+        data: Vec<u8>,
+        lifetime: Duration,
+        priority: u8,
+    ) -> Result<Self> {
+        let expires_ms = now_ms().saturating_add(lifetime.as_millis() as u64);
+        Self::expiring(key, destination, topic, data, expires_ms, priority)
+    }
+
+    /// Sign a bundle for `destination`, expiring at `expires_ms`.
+    pub fn expiring(
+        key: &Keypair,
+        destination: &PeerId,
+        topic: &str,
+        data: Vec<u8>,
+        expires_ms: u64,
+        priority: u8,
+    ) -> Result<Self> {
+        ensure!(data.len() <= MAX_DATA, "Bundles carry at most {} bytes", MAX_DATA);
+        let mut bundle = Self {
+            public_key: key.public().into_protobuf_encoding(),
+            destination: destination.to_base58(),
+            topic: topic.to_owned(),
+            expires_ms,
+            priority,
+            data,
+            signature: Vec::new(),
+        };
+        bundle.signature = key
+            .sign(&bundle.signed_bytes())
+            .context("Signing bundle")?;
+        Ok(bundle)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        Layout::new(Domain::Bundle)
+            .topic(&self.topic)
+            .timestamp(self.expires_ms)
+            .header("public_key", &self.public_key[..])
+            .header("destination", self.destination.as_bytes())
+            .header("priority", vec![self.priority])
+            .payload(&self.data)
+            .to_bytes()
+    }
+
+    pub fn id(&self) -> BundleId {
+        BundleId(Sha256::digest(&self.signed_bytes()).to_vec())
+    }
+
+    /// The source, if the signature is valid.
+    pub fn verify(&self) -> Option<PeerId> {
+        let public_key = PublicKey::from_protobuf_encoding(&self.public_key).ok()?;
+        if public_key.verify(&self.signed_bytes(), &self.signature) {
+            Some(PeerId::from(public_key))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_for(&self, peer_id: &PeerId) -> bool {
+        self.destination == peer_id.to_base58()
+    }
+
+    pub const fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_ms <= now_ms
+    }
+
+    pub fn is_report(&self) -> bool {
+        self.topic == REPORT_TOPIC
+    }
+
+    /// Bytes taken up in the store, roughly.
+    pub fn size(&self) -> usize {
+        self.public_key.len()
+            + self.destination.len()
+            + self.topic.len()
+            + self.data.len()
+            + self.signature.len()
+    }
+
+    fn rank(&self) -> Rank {
+        Rank {
+            priority:   self.priority,
+            expires_ms: self.expires_ms,
+            size:       self.size(),
+        }
+    }
+}
+
+/// What eviction policies look at.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Rank {
+    pub priority:   u8,
+    pub expires_ms: u64,
+    pub size:       usize,
+}
+
+/// A bundle evicted from a full store.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Evicted {
+    pub id:          BundleId,
+    /// Base58 peer id of the destination.
+    pub destination: String,
+    pub topic:       String,
+    pub priority:    u8,
+    pub size:        usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Status {
+    /// The reporter took custody to carry the bundle further.
+    Custody,
+    /// The bundle reached the reporter, its destination.
+    Delivered,
+}
+
+/// What became of a bundle, sent back to its source in a bundle signed by
+/// the reporter.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub bundle: BundleId,
+    pub status: Status,
+    pub at_ms:  u64,
+}
+
+impl Report {
+    /// A bundle carrying the report to `source`, expiring with the bundle
+    /// reported on and of the same priority.
+    pub fn to_bundle(
+        &self,
+        key: &Keypair,
+        source: &PeerId,
+        expires_ms: u64,
+        priority: u8,
+    ) -> Result<Bundle> {
+        let data = serde_cbor::to_vec(self)?;
+        Bundle::expiring(key, source, REPORT_TOPIC, data, expires_ms, priority)
+    }
+}
+
+/// A report received by the source of a bundle.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Hop {
+    /// Base58 peer id of the reporter.
+    pub peer:   String,
+    pub status: Status,
+    /// The reporter's wall clock.
+    pub at_ms:  u64,
+}
+
+/// The reports received on one of our bundles.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Trace {
+    pub expires_ms: u64,
+    /// In the order received.
+    pub hops:       Vec<Hop>,
+}
+
+impl Trace {
+    pub fn is_delivered(&self) -> bool {
+        self.hops.iter().any(|hop| hop.status == Status::Delivered)
+    }
+}
+
+/// A bundle offered to a peer.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub id:          BundleId,
+    pub destination: String,
+    pub rank:        Rank,
+}
+
+/// What became of a bundle received from a peer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Receipt {
+    /// It is for us, from this source.
+    Deliver(PeerId),
+    /// We took custody to carry it further, from this source.
+    Carry(PeerId),
+    /// We already had or delivered it.
+    Duplicate,
+    /// It is invalid or expired, or the store is full of bundles we rather
+    /// keep.
+    Refuse(&'static str),
+}
+
+impl Receipt {
+    /// Whether the sender may count the bundle as handed over.
+    pub const fn is_custody(&self) -> bool {
+        !matches!(self, Self::Refuse(_))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Carried {
+    bundle:  Bundle,
+    copies:  u32,
+    /// Peers that have the bundle, or were offered it.
+    offered: HashSet<PeerId>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    bundles:   Vec<(Bundle, u32)>,
+    delivered: Vec<(BundleId, u64)>,
+    #[serde(default)]
+    traces:    Vec<(BundleId, Trace)>,
+}
+
+/// The bundles carried by a node.
+#[derive(Debug)]
+pub struct Store {
+    slot:      Option<Slot>,
+    config:    Config,
+    bundles:   BTreeMap<BundleId, Carried>,
+    /// Bundles delivered to us, until they expire.
+    delivered: BTreeMap<BundleId, u64>,
+    /// Reports on the bundles we sent, until they expire.
+    traces:    BTreeMap<BundleId, Trace>,
+    /// Bytes taken up by the bundles.
+    size:      usize,
+    /// Evicted since the last [`Store::take_evicted`].
+    evicted:   Vec<Evicted>,
+    changed:   bool,
+}
+
+impl Store {
+    pub fn new(config: Config) -> Self {
+        Self {
+            slot: None,
+            config,
+            bundles: BTreeMap::new(),
+            delivered: BTreeMap::new(),
+            traces: BTreeMap::new(),
+            size: 0,
+            evicted: Vec::new(),
+            changed: false,
+        }
+    }
+
+    /// Load the bundles kept in `slot`, or start empty if there are none.
+    /// [`Store::flush`] writes changes back to `slot`.
+    pub fn load(slot: Slot, config: Config) -> Result<Self> {
+        let saved = match slot.read()? {
+            Some(bytes) => serde_cbor::from_slice(&bytes)
+                .with_context(|| format!("Parsing bundles from {}", slot))?,
+            None => Saved::default(),
+        };
+        let mut store = Self::new(config);
+        store.slot = Some(slot);
+        for (bundle, copies) in saved.bundles {
+            store.carry(bundle, copies, None);
+        }
+        store.delivered = saved.delivered.into_iter().collect();
+        store.traces = saved.traces.into_iter().collect();
+        Ok(store)
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.bundles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bundles.is_empty()
+    }
+
+    /// Bytes taken up by the bundles.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    fn carry(&mut self, bundle: Bundle, copies: u32, from: Option<PeerId>) {
+        self.size += bundle.size();
+        self.bundles.insert(bundle.id(), Carried {
+            bundle,
+            copies: copies.max(1),
+            offered: from.into_iter().collect(),
+        });
+        self.changed = true;
+    }
+
+    /// The bundles to evict to make room for a bundle of `rank`, or `None`
+    /// if it does not fit, or would be evicted first itself.
+    fn victims(&self, rank: &Rank) -> Option<Vec<BundleId>> {
+        let quota = self.config.quota.as_u64() as usize;
+        let policy = self.config.evict;
+        let mut candidates = self
+            .bundles
+            .iter()
+            .map(|(id, carried)| (id, carried.bundle.rank()))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| policy.compare(b, a));
+        let (mut count, mut size) = (self.bundles.len(), self.size);
+        let mut victims = Vec::new();
+        for (id, candidate) in candidates {
+            if count < self.config.capacity && size + rank.size <= quota {
+                break;
+            }
+            if policy.compare(rank, &candidate) != Ordering::Less {
+                return None;
+            }
+            count -= 1;
+            size -= candidate.size;
+            victims.push(id.clone());
+        }
+        if count < self.config.capacity && size + rank.size <= quota {
+            Some(victims)
+        } else {
+            None
+        }
+    }
+
+    /// Evict bundles to make room for `bundle`. Returns false if there is
+    /// no room for it.
+    fn make_room(&mut self, bundle: &Bundle) -> bool {
+        let victims = match self.victims(&bundle.rank()) {
+            Some(victims) => victims,
+            None => return false,
+        };
+        for id in victims {
+            if let Some(carried) = self.remove(&id) {
+                self.evicted.push(Evicted {
+                    id,
+                    destination: carried.bundle.destination.clone(),
+                    topic: carried.bundle.topic.clone(),
+                    priority: carried.bundle.priority,
+                    size: carried.bundle.size(),
+                });
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, id: &BundleId) -> Option<Carried> {
+        let carried = self.bundles.remove(id)?;
+        self.size -= carried.bundle.size();
+        self.changed = true;
+        Some(carried)
+    }
+
+    /// The bundles evicted since the last call.
+    pub fn take_evicted(&mut self) -> Vec<Evicted> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Store our own bundle, with all its copies, evicting others if the
+    /// store is full.
+    pub fn send(&mut self, bundle: Bundle) -> Result<BundleId> {
+        ensure!(self.make_room(&bundle), "Bundle store full");
+        let id = bundle.id();
+        if !bundle.is_report() {
+            self.traces.insert(id.clone(), Trace {
+                expires_ms: bundle.expires_ms,
+                hops:       Vec::new(),
+            });
+        }
+        self.carry(bundle, self.config.copies, None);
+        Ok(id)
+    }
+
+    /// Add the `report` of `reporter` to the trace of our bundle. Returns
+    /// false if the bundle is not ours or the report is known.
+    pub fn record(&mut self, reporter: &PeerId, report: &Report) -> bool {
+        let trace = match self.traces.get_mut(&report.bundle) {
+            Some(trace) => trace,
+            None => return false,
+        };
+        let peer = reporter.to_base58();
+        if trace
+            .hops
+            .iter()
+            .any(|hop| hop.peer == peer && hop.status == report.status)
+        {
+            return false;
+        }
+        trace.hops.push(Hop {
+            peer,
+            status: report.status,
+            at_ms: report.at_ms,
+        });
+        self.changed = true;
+        true
+    }
+
+    /// The reports on our bundle `id`.
+    pub fn trace(&self, id: &BundleId) -> Option<&Trace> {
+        self.traces.get(id)
+    }
+
+    /// Drop the bundles that expired at `now_ms`. Returns how many bundles
+    /// were dropped.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        let before = self.bundles.len();
+        self.bundles
+            .retain(|_, carried| !carried.bundle.is_expired(now_ms));
+        self.size = self
+            .bundles
+            .values()
+            .map(|carried| carried.bundle.size())
+            .sum();
+        let delivered = self.delivered.len();
+        self.delivered.retain(|_, expires_ms| *expires_ms > now_ms);
+        let traces = self.traces.len();
+        self.traces.retain(|_, trace| trace.expires_ms > now_ms);
+        let dropped = before - self.bundles.len();
+        self.changed |=
+            dropped > 0 || delivered != self.delivered.len() || traces != self.traces.len();
+        dropped
+    }
+
+    /// The bundles to offer to `peer_id`, at most `limit`. They are not
+    /// offered to the peer again until [`Store::reoffer`].
+    pub fn offer(&mut self, peer_id: &PeerId, limit: usize) -> Vec<Summary> {
+        let mut offer = Vec::new();
+        for (id, carried) in &mut self.bundles {
+            if offer.len() >= limit {
+                break;
+            }
+            if carried.offered.contains(peer_id)
+                || (carried.copies < 2 && !carried.bundle.is_for(peer_id))
+            {
+                continue;
+            }
+            carried.offered.insert(peer_id.clone());
+            offer.push(Summary {
+                id:          id.clone(),
+                destination: carried.bundle.destination.clone(),
+                rank:        carried.bundle.rank(),
+            });
+        }
+        offer
+    }
+
+    /// Offer `ids` to `peer_id` again, after an exchange failed.
+    pub fn reoffer(&mut self, peer_id: &PeerId, ids: &[BundleId]) {
+        for id in ids {
+            if let Some(carried) = self.bundles.get_mut(id) {
+                carried.offered.remove(peer_id);
+            }
+        }
+    }
+
+    /// The offered bundles we take, as `local`. Whether there is room for
+    /// them is decided again when they arrive.
+    pub fn wanted(&self, offer: &[Summary], local: &PeerId) -> Vec<BundleId> {
+        let local = local.to_base58();
+        offer
+            .iter()
+            .filter(|summary| {
+                !self.bundles.contains_key(&summary.id)
+                    && !self.delivered.contains_key(&summary.id)
+                    && (summary.destination == local || self.victims(&summary.rank).is_some())
+            })
+            .map(|summary| summary.id.clone())
+            .collect()
+    }
+
+    /// The bundle `id` to hand to `peer_id`, with the copies it gets.
+    pub fn transfer(&self, peer_id: &PeerId, id: &BundleId) -> Option<(Bundle, u32)> {
+        let carried = self.bundles.get(id)?;
+        let copies = if carried.bundle.is_for(peer_id) {
+            carried.copies
+        } else {
+            carried.copies / 2
+        };
+        if copies == 0 {
+            return None;
+        }
+        Some((carried.bundle.clone(), copies))
+    }
+
+    /// `peer_id` took custody of `copies` of bundle `id`.
+    pub fn handed_over(&mut self, peer_id: &PeerId, id: &BundleId, copies: u32) {
+        let carried = match self.bundles.get_mut(id) {
+            Some(carried) => carried,
+            None => return,
+        };
+        carried.copies = carried.copies.saturating_sub(copies);
+        if carried.copies == 0 || carried.bundle.is_for(peer_id) {
+            self.remove(id);
+        }
+        self.changed = true;
+    }
+
+    /// Take `copies` of `bundle`, sent by `from`, as `local`.
+    pub fn receive(
+        &mut self,
+        from: &PeerId,
+        bundle: Bundle,
+        copies: u32,
+        local: &PeerId,
+        now_ms: u64,
+    ) -> Receipt {
+        let source = match bundle.verify() {
+            Some(source) => source,
+            None => return Receipt::Refuse("bad signature"),
+        };
+        if bundle.is_expired(now_ms) {
+            return Receipt::Refuse("expired");
+        }
+        let id = bundle.id();
+        if self.bundles.contains_key(&id) || self.delivered.contains_key(&id) {
+            return Receipt::Duplicate;
+        }
+        if bundle.is_for(local) {
+            self.delivered.insert(id, bundle.expires_ms);
+            self.changed = true;
+            return Receipt::Deliver(source);
+        }
+        if !self.make_room(&bundle) {
+            return Receipt::Refuse("store full");
+        }
+        self.carry(bundle, copies, Some(from.clone()));
+        Receipt::Carry(source)
+    }
+
+    /// Write the store, if it changed since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        let slot = match &self.slot {
+            Some(slot) if self.changed => slot,
+            _ => return Ok(()),
+        };
+        let saved = Saved {
+            bundles:   self
+                .bundles
+                .values()
+                .map(|carried| (carried.bundle.clone(), carried.copies))
+                .collect(),
+            delivered: self
+                .delivered
+                .iter()
+                .map(|(id, expires_ms)| (id.clone(), *expires_ms))
+                .collect(),
+            traces:    self
+                .traces
+                .iter()
+                .map(|(id, trace)| (id.clone(), trace.clone()))
+                .collect(),
+        };
+        slot.write(&serde_cbor::to_vec(&saved)?)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_sprays_and_delivers_bundles() {
+        let config: Config = "capacity=2 lifetime=1h copies=4".parse().unwrap();
+        assert!("copies=0".parse::<Config>().is_err());
+        let keys = (0..3).map(|_| Keypair::generate_ed25519()).collect::<Vec<_>>();
+        let peers = keys
+            .iter()
+            .map(|key| PeerId::from(key.public()))
+            .collect::<Vec<_>>();
+        let (source, carrier, destination) = (&peers[0], &peers[1], &peers[2]);
+        let now = now_ms();
+
+        let bundle =
+            Bundle::new(&keys[0], destination, "news", b"hello".to_vec(), config.lifetime, 0)
+                .unwrap();
+        let mut forged = bundle.clone();
+        forged.data = b"forged".to_vec();
+        assert_eq!(bundle.verify().as_ref(), Some(source));
+        assert_eq!(forged.verify(), None);
+
+        let mut sender = Store::new(config.clone());
+        let id = sender.send(bundle).unwrap();
+        let mut carrying = Store::new(config.clone());
+        let offer = sender.offer(carrier, 10);
+        assert_eq!(sender.offer(carrier, 10), vec![]);
+        assert_eq!(carrying.wanted(&offer, carrier), vec![id.clone()]);
+
+        // The carrier gets half of the copies
+        let (bundle, copies) = sender.transfer(carrier, &id).unwrap();
+        assert_eq!(copies, 2);
+        let receipt = carrying.receive(source, bundle, copies, carrier, now);
+        assert_eq!(receipt, Receipt::Carry(source.clone()));
+        sender.handed_over(carrier, &id, copies);
+        assert_eq!(sender.bundles[&id].copies, 2);
+        assert_eq!(carrying.offer(source, 10), vec![]);
+        assert_eq!(
+            carrying.receive(source, forged, 1, carrier, now),
+            Receipt::Refuse("bad signature")
+        );
+
+        // The destination gets all copies, once
+        let mut receiving = Store::new(config);
+        let offer = carrying.offer(destination, 10);
+        assert_eq!(receiving.wanted(&offer, destination), vec![id.clone()]);
+        let (bundle, copies) = carrying.transfer(destination, &id).unwrap();
+        assert_eq!(copies, 2);
+        let receipt = receiving.receive(carrier, bundle.clone(), copies, destination, now);
+        assert_eq!(receipt, Receipt::Deliver(source.clone()));
+        carrying.handed_over(destination, &id, copies);
+        assert_eq!(carrying.len(), 0);
+        assert_eq!(receiving.len(), 0);
+        assert_eq!(
+            receiving.receive(source, bundle, 2, destination, now),
+            Receipt::Duplicate
+        );
+        assert_eq!(receiving.wanted(&offer, destination), vec![]);
+
+        // Everyone drops expired bundles
+        assert_eq!(sender.expire(now + 2 * 60 * 60 * 1000), 1);
+        assert_eq!(receiving.expire(now + 2 * 60 * 60 * 1000), 0);
+        assert!(receiving.delivered.is_empty());
+    }
+
+    #[test]
+    fn test_traces_reports() {
+        let config = Config::default();
+        let keys = (0..2).map(|_| Keypair::generate_ed25519()).collect::<Vec<_>>();
+        let peers = keys.iter().map(|key| PeerId::from(key.public())).collect::<Vec<_>>();
+        let (source, reporter) = (&peers[0], &peers[1]);
+        let now = now_ms();
+
+        let mut store = Store::new(config.clone());
+        let bundle = Bundle::new(&keys[0], reporter, "news", vec![], config.lifetime, 0).unwrap();
+        let expires_ms = bundle.expires_ms;
+        let id = store.send(bundle).unwrap();
+        assert_eq!(store.trace(&id), Some(&Trace {
+            expires_ms,
+            hops: vec![],
+        }));
+
+        let report = Report {
+            bundle: id.clone(),
+            status: Status::Delivered,
+            at_ms:  now,
+        };
+        let bundle = report.to_bundle(&keys[1], source, expires_ms, 0).unwrap();
+        assert!(bundle.is_report());
+        assert_eq!(
+            store.receive(reporter, bundle, 1, source, now),
+            Receipt::Deliver(reporter.clone())
+        );
+        assert!(store.record(reporter, &report));
+        assert!(!store.record(reporter, &report));
+        assert!(store.trace(&id).unwrap().is_delivered());
+        assert!(!store.record(reporter, &Report {
+            bundle: BundleId(vec![]),
+            ..report
+        }));
+
+        // Reports are not traced themselves, and traces expire
+        assert_eq!(store.traces.len(), 1);
+        store.expire(expires_ms);
+        assert_eq!(store.trace(&id), None);
+    }
+
+    #[test]
+    fn test_evicts_by_policy() {
+        let key = Keypair::generate_ed25519();
+        let (source, destination) = (PeerId::from(key.public()), PeerId::random());
+        let bundle = |data: usize, expires_ms: u64, priority: u8| {
+            Bundle::expiring(&key, &destination, "news", vec![0; data], expires_ms, priority)
+                .unwrap()
+        };
+        let now = now_ms();
+        let (small, large) = (bundle(10, now + 3000, 2), bundle(2000, now + 2000, 1));
+        let urgent = bundle(10, now + 1000, 3);
+        let quota = small.size() + large.size();
+        let store = |evict: &str| {
+            let mut store = Store::new(format!("quota={} evict={}", quota, evict).parse().unwrap());
+            store.send(small.clone()).unwrap();
+            store.send(large.clone()).unwrap();
+            store
+        };
+
+        // Evicts the lowest priority, and refuses bundles evicted first
+        let mut by_priority = store("priority");
+        let id = by_priority.send(urgent.clone()).unwrap();
+        let evicted = by_priority.take_evicted();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id, large.id());
+        assert_eq!(by_priority.size(), small.size() + urgent.size());
+        assert_eq!(
+            by_priority.receive(&source, bundle(2000, now + 4000, 0), 1, &source, now),
+            Receipt::Refuse("store full")
+        );
+        assert!(by_priority.bundles.contains_key(&id));
+
+        // Evicts the nearest expiry
+        let mut by_expiry = store("expiry");
+        assert!(by_expiry.send(urgent.clone()).is_err());
+        by_expiry.send(bundle(10, now + 5000, 0)).unwrap();
+        assert_eq!(by_expiry.take_evicted()[0].id, large.id());
+
+        // Evicts the largest
+        let mut by_size = store("largest");
+        by_size.send(urgent).unwrap();
+        assert_eq!(by_size.take_evicted()[0].id, large.id());
+        assert!(by_size.take_evicted().is_empty());
+        assert!("evict=random".parse::<Config>().is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! One connection per peer.
+//!
+//! Two nodes that dial each other at the same time, as after both discover
+//! the other over mDNS, end up with two connections. With the default
+//! `--duplicate-connections deterministic` [`Policy`], both keep the one
+//! dialed by the peer with the lower peer id and close the other, so they
+//! agree on which survives without exchanging anything. Among several
+//! connections dialed by the same side, the oldest is kept. Each closed
+//! connection emits [`Event::DuplicateClosed`]. `keep` leaves all
+//! connections open.
+//!
+//! [`Event::DuplicateClosed`]: crate::node::Event::DuplicateClosed
+
+use crate::prelude::*;
+use anyhow::bail;
+use libp2p::{core::Endpoint, PeerId};
+use std::str::FromStr;
+
+/// What to do with a second connection to a peer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Policy {
+    /// Keep every connection.
+    Keep,
+    /// Keep the connection dialed by the lower peer id.
+    #[default]
+    Deterministic,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "keep" => Self::Keep,
+            "deterministic" => Self::Deterministic,
+            _ => bail!("Unknown duplicate connection policy {}, expected keep or deterministic", s),
+        })
+    }
+}
+
+impl Policy {
+    /// Which of the `connections` between `local` and `remote`, oldest
+    /// first, to close. Each is `Endpoint::Dialer` if we dialed it.
+    pub fn duplicates<T: Copy>(
+        self,
+        local: &PeerId,
+        remote: &PeerId,
+        connections: &[(T, Endpoint)],
+    ) -> Vec<T> {
+        if self == Self::Keep || connections.len() < 2 {
+            return Vec::new();
+        }
+        let preferred = if local < remote {
+            Endpoint::Dialer
+        } else {
+            Endpoint::Listener
+        };
+        let kept = connections
+            .iter()
+            .position(|(_, endpoint)| *endpoint == preferred)
+            .unwrap_or(0);
+        connections
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != kept)
+            .map(|(_, (id, _))| *id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_both_sides_keep_the_same_connection() {
+        let (mut a, mut b) = (PeerId::random(), PeerId::random());
+        if a > b {
+            std::mem::swap(&mut a, &mut b);
+        }
+        let policy = Policy::Deterministic;
+        // Connection 1 was dialed by b, connection 2 by a
+        let seen_by_a = [(1, Endpoint::Listener), (2, Endpoint::Dialer)];
+        let seen_by_b = [(2, Endpoint::Listener), (1, Endpoint::Dialer)];
+        assert_eq!(policy.duplicates(&a, &b, &seen_by_a), vec![1]);
+        assert_eq!(policy.duplicates(&b, &a, &seen_by_b), vec![1]);
+
+        let dialed_twice = [(1, Endpoint::Listener), (2, Endpoint::Listener)];
+        assert_eq!(policy.duplicates(&a, &b, &dialed_twice), vec![2]);
+        assert_eq!(policy.duplicates(&a, &b, &seen_by_a[..1]), Vec::<i32>::new());
+        assert_eq!(Policy::Keep.duplicates(&a, &b, &seen_by_a), Vec::<i32>::new());
+        assert_eq!("keep".parse::<Policy>().unwrap(), Policy::Keep);
+    }
+}
@@ -0,0 +1,289 @@
+//! Leader election among the members of a group.
+//!
+//! Members of a group periodically publish a [`Heartbeat`] on the group topic
+//! containing their vote. Every member votes for the highest `PeerId` among
+//! the members it considers alive (bully algorithm), where a member is alive
+//! if we received a heartbeat from it within the lease duration.
+//!
+//! The group is the fixed set of [`ElectionConfig::members`]; heartbeats of
+//! other peers on the topic are ignored. A candidate is leader once the votes
+//! of more than half the group are for it, so at most one side of a network
+//! partition can elect a leader, preventing split brain. A leader that loses
+//! its quorum steps down when the votes expire.
+
+use crate::prelude::*;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug)]
+pub struct ElectionConfig {
+    /// How often to publish our heartbeat.
+    pub heartbeat_interval: Duration,
+
+    /// How long a heartbeat keeps a member alive.
+    pub lease: Duration,
+
+    /// The peers of the group, which may include us. We always count as a
+    /// member.
+    pub members: Vec<PeerId>,
+}
+
+impl ElectionConfig {
+    /// Elect a leader among `members`, with the default heartbeat interval
+    /// and lease.
+    pub fn new(members: Vec<PeerId>) -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(2),
+            lease: Duration::from_secs(7),
+            members,
+        }
+    }
+}
+
+/// Message published by group members.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub group: String,
+    pub vote:  String,
+}
+
+/// Emitted when the leader of a group changes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LeadershipChange {
+    pub group:     String,
+    pub leader:    Option<PeerId>,
+    pub is_leader: bool,
+    pub term:      u64,
+}
+
+#[derive(Clone, Debug)]
+struct Member {
+    last_seen: Instant,
+    vote:      PeerId,
+}
+
+#[derive(Debug)]
+pub struct Election {
+    group:          String,
+    local_peer_id:  PeerId,
+    config:         ElectionConfig,
+    /// The group, including us.
+    group_members:  HashSet<PeerId>,
+    /// Votes required to become leader, more than half the group.
+    quorum:         usize,
+    members:        HashMap<PeerId, Member>,
+    leader:         Option<PeerId>,
+    term:           u64,
+    next_heartbeat: Instant,
+}
+
+/// Topic on which the heartbeats for `group` are published.
+pub fn topic(group: &str) -> String {
+    format!("/mesh-rs/election/{}/version/1", group)
+}
+
+impl Election {
+    pub fn new(group: String, local_peer_id: PeerId, config: ElectionConfig, now: Instant) -> Self {
+        let group_members = config
+            .members
+            .iter()
+            .cloned()
+            .chain(std::iter::once(local_peer_id.clone()))
+            .collect::<HashSet<_>>();
+        let quorum = group_members.len() / 2 + 1;
+        Self {
+            group,
+            local_peer_id,
+            config,
+            group_members,
+            quorum,
+            members: HashMap::new(),
+            leader: None,
+            term: 0,
+            next_heartbeat: now,
+        }
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    pub const fn leader(&self) -> Option<&PeerId> {
+        self.leader.as_ref()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.as_ref() == Some(&self.local_peer_id)
+    }
+
+    /// Record a heartbeat received from `peer_id`.
+    pub fn receive(&mut self, peer_id: PeerId, heartbeat: &Heartbeat, now: Instant) {
+        if heartbeat.group != self.group || peer_id == self.local_peer_id {
+            return;
+        }
+        if !self.group_members.contains(&peer_id) {
+            debug!("Ignoring heartbeat of non-member {}", peer_id);
+            return;
+        }
+        let vote = match heartbeat.vote.parse() {
+            Ok(vote) if self.group_members.contains(&vote) => vote,
+            _ => {
+                warn!("Invalid vote in heartbeat from {}", peer_id);
+                return;
+            }
+        };
+        self.members.insert(peer_id, Member {
+            last_seen: now,
+            vote,
+        });
+    }
+
+    /// Advance time. Returns a heartbeat to publish if one is due, and the
+    /// leadership change if the leader changed.
+    pub fn tick(&mut self, now: Instant) -> (Option<Heartbeat>, Option<LeadershipChange>) {
+        // Expire members whose lease ran out
+        let lease = self.config.lease;
+        self.members
+            .retain(|_, member| now.duration_since(member.last_seen) < lease);
+
+        let change = self.update_leader();
+
+        let heartbeat = if now >= self.next_heartbeat {
+            self.next_heartbeat = now + self.config.heartbeat_interval;
+            Some(Heartbeat {
+                group: self.group.clone(),
+                vote:  self.vote().to_base58(),
+            })
+        } else {
+            None
+        };
+        (heartbeat, change)
+    }
+
+    /// The live member we vote for: the highest `PeerId`.
+    fn vote(&self) -> PeerId {
+        self.members
+            .keys()
+            .chain(std::iter::once(&self.local_peer_id))
+            .max_by(|a, b| a.as_bytes().cmp(b.as_bytes()))
+            .cloned()
+            .unwrap_or_else(|| self.local_peer_id.clone())
+    }
+
+    fn update_leader(&mut self) -> Option<LeadershipChange> {
+        // Count votes, including our own
+        let mut votes: HashMap<PeerId, usize> = HashMap::new();
+        for member in self.members.values() {
+            *votes.entry(member.vote.clone()).or_default() += 1;
+        }
+        *votes.entry(self.vote()).or_default() += 1;
+
+        let leader = votes
+            .into_iter()
+            .filter(|(_, count)| *count >= self.quorum)
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.as_bytes().cmp(b.0.as_bytes())))
+            .map(|(peer_id, _)| peer_id);
+
+        if leader == self.leader {
+            return None;
+        }
+        self.leader = leader;
+        self.term += 1;
+        Some(LeadershipChange {
+            group:     self.group.clone(),
+            leader:    self.leader.clone(),
+            is_leader: self.is_leader(),
+            term:      self.term,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn sorted_peers(n: usize) -> Vec<PeerId> {
+        let mut peers = (0..n).map(|_| PeerId::random()).collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        peers
+    }
+
+    fn config(members: &[PeerId]) -> ElectionConfig {
+        ElectionConfig::new(members.to_vec())
+    }
+
+    fn heartbeat(vote: &PeerId) -> Heartbeat {
+        Heartbeat {
+            group: "g".into(),
+            vote:  vote.to_base58(),
+        }
+    }
+
+    #[test]
+    fn test_single_member_elects_itself() {
+        let now = Instant::now();
+        let local = PeerId::random();
+        let mut election = Election::new("g".into(), local.clone(), config(&[]), now);
+        let (heartbeat, change) = election.tick(now);
+        assert!(heartbeat.is_some());
+        assert_eq!(change.unwrap().leader, Some(local));
+        assert!(election.is_leader());
+    }
+
+    #[test]
+    fn test_highest_peer_wins_with_quorum() {
+        let now = Instant::now();
+        let peers = sorted_peers(3);
+        let highest = peers[2].clone();
+        let mut election = Election::new("g".into(), peers[0].clone(), config(&peers), now);
+        election.receive(highest.clone(), &heartbeat(&highest), now);
+        election.tick(now);
+        assert_eq!(election.leader(), Some(&highest));
+        assert!(!election.is_leader());
+    }
+
+    #[test]
+    fn test_no_leader_without_quorum() {
+        let now = Instant::now();
+        let peers = sorted_peers(2);
+        let mut election = Election::new("g".into(), peers[0].clone(), config(&peers), now);
+        let (_, change) = election.tick(now);
+        assert!(change.is_none());
+        assert_eq!(election.leader(), None);
+
+        // Peers outside the group neither vote nor count towards it
+        let outsider = PeerId::random();
+        election.receive(outsider.clone(), &heartbeat(&outsider), now);
+        election.receive(peers[1].clone(), &heartbeat(&outsider), now);
+        let (_, change) = election.tick(now);
+        assert!(change.is_none());
+        assert_eq!(election.leader(), None);
+    }
+
+    #[test]
+    fn test_leader_expires_after_lease() {
+        let now = Instant::now();
+        let peers = sorted_peers(3);
+        let mut election = Election::new("g".into(), peers[0].clone(), config(&peers), now);
+        election.receive(peers[1].clone(), &heartbeat(&peers[2]), now);
+        election.receive(peers[2].clone(), &heartbeat(&peers[2]), now);
+        election.tick(now);
+        assert_eq!(election.leader(), Some(&peers[2]));
+
+        let later = now + config(&peers).lease;
+        election.receive(peers[1].clone(), &heartbeat(&peers[1]), later);
+        let (_, change) = election.tick(later);
+        let change = change.unwrap();
+        assert_eq!(change.leader, Some(peers[1].clone()));
+        assert!(!change.is_leader);
+        assert_eq!(change.term, 2);
+
+        // Alone, we are not a majority of three
+        let (_, change) = election.tick(later + config(&peers).lease);
+        assert_eq!(change.unwrap().leader, None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Message TTLs and hop limits.
+//!
+//! `--message-ttl "ttl=5m hops=4"` puts an expiry and a hop limit in the
+//! envelope of every message the node publishes, both signed with the rest
+//! of the envelope. Receivers drop messages past their expiry, by their own
+//! wall clock, instead of delivering them late, however long gossip or a
+//! chain of relays took. A bridge or relay republishing a message, see
+//! [`NodeHandle::republish`], keeps the expiry of the origin and passes on
+//! one hop less. It refuses to republish a message that expired or has no
+//! hops left, so messages do not circle between bridged meshes forever.
+//!
+//! Without `ttl` or `hops` messages carry neither and never expire, and
+//! peers that predate the fields deliver messages that have them as before.
+//! Gossipsub forwards a message within a mesh before the node looks into
+//! its envelope, so the limits stop delivery and republishing but not the
+//! gossip itself, which its seen cache bounds. Dropped messages are counted
+//! in the StatsD counters `messages.expired` and `messages.hop_limited` and
+//! the Prometheus counters `mesh_messages_expired_total` and
+//! `mesh_messages_hop_limited_total`.
+//!
+//! [`NodeHandle::republish`]: crate::node::NodeHandle::republish
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub struct Config {
+    /// How long our messages are delivered after they were published,
+    /// `None` for ever.
+    pub ttl:  Option<Duration>,
+    /// How many relays may republish our messages, `None` for any number.
+    pub hops: Option<u8>,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "ttl" if value == "never" => config.ttl = None,
+                "ttl" => {
+                    config.ttl = Some(
+                        humantime::parse_duration(value)
+                            .with_context(|| format!("Invalid ttl {}", value))?,
+                    );
+                }
+                "hops" if value == "any" => config.hops = None,
+                "hops" => {
+                    config.hops = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid hops {}", value))?,
+                    );
+                }
+                _ => bail!("Unknown message TTL option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.ttl.map_or(true, |ttl| ttl >= Duration::from_secs(1)),
+            "Message TTL must be at least a second"
+        );
+        Ok(())
+    }
+
+    /// The limits of a message published at `now`.
+    pub fn limits(&self, now: SystemTime) -> Limits {
+        Limits {
+            expires: self.ttl.map(|ttl| epoch_ms(now + ttl)),
+            hops:    self.hops,
+        }
+    }
+}
+
+pub fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Why a message was dropped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+pub enum Exceeded {
+    #[error("Message expired")]
+    Expired,
+    #[error("Message has no hops left")]
+    HopLimit,
+}
+
+/// The expiry and hop limit of a message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    /// Milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+    /// Relays that may still republish the message.
+    pub hops:    Option<u8>,
+}
+
+impl Limits {
+    /// Whether the message is past its expiry at `now_ms`.
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires.map_or(false, |expires| now_ms >= expires)
+    }
+
+    /// The limits to republish the message with at `now_ms`.
+    pub fn forward(&self, now_ms: u64) -> Result<Self, Exceeded> {
+        if self.is_expired(now_ms) {
+            return Err(Exceeded::Expired);
+        }
+        let hops = match self.hops {
+            Some(0) => return Err(Exceeded::HopLimit),
+            hops => hops.map(|hops| hops - 1),
+        };
+        Ok(Self {
+            expires: self.expires,
+            hops,
+        })
+    }
+}
+
+/// Messages dropped so far.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Drops {
+    pub expired:     u64,
+    pub hop_limited: u64,
+}
+
+impl Drops {
+    pub fn count(&mut self, exceeded: Exceeded) {
+        match exceeded {
+            Exceeded::Expired => self.expired += 1,
+            Exceeded::HopLimit => self.hop_limited += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "ttl=5m hops=4".parse().unwrap();
+        assert_eq!(config.ttl, Some(Duration::from_secs(300)));
+        assert_eq!(config.hops, Some(4));
+        assert_eq!("ttl=never hops=any".parse::<Config>().unwrap(), Config::default());
+        assert!("ttl=10ms".parse::<Config>().is_err());
+        assert!("hops=300".parse::<Config>().is_err());
+        assert!("age=5m".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_forwards_within_limits() {
+        let now = SystemTime::now();
+        let now_ms = epoch_ms(now);
+        let limits = "ttl=1m hops=1".parse::<Config>().unwrap().limits(now);
+        assert_eq!(limits.expires, Some(now_ms + 60_000));
+        assert!(!limits.is_expired(now_ms));
+        assert!(limits.is_expired(now_ms + 60_000));
+
+        let forwarded = limits.forward(now_ms).unwrap();
+        assert_eq!(forwarded.hops, Some(0));
+        assert_eq!(forwarded.forward(now_ms), Err(Exceeded::HopLimit));
+        assert_eq!(limits.forward(now_ms + 60_000), Err(Exceeded::Expired));
+        let unlimited = Limits::default();
+        assert_eq!(unlimited.forward(u64::MAX), Ok(unlimited));
+
+        let mut drops = Drops::default();
+        drops.count(Exceeded::Expired);
+        drops.count(Exceeded::HopLimit);
+        drops.count(Exceeded::HopLimit);
+        assert_eq!((drops.expired, drops.hop_limited), (1, 2));
+    }
+}
@@ -0,0 +1,275 @@
+//! IPv4 and IPv6.
+//!
+//! Without sockets passed to it, a node listens on all interfaces of both
+//! families, `/ip4/0.0.0.0/tcp/0` and `/ip6/::/tcp/0`, on whatever ports the
+//! OS assigns, and `--listen` adds more addresses of any transport. A host
+//! without IPv6 only gets a warning. Each address a listener binds is
+//! reported as [`Event::ListenAddr`], with the port the OS chose, and
+//! [`NodeHandle::listen_addrs`] returns those bound so far.
+//!
+//! `--address-family "listen=both dial=any"` narrows this down. `listen=ip4`
+//! or `listen=ip6` opens the default listener of that family only. When
+//! dialing, `dial=ip4` or `dial=ip6` refuses addresses of the other family,
+//! failing them as [`Outcome::UnsupportedAddress`], and `dial=prefer-ip6` or
+//! `dial=prefer-ip4` tries the addresses of that family first among those a
+//! peer announces or the address book has for it. Addresses without an IP,
+//! like `/dns/` names, Bluetooth or serial links, belong to neither family
+//! and are always dialed.
+//!
+//! [`Event::ListenAddr`]: crate::node::Event::ListenAddr
+//! [`NodeHandle::listen_addrs`]: crate::node::NodeHandle::listen_addrs
+//! [`Outcome::UnsupportedAddress`]: crate::node::dial::Outcome::UnsupportedAddress
+
+use super::{activation::Stream, options};
+use crate::prelude::*;
+use anyhow::bail;
+use libp2p::{
+    core::transport::{ListenerEvent, TransportError},
+    multiaddr::Protocol,
+    Multiaddr, Transport,
+};
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Family {
+    Ip4,
+    Ip6,
+}
+
+impl Family {
+    /// The family of `address`, if it starts with an IP or a name resolved
+    /// to one family.
+    pub fn of(address: &Multiaddr) -> Option<Self> {
+        match address.iter().next()? {
+            Protocol::Ip4(_) | Protocol::Dns4(_) => Some(Self::Ip4),
+            Protocol::Ip6(_) | Protocol::Dns6(_) => Some(Self::Ip6),
+            _ => None,
+        }
+    }
+
+    /// Listen on all interfaces of the family, on a port the OS assigns.
+    pub fn any_address(self) -> Multiaddr {
+        let ip = match self {
+            Self::Ip4 => Protocol::Ip4(Ipv4Addr::UNSPECIFIED),
+            Self::Ip6 => Protocol::Ip6(Ipv6Addr::UNSPECIFIED),
+        };
+        Multiaddr::empty().with(ip).with(Protocol::Tcp(0))
+    }
+}
+
+impl FromStr for Family {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "ip4" => Self::Ip4,
+            "ip6" => Self::Ip6,
+            _ => bail!("Unknown address family {}, expected ip4 or ip6", s),
+        })
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ip4 => "ip4",
+            Self::Ip6 => "ip6",
+        })
+    }
+}
+
+/// Which addresses to dial.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dial {
+    Any,
+    /// Try the addresses of the family first.
+    Prefer(Family),
+    /// Refuse the addresses of the other family.
+    Only(Family),
+}
+
+impl Default for Dial {
+    fn default() -> Self {
+        Self::Any
+    }
+}
+
+impl FromStr for Dial {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "any" {
+            return Ok(Self::Any);
+        }
+        match s.strip_prefix("prefer-") {
+            Some(family) => Ok(Self::Prefer(family.parse()?)),
+            None => Ok(Self::Only(s.parse()?)),
+        }
+    }
+}
+
+impl Dial {
+    pub fn allows(self, address: &Multiaddr) -> bool {
+        match (self, Family::of(address)) {
+            (Self::Only(only), Some(family)) => family == only,
+            _ => true,
+        }
+    }
+
+    /// Move the addresses of the preferred family to the front, keeping
+    /// their order otherwise.
+    pub fn sort(self, addresses: &mut [Multiaddr]) {
+        if let Self::Prefer(preferred) = self {
+            addresses.sort_by_key(|address| Family::of(address) != Some(preferred));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// Family of the default listeners, `None` for both.
+    pub listen: Option<Family>,
+    pub dial:   Dial,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            dial:   Dial::Any,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "listen" if value == "both" => config.listen = None,
+                "listen" => config.listen = Some(value.parse()?),
+                "dial" => config.dial = value.parse()?,
+                _ => bail!("Unknown address family option {}", key),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// The families to open default listeners of.
+    pub fn listen_families(&self) -> Vec<Family> {
+        match self.listen {
+            Some(family) => vec![family],
+            None => vec![Family::Ip4, Family::Ip6],
+        }
+    }
+}
+
+/// The error of dials refused by [`Families`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Not dialing {0} addresses")]
+pub struct WrongFamily(pub Family);
+
+/// The families to listen on and dial, shared with the transport.
+#[derive(Clone, Default, Debug)]
+pub struct Families(Arc<Mutex<Config>>);
+
+impl Families {
+    pub fn configure(&self, config: Config) {
+        *self.0.lock().unwrap() = config;
+    }
+
+    pub fn config(&self) -> Config {
+        *self.0.lock().unwrap()
+    }
+}
+
+type Refused = future::Ready<io::Result<Stream>>;
+
+/// Refuses to dial the addresses of a family we do not dial and leaves the
+/// others to the transports behind it. It never listens, nor makes a
+/// connection.
+impl Transport for Families {
+    type Dial = Refused;
+    type Error = io::Error;
+    type Listener = stream::Pending<io::Result<ListenerEvent<Refused, io::Error>>>;
+    type ListenerUpgrade = Refused;
+    type Output = Stream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(address))
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if !self.config().dial.allows(&address) {
+            if let Some(family) = Family::of(&address) {
+                return Err(TransportError::Other(io::Error::new(
+                    io::ErrorKind::Other,
+                    WrongFamily(family),
+                )));
+            }
+        }
+        Err(TransportError::MultiaddrNotSupported(address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "listen=ip6 dial=prefer-ip4".parse().unwrap();
+        assert_eq!(config.listen_families(), vec![Family::Ip6]);
+        assert_eq!(config.dial, Dial::Prefer(Family::Ip4));
+        let config: Config = "listen=both dial=ip6".parse().unwrap();
+        assert_eq!(config.listen_families(), vec![Family::Ip4, Family::Ip6]);
+        assert_eq!(config.dial, Dial::Only(Family::Ip6));
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("listen=ipx".parse::<Config>().is_err());
+        assert!("dial=prefer-any".parse::<Config>().is_err());
+        assert!("family=ip4".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_orders_and_refuses_addresses() {
+        let v4: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let v6: Multiaddr = "/ip6/fe80::1/tcp/4001".parse().unwrap();
+        let name: Multiaddr = "/dns/example.com/tcp/4001".parse().unwrap();
+        assert_eq!(Family::of(&v4), Some(Family::Ip4));
+        assert_eq!(Family::of(&name), None);
+        assert_eq!(Family::Ip6.any_address().to_string(), "/ip6/::/tcp/0");
+
+        let mut addresses = vec![v4.clone(), name.clone(), v6.clone()];
+        Dial::Any.sort(&mut addresses);
+        assert_eq!(addresses, vec![v4.clone(), name.clone(), v6.clone()]);
+        Dial::Prefer(Family::Ip6).sort(&mut addresses);
+        assert_eq!(addresses, vec![v6.clone(), v4.clone(), name.clone()]);
+
+        let only = Dial::Only(Family::Ip6);
+        assert!(only.allows(&v6));
+        assert!(only.allows(&name));
+        assert!(!only.allows(&v4));
+        let families = Families::default();
+        families.configure(Config {
+            dial: only,
+            ..Config::default()
+        });
+        assert!(matches!(families.clone().dial(v4), Err(TransportError::Other(_))));
+        assert!(matches!(
+            families.dial(v6),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+    }
+}
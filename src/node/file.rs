@@ -0,0 +1,364 @@
+//! Files sent to one peer in content-addressed chunks.
+//!
+//! `handle.send_file(&peer_id, path)`, or `/send <peer id> <path>` on the
+//! console, offers the peer a [`Manifest`] of the file: its name, size and
+//! the SHA-256 hash of each chunk of [`CHUNK_SIZE`] bytes. The [`FileId`] is
+//! the hash of the manifest. A receiver started with `--files "dir=<path>"`
+//! accepts files up to `max_size` and pulls their chunks, [`WINDOW`] at a
+//! time, into `<dir>/.partial/<file id>`, checking each against its hash.
+//! Once all arrived the file moves to `<dir>/<name>`, numbered if the name
+//! is taken. Nodes without a `dir` refuse files.
+//!
+//! Both sides emit [`Progress`] events as chunks go over. A transfer
+//! interrupted by a lost connection goes on from the missing chunks when
+//! the peer reconnects, and offering the same file again, even after a
+//! restart, only sends the chunks the partial file does not have yet.
+//! Transfers without progress for [`STALL_TIMEOUT`] fail, keeping the
+//! partial file.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// Size of every chunk but the last.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Chunks of a file requested at once.
+pub const WINDOW: usize = 4;
+
+/// Files received at the same time at most, others are refused.
+pub const MAX_INCOMING: usize = 4;
+
+/// A transfer without a chunk for this long fails.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Directory of the files being received, in the download directory.
+pub const PARTIAL_DIR: &str = ".partial";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// Where received files go, none to refuse files.
+    pub dir:      Option<PathBuf>,
+    /// Largest file accepted.
+    pub max_size: ByteUnit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dir:      None,
+            max_size: 256.mebibytes(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "dir" => config.dir = Some(PathBuf::from(value)),
+                "max_size" => {
+                    config.max_size = value
+                        .parse()
+                        .map_err(|err| anyhow!("Invalid max_size {}: {}", value, err))?;
+                }
+                _ => bail!("Unknown file transfer option {}", key),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// SHA-256 hash of a [`Manifest`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct FileId(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+/// What a file is made of.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name:   String,
+    pub size:   u64,
+    /// SHA-256 hash of each chunk.
+    pub chunks: Vec<serde_bytes::ByteBuf>,
+}
+
+fn chunk_count(size: u64) -> usize {
+    ((size + CHUNK_SIZE - 1) / CHUNK_SIZE) as usize
+}
+
+/// Fill `buffer` from `file`, short only at its end.
+fn read_chunk(file: &mut File, buffer: &mut Vec<u8>, len: u64) -> Result<()> {
+    buffer.clear();
+    file.take(len).read_to_end(buffer)?;
+    ensure!(buffer.len() as u64 == len, "File shorter than expected");
+    Ok(())
+}
+
+impl Manifest {
+    /// Hash the file at `path`.
+    pub fn of(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no file name", path.display()))?
+            .to_owned();
+        let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        let size = file.metadata()?.len();
+        let mut chunks = Vec::with_capacity(chunk_count(size));
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE as usize);
+        for index in 0..chunk_count(size) {
+            read_chunk(&mut file, &mut buffer, chunk_len(size, index))
+                .with_context(|| format!("Reading {}", path.display()))?;
+            chunks.push(serde_bytes::ByteBuf::from(Sha256::digest(&buffer).to_vec()));
+        }
+        Ok(Self { name, size, chunks })
+    }
+
+    pub fn id(&self) -> FileId {
+        let mut hasher = Sha256::new();
+        hasher.update(self.name.as_bytes());
+        hasher.update(&self.size.to_be_bytes());
+        for chunk in &self.chunks {
+            hasher.update(chunk);
+        }
+        FileId(hasher.finalize().to_vec())
+    }
+
+    /// Check a manifest from a peer.
+    pub fn validate(&self) -> Result<()> {
+        let path = Path::new(&self.name);
+        ensure!(
+            !self.name.starts_with('.')
+                && path.file_name().and_then(|name| name.to_str()) == Some(self.name.as_str()),
+            "Invalid file name {:?}",
+            self.name
+        );
+        ensure!(chunk_count(self.size) == self.chunks.len(), "Chunks do not match the size");
+        ensure!(self.chunks.iter().all(|chunk| chunk.len() == 32), "Invalid chunk hash");
+        Ok(())
+    }
+
+    pub fn chunk_len(&self, index: usize) -> u64 {
+        chunk_len(self.size, index)
+    }
+}
+
+fn chunk_len(size: u64, index: usize) -> u64 {
+    (size - index as u64 * CHUNK_SIZE).min(CHUNK_SIZE)
+}
+
+/// Chunk `index` of the file of `manifest` at `path`.
+pub fn read(path: &Path, manifest: &Manifest, index: usize) -> Result<Vec<u8>> {
+    ensure!(index < manifest.chunks.len(), "No chunk {}", index);
+    let mut file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    file.seek(SeekFrom::Start(index as u64 * CHUNK_SIZE))?;
+    let mut data = Vec::new();
+    read_chunk(&mut file, &mut data, manifest.chunk_len(index))?;
+    Ok(data)
+}
+
+/// Which way a file goes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum State {
+    Active,
+    /// Sent, or received into this path.
+    Done(Option<PathBuf>),
+    Failed(String),
+}
+
+/// How far a transfer got.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Progress {
+    pub peer:        PeerId,
+    pub id:          FileId,
+    pub name:        String,
+    pub direction:   Direction,
+    /// Bytes sent or received.
+    pub transferred: u64,
+    pub size:        u64,
+    pub state:       State,
+}
+
+/// A file being received.
+#[derive(Debug)]
+pub struct Download {
+    pub manifest: Manifest,
+    partial:      PathBuf,
+    have:         Vec<bool>,
+    requested:    Vec<usize>,
+}
+
+impl Download {
+    /// Receive `manifest` into `dir`, keeping the chunks a partial file
+    /// from before already has.
+    pub fn open(dir: &Path, manifest: Manifest) -> Result<Self> {
+        let partial_dir = dir.join(PARTIAL_DIR);
+        fs::create_dir_all(&partial_dir)
+            .with_context(|| format!("Creating {}", partial_dir.display()))?;
+        let partial = partial_dir.join(manifest.id().to_string());
+        let mut have = vec![false; manifest.chunks.len()];
+        if let Ok(mut file) = File::open(&partial) {
+            let mut buffer = Vec::with_capacity(CHUNK_SIZE as usize);
+            for (index, expected) in manifest.chunks.iter().enumerate() {
+                if read_chunk(&mut file, &mut buffer, manifest.chunk_len(index)).is_err() {
+                    break;
+                }
+                have[index] = Sha256::digest(&buffer).as_slice() == expected.as_slice();
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&partial)
+            .with_context(|| format!("Creating {}", partial.display()))?;
+        file.set_len(manifest.size)?;
+        Ok(Self {
+            manifest,
+            partial,
+            have,
+            requested: Vec::new(),
+        })
+    }
+
+    /// Bytes received so far.
+    pub fn received(&self) -> u64 {
+        self.have
+            .iter()
+            .enumerate()
+            .filter(|(_, have)| **have)
+            .map(|(index, _)| self.manifest.chunk_len(index))
+            .sum()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.have.iter().all(|have| *have)
+    }
+
+    /// The chunks to request next, to have [`WINDOW`] requested.
+    pub fn next(&mut self) -> Vec<usize> {
+        let mut next = Vec::new();
+        for index in 0..self.have.len() {
+            if self.requested.len() >= WINDOW {
+                break;
+            }
+            if !self.have[index] && !self.requested.contains(&index) {
+                self.requested.push(index);
+                next.push(index);
+            }
+        }
+        next
+    }
+
+    /// Chunk `index` will not come, request it again.
+    pub fn lost(&mut self, index: usize) {
+        self.requested.retain(|requested| *requested != index);
+    }
+
+    /// Store chunk `index` if it matches its hash.
+    pub fn write(&mut self, index: usize, data: &[u8]) -> Result<()> {
+        self.lost(index);
+        let expected = self.manifest.chunks.get(index).context("No such chunk")?;
+        ensure!(
+            data.len() as u64 == self.manifest.chunk_len(index)
+                && Sha256::digest(data).as_slice() == expected.as_slice(),
+            "Chunk {} does not match its hash",
+            index
+        );
+        let mut file = OpenOptions::new().write(true).open(&self.partial)?;
+        file.seek(SeekFrom::Start(index as u64 * CHUNK_SIZE))?;
+        file.write_all(data)?;
+        self.have[index] = true;
+        Ok(())
+    }
+
+    /// Move the complete file to `dir`, returning where it went.
+    pub fn finish(&self, dir: &Path) -> Result<PathBuf> {
+        ensure!(self.is_done(), "Chunks missing");
+        let name = Path::new(&self.manifest.name);
+        let mut path = dir.join(name);
+        let mut copy = 0;
+        while path.exists() {
+            copy += 1;
+            let stem = name.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            let numbered = match name.extension().and_then(|extension| extension.to_str()) {
+                Some(extension) => format!("{} ({}).{}", stem, copy, extension),
+                None => format!("{} ({})", stem, copy),
+            };
+            path = dir.join(numbered);
+        }
+        fs::rename(&self.partial, &path)
+            .with_context(|| format!("Moving the file to {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_receives_in_checked_chunks() {
+        let dir = std::env::temp_dir().join(format!("mesh-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let data = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let manifest = Manifest::of(&source).unwrap();
+        assert_eq!(manifest.chunks.len(), 3);
+        manifest.validate().unwrap();
+        let mut invalid = manifest.clone();
+        invalid.name = "../source.bin".into();
+        assert!(invalid.validate().is_err());
+        assert!("max_size=1GiB".parse::<Config>().unwrap().dir.is_none());
+        assert!("size=1GiB".parse::<Config>().is_err());
+
+        let downloads = dir.join("downloads");
+        let mut download = Download::open(&downloads, manifest.clone()).unwrap();
+        assert_eq!(download.next(), vec![0, 1, 2]);
+        assert!(download.write(1, &read(&source, &manifest, 0).unwrap()).is_err());
+        download.write(1, &read(&source, &manifest, 1).unwrap()).unwrap();
+        assert_eq!(download.received(), CHUNK_SIZE);
+
+        // Offered again, only the missing chunks are asked for
+        let mut download = Download::open(&downloads, manifest.clone()).unwrap();
+        assert_eq!(download.received(), CHUNK_SIZE);
+        assert_eq!(download.next(), vec![0, 2]);
+        for index in [0, 2].iter().copied() {
+            download.write(index, &read(&source, &manifest, index).unwrap()).unwrap();
+        }
+        assert!(download.is_done());
+        let path = download.finish(&downloads).unwrap();
+        assert_eq!(path, downloads.join("source.bin"));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
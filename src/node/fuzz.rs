@@ -0,0 +1,110 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! Each function feeds arbitrary bytes through the same decoding and state
+//! handling a message received from a hostile peer would take. They must not
+//! panic, allocate without bound or loop forever on any input.
+
+use super::{
+    aggregate::{Aggregates, Contribution},
+    behaviour::envelope::Envelope,
+    delta::{Decoder, Update},
+    election::{Election, ElectionConfig, Heartbeat},
+    hlc::{Hlc, Timestamp},
+    lock::{self, LockTable},
+};
+use libp2p::{identity, PeerId};
+use std::time::{Duration, Instant};
+
+/// A deterministic peer id, so runs are reproducible.
+fn peer_id(seed: u8) -> PeerId {
+    let mut secret = [seed; 32];
+    let secret = identity::ed25519::SecretKey::from_bytes(&mut secret)
+        .expect("Any 32 bytes are a valid secret key");
+    identity::Keypair::Ed25519(secret.into())
+        .public()
+        .into_peer_id()
+}
+
+/// Decode a pubsub envelope. Envelopes that decode must roundtrip.
+pub fn envelope(data: &[u8]) {
+    if let Some(envelope) = Envelope::decode(data) {
+        assert_eq!(Envelope::decode(&envelope.to_bytes()), Some(envelope));
+    }
+}
+
+/// Handle a control message. The first byte selects the message type, the
+/// rest is its CBOR encoding.
+pub fn control(data: &[u8]) {
+    let (kind, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let now = Instant::now();
+    let (local, remote) = (peer_id(1), peer_id(2));
+    match kind % 4 {
+        0 => {
+            if let Ok(heartbeat) = serde_cbor::from_slice::<Heartbeat>(data) {
+                let config = ElectionConfig::new(vec![remote.clone()]);
+                let mut election = Election::new(heartbeat.group.clone(), local, config, now);
+                election.receive(remote, &heartbeat, now);
+                election.tick(now);
+                election.tick(now + Duration::from_secs(3600));
+            }
+        }
+        1 => {
+            if let Ok(contribution) = serde_cbor::from_slice::<Contribution>(data) {
+                let mut aggregates = Aggregates::new(now);
+                aggregates.receive(remote.clone(), &contribution, now);
+                aggregates.receive(remote, &contribution, now);
+                for name in contribution.counters.keys().chain(contribution.gauges.keys()) {
+                    aggregates.value(name, now);
+                }
+            }
+        }
+        2 => {
+            if let Ok(request) = serde_cbor::from_slice::<lock::Request>(data) {
+                let mut table = LockTable::new(1);
+                table.handle(&remote, request.clone(), now);
+                table.handle(&local, request, now);
+            }
+        }
+        _ => {
+            if let Ok(timestamp) = serde_cbor::from_slice::<Timestamp>(data) {
+                let mut hlc = Hlc::default();
+                if hlc.receive(timestamp).is_ok() {
+                    assert!(hlc.now() >= timestamp);
+                }
+            }
+        }
+    }
+}
+
+/// Feed a sequence of state topic updates to a decoder.
+pub fn delta(data: &[u8]) {
+    if let Ok(updates) = serde_cbor::from_slice::<Vec<Update>>(data) {
+        let source = peer_id(1);
+        let mut decoder = Decoder::default();
+        for update in updates {
+            decoder.receive(&source, update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, path::Path};
+
+    /// Replay the corpus, which includes inputs for every bug found so far.
+    #[test]
+    fn test_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        let targets: [(&str, fn(&[u8])); 3] =
+            [("envelope", envelope), ("control", control), ("delta", delta)];
+        for (target, run) in &targets {
+            for entry in fs::read_dir(corpus.join(target)).unwrap() {
+                run(&fs::read(entry.unwrap().path()).unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Which peers may connect.
+//!
+//! `--allow` and `--deny` take peer ids and IP networks like `10.0.0.0/8`,
+//! and may be repeated. With networks allowed, connections to or from
+//! addresses outside them are refused, and with peers allowed, those of
+//! other peers. Denied peers and networks are refused in any case, and so
+//! are the peers banned with [`Node::ban`], until the ban runs out or
+//! [`Node::unban`] lifts it. Bans are kept in the [`storage`] of the data
+//! directory, so they outlast restarts.
+//!
+//! The transport checks the address of a connection before any handshake,
+//! and the peer id once the connection is authenticated, so a refused peer
+//! never gets to speak a protocol. Addresses without an IP, like those of
+//! Bluetooth or serial links, are judged by their peer only. Dials refused
+//! by the gate fail with [`Outcome::Denied`].
+//!
+//! [`Node::ban`]: crate::node::Node::ban
+//! [`Node::unban`]: crate::node::Node::unban
+//! [`Outcome::Denied`]: crate::node::dial::Outcome::Denied
+//! [`storage`]: crate::node::storage
+
+use super::storage::Slot;
+use crate::prelude::*;
+use anyhow::{anyhow, ensure};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Storage namespace of the bans.
+pub const NAMESPACE: &str = "gate";
+
+/// Storage key of the bans, once their file name.
+pub const KEY: &str = "bans.json";
+
+/// An IP network, like `192.168.0.0/16`, or a single address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Net {
+    ip:     IpAddr,
+    prefix: u8,
+}
+
+impl Net {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Net {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().with_context(|| format!("Invalid IP address {}", ip))?;
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().with_context(|| format!("Invalid prefix {}", prefix))?,
+            None => bits,
+        };
+        ensure!(prefix <= bits, "Prefix /{} is longer than the address", prefix);
+        Ok(Self { ip, prefix })
+    }
+}
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// A peer or network to allow or deny.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Rule {
+    Peer(PeerId),
+    Net(Net),
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parse a network if it has the dots or colons of one, else a peer id.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains(|c: char| c == '.' || c == ':') {
+            return Ok(Self::Net(s.parse()?));
+        }
+        s.parse()
+            .map(Self::Peer)
+            .map_err(|_| anyhow!("Expected a peer id or IP network, got {}", s))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub struct Config {
+    pub allow: Vec<Rule>,
+    pub deny:  Vec<Rule>,
+}
+
+/// Why a connection was refused.
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum Denied {
+    #[error("Address {0} is not allowed to connect")]
+    Address(IpAddr),
+    #[error("Peer {0} is not allowed to connect")]
+    Peer(PeerId),
+    #[error("Peer {0} is banned")]
+    Banned(PeerId),
+}
+
+impl From<Denied> for io::Error {
+    fn from(denied: Denied) -> Self {
+        Self::new(io::ErrorKind::PermissionDenied, denied)
+    }
+}
+
+/// Whether `error` is, or wraps, a connection refused by the gate.
+pub fn is_denied(error: &io::Error) -> bool {
+    let mut inner = error.get_ref();
+    while let Some(error) = inner {
+        if error.is::<Denied>() {
+            return true;
+        }
+        inner = error.downcast_ref::<io::Error>().and_then(io::Error::get_ref);
+    }
+    false
+}
+
+/// Seconds since the Unix epoch a ban ends at, if ever.
+type Until = Option<u64>;
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: Config,
+    bans:   HashMap<PeerId, Until>,
+    slot:   Option<Slot>,
+}
+
+impl State {
+    fn is_banned(&self, peer: &PeerId, now: SystemTime) -> bool {
+        match self.bans.get(peer) {
+            Some(Some(until)) => *until > seconds(now),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn save(&mut self, now: SystemTime) -> Result<()> {
+        let now = seconds(now);
+        self.bans.retain(|_, until| until.map_or(true, |until| until > now));
+        let slot = match &self.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        let bans: BTreeMap<_, _> = self
+            .bans
+            .iter()
+            .map(|(peer, until)| (peer.to_base58(), until))
+            .collect();
+        slot.write(&serde_json::to_vec_pretty(&bans)?)
+    }
+}
+
+/// The rules and bans, shared with the transport.
+#[derive(Clone, Debug, Default)]
+pub struct Gate(Arc<Mutex<State>>);
+
+impl Gate {
+    pub fn configure(&self, config: Config) {
+        self.0.lock().unwrap().config = config;
+    }
+
+    /// Load the bans kept in `slot`, if any, and keep them there from now
+    /// on.
+    pub fn load(&self, slot: Slot) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(json) = slot.read()? {
+            let bans: BTreeMap<String, Until> = serde_json::from_slice(&json)
+                .with_context(|| format!("Parsing bans from {}", slot))?;
+            for (peer, until) in bans {
+                let peer = peer
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid banned peer {} in {}", peer, slot))?;
+                state.bans.insert(peer, until);
+            }
+        }
+        state.slot = Some(slot);
+        Ok(())
+    }
+
+    /// Refuse `peer` for `duration` from `now`, or until unbanned.
+    pub fn ban(&self, peer: &PeerId, duration: Option<Duration>, now: SystemTime) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        let until = duration.map(|duration| seconds(now + duration));
+        state.bans.insert(peer.clone(), until);
+        state.save(now)
+    }
+
+    /// Lift the ban of `peer`. Returns whether it was banned.
+    pub fn unban(&self, peer: &PeerId, now: SystemTime) -> Result<bool> {
+        let mut state = self.0.lock().unwrap();
+        let banned = state.is_banned(peer, now);
+        state.bans.remove(peer);
+        state.save(now)?;
+        Ok(banned)
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, now: SystemTime) -> bool {
+        self.0.lock().unwrap().is_banned(peer, now)
+    }
+
+    /// Check the IP of `address`, before the handshake.
+    pub fn check_address(&self, address: &Multiaddr) -> Result<(), Denied> {
+        let ip = match address.iter().find_map(|protocol| {
+            match protocol {
+                Protocol::Ip4(ip) => Some(IpAddr::from(ip)),
+                Protocol::Ip6(ip) => Some(IpAddr::from(ip)),
+                _ => None,
+            }
+        }) {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        let config = &self.0.lock().unwrap().config;
+        let nets = |rules: &[Rule]| {
+            rules
+                .iter()
+                .filter_map(|rule| {
+                    match rule {
+                        Rule::Net(net) => Some(*net),
+                        Rule::Peer(_) => None,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let (allowed, denied) = (nets(&config.allow), nets(&config.deny));
+        if denied.iter().any(|net| net.contains(ip))
+            || !allowed.is_empty() && !allowed.iter().any(|net| net.contains(ip))
+        {
+            return Err(Denied::Address(ip));
+        }
+        Ok(())
+    }
+
+    /// Check an authenticated `peer`.
+    pub fn check_peer(&self, peer: &PeerId, now: SystemTime) -> Result<(), Denied> {
+        let state = self.0.lock().unwrap();
+        if state.is_banned(peer, now) {
+            return Err(Denied::Banned(peer.clone()));
+        }
+        let config = &state.config;
+        let listed = |rules: &[Rule]| rules.iter().any(|rule| *rule == Rule::Peer(peer.clone()));
+        let allow_peers = config.allow.iter().any(|rule| matches!(rule, Rule::Peer(_)));
+        if listed(&config.deny) || allow_peers && !listed(&config.allow) {
+            return Err(Denied::Peer(peer.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+
+    #[test]
+    fn test_gates_peers_and_networks() {
+        let (friend, stranger, pest) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let gate = Gate::default();
+        gate.configure(Config {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            deny:  vec![
+                "10.6.6.6".parse().unwrap(),
+                Rule::from_str(&pest.to_base58()).unwrap(),
+            ],
+        });
+        let address = |s: &str| s.parse::<Multiaddr>().unwrap();
+        assert_eq!(gate.check_address(&address("/ip4/10.1.2.3/tcp/4001")), Ok(()));
+        assert_eq!(gate.check_address(&address("/ip6/fd12::1/tcp/4001")), Ok(()));
+        assert!(gate.check_address(&address("/ip4/10.6.6.6/tcp/4001")).is_err());
+        assert!(gate.check_address(&address("/ip4/192.168.1.1/tcp/4001")).is_err());
+        assert_eq!(gate.check_address(&address("/dns4/example.com/tcp/4001")), Ok(()));
+        assert!("10.0.0.0/33".parse::<Net>().is_err());
+        assert!("nonsense".parse::<Rule>().is_err());
+
+        let now = SystemTime::now();
+        assert_eq!(gate.check_peer(&stranger, now), Ok(()));
+        assert_eq!(gate.check_peer(&pest, now), Err(Denied::Peer(pest.clone())));
+        let error = io::Error::new(io::ErrorKind::Other, io::Error::from(Denied::Peer(pest)));
+        assert!(is_denied(&error));
+
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+        gate.load(slot.clone()).unwrap();
+        gate.ban(&stranger, Some(Duration::from_secs(60)), now).unwrap();
+        gate.ban(&friend, None, now).unwrap();
+        let restarted = Gate::default();
+        restarted.load(slot).unwrap();
+        assert_eq!(
+            restarted.check_peer(&stranger, now),
+            Err(Denied::Banned(stranger.clone()))
+        );
+        assert_eq!(restarted.check_peer(&stranger, now + Duration::from_secs(61)), Ok(()));
+        assert!(restarted.unban(&friend, now).unwrap());
+        assert!(!restarted.unban(&friend, now).unwrap());
+        assert_eq!(restarted.check_peer(&friend, now), Ok(()));
+
+        // Allowing peers refuses the others
+        restarted.configure(Config {
+            allow: vec![Rule::Peer(friend.clone())],
+            deny:  Vec::new(),
+        });
+        let later = now + Duration::from_secs(61);
+        assert_eq!(restarted.check_peer(&stranger, later), Err(Denied::Peer(stranger)));
+        assert_eq!(restarted.check_peer(&friend, later), Ok(()));
+    }
+}
@@ -0,0 +1,252 @@
+//! Listener handoff for seamless upgrades.
+//!
+//! A node with a data directory listens on the Unix socket [`FILE_NAME`] in
+//! it. A new instance started on the same data directory connects to that
+//! socket before creating its node. The old instance passes its listening TCP
+//! sockets (as file descriptors) and its address book, then shuts down. The
+//! new instance accepts connections on the same sockets and redials the old
+//! peers, so the listening port never closes during an upgrade.
+//!
+//! The exchange is a single byte carrying the descriptors, followed by the
+//! address book as JSON until the old instance closes the connection.
+
+use super::Multiaddr;
+use crate::prelude::*;
+use libp2p::PeerId;
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::TcpListener,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+use tokio::{net::UnixListener, task::spawn_blocking};
+
+/// File name of the handoff socket inside the data directory.
+pub const FILE_NAME: &str = "handoff.sock";
+
+/// Most listening sockets handed over.
+const MAX_FDS: usize = 16;
+
+/// A known peer and the addresses it listens on.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Peer {
+    pub peer_id:   String,
+    pub addresses: Vec<String>,
+}
+
+impl Peer {
+    pub fn new(peer_id: &PeerId, addresses: &[Multiaddr]) -> Self {
+        Self {
+            peer_id:   peer_id.to_base58(),
+            addresses: addresses.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// The peer id and addresses, skipping anything that does not parse.
+    pub fn parse(&self) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let peer_id = self.peer_id.parse().ok()?;
+        let addresses = self
+            .addresses
+            .iter()
+            .filter_map(|address| address.parse().ok())
+            .collect();
+        Some((peer_id, addresses))
+    }
+}
+
+/// What an old instance passes to its successor.
+#[derive(Debug, Default)]
+pub struct Handoff {
+    pub listeners: Vec<TcpListener>,
+    pub peers:     Vec<Peer>,
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let fds = &fds[..fds.len().min(MAX_FDS)];
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len:  byte.len(),
+    };
+    let data_len = mem::size_of_val(fds) as u32;
+    // SAFETY: The control buffer is sized with `CMSG_SPACE` for the header
+    // and descriptors written into it, and outlives the `sendmsg` call.
+    unsafe {
+        let mut control = vec![0_u8; libc::CMSG_SPACE(data_len) as usize];
+        let mut message: libc::msghdr = mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        if !fds.is_empty() {
+            message.msg_control = control.as_mut_ptr().cast();
+            message.msg_controllen = control.len() as _;
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(data_len) as _;
+            ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(header).cast::<RawFd>(),
+                fds.len(),
+            );
+        }
+        if libc::sendmsg(stream.as_raw_fd(), &message, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn receive_fds(stream: &UnixStream) -> io::Result<Vec<RawFd>> {
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len:  byte.len(),
+    };
+    let mut fds = Vec::new();
+    // SAFETY: The kernel writes at most `msg_controllen` bytes of control
+    // messages, which we only read through the `CMSG_*` accessors.
+    unsafe {
+        let space = libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32);
+        let mut control = vec![0_u8; space as usize];
+        let mut message: libc::msghdr = mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = control.len() as _;
+        if libc::recvmsg(stream.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS
+            {
+                let len = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                for index in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(ptr::read_unaligned(data.add(index)));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    Ok(fds)
+}
+
+/// Take over from a running instance listening on the handoff socket at
+/// `path`. Returns `None` if no instance is running.
+pub async fn receive(path: &Path) -> Result<Option<Handoff>> {
+    let path = path.to_owned();
+    spawn_blocking(move || {
+        let mut stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(err) => {
+                debug!("No instance to take over from at {}: {}", path.display(), err);
+                return Ok(None);
+            }
+        };
+        info!("Taking over from the instance at {}", path.display());
+        let listeners = receive_fds(&stream)
+            .context("Receiving listening sockets")?
+            .into_iter()
+            // SAFETY: The descriptors were just passed to us and are owned by
+            // nothing else in this process.
+            .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+            .collect::<Vec<_>>();
+        let mut json = Vec::new();
+        stream
+            .read_to_end(&mut json)
+            .context("Receiving address book")?;
+        let peers = serde_json::from_slice(&json).context("Parsing address book")?;
+        Ok(Some(Handoff { listeners, peers }))
+    })
+    .await
+    .context("Handoff task failed")?
+}
+
+/// Listen on the handoff socket at `path` and wait for a successor to
+/// connect.
+pub async fn serve(path: PathBuf) -> Result<UnixStream> {
+    // A previous instance that has handed off, or crashed, may have left the
+    // socket behind
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Removing stale handoff socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Listening for handoff on {}", path.display()))?;
+    let (stream, _) = listener.accept().await.context("Accepting handoff")?;
+    // Continue with a blocking copy of the connection
+    // SAFETY: `dup` returns a new descriptor that only the `UnixStream` owns.
+    let fd = unsafe { libc::dup(stream.as_raw_fd()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Duplicating handoff connection");
+    }
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Pass `handoff` to the successor connected to `stream`.
+pub async fn send(mut stream: UnixStream, handoff: Handoff) -> Result<()> {
+    spawn_blocking(move || {
+        info!(
+            "Handing off {} listeners and {} peers",
+            handoff.listeners.len(),
+            handoff.peers.len()
+        );
+        let fds = handoff
+            .listeners
+            .iter()
+            .map(AsRawFd::as_raw_fd)
+            .collect::<Vec<_>>();
+        send_fds(&stream, &fds).context("Sending listening sockets")?;
+        let json = serde_json::to_vec(&handoff.peers)?;
+        stream.write_all(&json).context("Sending address book")
+    })
+    .await
+    .context("Handoff task failed")?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_hand_off_listener() {
+        let dir = std::env::temp_dir().join(format!("mesh-handoff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE_NAME);
+        assert!(receive(&path).await.unwrap().is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = Peer::new(&PeerId::random(), &["/ip4/1.2.3.4/tcp/5".parse().unwrap()]);
+        let serving = tokio::spawn(serve(path.clone()));
+        while !path.exists() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let receiving = tokio::spawn({
+            let path = path.clone();
+            async move { receive(&path).await }
+        });
+        let stream = serving.await.unwrap().unwrap();
+        send(stream, Handoff {
+            listeners: vec![listener],
+            peers:     vec![peer.clone()],
+        })
+        .await
+        .unwrap();
+
+        let handoff = receiving.await.unwrap().unwrap().unwrap();
+        assert_eq!(handoff.peers, vec![peer]);
+        assert_eq!(handoff.listeners.len(), 1);
+        assert_eq!(handoff.listeners[0].local_addr().unwrap(), address);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
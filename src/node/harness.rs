@@ -0,0 +1,397 @@
+//! Nodes of one process connected in memory, for integration tests.
+//!
+//! Built with the `harness` feature. [`Harness::spawn`] builds nodes that
+//! listen on `/memory/` addresses, without mDNS or the 0x Mesh bootnodes,
+//! with keypairs derived from their index, so peer ids are the same from
+//! run to run. Every node dials the ones before it, and `spawn` returns
+//! once all are connected to all. [`Harness::spawn_tcp`] binds the nodes
+//! to ports of `127.0.0.1` the OS assigns instead, and has each bootstrap
+//! through the ones before it, for end-to-end tests over real sockets.
+//! [`Harness::spawn_sparse`] dials only a few of the nodes before each, so
+//! large meshes do not connect every pair, as in [`super::simulation`].
+//! The nodes run on the current tokio `LocalSet`:
+//!
+//! ```ignore
+//! # async fn example() -> anyhow::Result<()> {
+//! use mesh::node::harness::Harness;
+//!
+//! tokio::task::LocalSet::new()
+//!     .run_until(async {
+//!         let mut harness = Harness::spawn(3).await?;
+//!         harness.subscribe("chat").await?;
+//!         harness.handle(0).publish("chat", b"hello").await?;
+//!         let message = harness.wait_for_message(2, "chat").await?;
+//!         assert_eq!(message.data, b"hello");
+//!         harness.shutdown().await
+//!     })
+//!     .await
+//! # }
+//! ```
+
+use super::{discovery, ready::Criteria, route, Event, Node, NodeHandle, TopicOptions};
+use crate::prelude::*;
+use anyhow::anyhow;
+use futures::channel::mpsc;
+use libp2p::{
+    identity::{ed25519, Keypair},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use rand::{rngs::StdRng, seq::index::sample, SeedableRng};
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How long the helpers wait before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Memory ports are shared by the process, so harnesses of tests running at
+/// once take different ones.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1 << 32);
+
+/// A node of a [`Harness`].
+pub struct TestNode {
+    pub peer_id: PeerId,
+    /// `/memory/` or `/tcp/` address the node listens on.
+    pub address: Multiaddr,
+    pub handle:  NodeHandle,
+    /// Read since the node started, so no message is missed.
+    events:      mpsc::Receiver<Event>,
+}
+
+/// Nodes connected to each other in memory.
+pub struct Harness {
+    nodes: Vec<TestNode>,
+}
+
+/// The keypair of the node at `index`, the same in every run.
+pub fn keypair(index: usize) -> Keypair {
+    let mut secret = [0_u8; 32];
+    secret[..8].copy_from_slice(&(index as u64 + 1).to_be_bytes());
+    let secret = ed25519::SecretKey::from_bytes(&mut secret).expect("32 bytes are a secret key");
+    Keypair::Ed25519(secret.into())
+}
+
+/// Fail if `future` does not finish within [`TIMEOUT`].
+async fn within<T>(what: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .map_err(|_| anyhow!("{} took over {:?}", what, TIMEOUT))?
+}
+
+/// Where the nodes of a [`Harness`] listen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Transport {
+    Memory,
+    Tcp,
+}
+
+/// Start the node at `index`, bootstrapping through `bootstrap`.
+async fn spawn_node(
+    index: usize,
+    transport: Transport,
+    bootstrap: &[Multiaddr],
+) -> Result<TestNode> {
+    let discovery = "mdns=false bootnodes=false".parse::<discovery::Config>()?;
+    let builder = Node::builder()
+        .with_keypair(keypair(index))
+        .with_discovery(discovery);
+    let (builder, address) = match transport {
+        Transport::Memory => {
+            let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+            let address = Multiaddr::empty().with(Protocol::Memory(port));
+            (builder.with_listen_addr(address.clone()), address)
+        }
+        Transport::Tcp => {
+            // Bound here, so the port is known and no other test takes it
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .context("Binding a localhost port")?;
+            let port = listener.local_addr()?.port();
+            let address = Multiaddr::empty()
+                .with(Protocol::Ip4(Ipv4Addr::LOCALHOST))
+                .with(Protocol::Tcp(port));
+            (builder.with_listener(listener), address)
+        }
+    };
+    let builder = bootstrap
+        .iter()
+        .fold(builder, |builder, peer| builder.with_bootstrap_peer(peer.clone()));
+    let mut node = builder.build().await?;
+    let peer_id = node.local_peer_id().clone();
+    let mut handle = node.handle();
+    tokio::task::spawn_local(async move {
+        if let Err(err) = node.run().await {
+            error!("Test node {} stopped: {:#}", index, err);
+        }
+    });
+    let events = handle.events().await?;
+    Ok(TestNode {
+        peer_id,
+        address,
+        handle,
+        events,
+    })
+}
+
+impl Harness {
+    /// Start `count` nodes and connect each to all others.
+    pub async fn spawn(count: usize) -> Result<Self> {
+        let mut nodes: Vec<TestNode> = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut node = spawn_node(index, Transport::Memory, &[]).await?;
+            for other in &nodes {
+                node.handle.dial(other.address.clone()).await?;
+            }
+            nodes.push(node);
+        }
+        let peers = nodes.len().saturating_sub(1);
+        Self::connected(nodes, peers).await
+    }
+
+    /// Start `count` nodes in memory, each dialing `degree` nodes before it
+    /// picked at random, the same ones in every run. The nodes form one
+    /// mesh, and `spawn_sparse` returns once each has a peer.
+    pub async fn spawn_sparse(count: usize, degree: usize) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(count as u64);
+        let mut nodes: Vec<TestNode> = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut node = spawn_node(index, Transport::Memory, &[]).await?;
+            for other in sample(&mut rng, index, degree.max(1).min(index)).into_iter() {
+                node.handle.dial(nodes[other].address.clone()).await?;
+            }
+            nodes.push(node);
+        }
+        Self::connected(nodes, usize::from(count > 1)).await
+    }
+
+    /// Start `count` nodes on localhost TCP ports, each with the nodes
+    /// before it as bootstrap peers, and wait until each is connected to
+    /// all others.
+    pub async fn spawn_tcp(count: usize) -> Result<Self> {
+        let mut nodes: Vec<TestNode> = Vec::with_capacity(count);
+        for index in 0..count {
+            let bootstrap = nodes
+                .iter()
+                .map(|node| node.address.clone().with(Protocol::P2p(node.peer_id.clone().into())))
+                .collect::<Vec<_>>();
+            nodes.push(spawn_node(index, Transport::Tcp, &bootstrap).await?);
+        }
+        let peers = nodes.len().saturating_sub(1);
+        Self::connected(nodes, peers).await
+    }
+
+    /// Wait until each of the `nodes` is connected to `peers` others.
+    async fn connected(nodes: Vec<TestNode>, peers: usize) -> Result<Self> {
+        let mut harness = Self { nodes };
+        within("Connecting the nodes", async {
+            for node in &mut harness.nodes {
+                node.handle
+                    .wait_ready(Criteria::default().peers(peers))
+                    .await?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(harness)
+    }
+
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    /// The handle of the node at `index`.
+    pub fn handle(&self, index: usize) -> NodeHandle {
+        self.nodes[index].handle.clone()
+    }
+
+    /// Subscribe every node to `topic`, and wait until each has a peer in
+    /// the topic mesh to publish to.
+    pub async fn subscribe(&mut self, topic: &str) -> Result<()> {
+        for node in &mut self.nodes {
+            node.handle.subscribe(topic, TopicOptions::default()).await?;
+        }
+        let peers = usize::from(self.nodes.len() > 1);
+        within("Joining the topic mesh", async {
+            for node in &mut self.nodes {
+                node.handle
+                    .wait_ready(Criteria::default().topic(topic, peers))
+                    .await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// The next message the node at `index` receives on `topic`, skipping
+    /// other events.
+    pub async fn wait_for_message(&mut self, index: usize, topic: &str) -> Result<route::Message> {
+        let events = &mut self.nodes[index].events;
+        within("Waiting for a message", async {
+            while let Some(event) = events.next().await {
+                if let Event::Message {
+                    source,
+                    topic: received,
+                    data,
+                    direct,
+                    timestamp,
+                    provenance,
+                    signed,
+                } = event
+                {
+                    if received == topic {
+                        return Ok(route::Message {
+                            source,
+                            topic: received,
+                            data,
+                            direct,
+                            timestamp,
+                            provenance,
+                            signed,
+                        });
+                    }
+                }
+            }
+            Err(anyhow!("Node {} stopped", index))
+        })
+        .await
+    }
+
+    /// Shut all nodes down, and wait until each stopped.
+    pub async fn shutdown(mut self) -> Result<()> {
+        for node in &mut self.nodes {
+            node.handle.shutdown().await?;
+        }
+        within("Shutting down", async {
+            for node in &mut self.nodes {
+                while node.events.next().await.is_some() {}
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use crate::node::typed::{Delivery, Echo};
+    use tokio::task::LocalSet;
+
+    #[tokio::test]
+    async fn test_delivers_between_memory_nodes() {
+        LocalSet::new()
+            .run_until(async {
+                let mut harness = Harness::spawn(3).await.unwrap();
+                assert_eq!(harness.nodes()[1].peer_id, keypair(1).public().into_peer_id());
+                harness.subscribe("chat").await.unwrap();
+                harness.handle(0).publish("chat", b"hello").await.unwrap();
+                for index in 1..3 {
+                    let message = harness.wait_for_message(index, "chat").await.unwrap();
+                    assert_eq!(message.data, b"hello");
+                    assert_eq!(message.source, harness.nodes()[0].peer_id);
+                }
+                harness.shutdown().await.unwrap();
+            })
+            .await;
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    struct Note(String);
+
+    #[tokio::test]
+    async fn test_runs_over_localhost_sockets() {
+        LocalSet::new()
+            .run_until(async {
+                let mut harness = Harness::spawn_tcp(4).await.unwrap();
+                harness.subscribe("chat").await.unwrap();
+                harness.handle(3).publish("chat", b"over tcp").await.unwrap();
+                for index in 0..3 {
+                    let message = harness.wait_for_message(index, "chat").await.unwrap();
+                    assert_eq!(message.data, b"over tcp");
+                }
+
+                // Receivers acknowledge the messages of an echo topic
+                let mut topics = Vec::new();
+                for index in 0..4 {
+                    topics.push(harness.handle(index).echo_topic::<Note>("notes").await.unwrap());
+                }
+                within("Joining the notes mesh", async {
+                    for index in 0..4 {
+                        let criteria = Criteria::default().topic("notes", 1);
+                        harness.handle(index).wait_ready(criteria).await?;
+                    }
+                    Ok(())
+                })
+                .await
+                .unwrap();
+                let id = topics[0].0.send(&Note("hi".into())).await.unwrap();
+                for (_, receiver) in &mut topics[1..] {
+                    let note = within("Receiving the note", async {
+                        loop {
+                            match receiver.next().await {
+                                Some(Echo::Received(message)) => return Ok(message.payload),
+                                Some(_) => {}
+                                None => return Err(anyhow!("Node stopped")),
+                            }
+                        }
+                    })
+                    .await
+                    .unwrap();
+                    assert_eq!(note, Note("hi".into()));
+                }
+                let receiver = &mut topics[0].1;
+                let mut delivered = within("Collecting receipts", async {
+                    let mut delivered = Vec::new();
+                    while delivered.len() < 3 {
+                        match receiver.next().await {
+                            Some(Echo::Status {
+                                id: status_id,
+                                status: Delivery::Delivered(peer),
+                            }) if status_id == id => delivered.push(peer),
+                            Some(_) => {}
+                            None => return Err(anyhow!("Node stopped")),
+                        }
+                    }
+                    Ok(delivered)
+                })
+                .await
+                .unwrap();
+                delivered.sort();
+                let mut others = harness.nodes()[1..]
+                    .iter()
+                    .map(|node| node.peer_id.clone())
+                    .collect::<Vec<_>>();
+                others.sort();
+                assert_eq!(delivered, others);
+                harness.shutdown().await.unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_sends_to_one_peer() {
+        LocalSet::new()
+            .run_until(async {
+                let mut harness = Harness::spawn(3).await.unwrap();
+                let peer_id = harness.nodes()[2].peer_id.clone();
+                harness.handle(0).send_to(&peer_id, b"psst").await.unwrap();
+                let events = &mut harness.nodes[2].events;
+                let received = within("Waiting for the message", async {
+                    while let Some(event) = events.next().await {
+                        if let Event::DirectMessage { source, data } = event {
+                            return Ok((source, data));
+                        }
+                    }
+                    Err(anyhow!("Node stopped"))
+                })
+                .await
+                .unwrap();
+                assert_eq!(received, (harness.nodes()[0].peer_id.clone(), b"psst".to_vec()));
+                harness.shutdown().await.unwrap();
+            })
+            .await;
+    }
+}
@@ -0,0 +1,198 @@
+//! Dial timeouts and reaping idle or unhealthy connections.
+//!
+//! `--connection-health "dial=20s idle=10m"` gives up on a dial, and on
+//! accepting a connection, that did not finish its handshakes within `dial`,
+//! failing it with a timeout. Connections to a peer that exchanged no bytes
+//! for `idle`, counted as in [`super::accounting`], are closed and the peer
+//! is not redialed until something else asks for it; `idle=never`, the
+//! default, keeps them. Critical and pinned peers are never reaped, and
+//! neither are the peers [`super::keepalive`] probes, as the probes are
+//! traffic. The keepalive probes are also the health check: a connection
+//! whose probes go unanswered is closed and redialed.
+//!
+//! Each reaped connection is reported as
+//! [`Event::ConnectionReaped`](crate::node::Event::ConnectionReaped), with
+//! the [`Reason`].
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// Longest a dial or an accepted connection takes to be upgraded.
+    pub dial: Duration,
+    /// Close connections without traffic for this long, `None` to keep
+    /// them.
+    pub idle: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dial: Duration::from_secs(20),
+            idle: None,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "dial" => {
+                    config.dial = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid dial {}", value))?;
+                }
+                "idle" if value == "never" => config.idle = None,
+                "idle" => {
+                    config.idle = Some(
+                        humantime::parse_duration(value)
+                            .with_context(|| format!("Invalid idle {}", value))?,
+                    );
+                }
+                _ => bail!("Unknown connection health option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.dial >= Duration::from_secs(1),
+            "Connection dial timeout must be at least a second"
+        );
+        ensure!(
+            self.idle.map_or(true, |idle| idle >= Duration::from_secs(1)),
+            "Connection idle timeout must be at least a second"
+        );
+        Ok(())
+    }
+}
+
+/// Why a connection was closed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    /// No traffic for the idle timeout.
+    Idle,
+    /// Keepalive probes went unanswered.
+    Unhealthy,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Idle => "idle",
+            Self::Unhealthy => "unhealthy",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Activity {
+    /// Bytes exchanged with the peer so far.
+    bytes: u64,
+    since: Instant,
+}
+
+/// When each connected peer last had traffic.
+#[derive(Clone, Debug, Default)]
+pub struct Reaper {
+    config: Config,
+    peers:  HashMap<PeerId, Activity>,
+}
+
+impl Reaper {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The `connected` peers, with the bytes exchanged with each so far,
+    /// that had no traffic for the idle timeout at `now`. They are then
+    /// forgotten, as are the peers no longer connected.
+    pub fn due(&mut self, now: Instant, connected: &[(PeerId, u64)]) -> Vec<PeerId> {
+        let idle = match self.config.idle {
+            Some(idle) => idle,
+            None => return Vec::new(),
+        };
+        self.peers
+            .retain(|peer_id, _| connected.iter().any(|(connected, _)| connected == peer_id));
+        let mut due = Vec::new();
+        for (peer_id, bytes) in connected {
+            let activity = self.peers.entry(peer_id.clone()).or_insert(Activity {
+                bytes: *bytes,
+                since: now,
+            });
+            if activity.bytes != *bytes {
+                activity.bytes = *bytes;
+                activity.since = now;
+            } else if now.saturating_duration_since(activity.since) >= idle {
+                due.push(peer_id.clone());
+            }
+        }
+        for peer_id in &due {
+            self.peers.remove(peer_id);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "dial=5s idle=10m".parse().unwrap();
+        assert_eq!(config.dial, Duration::from_secs(5));
+        assert_eq!(config.idle, Some(Duration::from_secs(600)));
+        assert_eq!("idle=never".parse::<Config>().unwrap(), Config::default());
+        assert!("dial=0s".parse::<Config>().is_err());
+        assert!("idle=10ms".parse::<Config>().is_err());
+        assert!("health=5s".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_reaps_idle_peers() {
+        let mut reaper = Reaper::new("idle=60s".parse().unwrap());
+        let (quiet, busy) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(reaper.due(start, &[(quiet.clone(), 10), (busy.clone(), 10)]), vec![]);
+        assert_eq!(reaper.due(after(30), &[(quiet.clone(), 10), (busy.clone(), 20)]), vec![]);
+        assert_eq!(
+            reaper.due(after(60), &[(quiet.clone(), 10), (busy.clone(), 20)]),
+            vec![quiet.clone()]
+        );
+        assert_eq!(reaper.due(after(80), &[(busy.clone(), 20)]), vec![]);
+        assert_eq!(reaper.due(after(90), &[(busy.clone(), 20)]), vec![busy.clone()]);
+
+        let mut keeper = Reaper::new(Config::default());
+        assert_eq!(keeper.due(after(1000), &[(busy, 20)]), vec![]);
+    }
+}
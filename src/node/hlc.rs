@@ -0,0 +1,224 @@
+//! Hybrid logical clock.
+//!
+//! A hybrid logical clock (Kulkarni et al., 2014) combines the wall clock with
+//! a logical counter. Timestamps stay close to physical time but respect
+//! causality: a timestamp taken after receiving a message is always larger than
+//! the timestamp of that message, even if the sender's clock runs ahead.
+//!
+//! Remote timestamps that are too far ahead of our wall clock are rejected, so
+//! a single peer with a broken clock can not drag everyone into the future.
+//! After our own clock jumped back, [`Hlc::rebaseline`] temporarily widens
+//! that window so the peers whose clocks did not jump are still accepted.
+
+use crate::prelude::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default maximum amount a remote timestamp may be ahead of our wall clock.
+pub const DEFAULT_MAX_DRIFT: Duration = Duration::from_secs(60);
+
+/// A hybrid logical clock timestamp. Ordered by wall time, then counter.
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    /// Milliseconds since the Unix epoch.
+    pub wall_ms: u64,
+    pub logical: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+#[error("Remote timestamp {remote:?} is {drift_ms}ms ahead of the local clock")]
+pub struct ClockDrift {
+    pub remote:   Timestamp,
+    pub drift_ms: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Hlc {
+    last:      Timestamp,
+    max_drift: Duration,
+    /// Extra drift tolerated, and until which wall time.
+    slack:     Option<(u64, u64)>,
+}
+
+impl Default for Hlc {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DRIFT)
+    }
+}
+
+fn wall_clock_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+impl Hlc {
+    pub const fn new(max_drift: Duration) -> Self {
+        Self {
+            last: Timestamp {
+                wall_ms: 0,
+                logical: 0,
+            },
+            max_drift,
+            slack: None,
+        }
+    }
+
+    /// The last timestamp issued or observed.
+    pub const fn last(&self) -> Timestamp {
+        self.last
+    }
+
+    /// Timestamp a local event or outgoing message.
+    pub fn now(&mut self) -> Timestamp {
+        self.tick(wall_clock_ms())
+    }
+
+    /// Merge the timestamp of a received message.
+    pub fn receive(&mut self, remote: Timestamp) -> Result<Timestamp, ClockDrift> {
+        self.update(remote, wall_clock_ms())
+    }
+
+    /// Our wall clock jumped back by `jump_ms`. Accept remote timestamps
+    /// that much further ahead for `grace`.
+    pub fn rebaseline(&mut self, jump_ms: u64, grace: Duration) {
+        self.rebaseline_at(jump_ms, grace, wall_clock_ms());
+    }
+
+    fn rebaseline_at(&mut self, jump_ms: u64, grace: Duration, wall_ms: u64) {
+        let slack_ms = self.slack_ms(wall_ms).saturating_add(jump_ms);
+        let until_ms = wall_ms.saturating_add(grace.as_millis() as u64);
+        self.slack = Some((slack_ms, until_ms));
+    }
+
+    fn slack_ms(&self, wall_ms: u64) -> u64 {
+        match self.slack {
+            Some((slack_ms, until_ms)) if wall_ms < until_ms => slack_ms,
+            _ => 0,
+        }
+    }
+
+    fn tick(&mut self, wall_ms: u64) -> Timestamp {
+        self.last = if wall_ms > self.last.wall_ms {
+            Timestamp {
+                wall_ms,
+                logical: 0,
+            }
+        } else {
+            Timestamp {
+                wall_ms: self.last.wall_ms,
+                logical: self.last.logical.saturating_add(1),
+            }
+        };
+        self.last
+    }
+
+    fn update(&mut self, remote: Timestamp, wall_ms: u64) -> Result<Timestamp, ClockDrift> {
+        let drift_ms = remote.wall_ms.saturating_sub(wall_ms);
+        let allowed_ms = self.max_drift.as_millis() + u128::from(self.slack_ms(wall_ms));
+        if u128::from(drift_ms) > allowed_ms {
+            return Err(ClockDrift { remote, drift_ms });
+        }
+        let last = self.last;
+        let max_wall = wall_ms.max(last.wall_ms).max(remote.wall_ms);
+        let logical = match (max_wall == last.wall_ms, max_wall == remote.wall_ms) {
+            (true, true) => last.logical.max(remote.logical).saturating_add(1),
+            (true, false) => last.logical.saturating_add(1),
+            (false, true) => remote.logical.saturating_add(1),
+            (false, false) => 0,
+        };
+        self.last = Timestamp {
+            wall_ms: max_wall,
+            logical,
+        };
+        Ok(self.last)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use proptest::prelude::*;
+
+    fn ts(wall_ms: u64, logical: u32) -> Timestamp {
+        Timestamp { wall_ms, logical }
+    }
+
+    #[test]
+    fn test_tick_follows_wall_clock() {
+        let mut hlc = Hlc::default();
+        assert_eq!(hlc.tick(100), ts(100, 0));
+        assert_eq!(hlc.tick(100), ts(100, 1));
+        assert_eq!(hlc.tick(90), ts(100, 2));
+        assert_eq!(hlc.tick(101), ts(101, 0));
+    }
+
+    #[test]
+    fn test_receive_from_the_future() {
+        let mut hlc = Hlc::default();
+        hlc.tick(100);
+        assert_eq!(hlc.update(ts(150, 3), 100), Ok(ts(150, 4)));
+        assert_eq!(hlc.tick(120), ts(150, 5));
+    }
+
+    #[test]
+    fn test_reject_excessive_drift() {
+        let mut hlc = Hlc::new(Duration::from_millis(10));
+        hlc.tick(100);
+        assert_eq!(hlc.update(ts(200, 0), 100), Err(ClockDrift {
+            remote:   ts(200, 0),
+            drift_ms: 100,
+        }));
+        assert_eq!(hlc.last(), ts(100, 0));
+    }
+
+    #[test]
+    fn test_rebaseline_widens_drift_window() {
+        let mut hlc = Hlc::new(Duration::from_millis(10));
+        hlc.rebaseline_at(100, Duration::from_millis(50), 100);
+        assert_eq!(hlc.update(ts(200, 0), 100), Ok(ts(200, 1)));
+        assert!(hlc.update(ts(400, 0), 160).is_err());
+    }
+
+    #[test]
+    fn test_logical_saturates() {
+        let mut hlc = Hlc::default();
+        hlc.tick(100);
+        assert_eq!(hlc.update(ts(100, u32::MAX), 100), Ok(ts(100, u32::MAX)));
+    }
+
+    proptest! {
+        #[test]
+        fn test_receive_is_causal(wall in 0_u64..1000, remote in 0_u64..1000, logical: u16) {
+            let mut hlc = Hlc::new(Duration::from_secs(1));
+            hlc.tick(wall);
+            let before = hlc.last();
+            let remote = ts(remote, logical.into());
+            let after = hlc.update(remote, wall).unwrap();
+            prop_assert!(after > remote);
+            prop_assert!(after > before);
+        }
+
+        #[test]
+        fn test_sender_timestamps_increase(events: Vec<(bool, u16, u16, u8)>) {
+            // Whatever the wall clock does and whatever we receive, every
+            // timestamp we issue is larger than the previous one, so receivers
+            // ordering by timestamp keep our messages in send order.
+            let mut hlc = Hlc::new(Duration::from_secs(1));
+            let mut previous = None;
+            for (receive, wall, remote, logical) in events {
+                let (wall, remote) = (u64::from(wall), u64::from(remote));
+                if receive {
+                    let _ = hlc.update(ts(remote, logical.into()), wall);
+                }
+                let issued = hlc.tick(wall);
+                if let Some(previous) = previous {
+                    prop_assert!(issued > previous);
+                }
+                previous = Some(issued);
+            }
+        }
+    }
+}
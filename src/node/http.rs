@@ -0,0 +1,212 @@
+//! Plain HTTP for the metrics, probe and control API endpoints.
+//!
+//! A connection carries one request, answered with `Connection: close`. A
+//! client gets [`TIMEOUT`] to send a request head of at most [`MAX_HEAD`]
+//! bytes and a body of at most what the endpoint reads, and again to take
+//! the response, so a client that stalls does not hold its task.
+
+use crate::prelude::*;
+use anyhow::{anyhow, ensure};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Largest request head read.
+pub const MAX_HEAD: usize = 8192;
+
+/// How long a client may take to send its request or take the response.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The request line of a request, the headers used and its body.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Request {
+    pub method:        String,
+    pub path:          String,
+    /// The `Accept` header, empty without one.
+    pub accept:        String,
+    /// The `Authorization` header.
+    pub authorization: Option<String>,
+    pub body:          Vec<u8>,
+}
+
+/// A request refused before it was read, and the status to answer with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("{message}")]
+pub struct Refused {
+    pub status:  &'static str,
+    pub message: &'static str,
+}
+
+/// Answer one request on `stream`, without a body, with the status line
+/// and body `respond` gives for it, as `content_type`.
+pub async fn answer<F, R>(mut stream: TcpStream, content_type: &str, respond: F) -> Result<()>
+where
+    F: FnOnce(Request) -> R,
+    R: Future<Output = Result<(&'static str, String)>>,
+{
+    let request = read(&mut stream, 0).await?;
+    let (status, body) = respond(request).await?;
+    write_response(&mut stream, status, content_type, &body).await
+}
+
+/// Read a request with a body of at most `max_body` bytes on `stream`, for
+/// answers given with [`write_response`]. Requests that are too large or
+/// malformed fail with [`Refused`].
+pub async fn read(stream: &mut TcpStream, max_body: usize) -> Result<Request> {
+    timeout(TIMEOUT, read_request(stream, max_body))
+        .await
+        .map_err(|_| anyhow!("No request within {:?}", TIMEOUT))?
+}
+
+/// Read the request line on `stream`, the headers after it and the body.
+async fn read_request(stream: &mut TcpStream, max_body: usize) -> Result<Request> {
+    let refuse = |status, message| Err(Refused { status, message }.into());
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    let head_len = loop {
+        if let Some(index) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break index + 4;
+        }
+        if data.len() > MAX_HEAD {
+            return refuse("431 Request Header Fields Too Large", "Header too large");
+        }
+        let read = stream.read(&mut buffer).await?;
+        ensure!(read > 0, "Connection closed");
+        data.extend_from_slice(&buffer[..read]);
+    };
+    let head = String::from_utf8_lossy(&data[..head_len]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut words = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return refuse("400 Bad Request", "Invalid request line"),
+    };
+    let mut accept = String::new();
+    let mut authorization = None;
+    let mut content_length = 0;
+    for line in lines {
+        let mut header = line.splitn(2, ':');
+        let (name, value) = match (header.next(), header.next()) {
+            (Some(name), Some(value)) => (name, value.trim()),
+            _ => continue,
+        };
+        if name.eq_ignore_ascii_case("accept") {
+            accept = value.to_owned();
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = match value.parse() {
+                Ok(length) => length,
+                Err(_) => return refuse("400 Bad Request", "Invalid Content-Length"),
+            };
+        }
+    }
+    if content_length > max_body {
+        return refuse("413 Payload Too Large", "Body too large");
+    }
+    let mut body = data.split_off(head_len);
+    while body.len() < content_length {
+        let read = stream.read(&mut buffer).await?;
+        ensure!(read > 0, "Connection closed");
+        body.extend_from_slice(&buffer[..read]);
+    }
+    body.truncate(content_length);
+    Ok(Request {
+        method,
+        path,
+        accept,
+        authorization,
+        body,
+    })
+}
+
+/// Write a response of `status` with `body` as `content_type` to `stream`
+/// and close it.
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    timeout(TIMEOUT, async {
+        stream.write_all(response.as_bytes()).await?;
+        AsyncWriteExt::shutdown(stream).await
+    })
+    .await
+    .map_err(|_| anyhow!("Response not taken within {:?}", TIMEOUT))??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use tokio::net::TcpListener;
+
+    async fn exchange(request: Vec<u8>) -> (Result<()>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let _ = stream.write_all(&request).await;
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response).await;
+            response
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let result = answer(stream, "text/plain", |request| {
+            async move {
+                let body = format!("{} {} {}", request.method, request.path, request.accept);
+                Ok(("200 OK", body.trim_end().to_owned()))
+            }
+        })
+        .await;
+        (result, client.await.unwrap())
+    }
+
+    fn refused(result: Result<()>) -> Option<&'static str> {
+        Some(result.unwrap_err().downcast_ref::<Refused>()?.status)
+    }
+
+    #[tokio::test]
+    async fn test_answers_one_request() {
+        let (result, response) =
+            exchange(b"GET /metrics HTTP/1.1\r\nHost: a\r\n\r\n".to_vec()).await;
+        result.unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 12\r\nConnection: \
+             close\r\n\r\nGET /metrics"
+        );
+        let (result, response) =
+            exchange(b"GET / HTTP/1.1\r\nACCEPT:  text/html \r\n\r\n".to_vec()).await;
+        result.unwrap();
+        assert!(response.ends_with("\r\n\r\nGET / text/html"));
+
+        let mut oversized = b"GET / HTTP/1.1\r\nHost: ".to_vec();
+        oversized.resize(MAX_HEAD * 2, b'a');
+        let (result, response) = exchange(oversized).await;
+        assert_eq!(refused(result), Some("431 Request Header Fields Too Large"));
+        assert_eq!(response, "");
+
+        let body = b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\na".to_vec();
+        assert_eq!(
+            refused(exchange(body).await.0),
+            Some("413 Payload Too Large")
+        );
+        let length = b"POST / HTTP/1.1\r\nContent-Length: a\r\n\r\n".to_vec();
+        assert_eq!(refused(exchange(length).await.0), Some("400 Bad Request"));
+        let line = b"GET\r\n\r\n".to_vec();
+        assert_eq!(refused(exchange(line).await.0), Some("400 Bad Request"));
+    }
+}
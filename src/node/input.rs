@@ -0,0 +1,127 @@
+//! Lines of stdin, read off the runtime.
+//!
+//! `tokio::io::stdin` reads on a blocking thread of the runtime, and the
+//! runtime waits for its blocking threads when it shuts down, so a node with
+//! a console that stopped for any other reason than the end of its input
+//! hung until another line came. [`Lines`] reads on a thread of its own,
+//! which the process does not wait for, and hands the lines over a channel
+//! of [`BUFFER`] lines, so a fast pipe waits for the console to take them
+//! instead of filling memory. The end of the input is the end of the lines,
+//! and lines that are not UTF-8, as a pipe may carry, are read lossily
+//! rather than failing. Cancelling or dropping [`Lines`] stops the reader
+//! at its next line without taking it.
+//!
+//! [`Lines::next_line`] may be dropped before it completes, as in a
+//! `tokio::select!`, without losing a line.
+
+use crate::prelude::*;
+use futures::{channel::mpsc, executor::block_on, stream::Fuse};
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// Lines read ahead at most.
+pub const BUFFER: usize = 64;
+
+pub struct Lines {
+    receiver:  Fuse<mpsc::Receiver<io::Result<String>>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Lines {
+    /// Read the lines of stdin.
+    pub fn stdin() -> io::Result<Self> {
+        Self::spawn(io::stdin())
+    }
+
+    /// Read the lines of `reader` on a thread of their own.
+    pub fn spawn<R: Read + Send + 'static>(reader: R) -> io::Result<Self> {
+        let (mut sender, receiver) = mpsc::channel(BUFFER);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = cancelled.clone();
+        thread::Builder::new()
+            .name("mesh-input".into())
+            .spawn(move || {
+                let mut reader = BufReader::new(reader);
+                let mut line = Vec::new();
+                while !cancel.load(Ordering::Relaxed) {
+                    line.clear();
+                    let result = match reader.read_until(b'\n', &mut line) {
+                        Ok(0) => break,
+                        Ok(_) => Ok(decode(&line)),
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => Err(err),
+                    };
+                    let failed = result.is_err();
+                    if cancel.load(Ordering::Relaxed) || block_on(sender.send(result)).is_err() {
+                        break;
+                    }
+                    if failed {
+                        break;
+                    }
+                }
+                trace!("Stopped reading input");
+            })?;
+        Ok(Self {
+            // Keeps returning `None` at the end, where the receiver panics
+            receiver: receiver.fuse(),
+            cancelled,
+        })
+    }
+
+    /// The next line, without its line ending, or `None` at the end of the
+    /// input.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        self.receiver.next().await.transpose()
+    }
+
+    /// Stop reading, dropping the lines read ahead.
+    pub fn cancel(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.receiver.get_mut().close();
+    }
+}
+
+impl Drop for Lines {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn decode(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_reads_piped_lines() {
+        let input = Cursor::new(b"hello\r\nb\xffd\n\nlast".to_vec());
+        let mut lines = Lines::spawn(input).unwrap();
+        assert_eq!(lines.next_line().await.unwrap(), Some("hello".into()));
+        assert_eq!(lines.next_line().await.unwrap(), Some("b\u{fffd}d".into()));
+        assert_eq!(lines.next_line().await.unwrap(), Some("".into()));
+        assert_eq!(lines.next_line().await.unwrap(), Some("last".into()));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        let mut cancelled = Lines::spawn(Cursor::new(b"a\nb\n".to_vec())).unwrap();
+        cancelled.cancel();
+        let mut left = 0;
+        while cancelled.next_line().await.unwrap().is_some() {
+            left += 1;
+        }
+        assert!(left <= 2);
+    }
+}
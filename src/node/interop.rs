@@ -0,0 +1,171 @@
+//! Pubsub with a go-libp2p peer, built with the `interop` feature.
+//!
+//! `cargo test --features interop --test interop` builds the reference peer
+//! in [`PEER_DIR`] into the Docker image [`IMAGE`] and runs it on the host
+//! network, listening on a port of `127.0.0.1`. A node in
+//! [`Compat::Libp2p`] mode dials it, and both join [`TOPIC`]. The peer
+//! publishes [`GO_MESSAGE`] every second and prints the messages of others,
+//! the node publishes [`RUST_MESSAGE`] every second, and the run passes once
+//! each received the message of the other, within [`TIMEOUT`]. The first
+//! build fetches the Go modules, so it needs network access; the container
+//! is removed when the run ends.
+
+use super::{
+    compat::Compat, discovery, input::Lines, ready::Criteria, Event, Node, NodeHandle, TopicOptions,
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::Multiaddr;
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+use tokio::time::{interval, timeout};
+
+/// Image the reference peer is built into.
+pub const IMAGE: &str = "mesh-rs-interop-go";
+
+/// Sources of the reference peer, relative to the package.
+pub const PEER_DIR: &str = "test/interop/go-peer";
+
+pub const TOPIC: &str = "mesh-rs/interop";
+
+/// What the reference peer publishes.
+pub const GO_MESSAGE: &[u8] = b"hello from go-libp2p";
+
+/// What the node publishes.
+pub const RUST_MESSAGE: &[u8] = b"hello from mesh-rs";
+
+/// How long a run may take, the image build excluded.
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Run `docker` with `args` and wait for it to succeed.
+fn docker(args: &[&str]) -> Result<()> {
+    let status = Command::new("docker")
+        .args(args)
+        .status()
+        .context("Running docker")?;
+    ensure!(status.success(), "docker {} failed with {}", args.join(" "), status);
+    Ok(())
+}
+
+/// A running container, removed on drop.
+struct Container(String);
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let removed = Command::new("docker")
+            .args(&["rm", "--force", &self.0])
+            .stdout(Stdio::null())
+            .status();
+        if let Err(err) = removed {
+            warn!("Container {} not removed: {}", self.0, err);
+        }
+    }
+}
+
+/// Exchange messages with the reference peer, building it from the sources
+/// in `package`.
+pub async fn go_pubsub(package: &Path) -> Result<()> {
+    let dir = package.join(PEER_DIR);
+    let dir = dir
+        .to_str()
+        .ok_or_else(|| anyhow!("Path {} is not UTF-8", dir.display()))?
+        .to_owned();
+    tokio::task::spawn_blocking(move || docker(&["build", "--tag", IMAGE, &dir]))
+        .await
+        .context("Building the go-libp2p peer")??;
+
+    let name = format!("mesh-rs-interop-{}", std::process::id());
+    let mut child = Command::new("docker")
+        .args(&["run", "--rm", "--network", "host", "--name", &name, IMAGE])
+        .args(&["-topic", TOPIC])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Starting the go-libp2p peer")?;
+    let container = Container(name);
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("No output of the go-libp2p peer"))?;
+    let mut peer = Lines::spawn(stdout)?;
+
+    let result = timeout(TIMEOUT, async {
+        let address = loop {
+            match peer.next_line().await? {
+                Some(line) if line.starts_with("LISTEN ") => {
+                    let address = &line["LISTEN ".len()..];
+                    break address
+                        .parse::<Multiaddr>()
+                        .with_context(|| format!("Invalid address {}", address))?;
+                }
+                Some(line) => debug!("go-libp2p: {}", line),
+                None => bail!("The go-libp2p peer exited"),
+            }
+        };
+        info!("go-libp2p peer listens on {}", address);
+
+        let discovery = "mdns=false bootnodes=false".parse::<discovery::Config>()?;
+        let mut node = Node::builder()
+            .with_compat(Compat::Libp2p)
+            .with_discovery(discovery)
+            .with_listen_addr("/ip4/127.0.0.1/tcp/0".parse()?)
+            .build()
+            .await?;
+        let handle = node.handle();
+        tokio::select! {
+            result = node.run() => result.and_then(|()| Err(anyhow!("Node stopped"))),
+            result = exchange(handle, address, &mut peer) => result,
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("Interop took over {:?}", TIMEOUT))
+    .and_then(|result| result);
+
+    drop(container);
+    let _ = child.wait();
+    result
+}
+
+/// Publish to the reference peer at `address`, whose output is `peer`, until
+/// each side received the message of the other.
+async fn exchange(mut handle: NodeHandle, address: Multiaddr, peer: &mut Lines) -> Result<()> {
+    handle.subscribe(TOPIC, TopicOptions::default()).await?;
+    let mut events = handle.events().await?;
+    handle.dial(address).await?;
+    handle.wait_ready(Criteria::default().topic(TOPIC, 1)).await?;
+    info!("Meshed with the go-libp2p peer on {}", TOPIC);
+
+    let expected = String::from_utf8_lossy(RUST_MESSAGE).into_owned();
+    let mut ticks = interval(Duration::from_secs(1));
+    let (mut received, mut delivered) = (false, false);
+    while !(received && delivered) {
+        tokio::select! {
+            _ = ticks.tick() => {
+                if !delivered {
+                    if let Err(err) = handle.publish(TOPIC, RUST_MESSAGE).await {
+                        debug!("Publishing to the go-libp2p peer: {:#}", err);
+                    }
+                }
+            }
+            line = peer.next_line() => match line? {
+                Some(line) if line.starts_with("RECEIVED ") && line.ends_with(&expected) => {
+                    info!("go-libp2p peer received {}", expected);
+                    delivered = true;
+                }
+                Some(line) => debug!("go-libp2p: {}", line),
+                None => bail!("The go-libp2p peer exited"),
+            },
+            event = events.next() => match event {
+                Some(Event::Message { topic, data, .. }) if topic == TOPIC => {
+                    ensure!(data == GO_MESSAGE, "Received {:?} from the go-libp2p peer", data);
+                    received = true;
+                }
+                Some(_) => {}
+                None => bail!("Node stopped"),
+            },
+        }
+    }
+    Ok(())
+}
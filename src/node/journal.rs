@@ -0,0 +1,192 @@
+//! Binary journal of delivered messages.
+//!
+//! Started with `--journal "path=<file>"`, the node appends every message it
+//! delivers to consumers, in a [`rolling`] file with the same options as the
+//! log file. Each [`Entry`] is CBOR, prefixed with its length as a 32 bit
+//! big-endian integer. [`read`] replays a journal, compressed or not, and
+//! `mesh journal <file>` prints one.
+
+use super::{names, rolling, Event};
+use crate::prelude::*;
+use anyhow::bail;
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Largest entry [`read`] accepts.
+const MAX_ENTRY: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch at delivery.
+    pub time_ms: u64,
+    pub source:  String,
+    pub topic:   String,
+    #[serde(with = "serde_bytes")]
+    pub data:    Vec<u8>,
+    pub direct:  bool,
+}
+
+pub struct Journal {
+    file: rolling::RollingFile,
+}
+
+impl Journal {
+    pub fn open(config: rolling::Config) -> Result<Self> {
+        info!("Journaling delivered messages to {}", config.path.display());
+        Ok(Self {
+            file: rolling::RollingFile::open(config)?,
+        })
+    }
+
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        let (source, topic, data, direct) = match event {
+            Event::Message {
+                source,
+                topic,
+                data,
+                direct,
+                ..
+            } => (source, topic, data, direct),
+            Event::Historical { .. }
+            | Event::DirectMessage { .. }
+            | Event::Scheduled { .. }
+            | Event::Announced { .. }
+            | Event::DuplicateClosed { .. }
+            | Event::ClockJump { .. }
+            | Event::SubsystemDegraded { .. }
+            | Event::BundleEvicted(_)
+            | Event::NegotiationFailed { .. }
+            | Event::DialFailed { .. }
+            | Event::IdentityMismatch { .. }
+            | Event::IdentityRotated { .. }
+            | Event::PresenceJoined { .. }
+            | Event::PresenceLeft { .. }
+            | Event::StateChanged { .. }
+            | Event::Bootstrapped { .. }
+            | Event::PeerDiscovered { .. }
+            | Event::PeerExpired { .. }
+            | Event::PeerUnresponsive { .. }
+            | Event::ConnectionReaped { .. }
+            | Event::NatStatusChanged { .. }
+            | Event::RendezvousDiscovered { .. }
+            | Event::FileTransfer(_)
+            | Event::Subscribed { .. }
+            | Event::Unsubscribed { .. }
+            | Event::ListenAddr { .. }
+            | Event::ListenAddrExpired { .. }
+            | Event::DhtQuery { .. } => return Ok(()),
+        };
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let entry = Entry {
+            time_ms: since_epoch.as_millis() as u64,
+            source:  source.to_base58(),
+            topic:   topic.clone(),
+            data:    data.clone(),
+            direct:  *direct,
+        };
+        let cbor = serde_cbor::to_vec(&entry)?;
+        let mut record = Vec::with_capacity(4 + cbor.len());
+        record.extend_from_slice(&(cbor.len() as u32).to_be_bytes());
+        record.extend_from_slice(&cbor);
+        self.file.write_all(&record).context("Writing journal")?;
+        Ok(())
+    }
+}
+
+/// Read all entries from `reader`. A truncated last entry, as left by a
+/// crash, is ignored.
+pub fn read_from(mut reader: impl Read) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    loop {
+        let mut len = [0_u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_ENTRY {
+            bail!("Journal entry of {} bytes is too large", len);
+        }
+        let mut cbor = vec![0; len];
+        match reader.read_exact(&mut cbor) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        entries.push(serde_cbor::from_slice(&cbor).context("Invalid journal entry")?);
+    }
+    Ok(entries)
+}
+
+/// Read all entries from the journal at `path`, decompressing `.gz` files.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    if matches!(path.extension(), Some(extension) if extension == "gz") {
+        read_from(GzDecoder::new(file))
+    } else {
+        read_from(file)
+    }
+}
+
+/// Print the journal at `path`, one entry per line.
+pub fn print(path: &Path) -> Result<()> {
+    for entry in read(path)? {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(entry.time_ms);
+        println!(
+            "{} {} {}{} {}",
+            humantime::format_rfc3339_millis(time),
+            entry.topic,
+            names::rewrite(&entry.source),
+            if entry.direct { " (direct)" } else { "" },
+            hex::encode(&entry.data)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::Provenance, test::prelude::assert_eq};
+    use libp2p::PeerId;
+
+    #[test]
+    fn test_replays_entries() {
+        let dir = std::env::temp_dir().join(format!("mesh-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("journal");
+        let mut journal = Journal::open(format!("path={}", path.display()).parse().unwrap())
+            .unwrap();
+        let source = PeerId::random();
+        for index in 0..3_u8 {
+            journal
+                .append(&Event::Message {
+                    source:     source.clone(),
+                    topic:      "chat".into(),
+                    data:       vec![index],
+                    direct:     index == 2,
+                    timestamp:  None,
+                    provenance: Provenance::default(),
+                    signed:     false,
+                })
+                .unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[0, 0, 1]);
+        let entries = read_from(&bytes[..]).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].data, vec![2]);
+        assert!(entries[2].direct);
+        assert_eq!(entries[0].source, source.to_base58());
+        assert_eq!(read(&path).unwrap(), entries);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
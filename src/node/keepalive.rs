@@ -0,0 +1,183 @@
+//! Keepalives for connections through carrier NATs.
+//!
+//! Mobile carriers drop idle NAT mappings after as little as 30 seconds,
+//! without telling either side, so a quiet connection is silently dead when
+//! traffic resumes. With `--keepalive "interval=25s failures=2 peers=critical"`
+//! the node sends a small probe to each selected peer every `interval` it is
+//! connected, which keeps the mapping open. Once `failures` probes in a row
+//! went unanswered, the node closes and redials the connection instead of
+//! waiting for it to time out.
+//!
+//! `peers=critical`, the default, only probes the `--critical` peers,
+//! `peers=all` probes every connected peer.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The peers to probe.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Peers {
+    Critical,
+    All,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Time between probes, below the NAT timeout.
+    pub interval: Duration,
+    /// Unanswered probes in a row after which the connection is redialed.
+    pub failures: u32,
+    pub peers:    Peers,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(25),
+            failures: 2,
+            peers:    Peers::Critical,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "interval" => {
+                    config.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid interval {}", value))?;
+                }
+                "failures" => {
+                    config.failures = value
+                        .parse()
+                        .with_context(|| format!("Invalid failures {}", value))?;
+                }
+                "peers" => {
+                    config.peers = match value {
+                        "critical" => Peers::Critical,
+                        "all" => Peers::All,
+                        _ => bail!("Unknown keepalive peers {}", value),
+                    };
+                }
+                _ => bail!("Unknown keepalive option {}", key),
+            }
+        }
+        ensure!(
+            config.interval > Duration::from_secs(0),
+            "Keepalive interval must be positive"
+        );
+        ensure!(config.failures > 0, "Keepalive failures must be positive");
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Probed {
+    last:     Instant,
+    failures: u32,
+}
+
+/// When each peer is due for a probe.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    config: Config,
+    peers:  HashMap<PeerId, Probed>,
+}
+
+impl Schedule {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The `connected` peers to probe at `now`, which are then considered
+    /// probed. Peers no longer connected are forgotten.
+    pub fn due(&mut self, now: Instant, connected: &[PeerId]) -> Vec<PeerId> {
+        self.peers.retain(|peer_id, _| connected.contains(peer_id));
+        let interval = self.config.interval;
+        let mut due = Vec::new();
+        for peer_id in connected {
+            let probed = self.peers.entry(peer_id.clone()).or_insert(Probed {
+                last:     now,
+                failures: 0,
+            });
+            if now.saturating_duration_since(probed.last) >= interval {
+                probed.last = now;
+                due.push(peer_id.clone());
+            }
+        }
+        due
+    }
+
+    pub fn answered(&mut self, peer_id: &PeerId) {
+        if let Some(probed) = self.peers.get_mut(peer_id) {
+            probed.failures = 0;
+        }
+    }
+
+    /// Count a failed probe. Returns true if the connection to `peer_id` is
+    /// considered dead.
+    pub fn failed(&mut self, peer_id: &PeerId) -> bool {
+        let probed = match self.peers.get_mut(peer_id) {
+            Some(probed) => probed,
+            None => return false,
+        };
+        probed.failures += 1;
+        if probed.failures < self.config.failures {
+            return false;
+        }
+        self.peers.remove(peer_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_schedules_probes() {
+        let config: Config = "interval=30s failures=2 peers=all".parse().unwrap();
+        assert_eq!(config.peers, Peers::All);
+        assert!("failures=0".parse::<Config>().is_err());
+        assert!("peers=some".parse::<Config>().is_err());
+
+        let mut schedule = Schedule::new(config);
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let both = [first.clone(), second.clone()];
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+        // Newly connected peers are probed one interval later
+        assert_eq!(schedule.due(start, &both), vec![]);
+        assert_eq!(schedule.due(after(29), &both), vec![]);
+        assert_eq!(schedule.due(after(30), &both), both.to_vec());
+        assert_eq!(schedule.due(after(31), &both), vec![]);
+
+        assert!(!schedule.failed(&first));
+        schedule.answered(&first);
+        assert!(!schedule.failed(&first));
+        assert!(schedule.failed(&first));
+        // Redialed, the peer starts over
+        assert_eq!(schedule.due(after(60), &both), vec![second.clone()]);
+        assert_eq!(schedule.due(after(90), std::slice::from_ref(&second)), vec![second]);
+    }
+}
@@ -0,0 +1,503 @@
+//! Keys for encrypted topics and their rotation.
+//!
+//! Payloads on topics subscribed with [`super::TopicOptions::encrypted`] are
+//! sealed with a topic key, starting from a key shared out of band and set
+//! with [`super::NodeHandle::set_topic_key`]. That call also names the topic
+//! admin, the only peer whose rotations are accepted.
+//!
+//! The admin rotates the key with [`super::NodeHandle::rotate_topic_key`]: it
+//! draws a fresh key and publishes a [`Rotation`] on [`topic`] with the key
+//! wrapped to the identity key of each member, so only those members learn
+//! it. Leaving a peer out of the member list removes it from the topic. The
+//! admin repeats its latest rotation every [`ANNOUNCE_INTERVAL`] for members
+//! that were offline.
+//!
+//! Publishers switch to a new key as soon as they receive it. The keys it
+//! replaces keep decrypting for [`OVERLAP`], so messages that were in flight
+//! during the rotation are not lost.
+//!
+//! Keys are wrapped with an X25519 exchange between an ephemeral key and the
+//! member's Ed25519 identity key, which is read from its peer id.
+//!
+//! `--topic-key chat=chat.key` reads a pre-shared key of 64 hex digits from
+//! a file, as printed by `mesh keygen --topic`, with the node itself as the
+//! admin. Each key has a [`fingerprint`] that is safe to show, so operators
+//! can check that nodes hold the same key without comparing the keys.
+
+use super::signing::{Domain, Layout};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key as CipherKey, XChaCha20Poly1305, XNonce,
+};
+use libp2p::{identity, multihash::Multihash, noise, PeerId};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// How long a replaced key still decrypts.
+pub const OVERLAP: Duration = Duration::from_secs(300);
+
+/// Interval at which the admin repeats its latest rotation.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A symmetric topic key.
+pub type Key = [u8; 32];
+
+/// Topic on which key rotations for `topic` are published.
+pub fn topic(topic: &str) -> String {
+    format!("/mesh-rs/keys/{}/version/1", topic)
+}
+
+/// A short name of `key` that does not reveal it.
+pub fn fingerprint(key: &Key) -> String {
+    let digest = Sha256::new()
+        .chain(b"mesh-rs key fingerprint")
+        .chain(key)
+        .finalize();
+    hex::encode(&digest[..8])
+}
+
+/// Parse a key of 64 hex digits.
+pub fn parse_key(s: &str) -> Result<Key> {
+    let bytes = hex::decode(s.trim()).context("Topic key is not hex")?;
+    if bytes.len() != 32 {
+        bail!("Topic key of {} bytes, expected 32", bytes.len());
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// A pre-shared key for a topic, kept in a file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyFile {
+    pub topic: String,
+    pub path:  PathBuf,
+}
+
+impl FromStr for KeyFile {
+    type Err = anyhow::Error;
+
+    /// Parse `<topic>=<path>`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.rfind('=') {
+            Some(index) if index > 0 && index + 1 < s.len() => {
+                Ok(Self {
+                    topic: s[..index].into(),
+                    path:  s[index + 1..].into(),
+                })
+            }
+            _ => bail!("Expected <topic>=<key file>, got {}", s),
+        }
+    }
+}
+
+impl KeyFile {
+    pub fn load(&self) -> Result<Key> {
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("Reading topic key {}", self.path.display()))?;
+        parse_key(&text).with_context(|| format!("Parsing topic key {}", self.path.display()))
+    }
+}
+
+/// A payload sealed with a topic key.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Sealed {
+    pub generation: u32,
+    pub nonce:      ByteBuf,
+    pub ciphertext: ByteBuf,
+}
+
+/// A new topic key, wrapped to each member.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Rotation {
+    pub generation: u32,
+    /// Public half of the ephemeral X25519 key the wraps are derived from.
+    pub ephemeral:  ByteBuf,
+    /// The key sealed to each member, by base58 peer id.
+    pub wrapped:    BTreeMap<String, ByteBuf>,
+}
+
+#[derive(Clone)]
+struct Generation {
+    key:    Key,
+    /// When this key stops decrypting, once it has been replaced.
+    retire: Option<Instant>,
+}
+
+#[derive(Clone)]
+struct TopicKeys {
+    admin:       PeerId,
+    generations: BTreeMap<u32, Generation>,
+    /// Our latest rotation and when to repeat it, if we are the admin.
+    announce:    Option<(Rotation, Instant)>,
+}
+
+impl TopicKeys {
+    fn current(&self) -> Option<(u32, &Key)> {
+        self.generations
+            .iter()
+            .next_back()
+            .map(|(generation, entry)| (*generation, &entry.key))
+    }
+
+    /// Make `key` the current key, retiring the others after the overlap.
+    fn install(&mut self, generation: u32, key: Key, now: Instant) {
+        for entry in self.generations.values_mut() {
+            entry.retire.get_or_insert(now + OVERLAP);
+        }
+        self.generations
+            .insert(generation, Generation { key, retire: None });
+    }
+}
+
+/// The keys of all encrypted topics.
+#[derive(Clone)]
+pub struct Keyring {
+    local_peer_id: PeerId,
+    /// Our identity key as an X25519 secret, to unwrap keys sent to us.
+    secret:        Option<[u8; 32]>,
+    topics:        HashMap<String, TopicKeys>,
+}
+
+/// The identity key inlined in `peer_id`. Hashed peer ids do not contain
+/// their key.
+pub fn public_key(peer_id: &PeerId) -> Option<identity::PublicKey> {
+    let multihash = Multihash::from_bytes(peer_id.as_bytes()).ok()?;
+    let public = identity::PublicKey::from_protobuf_encoding(multihash.digest()).ok()?;
+    if PeerId::from(public.clone()) != *peer_id {
+        return None;
+    }
+    Some(public)
+}
+
+/// The X25519 public key for the Ed25519 identity key inlined in `peer_id`.
+fn exchange_key(peer_id: &PeerId) -> Option<[u8; 32]> {
+    match public_key(peer_id)? {
+        identity::PublicKey::Ed25519(public) => {
+            let mut key = [0; 32];
+            key.copy_from_slice(noise::PublicKey::<noise::X25519>::from_ed25519(&public).as_ref());
+            Some(key)
+        }
+        _ => None,
+    }
+}
+
+/// What sealing a payload of `topic` with key `generation` authenticates.
+fn payload_aad(topic: &str, generation: u32) -> Vec<u8> {
+    Layout::new(Domain::Payload)
+        .topic(topic)
+        .number("generation", generation.into())
+        .to_bytes()
+}
+
+/// What wrapping key `generation` of `topic` to `member` authenticates.
+fn wrap_aad(topic: &str, generation: u32, member: &PeerId) -> Vec<u8> {
+    Layout::new(Domain::KeyWrap)
+        .topic(topic)
+        .number("generation", generation.into())
+        .header("member", member.as_bytes())
+        .to_bytes()
+}
+
+/// The cipher wrapping a key to `recipient`.
+fn wrapping(shared: &[u8; 32], ephemeral: &[u8], recipient: &[u8; 32]) -> XChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain(b"mesh-rs key wrap")
+        .chain(shared)
+        .chain(ephemeral)
+        .chain(recipient)
+        .finalize();
+    XChaCha20Poly1305::new(CipherKey::from_slice(&key))
+}
+
+impl Keyring {
+    pub fn new(keypair: &identity::Keypair) -> Self {
+        let secret = match keypair {
+            identity::Keypair::Ed25519(keypair) => {
+                let secret = noise::SecretKey::<noise::X25519>::from_ed25519(&keypair.secret());
+                let mut bytes = [0; 32];
+                bytes.copy_from_slice(secret.as_ref());
+                Some(bytes)
+            }
+            _ => None,
+        };
+        Self {
+            local_peer_id: PeerId::from(keypair.public()),
+            secret,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Use the pre-shared `key` for `topic`, accepting rotations from
+    /// `admin`. Replaces any keys the topic had.
+    pub fn set_key(&mut self, topic: &str, key: Key, admin: PeerId) {
+        let mut generations = BTreeMap::new();
+        generations.insert(0, Generation { key, retire: None });
+        self.topics.insert(topic.into(), TopicKeys {
+            admin,
+            generations,
+            announce: None,
+        });
+    }
+
+    /// Wait for the first key of `topic` from `admin`, unless we have one.
+    pub fn expect_key(&mut self, topic: &str, admin: PeerId) {
+        self.topics.entry(topic.into()).or_insert(TopicKeys {
+            admin,
+            generations: BTreeMap::new(),
+            announce: None,
+        });
+    }
+
+    pub fn has_key(&self, topic: &str) -> bool {
+        self.topics.contains_key(topic)
+    }
+
+    /// The generation and [`fingerprint`] of the current key of `topic`.
+    pub fn fingerprint(&self, topic: &str) -> Option<(u32, String)> {
+        let (generation, key) = self.topics.get(topic)?.current()?;
+        Some((generation, fingerprint(key)))
+    }
+
+    /// The topic whose rotations are published on `key_topic`, if any.
+    pub fn rotated_topic(&self, key_topic: &str) -> Option<String> {
+        self.topics
+            .keys()
+            .find(|name| topic(name) == key_topic)
+            .cloned()
+    }
+
+    /// Seal `data` with the current key of `topic`.
+    pub fn seal(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let (generation, key) = self
+            .topics
+            .get(topic)
+            .and_then(TopicKeys::current)
+            .ok_or_else(|| anyhow!("No key for encrypted topic {}", topic))?;
+        let nonce: [u8; 24] = rand::random();
+        let ciphertext = XChaCha20Poly1305::new(CipherKey::from_slice(key))
+            .encrypt(XNonce::from_slice(&nonce), Payload {
+                msg: data,
+                aad: &payload_aad(topic, generation),
+            })
+            .map_err(|_| anyhow!("Encrypting payload for {}", topic))?;
+        let sealed = Sealed {
+            generation,
+            nonce: ByteBuf::from(nonce.to_vec()),
+            ciphertext: ByteBuf::from(ciphertext),
+        };
+        Ok(serde_cbor::to_vec(&sealed)?)
+    }
+
+    /// Open a payload sealed with any live key of `topic`.
+    pub fn open(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let sealed: Sealed = serde_cbor::from_slice(data).context("Payload is not sealed")?;
+        let entry = self
+            .topics
+            .get(topic)
+            .and_then(|keys| keys.generations.get(&sealed.generation))
+            .ok_or_else(|| anyhow!("No key generation {} for {}", sealed.generation, topic))?;
+        if sealed.nonce.len() != 24 {
+            bail!("Invalid nonce length {}", sealed.nonce.len());
+        }
+        XChaCha20Poly1305::new(CipherKey::from_slice(&entry.key))
+            .decrypt(XNonce::from_slice(&sealed.nonce), Payload {
+                msg: &sealed.ciphertext,
+                aad: &payload_aad(topic, sealed.generation),
+            })
+            .map_err(|_| anyhow!("Payload does not decrypt with key {}", sealed.generation))
+    }
+
+    /// Replace the key of `topic`, which we must be the admin of, with a
+    /// fresh key wrapped to `members`.
+    ///
+    /// Members without an Ed25519 peer id can not receive the key and are
+    /// skipped.
+    pub fn rotate(&mut self, topic: &str, members: &[PeerId], now: Instant) -> Result<Rotation> {
+        let local_peer_id = self.local_peer_id.clone();
+        let keys = self
+            .topics
+            .get_mut(topic)
+            .ok_or_else(|| anyhow!("No key for encrypted topic {}", topic))?;
+        if keys.admin != local_peer_id {
+            bail!("Only admin {} can rotate the key of {}", keys.admin, topic);
+        }
+        let generation = keys.current().map_or(0, |(generation, _)| generation + 1);
+        let key: Key = rand::random();
+        let ephemeral_secret: [u8; 32] = rand::random();
+        let ephemeral = x25519(ephemeral_secret, X25519_BASEPOINT_BYTES);
+        let mut wrapped = BTreeMap::new();
+        for member in members {
+            let recipient = match exchange_key(member) {
+                Some(recipient) => recipient,
+                None => {
+                    warn!("Can not wrap key for {} to {}", topic, member);
+                    continue;
+                }
+            };
+            let shared = x25519(ephemeral_secret, recipient);
+            let sealed = wrapping(&shared, &ephemeral, &recipient)
+                .encrypt(&XNonce::default(), Payload {
+                    msg: &key,
+                    aad: &wrap_aad(topic, generation, member),
+                })
+                .map_err(|_| anyhow!("Wrapping key for {}", member))?;
+            wrapped.insert(member.to_base58(), ByteBuf::from(sealed));
+        }
+        let rotation = Rotation {
+            generation,
+            ephemeral: ByteBuf::from(ephemeral.to_vec()),
+            wrapped,
+        };
+        keys.install(generation, key, now);
+        keys.announce = Some((rotation.clone(), now + ANNOUNCE_INTERVAL));
+        Ok(rotation)
+    }
+
+    /// Accept a rotation of `topic` published by `source`. Returns whether it
+    /// changed the current key.
+    pub fn receive(
+        &mut self,
+        topic: &str,
+        source: &PeerId,
+        rotation: &Rotation,
+        now: Instant,
+    ) -> Result<bool> {
+        let keys = match self.topics.get_mut(topic) {
+            Some(keys) => keys,
+            None => return Ok(false),
+        };
+        if *source != keys.admin {
+            bail!("Rotation of {} from {}, who is not the admin", topic, source);
+        }
+        if matches!(keys.current(), Some((current, _)) if current >= rotation.generation) {
+            return Ok(false);
+        }
+        let wrapped = rotation
+            .wrapped
+            .get(&self.local_peer_id.to_base58())
+            .ok_or_else(|| anyhow!("Key {} of {} is not for us", rotation.generation, topic))?;
+        let secret = self
+            .secret
+            .ok_or_else(|| anyhow!("Our identity key can not receive wrapped keys"))?;
+        if rotation.ephemeral.len() != 32 {
+            bail!("Invalid ephemeral key length {}", rotation.ephemeral.len());
+        }
+        let mut ephemeral = [0; 32];
+        ephemeral.copy_from_slice(&rotation.ephemeral);
+        let recipient = x25519(secret, X25519_BASEPOINT_BYTES);
+        let shared = x25519(secret, ephemeral);
+        let aad = wrap_aad(topic, rotation.generation, &self.local_peer_id);
+        let key = wrapping(&shared, &ephemeral, &recipient)
+            .decrypt(&XNonce::default(), Payload {
+                msg: wrapped,
+                aad: &aad,
+            })
+            .map_err(|_| anyhow!("Key {} of {} does not unwrap", rotation.generation, topic))?;
+        if key.len() != 32 {
+            bail!("Invalid key length {}", key.len());
+        }
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(&key);
+        keys.install(rotation.generation, bytes, now);
+        Ok(true)
+    }
+
+    /// Drop retired keys. Returns the rotations due to be repeated, by
+    /// topic.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, Rotation)> {
+        let mut announcements = Vec::new();
+        for (topic, keys) in &mut self.topics {
+            keys.generations
+                .retain(|_, entry| !matches!(entry.retire, Some(retire) if retire <= now));
+            if let Some((rotation, next)) = &mut keys.announce {
+                if *next <= now {
+                    *next = now + ANNOUNCE_INTERVAL;
+                    announcements.push((topic.clone(), rotation.clone()));
+                }
+            }
+        }
+        announcements
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_parses_keys_and_fingerprints() {
+        let key = [7; 32];
+        assert_eq!(parse_key(&format!("{}\n", hex::encode(key))).unwrap(), key);
+        assert!(parse_key("0707").is_err());
+        assert!(parse_key("not hex").is_err());
+        assert_eq!(fingerprint(&key).len(), 16);
+        assert_eq!(fingerprint(&key), fingerprint(&[7; 32]));
+        assert_ne!(fingerprint(&key), fingerprint(&[8; 32]));
+
+        let file: KeyFile = "/orders=v1=keys/orders.key".parse().unwrap();
+        assert_eq!(file.topic, "/orders=v1");
+        assert_eq!(file.path, PathBuf::from("keys/orders.key"));
+        assert!("chat".parse::<KeyFile>().is_err());
+        assert!("chat=".parse::<KeyFile>().is_err());
+
+        let mut keyring = Keyring::new(&identity::Keypair::generate_ed25519());
+        assert_eq!(keyring.fingerprint("chat"), None);
+        keyring.set_key("chat", key, PeerId::random());
+        assert_eq!(keyring.fingerprint("chat"), Some((0, fingerprint(&key))));
+    }
+
+    #[test]
+    fn test_rotation_keeps_in_flight_messages() {
+        let admin_keys = identity::Keypair::generate_ed25519();
+        let admin = PeerId::from(admin_keys.public());
+        let member_keys = identity::Keypair::generate_ed25519();
+        let member = PeerId::from(member_keys.public());
+        let outsider_keys = identity::Keypair::generate_ed25519();
+        let outsider = PeerId::from(outsider_keys.public());
+        let mut keyrings = [&admin_keys, &member_keys, &outsider_keys]
+            .iter()
+            .map(|keys| {
+                let mut keyring = Keyring::new(keys);
+                keyring.set_key("chat", [7; 32], admin.clone());
+                keyring
+            })
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        let in_flight = keyrings[1].seal("chat", b"hello").unwrap();
+
+        // Members can only rotate through the admin
+        let members = [member];
+        assert!(keyrings[1].rotate("chat", &members, now).is_err());
+        let rotation = keyrings[0].rotate("chat", &members, now).unwrap();
+        assert_eq!(rotation.generation, 1);
+        assert!(keyrings[1].receive("chat", &outsider, &rotation, now).is_err());
+        assert!(keyrings[1].receive("chat", &admin, &rotation, now).unwrap());
+        assert!(!keyrings[1].receive("chat", &admin, &rotation, now).unwrap());
+        assert!(keyrings[2].receive("chat", &admin, &rotation, now).is_err());
+
+        let sealed = keyrings[1].seal("chat", b"rotated").unwrap();
+        assert_eq!(keyrings[0].open("chat", &sealed).unwrap(), b"rotated");
+        assert!(keyrings[2].open("chat", &sealed).is_err());
+        assert!(keyrings[1].open("news", &sealed).is_err());
+
+        // The old key decrypts until the overlap ends
+        assert_eq!(keyrings[0].open("chat", &in_flight).unwrap(), b"hello");
+        let later = now + OVERLAP + Duration::from_secs(1);
+        assert_eq!(keyrings[0].tick(later).len(), 1);
+        assert!(keyrings[0].open("chat", &in_flight).is_err());
+        assert_eq!(keyrings[0].open("chat", &sealed).unwrap(), b"rotated");
+        assert_eq!(keyrings[0].rotated_topic(&topic("chat")), Some("chat".into()));
+    }
+}
@@ -0,0 +1,472 @@
+//! The node identity, kept across restarts.
+//!
+//! The keypair behind our peer id is stored in the file `--identity`, by
+//! default in the [`storage`] of the data directory or else in
+//! `~/.mesh-rs/identity.key`, and an Ed25519 one is generated on the first
+//! start. Other peers thus
+//! recognise the node after a restart, and critical peer and bootstrap
+//! addresses pointing at it stay valid.
+//!
+//! [`rotate`] replaces the key with a new Ed25519 or Secp256k1 one, or with
+//! an imported Ed25519, Secp256k1 or RSA key, and keeps the old one in the
+//! identity until its grace period ends. Meanwhile the node vouches for its new
+//! peer id with the old key, see [`super::rotation`].
+//!
+//! The identity holds the keys sealed with XChaCha20-Poly1305 under a key
+//! derived from the passphrase in `MESH_IDENTITY_PASSPHRASE` by
+//! PBKDF2-HMAC-SHA256 with a random salt. Without a passphrase the key is
+//! derived from the empty one, which only guards against accidental
+//! disclosure; files are created readable by their owner only either way.
+//! Identities of version 1, which held a single Ed25519 keypair, are still
+//! read.
+//!
+//! [`storage`]: super::storage
+
+use super::storage::{Shared, Slot};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key as CipherKey, XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac, NewMac};
+use libp2p::{
+    identity::{ed25519, rsa, secp256k1, Keypair},
+    PeerId,
+};
+use rand::RngCore;
+use serde_bytes::ByteBuf;
+use sha2::Sha256;
+use std::{
+    fmt,
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Storage namespace of the identity.
+pub const NAMESPACE: &str = "keystore";
+
+/// Storage key of the identity, and its file name outside of storage.
+pub const KEY: &str = "identity.key";
+
+/// Environment variable holding the passphrase.
+pub const PASSPHRASE_VAR: &str = "MESH_IDENTITY_PASSPHRASE";
+
+/// How long [`rotate`] keeps the old key by default.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// PBKDF2 iterations for new files.
+const ITERATIONS: u32 = 100_000;
+
+const VERSION: u8 = 2;
+
+/// Where the identity is kept without `--identity`: in `storage`, or else
+/// in the home directory.
+pub fn default_slot(storage: Option<&Shared>) -> Option<Slot> {
+    if let Some(storage) = storage {
+        return Some(Slot::new(storage, NAMESPACE, KEY));
+    }
+    let home = std::env::var_os("HOME")?;
+    Some(Slot::File(PathBuf::from(home).join(".mesh-rs").join(KEY)))
+}
+
+/// The kinds of identity keys.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+    /// Only imported, as libp2p can not generate RSA keys.
+    Rsa,
+}
+
+impl FromStr for KeyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "ed25519" => Self::Ed25519,
+            "secp256k1" => Self::Secp256k1,
+            "rsa" => Self::Rsa,
+            _ => bail!("Unknown key type {}, expected ed25519, secp256k1 or rsa", s),
+        })
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ed25519 => "ed25519",
+            Self::Secp256k1 => "secp256k1",
+            Self::Rsa => "rsa",
+        })
+    }
+}
+
+/// A secret key, as sealed: an Ed25519 keypair, a raw Secp256k1 secret key
+/// or an RSA key in the PKCS#8 DER it was imported in, as libp2p can not
+/// encode RSA keys again.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Secret {
+    kind:  KeyType,
+    bytes: ByteBuf,
+}
+
+impl Secret {
+    fn generate(kind: KeyType) -> Result<Self> {
+        let bytes = match kind {
+            KeyType::Ed25519 => ed25519::Keypair::generate().encode().to_vec(),
+            KeyType::Secp256k1 => secp256k1::SecretKey::generate().to_bytes().to_vec(),
+            KeyType::Rsa => bail!("RSA keys can not be generated, import one"),
+        };
+        Ok(Self {
+            kind,
+            bytes: ByteBuf::from(bytes),
+        })
+    }
+
+    /// Read a key exported elsewhere: an Ed25519 secret key or keypair, a
+    /// raw or DER Secp256k1 secret key, or an RSA key in PKCS#8 DER.
+    fn import(kind: KeyType, mut data: Vec<u8>) -> Result<Self> {
+        let invalid = |err| anyhow!("Invalid {} key: {}", kind, err);
+        let bytes = match kind {
+            KeyType::Ed25519 if data.len() == 32 => {
+                let secret = ed25519::SecretKey::from_bytes(&mut data).map_err(invalid)?;
+                ed25519::Keypair::from(secret).encode().to_vec()
+            }
+            KeyType::Secp256k1 if data.len() == 32 => {
+                secp256k1::SecretKey::from_bytes(&mut data)
+                    .map_err(invalid)?
+                    .to_bytes()
+                    .to_vec()
+            }
+            KeyType::Secp256k1 => {
+                secp256k1::SecretKey::from_der(&mut data)
+                    .map_err(invalid)?
+                    .to_bytes()
+                    .to_vec()
+            }
+            KeyType::Ed25519 | KeyType::Rsa => data,
+        };
+        let secret = Self {
+            kind,
+            bytes: ByteBuf::from(bytes),
+        };
+        secret.keypair()?;
+        Ok(secret)
+    }
+
+    fn keypair(&self) -> Result<Keypair> {
+        let mut bytes = self.bytes.to_vec();
+        match self.kind {
+            KeyType::Ed25519 => ed25519::Keypair::decode(&mut bytes).map(Keypair::Ed25519),
+            KeyType::Secp256k1 => {
+                secp256k1::SecretKey::from_bytes(&mut bytes)
+                    .map(|secret| Keypair::Secp256k1(secret.into()))
+            }
+            KeyType::Rsa => rsa::Keypair::from_pkcs8(&mut bytes).map(Keypair::Rsa),
+        }
+        .map_err(|err| anyhow!("Invalid {} key: {}", self.kind, err))
+    }
+}
+
+/// A replaced key, kept for its grace period.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Retired {
+    secret: Secret,
+    /// Seconds since the Unix epoch when the grace period ends.
+    until:  u64,
+}
+
+/// What a file of [`VERSION`] seals.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Keys {
+    current:  Secret,
+    previous: Option<Retired>,
+}
+
+impl Keys {
+    fn identity(&self, now: SystemTime) -> Result<Identity> {
+        let previous = match &self.previous {
+            Some(retired) if seconds(now) < retired.until => {
+                Some(Previous {
+                    keypair: retired.secret.keypair()?,
+                    kind:    retired.secret.kind,
+                    until:   UNIX_EPOCH + Duration::from_secs(retired.until),
+                })
+            }
+            _ => None,
+        };
+        Ok(Identity {
+            keypair: self.current.keypair()?,
+            kind: self.current.kind,
+            previous,
+        })
+    }
+}
+
+/// The keys of the node.
+#[derive(Clone)]
+pub struct Identity {
+    pub keypair:  Keypair,
+    pub kind:     KeyType,
+    /// The key replaced by the last [`rotate`], until its grace period ends.
+    pub previous: Option<Previous>,
+}
+
+impl Identity {
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from(self.keypair.public())
+    }
+}
+
+/// A replaced key and the end of its grace period.
+#[derive(Clone)]
+pub struct Previous {
+    pub keypair: Keypair,
+    pub kind:    KeyType,
+    pub until:   SystemTime,
+}
+
+/// A sealed keypair, as stored.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Stored {
+    version:    u8,
+    iterations: u32,
+    salt:       ByteBuf,
+    nonce:      ByteBuf,
+    ciphertext: ByteBuf,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// PBKDF2-HMAC-SHA256 with a single output block.
+fn derive(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let prf = || Hmac::<Sha256>::new_varkey(passphrase).expect("HMAC takes keys of any size");
+    let mut mac = prf();
+    mac.update(salt);
+    mac.update(&1_u32.to_be_bytes());
+    let mut block = mac.finalize().into_bytes();
+    let mut key = [0; 32];
+    key.copy_from_slice(&block);
+    for _ in 1..iterations {
+        let mut mac = prf();
+        mac.update(&block);
+        block = mac.finalize().into_bytes();
+        for (k, b) in key.iter_mut().zip(block.iter()) {
+            *k ^= b;
+        }
+    }
+    key
+}
+
+fn cipher(passphrase: &[u8], salt: &[u8], iterations: u32) -> XChaCha20Poly1305 {
+    let key = derive(passphrase, salt, iterations);
+    XChaCha20Poly1305::new(CipherKey::from_slice(&key))
+}
+
+fn seal(keys: &Keys, passphrase: &[u8]) -> Result<Stored> {
+    let mut salt = [0; 16];
+    let mut nonce = [0; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher(passphrase, &salt, ITERATIONS)
+        .encrypt(XNonce::from_slice(&nonce), serde_cbor::to_vec(keys)?.as_ref())
+        .map_err(|_| anyhow!("Encrypting identity"))?;
+    Ok(Stored {
+        version:    VERSION,
+        iterations: ITERATIONS,
+        salt:       ByteBuf::from(salt.to_vec()),
+        nonce:      ByteBuf::from(nonce.to_vec()),
+        ciphertext: ByteBuf::from(ciphertext),
+    })
+}
+
+fn open(stored: &Stored, passphrase: &[u8]) -> Result<Keys> {
+    ensure!(
+        stored.version == 1 || stored.version == VERSION,
+        "Unsupported identity version {}",
+        stored.version
+    );
+    ensure!(stored.nonce.len() == 24, "Invalid identity nonce");
+    let plaintext = cipher(passphrase, &stored.salt, stored.iterations)
+        .decrypt(XNonce::from_slice(&stored.nonce), stored.ciphertext.as_ref())
+        .map_err(|_| anyhow!("Wrong passphrase or damaged identity, see {}", PASSPHRASE_VAR))?;
+    if stored.version == 1 {
+        return Ok(Keys {
+            current:  Secret {
+                kind:  KeyType::Ed25519,
+                bytes: ByteBuf::from(plaintext),
+            },
+            previous: None,
+        });
+    }
+    serde_cbor::from_slice(&plaintext).context("Decoding identity")
+}
+
+/// The keys kept in `slot`, if any.
+fn read(slot: &Slot, passphrase: &[u8]) -> Result<Option<Keys>> {
+    let data = match slot.read()? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let stored: Stored =
+        serde_cbor::from_slice(&data).with_context(|| format!("Parsing identity {}", slot))?;
+    let keys = open(&stored, passphrase).with_context(|| format!("Opening {}", slot))?;
+    Ok(Some(keys))
+}
+
+fn write(slot: &Slot, keys: &Keys, passphrase: &[u8]) -> Result<()> {
+    slot.write(&serde_cbor::to_vec(&seal(keys, passphrase)?)?)?;
+    slot.flush()
+}
+
+/// Load the identity kept in `slot`.
+pub fn load(slot: &Slot, passphrase: &[u8]) -> Result<Identity> {
+    read(slot, passphrase)?
+        .ok_or_else(|| anyhow!("No identity in {}", slot))?
+        .identity(SystemTime::now())
+        .with_context(|| format!("Opening {}", slot))
+}
+
+/// Load the identity kept in `slot`, or generate an Ed25519 one and keep it
+/// there if there is none.
+pub fn load_or_generate(slot: &Slot, passphrase: &[u8]) -> Result<Identity> {
+    if let Some(keys) = read(slot, passphrase)? {
+        return keys
+            .identity(SystemTime::now())
+            .with_context(|| format!("Opening {}", slot));
+    }
+    let keys = Keys {
+        current:  Secret::generate(KeyType::Ed25519)?,
+        previous: None,
+    };
+    write(slot, &keys, passphrase)?;
+    info!("Generated new identity in {}", slot);
+    keys.identity(SystemTime::now())
+}
+
+/// Replace the identity kept in `slot` with a new key of `kind`, or with the
+/// `import`ed one of that kind, and keep the current key for `grace`. A key
+/// kept from an earlier rotation is dropped. Without an identity in `slot`
+/// the new key becomes the first one.
+pub fn rotate(
+    slot: &Slot,
+    kind: KeyType,
+    import: Option<Vec<u8>>,
+    grace: Duration,
+    passphrase: &[u8],
+) -> Result<Identity> {
+    let current = read(slot, passphrase)?.map(|keys| keys.current);
+    let next = match import {
+        Some(data) => Secret::import(kind, data)?,
+        None => Secret::generate(kind)?,
+    };
+    if let Some(current) = &current {
+        ensure!(
+            current.keypair()?.public() != next.keypair()?.public(),
+            "The new key is the current one"
+        );
+    }
+    let now = SystemTime::now();
+    let keys = Keys {
+        current:  next,
+        previous: current.filter(|_| grace > Duration::from_secs(0)).map(|secret| {
+            Retired {
+                secret,
+                until: seconds(now + grace),
+            }
+        }),
+    };
+    write(slot, &keys, passphrase)?;
+    keys.identity(now)
+}
+
+/// The passphrase from [`PASSPHRASE_VAR`], empty if unset.
+pub fn passphrase() -> Vec<u8> {
+    std::env::var_os(PASSPHRASE_VAR).map_or_else(Vec::new, OsStringExt::into_vec)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+    use std::{fs, os::unix::fs::PermissionsExt, sync::Arc};
+
+    #[test]
+    fn test_keeps_identity_across_loads() {
+        // RFC 7914 section 11
+        assert_eq!(
+            hex::encode(derive(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert_eq!(
+            hex::encode(derive(b"password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+        let dir = std::env::temp_dir().join(format!("mesh-keystore-{}", std::process::id()));
+        let path = dir.join(KEY);
+        let _ = fs::remove_dir_all(&dir);
+        let slot = Slot::File(path.clone());
+
+        let generated = load_or_generate(&slot, b"secret").unwrap();
+        let loaded = load_or_generate(&slot, b"secret").unwrap();
+        assert_eq!(loaded.peer_id(), generated.peer_id());
+        assert!(load_or_generate(&slot, b"guess").is_err());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_and_imports_keys() {
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+        assert!(load(&slot, b"").is_err());
+        let hour = Duration::from_secs(3600);
+
+        // A file of version 1 holds a bare Ed25519 keypair
+        let keypair = ed25519::Keypair::generate();
+        let (salt, nonce) = ([1; 16], [2; 24]);
+        let ciphertext = cipher(b"", &salt, 1)
+            .encrypt(XNonce::from_slice(&nonce), keypair.encode().as_ref())
+            .unwrap();
+        let stored = Stored {
+            version:    1,
+            iterations: 1,
+            salt:       ByteBuf::from(salt.to_vec()),
+            nonce:      ByteBuf::from(nonce.to_vec()),
+            ciphertext: ByteBuf::from(ciphertext),
+        };
+        slot.write(&serde_cbor::to_vec(&stored).unwrap()).unwrap();
+        let first = load(&slot, b"").unwrap();
+        assert_eq!(first.peer_id(), PeerId::from(Keypair::Ed25519(keypair).public()));
+
+        // The old key stays for its grace period
+        let rotated = rotate(&slot, KeyType::Secp256k1, None, hour, b"").unwrap();
+        assert_eq!(rotated.kind, KeyType::Secp256k1);
+        let previous = rotated.previous.as_ref().unwrap();
+        assert_eq!(PeerId::from(previous.keypair.public()), first.peer_id());
+        assert!(previous.until > SystemTime::now() + hour - Duration::from_secs(5));
+        let loaded = load(&slot, b"").unwrap();
+        assert_eq!(loaded.peer_id(), rotated.peer_id());
+        assert!(loaded.previous.is_some());
+
+        // Imported keys, replacing the kept one
+        let secret = secp256k1::SecretKey::generate();
+        let imported = Keypair::Secp256k1(secret.clone().into());
+        let data = secret.to_bytes().to_vec();
+        let identity = rotate(&slot, KeyType::Secp256k1, Some(data.clone()), hour, b"").unwrap();
+        assert_eq!(identity.peer_id(), PeerId::from(imported.public()));
+        let previous = identity.previous.unwrap();
+        assert_eq!(PeerId::from(previous.keypair.public()), rotated.peer_id());
+        assert!(rotate(&slot, KeyType::Secp256k1, Some(data), hour, b"").is_err());
+        assert!(rotate(&slot, KeyType::Rsa, Some(vec![1, 2, 3]), hour, b"").is_err());
+        assert!(rotate(&slot, KeyType::Rsa, None, hour, b"").is_err());
+        let identity = rotate(&slot, KeyType::Ed25519, None, Duration::from_secs(0), b"").unwrap();
+        assert!(identity.previous.is_none());
+        assert_eq!("secp256k1".parse::<KeyType>().unwrap(), KeyType::Secp256k1);
+    }
+}
@@ -0,0 +1,115 @@
+//! Round trip times of pings, and evicting peers that stopped answering.
+//!
+//! The node pings each connected peer every `ping_interval=15s` and gives
+//! a ping `ping_timeout=20s` to be answered, both set through
+//! `--discovery` like the DHT settings. [`Latency`] keeps the last
+//! [`WINDOW`] round trip times of each peer for a rolling average, which
+//! [`NodeHandle::peer_info`] returns and the control status shows.
+//!
+//! Once `ping_failures=3` pings in a row failed, the connection is closed
+//! and the peer is evicted: it leaves the DHT routing table and, unless it
+//! is a critical peer, the known peers table, and the node emits
+//! [`Event::PeerUnresponsive`]. It comes back when it is discovered or
+//! connects again.
+//!
+//! [`NodeHandle::peer_info`]: crate::node::NodeHandle::peer_info
+//! [`Event::PeerUnresponsive`]: crate::node::Event::PeerUnresponsive
+
+use std::{collections::VecDeque, num::NonZeroU32, time::Duration};
+
+/// Round trip times averaged.
+pub const WINDOW: usize = 8;
+
+/// Settings of the pings.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub interval: Duration,
+    pub timeout:  Duration,
+    /// Failed pings in a row after which the peer is evicted.
+    pub failures: NonZeroU32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout:  Duration::from_secs(20),
+            failures: NonZeroU32::new(3).expect("3 != 0"),
+        }
+    }
+}
+
+/// Round trip times of the pings of one peer.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Latency {
+    /// The last [`WINDOW`] round trip times, oldest first.
+    samples:  VecDeque<Duration>,
+    /// Pings failed since the last one answered.
+    failures: u32,
+}
+
+impl Latency {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+        self.failures = 0;
+    }
+
+    /// Count a failed ping, returning the failures in a row.
+    pub fn fail(&mut self) -> u32 {
+        self.failures += 1;
+        self.failures
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    /// Average of the last [`WINDOW`] round trip times.
+    pub fn average(&self) -> Option<Duration> {
+        let count = self.samples.len() as u32;
+        if count == 0 {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / count)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_averages_recent_pings() {
+        let ms = Duration::from_millis;
+        let mut latency = Latency::default();
+        assert_eq!(latency.average(), None);
+        latency.record(ms(100));
+        assert_eq!(latency.fail(), 1);
+        assert_eq!(latency.fail(), 2);
+        for _ in 0..WINDOW {
+            latency.record(ms(10));
+        }
+        latency.record(ms(50));
+        assert_eq!(latency.failures(), 0);
+        assert_eq!(latency.last(), Some(ms(50)));
+        assert_eq!(latency.average(), Some(ms(15)));
+        assert_eq!((latency.min(), latency.max()), (Some(ms(10)), Some(ms(50))));
+    }
+}
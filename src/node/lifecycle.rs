@@ -0,0 +1,289 @@
+//! Signed announcements of node lifecycle.
+//!
+//! Nodes publish an [`Announcement`] on [`TOPIC`] once they have peers after
+//! starting, before they shut down, and when a new version takes over
+//! through a [`super::handoff`]. Announcements are signed with the
+//! announcer's identity key and name its agent version. Receivers emit
+//! [`Event::Announced`](crate::node::Event::Announced) for the ones they
+//! accept, so membership, topology and fleet management need no signaling
+//! of their own. They drop announcements that
+//!
+//! * are not signed by the peer that published them,
+//! * are more than [`MAX_SKEW`] off our clock, or not newer than the last
+//!   one of the peer,
+//! * go over [`BURST`] from one peer within [`WINDOW`].
+//!
+//! The node keeps its own announcements to the same limit.
+
+use super::{
+    keyring,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Topic on which announcements are published.
+pub const TOPIC: &str = "/mesh-rs/lifecycle/version/1";
+
+/// How far an announcement's time may be off our clock.
+pub const MAX_SKEW: Duration = Duration::from_secs(300);
+
+/// Most announcements of one peer within [`WINDOW`].
+pub const BURST: usize = 3;
+
+/// Span the [`BURST`] limit applies to.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Longest agent version accepted.
+const MAX_AGENT: usize = 64;
+
+/// What happened to the announcer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Kind {
+    Join,
+    Leave,
+    /// A new version took over, keeping the peer id and sockets.
+    Upgrade,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Join => "join",
+            Self::Leave => "leave",
+            Self::Upgrade => "upgrade",
+        })
+    }
+}
+
+/// A lifecycle change, signed by the peer it happened to.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Announcement {
+    pub kind:      Kind,
+    /// Agent version of the announcer, like `mesh-rs/0.1.0`.
+    pub agent:     String,
+    /// Milliseconds since the Unix epoch on the announcer's clock.
+    pub at_ms:     u64,
+    pub signature: ByteBuf,
+}
+
+impl Announcement {
+    fn signed_bytes(kind: Kind, agent: &str, at_ms: u64) -> Vec<u8> {
+        Layout::new(Domain::Announcement)
+            .timestamp(at_ms)
+            .header("kind", kind.to_string().into_bytes())
+            .header("agent", agent.as_bytes())
+            .to_bytes()
+    }
+
+    /// Whether the announcement is signed by `announcer`.
+    pub fn verify(&self, announcer: &PeerId) -> bool {
+        match keyring::public_key(announcer) {
+            Some(public) => {
+                public.verify(
+                    &Self::signed_bytes(self.kind, &self.agent, self.at_ms),
+                    &self.signature,
+                )
+            }
+            None => false,
+        }
+    }
+}
+
+/// Times of the recent announcements of one peer.
+#[derive(Clone, Debug, Default)]
+struct Limiter(VecDeque<Instant>);
+
+impl Limiter {
+    /// Count an announcement at `now` if it stays within the limit.
+    fn allow(&mut self, now: Instant) -> bool {
+        while matches!(self.0.front(), Some(time) if now.duration_since(*time) >= WINDOW) {
+            self.0.pop_front();
+        }
+        if self.0.len() >= BURST {
+            return false;
+        }
+        self.0.push_back(now);
+        true
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Peer {
+    limiter: Limiter,
+    /// Time of the last accepted announcement.
+    last_ms: u64,
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+pub struct Lifecycle {
+    keypair:  identity::Keypair,
+    agent:    String,
+    own:      Limiter,
+    /// Our announcement waiting for a peer to publish to.
+    pending:  Option<Kind>,
+    /// A successor took over, so we do not leave.
+    replaced: bool,
+    peers:    HashMap<PeerId, Peer>,
+}
+
+impl Lifecycle {
+    pub fn new(keypair: identity::Keypair, agent: &str) -> Self {
+        Self {
+            keypair,
+            agent: agent.to_owned(),
+            own: Limiter::default(),
+            pending: None,
+            replaced: false,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Announce `kind` once there is a peer to publish to, instead of an
+    /// announcement still waiting. Fails beyond the rate limit.
+    pub fn announce(&mut self, kind: Kind) -> Result<()> {
+        self.announce_at(kind, Instant::now())
+    }
+
+    fn announce_at(&mut self, kind: Kind, now: Instant) -> Result<()> {
+        if kind == Kind::Leave && self.replaced {
+            return Ok(());
+        }
+        if !self.own.allow(now) {
+            bail!("Over {} announcements in {:?}", BURST, WINDOW);
+        }
+        self.pending = Some(kind);
+        Ok(())
+    }
+
+    /// Leave it to the successor taking over to announce.
+    pub fn replace(&mut self) {
+        self.replaced = true;
+        self.pending = None;
+    }
+
+    /// Our waiting announcement, signed now.
+    pub fn pending(&self) -> Option<Result<Announcement>> {
+        self.pending.map(|kind| self.sign(kind, SystemTime::now()))
+    }
+
+    /// Our waiting announcement went out.
+    pub fn published(&mut self) {
+        self.pending = None;
+    }
+
+    fn sign(&self, kind: Kind, at: SystemTime) -> Result<Announcement> {
+        let at_ms = epoch_ms(at);
+        let signature = self
+            .keypair
+            .sign(&Announcement::signed_bytes(kind, &self.agent, at_ms))
+            .map_err(|err| anyhow!("Signing announcement: {:?}", err))?;
+        Ok(Announcement {
+            kind,
+            agent: self.agent.clone(),
+            at_ms,
+            signature: ByteBuf::from(signature),
+        })
+    }
+
+    /// Accept an announcement published by `source`.
+    pub fn receive(&mut self, source: &PeerId, announcement: &Announcement) -> Result<()> {
+        self.receive_at(
+            source,
+            announcement,
+            epoch_ms(SystemTime::now()),
+            Instant::now(),
+        )
+    }
+
+    fn receive_at(
+        &mut self,
+        source: &PeerId,
+        announcement: &Announcement,
+        wall_ms: u64,
+        now: Instant,
+    ) -> Result<()> {
+        if announcement.agent.len() > MAX_AGENT {
+            bail!("Agent version over {} bytes", MAX_AGENT);
+        }
+        let skew_ms = announcement.at_ms as i64 - wall_ms as i64;
+        if skew_ms.unsigned_abs() > MAX_SKEW.as_millis() as u64 {
+            bail!("Announcement is {}ms off our clock", skew_ms);
+        }
+        let peer = self.peers.entry(source.clone()).or_default();
+        if announcement.at_ms <= peer.last_ms {
+            bail!("Announcement is not newer than the last one");
+        }
+        if !peer.limiter.allow(now) {
+            bail!("Over {} announcements in {:?}", BURST, WINDOW);
+        }
+        // Checked last, as it is the most expensive
+        if !announcement.verify(source) {
+            bail!("Announcement not signed by {}", source);
+        }
+        peer.last_ms = announcement.at_ms;
+        Ok(())
+    }
+
+    /// Forget peers whose announcements since would not be replays.
+    pub fn tick(&mut self) {
+        let oldest_ms = epoch_ms(SystemTime::now()).saturating_sub(MAX_SKEW.as_millis() as u64);
+        self.peers.retain(|_, peer| peer.last_ms >= oldest_ms);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_validates_and_limits_announcements() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let announcer = PeerId::from(keypair.public());
+        let mut lifecycle = Lifecycle::new(keypair, "mesh-rs/0.1.0");
+        let mut receiver = Lifecycle::new(identity::Keypair::generate_ed25519(), "mesh-rs/0.1.0");
+        let (start, now) = (SystemTime::now(), Instant::now());
+        let at = |seconds| epoch_ms(start + Duration::from_secs(seconds));
+
+        let join = lifecycle.sign(Kind::Join, start).unwrap();
+        assert_eq!(join.agent, "mesh-rs/0.1.0");
+        let other = PeerId::random();
+        assert!(receiver.receive_at(&other, &join, at(0), now).is_err());
+        receiver.receive_at(&announcer, &join, at(0), now).unwrap();
+        // Replayed
+        assert!(receiver.receive_at(&announcer, &join, at(1), now).is_err());
+        let late = lifecycle.sign(Kind::Leave, start + Duration::from_secs(2)).unwrap();
+        assert!(receiver.receive_at(&announcer, &late, at(400), now).is_err());
+
+        // Forgeries count against the limit
+        let mut forged = lifecycle.sign(Kind::Leave, start + Duration::from_secs(1)).unwrap();
+        forged.kind = Kind::Upgrade;
+        for _ in 1..BURST {
+            assert!(receiver.receive_at(&announcer, &forged, at(1), now).is_err());
+        }
+        let upgrade = lifecycle.sign(Kind::Upgrade, start + Duration::from_secs(3)).unwrap();
+        assert!(receiver.receive_at(&announcer, &upgrade, at(3), now).is_err());
+        receiver.receive_at(&announcer, &upgrade, at(3), now + WINDOW).unwrap();
+
+        for _ in 0..BURST {
+            lifecycle.announce_at(Kind::Join, now).unwrap();
+        }
+        assert!(lifecycle.announce_at(Kind::Leave, now).is_err());
+        assert_eq!(lifecycle.pending().unwrap().unwrap().kind, Kind::Join);
+        lifecycle.replace();
+        lifecycle.announce_at(Kind::Leave, now + WINDOW).unwrap();
+        assert!(lifecycle.pending().is_none());
+    }
+}
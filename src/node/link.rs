@@ -0,0 +1,275 @@
+//! Local links.
+//!
+//! A [`LinkTransport`] carries a byte stream to a nearby device over
+//! something other than IP, like [`super::ble`] or [`super::serial`]. [`Link`]
+//! turns it into a libp2p transport for addresses
+//! `/unix/<scheme>:<link address>`, the multiaddr format having no codes for
+//! such links. Connections over a link
+//! are encrypted and multiplexed like TCP connections, and gossip flows over
+//! them the same way: a gateway node connected both over IP and over a link
+//! relays messages between the two meshes.
+//!
+//! Nodes on a link do not discover each other yet. A node listens with
+//! `--link <scheme>:<link address>` and is dialed at that address, for
+//! example as a `--critical` peer or once a gateway shared it through
+//! identify.
+
+use crate::prelude::*;
+use futures::{
+    future::BoxFuture,
+    io::{AsyncRead, AsyncWrite},
+    stream::BoxStream,
+};
+use libp2p::{
+    core::{
+        multiaddr::Protocol,
+        transport::{ListenerEvent, TransportError},
+    },
+    Multiaddr, Transport,
+};
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::unix::AsyncFd;
+
+/// Connections accepted on a link, with the link address of the remote.
+pub type Incoming<S> = BoxStream<'static, io::Result<(S, String)>>;
+
+/// A short range link between devices.
+pub trait LinkTransport: Clone + Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Prefix of the addresses on this link, like `ble`.
+    fn scheme(&self) -> &'static str;
+
+    /// Accept connections at link `address`.
+    fn listen(&self, address: &str) -> io::Result<Incoming<Self::Stream>>;
+
+    /// Connect to link `address`.
+    fn dial(&self, address: &str) -> BoxFuture<'static, io::Result<Self::Stream>>;
+}
+
+/// The multiaddr of `address` on the link `scheme`.
+pub fn to_multiaddr(scheme: &str, address: &str) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Unix(format!("{}:{}", scheme, address).into()))
+}
+
+/// The link address in `address`, if it is on the link `scheme`.
+pub fn link_address(scheme: &str, address: &Multiaddr) -> Option<String> {
+    let mut protocols = address.iter();
+    match (protocols.next()?, protocols.next()) {
+        (Protocol::Unix(path), None) => {
+            path.strip_prefix(scheme)?
+                .strip_prefix(':')
+                .map(Into::into)
+        }
+        _ => None,
+    }
+}
+
+type Upgrade<S> = future::Ready<io::Result<S>>;
+type Listener<S> = BoxStream<'static, io::Result<ListenerEvent<Upgrade<S>, io::Error>>>;
+
+/// A [`LinkTransport`] as a libp2p transport.
+#[derive(Clone, Debug)]
+pub struct Link<T>(pub T);
+
+impl<T: LinkTransport> Transport for Link<T> {
+    type Dial = BoxFuture<'static, io::Result<T::Stream>>;
+    type Error = io::Error;
+    type Listener = Listener<T::Stream>;
+    type ListenerUpgrade = Upgrade<T::Stream>;
+    type Output = T::Stream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let scheme = self.0.scheme();
+        let link_address = match link_address(scheme, &address) {
+            Some(link_address) => link_address,
+            None => return Err(TransportError::MultiaddrNotSupported(address)),
+        };
+        let incoming = self.0.listen(&link_address).map_err(TransportError::Other)?;
+        let local = address.clone();
+        let incoming = incoming.map(move |accepted| {
+            Ok(match accepted {
+                Ok((stream, remote)) => {
+                    ListenerEvent::Upgrade {
+                        upgrade:     future::ok(stream),
+                        local_addr:  local.clone(),
+                        remote_addr: to_multiaddr(scheme, &remote),
+                    }
+                }
+                Err(err) => ListenerEvent::Error(err),
+            })
+        });
+        let announced = stream::once(future::ok(ListenerEvent::NewAddress(address)));
+        Ok(announced.chain(incoming).boxed())
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        match link_address(self.0.scheme(), &address) {
+            Some(link_address) => Ok(self.0.dial(&link_address)),
+            None => Err(TransportError::MultiaddrNotSupported(address)),
+        }
+    }
+}
+
+/// The result of a system call returning -1 on error.
+pub fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn check_len(result: libc::ssize_t) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// A non-blocking file descriptor, closed when dropped.
+#[derive(Debug)]
+pub struct Fd(RawFd);
+
+impl Fd {
+    /// Take over `fd` and make it non-blocking.
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        let owned = Self(fd);
+        let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+        check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+        check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        Ok(owned)
+    }
+}
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A byte stream over a socket or device that can be polled, closed when
+/// dropped.
+#[derive(Debug)]
+pub struct FdStream(AsyncFd<Fd>);
+
+impl FdStream {
+    pub fn new(fd: Fd) -> io::Result<Self> {
+        Ok(Self(AsyncFd::new(fd)?))
+    }
+}
+
+impl AsyncRead for FdStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = futures::ready!(self.0.poll_read_ready(cx))?;
+            let fd = self.0.as_raw_fd();
+            let result = guard.with_io(|| {
+                check_len(unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) })
+            });
+            match result {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for FdStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = futures::ready!(self.0.poll_write_ready(cx))?;
+            let fd = self.0.as_raw_fd();
+            let result = guard.with_io(|| {
+                check_len(unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) })
+            });
+            match result {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use futures::io::Cursor;
+
+    /// A link whose connections carry a fixed greeting.
+    #[derive(Clone, Debug)]
+    struct Echo;
+
+    impl LinkTransport for Echo {
+        type Stream = Cursor<Vec<u8>>;
+
+        fn scheme(&self) -> &'static str {
+            "echo"
+        }
+
+        fn listen(&self, address: &str) -> io::Result<Incoming<Self::Stream>> {
+            let accepted = (Cursor::new(address.as_bytes().to_vec()), "remote".to_owned());
+            Ok(stream::once(future::ok(accepted)).boxed())
+        }
+
+        fn dial(&self, address: &str) -> BoxFuture<'static, io::Result<Self::Stream>> {
+            future::ok(Cursor::new(address.as_bytes().to_vec())).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maps_addresses() {
+        let address = to_multiaddr("echo", "a:b@1");
+        assert_eq!(address.to_string(), "/unix/echo:a:b@1");
+        assert_eq!(link_address("echo", &address), Some("a:b@1".into()));
+        assert_eq!(link_address("ble", &address), None);
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        assert!(matches!(
+            Link(Echo).dial(tcp),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+
+        let dialed = Link(Echo).dial(address.clone()).unwrap().await.unwrap();
+        assert_eq!(dialed.into_inner(), b"a:b@1".to_vec());
+        let mut events = Link(Echo).listen_on(address.clone()).unwrap();
+        match events.next().await {
+            Some(Ok(ListenerEvent::NewAddress(announced))) => assert_eq!(announced, address),
+            _ => panic!("Expected the listening address"),
+        }
+        match events.next().await {
+            Some(Ok(ListenerEvent::Upgrade { remote_addr, .. })) => {
+                assert_eq!(remote_addr, to_multiaddr("echo", "remote"));
+            }
+            _ => panic!("Expected a connection"),
+        }
+    }
+}
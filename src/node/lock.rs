@@ -0,0 +1,298 @@
+//! Distributed locks with lease expiry.
+//!
+//! Nodes that run [`serve`] take part in the election for [`GROUP`]. The
+//! elected leader advertises the [`SERVICE`] service and keeps the lock table;
+//! clients acquire and release locks by calling that service.
+//!
+//! Every grant carries a fencing token that strictly increases for each lock.
+//! Resources guarded by a lock should reject operations carrying a token lower
+//! than the highest one they have seen, so a holder whose lease expired
+//! without noticing can not corrupt state.
+//!
+//! Lock tables are not replicated. A new leader does not know the leases the
+//! previous one granted, so it refuses grants for [`MAX_TTL`] after it was
+//! elected, until they all expired. Its tokens start from the current time in
+//! microseconds, or above the tokens it issued in earlier terms if its clock
+//! runs behind, which keeps tokens increasing across leader changes as long as
+//! clocks are roughly synchronized.
+
+use super::{
+    election::{ElectionConfig, LeadershipChange},
+    NodeHandle, ServiceRequest,
+};
+use crate::prelude::*;
+use futures::channel::mpsc;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Election group of the lock servers.
+pub const GROUP: &str = "locks";
+
+/// Service advertised by the current lock leader.
+pub const SERVICE: &str = "lock";
+
+/// Longest lease granted; longer requests are shortened.
+pub const MAX_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Acquire { name: String, ttl_ms: u64 },
+    Release { name: String, token: u64 },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Granted { token: u64, ttl_ms: u64 },
+    Held { retry_after_ms: u64 },
+    Released,
+    NotHeld,
+}
+
+/// A granted lock.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Lock {
+    pub name:    String,
+    pub token:   u64,
+    pub expires: Instant,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    holder:  PeerId,
+    token:   u64,
+    expires: Instant,
+}
+
+/// The lock table kept by the leader.
+#[derive(Debug)]
+pub struct LockTable {
+    locks:       HashMap<String, Entry>,
+    next_token:  u64,
+    /// Grants are refused until then, while leases of a previous leader may
+    /// still run.
+    grace_until: Option<Instant>,
+}
+
+impl LockTable {
+    pub fn new(first_token: u64) -> Self {
+        Self {
+            locks:       HashMap::new(),
+            next_token:  first_token,
+            grace_until: None,
+        }
+    }
+
+    /// Start a term as leader at `now`: forget the locks of an earlier term,
+    /// refuse grants for [`MAX_TTL`] and issue tokens from `first_token`, or
+    /// above the tokens issued before if higher.
+    pub fn lead(&mut self, first_token: u64, now: Instant) {
+        self.locks.clear();
+        self.next_token = self.next_token.max(first_token);
+        self.grace_until = Some(now + MAX_TTL);
+    }
+
+    pub fn handle(&mut self, holder: &PeerId, request: Request, now: Instant) -> Response {
+        match request {
+            Request::Acquire { name, ttl_ms } => {
+                let ttl = Duration::from_millis(ttl_ms).min(MAX_TTL);
+                self.acquire(name, holder, ttl, now)
+            }
+            Request::Release { name, token } => self.release(&name, token),
+        }
+    }
+
+    /// Grant `name` to `holder` unless someone else holds an unexpired
+    /// lease. A holder can extend its own lease, receiving a new token.
+    pub fn acquire(
+        &mut self,
+        name: String,
+        holder: &PeerId,
+        ttl: Duration,
+        now: Instant,
+    ) -> Response {
+        if let Some(until) = self.grace_until.filter(|until| *until > now) {
+            let retry_after = until.duration_since(now);
+            return Response::Held {
+                retry_after_ms: retry_after.as_millis() as u64,
+            };
+        }
+        if let Some(entry) = self.locks.get(&name) {
+            if entry.expires > now && &entry.holder != holder {
+                let retry_after = entry.expires.duration_since(now);
+                return Response::Held {
+                    retry_after_ms: retry_after.as_millis() as u64,
+                };
+            }
+        }
+        let token = self.next_token;
+        self.next_token += 1;
+        self.locks.insert(name, Entry {
+            holder: holder.clone(),
+            token,
+            expires: now + ttl,
+        });
+        Response::Granted {
+            token,
+            ttl_ms: ttl.as_millis() as u64,
+        }
+    }
+
+    /// Release `name` if `token` is the current grant.
+    pub fn release(&mut self, name: &str, token: u64) -> Response {
+        match self.locks.get(name) {
+            Some(entry) if entry.token == token => {
+                self.locks.remove(name);
+                Response::Released
+            }
+            _ => Response::NotHeld,
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_micros() as u64)
+}
+
+/// Take part in serving locks: lead the lock table while elected.
+///
+/// Runs until the node stops.
+pub async fn serve(mut handle: NodeHandle, config: ElectionConfig) -> Result<()> {
+    let mut changes = handle.join_election(GROUP, config).await?;
+    let mut requests: Option<mpsc::Receiver<ServiceRequest>> = None;
+    let mut table = LockTable::new(now_micros());
+    loop {
+        let step = {
+            let next_request = async {
+                match &mut requests {
+                    Some(requests) => requests.next().await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                change = changes.next() => Step::Change(change),
+                Some(request) = next_request => Step::Request(request),
+            }
+        };
+        match step {
+            Step::Change(None) => return Ok(()),
+            Step::Change(Some(change)) => {
+                match (change.is_leader, requests.is_some()) {
+                    (true, false) => {
+                        info!("Became lock leader in term {}", change.term);
+                        table.lead(now_micros(), Instant::now());
+                        requests = Some(handle.advertise_service(SERVICE).await?);
+                    }
+                    (false, true) => {
+                        info!("Lost lock leadership in term {}", change.term);
+                        handle.withdraw_service(SERVICE).await?;
+                        requests = None;
+                    }
+                    _ => {}
+                }
+            }
+            Step::Request(request) => {
+                let response = serde_cbor::from_slice::<Request>(&request.data)
+                    .map_err(|err| format!("Invalid lock request: {}", err))
+                    .map(|lock_request| {
+                        table.handle(&request.peer_id, lock_request, Instant::now())
+                    })
+                    .and_then(|response| {
+                        serde_cbor::to_vec(&response).map_err(|err| err.to_string())
+                    });
+                request.respond(response);
+            }
+        }
+    }
+}
+
+enum Step {
+    Change(Option<LeadershipChange>),
+    Request(ServiceRequest),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_exclusive_until_expiry() {
+        let now = Instant::now();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let ttl = Duration::from_secs(10);
+        let mut table = LockTable::new(1);
+        assert_eq!(table.acquire("a".into(), &alice, ttl, now), Response::Granted {
+            token:  1,
+            ttl_ms: 10_000,
+        });
+        assert_eq!(
+            table.acquire("a".into(), &bob, ttl, now + Duration::from_secs(4)),
+            Response::Held {
+                retry_after_ms: 6_000,
+            }
+        );
+        assert_eq!(table.acquire("a".into(), &bob, ttl, now + ttl), Response::Granted {
+            token:  2,
+            ttl_ms: 10_000,
+        });
+    }
+
+    #[test]
+    fn test_ttl_is_capped() {
+        let mut table = LockTable::new(1);
+        let request = Request::Acquire {
+            name:   "a".into(),
+            ttl_ms: u64::MAX,
+        };
+        assert_eq!(
+            table.handle(&PeerId::random(), request, Instant::now()),
+            Response::Granted {
+                token:  1,
+                ttl_ms: MAX_TTL.as_millis() as u64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stale_token_can_not_release() {
+        let now = Instant::now();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let ttl = Duration::from_secs(1);
+        let mut table = LockTable::new(7);
+        table.acquire("a".into(), &alice, ttl, now);
+        table.acquire("a".into(), &bob, ttl, now + ttl);
+        assert_eq!(table.release("a", 7), Response::NotHeld);
+        assert_eq!(table.release("a", 8), Response::Released);
+    }
+
+    #[test]
+    fn test_new_term_waits_out_leases() {
+        let now = Instant::now();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let ttl = Duration::from_secs(10);
+        let mut table = LockTable::new(100);
+        table.acquire("a".into(), &alice, ttl, now);
+
+        // Re-elected with a clock running behind
+        table.lead(1, now);
+        assert_eq!(
+            table.acquire("b".into(), &bob, ttl, now + ttl),
+            Response::Held {
+                retry_after_ms: (MAX_TTL - ttl).as_millis() as u64,
+            }
+        );
+        assert_eq!(
+            table.acquire("b".into(), &bob, ttl, now + MAX_TTL),
+            Response::Granted {
+                token:  101,
+                ttl_ms: 10_000,
+            }
+        );
+        assert_eq!(table.release("a", 100), Response::NotHeld);
+    }
+}
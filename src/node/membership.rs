@@ -0,0 +1,282 @@
+//! Membership of private topics.
+//!
+//! A private topic is an encrypted topic whose [`super::keyring`] keys only
+//! go to peers the admin approved. A prospective member asks to join with
+//! [`super::NodeHandle::request_membership`], which publishes a
+//! [`Message::Request`] on [`topic`] every [`REQUEST_INTERVAL`] until it is
+//! granted. The admin lists the requests with
+//! [`super::NodeHandle::pending_members`] and approves one with
+//! [`super::NodeHandle::approve_member`]: it publishes a [`Grant`] signed
+//! with its identity key, and rotates the topic key to all holders of a
+//! grant. Revoking a grant rotates the key to the remaining members.
+//!
+//! Keys are only ever wrapped to grant holders, so approval is what lets a
+//! peer read the topic.
+
+use super::{
+    keyring,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Interval at which an ungranted join request is repeated.
+pub const REQUEST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Topic on which membership of `topic` is requested and granted.
+pub fn topic(topic: &str) -> String {
+    format!("/mesh-rs/members/{}/version/1", topic)
+}
+
+/// Admission of `member` to `topic`, signed by the topic admin.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Grant {
+    pub topic:     String,
+    pub member:    String,
+    pub signature: ByteBuf,
+}
+
+impl Grant {
+    fn signed_bytes(topic: &str, member: &str) -> Vec<u8> {
+        Layout::new(Domain::Grant)
+            .topic(topic)
+            .header("member", member.as_bytes())
+            .to_bytes()
+    }
+
+    /// Whether the grant is signed by `admin`.
+    pub fn verify(&self, admin: &PeerId) -> bool {
+        match keyring::public_key(admin) {
+            Some(public) => {
+                public.verify(
+                    &Self::signed_bytes(&self.topic, &self.member),
+                    &self.signature,
+                )
+            }
+            None => false,
+        }
+    }
+}
+
+/// Published on [`topic`].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Message {
+    /// The publisher asks to join.
+    Request,
+    Grant(Grant),
+}
+
+/// Members and join requests of a topic we administer.
+#[derive(Clone, Debug, Default)]
+struct Roster {
+    pending: BTreeSet<PeerId>,
+    grants:  BTreeMap<PeerId, Grant>,
+}
+
+/// A join request of ours.
+#[derive(Clone, Debug)]
+struct Request {
+    admin:  PeerId,
+    repeat: Instant,
+}
+
+#[derive(Clone)]
+pub struct Membership {
+    keypair:  identity::Keypair,
+    /// Topics we administer.
+    rosters:  HashMap<String, Roster>,
+    /// Our join requests that are not yet granted, by topic.
+    requests: HashMap<String, Request>,
+    /// Grants issued to us, by topic.
+    grants:   HashMap<String, Grant>,
+}
+
+impl Membership {
+    pub fn new(keypair: identity::Keypair) -> Self {
+        Self {
+            keypair,
+            rosters: HashMap::new(),
+            requests: HashMap::new(),
+            grants: HashMap::new(),
+        }
+    }
+
+    /// Accept join requests for `topic`.
+    pub fn administer(&mut self, topic: &str) {
+        self.rosters.entry(topic.into()).or_default();
+    }
+
+    /// Ask `admin` to join `topic`. The request is sent on the next tick.
+    pub fn request(&mut self, topic: &str, admin: PeerId, now: Instant) {
+        self.requests.insert(topic.into(), Request {
+            admin,
+            repeat: now,
+        });
+    }
+
+    /// The topic whose membership is managed on `membership_topic`, if any.
+    pub fn managed_topic(&self, membership_topic: &str) -> Option<String> {
+        self.rosters
+            .keys()
+            .chain(self.requests.keys())
+            .chain(self.grants.keys())
+            .find(|name| topic(name) == membership_topic)
+            .cloned()
+    }
+
+    /// Whether we administer `topic`.
+    pub fn is_admin(&self, topic: &str) -> bool {
+        self.rosters.contains_key(topic)
+    }
+
+    /// Peers waiting for approval to join `topic`.
+    pub fn pending(&self, topic: &str) -> Vec<PeerId> {
+        self.rosters
+            .get(topic)
+            .map(|roster| roster.pending.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Holders of a grant for `topic`.
+    pub fn members(&self, topic: &str) -> Vec<PeerId> {
+        self.rosters
+            .get(topic)
+            .map(|roster| roster.grants.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `peer_id` holds a grant for `topic`.
+    pub fn is_member(&self, topic: &str, peer_id: &PeerId) -> bool {
+        matches!(self.rosters.get(topic), Some(roster) if roster.grants.contains_key(peer_id))
+    }
+
+    /// Our grant for `topic`, if we have one.
+    pub fn grant(&self, topic: &str) -> Option<&Grant> {
+        self.grants.get(topic)
+    }
+
+    /// Admit `member` to `topic`, which we must administer.
+    pub fn approve(&mut self, topic: &str, member: &PeerId) -> Result<Grant> {
+        let roster = self
+            .rosters
+            .get_mut(topic)
+            .ok_or_else(|| anyhow!("Not the admin of {}", topic))?;
+        let member_id = member.to_base58();
+        let signature = self
+            .keypair
+            .sign(&Grant::signed_bytes(topic, &member_id))
+            .map_err(|err| anyhow!("Signing grant: {:?}", err))?;
+        let grant = Grant {
+            topic:     topic.into(),
+            member:    member_id,
+            signature: ByteBuf::from(signature),
+        };
+        roster.pending.remove(member);
+        roster.grants.insert(member.clone(), grant.clone());
+        Ok(grant)
+    }
+
+    /// Drop the grant or join request of `member` for `topic`. Returns
+    /// false if it had neither.
+    pub fn revoke(&mut self, topic: &str, member: &PeerId) -> Result<bool> {
+        let roster = self
+            .rosters
+            .get_mut(topic)
+            .ok_or_else(|| anyhow!("Not the admin of {}", topic))?;
+        let pending = roster.pending.remove(member);
+        Ok(roster.grants.remove(member).is_some() || pending)
+    }
+
+    /// Handle a message on the membership topic of `topic` from `source`.
+    pub fn receive(&mut self, topic: &str, source: &PeerId, message: Message) -> Result<()> {
+        match message {
+            Message::Request => {
+                if let Some(roster) = self.rosters.get_mut(topic) {
+                    if !roster.grants.contains_key(source) && roster.pending.insert(source.clone())
+                    {
+                        info!("{} asks to join {}", source, topic);
+                    }
+                }
+            }
+            Message::Grant(grant) => {
+                let local_peer_id = PeerId::from(self.keypair.public()).to_base58();
+                if grant.member != local_peer_id || grant.topic != topic {
+                    return Ok(());
+                }
+                let admin = match self.requests.get(topic) {
+                    Some(request) => &request.admin,
+                    None => return Ok(()),
+                };
+                if *source != *admin || !grant.verify(admin) {
+                    bail!("Grant for {} is not signed by admin {}", topic, admin);
+                }
+                info!("Admitted to {} by {}", topic, admin);
+                self.requests.remove(topic);
+                self.grants.insert(topic.into(), grant);
+            }
+        }
+        Ok(())
+    }
+
+    /// Topics for which to send our join request now.
+    pub fn tick(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for (topic, request) in &mut self.requests {
+            if request.repeat <= now {
+                request.repeat = now + REQUEST_INTERVAL;
+                due.push(topic.clone());
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_approved_request_grants_membership() {
+        let admin_keys = identity::Keypair::generate_ed25519();
+        let admin = PeerId::from(admin_keys.public());
+        let member_keys = identity::Keypair::generate_ed25519();
+        let member = PeerId::from(member_keys.public());
+        let mut admin_side = Membership::new(admin_keys);
+        let mut member_side = Membership::new(member_keys);
+        let now = Instant::now();
+        admin_side.administer("chat");
+        member_side.request("chat", admin.clone(), now);
+        assert_eq!(member_side.tick(now), vec!["chat".to_owned()]);
+        assert!(member_side.tick(now).is_empty());
+
+        admin_side
+            .receive("chat", &member, Message::Request)
+            .unwrap();
+        assert_eq!(admin_side.pending("chat"), vec![member.clone()]);
+        assert!(member_side.approve("chat", &admin).is_err());
+        let grant = admin_side.approve("chat", &member).unwrap();
+        assert!(admin_side.pending("chat").is_empty());
+        assert!(admin_side.is_member("chat", &member));
+        assert!(grant.verify(&admin));
+        assert!(!grant.verify(&member));
+
+        // Only the admin can hand out grants
+        let forged = Message::Grant(grant.clone());
+        assert!(member_side.receive("chat", &member, forged).is_err());
+        member_side
+            .receive("chat", &admin, Message::Grant(grant.clone()))
+            .unwrap();
+        assert_eq!(member_side.grant("chat"), Some(&grant));
+        assert!(member_side.tick(now + REQUEST_INTERVAL).is_empty());
+
+        assert!(admin_side.revoke("chat", &member).unwrap());
+        assert!(admin_side.members("chat").is_empty());
+    }
+}
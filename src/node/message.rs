@@ -0,0 +1,173 @@
+//! Versioned envelope of typed messages.
+//!
+//! Typed topics publish a frame holding the envelope [`VERSION`], the
+//! content type of the payload and the payload itself. Frames are CBOR, or
+//! JSON in [`Format::Json`], the debug mode, so payloads can be read in
+//! packet captures and logs. Receivers tell the two apart by the first byte
+//! and accept both.
+//!
+//! A frame may carry a receipt id, asking receivers to acknowledge it, see
+//! [`typed`](super::typed). Receivers that do not know the field ignore it.
+//!
+//! The sender, timestamp and topic of a [`MeshMessage`] are not repeated in
+//! the frame: they come from the signed pubsub message and the transport
+//! envelope around it, so a sender can not claim to be someone else.
+
+use super::{hlc::Timestamp, route};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::PeerId;
+use serde::de::DeserializeOwned;
+use std::str::FromStr;
+
+/// Version of the frame this node writes.
+pub const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Cbor,
+    /// Readable frames, for debugging.
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::Cbor
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "cbor" => Self::Cbor,
+            "json" => Self::Json,
+            _ => bail!("Unknown message format {}, expected cbor or json", s),
+        })
+    }
+}
+
+impl Format {
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Cbor => "application/cbor",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Frame<T> {
+    version:      u8,
+    content_type: String,
+    payload:      T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receipt:      Option<u64>,
+}
+
+/// A decoded message with its payload of `T`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MeshMessage<T> {
+    pub sender:       PeerId,
+    pub timestamp:    Option<Timestamp>,
+    pub topic:        String,
+    pub content_type: String,
+    pub payload:      T,
+}
+
+/// Encode `payload` in a frame.
+pub fn encode<T: Serialize>(payload: &T, format: Format) -> Result<Vec<u8>> {
+    encode_with_receipt(payload, format, None)
+}
+
+/// Encode `payload` in a frame asking for a `receipt`.
+pub(crate) fn encode_with_receipt<T: Serialize>(
+    payload: &T,
+    format: Format,
+    receipt: Option<u64>,
+) -> Result<Vec<u8>> {
+    let frame = Frame {
+        version: VERSION,
+        content_type: format.content_type().to_owned(),
+        payload,
+        receipt,
+    };
+    match format {
+        Format::Cbor => serde_cbor::to_vec(&frame).context("Encoding message"),
+        Format::Json => serde_json::to_vec(&frame).context("Encoding message"),
+    }
+}
+
+/// Decode the frame of a received message.
+pub fn decode<T: DeserializeOwned>(message: route::Message) -> Result<MeshMessage<T>> {
+    decode_with_receipt(message).map(|(message, _)| message)
+}
+
+/// Decode the frame of a received message and the receipt it asks for.
+pub(crate) fn decode_with_receipt<T: DeserializeOwned>(
+    message: route::Message,
+) -> Result<(MeshMessage<T>, Option<u64>)> {
+    // A JSON object starts with `{`, which in CBOR starts a text string
+    // with a 64 bit length, never a frame.
+    let frame: Frame<T> = match message.data.first() {
+        Some(b'{') => serde_json::from_slice(&message.data)?,
+        Some(_) => serde_cbor::from_slice(&message.data)?,
+        None => return Err(anyhow!("Empty message")),
+    };
+    if frame.version > VERSION {
+        bail!("Unsupported message version {}", frame.version);
+    }
+    let decoded = MeshMessage {
+        sender:       message.source,
+        timestamp:    message.timestamp,
+        topic:        message.topic,
+        content_type: frame.content_type,
+        payload:      frame.payload,
+    };
+    Ok((decoded, frame.receipt))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::behaviour::envelope::Provenance, test::prelude::assert_eq};
+
+    #[test]
+    fn test_decodes_both_formats() {
+        let message = |data| {
+            route::Message {
+                source: PeerId::random(),
+                topic: "chat".into(),
+                data,
+                direct: false,
+                timestamp: None,
+                provenance: Provenance::default(),
+                signed: false,
+            }
+        };
+        let cbor = encode(&"hello", Format::Cbor).unwrap();
+        let json = encode(&"hello", Format::Json).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&json).unwrap(),
+            r#"{"version":1,"content_type":"application/json","payload":"hello"}"#
+        );
+        let decoded = decode::<String>(message(cbor)).unwrap();
+        assert_eq!(decoded.payload, "hello");
+        assert_eq!(decoded.content_type, "application/cbor");
+        assert_eq!(decode::<String>(message(json)).unwrap().payload, "hello");
+        let tracked = encode_with_receipt(&"hello", Format::Cbor, Some(7)).unwrap();
+        assert_eq!(decode_with_receipt::<String>(message(tracked)).unwrap().1, Some(7));
+
+        assert!(decode::<u32>(message(encode(&"hello", Format::Cbor).unwrap())).is_err());
+        assert!(decode::<String>(message(b"hello".to_vec())).is_err());
+        let future = serde_json::to_vec(&Frame {
+            version:      VERSION + 1,
+            content_type: "application/json".into(),
+            payload:      "hello",
+            receipt:      None,
+        })
+        .unwrap();
+        assert!(decode::<String>(message(future)).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+//! Prometheus metrics endpoint.
+//!
+//! Started with `--metrics 127.0.0.1:9090` the node answers `GET /metrics`
+//! on that address with its metrics in the Prometheus text format, or in
+//! OpenMetrics for scrapers that accept it:
+//!
+//! * `mesh_peers_connected`, `mesh_peers_known` and `mesh_subscriptions`
+//!   gauges.
+//! * `mesh_messages_published_total` and `mesh_messages_received_total`
+//!   counters, by `topic`.
+//! * `mesh_bandwidth_inbound_bytes_total` and
+//!   `mesh_bandwidth_outbound_bytes_total` counters.
+//! * `mesh_dial_failures_total`, by `outcome` as in [`super::dial`].
+//! * `mesh_messages_expired_total` and `mesh_messages_hop_limited_total`
+//!   counters of messages dropped, see [`super::expiry`].
+//! * A `mesh_connection_duration_seconds` histogram of closed connections.
+//! * A `mesh_message_latency_seconds` histogram of the time from the timestamp
+//!   of received messages to their arrival. In OpenMetrics each bucket has the
+//!   last message in it as an exemplar, with the message id as its `trace_id`,
+//!   which is the `message_id` of the spans that the sender and every receiver
+//!   logged the message in, see [`super::spans`].
+//! * `mesh_connections_rejected_total`, by the `limit` of [`super::admission`]
+//!   that refused them, `swarm` or `per_ip`.
+//! * `mesh_peer_bytes_total` and `mesh_protocol_bytes_total` counters, by
+//!   `direction` and `peer` or `protocol`, as in [`super::accounting`], and
+//!   their recent rates `mesh_peer_bytes_per_second` and
+//!   `mesh_protocol_bytes_per_second`.
+//!
+//! Topics beyond [`MAX_TOPICS`] are counted as `(other)`.
+
+use super::{accounting::Report, admission::Rejected, expiry::Drops, http};
+use crate::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Topics counted by name, the others are counted together.
+pub const MAX_TOPICS: usize = 256;
+
+/// Label of the topics beyond [`MAX_TOPICS`].
+const OTHER_TOPIC: &str = "(other)";
+
+/// Upper bounds of the connection duration buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0];
+
+/// Upper bounds of the message latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How metrics are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// The Prometheus text format 0.0.4.
+    Prometheus,
+    /// OpenMetrics 1.0, which has exemplars.
+    OpenMetrics,
+}
+
+impl Format {
+    /// The format for a scraper sending the `accept` header.
+    pub fn accepted(accept: &str) -> Self {
+        if accept.contains("application/openmetrics-text") {
+            Self::OpenMetrics
+        } else {
+            Self::Prometheus
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Prometheus => "text/plain; version=0.0.4",
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Values the node reads when scraped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Gauges {
+    pub peers_connected:  usize,
+    pub peers_known:      usize,
+    pub subscriptions:    usize,
+    pub inbound:          u64,
+    pub outbound:         u64,
+    pub rejected:         Rejected,
+    pub publish_queue:    usize,
+    pub publish_rejected: u64,
+    pub drops:            Drops,
+}
+
+/// An observation standing for its bucket.
+#[derive(Clone, Debug)]
+struct Exemplar {
+    trace_id: String,
+    value:    f64,
+    time:     SystemTime,
+}
+
+/// Observations per bucket of some upper bounds, and beyond.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    counts:    Vec<u64>,
+    sum:       f64,
+    /// The last observation with a trace id in each bucket.
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64], value: f64, trace_id: Option<&str>) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; bounds.len() + 1];
+            self.exemplars = vec![None; bounds.len() + 1];
+        }
+        let bucket = bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        if let Some(trace_id) = trace_id {
+            self.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace_id.to_owned(),
+                value,
+                time: SystemTime::now(),
+            });
+        }
+    }
+
+    fn render(&self, out: &mut String, format: Format, name: &str, help: &str, bounds: &[f64]) {
+        header(out, format, name, "histogram", help);
+        let bounds = bounds.iter().map(f64::to_string);
+        let mut cumulative = 0;
+        for (index, bound) in bounds.chain(std::iter::once("+Inf".to_owned())).enumerate() {
+            cumulative += self.counts.get(index).copied().unwrap_or_default();
+            let _ = write!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+            if let (Format::OpenMetrics, Some(Some(exemplar))) = (format, self.exemplars.get(index))
+            {
+                let time = exemplar.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let _ = write!(
+                    out,
+                    " # {{trace_id=\"{}\"}} {} {}",
+                    escape(&exemplar.trace_id),
+                    exemplar.value,
+                    time.as_secs_f64()
+                );
+            }
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Counts of what happened since the node started.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    published:     BTreeMap<String, u64>,
+    received:      BTreeMap<String, u64>,
+    dial_failures: BTreeMap<String, u64>,
+    /// When each open connection was established, by peer and address.
+    open:          HashMap<(PeerId, Multiaddr), Vec<Instant>>,
+    /// How long closed connections were open, over [`DURATION_BUCKETS`].
+    durations:     Histogram,
+    /// Latencies of received messages, over [`LATENCY_BUCKETS`].
+    latencies:     Histogram,
+}
+
+fn count(counts: &mut BTreeMap<String, u64>, topic: &str) {
+    let topic = if counts.contains_key(topic) || counts.len() < MAX_TOPICS {
+        topic
+    } else {
+        OTHER_TOPIC
+    };
+    *counts.entry(topic.to_owned()).or_default() += 1;
+}
+
+impl Metrics {
+    pub fn published(&mut self, topic: &str) {
+        count(&mut self.published, topic);
+    }
+
+    pub fn received(&mut self, topic: &str) {
+        count(&mut self.received, topic);
+    }
+
+    /// Count a failed dial of one address.
+    pub fn dial_failed(&mut self, outcome: &str) {
+        *self.dial_failures.entry(outcome.to_owned()).or_default() += 1;
+    }
+
+    pub fn connected(&mut self, peer: &PeerId, address: &Multiaddr, now: Instant) {
+        self.open
+            .entry((peer.clone(), address.clone()))
+            .or_default()
+            .push(now);
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId, address: &Multiaddr, now: Instant) {
+        let key = (peer.clone(), address.clone());
+        let since = match self.open.get_mut(&key) {
+            Some(since) if !since.is_empty() => since.remove(0),
+            _ => return,
+        };
+        if self.open[&key].is_empty() {
+            self.open.remove(&key);
+        }
+        let duration = now.saturating_duration_since(since);
+        self.durations
+            .observe(DURATION_BUCKETS, duration.as_secs_f64(), None);
+    }
+
+    /// Count a message that arrived `latency` after it was sent, the
+    /// exemplar of its bucket with `message_id` as the trace id.
+    pub fn delivered(&mut self, latency: Duration, message_id: &str) {
+        self.latencies
+            .observe(LATENCY_BUCKETS, latency.as_secs_f64(), Some(message_id));
+    }
+
+    /// The metrics in `format`, with the traffic by peer and protocol in
+    /// `usage`.
+    pub fn render(&self, format: Format, gauges: Gauges, usage: &Report) -> String {
+        let mut out = String::new();
+        for (name, help, value) in &[
+            ("mesh_peers_connected", "Connected peers.", gauges.peers_connected),
+            ("mesh_peers_known", "Peers in the known peers table.", gauges.peers_known),
+            ("mesh_subscriptions", "Subscribed topics.", gauges.subscriptions),
+            ("mesh_publish_queue_depth", "Publishes waiting to be sent.", gauges.publish_queue),
+        ] {
+            header(&mut out, format, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        for (name, help, label, counts) in &[
+            (
+                "mesh_messages_published_total",
+                "Messages published, by topic.",
+                "topic",
+                &self.published,
+            ),
+            (
+                "mesh_messages_received_total",
+                "Messages received, by topic.",
+                "topic",
+                &self.received,
+            ),
+            (
+                "mesh_dial_failures_total",
+                "Failed dials of an address, by outcome.",
+                "outcome",
+                &self.dial_failures,
+            ),
+        ] {
+            header(&mut out, format, name, "counter", help);
+            for (value, total) in counts.iter() {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), total);
+            }
+        }
+        for (name, help, total) in &[
+            ("mesh_bandwidth_inbound_bytes_total", "Bytes received.", gauges.inbound),
+            ("mesh_bandwidth_outbound_bytes_total", "Bytes sent.", gauges.outbound),
+            (
+                "mesh_publish_rejected_total",
+                "Publishes refused with a full queue.",
+                gauges.publish_rejected,
+            ),
+            (
+                "mesh_messages_expired_total",
+                "Messages dropped past their expiry.",
+                gauges.drops.expired,
+            ),
+            (
+                "mesh_messages_hop_limited_total",
+                "Messages not republished for their hop limit.",
+                gauges.drops.hop_limited,
+            ),
+        ] {
+            header(&mut out, format, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, total);
+        }
+        let name = "mesh_connections_rejected_total";
+        header(&mut out, format, name, "counter", "Connections refused, by limit.");
+        let rejected = gauges.rejected;
+        for (limit, total) in &[("swarm", rejected.swarm), ("per_ip", rejected.per_ip)] {
+            let _ = writeln!(out, "{}{{limit=\"{}\"}} {}", name, limit, total);
+        }
+
+        self.durations.render(
+            &mut out,
+            format,
+            "mesh_connection_duration_seconds",
+            "How long closed connections were open.",
+            DURATION_BUCKETS,
+        );
+        self.latencies.render(
+            &mut out,
+            format,
+            "mesh_message_latency_seconds",
+            "Time from the timestamp of received messages to their arrival.",
+            LATENCY_BUCKETS,
+        );
+
+        let peers: Vec<_> = usage
+            .peers
+            .iter()
+            .map(|(peer_id, usage)| (peer_id.to_base58(), *usage))
+            .collect();
+        for (label, usages) in &[("peer", &peers), ("protocol", &usage.protocols)] {
+            let name = format!("mesh_{}_bytes_total", label);
+            let help = format!("Bytes of substreams, by {} and direction.", label);
+            header(&mut out, format, &name, "counter", &help);
+            for (value, usage) in usages.iter() {
+                for (direction, total) in &[("in", usage.inbound), ("out", usage.outbound)] {
+                    let _ = writeln!(
+                        out,
+                        "{}{{{}=\"{}\",direction=\"{}\"}} {}",
+                        name,
+                        label,
+                        escape(value),
+                        direction,
+                        total
+                    );
+                }
+            }
+            let name = format!("mesh_{}_bytes_per_second", label);
+            let help = format!("Recent bytes per second, by {} and direction.", label);
+            header(&mut out, format, &name, "gauge", &help);
+            for (value, usage) in usages.iter() {
+                for (direction, rate) in &[("in", usage.inbound_rate), ("out", usage.outbound_rate)]
+                {
+                    let _ = writeln!(
+                        out,
+                        "{}{{{}=\"{}\",direction=\"{}\"}} {}",
+                        name,
+                        label,
+                        escape(value),
+                        direction,
+                        rate
+                    );
+                }
+            }
+        }
+        if format == Format::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, format: Format, name: &str, kind: &str, help: &str) {
+    // OpenMetrics names counters without the suffix of their samples
+    let name = match (format, kind) {
+        (Format::OpenMetrics, "counter") => name.strip_suffix("_total").unwrap_or(name),
+        _ => name,
+    };
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A scrape waiting for the node to render its metrics in `format`.
+#[derive(Debug)]
+pub struct Scrape {
+    pub format: Format,
+    pub sender: oneshot::Sender<String>,
+}
+
+/// Answer one HTTP request on `stream`, in the format it accepts.
+async fn serve_client(mut stream: TcpStream, mut scrapes: mpsc::Sender<Scrape>) -> Result<()> {
+    let request = http::read(&mut stream, 0).await?;
+    let format = Format::accepted(&request.accept);
+    let (status, format, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let (sender, receiver) = oneshot::channel();
+            let scrape = Scrape { format, sender };
+            scrapes.send(scrape).await.context("Node stopped")?;
+            ("200 OK", format, receiver.await.context("Node stopped")?)
+        }
+        ("GET", _) => {
+            let body = "Not found, try /metrics\n".to_owned();
+            ("404 Not Found", Format::Prometheus, body)
+        }
+        _ => {
+            let body = "Only GET is supported\n".to_owned();
+            ("405 Method Not Allowed", Format::Prometheus, body)
+        }
+    };
+    http::write_response(&mut stream, status, format.content_type(), &body).await
+}
+
+/// Listen for scrapes on `address`.
+pub async fn bind(address: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Listening for metrics scrapes on {}", address))
+}
+
+/// Accept scrapes on `listener`, passing them to `scrapes`.
+pub async fn serve(listener: TcpListener, scrapes: mpsc::Sender<Scrape>) -> Result<()> {
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await.context("Accepting scrape")?;
+        let scrapes = scrapes.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, scrapes).await {
+                debug!("Metrics scrape failed: {:#}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::accounting::Usage, test::prelude::assert_eq};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get(
+        listener: &mut TcpListener,
+        scrapes: &mpsc::Sender<Scrape>,
+        path: &'static str,
+        accept: &'static str,
+    ) -> String {
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n",
+                path, accept
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        serve_client(stream, scrapes.clone()).await.unwrap();
+        client.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics() {
+        let mut metrics = Metrics::default();
+        metrics.published("chat");
+        metrics.published("chat");
+        metrics.received("say \"hi\"");
+        metrics.dial_failed("refused");
+        let (peer, address) = (PeerId::random(), "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let now = Instant::now();
+        metrics.connected(&peer, &address, now);
+        metrics.disconnected(&peer, &address, now + Duration::from_secs(30));
+        metrics.disconnected(&peer, &address, now + Duration::from_secs(40));
+        let usage = Report {
+            peers:     vec![],
+            protocols: vec![("/ipfs/ping/1.0.0".to_owned(), Usage {
+                inbound: 64,
+                outbound_rate: 0.5,
+                ..Usage::default()
+            })],
+        };
+        let text = metrics.render(
+            Format::Prometheus,
+            Gauges {
+                peers_connected: 2,
+                rejected: Rejected {
+                    swarm:  0,
+                    per_ip: 3,
+                },
+                ..Gauges::default()
+            },
+            &usage,
+        );
+        for line in &[
+            "# TYPE mesh_peers_connected gauge",
+            "mesh_peers_connected 2",
+            "# TYPE mesh_publish_queue_depth gauge",
+            "mesh_messages_published_total{topic=\"chat\"} 2",
+            "mesh_messages_received_total{topic=\"say \\\"hi\\\"\"} 1",
+            "mesh_dial_failures_total{outcome=\"refused\"} 1",
+            "mesh_connections_rejected_total{limit=\"per_ip\"} 3",
+            "mesh_connection_duration_seconds_bucket{le=\"10\"} 0",
+            "mesh_connection_duration_seconds_bucket{le=\"60\"} 1",
+            "mesh_connection_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "mesh_connection_duration_seconds_sum 30",
+            "# TYPE mesh_peer_bytes_total counter",
+            "mesh_protocol_bytes_total{protocol=\"/ipfs/ping/1.0.0\",direction=\"in\"} 64",
+            "mesh_protocol_bytes_per_second{protocol=\"/ipfs/ping/1.0.0\",direction=\"out\"} 0.5",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "{} missing in\n{}", line, text);
+        }
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (sender, mut scrapes) = mpsc::channel(1);
+        let node = tokio::spawn(async move {
+            let scrape: Scrape = scrapes.next().await.unwrap();
+            assert_eq!(scrape.format, Format::Prometheus);
+            scrape.sender.send(text).unwrap();
+        });
+        let response = get(&mut listener, &sender, "/metrics", "*/*").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("mesh_connection_duration_seconds_count 1\n"));
+        node.await.unwrap();
+        let response = get(&mut listener, &sender, "/", "*/*").await;
+        assert_eq!(response.lines().next(), Some("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_exemplars_in_openmetrics() {
+        let mut metrics = Metrics::default();
+        metrics.published("chat");
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        metrics.delivered(Duration::from_millis(30), trace_id);
+        metrics.delivered(Duration::from_millis(40), "b7ad6b7169203331b7ad6b7169203331");
+        metrics.delivered(Duration::from_secs(20), trace_id);
+
+        let report = Report {
+            peers:     vec![],
+            protocols: vec![],
+        };
+        let text = metrics.render(Format::Prometheus, Gauges::default(), &report);
+        assert!(text
+            .lines()
+            .any(|line| line == "mesh_message_latency_seconds_bucket{le=\"0.05\"} 2"));
+        assert!(!text.contains("trace_id"));
+        assert!(!text.contains("# EOF"));
+
+        let text = metrics.render(Format::OpenMetrics, Gauges::default(), &report);
+        for prefix in &[
+            "# TYPE mesh_messages_published counter",
+            "mesh_messages_published_total{topic=\"chat\"} 1",
+            "mesh_message_latency_seconds_bucket{le=\"0.025\"} 0\n",
+            "mesh_message_latency_seconds_bucket{le=\"0.05\"} 2 # \
+             {trace_id=\"b7ad6b7169203331b7ad6b7169203331\"} 0.04 ",
+            "mesh_message_latency_seconds_bucket{le=\"+Inf\"} 3 # \
+             {trace_id=\"0af7651916cd43dd8448eb211c80319c\"} 20 ",
+            "mesh_message_latency_seconds_count 3",
+            "mesh_connection_duration_seconds_bucket{le=\"+Inf\"} 0\n",
+        ] {
+            assert!(text.contains(prefix), "{} missing in\n{}", prefix, text);
+        }
+        assert!(text.ends_with("# EOF\n"));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (sender, mut scrapes) = mpsc::channel(1);
+        let node = tokio::spawn(async move {
+            let scrape: Scrape = scrapes.next().await.unwrap();
+            scrape.sender.send(metrics.render(scrape.format, Gauges::default(), &report)).unwrap();
+        });
+        let accept = "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5";
+        let response = get(&mut listener, &sender, "/metrics", accept).await;
+        assert!(response.contains(
+            "Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n"
+        ));
+        assert!(response.contains("} 20 "));
+        node.await.unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+//! Interceptors on the message path.
+//!
+//! A [`Chain`] of [`Middleware`] sees every message on application topics:
+//! published ones in the order the layers were added, before the [`keyring`]
+//! encrypts them and pubsub signs them, and received ones in reverse order,
+//! after decryption and before delivery. A layer added after [`Compress`]
+//! thus sees compressed payloads, and sign, compress, encrypt pipelines are
+//! built by adding the layers in that order. Internal topics, like election
+//! heartbeats and key rotations, bypass the chain.
+//!
+//! Layers transform payloads, count them or filter them. An error from
+//! [`Middleware::outbound`] fails the publish, `None` from
+//! [`Middleware::inbound`] drops the message quietly and an error drops it
+//! with a warning. Every node on a topic needs the same transforming layers
+//! in the same order.
+//!
+//! [`keyring`]: crate::node::keyring
+
+use crate::prelude::*;
+use anyhow::{anyhow, ensure};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use libp2p::PeerId;
+use std::{
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+pub trait Middleware {
+    /// Transform `data` published on `topic`, or reject it with an error.
+    fn outbound(&mut self, _topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(data)
+    }
+
+    /// Transform `data` received on `topic` from `source`, or drop it with
+    /// `None`.
+    fn inbound(
+        &mut self,
+        _topic: &str,
+        _source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(Some(data))
+    }
+}
+
+/// Named layers, outermost first.
+#[derive(Default)]
+pub struct Chain {
+    layers: Vec<(String, Box<dyn Middleware>)>,
+}
+
+impl Chain {
+    pub fn push(&mut self, name: &str, layer: Box<dyn Middleware>) {
+        self.layers.push((name.to_owned(), layer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Pass `data` published on `topic` through all layers.
+    pub fn outbound(&mut self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.layers.iter_mut().try_fold(data, |data, (name, layer)| {
+            layer
+                .outbound(topic, data)
+                .with_context(|| format!("Middleware {}", name))
+        })
+    }
+
+    /// Pass `data` received on `topic` through all layers, innermost
+    /// first.
+    pub fn inbound(
+        &mut self,
+        topic: &str,
+        source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut data = data;
+        for (name, layer) in self.layers.iter_mut().rev() {
+            match layer
+                .inbound(topic, source, data)
+                .with_context(|| format!("Middleware {}", name))?
+            {
+                Some(next) => data = next,
+                None => {
+                    trace!("Middleware {} dropped message on {}", name, topic);
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+/// Deflate payloads, for topics carrying text or other redundant data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Compress;
+
+/// Most bytes a compressed payload may expand to.
+pub const MAX_DECOMPRESSED: u64 = 16 << 20;
+
+impl Middleware for Compress {
+    fn outbound(&mut self, _topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn inbound(
+        &mut self,
+        _topic: &str,
+        _source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut plain = Vec::new();
+        DeflateDecoder::new(data.as_slice())
+            .take(MAX_DECOMPRESSED + 1)
+            .read_to_end(&mut plain)
+            .context("Decompressing")?;
+        ensure!(
+            plain.len() as u64 <= MAX_DECOMPRESSED,
+            "Decompressed payload over {} bytes",
+            MAX_DECOMPRESSED
+        );
+        Ok(Some(plain))
+    }
+}
+
+/// Keep only the messages `predicate` accepts, both ways.
+pub struct Filter<F>(pub F);
+
+impl<F: FnMut(&str, &[u8]) -> bool> Middleware for Filter<F> {
+    fn outbound(&mut self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        if (self.0)(topic, &data) {
+            Ok(data)
+        } else {
+            Err(anyhow!("Rejected message on {}", topic))
+        }
+    }
+
+    fn inbound(
+        &mut self,
+        topic: &str,
+        _source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(Some(data).filter(|data| (self.0)(topic, data)))
+    }
+}
+
+/// Log every message at trace level, with its size at this layer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Trace;
+
+impl Middleware for Trace {
+    fn outbound(&mut self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        trace!("Publishing {} bytes on {}", data.len(), topic);
+        Ok(data)
+    }
+
+    fn inbound(
+        &mut self,
+        topic: &str,
+        source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        trace!("Received {} bytes on {} from {}", data.len(), topic, source);
+        Ok(Some(data))
+    }
+}
+
+/// Count messages and bytes passing this layer. Keep a clone of
+/// [`Metrics::counts`] before adding it to read them.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    counts: Arc<Counts>,
+}
+
+#[derive(Debug, Default)]
+pub struct Counts {
+    pub published:      AtomicU64,
+    pub published_size: AtomicU64,
+    pub received:       AtomicU64,
+    pub received_size:  AtomicU64,
+}
+
+impl Metrics {
+    pub fn counts(&self) -> Arc<Counts> {
+        Arc::clone(&self.counts)
+    }
+}
+
+impl Middleware for Metrics {
+    fn outbound(&mut self, _topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.counts.published.fetch_add(1, Ordering::Relaxed);
+        self.counts
+            .published_size
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    fn inbound(
+        &mut self,
+        _topic: &str,
+        _source: &PeerId,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.counts.received.fetch_add(1, Ordering::Relaxed);
+        self.counts
+            .received_size
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_runs_layers_in_order() {
+        let metrics = Metrics::default();
+        let counts = metrics.counts();
+        let mut chain = Chain::default();
+        chain.push("filter", Box::new(Filter(|_: &str, data: &[u8]| !data.is_empty())));
+        chain.push("compress", Box::new(Compress));
+        chain.push("metrics", Box::new(metrics));
+
+        let data = b"hello hello hello hello hello hello".to_vec();
+        let sent = chain.outbound("chat", data.clone()).unwrap();
+        assert!(sent.len() < data.len());
+        assert_eq!(counts.published_size.load(Ordering::Relaxed), sent.len() as u64);
+        let source = PeerId::random();
+        assert_eq!(chain.inbound("chat", &source, sent).unwrap(), Some(data));
+        assert_eq!(counts.received.load(Ordering::Relaxed), 1);
+
+        assert!(chain.outbound("chat", Vec::new()).is_err());
+        let empty = Compress.outbound("chat", Vec::new()).unwrap();
+        assert_eq!(chain.inbound("chat", &source, empty).unwrap(), None);
+        assert!(chain.inbound("chat", &source, b"garbage".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Dialed addresses that answer with another peer id.
+//!
+//! A node that was reinstalled comes back on its old address with a new key,
+//! so dialing the address book entry of its old peer id fails with a "wrong
+//! peer id" [`dial`] outcome every time. The transport remembers which peer
+//! each recent outbound connection authenticated as in [`Identities`], which
+//! tells the node who answered instead, and `--identity-mismatch` picks the
+//! [`Policy`]: `reject` keeps the address book as it is, `update` moves the
+//! address to the new peer id and dials it, and `prompt` leaves that to the
+//! application, which sees [`Event::IdentityMismatch`] and may call
+//! [`NodeHandle::accept_identity`].
+//!
+//! [`dial`]: crate::node::dial
+//! [`Event::IdentityMismatch`]: crate::node::Event::IdentityMismatch
+//! [`NodeHandle::accept_identity`]: crate::node::NodeHandle::accept_identity
+
+use crate::prelude::*;
+use anyhow::bail;
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Number of outbound connections remembered.
+const REMEMBERED: usize = 64;
+
+/// What to do when a dialed address answers with another peer id.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Policy {
+    /// Fail the dial and keep the address book.
+    #[default]
+    Reject,
+    /// Move the address to the peer that answered and dial it.
+    Update,
+    /// Only emit the event, for the application to decide.
+    Prompt,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "reject" => Self::Reject,
+            "update" => Self::Update,
+            "prompt" => Self::Prompt,
+            _ => bail!("Unknown identity mismatch policy {}, expected reject, update or prompt", s),
+        })
+    }
+}
+
+/// The peer ids recent outbound connections authenticated as, by the
+/// address dialed. Shared with the transport.
+#[derive(Clone, Default, Debug)]
+pub struct Identities(Arc<Mutex<VecDeque<(Multiaddr, PeerId)>>>);
+
+impl Identities {
+    /// Remember that dialing `address` reached `peer_id`.
+    pub fn record(&self, address: &Multiaddr, peer_id: &PeerId) {
+        let mut seen = self.0.lock().unwrap();
+        seen.retain(|(known, _)| known != address);
+        if seen.len() >= REMEMBERED {
+            seen.pop_front();
+        }
+        seen.push_back((address.clone(), peer_id.clone()));
+    }
+
+    /// The peer last reached by dialing `address`.
+    pub fn get(&self, address: &Multiaddr) -> Option<PeerId> {
+        let seen = self.0.lock().unwrap();
+        seen.iter()
+            .find(|(known, _)| known == address)
+            .map(|(_, peer_id)| peer_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_remembers_recent_identities() {
+        assert_eq!("update".parse::<Policy>().unwrap(), Policy::Update);
+        assert!("ask".parse::<Policy>().is_err());
+
+        let identities = Identities::default();
+        let address: Multiaddr = "/ip4/10.0.0.7/tcp/60558".parse().unwrap();
+        let (old, new) = (PeerId::random(), PeerId::random());
+        identities.record(&address, &old);
+        identities.record(&address, &new);
+        assert_eq!(identities.get(&address), Some(new));
+        for port in 0..REMEMBERED {
+            let other = format!("/ip4/10.0.0.8/tcp/{}", port).parse().unwrap();
+            identities.record(&other, &old);
+        }
+        assert_eq!(identities.get(&address), None);
+    }
+}
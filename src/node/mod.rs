@@ -9,172 +9,6538 @@
 // See https://github.com/libp2p/rust-libp2p/issues/983
 // See https://github.com/libp2p/rust-libp2p/issues/1021
 
+pub mod access;
+pub mod accounting;
+mod activation;
+pub mod admission;
+pub mod addressbook;
+pub mod aggregate;
+pub mod api;
+pub mod archive;
+pub mod audit;
+pub mod autonat;
+pub mod baseline;
 mod behaviour;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod ble;
+pub mod bootstrap;
+pub mod builder;
+pub mod bundle;
+pub mod capability;
+pub mod clock;
+pub mod compat;
+pub mod console;
+pub mod control;
+pub mod crash;
+pub mod daemon;
+pub mod degrade;
+pub mod delta;
+pub mod dial;
+pub mod discovery;
+pub mod dnsaddr;
+pub mod dtn;
+pub mod duplicate;
+pub mod election;
+pub mod expiry;
+pub mod family;
+pub mod file;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+pub mod gate;
+pub mod handoff;
+#[cfg(any(test, feature = "harness"))]
+pub mod harness;
+pub mod health;
+pub mod hlc;
+pub mod http;
+pub mod input;
+#[cfg(feature = "interop")]
+pub mod interop;
+pub mod journal;
+pub mod keepalive;
+pub mod keyring;
+pub mod keystore;
+pub mod latency;
+pub mod lifecycle;
+pub mod link;
+pub mod lock;
+pub mod membership;
+pub mod message;
+pub mod metrics;
+pub mod middleware;
+pub mod mismatch;
+pub mod mode;
+pub mod moderation;
+pub mod names;
+pub mod naming;
+pub mod negotiation;
+pub mod nickname;
+pub mod options;
+pub mod outbound;
+pub mod outbox;
+pub mod pnet;
+pub mod power;
+pub mod presence;
+pub mod probe;
+pub mod profile;
+pub mod publisher;
+pub mod proxy;
+pub mod pubsub;
+pub mod qos;
+pub mod quiet;
+pub mod ready;
+pub mod reload;
+pub mod rendezvous;
+pub mod replica;
+pub mod roaming;
+pub mod rolling;
+pub mod rotation;
+pub mod route;
+pub mod schedule;
+pub mod schema;
+pub mod scoring;
+#[cfg(feature = "search")]
+pub mod search;
+pub mod security;
+pub mod seen;
+pub mod serial;
+pub mod shaping;
+pub mod signing;
+#[cfg(any(test, feature = "harness"))]
+pub mod simulation;
+pub mod soak;
+pub mod spans;
+pub mod statsd;
+pub mod storage;
+pub mod subscriptions;
+pub mod supervisor;
 mod transport;
+pub mod typed;
+pub mod udp;
+pub mod verification;
+pub mod warm;
 
+pub use self::{
+    behaviour::{
+        codec,
+        discovery::{PeerInfo, PeerStore},
+        envelope::Provenance,
+        fragment,
+        reliable,
+        rpc::RpcRequest,
+        service::{ServiceDescriptor, ServiceRequest},
+        Event,
+    },
+    builder::NodeBuilder,
+};
 use self::{
-    behaviour::{order_sync, Behaviour, discovery::PeerInfo},
+    activation::Activated,
+    aggregate::{Aggregates, Contribution},
+    delta::{Decoder, Encoder, Update},
+    keyring::{Keyring, Rotation},
+    membership::Membership,
+    moderation::{Blocklist, Moderation},
+    schema::SchemaRegistry,
+    shaping::Shaper,
+    subscriptions::{Subscriptions, TopicOptions},
+    election::{Election, ElectionConfig, Heartbeat, LeadershipChange},
+    behaviour::{diagnostics, order_sync, rpc, service, Behaviour},
     transport::make_transport,
+    udp::Udp,
 };
 use crate::prelude::*;
 use futures::channel::{mpsc, oneshot};
 use libp2p::{
-    bandwidth::BandwidthSinks, core::network::NetworkInfo, gossipsub::Topic, identity,
-    swarm::SwarmBuilder, Multiaddr, PeerId, Swarm,
+    bandwidth::BandwidthSinks,
+    core::network::NetworkInfo,
+    gossipsub::{error::PublishError, Topic},
+    identity,
+    multiaddr::Protocol,
+    core::connection::{ListenerId, PendingConnectionError},
+    core::ConnectedPoint,
+    swarm::{AddressScore, SwarmBuilder, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
 };
+use tracing::Instrument;
 use ubyte::ToByteUnit;
-use tokio::time::sleep;
-use std::time::Duration;
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{interval, sleep, Interval},
+};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+use self::hlc::Timestamp;
+
+/// Interval between ticks of elections, aggregates and topic expiry.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time connections get to send the last publishes when shutting down,
+/// before they are closed.
+const DRAIN_TIME: Duration = Duration::from_millis(500);
+
+/// Time a shutdown may take by default, see [`Node::close`].
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type OrderSyncRequest = (
+    PeerId,
+    order_sync::messages::Request,
+    oneshot::Sender<order_sync::Result>,
+);
+
+/// Requests from a [`NodeHandle`] to the event loop.
+enum Command {
+    Events {
+        sender: mpsc::Sender<Event>,
+    },
+    Route {
+        topic:  String,
+        sender: mpsc::Sender<route::Message>,
+    },
+    Unroute {
+        topic: String,
+    },
+    Shutdown,
+    WaitReady {
+        criteria: ready::Criteria,
+        sender:   oneshot::Sender<()>,
+    },
+    Dial {
+        address: Multiaddr,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    FindPeer {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<Result<Vec<Multiaddr>>>,
+    },
+    ClosestPeers {
+        key:    Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<PeerId>>>,
+    },
+    StartProviding {
+        key:    Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    StopProviding {
+        key: Vec<u8>,
+    },
+    Provided {
+        sender: oneshot::Sender<Vec<discovery::Provided>>,
+    },
+    PublishName {
+        name:     String,
+        metadata: BTreeMap<String, String>,
+        sender:   oneshot::Sender<Result<()>>,
+    },
+    ResolveName {
+        name:   String,
+        sender: oneshot::Sender<Result<naming::NameRecord>>,
+    },
+    DhtPut {
+        key:    Vec<u8>,
+        value:  Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    DhtGet {
+        key:    Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<Vec<u8>>>>,
+    },
+    Timestamp {
+        sender: oneshot::Sender<Timestamp>,
+    },
+    Subscribe {
+        topic:   String,
+        options: TopicOptions,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    Unsubscribe {
+        topic:  String,
+        sender: oneshot::Sender<Result<bool>>,
+    },
+    Topics {
+        sender: oneshot::Sender<Vec<(String, TopicOptions)>>,
+    },
+    Publish {
+        topic:  String,
+        data:   Vec<u8>,
+        /// Whether the message went to pubsub right away.
+        sender: oneshot::Sender<Result<bool>>,
+    },
+    Republish {
+        topic:      String,
+        data:       Vec<u8>,
+        source:     PeerId,
+        provenance: Provenance,
+        sender:     oneshot::Sender<Result<()>>,
+    },
+    PublishState {
+        topic:  String,
+        data:   Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    PublishTo {
+        peers:  Vec<PeerId>,
+        topic:  String,
+        data:   Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<PeerId>>>,
+    },
+    SendTo {
+        peer_id: PeerId,
+        data:    Vec<u8>,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    SendFile {
+        peer_id:  PeerId,
+        path:     PathBuf,
+        manifest: file::Manifest,
+        sender:   oneshot::Sender<Result<file::FileId>>,
+    },
+    SendBundle {
+        destination: PeerId,
+        topic:       String,
+        data:        Vec<u8>,
+        priority:    u8,
+        sender:      oneshot::Sender<Result<dtn::BundleId>>,
+    },
+    BundleTrace {
+        id:     dtn::BundleId,
+        sender: oneshot::Sender<Option<dtn::Trace>>,
+    },
+    AdvertiseService {
+        descriptor: ServiceDescriptor,
+        handler:    mpsc::Sender<ServiceRequest>,
+    },
+    WithdrawService {
+        service: String,
+    },
+    Request {
+        peer_id: PeerId,
+        data:    Vec<u8>,
+        sender:  oneshot::Sender<rpc::Result>,
+    },
+    /// A `/dnsaddr/` bootstrap entry was resolved, see [`dnsaddr`].
+    DnsaddrResolved {
+        address: Multiaddr,
+        result:  Result<Vec<(PeerId, Multiaddr)>>,
+    },
+    ServeRequests {
+        handler: mpsc::Sender<RpcRequest>,
+    },
+    CallService {
+        service: String,
+        data:    Vec<u8>,
+        sender:  oneshot::Sender<service::Result>,
+    },
+    PushJob {
+        queue:              String,
+        key:                String,
+        data:               Vec<u8>,
+        visibility_timeout: Duration,
+        sender:             oneshot::Sender<service::Result>,
+    },
+    JoinElection {
+        group:   String,
+        config:  ElectionConfig,
+        changes: mpsc::Sender<LeadershipChange>,
+    },
+    LeaveElection {
+        group: String,
+    },
+    Increment {
+        name:  String,
+        delta: i64,
+    },
+    SetGauge {
+        name:  String,
+        value: i64,
+    },
+    Metric {
+        name:   String,
+        sender: oneshot::Sender<Option<i64>>,
+    },
+    ResolvePeer {
+        name:   String,
+        sender: oneshot::Sender<Result<PeerId>>,
+    },
+    PeerInfo {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<Option<PeerInfo>>,
+    },
+    FindServices {
+        service: String,
+        sender:  oneshot::Sender<Vec<(PeerId, ServiceDescriptor)>>,
+    },
+    WarmPeer {
+        address: Multiaddr,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    CoolPeer {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<bool>,
+    },
+    HotPeers {
+        sender: oneshot::Sender<Vec<warm::HotPeer>>,
+    },
+    Pin {
+        address: Multiaddr,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    Unpin {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<bool>,
+    },
+    PinnedPeers {
+        sender: oneshot::Sender<Vec<warm::HotPeer>>,
+    },
+    Peers {
+        sender: oneshot::Sender<Vec<PeerId>>,
+    },
+    ListenAddrs {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    Ban {
+        peer_id:  PeerId,
+        duration: Option<Duration>,
+        sender:   oneshot::Sender<Result<()>>,
+    },
+    Unban {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<Result<bool>>,
+    },
+    NatStatus {
+        sender: oneshot::Sender<autonat::NatStatus>,
+    },
+    PowerSave {
+        enabled: bool,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    AcceptIdentity {
+        expected: PeerId,
+        actual:   PeerId,
+        address:  Multiaddr,
+    },
+    SetTopicKey {
+        topic: String,
+        key:   keyring::Key,
+        admin: PeerId,
+    },
+    TopicFingerprint {
+        topic:  String,
+        sender: oneshot::Sender<Option<(u32, String)>>,
+    },
+    RotateTopicKey {
+        topic:   String,
+        members: Vec<PeerId>,
+        sender:  oneshot::Sender<Result<u32>>,
+    },
+    RequestMembership {
+        topic: String,
+        admin: PeerId,
+    },
+    PendingMembers {
+        topic:  String,
+        sender: oneshot::Sender<Vec<PeerId>>,
+    },
+    Roster {
+        topic:  String,
+        sender: oneshot::Sender<Vec<presence::Present>>,
+    },
+    Profile {
+        peer_id: PeerId,
+        sender:  oneshot::Sender<Option<nickname::Profile>>,
+    },
+    BandwidthUsage {
+        sender: oneshot::Sender<accounting::Report>,
+    },
+    Samples {
+        sender: oneshot::Sender<Vec<statsd::Sample>>,
+    },
+    GetState {
+        map:    String,
+        key:    String,
+        sender: oneshot::Sender<Result<Option<Vec<u8>>>>,
+    },
+    SetState {
+        map:    String,
+        key:    String,
+        value:  Option<Vec<u8>>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    StateEntries {
+        map:    String,
+        sender: oneshot::Sender<Result<BTreeMap<String, Vec<u8>>>>,
+    },
+    ApproveMember {
+        topic:  String,
+        member: PeerId,
+        sender: oneshot::Sender<Result<u32>>,
+    },
+    RevokeMember {
+        topic:  String,
+        member: PeerId,
+        sender: oneshot::Sender<Result<u32>>,
+    },
+    Mute {
+        peer_id: PeerId,
+        muted:   bool,
+    },
+    TrustModerator {
+        topic:     String,
+        moderator: PeerId,
+    },
+    PublishBlocklist {
+        topic:   String,
+        blocked: Vec<PeerId>,
+        sender:  oneshot::Sender<Result<()>>,
+    },
+    RestrictTopic {
+        topic: String,
+        owner: Option<PeerId>,
+    },
+    IssueCapability {
+        topic:   String,
+        holder:  PeerId,
+        expires: Option<std::time::SystemTime>,
+        sender:  oneshot::Sender<Result<capability::Capability>>,
+    },
+    AddCapability {
+        capability: capability::Capability,
+        sender:     oneshot::Sender<Result<()>>,
+    },
+    TrustScheduler {
+        issuer: PeerId,
+    },
+    Schedule {
+        name:   String,
+        at:     std::time::SystemTime,
+        data:   Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// TODO: Impl Debug
+pub struct Node {
+    bandwidth_monitor: Arc<BandwidthSinks>,
+    swarm:             Swarm<Behaviour>,
+
+    /// Traffic by peer and protocol, shared with the transport.
+    meter: accounting::Meter,
+
+    /// The UDP transport, for its packet counts.
+    udp: Udp,
+
+    /// Listening sockets inherited through socket activation or handoff.
+    activated: Activated,
+
+    order_sync_sender:   mpsc::Sender<OrderSyncRequest>,
+    order_sync_receiver: mpsc::Receiver<OrderSyncRequest>,
+
+    command_sender:   mpsc::Sender<Command>,
+    command_receiver: mpsc::Receiver<Command>,
+
+    /// Debug bundle requests from authorized peers.
+    bundle_receiver: mpsc::Receiver<diagnostics::BundleRequest>,
+
+    /// Files included in debug bundles.
+    bundle_sources: bundle::Sources,
+
+    /// Elections we take part in, by group.
+    elections: HashMap<String, (Election, mpsc::Sender<LeadershipChange>)>,
+
+    /// Topics subscribed to through the handle.
+    subscriptions: Subscriptions,
+
+    /// Our last published state, per state topic.
+    state_encoders: HashMap<String, Encoder>,
+
+    /// Reconstructed states of other publishers, per state topic.
+    state_decoders: HashMap<String, Decoder>,
+
+    /// Schemas that payloads on a topic must conform to.
+    schemas: SchemaRegistry,
+
+    /// Keys of encrypted topics.
+    keyring: Keyring,
+
+    /// Members of private topics.
+    membership: Membership,
+
+    /// Muted senders and topic blocklists.
+    moderation: Moderation,
+
+    /// Actions waiting for their time.
+    schedule: schedule::Schedule,
+
+    /// Our lifecycle announcements and those of peers.
+    lifecycle: lifecycle::Lifecycle,
+
+    /// Our migration to a rotated identity and those of peers.
+    rotations: rotation::Rotations,
+
+    /// Our heartbeats and the peers online, with `--presence`.
+    presence: Option<presence::Presence>,
+
+    /// Our profile, with `--nickname`, and the profiles of peers.
+    nicknames: nickname::Nicknames,
+
+    /// Replicated maps in use.
+    replicas: replica::Replicas,
+
+    /// Consumers of received messages.
+    event_senders: Vec<mpsc::Sender<Event>>,
+
+    /// Handlers of messages by topic.
+    routes: route::Routes,
+
+    /// Last time a subscribed topic saw a message or subscriber.
+    topic_activity: HashMap<String, Instant>,
+
+    /// Mesh-wide counters and gauges.
+    aggregates: Aggregates,
+
+    /// Drives elections and aggregate publishing.
+    tick: Interval,
+
+    /// Whether mDNS runs outside power saving, see [`discovery`].
+    mdns: bool,
+
+    /// Whether we are in [`power`] save mode.
+    power_save: bool,
+
+    /// Windows of the day in which we are dormant.
+    quiet_hours: quiet::Schedule,
+
+    /// Whether we are in a quiet window.
+    dormant: bool,
+
+    /// Watches for wall clock jumps.
+    clock_jumps: clock::Detector,
+
+    /// Watches for changes of our addresses.
+    roaming: roaming::Watcher,
+
+    /// Recent events, for [`control`] clients.
+    recent: control::Recent,
+
+    /// Where delivered messages are recorded, if anywhere.
+    journal: Option<journal::Journal>,
+
+    /// Where swarm events are recorded, if anywhere.
+    audit: Option<audit::Journal>,
+
+    /// Messages published in power-save mode, waiting for the next tick, or
+    /// while dormant, waiting for the end of the quiet window.
+    batch: power::Batch,
+
+    /// Publishes on durable topics, kept until sent.
+    outbox: outbox::Outbox,
+
+    /// Publishes of handles, waiting for pubsub, see [`outbound`].
+    publish_queue: outbound::Queue,
+
+    /// Interceptors on application messages.
+    middleware: middleware::Chain,
+
+    /// Recent messages we answer backfills with, if we are an archiver, and
+    /// the backfill requests.
+    archive:          Option<archive::Archive>,
+    archive_sender:   mpsc::Sender<ServiceRequest>,
+    archive_receiver: mpsc::Receiver<ServiceRequest>,
+
+    /// Our backfills waiting for an archiver.
+    backfills: archive::Backfills,
+
+    /// Bounds on collections, see [`profile`].
+    limits: profile::Limits,
+
+    /// Connection and substream upgrade failures, by reason.
+    negotiation: negotiation::Failures,
+
+    /// Failed addresses of dials in progress.
+    dials:      dial::Dials,
+    /// Addresses not to dial again for now, shared with the transport.
+    backoffs:   dial::Backoffs,
+    /// Address families to listen on and dial, shared with the transport.
+    families:   family::Families,
+    /// SOCKS5 proxies to dial through, shared with the transport.
+    proxies:    proxy::Proxies,
+    /// Peers to keep a connection to, see [`warm`].
+    hot:        warm::Peers,
+    /// Important peers to reconnect to when they drop, see [`supervisor`].
+    supervisor: supervisor::Supervisor,
+    /// Peers always kept connected, see [`warm`].
+    pinned:     warm::Peers,
+    /// Connections to close for going idle, see [`health`].
+    reaper:     health::Reaper,
+    /// Peers seen before, see [`addressbook`].
+    known:      addressbook::AddressBook,
+    /// Our reachability, as peers dialing us back found it.
+    nat:        autonat::NatStatus,
+
+    /// Counts exported to Prometheus.
+    metrics: metrics::Metrics,
+    /// What liveness and readiness probes are answered from, once served.
+    probes:  Option<probe::Probes>,
+    /// Where the persistent subsystems keep their state, see [`storage`].
+    storage: Option<storage::Shared>,
+
+    /// Who dialed addresses answered as, and what to do if it was not the
+    /// peer we dialed.
+    identities:      mismatch::Identities,
+    identity_policy: mismatch::Policy,
+
+    /// Who may connect, shared with the transport.
+    gate: gate::Gate,
+    /// Connections per IP address, shared with the transport.
+    admission: admission::Admission,
+
+    /// Whether optional subsystems may fail, and those that did.
+    subsystem_failures: degrade::Policy,
+    degraded:           Vec<degrade::Degraded>,
+
+    /// Bootstrap peers, and how many of them must answer to complete the
+    /// bootstrap.
+    bootstrap_peers:  Vec<(PeerId, Multiaddr)>,
+    bootstrap_quorum: usize,
+    bootstrap:        bootstrap::Progress,
+    /// `/dnsaddr/` bootstrap entries.
+    dnsaddr:          dnsaddr::Names,
+
+    /// Callers of [`NodeHandle::wait_ready`] still waiting.
+    waiting_ready: Vec<(ready::Criteria, oneshot::Sender<()>)>,
+
+    /// Our listeners, removed when shutting down.
+    listener_ids: Vec<ListenerId>,
+
+    /// How long [`Node::run`] may take to shut down gracefully.
+    shutdown_timeout: Duration,
+
+    /// Whether [`Node::run`] should return.
+    stopping: bool,
+
+    /// Span of the node, and of each connected peer, see [`spans`].
+    span:        tracing::Span,
+    connections: HashMap<PeerId, tracing::Span>,
+}
+
+#[derive(Clone)]
+pub struct OrderSyncRpc {
+    sender: mpsc::Sender<OrderSyncRequest>,
+}
+
+impl OrderSyncRpc {
+    pub async fn call(
+        &mut self,
+        peer_id: PeerId,
+        request: order_sync::messages::Request,
+    ) -> order_sync::Result {
+        let (sender, receiver) = oneshot::channel();
+        self.sender.send((peer_id, request, sender)).await?;
+        receiver.await?
+    }
+}
+
+/// A `Send + Sync` handle to a running [`Node`].
+#[derive(Clone)]
+pub struct NodeHandle {
+    sender:  mpsc::Sender<Command>,
+    peer_id: PeerId,
+}
+
+impl NodeHandle {
+    /// Whether the node is gone, so requests fail with "Node stopped".
+    pub fn is_stopped(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// A [`publisher::Publisher`] for `topic`.
+    pub fn publisher(&self, topic: &str) -> publisher::Publisher {
+        publisher::Publisher::new(self.clone(), topic)
+    }
+
+    /// The peer id of the node.
+    pub fn local_peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Receive the messages arriving on subscribed topics.
+    ///
+    /// Messages are dropped for consumers that fall behind.
+    pub async fn events(&mut self) -> Result<mpsc::Receiver<Event>> {
+        let (sender, receiver) = mpsc::channel(64);
+        self.sender
+            .send(Command::Events { sender })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver)
+    }
+
+    /// Call `handler` on every message on `topic`, at most `limit` at once,
+    /// see [`route`]. Routing `topic` again replaces the handler.
+    pub async fn route<F, Fut>(&mut self, topic: &str, limit: usize, handler: F) -> Result<()>
+    where
+        F: FnMut(route::Message) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.sender
+            .send(Command::Route {
+                topic:  topic.into(),
+                sender: route::spawn(limit, handler),
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// The replicated map `map`, see [`replica`].
+    pub fn state(&self, map: &str) -> Result<replica::SharedState> {
+        replica::validate(map)?;
+        Ok(replica::SharedState::new(self.clone(), map))
+    }
+
+    /// The value of `key` in the replicated `map`, which we join if new.
+    pub async fn get_state(&mut self, map: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::GetState {
+                map: map.into(),
+                key: key.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Set `key` in the replicated `map` to `value`, or remove it without
+    /// one, and send the change to the other replicas.
+    pub async fn set_state(
+        &mut self,
+        map: &str,
+        key: &str,
+        value: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SetState {
+                map: map.into(),
+                key: key.into(),
+                value,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// The keys and values of the replicated `map`.
+    pub async fn state_entries(&mut self, map: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::StateEntries {
+                map: map.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Subscribe to `topic` and exchange values of `T` on it, see
+    /// [`typed`].
+    pub async fn typed_topic<T>(
+        &mut self,
+        topic: &str,
+    ) -> Result<(typed::TypedSender<T>, typed::TypedReceiver<T>)>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        self.subscribe(topic, TopicOptions::default()).await?;
+        let (sender, receiver) = mpsc::channel(route::BUFFER);
+        self.sender
+            .send(Command::Route {
+                topic: topic.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok((
+            typed::TypedSender::new(self.clone(), topic),
+            typed::TypedReceiver::new(receiver, Some(self.clone())),
+        ))
+    }
+
+    /// Subscribe to `topic` and exchange values of `T` on it with local
+    /// echo and delivery statuses, see [`typed`].
+    pub async fn echo_topic<T>(
+        &mut self,
+        topic: &str,
+    ) -> Result<(typed::EchoSender<T>, typed::EchoReceiver<T>)>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let (sender, receiver) = self.typed_topic(topic).await?;
+        let (receipt_sender, receipts) = mpsc::channel(route::BUFFER);
+        self.sender
+            .send(Command::Route {
+                topic:  typed::receipt_topic(topic),
+                sender: receipt_sender,
+            })
+            .await
+            .context("Node stopped")?;
+        let (echo_sender, echoes) = mpsc::unbounded();
+        Ok((
+            typed::EchoSender::new(sender, echo_sender),
+            typed::EchoReceiver::new(receiver, echoes, receipts),
+        ))
+    }
+
+    /// Subscribe to `topic` and call `handler` on the values of `T` on it,
+    /// at most `limit` at once, see [`typed`]. Returns the messages that did
+    /// not decode as `T`.
+    pub async fn on_topic<T, F, Fut>(
+        &mut self,
+        topic: &str,
+        limit: usize,
+        handler: F,
+    ) -> Result<mpsc::Receiver<typed::Malformed>>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+        F: FnMut(message::MeshMessage<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.subscribe(topic, TopicOptions::default()).await?;
+        let (errors, malformed) = mpsc::channel(route::BUFFER);
+        self.sender
+            .send(Command::Route {
+                topic:  topic.into(),
+                sender: typed::spawn(limit, Some(self.clone()), handler, errors),
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(malformed)
+    }
+
+    /// Stop calling the handler of `topic`.
+    pub async fn unroute(&mut self, topic: &str) -> Result<()> {
+        self.sender
+            .send(Command::Unroute {
+                topic: topic.into(),
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Make [`Node::run`] shut down gracefully and return, see
+    /// [`Node::close`].
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.sender
+            .send(Command::Shutdown)
+            .await
+            .context("Node stopped")
+    }
+
+    /// Wait until the mesh meets `criteria`, see [`ready`].
+    pub async fn wait_ready(&mut self, criteria: ready::Criteria) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::WaitReady { criteria, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Connect to a peer at `address`.
+    pub async fn dial(&mut self, address: Multiaddr) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Dial { address, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Look up the addresses of `peer_id` in the DHT, see
+    /// [`Node::find_peer`].
+    pub async fn find_peer(&mut self, peer_id: PeerId) -> Result<Vec<Multiaddr>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::FindPeer { peer_id, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Look up the peers closest to `key` in the DHT, see
+    /// [`Node::closest_peers`].
+    pub async fn closest_peers(&mut self, key: Vec<u8>) -> Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ClosestPeers { key, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Announce in the DHT that we provide `key`, see
+    /// [`Node::start_providing`].
+    pub async fn start_providing(&mut self, key: Vec<u8>) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::StartProviding { key, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Stop announcing `key`.
+    pub async fn stop_providing(&mut self, key: Vec<u8>) -> Result<()> {
+        self.sender
+            .send(Command::StopProviding { key })
+            .await
+            .context("Node stopped")
+    }
+
+    /// The keys we provide, with when they are announced next.
+    pub async fn provided(&mut self) -> Result<Vec<discovery::Provided>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Provided { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Point `name` at us in the DHT, see [`naming`].
+    pub async fn publish_name(
+        &mut self,
+        name: &str,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PublishName {
+                name: name.into(),
+                metadata,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Look up the latest record of `name` in the DHT.
+    pub async fn resolve_name(&mut self, name: &str) -> Result<naming::NameRecord> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ResolveName {
+                name: name.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Store `value` under `key` in the DHT, see [`Node::dht_put`].
+    pub async fn dht_put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::DhtPut {
+                key: key.to_vec(),
+                value,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Look up the values stored under `key` in the DHT, see
+    /// [`Node::dht_get`].
+    pub async fn dht_get(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::DhtGet {
+                key: key.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Timestamp a local event with the node's hybrid logical clock.
+    ///
+    /// The timestamp is larger than that of any message received so far.
+    pub async fn timestamp(&mut self) -> Result<Timestamp> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Timestamp { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Subscribe to `topic`. The subscription is restored when the node
+    /// restarts with the same data directory.
+    pub async fn subscribe(&mut self, topic: &str, options: TopicOptions) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Subscribe {
+                topic: topic.into(),
+                options,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Unsubscribe from `topic`. Returns false if not subscribed.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Unsubscribe {
+                topic: topic.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// The topics we are subscribed to, with their options.
+    pub async fn topics(&mut self) -> Result<Vec<(String, TopicOptions)>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Topics { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Publish `data` on `topic` to the gossip mesh. Returns once the
+    /// message was handed to the transport, or with [`outbound::Full`] if
+    /// too many publishes wait already.
+    pub async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<()> {
+        self.publish_now(topic, data).await.map(drop)
+    }
+
+    /// Publish like [`NodeHandle::publish`], telling whether the message
+    /// went to pubsub right away, see [`Node::publish_now`].
+    pub(crate) async fn publish_now(&mut self, topic: &str, data: &[u8]) -> Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Publish {
+                topic: topic.into(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Hand `data` on `topic` directly to `peer` if the command queue has
+    /// room, without waiting for the node, for receipts sent while polling.
+    pub(crate) fn try_publish_to(&mut self, peer: &PeerId, topic: &str, data: Vec<u8>) -> bool {
+        let (sender, _) = oneshot::channel();
+        self.sender
+            .try_send(Command::PublishTo {
+                peers: vec![peer.clone()],
+                topic: topic.into(),
+                data,
+                sender,
+            })
+            .is_ok()
+    }
+
+    /// Republish a message received from `source` with `provenance` on
+    /// `topic`, as a bridge or relay. Receivers see `source` as the origin
+    /// of the message and us as one of its relays.
+    pub async fn republish(
+        &mut self,
+        topic: &str,
+        data: &[u8],
+        source: &PeerId,
+        provenance: &Provenance,
+    ) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Republish {
+                topic: topic.into(),
+                data: data.to_vec(),
+                source: source.clone(),
+                provenance: provenance.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Publish the new version of our state on state topic `topic`.
+    ///
+    /// Only the difference with the previous version is sent, with a full
+    /// snapshot at regular intervals. Receivers subscribe with
+    /// [`TopicOptions::delta`] set to reconstruct the full state.
+    pub async fn publish_state(&mut self, topic: &str, data: &[u8]) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PublishState {
+                topic: topic.into(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Deliver `data` on `topic` directly to those of `peers` that are
+    /// currently connected, bypassing gossip fanout.
+    ///
+    /// Returns the peers the message was handed to.
+    pub async fn publish_to(
+        &mut self,
+        peers: &[PeerId],
+        topic: &str,
+        data: &[u8],
+    ) -> Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PublishTo {
+                peers: peers.to_vec(),
+                topic: topic.into(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Send `data` to `peer_id` alone, dialing it if needed, and wait until
+    /// it acknowledged the message. The peer receives
+    /// [`Event::DirectMessage`], not a message of a topic.
+    pub async fn send_to(&mut self, peer_id: &PeerId, data: &[u8]) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SendTo {
+                peer_id: peer_id.clone(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Offer `peer_id` the file at `path`, and wait until it accepted or
+    /// refused it. The transfer goes on in the background, reported by
+    /// [`Event::FileTransfer`], see [`file`].
+    pub async fn send_file(&mut self, peer_id: &PeerId, path: &Path) -> Result<file::FileId> {
+        let path = path.to_path_buf();
+        let manifest = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || file::Manifest::of(&path))
+                .await
+                .context("Hashing the file")??
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SendFile {
+                peer_id: peer_id.clone(),
+                path,
+                manifest,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Deliver `data` on `topic` to `destination` by store-and-forward
+    /// [`dtn`], carried by the peers we meet until it gets there or expires.
+    /// Needs DTN mode. Full stores evict bundles of lower `priority` first,
+    /// with the default eviction policy. The returned id looks up the
+    /// bundle's trace.
+    pub async fn send_bundle(
+        &mut self,
+        destination: &PeerId,
+        topic: &str,
+        data: &[u8],
+        priority: u8,
+    ) -> Result<dtn::BundleId> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SendBundle {
+                destination: destination.clone(),
+                topic: topic.into(),
+                data: data.to_vec(),
+                priority,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// The custody and delivery reports received on our bundle `id`, or
+    /// `None` once it expired.
+    pub async fn bundle_trace(&mut self, id: &dtn::BundleId) -> Result<Option<dtn::Trace>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::BundleTrace {
+                id: id.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Send `data` to `peer_id` and wait for its reply, see
+    /// [`Node::request`].
+    pub async fn request(&mut self, peer_id: &PeerId, data: &[u8]) -> Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Request {
+                peer_id: peer_id.clone(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver.await.context("Node stopped")??)
+    }
+
+    /// Answer requests of other peers.
+    ///
+    /// Inbound requests arrive on the returned stream and are answered with
+    /// [`RpcRequest::respond`]. Calling this again replaces the stream.
+    pub async fn serve_requests(&mut self) -> Result<mpsc::Receiver<RpcRequest>> {
+        let (handler, receiver) = mpsc::channel(16);
+        self.sender
+            .send(Command::ServeRequests { handler })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver)
+    }
+
+    /// Provide the named service to other nodes.
+    ///
+    /// Inbound calls arrive on the returned stream and are answered with
+    /// [`ServiceRequest::respond`].
+    pub async fn advertise_service(
+        &mut self,
+        service: &str,
+    ) -> Result<mpsc::Receiver<ServiceRequest>> {
+        self.register_service(ServiceDescriptor::new(service, "")).await
+    }
+
+    /// Provide the service of `descriptor`, advertising its version and
+    /// metadata too, like [`NodeHandle::advertise_service`].
+    pub async fn register_service(
+        &mut self,
+        descriptor: ServiceDescriptor,
+    ) -> Result<mpsc::Receiver<ServiceRequest>> {
+        let (handler, receiver) = mpsc::channel(16);
+        self.sender
+            .send(Command::AdvertiseService {
+                descriptor,
+                handler,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver)
+    }
+
+    /// The known providers of `service` with their descriptors, connected
+    /// and nearest first.
+    pub async fn find_services(
+        &mut self,
+        service: &str,
+    ) -> Result<Vec<(PeerId, ServiceDescriptor)>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::FindServices {
+                service: service.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Stop providing the named service.
+    pub async fn withdraw_service(&mut self, service: &str) -> Result<()> {
+        self.sender
+            .send(Command::WithdrawService {
+                service: service.into(),
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Call the lowest-latency provider of `service`, failing over to the
+    /// next provider on error.
+    pub async fn call_service(&mut self, service: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::CallService {
+                service: service.into(),
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver.await.context("Node stopped")??)
+    }
+
+    /// Take part in leader election for `group`, among the members of
+    /// `config`.
+    ///
+    /// Leadership changes, including whether we are the leader, are sent on
+    /// the returned stream. Leave by dropping the stream or with
+    /// [`NodeHandle::leave_election`].
+    pub async fn join_election(
+        &mut self,
+        group: &str,
+        config: ElectionConfig,
+    ) -> Result<mpsc::Receiver<LeadershipChange>> {
+        let (changes, receiver) = mpsc::channel(16);
+        self.sender
+            .send(Command::JoinElection {
+                group: group.into(),
+                config,
+                changes,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver)
+    }
+
+    pub async fn leave_election(&mut self, group: &str) -> Result<()> {
+        self.sender
+            .send(Command::LeaveElection {
+                group: group.into(),
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Add `delta` to the mesh-wide counter `name`.
+    pub async fn increment(&mut self, name: &str, delta: i64) -> Result<()> {
+        self.sender
+            .send(Command::Increment {
+                name: name.into(),
+                delta,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Set this node's contribution to the mesh-wide gauge `name`.
+    ///
+    /// The mesh value is the sum over all live nodes.
+    pub async fn set_gauge(&mut self, name: &str, value: i64) -> Result<()> {
+        self.sender
+            .send(Command::SetGauge {
+                name: name.into(),
+                value,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// The peer that `name` refers to, see [`Node::resolve_peer`].
+    pub async fn resolve_peer(&mut self, name: &str) -> Result<PeerId> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ResolvePeer {
+                name: name.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// What we know about `peer_id`, see [`Node::peer_info`].
+    pub async fn peer_info(&mut self, peer_id: &PeerId) -> Result<Option<PeerInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PeerInfo {
+                peer_id: peer_id.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Keep a connection open to the peer at `address`, which must end in
+    /// `/p2p/<peer id>`, see [`warm`].
+    pub async fn warm_peer(&mut self, address: Multiaddr) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::WarmPeer { address, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Stop keeping a connection open to `peer_id`, returning false if it
+    /// was not hot.
+    pub async fn cool_peer(&mut self, peer_id: &PeerId) -> Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::CoolPeer {
+                peer_id: peer_id.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The peers [`NodeHandle::warm_peer`] keeps connections to.
+    pub async fn hot_peers(&mut self) -> Result<Vec<warm::HotPeer>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::HotPeers { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// See [`Node::pin`].
+    pub async fn pin(&mut self, address: Multiaddr) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Pin { address, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// See [`Node::unpin`].
+    pub async fn unpin(&mut self, peer_id: &PeerId) -> Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Unpin {
+                peer_id: peer_id.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// See [`Node::pinned_peers`].
+    pub async fn pinned_peers(&mut self) -> Result<Vec<warm::HotPeer>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PinnedPeers { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The peers we are connected to.
+    pub async fn peers(&mut self) -> Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Peers { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The addresses our listeners are bound to, with the ports the OS
+    /// chose, see [`family`].
+    pub async fn listen_addrs(&mut self) -> Result<Vec<Multiaddr>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ListenAddrs { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// See [`Node::ban`].
+    pub async fn ban(&mut self, peer_id: PeerId, duration: Option<Duration>) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Ban {
+                peer_id,
+                duration,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// See [`Node::unban`].
+    pub async fn unban(&mut self, peer_id: PeerId) -> Result<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Unban { peer_id, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Whether peers can dial us and at which addresses, see [`autonat`].
+    pub async fn nat_status(&mut self) -> Result<autonat::NatStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::NatStatus { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The mesh-wide value of counter or gauge `name`, as far as we know.
+    pub async fn metric(&mut self, name: &str) -> Result<Option<i64>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Metric {
+                name: name.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Help serve distributed locks, leading the lock table when elected.
+    pub fn serve_locks(&self, config: ElectionConfig) {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = lock::serve(handle, config).await {
+                error!("Lock server stopped: {:?}", err);
+            }
+        });
+    }
+
+    /// Acquire the distributed lock `name` for `ttl`.
+    ///
+    /// Returns `None` if the lock is held by someone else. The lock must be
+    /// renewed by acquiring it again before it expires. Pass the fencing
+    /// token of the returned [`lock::Lock`] along to the guarded resource.
+    pub async fn lock(&mut self, name: &str, ttl: Duration) -> Result<Option<lock::Lock>> {
+        let requested = Instant::now();
+        let request = lock::Request::Acquire {
+            name:   name.into(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        match self.call_lock_service(&request).await? {
+            lock::Response::Granted { token, ttl_ms } => {
+                Ok(Some(lock::Lock {
+                    name: name.into(),
+                    token,
+                    // Measure from before the request to be conservative.
+                    expires: requested + Duration::from_millis(ttl_ms),
+                }))
+            }
+            lock::Response::Held { .. } => Ok(None),
+            response => Err(anyhow::anyhow!("Unexpected lock response {:?}", response)),
+        }
+    }
+
+    /// Release a lock before its lease expires.
+    pub async fn unlock(&mut self, lock: lock::Lock) -> Result<()> {
+        let request = lock::Request::Release {
+            name:  lock.name,
+            token: lock.token,
+        };
+        match self.call_lock_service(&request).await? {
+            lock::Response::Released => Ok(()),
+            lock::Response::NotHeld => Err(anyhow::anyhow!("Lock lease already expired")),
+            response => Err(anyhow::anyhow!("Unexpected lock response {:?}", response)),
+        }
+    }
+
+    async fn call_lock_service(&mut self, request: &lock::Request) -> Result<lock::Response> {
+        let data = serde_cbor::to_vec(request).context("Encoding lock request")?;
+        let response = self.call_service(lock::SERVICE, &data).await?;
+        serde_cbor::from_slice(&response).context("Decoding lock response")
+    }
+
+    /// Consume jobs pushed to work queue `queue`.
+    ///
+    /// Each job is delivered to exactly one consumer. Respond to a job once it
+    /// is done; unanswered jobs are handed to another consumer after their
+    /// visibility timeout.
+    pub async fn consume_jobs(&mut self, queue: &str) -> Result<mpsc::Receiver<ServiceRequest>> {
+        self.advertise_service(&service::job_service(queue)).await
+    }
+
+    /// Push a job to work queue `queue` and wait for its result.
+    ///
+    /// The consumer is selected by consistent hashing on `key`, so jobs with
+    /// the same key go to the same consumer while the consumer set is stable.
+    pub async fn push_job(
+        &mut self,
+        queue: &str,
+        key: &str,
+        data: &[u8],
+        visibility_timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PushJob {
+                queue: queue.into(),
+                key: key.into(),
+                data: data.to_vec(),
+                visibility_timeout,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        Ok(receiver.await.context("Node stopped")??)
+    }
+
+    /// Enter or leave [`power`] save mode.
+    pub async fn set_power_save(&mut self, enabled: bool) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PowerSave { enabled, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Move `address` from `expected` to `actual` in the address book and
+    /// dial `actual`, after an [`Event::IdentityMismatch`].
+    pub async fn accept_identity(
+        &mut self,
+        expected: PeerId,
+        actual: PeerId,
+        address: Multiaddr,
+    ) -> Result<()> {
+        self.sender
+            .send(Command::AcceptIdentity {
+                expected,
+                actual,
+                address,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Encrypt payloads on `topic` with the pre-shared `key`, and accept
+    /// [`keyring`] rotations of it from `admin`.
+    pub async fn set_topic_key(
+        &mut self,
+        topic: &str,
+        key: keyring::Key,
+        admin: PeerId,
+    ) -> Result<()> {
+        self.sender
+            .send(Command::SetTopicKey {
+                topic: topic.into(),
+                key,
+                admin,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// The generation and fingerprint of the current key of `topic`, to
+    /// check that peers hold the same key, see [`keyring::fingerprint`].
+    pub async fn topic_fingerprint(&mut self, topic: &str) -> Result<Option<(u32, String)>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::TopicFingerprint {
+                topic: topic.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Replace the key of `topic`, of which we are the admin, and send it to
+    /// `members` only. Returns the generation of the new key.
+    ///
+    /// On a private topic, members without a [`membership`] grant are
+    /// skipped.
+    pub async fn rotate_topic_key(&mut self, topic: &str, members: &[PeerId]) -> Result<u32> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RotateTopicKey {
+                topic: topic.into(),
+                members: members.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Ask `admin` to admit us to private topic `topic`. Its key arrives once
+    /// the admin approves.
+    pub async fn request_membership(&mut self, topic: &str, admin: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::RequestMembership {
+                topic: topic.into(),
+                admin,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Peers asking to join private topic `topic`, which we administer.
+    pub async fn pending_members(&mut self, topic: &str) -> Result<Vec<PeerId>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PendingMembers {
+                topic: topic.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The peers online on `topic` and when each was last seen there, see
+    /// [`presence`]. Empty without `--presence`.
+    pub async fn roster(&mut self, topic: &str) -> Result<Vec<presence::Present>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Roster {
+                topic: topic.into(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The verified profile `peer_id` published, see [`nickname`].
+    pub async fn profile(&mut self, peer_id: &PeerId) -> Result<Option<nickname::Profile>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Profile {
+                peer_id: peer_id.clone(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// The bytes sent and received, in total and recently, by peer and by
+    /// protocol, see [`accounting`].
+    pub async fn bandwidth_usage(&mut self) -> Result<accounting::Report> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::BandwidthUsage { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Current values of the metrics pushed to [`statsd`].
+    pub async fn samples(&mut self) -> Result<Vec<statsd::Sample>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Samples { sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")
+    }
+
+    /// Grant `member` access to private topic `topic` and send it the key.
+    /// Returns the generation of the new key.
+    pub async fn approve_member(&mut self, topic: &str, member: PeerId) -> Result<u32> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ApproveMember {
+                topic: topic.into(),
+                member,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Revoke the grant of `member` for private topic `topic`, rotating the
+    /// key to the remaining members. Returns the generation of the new key.
+    pub async fn revoke_member(&mut self, topic: &str, member: PeerId) -> Result<u32> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RevokeMember {
+                topic: topic.into(),
+                member,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Drop all messages from `peer_id` before they are delivered.
+    pub async fn mute(&mut self, peer_id: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::Mute {
+                peer_id,
+                muted: true,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    pub async fn unmute(&mut self, peer_id: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::Mute {
+                peer_id,
+                muted: false,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Honor the [`moderation`] blocklists for `topic` signed by `moderator`.
+    pub async fn trust_moderator(&mut self, topic: &str, moderator: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::TrustModerator {
+                topic: topic.into(),
+                moderator,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Block `blocked` from `topic` on the nodes that trust us as its
+    /// moderator, replacing our previous blocklist.
+    pub async fn publish_blocklist(&mut self, topic: &str, blocked: &[PeerId]) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PublishBlocklist {
+                topic: topic.into(),
+                blocked: blocked.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Only deliver messages on `topic` from `owner` and the peers holding
+    /// a [`capability`] it signed.
+    pub async fn restrict_topic(&mut self, topic: &str, owner: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::RestrictTopic {
+                topic: topic.into(),
+                owner: Some(owner),
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Deliver messages on `topic` from anyone again.
+    pub async fn unrestrict_topic(&mut self, topic: &str) -> Result<()> {
+        self.sender
+            .send(Command::RestrictTopic {
+                topic: topic.into(),
+                owner: None,
+            })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Sign a capability for `holder` to publish on `topic`, which we own,
+    /// valid until `expires` or forever.
+    pub async fn issue_capability(
+        &mut self,
+        topic: &str,
+        holder: PeerId,
+        expires: Option<std::time::SystemTime>,
+    ) -> Result<capability::Capability> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::IssueCapability {
+                topic: topic.into(),
+                holder,
+                expires,
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Attach `capability` to what we publish on its restricted topic.
+    /// Fails if the topic is not restricted or the owner did not sign it
+    /// for us.
+    pub async fn add_capability(&mut self, capability: capability::Capability) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::AddCapability { capability, sender })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+
+    /// Run the [`schedule`]d actions of `issuer`.
+    pub async fn trust_scheduler(&mut self, issuer: PeerId) -> Result<()> {
+        self.sender
+            .send(Command::TrustScheduler { issuer })
+            .await
+            .context("Node stopped")
+    }
+
+    /// Run the action `name` with `data` at wall clock time `at`, here and
+    /// on the nodes that trust us as a scheduler.
+    pub async fn schedule(
+        &mut self,
+        name: &str,
+        at: std::time::SystemTime,
+        data: &[u8],
+    ) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Schedule {
+                name: name.into(),
+                at,
+                data: data.to_vec(),
+                sender,
+            })
+            .await
+            .context("Node stopped")?;
+        receiver.await.context("Node stopped")?
+    }
+}
+
+impl Node {
+    /// Set up a node to embed in an application, see [`builder`].
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    pub async fn new(peer_id_keys: identity::Keypair) -> Result<Self> {
+        Self::with_listeners(
+            peer_id_keys,
+            activation::listen_fds(),
+            shaping::Config::default(),
+            security::Config::default(),
+            admission::Config::default(),
+            health::Config::default(),
+        )
+        .await
+    }
+
+    /// Create a node accepting connections on already listening sockets,
+    /// like those passed by systemd or a previous instance, limiting its
+    /// traffic to the `bandwidth` caps and its connections to `limits`,
+    /// securing connections with the protocols of `security` and timing
+    /// them out as `health` says.
+    pub async fn with_listeners(
+        peer_id_keys: identity::Keypair,
+        listeners: Vec<TcpListener>,
+        bandwidth: shaping::Config,
+        security: security::Config,
+        limits: admission::Config,
+        health: health::Config,
+    ) -> Result<Self> {
+        // Generate peer id
+        let peer_id = PeerId::from(peer_id_keys.public());
+        if names::format() == names::Format::Full {
+            info!("Peer Id: {}", peer_id.clone());
+        } else {
+            // The address form is kept in full
+            info!("Peer Id: {} is /p2p/{}", peer_id, peer_id);
+        }
+
+        // Create a transport
+        let activated = Activated::new(listeners);
+        let shaper = Shaper::new(bandwidth);
+        let udp = Udp::default();
+        let identities = mismatch::Identities::default();
+        let backoffs = dial::Backoffs::default();
+        let families = family::Families::default();
+        let proxies = proxy::Proxies::default();
+        let gate = gate::Gate::default();
+        let admission = admission::Admission::new(&limits);
+        let meter = accounting::Meter::default();
+        let (transport, bandwidth_monitor) = make_transport(
+            peer_id_keys.clone(),
+            activated.clone(),
+            shaper,
+            udp.clone(),
+            identities.clone(),
+            backoffs.clone(),
+            families.clone(),
+            proxies.clone(),
+            gate.clone(),
+            admission.clone(),
+            security,
+            meter.clone(),
+            health.dial,
+        )
+        .context("Creating libp2p transport")?;
+
+        let keyring = Keyring::new(&peer_id_keys);
+        let membership = Membership::new(peer_id_keys.clone());
+        let moderation = Moderation::new(peer_id_keys.clone());
+        let schedule = schedule::Schedule::new(peer_id_keys.clone());
+        let lifecycle =
+            lifecycle::Lifecycle::new(peer_id_keys.clone(), behaviour::discovery::AGENT_VERSION);
+        let nicknames = nickname::Nicknames::new(peer_id_keys.clone());
+
+        // Create node behaviour
+        let behaviour = Behaviour::new(peer_id_keys)
+            .await
+            .context("Creating node behaviour")?;
+
+        // Executor for connection background tasks.
+        let executor = Box::new(|future| {
+            trace!("Spawning background task");
+            tokio::spawn(future);
+        });
+
+        // Create a Swarm to manage peers and events.
+        let span = spans::swarm(&peer_id);
+        let mut swarm: Swarm<Behaviour> = SwarmBuilder::new(transport, behaviour, peer_id)
+            .executor(executor)
+            .connection_limits(limits.swarm_limits())
+            .build();
+
+        // Create a channel for OrderSync requests
+        let request_buffer_size = 16;
+        let (order_sync_sender, order_sync_receiver) = mpsc::channel(request_buffer_size);
+
+        // Create a channel for handle commands
+        let (command_sender, command_receiver) = mpsc::channel(request_buffer_size);
+
+        // Create a channel for debug bundle requests
+        let (bundle_sender, bundle_receiver) = mpsc::channel(request_buffer_size);
+        swarm.serve_diagnostics(bundle_sender);
+        let (archive_sender, archive_receiver) = mpsc::channel(request_buffer_size);
+
+        Ok(Self {
+            bandwidth_monitor,
+            swarm,
+            meter,
+            udp,
+            activated,
+            order_sync_sender,
+            order_sync_receiver,
+            command_sender,
+            command_receiver,
+            bundle_receiver,
+            bundle_sources: bundle::Sources::default(),
+            elections: HashMap::new(),
+            subscriptions: Subscriptions::default(),
+            state_encoders: HashMap::new(),
+            state_decoders: HashMap::new(),
+            schemas: SchemaRegistry::default(),
+            keyring,
+            membership,
+            moderation,
+            schedule,
+            lifecycle,
+            rotations: rotation::Rotations::default(),
+            presence: None,
+            nicknames,
+            replicas: replica::Replicas::default(),
+            event_senders: Vec::new(),
+            routes: route::Routes::default(),
+            topic_activity: HashMap::new(),
+            aggregates: Aggregates::new(Instant::now()),
+            tick: interval(TICK_INTERVAL),
+            mdns: true,
+            power_save: false,
+            quiet_hours: quiet::Schedule::default(),
+            dormant: false,
+            clock_jumps: clock::Detector::new(clock::Config::default()),
+            roaming: roaming::Watcher::new(),
+            recent: control::Recent::default(),
+            journal: None,
+            audit: None,
+            batch: power::Batch::default(),
+            outbox: outbox::Outbox::default(),
+            publish_queue: outbound::Queue::default(),
+            middleware: middleware::Chain::default(),
+            archive: None,
+            archive_sender,
+            archive_receiver,
+            backfills: archive::Backfills::default(),
+            limits: profile::Limits::default(),
+            negotiation: negotiation::Failures::default(),
+            dials: dial::Dials::default(),
+            backoffs,
+            families,
+            proxies,
+            hot: warm::Peers::default(),
+            supervisor: supervisor::Supervisor::default(),
+            pinned: warm::Peers::default(),
+            reaper: health::Reaper::new(health),
+            known: addressbook::AddressBook::default(),
+            nat: autonat::NatStatus::default(),
+            metrics: metrics::Metrics::default(),
+            probes: None,
+            storage: None,
+            identities,
+            identity_policy: mismatch::Policy::default(),
+            gate,
+            admission,
+            subsystem_failures: degrade::Policy::default(),
+            degraded: Vec::new(),
+            bootstrap_peers: behaviour::discovery::bootnodes()?,
+            bootstrap_quorum: 1,
+            bootstrap: bootstrap::Progress::new(Vec::new(), 1, Instant::now()),
+            dnsaddr: dnsaddr::Names::default(),
+            waiting_ready: Vec::new(),
+            listener_ids: Vec::new(),
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            stopping: false,
+            span,
+            connections: HashMap::new(),
+        })
+    }
+
+    /// Start the behaviours and listen on the sockets passed to us, or else
+    /// on all interfaces of the address families of
+    /// [`Node::set_address_family`] and whatever port the OS assigns.
+    pub fn start(&mut self) -> Result<()> {
+        self.start_behaviours()?;
+        if self.activated.addresses().is_empty() {
+            let families = self.families.config().listen_families();
+            for &family in &families {
+                let result = self
+                    .listen(family.any_address())
+                    .with_context(|| format!("Starting to listen on {}", family));
+                match result {
+                    // Hosts without IPv6 still listen on IPv4
+                    Err(err) if family == family::Family::Ip6 && families.len() > 1 => {
+                        warn!("Not listening on IPv6: {:#}", err);
+                    }
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start the behaviours and listen on the sockets passed to us only.
+    pub(crate) fn start_behaviours(&mut self) -> Result<()> {
+        if let Some(err) = self.swarm.take_mdns_error() {
+            self.degrade(degrade::Subsystem::Mdns, err)?;
+        }
+        self.swarm.start()?;
+        self.swarm.subscribe(aggregate::TOPIC);
+        self.swarm.subscribe(lifecycle::TOPIC);
+        self.swarm.subscribe(rotation::TOPIC);
+        self.swarm.subscribe(nickname::TOPIC);
+        self.announce(lifecycle::Kind::Join);
+        for address in self.activated.addresses() {
+            self.listen(address.clone())
+                .with_context(|| format!("Listening on inherited socket {}", address))?;
+        }
+        self.start_bootstrap();
+        Ok(())
+    }
+
+    /// Also bootstrap through the peer at `address`, which must end in
+    /// `/p2p/<peer id>`, or the peers a `/dnsaddr/` address lists, see
+    /// [`dnsaddr`]. Call before [`Node::start`].
+    pub fn add_bootstrap_peer(&mut self, address: &Multiaddr) -> Result<()> {
+        if dnsaddr::is_dnsaddr(address) {
+            self.dnsaddr.add(address.clone(), Instant::now());
+            return Ok(());
+        }
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Bootstrap peer {} has no /p2p/ peer id", address))?;
+        self.bootstrap_peers.push((peer_id, address));
+        Ok(())
+    }
+
+    /// Keep the registrations of other peers as a [`rendezvous`] point.
+    pub fn set_rendezvous_server(&mut self) {
+        info!("Serving as a rendezvous point");
+        self.swarm.serve_rendezvous();
+    }
+
+    /// Receive files into the directory of `config`, see [`file`].
+    pub fn set_files(&mut self, config: file::Config) {
+        if let Some(dir) = &config.dir {
+            info!("Receiving files up to {} into {}", config.max_size, dir.display());
+        }
+        self.swarm.set_file_config(config);
+    }
+
+    /// Offer `peer_id` the file at `path`, reading it to hash its chunks.
+    /// Progress is reported by [`Event::FileTransfer`], see [`file`].
+    pub fn send_file(&mut self, peer_id: &PeerId, path: &Path) -> Result<file::FileId> {
+        let manifest = file::Manifest::of(path)?;
+        Ok(self
+            .swarm
+            .offer_file(peer_id, path.to_path_buf(), manifest, None))
+    }
+
+    /// Register our topics at the [`rendezvous`] point at `address`, which
+    /// must end in `/p2p/<peer id>`, and discover their other peers there.
+    pub fn add_rendezvous_point(&mut self, address: &Multiaddr) -> Result<()> {
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Rendezvous point {} has no /p2p/ peer id", address))?;
+        self.swarm.add_address(&peer_id, address);
+        self.swarm.add_rendezvous_point(peer_id);
+        Ok(())
+    }
+
+    /// Turn off the [`discovery`] mechanisms `config` disables and apply its
+    /// DHT settings. Call before [`Node::start`].
+    pub fn set_discovery(&mut self, config: discovery::Config) -> Result<()> {
+        if config.dht != discovery::Dht::default() {
+            info!("DHT queries with {:?}", config.dht);
+            self.swarm.set_dht(config.dht);
+        }
+        if config.ping != latency::Config::default() {
+            info!("Pinging peers with {:?}", config.ping);
+            self.swarm.set_ping(config.ping);
+        }
+        if !config.mdns {
+            info!("mDNS discovery off");
+            self.mdns = false;
+            self.swarm.suspend_mdns();
+        } else if config.lan != discovery::Lan::default() {
+            info!("mDNS discovery with {:?}", config.lan);
+            self.swarm.set_lan(config.lan)?;
+        }
+        if !config.bootnodes {
+            info!("Not bootstrapping through the 0x Mesh bootnodes");
+            let bootnodes = behaviour::discovery::bootnodes()?;
+            self.bootstrap_peers
+                .retain(|peer| !bootnodes.iter().any(|(peer_id, _)| *peer_id == peer.0));
+            self.swarm.remove_bootnodes()?;
+        }
+        Ok(())
+    }
+
+    /// Complete the bootstrap once `quorum` bootstrap peers answered. Call
+    /// before [`Node::start`].
+    pub fn set_bootstrap_quorum(&mut self, quorum: usize) {
+        self.bootstrap_quorum = quorum;
+    }
+
+    /// Dial all bootstrap peers at once, see [`bootstrap`].
+    fn start_bootstrap(&mut self) {
+        let peers = self.bootstrap_peers.iter().map(|(peer_id, _)| peer_id.clone());
+        self.bootstrap = bootstrap::Progress::new(peers, self.bootstrap_quorum, Instant::now())
+            .with_names(self.dnsaddr.len());
+        info!(
+            "Dialing {} bootstrap peers, waiting for {}",
+            self.bootstrap_peers.len(),
+            self.bootstrap_quorum
+        );
+        self.resolve_dnsaddrs(Instant::now());
+        for (peer_id, address) in &self.bootstrap_peers {
+            self.proxies.add_address(peer_id, address);
+            self.swarm.add_address(peer_id, address.clone());
+            if let Err(err) = Swarm::dial(&mut self.swarm, peer_id) {
+                debug!("Could not dial bootstrap peer {}: {:?}", peer_id, err);
+            }
+        }
+        if self.bootstrap.is_done() {
+            self.swarm.start_dht(Instant::now());
+        }
+    }
+
+    /// Dial the peers of the address book seen recently, see
+    /// [`addressbook`].
+    fn start_reconnect(&mut self) {
+        let recent = self.known.reconnect(std::time::SystemTime::now(), Instant::now());
+        if recent.is_empty() {
+            return;
+        }
+        info!("Reconnecting to {} recently seen peers", recent.len());
+        let dial = self.families.config().dial;
+        for (peer_id, mut addresses) in recent {
+            dial.sort(&mut addresses);
+            for address in addresses {
+                self.swarm.add_address(&peer_id, address);
+            }
+        }
+        self.tick_address_book(Instant::now());
+    }
+
+    /// Redial the peers of the address book that did not reconnect yet, and
+    /// write it when due.
+    fn tick_address_book(&mut self, now: Instant) {
+        let swarm = &self.swarm;
+        for peer_id in self.known.due(now, |peer_id| Swarm::is_connected(swarm, peer_id)) {
+            debug!("Reconnecting to {}", peer_id);
+            if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                debug!("Could not reconnect to {}: {:?}", peer_id, err);
+            }
+        }
+        self.record_known_peers(now);
+        if let Err(err) = self.known.tick(now) {
+            error!("Could not save the address book: {:#}", err);
+        }
+    }
+
+    /// Record the connected peers in the address book, with the addresses
+    /// they announce.
+    fn record_known_peers(&mut self, now: Instant) {
+        let connected = self.peers();
+        let known_peers = self.known_peers();
+        let known_peers = known_peers.read().unwrap();
+        for peer_id in connected {
+            let addresses = known_peers
+                .get(&peer_id)
+                .and_then(|info| info.identify.as_ref())
+                .map_or_else(Vec::new, |identify| identify.listen_addrs.clone());
+            self.known.seen(&peer_id, addresses, std::time::SystemTime::now(), now);
+        }
+    }
+
+    /// Look up the `/dnsaddr/` bootstrap entries due at `now`.
+    fn resolve_dnsaddrs(&mut self, now: Instant) {
+        for address in self.dnsaddr.due(now) {
+            debug!("Resolving bootstrap peers {}", address);
+            let mut sender = self.command_sender.clone();
+            tokio::spawn(async move {
+                let result = dnsaddr::resolve(address.clone()).await;
+                let _ = sender
+                    .send(Command::DnsaddrResolved { address, result })
+                    .await;
+            });
+        }
+    }
+
+    /// Dial the bootstrap peers `address` resolved to.
+    fn dnsaddr_resolved(
+        &mut self,
+        address: &Multiaddr,
+        result: Result<Vec<(PeerId, Multiaddr)>>,
+    ) {
+        let now = Instant::now();
+        let (first, peers) = self.dnsaddr.resolved(address, result, now);
+        for (peer_id, address) in &peers {
+            if !self.bootstrap_peers.contains(&(peer_id.clone(), address.clone())) {
+                self.bootstrap_peers.push((peer_id.clone(), address.clone()));
+            }
+            self.proxies.add_address(peer_id, address);
+            self.swarm.add_address(peer_id, address.clone());
+            if !first {
+                self.bootstrap.add(peer_id.clone());
+            }
+        }
+        if first {
+            let peer_ids = peers.iter().map(|(peer_id, _)| peer_id.clone()).collect();
+            if let Some(update) = self.bootstrap.resolved(peer_ids) {
+                self.bootstrap_update(update);
+            }
+        }
+        let mut dialed = Vec::new();
+        for (peer_id, _) in peers {
+            if dialed.contains(&peer_id) || Swarm::is_connected(&self.swarm, &peer_id) {
+                continue;
+            }
+            if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                debug!("Could not dial bootstrap peer {}: {:?}", peer_id, err);
+            }
+            dialed.push(peer_id);
+        }
+    }
+
+    /// Dial the bootstrap peers whose retry is due.
+    fn retry_bootstrap(&mut self) {
+        self.resolve_dnsaddrs(Instant::now());
+        for peer_id in self.bootstrap.due(Instant::now()) {
+            debug!("Dialing bootstrap peer {} again", peer_id);
+            if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                debug!("Could not dial bootstrap peer {}: {:?}", peer_id, err);
+            }
+        }
+    }
+
+    fn bootstrap_update(&mut self, update: bootstrap::Update) {
+        self.swarm.start_dht(Instant::now());
+        match update {
+            bootstrap::Update::Bootstrapped { peers, elapsed } => {
+                self.handle_event(Event::Bootstrapped { peers, elapsed });
+            }
+            bootstrap::Update::Failed { connected, quorum } => {
+                warn!(
+                    "Bootstrap incomplete: only {} of the {} bootstrap peers needed answered",
+                    connected, quorum
+                );
+                self.recent
+                    .record(format!("bootstrap failed, {} of {} peers", connected, quorum));
+            }
+        }
+    }
+
+    /// Connect to a peer at `address`, like [`NodeHandle::dial`].
+    pub fn dial(&mut self, address: Multiaddr) -> Result<()> {
+        info!("Dialing {}", address);
+        self.backoffs.clear(&address);
+        Swarm::dial_addr(&mut self.swarm, address)
+            .map_err(|err| anyhow::anyhow!("Dial failed: {:?}", err))
+    }
+
+    /// Also listen on `address`, like `/ip4/0.0.0.0/udp/4002` to accept
+    /// connections over the [`udp`] transport.
+    pub fn listen_on(&mut self, address: Multiaddr) -> Result<()> {
+        self.listen(address.clone())
+            .with_context(|| format!("Listening on {}", address))?;
+        Ok(())
+    }
+
+    fn listen(&mut self, address: Multiaddr) -> Result<()> {
+        let id = Swarm::listen_on(&mut self.swarm, address)
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        self.listener_ids.push(id);
+        Ok(())
+    }
+
+    /// Also listen on the local [`link`] address `<scheme>:<address>`,
+    /// like `ble:any`.
+    pub fn listen_on_link(&mut self, address: &str) -> Result<()> {
+        let (scheme, link_address) = match address.find(':') {
+            Some(index) => (&address[..index], &address[index + 1..]),
+            None => anyhow::bail!("Expected <scheme>:<address>, got {}", address),
+        };
+        self.listen(link::to_multiaddr(scheme, link_address))
+            .with_context(|| format!("Listening on link {}", address))?;
+        Ok(())
+    }
+
+    /// Put all topics used through the handle in application namespace
+    /// `name`, so unrelated applications using the same topic names do not
+    /// receive each other's messages. Call before subscribing.
+    pub fn set_namespace(&mut self, name: &str) {
+        info!("Using application namespace {}", name);
+        self.swarm.set_namespace(name);
+    }
+
+    /// Listen on and dial the address families of `config`, see
+    /// [`family`]. Call before [`Node::start`].
+    pub fn set_address_family(&mut self, config: family::Config) {
+        if config != family::Config::default() {
+            info!("Address families {:?}", config);
+        }
+        self.families.configure(config);
+        self.swarm.set_dial_preference(config.dial);
+    }
+
+    /// Dial TCP addresses through the SOCKS5 proxies of `routes`, for all
+    /// peers or for a bootstrap peer, see [`proxy`].
+    pub fn set_proxy(&mut self, routes: Vec<proxy::Route>) -> Result<()> {
+        for route in &routes {
+            match &route.peer {
+                Some(peer_id) => info!("Dialing {} through {}", peer_id, route.proxy),
+                None => info!("Dialing through {}", route.proxy),
+            }
+        }
+        self.proxies.configure(routes)
+    }
+
+    /// Refuse connections by the allow and deny lists of `config`, see
+    /// [`gate`].
+    pub fn set_gate(&mut self, config: gate::Config) {
+        if config != gate::Config::default() {
+            info!("Gating connections with {:?}", config);
+        }
+        self.gate.configure(config);
+    }
+
+    /// Keep the state of the persistent subsystems in `storage`, and flush
+    /// it on shutdown. Restores the bans, the address book and the
+    /// subscriptions kept there.
+    pub fn set_storage(&mut self, storage: storage::Shared) -> Result<()> {
+        self.load_bans(&storage)?;
+        self.load_address_book(&storage)?;
+        self.load_subscriptions(&storage)?;
+        self.storage = Some(storage);
+        Ok(())
+    }
+
+    /// Restore the bans kept in `storage` and keep them up to date.
+    pub fn load_bans(&mut self, storage: &storage::Shared) -> Result<()> {
+        self.gate.load(storage::Slot::new(storage, gate::NAMESPACE, gate::KEY))
+    }
+
+    /// Close the connections to `peer_id` and refuse it for `duration`, or
+    /// until [`Node::unban`].
+    pub fn ban(&mut self, peer_id: &PeerId, duration: Option<Duration>) -> Result<()> {
+        self.gate.ban(peer_id, duration, std::time::SystemTime::now())?;
+        match duration {
+            Some(duration) => info!("Banned {} for {:?}", peer_id, duration),
+            None => info!("Banned {}", peer_id),
+        }
+        self.recent.record(format!("banned {}", peer_id));
+        // The gate keeps the peer out
+        self.disconnect(peer_id);
+        Ok(())
+    }
+
+    /// Let a banned peer connect again. Returns whether it was banned.
+    pub fn unban(&mut self, peer_id: &PeerId) -> Result<bool> {
+        let banned = self.gate.unban(peer_id, std::time::SystemTime::now())?;
+        if banned {
+            info!("Unbanned {}", peer_id);
+        }
+        Ok(banned)
+    }
+
+    /// Remember the peers seen in the address book kept in `storage`, and
+    /// reconnect to the recent ones.
+    pub fn load_address_book(&mut self, storage: &storage::Shared) -> Result<()> {
+        let slot = storage::Slot::new(storage, addressbook::NAMESPACE, addressbook::KEY);
+        self.known = addressbook::AddressBook::load(slot)?;
+        debug!("Address book has {} peers", self.known.len());
+        self.start_reconnect();
+        Ok(())
+    }
+
+    /// Restore the subscriptions kept in `storage` and keep them up to date.
+    pub fn load_subscriptions(&mut self, storage: &storage::Shared) -> Result<()> {
+        let slot = storage::Slot::new(storage, subscriptions::NAMESPACE, subscriptions::KEY);
+        self.subscriptions = Subscriptions::load(slot)?;
+        for (topic, options) in self.subscriptions.topics() {
+            info!("Restoring subscription to {} ({:?})", topic, options);
+            self.swarm.subscribe(topic);
+            self.swarm.set_multicast(topic, options.multicast);
+            self.swarm.set_acked(topic, options.acked);
+            if options.delta {
+                self.state_decoders.insert(topic.clone(), Decoder::default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate payloads against the schema registry stored at `path`.
+    pub fn load_schemas(&mut self, path: &Path) -> Result<()> {
+        self.schemas = SchemaRegistry::load(path)?;
+        Ok(())
+    }
+
+    /// Create a Send + Sync handle to the OrderSync RPC interface.
+    pub fn order_sync_rpc(&self) -> OrderSyncRpc {
+        OrderSyncRpc {
+            sender: self.order_sync_sender.clone(),
+        }
+    }
+
+    /// Create a `Send + Sync` handle to control the node.
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            sender:  self.command_sender.clone(),
+            peer_id: self.local_peer_id().clone(),
+        }
+    }
+
+    /// A [`publisher::Publisher`] for `topic`, publishing through a
+    /// [`Node::handle`] while the node runs.
+    pub fn publisher(&self, topic: &str) -> publisher::Publisher {
+        self.handle().publisher(topic)
+    }
+
+    /// Run the event loop until [`Node::shutdown`] or
+    /// [`NodeHandle::shutdown`].
+    pub async fn run(&mut self) -> Result<()> {
+        let span = self.span.clone();
+        async {
+            while !self.stopping {
+                self.step().await?;
+            }
+            self.close(self.shutdown_timeout).await;
+            info!("Node shut down");
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Make [`Node::run`] shut down gracefully and return.
+    pub fn shutdown(&mut self) {
+        self.stopping = true;
+    }
+
+    /// Give [`Node::run`] `timeout` to shut down, [`SHUTDOWN_TIMEOUT`] by
+    /// default.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Shut down gracefully: stop listening, send the publishes queued so
+    /// far, held in power-save mode or in the outbox, then close all
+    /// connections, which lets the muxers tell peers. Gives up after
+    /// `timeout` and leaves the rest to dropping the node.
+    pub async fn close(&mut self, timeout: Duration) {
+        self.stopping = true;
+        self.record_probes();
+        if tokio::time::timeout(timeout, self.close_gracefully())
+            .await
+            .is_err()
+        {
+            warn!("Shutdown took over {:?}, dropping connections", timeout);
+        }
+    }
+
+    async fn close_gracefully(&mut self) {
+        info!("Shutting down");
+        for id in self.listener_ids.drain(..) {
+            let _ = Swarm::remove_listener(&mut self.swarm, id);
+        }
+        while let Ok(Some(command)) = self.command_receiver.try_next() {
+            // Switching power modes no longer matters
+            if !matches!(command, Command::PowerSave { .. }) {
+                self.handle_command(command);
+            }
+        }
+        while !self.publish_queue.is_empty() {
+            self.drain_publishes();
+        }
+        self.flush_batch();
+        self.flush_outbox();
+        self.announce(lifecycle::Kind::Leave);
+        self.tick_lifecycle();
+        let drain = sleep(DRAIN_TIME);
+        tokio::pin!(drain);
+        loop {
+            tokio::select! {
+                () = &mut drain => break,
+                event = self.swarm.next_event() => self.handle_swarm_event(event),
+            }
+        }
+        self.record_known_peers(Instant::now());
+        if let Err(err) = self.known.save() {
+            error!("Could not save the address book: {:#}", err);
+        }
+        if let Some(Err(err)) = self.archive.as_mut().map(archive::Archive::save) {
+            error!("Could not save the archive: {:#}", err);
+        }
+        if let Some(Err(err)) = self.storage.as_ref().map(|storage| storage.flush()) {
+            error!("Could not flush the storage: {:#}", err);
+        }
+        debug!("Closing connections to {} peers", self.network_info().num_peers());
+        // Banning closes the connections and keeps dials in progress from
+        // opening new ones
+        loop {
+            for peer_id in self.peers() {
+                Swarm::ban_peer_id(&mut self.swarm, peer_id);
+            }
+            if self.network_info().num_peers() == 0 {
+                break;
+            }
+            let event = self.swarm.next_event().await;
+            self.handle_swarm_event(event);
+        }
+    }
+
+    /// Drive the event loop forward by one event.
+    pub async fn step(&mut self) -> Result<()> {
+        let next_action = self.schedule.next_deadline();
+        tokio::select! {
+            event = self.swarm.next_event() => self.handle_swarm_event(event),
+            Some((peer_id, request, sender)) = self.order_sync_receiver.next() => {
+                self.swarm.order_sync_send(&peer_id, request, sender);
+            }
+            Some(request) = self.bundle_receiver.next() => {
+                let result = self.debug_bundle().map_err(|err| format!("{:#}", err));
+                request.respond(result);
+            }
+            Some(request) = self.archive_receiver.next() => {
+                let result = match &self.archive {
+                    Some(archive) => archive.answer(&request.data),
+                    None => Err(anyhow::anyhow!("Not an archiver")),
+                };
+                request.respond(result.map_err(|err| format!("{:#}", err)));
+            }
+            _ = tokio::time::sleep_until(next_action.unwrap_or_else(Instant::now).into()),
+                if next_action.is_some() =>
+            {
+                self.run_scheduled();
+            }
+            Some((topic, result)) = self.backfills.next() => {
+                self.finish_backfill(&topic, result);
+            }
+            () = futures::future::ready(()), if !self.publish_queue.is_empty() => {
+                self.drain_publishes();
+            }
+            Some(command) = self.command_receiver.next() => match command {
+                // Resuming mDNS is asynchronous
+                Command::PowerSave { enabled, sender } => {
+                    let _ = sender.send(self.set_power_save(enabled).await);
+                }
+                command => self.handle_command(command),
+            },
+            _ = self.tick.tick() => {
+                if let Err(err) = self.tick_clock().await {
+                    error!("Could not recover from suspend: {:#}", err);
+                }
+                if let Err(err) = self.tick_roaming().await {
+                    error!("Could not recover from address change: {:#}", err);
+                }
+                self.swarm.resend_unacked();
+                self.swarm.tick_reliable(Instant::now());
+                self.tick_elections();
+                self.tick_aggregates();
+                self.tick_keyring();
+                self.tick_membership();
+                self.tick_moderation();
+                self.expire_topics();
+                self.tick_archive(Instant::now());
+                self.meter.tick(Instant::now());
+                if let Err(err) = self.tick_quiet_hours().await {
+                    error!("Could not switch quiet mode: {:#}", err);
+                }
+                if !self.dormant {
+                    self.swarm.tick_multipath(Instant::now());
+                    self.tick_hot_peers(Instant::now());
+                    self.tick_supervisor(Instant::now());
+                    self.tick_address_book(Instant::now());
+                    self.tick_autonat(Instant::now());
+                    self.tick_rendezvous(Instant::now());
+                    self.swarm.tick_files(Instant::now());
+                    self.tick_scores(Instant::now());
+                    self.swarm.tick_discovery(Instant::now());
+                    self.retry_bootstrap();
+                    self.tick_keepalive();
+                    self.tick_idle(Instant::now());
+                    self.tick_lifecycle();
+                    self.tick_rotation(Instant::now());
+                    self.tick_presence(Instant::now());
+                    self.tick_nickname(Instant::now());
+                    self.tick_replicas(Instant::now());
+                    self.swarm.tick_dtn(Instant::now());
+                    self.flush_batch();
+                    self.flush_outbox();
+                }
+                self.trim_connections();
+                self.trim_known_peers();
+            }
+        };
+        self.check_ready();
+        self.record_probes();
+        Ok(())
+    }
+
+    /// What liveness and readiness probes are answered from, recorded after
+    /// every step from now on, see [`probe`].
+    pub fn probes(&mut self) -> probe::Probes {
+        let probes = self.probes.get_or_insert_with(probe::Probes::default).clone();
+        self.record_probes();
+        probes
+    }
+
+    fn record_probes(&self) {
+        if let Some(probes) = &self.probes {
+            probes.record(probe::Status {
+                stepped:      Instant::now(),
+                listening:    Swarm::listeners(&self.swarm).next().is_some(),
+                peers:        self.network_info().num_peers(),
+                bootstrapped: self.bootstrap.is_complete(),
+                stopping:     self.stopping,
+            });
+        }
+    }
+
+    /// The state of the node for `criteria`.
+    fn ready_status(&self, criteria: &ready::Criteria) -> ready::Status {
+        ready::Status {
+            peers:            self.network_info().num_peers(),
+            topics:           criteria
+                .topics
+                .keys()
+                .map(|topic| (topic.clone(), self.swarm.mesh_peer_count(topic)))
+                .collect(),
+            bootstrapped:     self.bootstrap.is_complete(),
+            external_address: Swarm::external_addresses(&self.swarm).next().is_some(),
+        }
+    }
+
+    /// Wake the callers of [`NodeHandle::wait_ready`] whose criteria hold.
+    fn check_ready(&mut self) {
+        if self.waiting_ready.is_empty() {
+            return;
+        }
+        let waiting = std::mem::take(&mut self.waiting_ready);
+        for (criteria, sender) in waiting {
+            if sender.is_canceled() {
+                continue;
+            }
+            if criteria.is_met(&self.ready_status(&criteria)) {
+                debug!("Mesh ready for {:?}", criteria);
+                let _ = sender.send(());
+            } else {
+                self.waiting_ready.push((criteria, sender));
+            }
+        }
+    }
+
+    /// Enter or leave [`power`] save mode.
+    pub async fn set_power_save(&mut self, enabled: bool) -> Result<()> {
+        if enabled == self.power_save {
+            return Ok(());
+        }
+        info!(
+            "{} power-save mode",
+            if enabled { "Entering" } else { "Leaving" }
+        );
+        let was_saving = self.is_saving_power();
+        self.power_save = enabled;
+        self.apply_power_mode(was_saving).await
+    }
+
+    /// Record delivered messages in `journal`.
+    pub fn set_journal(&mut self, journal: journal::Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// Record swarm events in `journal`.
+    pub fn set_event_journal(&mut self, journal: audit::Journal) {
+        self.audit = Some(journal);
+    }
+
+    /// Detect and handle wall clock jumps as configured.
+    pub fn set_clock_jumps(&mut self, config: clock::Config) {
+        self.clock_jumps = clock::Detector::new(config);
+    }
+
+    async fn tick_clock(&mut self) -> Result<()> {
+        let interval = if self.is_saving_power() {
+            power::TICK_INTERVAL
+        } else {
+            TICK_INTERVAL
+        };
+        let observation = self.clock_jumps.check(interval);
+        if let Some(offset_ms) = observation.jump_ms {
+            self.handle_clock_jump(offset_ms);
+        }
+        if observation.resumed {
+            self.resume().await?;
+        }
+        Ok(())
+    }
+
+    fn handle_clock_jump(&mut self, offset_ms: i64) {
+        let direction = if offset_ms < 0 { "back" } else { "forward" };
+        warn!(
+            "Wall clock jumped {} by {}ms",
+            direction,
+            offset_ms.unsigned_abs()
+        );
+        if offset_ms < 0 && self.clock_jumps.action() == clock::Action::Rebaseline {
+            self.swarm
+                .rebaseline_clock(offset_ms.unsigned_abs(), clock::GRACE_PERIOD);
+        }
+        self.recent
+            .record(format!("clock jumped {} by {}ms", direction, offset_ms.unsigned_abs()));
+        self.emit(&Event::ClockJump { offset_ms });
+    }
+
+    /// Replace connections that may have died while we were suspended, so
+    /// peers also learn our subscriptions again, and look for peers again.
+    async fn resume(&mut self) -> Result<()> {
+        info!("Resumed, redialing peers and refreshing discovery");
+        self.recent.record("resumed".into());
+        self.redial_peers();
+        self.swarm.refresh_discovery().await
+    }
+
+    /// Follow changes of our addresses, see [`roaming`].
+    async fn tick_roaming(&mut self) -> Result<()> {
+        let change = match self.roaming.check() {
+            Some(change) => change,
+            None => return Ok(()),
+        };
+        info!(
+            "Addresses changed, added {:?}, removed {:?}, redialing peers",
+            change.added, change.removed
+        );
+        self.recent.record(format!(
+            "addresses changed, added {:?}, removed {:?}",
+            change.added, change.removed
+        ));
+        self.advertise_addresses(&change);
+        self.redial_peers();
+        self.swarm.refresh_discovery().await
+    }
+
+    fn advertise_addresses(&mut self, change: &roaming::Change) {
+        let mut ports = Swarm::listeners(&self.swarm)
+            .filter_map(|address| {
+                address.iter().find_map(|protocol| match protocol {
+                    Protocol::Tcp(port) => Some(port),
+                    _ => None,
+                })
+            })
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        ports.dedup();
+        let stale = Swarm::external_addresses(&self.swarm)
+            .map(|record| record.addr.clone())
+            .filter(|address| {
+                address.iter().any(|protocol| match protocol {
+                    Protocol::Ip4(ip) => change.removed.contains(&ip.into()),
+                    Protocol::Ip6(ip) => change.removed.contains(&ip.into()),
+                    _ => false,
+                })
+            })
+            .collect::<Vec<_>>();
+        for address in stale {
+            debug!("No longer advertising {}", address);
+            Swarm::remove_external_address(&mut self.swarm, &address);
+        }
+        for ip in &change.added {
+            for &port in &ports {
+                let address = Multiaddr::from(*ip).with(Protocol::Tcp(port));
+                debug!("Advertising {}", address);
+                Swarm::add_external_address(&mut self.swarm, address, AddressScore::Infinite);
+            }
+        }
+    }
+
+    /// Use the pubsub protocol and tuning of `config`, see [`pubsub`]. Call
+    /// before [`Node::start`].
+    pub fn set_pubsub(&mut self, config: &pubsub::Config) {
+        debug!("Pubsub config: {:?}", config);
+        self.swarm.configure_pubsub(config);
+    }
+
+    /// Queue at most `capacity` publishes of handles, see [`outbound`].
+    pub fn set_publish_queue(&mut self, capacity: usize) {
+        debug!("Queueing up to {} publishes", capacity);
+        self.publish_queue = outbound::Queue::new(capacity);
+    }
+
+    /// Rate limit the messages of each peer and disconnect or ban the peers
+    /// that misbehave, see [`scoring`].
+    pub fn set_scoring(&mut self, config: scoring::Config) {
+        info!(
+            "Taking {} messages a second from each peer, banning below a score of {}",
+            config.rate, config.ban
+        );
+        self.swarm.set_scoring(config);
+    }
+
+    /// Probe selected connections through [`keepalive`].
+    pub fn set_keepalive(&mut self, config: keepalive::Config) {
+        let peers = match config.peers {
+            keepalive::Peers::Critical => "critical",
+            keepalive::Peers::All => "all",
+        };
+        info!("Probing {} peers every {:?}", peers, config.interval);
+        self.swarm.set_keepalive(config);
+    }
+
+    /// Keep publishes on the durable topics of `outbox` until sent, see
+    /// [`outbox`].
+    pub fn set_outbox(&mut self, outbox: outbox::Outbox) {
+        info!("Keeping {} outbox messages", outbox.len());
+        self.outbox = outbox;
+    }
+
+    /// Bound collections by `limits`, see [`profile`].
+    pub fn set_limits(&mut self, limits: profile::Limits) {
+        debug!("Limits: {:?}", limits);
+        self.limits = limits;
+        self.swarm.set_blob_capacity(limits.blob_capacity);
+        self.schedule.set_max_pending(limits.scheduled);
+    }
+
+    /// Keep the last `capacity` messages of each unencrypted topic and
+    /// answer backfills with them, see [`archive`].
+    pub fn set_archive(&mut self, capacity: usize) {
+        info!("Archiving {} messages per topic", capacity);
+        self.archive = Some(archive::Archive::new(capacity));
+        self.swarm.advertise_service(
+            ServiceDescriptor::new(archive::SERVICE, ""),
+            self.archive_sender.clone(),
+        );
+    }
+
+    /// Keep the archive in `storage` too, and answer with the messages it
+    /// holds. Call after [`Node::set_archive`].
+    pub fn load_archive(&mut self, storage: &storage::Shared) -> Result<()> {
+        let capacity = match &self.archive {
+            Some(archive) => archive.capacity(),
+            None => anyhow::bail!("Not an archiver"),
+        };
+        let slot = storage::Slot::new(storage, archive::NAMESPACE, archive::KEY);
+        let archive = archive::Archive::load(slot, capacity)?;
+        if !archive.is_empty() {
+            info!("Loaded {} archived messages", archive.len());
+        }
+        self.archive = Some(archive);
+        Ok(())
+    }
+
+    /// Index the text of archived messages in `index` for full-text
+    /// [`search`]. Call after [`Node::load_archive`].
+    #[cfg(feature = "search")]
+    pub fn index_archive(&mut self, index: search::Index) -> Result<()> {
+        match &mut self.archive {
+            Some(archive) => archive.set_index(index),
+            None => anyhow::bail!("Not an archiver"),
+        }
+    }
+
+    fn tick_archive(&mut self, now: Instant) {
+        if let Some(archive) = &mut self.archive {
+            if let Err(err) = archive.tick(now) {
+                error!("Could not save the archive: {:#}", err);
+            }
+        }
+    }
+
+    /// Add `layer` to the [`middleware`] chain, after those added before.
+    pub fn add_middleware(&mut self, name: &str, layer: Box<dyn middleware::Middleware>) {
+        debug!("Adding middleware {}", name);
+        self.middleware.push(name, layer);
+    }
+
+    /// Carry bundles for disconnected peers in [`dtn`] mode.
+    pub fn set_dtn(&mut self, store: dtn::Store) {
+        info!(
+            "Carrying up to {} bundles, {} stored",
+            store.config().capacity,
+            store.len()
+        );
+        self.swarm.set_dtn(store);
+    }
+
+    /// Dial the hot and pinned peers that lost their connection.
+    fn tick_hot_peers(&mut self, now: Instant) {
+        let swarm = &self.swarm;
+        let mut due = self
+            .hot
+            .due(now, |peer_id| Swarm::is_connected(swarm, peer_id));
+        due.extend(self.pinned.due(now, |peer_id| Swarm::is_connected(swarm, peer_id)));
+        for (peer_id, address) in due {
+            debug!("Dialing hot peer {} at {}", peer_id, address);
+            if let Err(err) = Swarm::dial_addr(&mut self.swarm, address) {
+                debug!("Dialing hot peer {} failed: {:?}", peer_id, err);
+            }
+        }
+    }
+
+    /// Probe our reachability at our listen and observed addresses, and
+    /// advertise the addresses peers dialed us back at.
+    fn tick_autonat(&mut self, now: Instant) {
+        let mut candidates = Swarm::listeners(&self.swarm)
+            .chain(Swarm::external_addresses(&self.swarm).map(|record| &record.addr))
+            .cloned()
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+        let status = match self.swarm.tick_autonat(now, candidates) {
+            Some(status) => status,
+            None => return,
+        };
+        for address in &self.nat.addresses {
+            if !status.addresses.contains(address) {
+                debug!("No longer advertising {}", address);
+                Swarm::remove_external_address(&mut self.swarm, address);
+            }
+        }
+        for address in &status.addresses {
+            if !self.nat.addresses.contains(address) {
+                info!("Peers reached us at {}", address);
+                let score = AddressScore::Infinite;
+                Swarm::add_external_address(&mut self.swarm, address.clone(), score);
+            }
+        }
+        self.recent
+            .record(format!("reachability {}", status.reachability));
+        self.emit(&Event::NatStatusChanged {
+            reachability: status.reachability,
+            addresses:    status.addresses.clone(),
+        });
+        self.nat = status;
+    }
+
+    /// Dial the rendezvous points we lost, and register our subscriptions
+    /// at our listen and observed addresses with the others.
+    fn tick_rendezvous(&mut self, now: Instant) {
+        let connected = self
+            .swarm
+            .rendezvous_points()
+            .iter()
+            .filter(|peer_id| Swarm::is_connected(&self.swarm, peer_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let lost = self
+            .swarm
+            .rendezvous_dials(now, |peer_id| connected.contains(peer_id));
+        if connected.is_empty() && lost.is_empty() {
+            return;
+        }
+        for peer_id in lost {
+            debug!("Dialing rendezvous point {}", peer_id);
+            if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                debug!("Could not dial rendezvous point {}: {:?}", peer_id, err);
+            }
+        }
+        let mut addresses = Swarm::listeners(&self.swarm)
+            .chain(Swarm::external_addresses(&self.swarm).map(|record| &record.addr))
+            .filter(|address| {
+                !address.iter().any(|protocol| {
+                    match protocol {
+                        Protocol::Ip4(ip) => ip.is_unspecified(),
+                        Protocol::Ip6(ip) => ip.is_unspecified(),
+                        _ => false,
+                    }
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses.dedup();
+        let topics = self
+            .subscriptions
+            .topics()
+            .map(|(topic, _)| topic.as_str())
+            .collect::<Vec<_>>();
+        self.swarm.tick_rendezvous(now, &topics, &addresses);
+    }
+
+    fn tick_scores(&mut self, now: Instant) {
+        for (peer_id, verdict) in self.swarm.tick_scores(now) {
+            if self.swarm.is_critical_peer(&peer_id) {
+                warn!("Critical peer {} misbehaves, keeping it", peer_id);
+                continue;
+            }
+            if self.pinned.contains(&peer_id) {
+                warn!("Pinned peer {} misbehaves, keeping it", peer_id);
+                continue;
+            }
+            match verdict {
+                scoring::Verdict::Disconnect => {
+                    warn!("Disconnecting {} for its score", peer_id);
+                    self.recent.record(format!("disconnected {} for its score", peer_id));
+                    self.evict(&peer_id);
+                }
+                scoring::Verdict::Ban(duration) => {
+                    warn!("Banning {} for its score", peer_id);
+                    if let Err(err) = self.ban(&peer_id, Some(duration)) {
+                        error!("Could not ban {}: {:#}", peer_id, err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn tick_keepalive(&mut self) {
+        for peer_id in self.swarm.tick_keepalive(Instant::now()) {
+            warn!("Keepalive probes to {} unanswered, redialing", peer_id);
+            self.recent.record(format!("keepalive to {} failed", peer_id));
+            self.redial(peer_id.clone());
+            self.emit(&Event::ConnectionReaped {
+                peer:   peer_id,
+                reason: health::Reason::Unhealthy,
+            });
+        }
+    }
+
+    /// Close the connections that went idle, see [`health`].
+    fn tick_idle(&mut self, now: Instant) {
+        if self.reaper.config().idle.is_none() {
+            return;
+        }
+        let connected = self
+            .meter
+            .report()
+            .peers
+            .into_iter()
+            .filter(|(peer_id, _)| Swarm::is_connected(&self.swarm, peer_id))
+            .filter(|(peer_id, _)| {
+                !self.swarm.is_critical_peer(peer_id) && !self.pinned.contains(peer_id)
+            })
+            .map(|(peer_id, usage)| (peer_id, usage.inbound + usage.outbound))
+            .collect::<Vec<_>>();
+        for peer_id in self.reaper.due(now, &connected) {
+            info!("Closing idle connection to {}", peer_id);
+            self.recent.record(format!("closed idle connection to {}", peer_id));
+            self.disconnect(&peer_id);
+            self.emit(&Event::ConnectionReaped {
+                peer:   peer_id,
+                reason: health::Reason::Idle,
+            });
+        }
+    }
+
+    /// Close the connections to `peer_id`, and forget it unless it is a
+    /// critical or pinned peer.
+    fn evict(&mut self, peer_id: &PeerId) {
+        self.disconnect(peer_id);
+        if !self.swarm.is_critical_peer(peer_id) && !self.pinned.contains(peer_id) {
+            self.known_peers().write().unwrap().remove(peer_id); // FIXME: Can block
+        }
+    }
+
+    /// Close the connections to our connected peers and dial them again.
+    fn redial_peers(&mut self) {
+        let connected = self
+            .known_peers()
+            .read()
+            .unwrap() // FIXME: Can block
+            .keys()
+            .filter(|peer_id| Swarm::is_connected(&self.swarm, peer_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for peer_id in connected {
+            self.redial(peer_id);
+        }
+    }
+
+    fn redial(&mut self, peer_id: PeerId) {
+        self.disconnect(&peer_id);
+        if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+            debug!("Could not redial {}: {:?}", peer_id, err);
+        }
+    }
+
+    /// Close the connections to `peer_id`, without the [`supervisor`]
+    /// reconnecting.
+    fn disconnect(&mut self, peer_id: &PeerId) {
+        if self.is_important(peer_id) {
+            self.supervisor.intend(peer_id);
+        }
+        // Banning closes the connections, unbanning lets the peer back in
+        Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+        Swarm::unban_peer_id(&mut self.swarm, peer_id.clone());
+    }
+
+    /// Whether to reconnect to `peer_id` when it drops, see [`supervisor`].
+    fn is_important(&self, peer_id: &PeerId) -> bool {
+        self.supervisor.is_supervised(peer_id)
+            || self.bootstrap_peers.iter().any(|(bootstrap, _)| bootstrap == peer_id)
+    }
+
+    /// Redial the important peers that dropped, see [`supervisor`].
+    fn tick_supervisor(&mut self, now: Instant) {
+        let swarm = &self.swarm;
+        let actions = self
+            .supervisor
+            .due(now, |peer_id| Swarm::is_connected(swarm, peer_id));
+        for action in actions {
+            match action {
+                supervisor::Action::Redial { peer_id, attempt } => {
+                    debug!("Reconnecting to {}, attempt {}", peer_id, attempt);
+                    if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                        debug!("Could not reconnect to {}: {:?}", peer_id, err);
+                    }
+                }
+                supervisor::Action::GiveUp { peer_id, attempts } => {
+                    warn!("Giving up on reconnecting to {} after {} dials", peer_id, attempts);
+                    self.recent.record(format!("gave up reconnecting to {}", peer_id));
+                }
+            }
+        }
+    }
+
+    /// Go dormant during the [`quiet`] hours of `schedule`.
+    pub fn set_quiet_hours(&mut self, schedule: quiet::Schedule) {
+        self.quiet_hours = schedule;
+    }
+
+    /// What to do when a dialed address answers with another peer id.
+    pub fn set_identity_policy(&mut self, policy: mismatch::Policy) {
+        self.identity_policy = policy;
+    }
+
+    /// What to do with a second connection to a peer, see [`duplicate`].
+    pub fn set_duplicate_policy(&mut self, policy: duplicate::Policy) {
+        self.swarm.set_duplicate_policy(policy);
+    }
+
+    /// Whether to deliver messages that are unsigned, or not signed by their
+    /// source, see [`verification`].
+    pub fn set_verification(&mut self, policy: verification::Policy) {
+        self.swarm.set_verification(policy);
+    }
+
+    /// Whether the node may start without mDNS, or in [`run`] without the
+    /// metrics endpoint or the StatsD exporter, when they fail. Call before
+    /// [`Node::start`]. See [`degrade`].
+    pub fn set_subsystem_failures(&mut self, policy: degrade::Policy) {
+        self.subsystem_failures = policy;
+    }
+
+    /// The subsystems the node went without.
+    pub fn degraded(&self) -> &[degrade::Degraded] {
+        &self.degraded
+    }
+
+    /// Go on without `subsystem`, which failed with `error`, unless the
+    /// policy is to fail.
+    fn degrade(&mut self, subsystem: degrade::Subsystem, error: anyhow::Error) -> Result<()> {
+        let degraded = self.subsystem_failures.apply(subsystem, error)?;
+        if subsystem == degrade::Subsystem::Mdns {
+            self.mdns = false;
+        }
+        self.emit(&Event::SubsystemDegraded {
+            subsystem,
+            error: degraded.error.clone(),
+        });
+        self.degraded.push(degraded);
+        Ok(())
+    }
+
+    async fn tick_quiet_hours(&mut self) -> Result<()> {
+        let dormant = self.quiet_hours.is_quiet();
+        if dormant == self.dormant {
+            return Ok(());
+        }
+        if dormant {
+            info!("Quiet hours started, going dormant");
+        } else {
+            info!(
+                "Quiet hours ended, sending {} held messages",
+                self.batch.len()
+            );
+        }
+        let was_saving = self.is_saving_power();
+        self.dormant = dormant;
+        self.apply_power_mode(was_saving).await
+    }
+
+    fn is_saving_power(&self) -> bool {
+        self.power_save || self.dormant
+    }
+
+    /// Suspend or resume mDNS and set the tick interval when switching in or
+    /// out of power saving, and send held messages unless dormant.
+    async fn apply_power_mode(&mut self, was_saving: bool) -> Result<()> {
+        if self.is_saving_power() != was_saving {
+            if self.is_saving_power() {
+                self.swarm.suspend_mdns();
+                self.tick = interval(power::TICK_INTERVAL);
+            } else {
+                if self.mdns {
+                    if let Err(err) = self.swarm.resume_mdns().await {
+                        self.degrade(degrade::Subsystem::Mdns, err)?;
+                    }
+                }
+                self.tick = interval(TICK_INTERVAL);
+            }
+        }
+        if !self.dormant {
+            self.flush_batch();
+            self.flush_outbox();
+        }
+        Ok(())
+    }
+
+    /// Forget disconnected peers beyond the known peers [`profile`] limit.
+    fn trim_known_peers(&mut self) {
+        let known_peers = self.known_peers();
+        let mut known_peers = known_peers.write().unwrap(); // FIXME: Can block
+        if known_peers.len() <= self.limits.known_peers {
+            return;
+        }
+        let peers = known_peers
+            .keys()
+            .map(|peer_id| {
+                let keep = Swarm::is_connected(&self.swarm, peer_id)
+                    || self.swarm.is_critical_peer(peer_id)
+                    || self.hot.contains(peer_id)
+                    || self.pinned.contains(peer_id);
+                (peer_id.clone(), keep)
+            })
+            .collect::<Vec<_>>();
+        let excess = profile::excess_known_peers(peers, self.limits.known_peers);
+        debug!("Forgetting {} disconnected peers", excess.len());
+        for peer_id in excess {
+            known_peers.remove(&peer_id);
+        }
+    }
+
+    /// Disconnect from peers beyond the connection limit of the current
+    /// mode.
+    fn trim_connections(&mut self) {
+        let max_peers = if self.is_saving_power() {
+            Some(
+                self.limits
+                    .max_peers
+                    .map_or(power::MAX_PEERS, |max| max.min(power::MAX_PEERS)),
+            )
+        } else {
+            self.limits.max_peers
+        };
+        let max_peers = match max_peers {
+            Some(max_peers) => max_peers,
+            None => return,
+        };
+        let known_peers = self.known_peers();
+        let connected = known_peers
+            .read()
+            .unwrap() // FIXME: Can block
+            .values()
+            // Pinned peers do not count towards the limit
+            .filter(|info| {
+                Swarm::is_connected(&self.swarm, &info.peer_id)
+                    && !self.pinned.contains(&info.peer_id)
+            })
+            .map(|info| {
+                power::Connected {
+                    peer_id:  info.peer_id.clone(),
+                    ping:     info.ping,
+                    critical: self.swarm.is_critical_peer(&info.peer_id)
+                        || self.hot.contains(&info.peer_id),
+                }
+            })
+            .collect::<Vec<_>>();
+        let keep = if self.dormant {
+            1
+        } else {
+            max_peers + connected.iter().filter(|peer| peer.critical).count()
+        };
+        for peer_id in power::excess_peers(connected, keep) {
+            debug!("Disconnecting from {} to keep {} peers", peer_id, keep);
+            self.disconnect(&peer_id);
+        }
+    }
+
+    /// The class of service of our messages on `topic`.
+    fn qos(&self, topic: &str) -> qos::Class {
+        self.subscriptions
+            .get(topic)
+            .map_or_else(qos::Class::default, |options| options.qos)
+    }
+
+    /// Send the messages queued in power-save mode, higher [`qos`] classes
+    /// first.
+    fn flush_batch(&mut self) {
+        let mut batch = self.batch.take();
+        qos::prioritize(&mut batch, |(topic, _)| self.qos(topic));
+        for (topic, data) in batch {
+            if let Err(err) = self.swarm.publish(&topic, &data) {
+                warn!("Queued message on {} not published: {:?}", topic, err);
+            }
+        }
+    }
+
+    /// Hand the next [`outbound::BATCH`] publishes of handles to pubsub.
+    fn drain_publishes(&mut self) {
+        for pending in self.publish_queue.batch() {
+            let waited = pending.queued.elapsed();
+            if waited > Duration::from_secs(1) {
+                debug!("Publish on {} waited {:?} in the queue", pending.topic, waited);
+            }
+            let _ = pending.sender.send(self.publish_now(&pending.topic, pending.data));
+        }
+    }
+
+    /// Send the messages waiting in the [`outbox`], higher [`qos`] classes
+    /// first, keeping those pubsub did not take.
+    fn flush_outbox(&mut self) {
+        let mut pending = self.outbox.pending();
+        qos::prioritize(&mut pending, |(_, topic, _)| self.qos(topic));
+        for (id, topic, data) in pending {
+            let data = match self.encrypt(&topic, data) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Could not seal outbox message on {}: {:#}", topic, err);
+                    continue;
+                }
+            };
+            if let Err(err) = self.swarm.publish(&topic, &data) {
+                trace!("Outbox message on {} not sent yet: {:?}", topic, err);
+                continue;
+            }
+            if let Err(err) = self.outbox.confirm(id) {
+                error!("Could not update outbox: {:#}", err);
+            }
+        }
+    }
+
+    fn tick_elections(&mut self) {
+        let now = Instant::now();
+        let mut heartbeats = Vec::new();
+        self.elections.retain(|group, (election, changes)| {
+            let (heartbeat, change) = election.tick(now);
+            if let Some(heartbeat) = heartbeat {
+                heartbeats.push(heartbeat);
+            }
+            if let Some(change) = change {
+                info!(
+                    "Election {} term {}: leader {:?}",
+                    group, change.term, change.leader
+                );
+                if changes.try_send(change).is_err() && changes.is_closed() {
+                    info!("Leaving election {}, receiver dropped", group);
+                    return false;
+                }
+            }
+            true
+        });
+        for heartbeat in heartbeats {
+            let data = match serde_cbor::to_vec(&heartbeat) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("Could not encode heartbeat: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = self.swarm.publish(&election::topic(&heartbeat.group), &data) {
+                trace!("Heartbeat for {} not published: {:?}", heartbeat.group, err);
+            }
+        }
+    }
+
+    /// Encrypt payloads on `topic` with the pre-shared `key`, like
+    /// [`NodeHandle::set_topic_key`].
+    pub fn set_topic_key(&mut self, topic: &str, key: keyring::Key, admin: PeerId) {
+        info!(
+            "Using pre-shared key {} for {} with admin {}",
+            keyring::fingerprint(&key),
+            topic,
+            admin
+        );
+        self.swarm.subscribe(&keyring::topic(topic));
+        if admin == *Swarm::local_peer_id(&self.swarm) {
+            self.swarm.subscribe(&membership::topic(topic));
+            self.membership.administer(topic);
+        }
+        self.keyring.set_key(topic, key, admin);
+    }
+
+    fn tick_keyring(&mut self) {
+        for (topic, rotation) in self.keyring.tick(Instant::now()) {
+            self.publish_rotation(&topic, &rotation);
+        }
+    }
+
+    fn publish_rotation(&mut self, topic: &str, rotation: &Rotation) {
+        let data = serde_cbor::to_vec(rotation).expect("Rotations always encode");
+        if let Err(err) = self.swarm.publish(&keyring::topic(topic), &data) {
+            trace!("Key rotation for {} not published: {:?}", topic, err);
+        }
+    }
+
+    /// Replace the key of `topic`, wrapping it only to grant holders if the
+    /// topic is private.
+    fn rotate_topic_key(&mut self, topic: &str, mut members: Vec<PeerId>) -> Result<u32> {
+        if self.membership.is_admin(topic) {
+            members.retain(|member| {
+                let granted = self.membership.is_member(topic, member);
+                if !granted {
+                    warn!("Not sending key for {} to {}, who has no grant", topic, member);
+                }
+                granted
+            });
+        }
+        let rotation = self.keyring.rotate(topic, &members, Instant::now())?;
+        let (_, fingerprint) = self.keyring.fingerprint(topic).unwrap_or_default();
+        info!(
+            "Rotated key of {} to generation {} with fingerprint {} for {} members",
+            topic,
+            rotation.generation,
+            fingerprint,
+            rotation.wrapped.len()
+        );
+        self.publish_rotation(topic, &rotation);
+        Ok(rotation.generation)
+    }
+
+    fn tick_membership(&mut self) {
+        for topic in self.membership.tick(Instant::now()) {
+            self.publish_membership(&topic, &membership::Message::Request);
+        }
+    }
+
+    fn publish_membership(&mut self, topic: &str, message: &membership::Message) {
+        let data = serde_cbor::to_vec(message).expect("Membership messages always encode");
+        if let Err(err) = self.swarm.publish(&membership::topic(topic), &data) {
+            trace!("Membership message for {} not published: {:?}", topic, err);
+        }
+    }
+
+    fn tick_moderation(&mut self) {
+        for blocklist in self.moderation.tick(Instant::now()) {
+            self.publish_blocklist(&blocklist);
+        }
+    }
+
+    /// Emit the [`schedule`]d actions that are due.
+    fn run_scheduled(&mut self) {
+        for due in self.schedule.due(Instant::now()) {
+            info!(
+                "Running action {} of {}, {:?} late",
+                due.action.name, due.issuer, due.late
+            );
+            self.emit(&Event::Scheduled {
+                issuer: due.issuer,
+                name:   due.action.name,
+                data:   due.action.data.into_vec(),
+                late:   due.late,
+            });
+        }
+    }
+
+    fn publish_blocklist(&mut self, blocklist: &Blocklist) {
+        let data = serde_cbor::to_vec(blocklist).expect("Blocklists always encode");
+        if let Err(err) = self.swarm.publish(&moderation::topic(&blocklist.topic), &data) {
+            trace!("Blocklist for {} not published: {:?}", blocklist.topic, err);
+        }
+    }
+
+    /// Whether payloads on `topic` are sealed with a [`keyring`] key.
+    fn is_encrypted(&self, topic: &str) -> bool {
+        self.keyring.has_key(topic)
+            || matches!(self.subscriptions.get(topic), Some(options) if options.encrypted)
+    }
+
+    /// Pass `data` through the [`middleware`] and seal it if `topic` is
+    /// encrypted.
+    fn seal(&mut self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.middleware.outbound(topic, data)?;
+        self.encrypt(topic, data)
+    }
+
+    /// Seal `data` if `topic` is encrypted, after the [`middleware`].
+    fn encrypt(&self, topic: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_encrypted(topic) {
+            self.keyring.seal(topic, &data)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Drop subscriptions to ephemeral topics that have been inactive for
+    /// longer than their `expire_after`.
+    fn expire_topics(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (topic, options) in self.subscriptions.topics() {
+            let expire_after = match options.expire_after {
+                Some(expire_after) => expire_after,
+                None => continue,
+            };
+            let last_active = self.topic_activity.entry(topic.clone()).or_insert(now);
+            if self.swarm.mesh_peer_count(topic) > 0 {
+                *last_active = now;
+            } else if now.duration_since(*last_active) >= expire_after {
+                expired.push(topic.clone());
+            }
+        }
+        for topic in expired {
+            info!("Topic {} expired, unsubscribing", topic);
+            self.swarm.unsubscribe(&topic);
+            self.swarm.set_multicast(&topic, false);
+            self.swarm.set_acked(&topic, false);
+            self.topic_activity.remove(&topic);
+            if let Err(err) = self.subscriptions.remove(&topic) {
+                error!("Could not remove expired subscription {}: {:?}", topic, err);
+            }
+        }
+    }
+
+    /// Announce `kind` on the [`lifecycle`] topic once there is a peer to
+    /// publish to.
+    pub fn announce(&mut self, kind: lifecycle::Kind) {
+        if let Err(err) = self.lifecycle.announce(kind) {
+            warn!("Not announcing {}: {:#}", kind, err);
+        }
+    }
+
+    fn tick_lifecycle(&mut self) {
+        self.lifecycle.tick();
+        let data = match self.lifecycle.pending().map(|signed| {
+            signed.and_then(|announcement| Ok(serde_cbor::to_vec(&announcement)?))
+        }) {
+            None => return,
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                error!("Could not sign announcement: {:#}", err);
+                self.lifecycle.published();
+                return;
+            }
+        };
+        match self.swarm.publish(lifecycle::TOPIC, &data) {
+            Ok(_) => self.lifecycle.published(),
+            Err(err) => trace!("Announcement not published: {:?}", err),
+        }
+    }
+
+    /// Gossip our heartbeat and keep a roster of the peers online, see
+    /// [`presence`].
+    pub fn set_presence(&mut self, config: presence::Config) {
+        info!(
+            "Announcing presence every {}",
+            humantime::format_duration(config.interval)
+        );
+        self.swarm.subscribe(presence::TOPIC);
+        self.presence = Some(presence::Presence::new(config));
+    }
+
+    /// Refuse payloads over `config.max` and fragment envelopes over
+    /// `config.frame`, see [`fragment`].
+    pub fn set_message_size(&mut self, config: fragment::Config) {
+        self.swarm.set_message_size(config);
+    }
+
+    /// Let our messages expire after `config.ttl` and be republished by at
+    /// most `config.hops` relays, see [`expiry`].
+    pub fn set_message_ttl(&mut self, config: expiry::Config) {
+        self.swarm.set_expiry(config);
+    }
+
+    /// Retry acknowledged messages and drop duplicates as configured, see
+    /// [`reliable`].
+    pub fn set_reliable(&mut self, config: reliable::Config) {
+        self.swarm.set_reliable(config);
+    }
+
+    /// Compress payloads with the codecs of `config` for the peers that
+    /// advertised them, see [`codec`].
+    pub fn set_compression(&mut self, config: codec::Config) {
+        self.swarm.set_compression(config);
+    }
+
+    fn tick_presence(&mut self, now: Instant) {
+        let topics = self.topics().into_iter().map(|(topic, _)| topic).collect();
+        let presence = match &mut self.presence {
+            Some(presence) => presence,
+            None => return,
+        };
+        let changes = presence.tick(now);
+        let heartbeat = presence.due(now, topics);
+        self.presence_changed(changes);
+        let data = match heartbeat.map(|heartbeat| serde_cbor::to_vec(&heartbeat)) {
+            None => return,
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                error!("Could not encode heartbeat: {:#}", err);
+                return;
+            }
+        };
+        match self.swarm.publish(presence::TOPIC, &data) {
+            Ok(_) => {
+                if let Some(presence) = &mut self.presence {
+                    presence.published(now);
+                }
+            }
+            Err(err) => trace!("Heartbeat not published: {:?}", err),
+        }
+    }
+
+    fn presence_changed(&mut self, changes: Vec<presence::Change>) {
+        for change in changes {
+            match change {
+                presence::Change::Joined {
+                    peer_id,
+                    topic,
+                    nickname,
+                } => {
+                    debug!("{} ({}) is online on {}", peer_id, nickname, topic);
+                    self.emit(&Event::PresenceJoined {
+                        peer_id,
+                        topic,
+                        nickname,
+                    });
+                }
+                presence::Change::Left { peer_id, topic } => {
+                    debug!("{} went offline on {}", peer_id, topic);
+                    self.emit(&Event::PresenceLeft { peer_id, topic });
+                }
+            }
+        }
+    }
+
+    /// The peers online on `topic`, like [`NodeHandle::roster`].
+    pub fn roster(&self, topic: &str) -> Vec<presence::Present> {
+        self.presence
+            .as_ref()
+            .map_or_else(Vec::new, |presence| presence.roster(topic, Instant::now()))
+    }
+
+    /// Publish our signed profile, see [`nickname`].
+    pub fn set_nickname(&mut self, config: nickname::Config) {
+        info!(
+            "Publishing profile of {} every {}",
+            config.nickname,
+            humantime::format_duration(config.interval)
+        );
+        self.nicknames.set_config(config);
+    }
+
+    fn tick_nickname(&mut self, now: Instant) {
+        let data = match self.nicknames.due(now).map(|signed| {
+            signed.and_then(|profile| Ok(serde_cbor::to_vec(&profile)?))
+        }) {
+            None => return,
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                error!("Could not sign profile: {:#}", err);
+                self.nicknames.published(now);
+                return;
+            }
+        };
+        match self.swarm.publish(nickname::TOPIC, &data) {
+            Ok(_) => self.nicknames.published(now),
+            Err(err) => trace!("Profile not published: {:?}", err),
+        }
+    }
+
+    /// The verified profile of `peer_id`, like [`NodeHandle::profile`].
+    pub fn profile(&self, peer_id: &PeerId) -> Option<nickname::Profile> {
+        self.nicknames.profile(peer_id).cloned()
+    }
+
+    /// Start replicating `map`, unless we do, see [`replica`].
+    fn join_state(&mut self, map: &str) -> Result<()> {
+        if self.replicas.join(map)?.1 {
+            info!("Replicating state map {}", map);
+            self.swarm.subscribe(&replica::topic(map));
+        }
+        Ok(())
+    }
+
+    /// The value of `key` in the replicated `map`, like
+    /// [`NodeHandle::get_state`].
+    pub fn get_state(&mut self, map: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        self.join_state(map)?;
+        Ok(self
+            .replicas
+            .get(map)
+            .and_then(|replica| replica.get(key))
+            .map(<[u8]>::to_vec))
+    }
+
+    /// Set or remove `key` in the replicated `map`, like
+    /// [`NodeHandle::set_state`].
+    pub fn set_state(&mut self, map: &str, key: &str, value: Option<Vec<u8>>) -> Result<()> {
+        self.join_state(map)?;
+        let version = replica::Version {
+            timestamp: self.swarm.timestamp(),
+            writer:    serde_bytes::ByteBuf::from(self.local_peer_id().as_bytes().to_vec()),
+        };
+        let (replica, _) = self.replicas.join(map)?;
+        let delta = replica.write(key, value, version)?;
+        self.publish_replica(&replica::topic(map), &delta);
+        Ok(())
+    }
+
+    /// The keys and values of the replicated `map`, like
+    /// [`NodeHandle::state_entries`].
+    pub fn state_entries(&mut self, map: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        self.join_state(map)?;
+        Ok(self
+            .replicas
+            .get(map)
+            .map(replica::Replica::entries)
+            .unwrap_or_default())
+    }
+
+    fn publish_replica(&mut self, topic: &str, message: &replica::Message) {
+        let data = match serde_cbor::to_vec(message) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Could not encode state update: {:#}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.swarm.publish(topic, &data) {
+            trace!("State update on {} not published: {:?}", topic, err);
+        }
+    }
+
+    fn tick_replicas(&mut self, now: Instant) {
+        for (topic, digest) in self.replicas.digests(now) {
+            self.publish_replica(&topic, &digest);
+        }
+    }
+
+    /// Vouch for our peer id with the `previous` key until `until`, see
+    /// [`rotation`].
+    pub fn set_previous_identity(
+        &mut self,
+        previous: &identity::Keypair,
+        until: std::time::SystemTime,
+    ) -> Result<()> {
+        let migration = rotation::Migration::sign(previous, self.local_peer_id(), until)?;
+        info!(
+            "Rotated from {} until {}",
+            PeerId::from(previous.public()),
+            humantime::format_rfc3339_seconds(until)
+        );
+        self.rotations.set_own(migration);
+        Ok(())
+    }
+
+    fn tick_rotation(&mut self, now: Instant) {
+        let data = match self.rotations.due(now, std::time::SystemTime::now()) {
+            None => return,
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                error!("Could not encode migration: {:#}", err);
+                self.rotations.published(now);
+                return;
+            }
+        };
+        match self.swarm.publish(rotation::TOPIC, &data) {
+            Ok(_) => self.rotations.published(now),
+            Err(err) => trace!("Migration not published: {:?}", err),
+        }
+    }
+
+    /// Move what we know of `previous` over to `current`, which it rotated
+    /// to.
+    fn migrate(&mut self, previous: &PeerId, current: &PeerId) {
+        let addresses = self.known.migrate(previous, current, Instant::now());
+        info!("{} rotated its identity to {}", previous, current);
+        for address in addresses {
+            self.swarm.move_address(previous, current, address);
+        }
+        if !Swarm::is_connected(&self.swarm, current) {
+            if let Err(err) = Swarm::dial(&mut self.swarm, current) {
+                debug!("Could not dial {}: {:?}", current, err);
+            }
+        }
+        self.recent.record(format!("{} rotated to {}", previous, current));
+        self.emit(&Event::IdentityRotated {
+            previous: previous.clone(),
+            current:  current.clone(),
+        });
+    }
+
+    fn tick_aggregates(&mut self) {
+        let data = match self.aggregates.tick(Instant::now()).map(serde_cbor::to_vec) {
+            None => return,
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                error!("Could not encode aggregates: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.swarm.publish(aggregate::TOPIC, &data) {
+            trace!("Aggregates not published: {:?}", err);
+        }
+    }
+
+    /// Classify connections that failed to upgrade, and handle behaviour
+    /// events.
+    fn handle_swarm_event<E>(&mut self, event: SwarmEvent<Event, E>)
+    where
+        E: std::error::Error + 'static,
+    {
+        let connection = match &event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                let swarm = &self.span;
+                let span = self.connections.entry(peer_id.clone()).or_insert_with(|| {
+                    spans::connection(swarm, peer_id, endpoint.get_remote_address())
+                });
+                Some(span.clone())
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => self.connections.remove(peer_id),
+            SwarmEvent::ConnectionClosed { peer_id, .. }
+            | SwarmEvent::UnreachableAddr { peer_id, .. } => self.connections.get(peer_id).cloned(),
+            _ => None,
+        };
+        let _entered = connection.as_ref().map(tracing::Span::enter);
+        if let Some(audit) = &mut self.audit {
+            if let Some(record) = audit::Record::of_swarm(&event, std::time::SystemTime::now()) {
+                audit.record(&record);
+            }
+        }
+        if let SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } = &event
+        {
+            self.swarm.pubsub_disconnected(peer_id);
+            if self.is_important(peer_id) {
+                if let Some(backoff) = self.supervisor.disconnected(peer_id, Instant::now()) {
+                    info!("Lost the connection to {}, reconnecting in {:?}", peer_id, backoff);
+                }
+            }
+        }
+        if let SwarmEvent::ConnectionClosed {
+            peer_id, endpoint, ..
+        } = &event
+        {
+            let address = endpoint.get_remote_address();
+            self.metrics.disconnected(peer_id, address, Instant::now());
+            self.admission.closed(endpoint);
+        }
+        if let SwarmEvent::IncomingConnectionError {
+            error: PendingConnectionError::ConnectionLimit(_),
+            ..
+        }
+        | SwarmEvent::UnreachableAddr {
+            error: PendingConnectionError::ConnectionLimit(_),
+            ..
+        }
+        | SwarmEvent::UnknownPeerUnreachableAddr {
+            error: PendingConnectionError::ConnectionLimit(_),
+            ..
+        } = &event
+        {
+            self.admission.rejected_by_swarm();
+        }
+        let (peer, address, reason, error) = match event {
+            SwarmEvent::Behaviour(event) => return self.handle_event(event),
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                if let ConnectedPoint::Dialer { address } = &endpoint {
+                    self.backoffs.clear(address);
+                }
+                let address = endpoint.get_remote_address();
+                self.metrics.connected(&peer_id, address, Instant::now());
+                self.admission.established(&endpoint);
+                if let ConnectedPoint::Dialer { address } = &endpoint {
+                    let (addresses, now) = (std::iter::once(address.clone()), Instant::now());
+                    self.known.seen(&peer_id, addresses, std::time::SystemTime::now(), now);
+                }
+                self.dials.connected(&peer_id);
+                self.hot.connected(&peer_id);
+                self.pinned.connected(&peer_id);
+                self.supervisor.connected(&peer_id);
+                self.swarm.pubsub_connected(&peer_id);
+                if let Some(update) = self.bootstrap.connected(&peer_id, Instant::now()) {
+                    self.bootstrap_update(update);
+                }
+                return;
+            }
+            SwarmEvent::UnreachableAddr {
+                peer_id,
+                address,
+                error,
+                attempts_remaining,
+            } => {
+                if matches!(error, PendingConnectionError::InvalidPeerId) {
+                    self.check_identity(&peer_id, &address);
+                }
+                let attempt = dial::Attempt::new(address.clone(), &error);
+                let outcome = attempt.outcome;
+                self.back_off(&address, Some(&peer_id), &error);
+                if let Some(attempts) = self.dials.failed(&peer_id, attempt, attempts_remaining) {
+                    if attempts
+                        .iter()
+                        .all(|attempt| attempt.outcome == dial::Outcome::BackingOff)
+                    {
+                        debug!("Not dialing {}, its addresses are backing off", peer_id);
+                    } else {
+                        self.handle_event(Event::DialFailed {
+                            peer: Some(peer_id.clone()),
+                            attempts,
+                        });
+                    }
+                    if let Some(update) = self.bootstrap.failed(&peer_id, Instant::now()) {
+                        self.bootstrap_update(update);
+                    }
+                    self.dnsaddr.dial_failed(&peer_id, Instant::now());
+                }
+                if outcome == dial::Outcome::BackingOff {
+                    return;
+                }
+                self.metrics.dial_failed(&outcome.to_string());
+                (Some(peer_id), Some(address), negotiation::pending(&error), error.to_string())
+            }
+            SwarmEvent::NewListenAddr(address) => {
+                return self.handle_event(Event::ListenAddr { address });
+            }
+            SwarmEvent::ExpiredListenAddr(address) => {
+                return self.handle_event(Event::ListenAddrExpired { address });
+            }
+            SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
+                let attempt = dial::Attempt::new(address.clone(), &error);
+                if attempt.outcome == dial::Outcome::BackingOff {
+                    debug!("Not dialing {}, it is backing off", address);
+                    return;
+                }
+                self.back_off(&address, None, &error);
+                self.metrics.dial_failed(&attempt.outcome.to_string());
+                self.handle_event(Event::DialFailed {
+                    peer:     None,
+                    attempts: vec![attempt],
+                });
+                (None, Some(address), negotiation::pending(&error), error.to_string())
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => (None, Some(send_back_addr), negotiation::pending(&error), error.to_string()),
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause: Some(cause),
+                ..
+            } => (Some(peer_id), None, negotiation::reason(&cause), cause.to_string()),
+            _ => return,
+        };
+        if let Some(reason) = reason {
+            self.handle_event(Event::NegotiationFailed {
+                peer,
+                address,
+                reason,
+                error,
+            });
+        }
+    }
+
+    /// Wait before dialing `address` again after it failed with `error`,
+    /// unless the address was not at fault.
+    fn back_off(
+        &self,
+        address: &Multiaddr,
+        peer: Option<&PeerId>,
+        error: &PendingConnectionError<std::io::Error>,
+    ) {
+        if matches!(error, PendingConnectionError::ConnectionLimit(_)) {
+            return;
+        }
+        match dial::outcome(error) {
+            dial::Outcome::BackingOff
+            | dial::Outcome::Denied
+            | dial::Outcome::WrongPeerId
+            | dial::Outcome::UnsupportedAddress => {}
+            outcome => self.backoffs.failed(address, peer, outcome, Instant::now()),
+        }
+    }
+
+    /// Find out who answered instead of `expected` at `address`.
+    fn check_identity(&mut self, expected: &PeerId, address: &Multiaddr) {
+        let actual = match self.identities.get(address) {
+            Some(actual) if actual != *expected => actual,
+            _ => return,
+        };
+        // Dialing ourselves also fails with a wrong peer id
+        if actual == *Swarm::local_peer_id(&self.swarm) {
+            return;
+        }
+        self.handle_event(Event::IdentityMismatch {
+            expected: expected.clone(),
+            actual,
+            address: address.clone(),
+            policy: self.identity_policy,
+        });
+    }
+
+    /// Move `address` from `expected` to `actual` in the address book and
+    /// dial `actual`.
+    fn accept_identity(&mut self, expected: &PeerId, actual: &PeerId, address: Multiaddr) {
+        info!("Moving address {} from {} to {}", address, expected, actual);
+        self.swarm.move_address(expected, actual, address);
+        if let Err(err) = Swarm::dial(&mut self.swarm, actual) {
+            debug!("Could not dial {}: {:?}", actual, err);
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Message {
+                source,
+                topic,
+                data,
+                direct,
+                timestamp,
+                provenance,
+                signed,
+            } => {
+                // Relays send republished messages in their own envelope
+                let sender = provenance.relays().last().unwrap_or(&source);
+                let message_id = timestamp.map(|timestamp| spans::message_id(sender, timestamp));
+                let parent = self.connections.get(sender).unwrap_or(&self.span);
+                let span = spans::receive(parent, message_id.as_deref(), &topic);
+                let _entered = span.enter();
+                let mut data = data;
+                if let Some((election, _)) = self
+                    .elections
+                    .values_mut()
+                    .find(|(election, _)| election::topic(election.group()) == topic)
+                {
+                    match serde_cbor::from_slice::<Heartbeat>(&data) {
+                        Ok(heartbeat) => election.receive(source, &heartbeat, Instant::now()),
+                        Err(err) => warn!("Invalid heartbeat from {}: {}", source, err),
+                    }
+                    return;
+                }
+                if topic == aggregate::TOPIC {
+                    match serde_cbor::from_slice::<Contribution>(&data) {
+                        Ok(contribution) => {
+                            self.aggregates
+                                .receive(source, &contribution, Instant::now());
+                        }
+                        Err(err) => warn!("Invalid aggregates from {}: {}", source, err),
+                    }
+                    return;
+                }
+                if let Some(rotated) = self.keyring.rotated_topic(&topic) {
+                    let result = serde_cbor::from_slice::<Rotation>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|rotation| {
+                            self.keyring
+                                .receive(&rotated, &source, &rotation, Instant::now())
+                        });
+                    match result {
+                        Ok(true) => {
+                            let (generation, fingerprint) =
+                                self.keyring.fingerprint(&rotated).unwrap_or_default();
+                            info!(
+                                "Received key {} of generation {} for {} from {}",
+                                fingerprint, generation, rotated, source
+                            );
+                        }
+                        Ok(false) => {}
+                        Err(err) => warn!("Ignoring key rotation from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if let Some(managed) = self.membership.managed_topic(&topic) {
+                    let result = serde_cbor::from_slice::<membership::Message>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|message| self.membership.receive(&managed, &source, message));
+                    if let Err(err) = result {
+                        warn!("Ignoring membership message from {}: {:#}", source, err);
+                    }
+                    return;
+                }
+                if topic == lifecycle::TOPIC {
+                    let result = serde_cbor::from_slice::<lifecycle::Announcement>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|announcement| {
+                            self.lifecycle.receive(&source, &announcement)?;
+                            Ok(announcement)
+                        });
+                    match result {
+                        Ok(announcement) => {
+                            info!("{} announced {}", source, announcement.kind);
+                            if announcement.kind == lifecycle::Kind::Leave {
+                                if let Some(presence) = &mut self.presence {
+                                    let changes = presence.remove(&source);
+                                    self.presence_changed(changes);
+                                }
+                            }
+                            self.emit(&Event::Announced {
+                                peer:  source,
+                                kind:  announcement.kind,
+                                agent: announcement.agent,
+                            });
+                        }
+                        Err(err) => warn!("Ignoring announcement from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if let Some((map, replica)) = self.replicas.on_topic(&topic) {
+                    let now = self.swarm.timestamp();
+                    let result = serde_cbor::from_slice::<replica::Message>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|message| {
+                            match message {
+                                replica::Message::Delta(delta) => {
+                                    Ok((replica.merge(delta, now)?, Vec::new()))
+                                }
+                                replica::Message::Digest {
+                                    after,
+                                    through,
+                                    versions,
+                                } => {
+                                    let answer = replica.answer(
+                                        after.as_deref(),
+                                        through.as_deref(),
+                                        &versions,
+                                    );
+                                    Ok((Vec::new(), answer))
+                                }
+                            }
+                        });
+                    match result {
+                        Ok((changed, answer)) => {
+                            for (key, value) in changed {
+                                self.emit(&Event::StateChanged {
+                                    map: map.clone(),
+                                    key,
+                                    value,
+                                });
+                            }
+                            for delta in answer {
+                                self.publish_replica(&topic, &delta);
+                            }
+                        }
+                        Err(err) => {
+                            warn!("Ignoring state update on {} from {}: {:#}", map, source, err);
+                        }
+                    }
+                    return;
+                }
+                if topic == nickname::TOPIC {
+                    let result = serde_cbor::from_slice::<nickname::Profile>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|profile| {
+                            let nickname = profile.nickname.clone();
+                            Ok((self.nicknames.receive(&source, profile)?, nickname))
+                        });
+                    match result {
+                        Ok((true, nickname)) => debug!("{} is {}", source, nickname),
+                        Ok((false, _)) => {}
+                        Err(err) => warn!("Ignoring profile from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if topic == presence::TOPIC {
+                    let now = Instant::now();
+                    let result = match &mut self.presence {
+                        Some(presence) => {
+                            serde_cbor::from_slice::<presence::Heartbeat>(&data)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|heartbeat| presence.receive(&source, heartbeat, now))
+                        }
+                        None => Ok(Vec::new()),
+                    };
+                    match result {
+                        Ok(changes) => self.presence_changed(changes),
+                        Err(err) => warn!("Ignoring heartbeat from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if topic == rotation::TOPIC {
+                    let now = std::time::SystemTime::now();
+                    let result = serde_cbor::from_slice::<rotation::Migration>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|migration| self.rotations.receive(&migration, now));
+                    match result {
+                        Ok(Some((previous, current))) => self.migrate(&previous, &current),
+                        Ok(None) => {}
+                        Err(err) => warn!("Ignoring migration from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if topic == schedule::TOPIC {
+                    let result = serde_cbor::from_slice::<schedule::Action>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|action| self.schedule.receive(source.clone(), action));
+                    if let Err(err) = result {
+                        warn!("Ignoring action from {}: {:#}", source, err);
+                    }
+                    return;
+                }
+                if let Some(moderated) = self.moderation.moderated_topic(&topic) {
+                    let result = serde_cbor::from_slice::<Blocklist>(&data)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|blocklist| self.moderation.receive(&moderated, &blocklist));
+                    match result {
+                        Ok(true) => info!("Updated blocklist for {}", moderated),
+                        Ok(false) => {}
+                        Err(err) => warn!("Ignoring blocklist from {}: {:#}", source, err),
+                    }
+                    return;
+                }
+                if let Some(last_active) = self.topic_activity.get_mut(&topic) {
+                    *last_active = Instant::now();
+                }
+                self.metrics.received(&topic);
+                if let (Some(timestamp), Some(message_id)) = (timestamp, &message_id) {
+                    let sent = std::time::UNIX_EPOCH + Duration::from_millis(timestamp.wall_ms);
+                    let latency = std::time::SystemTime::now().duration_since(sent);
+                    self.metrics
+                        .delivered(latency.unwrap_or_default(), message_id);
+                }
+                if self.moderation.is_blocked(&topic, &source) {
+                    debug!("Dropping message on {} from blocked {}", topic, source);
+                    return;
+                }
+                if let Some(presence) = &mut self.presence {
+                    presence.seen(&source, &topic, Instant::now());
+                }
+                if self.is_encrypted(&topic) {
+                    match self.keyring.open(&topic, &data) {
+                        Ok(plaintext) => data = plaintext,
+                        Err(err) => {
+                            warn!("Dropping message on {} from {}: {:#}", topic, source, err);
+                            return;
+                        }
+                    }
+                }
+                match self.middleware.inbound(&topic, &source, data) {
+                    Ok(Some(next)) => data = next,
+                    Ok(None) => return,
+                    Err(err) => {
+                        warn!("Dropping message on {} from {}: {:#}", topic, source, err);
+                        return;
+                    }
+                }
+                if !provenance.matches(&data) {
+                    warn!("Dropping message on {} from {} changed by a relay", topic, source);
+                    self.swarm.penalize(&source);
+                    return;
+                }
+                let max_relays = self
+                    .subscriptions
+                    .get(&topic)
+                    .and_then(|options| options.max_relays);
+                if matches!(max_relays, Some(max) if provenance.relays().len() > max) {
+                    debug!(
+                        "Dropping message on {} from {} through {} relays",
+                        topic,
+                        source,
+                        provenance.relays().len()
+                    );
+                    return;
+                }
+                if let Some(decoder) = self.state_decoders.get_mut(&topic) {
+                    let update = match serde_cbor::from_slice::<Update>(&data) {
+                        Ok(update) => update,
+                        Err(err) => {
+                            warn!("Invalid state update on {} from {}: {}", topic, source, err);
+                            self.swarm.penalize(&source);
+                            return;
+                        }
+                    };
+                    match decoder.receive(&source, update) {
+                        Some(state) => data = state.to_vec(),
+                        None => return,
+                    }
+                }
+                if let Err(err) = self.schemas.validate(&topic, &data) {
+                    warn!("Dropping message on {} from {}: {}", topic, source, err);
+                    self.swarm.penalize(&source);
+                    return;
+                }
+                debug!(
+                    "Received {} bytes on {} from {}{} at {:?}",
+                    data.len(),
+                    topic,
+                    source,
+                    if direct { " (direct)" } else { "" },
+                    timestamp
+                );
+                self.recent.record(format!(
+                    "received {} bytes on {} from {}{}",
+                    data.len(),
+                    topic,
+                    source,
+                    if direct { " (direct)" } else { "" },
+                ));
+                let message = route::Message {
+                    source,
+                    topic,
+                    data,
+                    direct,
+                    timestamp,
+                    provenance,
+                    signed,
+                };
+                let encrypted = self.is_encrypted(&message.topic);
+                if let (Some(archive), false) = (&mut self.archive, encrypted) {
+                    let route::Message { topic, source, data, timestamp, .. } = &message;
+                    archive.record(topic, source, data, *timestamp);
+                }
+                if let Some(message) = self.backfills.hold(message, self.limits.backfill_held) {
+                    self.deliver(message);
+                }
+            }
+            event @ Event::Historical { .. }
+            | event @ Event::DirectMessage { .. }
+            | event @ Event::Scheduled { .. }
+            | event @ Event::Announced { .. }
+            | event @ Event::IdentityRotated { .. }
+            | event @ Event::PresenceJoined { .. }
+            | event @ Event::PresenceLeft { .. }
+            | event @ Event::StateChanged { .. }
+            | event @ Event::DuplicateClosed { .. }
+            | event @ Event::ClockJump { .. }
+            | event @ Event::SubsystemDegraded { .. }
+            | event @ Event::PeerDiscovered { .. }
+            | event @ Event::PeerExpired { .. }
+            | event @ Event::Unsubscribed { .. }
+            | event @ Event::ListenAddr { .. }
+            | event @ Event::ListenAddrExpired { .. }
+            | event @ Event::NatStatusChanged { .. }
+            | event @ Event::DhtQuery { .. }
+            | event @ Event::ConnectionReaped { .. }
+            | event @ Event::FileTransfer(_) => {
+                self.emit(&event);
+            }
+            Event::BundleEvicted(evicted) => {
+                warn!(
+                    "Bundle store full, evicted bundle {:?} of {} bytes on {} for {}",
+                    evicted.id, evicted.size, evicted.topic, evicted.destination
+                );
+                self.emit(&Event::BundleEvicted(evicted));
+            }
+            Event::RendezvousDiscovered {
+                namespace,
+                peer,
+                addresses,
+            } => {
+                if peer != *self.local_peer_id() && !Swarm::is_connected(&self.swarm, &peer) {
+                    debug!("Dialing {} of rendezvous namespace {}", peer, namespace);
+                    for address in &addresses {
+                        self.swarm.add_address(&peer, address.clone());
+                    }
+                    if let Err(err) = Swarm::dial(&mut self.swarm, &peer) {
+                        debug!("Could not dial {}: {:?}", peer, err);
+                    }
+                }
+                self.emit(&Event::RendezvousDiscovered {
+                    namespace,
+                    peer,
+                    addresses,
+                });
+            }
+            Event::PeerUnresponsive { peer, failures } => {
+                self.recent
+                    .record(format!("{} evicted after {} failed pings", peer, failures));
+                self.evict(&peer);
+                self.emit(&Event::PeerUnresponsive { peer, failures });
+            }
+            Event::NegotiationFailed {
+                peer,
+                address,
+                reason,
+                error,
+            } => {
+                let at = address.as_ref().map_or_else(String::new, |a| format!(" at {}", a));
+                match &peer {
+                    Some(peer) => debug!("Upgrade with {}{} failed: {}", peer, at, error),
+                    None => debug!("Upgrade with unknown peer{} failed: {}", at, error),
+                }
+                self.negotiation.record(reason);
+                self.emit(&Event::NegotiationFailed {
+                    peer,
+                    address,
+                    reason,
+                    error,
+                });
+            }
+            Event::IdentityMismatch {
+                expected,
+                actual,
+                address,
+                policy,
+            } => {
+                let now = std::time::SystemTime::now();
+                let rotated = self.rotations.successor(&expected, now) == Some(&actual);
+                match policy {
+                    _ if rotated => {
+                        info!("Address {} of {} belongs to its successor {}", address, expected, actual);
+                        self.accept_identity(&expected, &actual, address.clone());
+                    }
+                    mismatch::Policy::Reject => {
+                        warn!(
+                            "Address {} of {} belongs to {} now, see --identity-mismatch",
+                            address, expected, actual
+                        );
+                    }
+                    mismatch::Policy::Update => {
+                        self.accept_identity(&expected, &actual, address.clone());
+                    }
+                    mismatch::Policy::Prompt => {
+                        info!("Address {} of {} belongs to {} now", address, expected, actual);
+                    }
+                }
+                self.recent
+                    .record(format!("{} answered at {} instead of {}", actual, address, expected));
+                self.emit(&Event::IdentityMismatch {
+                    expected,
+                    actual,
+                    address,
+                    policy,
+                });
+            }
+            Event::DialFailed { peer, attempts } => {
+                let tried = attempts
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let peer_name = peer.as_ref().map_or_else(|| "address".into(), PeerId::to_base58);
+                info!("Could not dial {}: {}", peer_name, tried);
+                for attempt in &attempts {
+                    debug!("Dialing {} failed: {}", attempt.address, attempt.error);
+                }
+                self.recent
+                    .record(format!("dial {} failed: {}", peer_name, tried));
+                self.emit(&Event::DialFailed { peer, attempts });
+            }
+            Event::Subscribed { peer, topic } => {
+                if let Some((_, replica)) = self.replicas.on_topic(&topic) {
+                    replica.want_digest();
+                }
+                self.emit(&Event::Subscribed { peer, topic });
+            }
+            Event::Bootstrapped { peers, elapsed } => {
+                info!(
+                    "Bootstrapped through {} peers in {} ms",
+                    peers.len(),
+                    elapsed.as_millis()
+                );
+                self.recent
+                    .record(format!("bootstrapped through {} peers", peers.len()));
+                self.emit(&Event::Bootstrapped { peers, elapsed });
+            }
+        }
+    }
+
+    /// Hand a live message to its route and the event consumers.
+    fn deliver(&mut self, message: route::Message) {
+        self.routes.dispatch(&message);
+        self.emit(&message.into());
+    }
+
+    /// Deliver the archived messages of a finished backfill of `topic`, then
+    /// the live messages held meanwhile.
+    fn finish_backfill(&mut self, topic: &str, result: service::Result) {
+        let entries: Vec<archive::Entry> = match result
+            .map_err(anyhow::Error::from)
+            .and_then(|data| serde_cbor::from_slice(&data).context("Decoding archive"))
+        {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Could not backfill {}: {:#}", topic, err);
+                Vec::new()
+            }
+        };
+        let held = self.backfills.finish(topic, &entries);
+        if self.subscriptions.get(topic).is_none() {
+            return;
+        }
+        info!("Backfilled {} messages on {}", entries.len(), topic);
+        for entry in entries {
+            match entry.decode() {
+                Ok((source, data, timestamp)) => {
+                    self.emit(&Event::Historical {
+                        source,
+                        topic: topic.to_owned(),
+                        data,
+                        timestamp,
+                    });
+                }
+                Err(err) => warn!("Dropping archived message on {}: {:#}", topic, err),
+            }
+        }
+        for message in held {
+            self.deliver(message);
+        }
+    }
+
+    /// Hand an event to all consumers, forgetting those that went away.
+    fn emit(&mut self, event: &Event) {
+        if let Some(journal) = &mut self.journal {
+            if let Err(err) = journal.append(event) {
+                warn!("Could not journal message: {:#}", err);
+            }
+        }
+        if let Some(audit) = &mut self.audit {
+            if let Some(record) = audit::Record::of_event(event, std::time::SystemTime::now()) {
+                audit.record(&record);
+            }
+        }
+        self.event_senders.retain(|sender| !sender.is_closed());
+        for sender in &mut self.event_senders {
+            if sender.try_send(event.clone()).is_err() {
+                warn!("Event consumer is falling behind, dropping event");
+            }
+        }
+    }
+
+    /// Call `handler` on every message on `topic`, like
+    /// [`NodeHandle::route`].
+    pub fn route<F, Fut>(&mut self, topic: &str, limit: usize, handler: F)
+    where
+        F: FnMut(route::Message) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.routes.insert(topic, route::spawn(limit, handler));
+    }
+
+    /// Subscribe to `topic` and exchange values of `T` on it, like
+    /// [`NodeHandle::typed_topic`].
+    pub fn typed_topic<T>(
+        &mut self,
+        topic: &str,
+    ) -> Result<(typed::TypedSender<T>, typed::TypedReceiver<T>)>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        self.subscribe(topic, TopicOptions::default())?;
+        let (sender, receiver) = mpsc::channel(route::BUFFER);
+        self.routes.insert(topic, sender);
+        Ok((
+            typed::TypedSender::new(self.handle(), topic),
+            typed::TypedReceiver::new(receiver, Some(self.handle())),
+        ))
+    }
+
+    /// Subscribe to `topic` and call `handler` on the values of `T` on it,
+    /// like [`NodeHandle::on_topic`].
+    pub fn on_topic<T, F, Fut>(
+        &mut self,
+        topic: &str,
+        limit: usize,
+        handler: F,
+    ) -> Result<mpsc::Receiver<typed::Malformed>>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+        F: FnMut(message::MeshMessage<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.subscribe(topic, TopicOptions::default())?;
+        let (errors, malformed) = mpsc::channel(route::BUFFER);
+        let sender = typed::spawn(limit, Some(self.handle()), handler, errors);
+        self.routes.insert(topic, sender);
+        Ok(malformed)
+    }
+
+    /// Subscribe to `topic` and exchange values of `T` on it with local
+    /// echo, like [`NodeHandle::echo_topic`].
+    pub fn echo_topic<T>(
+        &mut self,
+        topic: &str,
+    ) -> Result<(typed::EchoSender<T>, typed::EchoReceiver<T>)>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let (sender, receiver) = self.typed_topic(topic)?;
+        let (receipt_sender, receipts) = mpsc::channel(route::BUFFER);
+        self.routes.insert(&typed::receipt_topic(topic), receipt_sender);
+        let (echo_sender, echoes) = mpsc::unbounded();
+        Ok((
+            typed::EchoSender::new(sender, echo_sender),
+            typed::EchoReceiver::new(receiver, echoes, receipts),
+        ))
+    }
+
+    /// Subscribe to `topic`, like [`NodeHandle::subscribe`].
+    pub fn subscribe(&mut self, topic: &str, options: TopicOptions) -> Result<()> {
+        self.swarm.subscribe(topic);
+        self.swarm.set_multicast(topic, options.multicast);
+        self.swarm.set_acked(topic, options.acked);
+        self.topic_activity.insert(topic.to_owned(), Instant::now());
+        if options.delta {
+            self.state_decoders.entry(topic.to_owned()).or_default();
+        } else {
+            self.state_decoders.remove(topic);
+        }
+        if options.backfill.is_some() || options.backfill_since.is_some() {
+            if self.subscriptions.get(topic).is_none() {
+                let count = options.backfill.unwrap_or(usize::MAX);
+                self.backfill(topic, count, options.backfill_since)?;
+            }
+        }
+        self.subscriptions.insert(topic, options)
+    }
+
+    /// Ask a connected archiver for the last `count` messages on `topic`,
+    /// of those from `since` on.
+    fn backfill(
+        &mut self,
+        topic: &str,
+        count: usize,
+        since: Option<std::time::SystemTime>,
+    ) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        if !self.backfills.start(topic, receiver) {
+            return Ok(());
+        }
+        match since {
+            Some(since) => {
+                debug!(
+                    "Backfilling messages on {} since {}",
+                    topic,
+                    humantime::format_rfc3339_seconds(since)
+                );
+            }
+            None => debug!("Backfilling {} messages on {}", count, topic),
+        }
+        let request = archive::Request {
+            topic:    topic.to_owned(),
+            count,
+            since_ms: since.map(archive::epoch_ms),
+        };
+        let data = serde_cbor::to_vec(&request)?;
+        self.swarm
+            .call_service(archive::SERVICE.into(), data, sender);
+        Ok(())
+    }
+
+    /// Unsubscribe from `topic`, like [`NodeHandle::unsubscribe`].
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<bool> {
+        self.swarm.unsubscribe(topic);
+        self.swarm.set_multicast(topic, false);
+        self.swarm.set_acked(topic, false);
+        self.topic_activity.remove(topic);
+        self.state_decoders.remove(topic);
+        if let Some(archive) = &mut self.archive {
+            archive.remove(topic);
+        }
+        for message in self.backfills.finish(topic, &[]) {
+            self.deliver(message);
+        }
+        self.subscriptions.remove(topic)
+    }
+
+    /// The topics we are subscribed to, like [`NodeHandle::topics`].
+    pub fn topics(&self) -> Vec<(String, TopicOptions)> {
+        self.subscriptions
+            .topics()
+            .map(|(topic, options)| (topic.clone(), *options))
+            .collect()
+    }
+
+    /// Publish `data` on `topic`, like [`NodeHandle::publish`].
+    pub fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<()> {
+        self.publish_now(topic, data).map(drop)
+    }
+
+    /// Publish like [`Node::publish`], telling whether the message went to
+    /// pubsub right away rather than waiting in the outbox, or in the batch
+    /// of power-save mode or quiet hours.
+    pub fn publish_now(&mut self, topic: &str, data: Vec<u8>) -> Result<bool> {
+        if let Some(last_active) = self.topic_activity.get_mut(topic) {
+            *last_active = Instant::now();
+        }
+        self.recent
+            .record(format!("published {} bytes on {}", data.len(), topic));
+        self.schemas.validate(topic, &data)?;
+        let data = self.middleware.outbound(topic, data)?;
+        let max = self.swarm.max_message_size();
+        anyhow::ensure!(
+            data.len() <= max,
+            "Message of {} on {} is over the limit of {}",
+            data.len().bytes(),
+            topic,
+            max.bytes()
+        );
+        self.swarm.may_publish(topic)?;
+        self.metrics.published(topic);
+        if self.outbox.is_durable(topic) {
+            self.outbox.push(topic, &data)?;
+            if !self.is_saving_power() {
+                self.flush_outbox();
+            }
+            return Ok(false);
+        }
+        let data = self.encrypt(topic, data)?;
+        if self.dormant {
+            if self.batch.len() >= quiet::MAX_BUFFERED {
+                anyhow::bail!("Outbound buffer full during quiet hours");
+            }
+            self.batch.push(topic.to_owned(), data);
+            return Ok(false);
+        }
+        if self.power_save && self.qos(topic) != qos::Class::Realtime {
+            let full = self.batch.push(topic.to_owned(), data);
+            if full {
+                self.flush_batch();
+            }
+            return Ok(full);
+        }
+        self.swarm
+            .publish(topic, &data)
+            .map(|()| true)
+            .map_err(|err| match err {
+                PublishError::InsufficientPeers => publisher::NoPeers.into(),
+                err => anyhow::anyhow!("Publish failed: {:?}", err),
+            })
+    }
+
+    /// Look up the addresses of `peer_id` in the Kademlia DHT, which finds
+    /// peers beyond the local network. The lookup runs while the node does,
+    /// so spawn the result or keep driving the node.
+    pub fn find_peer(&mut self, peer_id: &PeerId) -> impl Future<Output = Result<Vec<Multiaddr>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.find_peer(peer_id.clone(), sender);
+        receiver.map(|result| result.context("Node stopped")?)
+    }
+
+    /// Look up the peers closest to `key` in the Kademlia DHT, like
+    /// [`Node::find_peer`]. Closest by the XOR distance of the hashes.
+    pub fn closest_peers(&mut self, key: &[u8]) -> impl Future<Output = Result<Vec<PeerId>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.closest_peers(key.to_vec(), sender);
+        receiver.map(|result| result.context("Node stopped")?)
+    }
+
+    /// Announce in the Kademlia DHT that we provide `key`, so peers looking
+    /// for it find us. The announcement is repeated before the records
+    /// expire, see [`discovery`].
+    pub fn start_providing(&mut self, key: &[u8]) -> Result<()> {
+        self.swarm.start_providing(key.to_vec())
+    }
+
+    /// Stop announcing `key`. Peers forget us as its provider once the
+    /// announced records expire.
+    pub fn stop_providing(&mut self, key: &[u8]) {
+        self.swarm.stop_providing(key);
+    }
+
+    /// The keys we provide, next to be announced first.
+    pub fn provided(&self) -> Vec<discovery::Provided> {
+        self.swarm.provided()
+    }
+
+    /// Store a signed record of `name` in the DHT, pointing at our peer id
+    /// and listen addresses with `metadata`. Publishing again replaces it.
+    pub fn publish_name(
+        &mut self,
+        name: &str,
+        metadata: BTreeMap<String, String>,
+    ) -> impl Future<Output = Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+        let addresses: Vec<Multiaddr> = self.listeners().cloned().collect();
+        let migration = self.rotations.own();
+        self.swarm.publish_name(name, &addresses, metadata, migration, sender);
+        receiver.map(|result| result.context("Node stopped")?)
+    }
+
+    /// Look up the latest valid record of `name` in the DHT. Fails if more
+    /// than one peer claims the name.
+    pub fn resolve_name(&mut self, name: &str) -> impl Future<Output = Result<naming::NameRecord>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.name_records(name, sender);
+        let name = name.to_owned();
+        receiver.map(move |result| naming::latest(&name, &result.context("Node stopped")??))
+    }
+
+    /// Store `value` under the application record `key` in the DHT, on the
+    /// closest peers, see [`discovery`]. Storing again replaces it.
+    pub fn dht_put(&mut self, key: &[u8], value: Vec<u8>) -> impl Future<Output = Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.dht_put(key, value, sender);
+        receiver.map(|result| result.context("Node stopped")?)
+    }
+
+    /// Look up the distinct values stored under the application record
+    /// `key` in the DHT. Fails if none is found.
+    pub fn dht_get(&mut self, key: &[u8]) -> impl Future<Output = Result<Vec<Vec<u8>>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.dht_get(key, sender);
+        receiver.map(|result| {
+            let mut values = result.context("Node stopped")??;
+            values.sort();
+            values.dedup();
+            Ok(values)
+        })
+    }
+
+    /// The peers we are connected to.
+    pub fn peers(&self) -> Vec<PeerId> {
+        let known_peers = self.known_peers();
+        let known_peers = known_peers.read().unwrap();
+        known_peers
+            .keys()
+            .filter(|peer_id| Swarm::is_connected(&self.swarm, peer_id))
+            .cloned()
+            .collect()
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Events { mut sender } => {
+                // Subsystems disabled before the consumer subscribed
+                for degraded in &self.degraded {
+                    let _ = sender.try_send(Event::SubsystemDegraded {
+                        subsystem: degraded.subsystem,
+                        error:     degraded.error.clone(),
+                    });
+                }
+                self.event_senders.push(sender);
+            }
+            Command::Route { topic, sender } => self.routes.insert(&topic, sender),
+            Command::Unroute { topic } => self.routes.remove(&topic),
+            Command::WaitReady { criteria, sender } => {
+                self.waiting_ready.push((criteria, sender));
+            }
+            Command::FindPeer { peer_id, sender } => self.swarm.find_peer(peer_id, sender),
+            Command::ClosestPeers { key, sender } => self.swarm.closest_peers(key, sender),
+            Command::StartProviding { key, sender } => {
+                let _ = sender.send(self.start_providing(&key));
+            }
+            Command::StopProviding { key } => self.stop_providing(&key),
+            Command::Provided { sender } => {
+                let _ = sender.send(self.provided());
+            }
+            Command::PublishName {
+                name,
+                metadata,
+                sender,
+            } => {
+                let publish = self.publish_name(&name, metadata);
+                tokio::spawn(async move {
+                    let _ = sender.send(publish.await);
+                });
+            }
+            Command::ResolveName { name, sender } => {
+                let resolve = self.resolve_name(&name);
+                tokio::spawn(async move {
+                    let _ = sender.send(resolve.await);
+                });
+            }
+            Command::DhtPut { key, value, sender } => {
+                let put = self.dht_put(&key, value);
+                tokio::spawn(async move {
+                    let _ = sender.send(put.await);
+                });
+            }
+            Command::DhtGet { key, sender } => {
+                let get = self.dht_get(&key);
+                tokio::spawn(async move {
+                    let _ = sender.send(get.await);
+                });
+            }
+            Command::Shutdown => self.shutdown(),
+            Command::Dial { address, sender } => {
+                let _ = sender.send(self.dial(address));
+            }
+            Command::Timestamp { sender } => {
+                let _ = sender.send(self.swarm.timestamp());
+            }
+            Command::Subscribe {
+                topic,
+                options,
+                sender,
+            } => {
+                let _ = sender.send(self.subscribe(&topic, options));
+            }
+            Command::Unsubscribe { topic, sender } => {
+                let _ = sender.send(self.unsubscribe(&topic));
+            }
+            Command::Topics { sender } => {
+                let _ = sender.send(self.topics());
+            }
+            Command::Publish {
+                topic,
+                data,
+                sender,
+            } => {
+                self.publish_queue.push(topic, data, sender, Instant::now());
+            }
+            Command::Republish {
+                topic,
+                data,
+                source,
+                provenance,
+                sender,
+            } => {
+                // The peer we got it from is the last relay, if any
+                let from = provenance.relays().last().cloned().unwrap_or(source);
+                let result = self
+                    .schemas
+                    .validate(&topic, &data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| self.swarm.relay_hop(&provenance, &data, &from))
+                    .and_then(|provenance| {
+                        let data = self.seal(&topic, data)?;
+                        self.swarm
+                            .republish(&topic, &data, provenance)
+                            .map_err(|err| anyhow::anyhow!("Publish failed: {:?}", err))
+                    });
+                let _ = sender.send(result);
+            }
+            Command::PublishState {
+                topic,
+                data,
+                sender,
+            } => {
+                let result = self.schemas.validate(&topic, &data).map(|()| {
+                    let encoder = self.state_encoders.entry(topic.clone()).or_default();
+                    let update = encoder.update(&data);
+                    serde_cbor::to_vec(&update).expect("State updates always encode")
+                });
+                let result = result
+                    .map_err(anyhow::Error::from)
+                    .and_then(|update| self.seal(&topic, update));
+                let result = result.and_then(|update| {
+                    self.swarm
+                        .publish(&topic, &update)
+                        .map_err(|err| anyhow::anyhow!("Publish failed: {:?}", err))
+                });
+                let _ = sender.send(result);
+            }
+            Command::PublishTo {
+                peers,
+                topic,
+                data,
+                sender,
+            } => {
+                let result = self
+                    .schemas
+                    .validate(&topic, &data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| self.seal(&topic, data))
+                    .map(|data| self.swarm.publish_to(&peers, &topic, &data));
+                let _ = sender.send(result);
+            }
+            Command::SendBundle {
+                destination,
+                topic,
+                data,
+                priority,
+                sender,
+            } => {
+                let result = self
+                    .schemas
+                    .validate(&topic, &data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| self.seal(&topic, data))
+                    .and_then(|data| {
+                        self.swarm
+                            .send_bundle(&destination, &topic, &data, priority)
+                    });
+                let _ = sender.send(result);
+            }
+            Command::BundleTrace { id, sender } => {
+                let _ = sender.send(self.swarm.bundle_trace(&id));
+            }
+            Command::AdvertiseService {
+                descriptor,
+                handler,
+            } => {
+                info!("Advertising service {}", descriptor.name);
+                self.swarm.advertise_service(descriptor, handler);
+            }
+            Command::WithdrawService { service } => {
+                info!("Withdrawing service {}", service);
+                self.swarm.withdraw_service(&service);
+            }
+            Command::CallService {
+                service,
+                data,
+                sender,
+            } => self.swarm.call_service(service, data, sender),
+            Command::Request {
+                peer_id,
+                data,
+                sender,
+            } => self.swarm.request(&peer_id, data, sender),
+            Command::SendTo {
+                peer_id,
+                data,
+                sender,
+            } => self.swarm.send_to(&peer_id, data, sender),
+            Command::SendFile {
+                peer_id,
+                path,
+                manifest,
+                sender,
+            } => {
+                self.swarm.offer_file(&peer_id, path, manifest, Some(sender));
+            }
+            Command::DnsaddrResolved { address, result } => {
+                self.dnsaddr_resolved(&address, result);
+            }
+            Command::ServeRequests { handler } => self.serve_requests(handler),
+            Command::PushJob {
+                queue,
+                key,
+                data,
+                visibility_timeout,
+                sender,
+            } => {
+                self.swarm
+                    .push_job(&queue, &key, data, visibility_timeout, sender);
+            }
+            Command::JoinElection {
+                group,
+                config,
+                changes,
+            } => {
+                info!("Joining election {}", group);
+                self.swarm.subscribe(&election::topic(&group));
+                let local_peer_id = Swarm::local_peer_id(&self.swarm).clone();
+                let election = Election::new(group.clone(), local_peer_id, config, Instant::now());
+                self.elections.insert(group, (election, changes));
+            }
+            Command::LeaveElection { group } => {
+                info!("Leaving election {}", group);
+                self.swarm.unsubscribe(&election::topic(&group));
+                self.elections.remove(&group);
+            }
+            Command::Increment { name, delta } => self.aggregates.increment(&name, delta),
+            Command::SetGauge { name, value } => {
+                let timestamp = self.swarm.timestamp();
+                self.aggregates.set_gauge(&name, value, timestamp);
+            }
+            Command::Metric { name, sender } => {
+                let _ = sender.send(self.aggregates.value(&name, Instant::now()));
+            }
+            Command::ResolvePeer { name, sender } => {
+                let _ = sender.send(self.resolve_peer(&name));
+            }
+            Command::PeerInfo { peer_id, sender } => {
+                let _ = sender.send(self.peer_info(&peer_id));
+            }
+            Command::FindServices { service, sender } => {
+                let _ = sender.send(self.swarm.find_services(&service));
+            }
+            Command::WarmPeer { address, sender } => {
+                let _ = sender.send(self.warm_peer(&address));
+            }
+            Command::CoolPeer { peer_id, sender } => {
+                let _ = sender.send(self.cool_peer(&peer_id));
+            }
+            Command::NatStatus { sender } => {
+                let _ = sender.send(self.nat.clone());
+            }
+            Command::HotPeers { sender } => {
+                let swarm = &self.swarm;
+                let _ = sender.send(self.hot.list(|peer_id| Swarm::is_connected(swarm, peer_id)));
+            }
+            Command::Pin { address, sender } => {
+                let _ = sender.send(self.pin(&address));
+            }
+            Command::Unpin { peer_id, sender } => {
+                let _ = sender.send(self.unpin(&peer_id));
+            }
+            Command::PinnedPeers { sender } => {
+                let _ = sender.send(self.pinned_peers());
+            }
+            Command::Peers { sender } => {
+                let _ = sender.send(self.peers());
+            }
+            Command::ListenAddrs { sender } => {
+                let _ = sender.send(self.listeners().cloned().collect());
+            }
+            Command::Ban {
+                peer_id,
+                duration,
+                sender,
+            } => {
+                let _ = sender.send(self.ban(&peer_id, duration));
+            }
+            Command::Unban { peer_id, sender } => {
+                let _ = sender.send(self.unban(&peer_id));
+            }
+            Command::AcceptIdentity {
+                expected,
+                actual,
+                address,
+            } => self.accept_identity(&expected, &actual, address),
+            Command::PowerSave { .. } => unreachable!("Handled in Node::run"),
+            Command::SetTopicKey { topic, key, admin } => self.set_topic_key(&topic, key, admin),
+            Command::TopicFingerprint { topic, sender } => {
+                let _ = sender.send(self.keyring.fingerprint(&topic));
+            }
+            Command::RotateTopicKey {
+                topic,
+                members,
+                sender,
+            } => {
+                let _ = sender.send(self.rotate_topic_key(&topic, members));
+            }
+            Command::RequestMembership { topic, admin } => {
+                info!("Asking {} to join {}", admin, topic);
+                self.swarm.subscribe(&keyring::topic(&topic));
+                self.swarm.subscribe(&membership::topic(&topic));
+                self.keyring.expect_key(&topic, admin.clone());
+                self.membership.request(&topic, admin, Instant::now());
+            }
+            Command::PendingMembers { topic, sender } => {
+                let _ = sender.send(self.membership.pending(&topic));
+            }
+            Command::Roster { topic, sender } => {
+                let _ = sender.send(self.roster(&topic));
+            }
+            Command::Profile { peer_id, sender } => {
+                let _ = sender.send(self.profile(&peer_id));
+            }
+            Command::BandwidthUsage { sender } => {
+                let _ = sender.send(self.bandwidth_usage());
+            }
+            Command::Samples { sender } => {
+                let _ = sender.send(self.statsd_samples());
+            }
+            Command::GetState { map, key, sender } => {
+                let _ = sender.send(self.get_state(&map, &key));
+            }
+            Command::SetState {
+                map,
+                key,
+                value,
+                sender,
+            } => {
+                let _ = sender.send(self.set_state(&map, &key, value));
+            }
+            Command::StateEntries { map, sender } => {
+                let _ = sender.send(self.state_entries(&map));
+            }
+            Command::ApproveMember {
+                topic,
+                member,
+                sender,
+            } => {
+                let result = self.membership.approve(&topic, &member).and_then(|grant| {
+                    info!("Admitting {} to {}", member, topic);
+                    self.publish_membership(&topic, &membership::Message::Grant(grant));
+                    let members = self.membership.members(&topic);
+                    self.rotate_topic_key(&topic, members)
+                });
+                let _ = sender.send(result);
+            }
+            Command::RevokeMember {
+                topic,
+                member,
+                sender,
+            } => {
+                let result = self.membership.revoke(&topic, &member).and_then(|_| {
+                    info!("Removing {} from {}", member, topic);
+                    let members = self.membership.members(&topic);
+                    self.rotate_topic_key(&topic, members)
+                });
+                let _ = sender.send(result);
+            }
+            Command::Mute { peer_id, muted } => {
+                if self.moderation.set_muted(peer_id.clone(), muted) {
+                    info!("{} {}", if muted { "Muted" } else { "Unmuted" }, peer_id);
+                }
+            }
+            Command::TrustModerator { topic, moderator } => {
+                info!("Honoring blocklists for {} from {}", topic, moderator);
+                self.swarm.subscribe(&moderation::topic(&topic));
+                self.moderation.trust(&topic, moderator);
+            }
+            Command::PublishBlocklist {
+                topic,
+                blocked,
+                sender,
+            } => {
+                let result = self
+                    .moderation
+                    .publish(&topic, &blocked, Instant::now())
+                    .map(|blocklist| {
+                        info!("Blocking {} senders on {}", blocklist.blocked.len(), topic);
+                        self.publish_blocklist(&blocklist);
+                    });
+                let _ = sender.send(result);
+            }
+            Command::RestrictTopic {
+                topic,
+                owner: Some(owner),
+            } => {
+                if self.swarm.restrict_topic(&topic, owner.clone()) {
+                    info!("Restricting {} to capabilities of {}", topic, owner);
+                }
+            }
+            Command::RestrictTopic { topic, owner: None } => {
+                if self.swarm.unrestrict_topic(&topic) {
+                    info!("Lifted the restriction of {}", topic);
+                }
+            }
+            Command::IssueCapability {
+                topic,
+                holder,
+                expires,
+                sender,
+            } => {
+                let result = self.swarm.issue_capability(&topic, &holder, expires);
+                if result.is_ok() {
+                    info!("Issued capability on {} to {}", topic, holder);
+                }
+                let _ = sender.send(result);
+            }
+            Command::AddCapability { capability, sender } => {
+                let topic = capability.topic.clone();
+                let result = self.swarm.add_capability(capability);
+                if result.is_ok() {
+                    info!("Publishing on {} with a capability", topic);
+                }
+                let _ = sender.send(result);
+            }
+            Command::TrustScheduler { issuer } => {
+                info!("Running actions scheduled by {}", issuer);
+                self.swarm.subscribe(schedule::TOPIC);
+                self.schedule.trust(issuer);
+            }
+            Command::Schedule {
+                name,
+                at,
+                data,
+                sender,
+            } => {
+                let timestamp = self.swarm.timestamp();
+                let result = self
+                    .schedule
+                    .schedule(&name, at, &data, timestamp)
+                    .map(|action| {
+                        info!("Scheduled action {} at {}", name, humantime::format_rfc3339(at));
+                        let data = serde_cbor::to_vec(&action).expect("Actions always encode");
+                        if let Err(err) = self.swarm.publish(schedule::TOPIC, &data) {
+                            warn!("Action {} not published: {:?}", name, err);
+                        }
+                    });
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+// Pass-through accessors
+impl Node {
+    pub fn local_peer_id<'a>(&'a self) -> &'a PeerId {
+        Swarm::local_peer_id(&self.swarm)
+    }
+
+    pub fn listeners<'a>(&'a self) -> impl Iterator<Item = &'a Multiaddr> {
+        Swarm::listeners(&self.swarm)
+    }
+
+    pub fn network_info(&self) -> NetworkInfo {
+        Swarm::network_info(&self.swarm)
+    }
+
+    pub fn total_inbound(&self) -> u64 {
+        self.bandwidth_monitor.total_inbound()
+    }
+
+    pub fn total_outbound(&self) -> u64 {
+        self.bandwidth_monitor.total_outbound()
+    }
 
+    /// Traffic by peer and by protocol, see [`accounting`].
+    pub fn bandwidth_usage(&self) -> accounting::Report {
+        self.meter.report()
+    }
 
-type OrderSyncRequest = (
-    PeerId,
-    order_sync::messages::Request,
-    oneshot::Sender<order_sync::Result>,
-);
+    /// Return a handle to the peer database
+    pub fn known_peers(&self) -> PeerStore {
+        self.swarm.known_peers()
+    }
 
-/// TODO: Impl Debug
-pub struct Node {
-    bandwidth_monitor: Arc<BandwidthSinks>,
-    swarm:             Swarm<Behaviour>,
+    /// Our listening sockets and address book, for a successor to take over.
+    /// The successor announces the upgrade, so we do not announce leaving.
+    pub fn handoff(&mut self) -> handoff::Handoff {
+        self.lifecycle.replace();
+        let peers = self
+            .known_peers()
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|info| {
+                let identify = info.identify.as_ref()?;
+                Some(handoff::Peer::new(&info.peer_id, &identify.listen_addrs))
+            })
+            .collect();
+        handoff::Handoff {
+            listeners: self.activated.listening(),
+            peers,
+        }
+    }
 
-    order_sync_sender:   mpsc::Sender<OrderSyncRequest>,
-    order_sync_receiver: mpsc::Receiver<OrderSyncRequest>,
-}
+    /// Learn the addresses of `peers` from a predecessor and reconnect.
+    pub fn add_peers(&mut self, peers: &[handoff::Peer]) {
+        for (peer_id, addresses) in peers.iter().filter_map(handoff::Peer::parse) {
+            for address in addresses {
+                self.swarm.add_address(&peer_id, address);
+            }
+            if let Err(err) = Swarm::dial(&mut self.swarm, &peer_id) {
+                debug!("Could not redial {}: {:?}", peer_id, err);
+            }
+        }
+    }
 
-#[derive(Clone)]
-pub struct OrderSyncRpc {
-    sender: mpsc::Sender<OrderSyncRequest>,
-}
+    /// A snapshot for [`control`] clients.
+    pub fn status(&self) -> control::Status {
+        let peers = self
+            .known_peers()
+            .read()
+            .unwrap() // FIXME: Can block
+            .values()
+            .map(|info| {
+                control::PeerStatus {
+                    peer_id:       info.peer_id.to_base58(),
+                    connected:     Swarm::is_connected(&self.swarm, &info.peer_id),
+                    ping_ms:       info.ping.map(|ping| ping.as_millis() as u64),
+                    ping_avg_ms:   info.latency.average().map(|ping| ping.as_millis() as u64),
+                    ping_failures: info.latency.failures(),
+                    agent:         info
+                        .identify
+                        .as_ref()
+                        .map(|identify| identify.agent_version.clone()),
+                    protocols:     info
+                        .identify
+                        .as_ref()
+                        .map_or_else(Vec::new, |identify| identify.protocols.clone()),
+                }
+            })
+            .collect();
+        control::Status {
+            peer_id: self.local_peer_id().to_base58(),
+            peers,
+            inbound: self.total_inbound(),
+            outbound: self.total_outbound(),
+            topics: self
+                .subscriptions
+                .topics()
+                .map(|(topic, _)| topic.clone())
+                .collect(),
+            events: self.recent.to_vec(),
+            backoffs: self.backoffs.status(Instant::now()),
+            keys: self
+                .subscriptions
+                .topics()
+                .filter_map(|(topic, _)| {
+                    let (_, fingerprint) = self.keyring.fingerprint(topic)?;
+                    Some((topic.clone(), fingerprint))
+                })
+                .collect(),
+        }
+    }
 
-impl OrderSyncRpc {
-    pub async fn call(
-        &mut self,
-        peer_id: PeerId,
-        request: order_sync::messages::Request,
-    ) -> order_sync::Result {
-        let (sender, receiver) = oneshot::channel();
-        self.sender.send((peer_id, request, sender)).await?;
-        receiver.await?
+    /// Answer a request from a [`control`] client.
+    pub fn control(&mut self, request: control::Request, sender: oneshot::Sender<control::Response>) {
+        match request {
+            control::Request::Status => {
+                let _ = sender.send(control::Response::Status(self.status()));
+            }
+            control::Request::Bundle { peer_id } => {
+                let peer_id = match self.resolve_peer(&peer_id) {
+                    Ok(peer_id) => peer_id,
+                    Err(err) => {
+                        let _ = sender.send(control::Response::Error(format!("{:#}", err)));
+                        return;
+                    }
+                };
+                let receiver = self.fetch_bundle(&peer_id);
+                tokio::spawn(async move {
+                    let response = match receiver.await {
+                        Ok(Ok(bundle)) => control::Response::Bundle(hex::encode(bundle)),
+                        Ok(Err(message)) => control::Response::Error(message),
+                        Err(_) => control::Response::Error("Node stopped".into()),
+                    };
+                    let _ = sender.send(response);
+                });
+            }
+            control::Request::Dial { address } => {
+                let response = address
+                    .parse::<Multiaddr>()
+                    .map_err(|err| anyhow::anyhow!("Invalid address {}: {}", address, err))
+                    .and_then(|address| self.dial(address));
+                let _ = sender.send(match response {
+                    Ok(()) => control::Response::Dialing,
+                    Err(err) => control::Response::Error(format!("{:#}", err)),
+                });
+            }
+            control::Request::Shutdown => {
+                info!("Shutting down on request of a control client");
+                self.shutdown();
+                let _ = sender.send(control::Response::ShuttingDown);
+            }
+            // Taken by the control socket itself
+            control::Request::Attach | control::Request::Authenticate { .. } => {
+                let _ = sender.send(control::Response::Error("Not a daemon".into()));
+            }
+        }
     }
-}
 
-impl Node {
-    pub async fn new(peer_id_keys: identity::Keypair) -> Result<Self> {
-        // Generate peer id
-        let peer_id = PeerId::from(peer_id_keys.public());
-        info!("Peer Id: {}", peer_id.clone());
+    /// The peer whose [`nickname`] is `name`, or else the known peer that
+    /// `name` refers to, see [`names::resolve`].
+    pub fn resolve_peer(&self, name: &str) -> Result<PeerId> {
+        if let Some(peer_id) = self.nicknames.resolve(name)? {
+            return Ok(peer_id);
+        }
+        let known_peers = self.known_peers();
+        let known_peers = known_peers.read().unwrap(); // FIXME: Can block
+        names::resolve(name, known_peers.keys())
+    }
 
-        // Create a transport
-        let (transport, bandwidth_monitor) =
-            make_transport(peer_id_keys.clone()).context("Creating libp2p transport")?;
+    /// What we know about `peer_id`: its agent version and protocols from
+    /// identify, the address it saw us at, its round trip time, services and
+    /// score.
+    pub fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        let known_peers = self.known_peers();
+        let known_peers = known_peers.read().unwrap(); // FIXME: Can block
+        let mut info = known_peers.get(peer_id).cloned()?;
+        info.score = self.swarm.peer_score(peer_id);
+        Some(info)
+    }
 
-        // Create node behaviour
-        let behaviour = Behaviour::new(peer_id_keys)
-            .await
-            .context("Creating node behaviour")?;
+    /// Known peers, for debug bundles.
+    pub fn topology(&self) -> Vec<bundle::Peer> {
+        self.known_peers()
+            .read()
+            .unwrap() // FIXME: Can block
+            .values()
+            .map(|info| {
+                let identify = info.identify.as_ref();
+                bundle::Peer {
+                    peer_id:   info.peer_id.to_base58(),
+                    connected: Swarm::is_connected(&self.swarm, &info.peer_id),
+                    ping_ms:   info.ping.map(|ping| ping.as_millis() as u64),
+                    agent:     identify.map(|identify| identify.agent_version.clone()),
+                    addresses: identify.map_or_else(Vec::new, |identify| {
+                        identify.listen_addrs.iter().map(ToString::to_string).collect()
+                    }),
+                    protocols: identify.map_or_else(Vec::new, |identify| identify.protocols.clone()),
+                    observed:  info.observed_addr.as_ref().map(ToString::to_string),
+                }
+            })
+            .collect()
+    }
 
-        // Executor for connection background tasks.
-        let executor = Box::new(|future| {
-            trace!("Spawning background task");
-            tokio::spawn(future);
-        });
+    /// Include the log file and crash reports from `sources` in debug
+    /// bundles.
+    pub fn set_bundle_sources(&mut self, sources: bundle::Sources) {
+        self.bundle_sources = sources;
+    }
 
-        // Create a Swarm to manage peers and events.
-        let swarm: Swarm<Behaviour> = SwarmBuilder::new(transport, behaviour, peer_id)
-            .executor(executor)
-            .build();
+    /// Let `peer_id` retrieve our debug bundle.
+    pub fn add_debug_admin(&mut self, peer_id: PeerId) {
+        self.swarm.authorize_diagnostics(peer_id);
+    }
 
-        // Create a channel for OrderSync requests
-        let request_buffer_size = 16;
-        let (order_sync_sender, order_sync_receiver) = mpsc::channel(request_buffer_size);
+    /// Package our debug [`bundle`].
+    pub fn debug_bundle(&self) -> Result<Vec<u8>> {
+        bundle::build(&self.bundle_sources, &self.status(), &self.topology())
+    }
 
-        Ok(Self {
-            bandwidth_monitor,
-            swarm,
-            order_sync_sender,
-            order_sync_receiver,
-        })
+    /// Send `data` to `peer_id` over the [`rpc`] protocol, dialing it if
+    /// needed, and wait for the reply of its handler. Fails if the peer does
+    /// not reply within the request timeout, ten seconds unless changed with
+    /// [`Node::set_request_timeout`].
+    pub fn request(
+        &mut self,
+        peer_id: &PeerId,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.request(peer_id, data, sender);
+        receiver.map(|result| Ok(result.context("Node stopped")??))
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        // Start behaviours
-        self.swarm.start()?;
+    /// Send requests of other peers to `handler`, see
+    /// [`NodeHandle::serve_requests`].
+    pub fn serve_requests(&mut self, handler: mpsc::Sender<RpcRequest>) {
+        self.swarm.serve_requests(handler);
+    }
 
-        // Listen on all interfaces and whatever port the OS assigns
-        Swarm::listen_on(
-            &mut self.swarm,
-            "/ip4/0.0.0.0/tcp/0"
-                .parse()
-                .context("Parsing listening address")?,
-        )
-        .context("Starting to listen")?;
+    /// Fail requests not answered within `timeout`, at most
+    /// [`rpc::MAX_TIMEOUT`].
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.swarm.set_request_timeout(timeout);
+    }
 
-        Ok(())
+    /// Retrieve the debug bundle of `peer_id`.
+    pub fn fetch_bundle(&mut self, peer_id: &PeerId) -> oneshot::Receiver<diagnostics::Result> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm.fetch_bundle(peer_id, sender);
+        receiver
     }
 
-    /// Create a Send + Sync handle to the OrderSync RPC interface.
-    pub fn order_sync_rpc(&self) -> OrderSyncRpc {
-        OrderSyncRpc {
-            sender: self.order_sync_sender.clone(),
+    /// Current values of the metrics pushed to [`statsd`].
+    pub fn statsd_samples(&self) -> Vec<statsd::Sample> {
+        use statsd::Sample;
+        let known_peers = self.known_peers();
+        let known_peers = known_peers.read().unwrap(); // FIXME: Can block
+        let now = Instant::now();
+        let rejected = self.admission.rejected();
+        let drops = self.swarm.expiry_drops();
+        let mut samples = vec![
+            Sample::Gauge(
+                "peers.connected".into(),
+                self.network_info().num_peers() as i64,
+            ),
+            Sample::Gauge("peers.known".into(), known_peers.len() as i64),
+            Sample::Gauge(
+                "subscriptions".into(),
+                self.subscriptions.topics().count() as i64,
+            ),
+            Sample::Counter("bandwidth.inbound".into(), self.total_inbound()),
+            Sample::Counter("bandwidth.outbound".into(), self.total_outbound()),
+            Sample::Counter("udp.sent".into(), self.udp.stats().sent()),
+            Sample::Counter("udp.received".into(), self.udp.stats().received()),
+            Sample::Counter("udp.retransmitted".into(), self.udp.stats().retransmitted()),
+            Sample::Counter("udp.duplicates".into(), self.udp.stats().duplicates()),
+            Sample::Counter("pubsub.duplicates".into(), self.swarm.pubsub_duplicates()),
+            Sample::Counter("messages.expired".into(), drops.expired),
+            Sample::Counter("messages.hop_limited".into(), drops.hop_limited),
+            Sample::Gauge("dtn.bundles".into(), self.swarm.bundle_count() as i64),
+            Sample::Gauge("outbox.pending".into(), self.outbox.len() as i64),
+            Sample::Gauge("publish.queued".into(), self.publish_queue.len() as i64),
+            Sample::Counter("publish.rejected".into(), self.publish_queue.stats().rejected),
+            Sample::Counter("connections.rejected.swarm".into(), rejected.swarm),
+            Sample::Counter("connections.rejected.per_ip".into(), rejected.per_ip),
+        ];
+        samples.extend(negotiation::Reason::ALL.iter().map(|reason| {
+            Sample::Counter(
+                format!("negotiation.{}", reason),
+                self.negotiation.count(*reason),
+            )
+        }));
+        samples.extend(
+            known_peers
+                .values()
+                .filter(|info| Swarm::is_connected(&self.swarm, &info.peer_id))
+                .filter_map(|info| info.ping)
+                .map(|ping| Sample::Timer("ping".into(), ping)),
+        );
+        for name in self.aggregates.names() {
+            if let Some(value) = self.aggregates.value(name, now) {
+                samples.push(Sample::Gauge(format!("aggregate.{}", name), value));
+            }
         }
+        samples
     }
 
-    /// Drive the event loop forward
-    pub async fn run(&mut self) -> Result<()> {
-        let order_sync_request = tokio::select! {
-            _ = self.swarm.next() => None,
-            r = self.order_sync_receiver.next() => r,
+    /// Current [`metrics`] in `format`.
+    pub fn metrics_text(&self, format: metrics::Format) -> String {
+        let known_peers = self.known_peers();
+        let peers_known = known_peers.read().unwrap().len(); // FIXME: Can block
+        let gauges = metrics::Gauges {
+            peers_connected: self.network_info().num_peers(),
+            peers_known,
+            subscriptions: self.subscriptions.topics().count(),
+            inbound: self.total_inbound(),
+            outbound: self.total_outbound(),
+            rejected: self.admission.rejected(),
+            publish_queue: self.publish_queue.len(),
+            publish_rejected: self.publish_queue.stats().rejected,
+            drops: self.swarm.expiry_drops(),
         };
-        if let Some((peer_id, request, sender)) = order_sync_request {
-            self.swarm.order_sync_send(&peer_id, request, sender);
+        self.metrics.render(format, gauges, &self.bandwidth_usage())
+    }
+
+    /// Keep redundant connections to the peer at `address`, which must end
+    /// in `/p2p/<peer id>`.
+    pub fn add_critical_peer(&mut self, address: &Multiaddr) -> Result<()> {
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Critical peer {} has no /p2p/ peer id", address))?;
+        info!("Keeping redundant connections to critical peer {}", peer_id);
+        self.swarm.add_critical_peer(peer_id, address);
+        Ok(())
+    }
+
+    /// Apply the `changes` of a reloaded config, see [`reload`].
+    pub fn reconfigure(&mut self, changes: &reload::Changes) {
+        if changes.is_empty() {
+            info!("Config reloaded, nothing changed");
+            return;
+        }
+        for topic in &changes.unsubscribe {
+            if let Err(err) = self.unsubscribe(topic) {
+                error!("Could not unsubscribe from {}: {:#}", topic, err);
+            }
+        }
+        for topic in &changes.subscribe {
+            if self.subscriptions.get(topic).is_some() {
+                continue;
+            }
+            if let Err(err) = self.subscribe(topic, TopicOptions::default()) {
+                error!("Could not subscribe to {}: {:#}", topic, err);
+            }
+        }
+        for address in &changes.listen {
+            if let Err(err) = self.listen_on(address.clone()) {
+                error!("{:#}", err);
+            }
+        }
+        for address in &changes.bootstrap {
+            if let Err(err) = self.dial_bootstrap_peer(address) {
+                error!("{:#}", err);
+            }
+        }
+        for address in &changes.critical {
+            if let Err(err) = self.add_critical_peer(address) {
+                error!("{:#}", err);
+            }
+        }
+        for address in &changes.pinned {
+            if let Err(err) = self.pin(address) {
+                error!("{:#}", err);
+            }
+        }
+        for address in &changes.rendezvous {
+            if let Err(err) = self.add_rendezvous_point(address) {
+                error!("{:#}", err);
+            }
+        }
+        for option in &changes.restart {
+            warn!("Removals from {} apply on restart", option);
+        }
+        self.recent.record("config reloaded".into());
+        info!(
+            "Config reloaded: {} topics subscribed, {} unsubscribed, {} addresses and peers added",
+            changes.subscribe.len(),
+            changes.unsubscribe.len(),
+            changes.listen.len()
+                + changes.bootstrap.len()
+                + changes.critical.len()
+                + changes.pinned.len()
+                + changes.rendezvous.len()
+        );
+    }
+
+    /// Bootstrap through the peer at `address` too, which must end in
+    /// `/p2p/<peer id>` or be a `/dnsaddr/` address, dialing it now.
+    pub fn dial_bootstrap_peer(&mut self, address: &Multiaddr) -> Result<()> {
+        if dnsaddr::is_dnsaddr(address) {
+            info!("Resolving new bootstrap peers {}", address);
+            self.dnsaddr.add(address.clone(), Instant::now());
+            self.resolve_dnsaddrs(Instant::now());
+            return Ok(());
+        }
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Bootstrap peer {} has no /p2p/ peer id", address))?;
+        info!("Dialing new bootstrap peer {}", peer_id);
+        self.bootstrap_peers.push((peer_id.clone(), address.clone()));
+        self.swarm.add_address(&peer_id, address);
+        Swarm::dial(&mut self.swarm, &peer_id)
+            .map_err(|err| anyhow::anyhow!("Could not dial bootstrap peer {}: {:?}", peer_id, err))
+    }
+
+    /// Keep a connection to the peer at `address`, which must end in
+    /// `/p2p/<peer id>`, dialing it now, see [`warm`].
+    pub fn warm_peer(&mut self, address: &Multiaddr) -> Result<()> {
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Hot peer {} has no /p2p/ peer id", address))?;
+        if self.hot.warm(peer_id.clone(), address) {
+            info!("Keeping a connection to hot peer {}", peer_id);
         }
+        self.tick_hot_peers(Instant::now());
         Ok(())
     }
-}
 
-// Pass-through accessors
-impl Node {
-    pub fn local_peer_id<'a>(&'a self) -> &'a PeerId {
-        Swarm::local_peer_id(&self.swarm)
+    /// Always keep a connection to the peer at `address`, which must end in
+    /// `/p2p/<peer id>`, dialing it now, see [`warm`]. Unlike hot peers,
+    /// pinned peers do not count towards `--max-peers`, are kept connected
+    /// while dormant and are not evicted for their score.
+    pub fn pin(&mut self, address: &Multiaddr) -> Result<()> {
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Pinned peer {} has no /p2p/ peer id", address))?;
+        if self.pinned.warm(peer_id.clone(), address) {
+            info!("Pinned peer {}", peer_id);
+            self.recent.record(format!("pinned {}", peer_id));
+        }
+        self.tick_hot_peers(Instant::now());
+        Ok(())
     }
 
-    pub fn listeners<'a>(&'a self) -> impl Iterator<Item = &'a Multiaddr> {
-        Swarm::listeners(&self.swarm)
+    /// Stop keeping a connection to `peer_id` for being pinned, returning
+    /// false if it was not pinned.
+    pub fn unpin(&mut self, peer_id: &PeerId) -> bool {
+        let unpinned = self.pinned.cool(peer_id);
+        if unpinned {
+            info!("Unpinned peer {}", peer_id);
+            self.recent.record(format!("unpinned {}", peer_id));
+        }
+        unpinned
     }
 
-    pub fn network_info(&self) -> NetworkInfo {
-        Swarm::network_info(&self.swarm)
+    /// The peers [`Node::pin`] keeps connected.
+    pub fn pinned_peers(&self) -> Vec<warm::HotPeer> {
+        let swarm = &self.swarm;
+        self.pinned.list(|peer_id| Swarm::is_connected(swarm, peer_id))
     }
 
-    pub fn total_inbound(&self) -> u64 {
-        self.bandwidth_monitor.total_inbound()
+    /// How to reconnect to important peers that dropped, see [`supervisor`].
+    pub fn set_reconnect(&mut self, config: supervisor::Config) {
+        self.supervisor.set_config(config);
     }
 
-    pub fn total_outbound(&self) -> u64 {
-        self.bandwidth_monitor.total_outbound()
+    /// Reconnect to the peer at `address`, which must end in
+    /// `/p2p/<peer id>`, when its connection drops, see [`supervisor`].
+    pub fn supervise(&mut self, address: &Multiaddr) -> Result<()> {
+        let (peer_id, address) = behaviour::multipath::split_peer_id(address)
+            .ok_or_else(|| anyhow::anyhow!("Supervised peer {} has no /p2p/ peer id", address))?;
+        self.swarm.add_address(&peer_id, address);
+        if self.supervisor.supervise(peer_id.clone()) {
+            info!("Reconnecting to {} when it drops", peer_id);
+        }
+        Ok(())
     }
 
-    /// Return a handle to the peer database
-    pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
-        self.swarm.known_peers()
+    /// Stop reconnecting to `peer_id`, returning false if it was not
+    /// supervised.
+    pub fn unsupervise(&mut self, peer_id: &PeerId) -> bool {
+        self.supervisor.unsupervise(peer_id)
     }
+
+    /// Stop keeping a connection to `peer_id`, returning false if it was not
+    /// hot.
+    pub fn cool_peer(&mut self, peer_id: &PeerId) -> bool {
+        let cooled = self.hot.cool(peer_id);
+        if cooled {
+            info!("No longer keeping a connection to {}", peer_id);
+        }
+        cooled
+    }
+}
+
+/// How [`run`] sets up the node.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub data_dir:           Option<PathBuf>,
+    /// How the data directory keeps state, see [`storage`].
+    pub storage:            storage::Backend,
+    /// Where the node identity is kept, see [`keystore`].
+    pub identity:           Option<PathBuf>,
+    pub namespace:          Option<String>,
+    pub soak:               Option<soak::Config>,
+    pub statsd:             Option<statsd::Config>,
+    pub journal:            Option<rolling::Config>,
+    /// Swarm events as JSON lines, see [`audit`].
+    pub event_journal:      Option<rolling::Config>,
+    pub clock_jumps:        clock::Config,
+    pub keepalive:          Option<keepalive::Config>,
+    /// Reconnecting to important peers, see [`supervisor`].
+    pub reconnect:          supervisor::Config,
+    /// Dial timeout and reaping idle connections, see [`health`].
+    pub connection_health:  health::Config,
+    /// Flood protection, see [`scoring`].
+    pub scoring:            Option<scoring::Config>,
+    /// Heartbeats and the roster of peers online, see [`presence`].
+    pub presence:           Option<presence::Config>,
+    /// Our signed profile, see [`nickname`].
+    pub nickname:           Option<nickname::Config>,
+    /// Largest payload and frame, see [`fragment`].
+    pub message_size:       fragment::Config,
+    /// Expiry and hop limit of our messages, see [`expiry`].
+    pub message_ttl:        expiry::Config,
+    /// Which payloads to compress and how, see [`codec`].
+    pub compression:        codec::Config,
+    /// Retransmissions on acknowledged topics, see [`reliable`].
+    pub reliable:           reliable::Config,
+    /// Publishes of handles waiting at most, see [`outbound`].
+    pub publish_queue:      usize,
+    pub dtn:                Option<dtn::Config>,
+    /// The log file to include in debug bundles.
+    pub log_file:           Option<PathBuf>,
+    /// Peers allowed to retrieve debug bundles.
+    pub debug_admin:        Vec<PeerId>,
+    pub critical:           Vec<Multiaddr>,
+    /// Peers always kept connected, see [`Node::pin`].
+    pub pinned:             Vec<Multiaddr>,
+    /// Addresses to listen on besides the TCP listener.
+    pub listen:             Vec<Multiaddr>,
+    /// Address families of the default listeners and dials, see [`family`].
+    pub address_family:     family::Config,
+    /// SOCKS5 proxies to dial through, see [`proxy`].
+    pub proxy:              Vec<proxy::Route>,
+    /// Local link addresses to listen on.
+    pub links:              Vec<String>,
+    pub bandwidth:          shaping::Config,
+    /// Connection caps, see [`admission`].
+    pub limits:             admission::Config,
+    pub power_save:         bool,
+    pub quiet_hours:        quiet::Schedule,
+    pub identity_mismatch:  mismatch::Policy,
+    pub duplicates:         duplicate::Policy,
+    /// What to do with messages not signed by their source, see
+    /// [`verification`].
+    pub verification:       verification::Policy,
+    /// Bootstrap peers besides the 0x Mesh bootnodes.
+    pub bootstrap:          Vec<Multiaddr>,
+    pub bootstrap_quorum:   usize,
+    /// Serve as a [`rendezvous`] point.
+    pub rendezvous_server:  bool,
+    /// Rendezvous points to register our topics at.
+    pub rendezvous:         Vec<Multiaddr>,
+    /// Where to receive files, see [`file`].
+    pub files:              file::Config,
+    pub pubsub:             pubsub::Config,
+    /// Topics whose publishes go through the [`outbox`].
+    pub outbox:             Vec<String>,
+    /// Data to publish on a timer, see [`publisher`].
+    pub heartbeats:         Vec<publisher::Heartbeat>,
+    /// Topics to subscribe to, unless subscribed with other options before.
+    pub topics:             Vec<String>,
+    /// Pre-shared keys of encrypted topics, see [`keyring`].
+    pub topic_keys:         Vec<keyring::KeyFile>,
+    pub discovery:          discovery::Config,
+    /// Peers and networks to allow or deny, see [`gate`].
+    pub gate:               gate::Config,
+    pub security:           security::Config,
+    /// Pins the wire to go-libp2p and js-libp2p defaults, see [`compat`].
+    pub compat:             compat::Compat,
+    /// The key file of a private network, see [`pnet`].
+    pub swarm_key:          Option<PathBuf>,
+    pub profile:            profile::Profile,
+    /// Overrides the connection limit of the profile, see [`profile`].
+    pub max_peers:          Option<usize>,
+    /// How long shutting down may take, see [`Node::close`].
+    pub shutdown_timeout:   Duration,
+    /// Messages per topic to keep for backfills, see [`archive`].
+    pub archive:            Option<usize>,
+    /// Keep the archive in the data directory, see [`archive`].
+    pub persist_archive:    bool,
+    /// Where to serve [`metrics`] to Prometheus.
+    pub metrics:            Option<std::net::SocketAddr>,
+    /// Where to answer liveness and readiness [`probe`]s.
+    pub probes:             Option<probe::Config>,
+    /// Reads the config again on `SIGHUP`, see [`reload`].
+    pub reload:             Option<reload::Source>,
+    /// Where to serve the HTTP control [`api`].
+    pub api:                Option<api::Config>,
+    /// Tokens of the control socket and the API, see [`access`].
+    pub access:             Option<PathBuf>,
+    /// Whether mDNS, the metrics endpoint and the StatsD exporter may fail,
+    /// see [`degrade`].
+    pub subsystem_failures: degrade::Policy,
+    /// Whether to read commands from stdin, see [`console`].
+    pub interactive:        bool,
+    pub mode:               mode::Mode,
 }
 
-pub async fn run() -> Result<()> {
-    let peer_id_keys = identity::Keypair::generate_ed25519();
-    let mut node = Node::new(peer_id_keys).await.context("Creating node")?;
-    node.start()?;
+pub async fn run(options: RunOptions) -> Result<()> {
+    let started = Instant::now();
+    let config_hash = crash::config_hash(&options);
+    if let Some(option) = options.mode.unsupported(&options) {
+        anyhow::bail!("--{} is not available in {} mode", option, options.mode);
+    }
+    let RunOptions {
+        data_dir,
+        storage: storage_backend,
+        identity,
+        namespace,
+        soak,
+        statsd,
+        journal,
+        event_journal,
+        clock_jumps,
+        keepalive,
+        reconnect,
+        connection_health,
+        scoring,
+        presence,
+        nickname,
+        message_size,
+        message_ttl,
+        compression,
+        reliable,
+        publish_queue,
+        dtn,
+        log_file,
+        debug_admin,
+        critical,
+        pinned,
+        listen,
+        address_family,
+        proxy,
+        links,
+        bandwidth,
+        limits,
+        power_save,
+        quiet_hours,
+        identity_mismatch,
+        duplicates,
+        verification,
+        bootstrap,
+        bootstrap_quorum,
+        rendezvous_server,
+        rendezvous,
+        files,
+        mut pubsub,
+        outbox,
+        heartbeats,
+        topics,
+        topic_keys,
+        discovery,
+        gate,
+        mut security,
+        compat,
+        swarm_key,
+        profile,
+        max_peers,
+        shutdown_timeout,
+        archive,
+        persist_archive,
+        metrics,
+        probes,
+        mut reload,
+        api,
+        access,
+        subsystem_failures,
+        interactive,
+        mode,
+    } = options;
+    let full = mode != mode::Mode::Bootstrap;
+    let daemon = mode == mode::Mode::Daemon;
+    if daemon && data_dir.is_none() {
+        anyhow::bail!("--mode daemon needs --data-dir for its control socket");
+    }
+    if !full {
+        pubsub.protocol = pubsub::Protocol::Off;
+    }
+
+    // Take over the listening sockets of an instance running on the same
+    // data directory. Otherwise listen on our own socket, so we can in turn
+    // hand it off.
+    let handoff_path = data_dir.as_ref().map(|data_dir| data_dir.join(handoff::FILE_NAME));
+    let access = access.as_deref().map(access::Tokens::load).transpose()?;
+    let mut listeners = activation::listen_fds();
+    let mut peers = Vec::new();
+    let mut upgraded = false;
+    let storage = match &data_dir {
+        Some(data_dir) => {
+            std::fs::create_dir_all(data_dir)
+                .with_context(|| format!("Creating data directory {}", data_dir.display()))?;
+            Some(storage::open(storage_backend, data_dir)?)
+        }
+        None => None,
+    };
+
+    // Unlock the identity and load the stores on other threads meanwhile
+    // The old default file of the identity moved into storage
+    let moved = data_dir.as_ref().map(|data_dir| data_dir.join(keystore::KEY));
+    let identity = identity
+        .filter(|path| Some(path) != moved.as_ref())
+        .map(storage::Slot::File)
+        .or_else(|| keystore::default_slot(storage.as_ref()));
+    let keypair = tokio::task::spawn_blocking(move || {
+        identity
+            .map(|slot| keystore::load_or_generate(&slot, &keystore::passphrase()))
+            .transpose()
+    });
+    let dtn_slot = storage
+        .as_ref()
+        .map(|storage| storage::Slot::new(storage, dtn::NAMESPACE, dtn::KEY));
+    let dtn_store = dtn.map(|config| {
+        tokio::task::spawn_blocking(move || {
+            match dtn_slot {
+                Some(slot) => dtn::Store::load(slot, config),
+                None => Ok(dtn::Store::new(config)),
+            }
+        })
+    });
+    let outbox = if outbox.is_empty() {
+        None
+    } else {
+        let storage = storage.as_ref().context("--outbox needs --data-dir")?;
+        let slot = storage::Slot::new(storage, outbox::NAMESPACE, outbox::KEY);
+        Some(tokio::task::spawn_blocking(move || outbox::Outbox::load(slot, outbox)))
+    };
+
+    if let Some(path) = &handoff_path {
+        if let Some(handoff) = handoff::receive(path).await? {
+            listeners.extend(handoff.listeners);
+            peers = handoff.peers;
+            upgraded = true;
+        }
+        if listeners.is_empty() {
+            let families = address_family.listen_families();
+            for &family in &families {
+                let listener = match family {
+                    family::Family::Ip4 => TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)),
+                    family::Family::Ip6 => TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                match listener {
+                    Ok(listener) => listeners.push(listener),
+                    // Hosts without IPv6 still listen on IPv4
+                    Err(err) if family == family::Family::Ip6 && families.len() > 1 => {
+                        warn!("Not listening on IPv6: {}", err);
+                    }
+                    Err(err) => return Err(err).context("Binding listening socket"),
+                }
+            }
+        }
+    }
+
+    if let Some(path) = swarm_key {
+        security.swarm_key = Some(pnet::SwarmKey::load(&path)?);
+    }
+    let mut builder = Node::builder();
+    if let Some(identity) = keypair.await.context("Loading identity")?? {
+        debug!("Identity key is {}", identity.kind);
+        builder = builder.with_keypair(identity.keypair);
+        if let Some(previous) = identity.previous {
+            builder = builder.with_previous_keypair(previous.keypair, previous.until);
+        }
+    }
+    let mut builder = builder
+        .with_bandwidth(bandwidth)
+        .with_connection_limits(limits)
+        .with_connection_health(connection_health)
+        .with_address_family(address_family)
+        .with_bootstrap_quorum(bootstrap_quorum)
+        .with_pubsub(pubsub)
+        .with_discovery(discovery)
+        .with_gate(gate)
+        .with_security(security)
+        .with_compat(compat)
+        .with_profile(profile)
+        .with_subsystem_failures(subsystem_failures);
+    if let Some(max) = max_peers {
+        builder = builder.with_max_peers(max);
+    }
+    let bootstrap_peers = bootstrap.clone();
+    for address in bootstrap {
+        builder = builder.with_bootstrap_peer(address);
+    }
+    for route in proxy {
+        builder = builder.with_proxy(route);
+    }
+    for listener in listeners {
+        builder = builder.with_listener(listener);
+    }
+    if let Some(namespace) = &namespace {
+        builder = builder.with_namespace(namespace);
+    }
+    let mut node = builder.build().await?;
+    let mut config = reload::Config {
+        topics:     topics.clone(),
+        listen:     listen.clone(),
+        bootstrap:  bootstrap_peers,
+        critical:   critical.clone(),
+        pinned:     pinned.clone(),
+        rendezvous: rendezvous.clone(),
+    };
+    for address in listen {
+        node.listen_on(address)?;
+    }
+    for address in &links {
+        node.listen_on_link(address)?;
+    }
+    node.set_power_save(power_save).await?;
+    node.set_quiet_hours(quiet_hours);
+    node.set_identity_policy(identity_mismatch);
+    node.set_duplicate_policy(duplicates);
+    node.set_verification(verification);
+    node.set_clock_jumps(clock_jumps);
+    if let Some(config) = scoring {
+        node.set_scoring(config);
+    }
+    node.set_publish_queue(publish_queue);
+    if let Some(config) = keepalive {
+        node.set_keepalive(config);
+    }
+    node.set_reconnect(reconnect);
+    if let Some(config) = presence {
+        node.set_presence(config);
+    }
+    if let Some(config) = nickname {
+        node.set_nickname(config);
+    }
+    node.set_message_size(message_size);
+    node.set_message_ttl(message_ttl);
+    node.set_compression(compression);
+    node.set_reliable(reliable);
+    if let Some(store) = dtn_store {
+        node.set_dtn(store.await.context("Loading bundles")??);
+    }
+    if let Some(outbox) = outbox {
+        node.set_outbox(outbox.await.context("Loading outbox")??);
+    }
+    if let Some(capacity) = archive {
+        node.set_archive(capacity);
+        if persist_archive {
+            let storage = storage
+                .as_ref()
+                .context("--persist-archive needs --data-dir")?;
+            node.load_archive(storage)?;
+            #[cfg(feature = "search")]
+            {
+                let data_dir = data_dir.as_ref().context("--persist-archive needs --data-dir")?;
+                node.index_archive(search::Index::open(&data_dir.join(search::FILE_NAME))?)?;
+            }
+        }
+    }
+    if let Some(config) = journal {
+        node.set_journal(journal::Journal::open(config)?);
+    }
+    if let Some(config) = event_journal {
+        node.set_event_journal(audit::Journal::open(config)?);
+    }
+    node.set_bundle_sources(bundle::Sources {
+        data_dir: data_dir.clone(),
+        log_file,
+    });
+    for peer_id in debug_admin {
+        node.add_debug_admin(peer_id);
+    }
+    node.add_peers(&peers);
+    if upgraded {
+        node.announce(lifecycle::Kind::Upgrade);
+    }
+    for address in &critical {
+        node.add_critical_peer(address)?;
+    }
+    for address in &pinned {
+        node.pin(address)?;
+    }
+    if rendezvous_server {
+        node.set_rendezvous_server();
+    }
+    for address in &rendezvous {
+        node.add_rendezvous_point(address)?;
+    }
+    node.set_files(files);
+    if let Some(storage) = &storage {
+        node.load_bans(storage)?;
+        node.load_address_book(storage)?;
+        node.storage = Some(storage.clone());
+    }
+    if let (Some(data_dir), Some(storage), true) = (&data_dir, &storage, full) {
+        // Attached clients subscribe again when they reattach
+        if !daemon {
+            node.load_subscriptions(storage)?;
+        }
+        let schemas = data_dir.join(schema::FILE_NAME);
+        if schemas.exists() {
+            node.load_schemas(&schemas)?;
+        }
+    }
+    let local_peer_id = node.local_peer_id().clone();
+    for file in &topic_keys {
+        node.set_topic_key(&file.topic, file.load()?, local_peer_id.clone());
+    }
+    for topic in &topics {
+        if node.subscriptions.get(topic).is_none() {
+            node.subscribe(topic, TopicOptions::default())?;
+        }
+    }
 
     let known_peers = node.known_peers();
     let mut order_sync_rpc = node.order_sync_rpc();
 
+    // Generate soak test traffic, if requested
+    let soak_handle = node.handle();
+    let soak = async move {
+        match soak {
+            Some(config) => soak::run(soak_handle, config).await,
+            None => future::pending().await,
+        }
+    }
+    .fuse();
+    tokio::pin!(soak);
+
+    // Publish heartbeats, if requested
+    for heartbeat in heartbeats {
+        tokio::spawn(heartbeat.run(node.handle()));
+    }
+
+    // Read commands from stdin, if requested
+    let console_handle = node.handle();
+    let console_topic = topics.first().cloned().unwrap_or_else(|| console::DEFAULT_TOPIC.into());
+    let console = async move {
+        if interactive {
+            console::run(console_handle, console_topic).await
+        } else {
+            future::pending().await
+        }
+    }
+    .fuse();
+    tokio::pin!(console);
+    let mut result = Ok(());
+
+    // Wait for a successor to take over
+    let handoff_request = async move {
+        match handoff_path {
+            Some(path) => handoff::serve(path).await,
+            None => future::pending().await,
+        }
+    }
+    .fuse();
+    tokio::pin!(handoff_request);
+    let mut successor = None;
+
+    // Serve control clients like `mesh top`, and the processes attaching to
+    // a daemon
+    let (control_sender, mut control_calls) = mpsc::channel(16);
+    if let Some(data_dir) = &data_dir {
+        let path = data_dir.join(control::FILE_NAME);
+        let attached = if daemon {
+            let (attached, broker) = daemon::Daemon::new(node.handle());
+            tokio::spawn(async move {
+                if let Err(err) = broker.await {
+                    error!("Daemon stopped: {:#}", err);
+                }
+            });
+            Some(attached)
+        } else {
+            None
+        };
+        let access = access.clone();
+        tokio::spawn(async move {
+            if let Err(err) = control::serve(path, control_sender, attached, access).await {
+                error!("Control socket unavailable: {:#}", err);
+            }
+        });
+    }
+
+    // Serve metrics to Prometheus, if requested
+    let (metrics_sender, mut metrics_scrapes) = mpsc::channel(16);
+    if let Some(address) = metrics {
+        match metrics::bind(address).await {
+            Ok(listener) => {
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(listener, metrics_sender).await {
+                        error!("Metrics endpoint unavailable: {:#}", err);
+                    }
+                });
+            }
+            Err(err) => node.degrade(degrade::Subsystem::Metrics, err)?,
+        }
+    }
+
+    // Answer liveness and readiness probes, if requested
+    if let Some(config) = probes {
+        let listener = probe::bind(&config).await?;
+        let probes = node.probes();
+        tokio::spawn(probe::watch(probes.clone(), config));
+        tokio::spawn(async move {
+            if let Err(err) = probe::serve(listener, probes, config).await {
+                error!("Probes unavailable: {:#}", err);
+            }
+        });
+    }
+
+    // Serve the control API, if requested
+    if let Some(mut config) = api {
+        if let Some(access) = &access {
+            config.tokens.extend(access.clone());
+        }
+        let listener = api::bind(&config).await?;
+        let api_handle = node.handle();
+        tokio::spawn(async move {
+            if let Err(err) = api::serve(listener, api_handle, config).await {
+                error!("Control API unavailable: {:#}", err);
+            }
+        });
+    }
+
+    // Leave a diagnostic bundle when crashing
+    let crash_snapshot = data_dir
+        .clone()
+        .map(|data_dir| crash::install(data_dir, config_hash));
+    let mut crash_tick = interval(crash::SNAPSHOT_INTERVAL);
+
+    // Push metrics, if requested
+    let mut statsd = match statsd {
+        Some(config) => match statsd::Exporter::new(config).await {
+            Ok(exporter) => Some(exporter),
+            Err(err) => {
+                node.degrade(degrade::Subsystem::Statsd, err)?;
+                None
+            }
+        },
+        None => None,
+    };
+    let mut statsd_tick = interval(
+        statsd
+            .as_ref()
+            .map_or(Duration::from_secs(3600), statsd::Exporter::interval),
+    );
+
     // Catch SIGTERM so the container can shutdown without an init process.
     let sigterm = tokio::signal::ctrl_c();
     tokio::pin!(sigterm);
 
+    // Let a battery monitor switch power-save mode
+    let mut power_save_on =
+        signal(SignalKind::user_defined1()).context("Listening for SIGUSR1")?;
+    let mut power_save_off =
+        signal(SignalKind::user_defined2()).context("Listening for SIGUSR2")?;
+
+    // Apply config changes on SIGHUP
+    let mut hangup = signal(SignalKind::hangup()).context("Listening for SIGHUP")?;
+
     // Fetch orders from node
     // 16Uiu2HAkzQUGvnR21snR3HSsfCgYFkUJn4LzSSSkNbBwefwfdtT8
     let fetch = async {
@@ -217,11 +6583,13 @@ pub async fn run() -> Result<()> {
     tokio::pin!(fetch);
 
     // Kick it off
+    info!("Started in {} ms", started.elapsed().as_millis());
     loop {
         tokio::select! {
-            _ = node.run() => {
+            _ = node.step() => if node.stopping {
+                break;
             },
-            result = &mut fetch  => match result {
+            result = &mut fetch, if full => match result {
                 Err(err) => error!("OrderSync fetch failed: {}", err),
                 Ok(orders) => {
                     info!("OrderSync fetch finished successfully with {} orders.", orders.len());
@@ -230,14 +6598,69 @@ pub async fn run() -> Result<()> {
                     serde_json::to_writer_pretty(file, &orders).unwrap();
                 }
             },
+            request = &mut handoff_request => match request {
+                Ok(stream) => {
+                    successor = Some(stream);
+                    break;
+                }
+                Err(err) => error!("Handoff unavailable: {:#}", err),
+            },
+            soak_result = &mut soak => {
+                result = soak_result.context("Soak test failed");
+                break;
+            }
+            console_result = &mut console => {
+                result = console_result.context("Console failed");
+                break;
+            }
+            Some((request, sender)) = control_calls.next() => node.control(request, sender),
+            Some(scrape) = metrics_scrapes.next() => {
+                let _ = scrape.sender.send(node.metrics_text(scrape.format));
+            }
+            _ = crash_tick.tick(), if crash_snapshot.is_some() => {
+                if let Some(snapshot) = &crash_snapshot {
+                    snapshot.update(node.status());
+                }
+            }
+            _ = statsd_tick.tick(), if statsd.is_some() => {
+                if let Some(exporter) = &mut statsd {
+                    if let Err(err) = exporter.push(node.statsd_samples()).await {
+                        warn!("Could not push metrics: {:#}", err);
+                    }
+                }
+            }
+            Some(()) = power_save_on.recv() => {
+                if let Err(err) = node.set_power_save(true).await {
+                    error!("Could not enter power-save mode: {:#}", err);
+                }
+            }
+            Some(()) = power_save_off.recv() => {
+                if let Err(err) = node.set_power_save(false).await {
+                    error!("Could not leave power-save mode: {:#}", err);
+                }
+            }
+            Some(()) = hangup.recv() => match &mut reload {
+                Some(source) => match source.load() {
+                    Ok(reloaded) => {
+                        node.reconfigure(&config.diff(&reloaded));
+                        config = reloaded;
+                    }
+                    Err(err) => error!("Could not reload the config: {:#}", err),
+                },
+                None => warn!("SIGHUP received without --config, nothing to reload"),
+            },
             _ = &mut sigterm => {
                 info!("SIGTERM received, shutting down");
-                // TODO: Shut down swarm?
                 break;
             }
         }
     }
 
+    if let Some(stream) = successor {
+        handoff::send(stream, node.handoff()).await?;
+    }
+    node.close(shutdown_timeout).await;
+
     // Log final stats
     info!("Network: {:?}", node.network_info());
     info!("Listened on: {:?}", node.listeners().collect::<Vec<_>>());
@@ -249,5 +6672,5 @@ pub async fn run() -> Result<()> {
     info!("Peers discovered: {:?}", known_peers.read().unwrap().len());
     // TODO: Store and load peer info
 
-    Ok(())
+    result
 }
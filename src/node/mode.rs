@@ -0,0 +1,89 @@
+//! What a node runs.
+//!
+//! `--mode bootstrap` runs an infrastructure node that only helps others
+//! join: it keeps its identity and listeners, answers identify and ping,
+//! serves the DHT, where peers find each other and the providers of
+//! services and rendezvous namespaces, and dials peers back for
+//! [`super::autonat`]. It runs no pubsub protocol and subscribes to no
+//! topics, not even the 0x orders topic, skips the order sync fetch, and
+//! refuses the options that need pubsub or stdin. Kademlia in libp2p 0.32
+//! always runs in server mode, and there is no circuit relay to offer, see
+//! the blocking issues in the Readme.
+//!
+//! `--mode daemon` runs a full node that the processes of the host share,
+//! attaching to it over the control socket, see [`super::daemon`].
+
+use super::RunOptions;
+use crate::prelude::*;
+use anyhow::bail;
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Mode {
+    #[default]
+    Full,
+    Bootstrap,
+    Daemon,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "full" => Self::Full,
+            "bootstrap" => Self::Bootstrap,
+            "daemon" => Self::Daemon,
+            _ => bail!("Unknown mode {}, expected full, bootstrap or daemon", s),
+        })
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Full => "full",
+            Self::Bootstrap => "bootstrap",
+            Self::Daemon => "daemon",
+        })
+    }
+}
+
+impl Mode {
+    /// The first option of `options` this mode can not run, by its long
+    /// name.
+    pub fn unsupported(self, options: &RunOptions) -> Option<&'static str> {
+        if self != Self::Bootstrap {
+            return None;
+        }
+        let used = [
+            ("interactive", options.interactive),
+            ("topic", !options.topics.is_empty()),
+            ("outbox", !options.outbox.is_empty()),
+            ("heartbeat", !options.heartbeats.is_empty()),
+            ("soak", options.soak.is_some()),
+            ("archive", options.archive.is_some()),
+            ("dtn", options.dtn.is_some()),
+            ("presence", options.presence.is_some()),
+        ];
+        used.iter().find(|(_, used)| *used).map(|(name, _)| *name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_bootstrap_refuses_pubsub_options() {
+        assert_eq!("bootstrap".parse::<Mode>().unwrap(), Mode::Bootstrap);
+        assert!("relay".parse::<Mode>().is_err());
+        let mut options = RunOptions::default();
+        assert_eq!(Mode::Bootstrap.unsupported(&options), None);
+        options.topics.push("chat".into());
+        assert_eq!(Mode::Full.unsupported(&options), None);
+        assert_eq!(Mode::Bootstrap.unsupported(&options), Some("topic"));
+    }
+}
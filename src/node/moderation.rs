@@ -0,0 +1,251 @@
+//! Muting senders and topic blocklists.
+//!
+//! Messages from a peer muted with [`super::NodeHandle::mute`] are dropped
+//! before they are delivered, on all topics.
+//!
+//! The moderator of a topic publishes a [`Blocklist`] of senders with
+//! [`super::NodeHandle::publish_blocklist`]. Nodes that accept the moderator
+//! with [`super::NodeHandle::trust_moderator`] drop messages on the topic from
+//! those senders. Blocklists are signed, replace earlier versions and are
+//! repeated every [`ANNOUNCE_INTERVAL`] for nodes that join later.
+//!
+//! Gossip still relays dropped messages, so blocking only works as far as the
+//! receiving nodes honor it.
+
+use super::{
+    keyring,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Interval at which a moderator repeats its blocklist.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Topic on which blocklists for `topic` are published.
+pub fn topic(topic: &str) -> String {
+    format!("/mesh-rs/blocklist/{}/version/1", topic)
+}
+
+/// Senders blocked on a topic, signed by its moderator.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Blocklist {
+    pub topic:     String,
+    /// Milliseconds since the Unix epoch, so later lists win.
+    pub version:   u64,
+    /// Base58 peer ids.
+    pub blocked:   Vec<String>,
+    pub signature: ByteBuf,
+}
+
+impl Blocklist {
+    fn signed_bytes(topic: &str, version: u64, blocked: &[String]) -> Vec<u8> {
+        let blocked = serde_cbor::to_vec(&blocked).expect("Blocklists always encode");
+        Layout::new(Domain::Blocklist)
+            .topic(topic)
+            .timestamp(version)
+            .header("blocked", blocked)
+            .to_bytes()
+    }
+
+    /// Whether the list is signed by `moderator`.
+    pub fn verify(&self, moderator: &PeerId) -> bool {
+        match keyring::public_key(moderator) {
+            Some(public) => {
+                public.verify(
+                    &Self::signed_bytes(&self.topic, self.version, &self.blocked),
+                    &self.signature,
+                )
+            }
+            None => false,
+        }
+    }
+}
+
+/// A topic's moderator and the latest blocklist we have from it.
+#[derive(Clone, Debug)]
+struct Moderated {
+    moderator: PeerId,
+    blocked:   HashSet<PeerId>,
+    version:   u64,
+}
+
+#[derive(Clone)]
+pub struct Moderation {
+    keypair:   identity::Keypair,
+    muted:     HashSet<PeerId>,
+    moderated: HashMap<String, Moderated>,
+    /// Blocklists we published and when to repeat them.
+    published: HashMap<String, (Blocklist, Instant)>,
+}
+
+impl Moderation {
+    pub fn new(keypair: identity::Keypair) -> Self {
+        Self {
+            keypair,
+            muted: HashSet::new(),
+            moderated: HashMap::new(),
+            published: HashMap::new(),
+        }
+    }
+
+    /// Mute or unmute `peer_id`. Returns false if it already was.
+    pub fn set_muted(&mut self, peer_id: PeerId, muted: bool) -> bool {
+        if muted {
+            self.muted.insert(peer_id)
+        } else {
+            self.muted.remove(&peer_id)
+        }
+    }
+
+    /// Drop messages on `topic` from senders blocked by `moderator`.
+    pub fn trust(&mut self, topic: &str, moderator: PeerId) {
+        if !matches!(self.moderated.get(topic), Some(moderated) if moderated.moderator == moderator)
+        {
+            self.moderated.insert(topic.into(), Moderated {
+                moderator,
+                blocked: HashSet::new(),
+                version: 0,
+            });
+        }
+    }
+
+    /// The topic whose blocklists are published on `blocklist_topic`, if
+    /// any.
+    pub fn moderated_topic(&self, blocklist_topic: &str) -> Option<String> {
+        self.moderated
+            .keys()
+            .find(|name| topic(name) == blocklist_topic)
+            .cloned()
+    }
+
+    /// Whether to drop a message on `topic` from `source`.
+    pub fn is_blocked(&self, topic: &str, source: &PeerId) -> bool {
+        self.muted.contains(source)
+            || matches!(
+                self.moderated.get(topic),
+                Some(moderated) if moderated.blocked.contains(source)
+            )
+    }
+
+    /// Sign a blocklist for `topic`, replacing the one we published before.
+    pub fn publish(&mut self, topic: &str, blocked: &[PeerId], now: Instant) -> Result<Blocklist> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut version = since_epoch.as_millis() as u64;
+        if let Some((previous, _)) = self.published.get(topic) {
+            version = version.max(previous.version + 1);
+        }
+        let blocked = blocked.iter().map(PeerId::to_base58).collect::<Vec<_>>();
+        let signature = self
+            .keypair
+            .sign(&Blocklist::signed_bytes(topic, version, &blocked))
+            .map_err(|err| anyhow!("Signing blocklist: {:?}", err))?;
+        let blocklist = Blocklist {
+            topic: topic.into(),
+            version,
+            blocked,
+            signature: ByteBuf::from(signature),
+        };
+        self.published.insert(
+            topic.into(),
+            (blocklist.clone(), now + ANNOUNCE_INTERVAL),
+        );
+        // Honor our own list
+        let local_peer_id = PeerId::from(self.keypair.public());
+        self.trust(topic, local_peer_id);
+        self.receive(topic, &blocklist)?;
+        Ok(blocklist)
+    }
+
+    /// Accept a blocklist for `topic`. Returns whether it replaced the list
+    /// we had.
+    pub fn receive(&mut self, topic: &str, blocklist: &Blocklist) -> Result<bool> {
+        let moderated = match self.moderated.get_mut(topic) {
+            Some(moderated) => moderated,
+            None => return Ok(false),
+        };
+        if blocklist.topic != topic || !blocklist.verify(&moderated.moderator) {
+            bail!(
+                "Blocklist for {} not signed by moderator {}",
+                topic,
+                moderated.moderator
+            );
+        }
+        if blocklist.version <= moderated.version {
+            return Ok(false);
+        }
+        moderated.version = blocklist.version;
+        moderated.blocked = blocklist
+            .blocked
+            .iter()
+            .filter_map(|peer_id| peer_id.parse().ok())
+            .collect();
+        Ok(true)
+    }
+
+    /// Our blocklists that are due to be repeated.
+    pub fn tick(&mut self, now: Instant) -> Vec<Blocklist> {
+        let mut due = Vec::new();
+        for (blocklist, next) in self.published.values_mut() {
+            if *next <= now {
+                *next = now + ANNOUNCE_INTERVAL;
+                due.push(blocklist.clone());
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_honors_signed_blocklists() {
+        let moderator_keys = identity::Keypair::generate_ed25519();
+        let moderator = PeerId::from(moderator_keys.public());
+        let mut moderator_side = Moderation::new(moderator_keys);
+        let mut node = Moderation::new(identity::Keypair::generate_ed25519());
+        let spammer = PeerId::random();
+        let chatter = PeerId::random();
+        let now = Instant::now();
+
+        assert!(node.set_muted(chatter.clone(), true));
+        assert!(node.is_blocked("news", &chatter));
+        assert!(node.set_muted(chatter.clone(), false));
+        assert!(!node.is_blocked("news", &chatter));
+
+        let blocklist = moderator_side
+            .publish("chat", &[spammer.clone()], now)
+            .unwrap();
+        assert!(moderator_side.is_blocked("chat", &spammer));
+        // Not accepted before trusting the moderator
+        assert!(!node.receive("chat", &blocklist).unwrap());
+        node.trust("chat", moderator);
+        assert!(node.receive("chat", &blocklist).unwrap());
+        assert!(!node.receive("chat", &blocklist).unwrap());
+        assert!(node.is_blocked("chat", &spammer));
+        assert!(!node.is_blocked("news", &spammer));
+        assert!(!node.is_blocked("chat", &chatter));
+
+        let mut forged = blocklist.clone();
+        forged.blocked.push(chatter.to_base58());
+        forged.version += 1;
+        assert!(node.receive("chat", &forged).is_err());
+
+        let cleared = moderator_side.publish("chat", &[], now).unwrap();
+        assert!(cleared.version > blocklist.version);
+        assert!(node.receive("chat", &cleared).unwrap());
+        assert!(!node.is_blocked("chat", &spammer));
+        assert_eq!(moderator_side.tick(now + ANNOUNCE_INTERVAL), vec![cleared]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Short, human-friendly names for peers.
+//!
+//! Base58 peer ids are 46 to 52 characters that all start the same, which
+//! makes logs hard to read and peers hard to tell apart. With
+//! `--peer-names words` every peer id in log lines, `mesh top` and recent
+//! events is shown as a name derived from its hash, like `brave-otter-7f3a`,
+//! and with `--peer-names short` as its last eight characters. Ids in
+//! addresses, after `/p2p/`, stay in full so addresses can still be copied.
+//!
+//! Names are only for display. [`resolve`] maps a name, or the end of an id,
+//! back to one of the peers we know; commands taking a peer id accept either.
+
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const ADJECTIVES: [&str; 64] = [
+    "able", "amber", "bold", "brave", "brisk", "calm", "clever", "cool", "crisp", "daring", "deep",
+    "eager", "early", "fair", "fancy", "fast", "fierce", "fond", "free", "fresh", "gentle", "glad",
+    "golden", "grand", "happy", "hardy", "honest", "humble", "jolly", "keen", "kind", "lively",
+    "loyal", "lucky", "merry", "mighty", "modest", "neat", "noble", "patient", "plain", "polite",
+    "proud", "quick", "quiet", "rapid", "ready", "rustic", "shy", "silent", "sleek", "smart",
+    "snowy", "steady", "sunny", "swift", "tidy", "tiny", "vivid", "warm", "wild", "wise", "witty",
+    "young",
+];
+
+const ANIMALS: [&str; 64] = [
+    "ant", "badger", "bat", "bear", "beaver", "bee", "bison", "boar", "camel", "cat", "cobra",
+    "crane", "crow", "deer", "dingo", "dove", "duck", "eagle", "eel", "elk", "falcon", "ferret",
+    "finch", "fox", "frog", "gecko", "goat", "goose", "hare", "hawk", "heron", "horse", "ibis",
+    "koala", "lark", "lemur", "lion", "llama", "lynx", "mole", "moose", "mouse", "newt", "otter",
+    "owl", "panda", "puffin", "quail", "rabbit", "raven", "seal", "shark", "sheep", "snail",
+    "swan", "tiger", "toad", "trout", "turtle", "viper", "walrus", "whale", "wolf", "yak",
+];
+
+/// Length of the [`Format::Short`] names.
+const SHORT_LEN: usize = 8;
+
+/// How peer ids are displayed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// The full base58 id.
+    Full,
+    /// The last characters of the id.
+    Short,
+    /// Words and digits derived from the hash of the id.
+    Words,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "full" => Self::Full,
+            "short" => Self::Short,
+            "words" => Self::Words,
+            _ => bail!("Unknown peer name format {}, expected full, short or words", s),
+        })
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(0);
+
+/// Display peer ids in `format` from now on.
+pub fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => Format::Short,
+        2 => Format::Words,
+        _ => Format::Full,
+    }
+}
+
+/// The word name of `peer_id`, like `brave-otter-7f3a`.
+pub fn words(peer_id: &PeerId) -> String {
+    let hash = Sha256::digest(peer_id.as_bytes());
+    format!(
+        "{}-{}-{}",
+        ADJECTIVES[usize::from(hash[0]) % ADJECTIVES.len()],
+        ANIMALS[usize::from(hash[1]) % ANIMALS.len()],
+        hex::encode(&hash[2..4])
+    )
+}
+
+fn short(base58: &str) -> &str {
+    &base58[base58.len().saturating_sub(SHORT_LEN)..]
+}
+
+/// Displays a peer id in the current [`format`].
+pub struct Name<'a>(&'a PeerId);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", display(self.0, format()))
+    }
+}
+
+fn display(peer_id: &PeerId, format: Format) -> String {
+    match format {
+        Format::Full => peer_id.to_base58(),
+        Format::Short => short(&peer_id.to_base58()).to_owned(),
+        Format::Words => words(peer_id),
+    }
+}
+
+pub const fn name(peer_id: &PeerId) -> Name<'_> {
+    Name(peer_id)
+}
+
+/// Replace the peer ids in `text` by their names, except those in addresses.
+pub fn rewrite(text: &str) -> Cow<'_, str> {
+    rewrite_as(text, format())
+}
+
+fn rewrite_as(text: &str, format: Format) -> Cow<'_, str> {
+    if format == Format::Full {
+        return Cow::Borrowed(text);
+    }
+    let is_base58 = |c: char| c.is_ascii_alphanumeric() && !"0OIl".contains(c);
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find(is_base58) {
+        let end = rest[start..].find(|c| !is_base58(c)).map_or(rest.len(), |end| start + end);
+        let offset = text.len() - rest.len();
+        let word = &rest[start..end];
+        if word.len() >= 40 && !text[..offset + start].ends_with("/p2p/") {
+            if let Ok(peer_id) = PeerId::from_str(word) {
+                out.push_str(&text[copied..offset + start]);
+                out.push_str(&display(&peer_id, format));
+                copied = offset + end;
+            }
+        }
+        rest = &rest[end..];
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(&text[copied..]);
+    Cow::Owned(out)
+}
+
+/// The peer among `known` that `text` names: a full peer id, a word name,
+/// or the end of an id.
+pub fn resolve<'a>(text: &str, known: impl IntoIterator<Item = &'a PeerId>) -> Result<PeerId> {
+    if let Ok(peer_id) = PeerId::from_str(text) {
+        return Ok(peer_id);
+    }
+    ensure!(!text.is_empty(), "Empty peer name");
+    let matches = known
+        .into_iter()
+        .filter(|peer_id| words(peer_id) == text || peer_id.to_base58().ends_with(text))
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [peer_id] => Ok((*peer_id).clone()),
+        [] => bail!("Unknown peer {}", text),
+        _ => bail!("Peer name {} matches {} peers", text, matches.len()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_names_and_resolves_peers() {
+        let peers = (0..3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        let id = peers[0].to_base58();
+        let name = words(&peers[0]);
+        assert_eq!(name, words(&peers[0]));
+        assert_eq!(name.split('-').count(), 3);
+
+        assert_eq!(resolve(&name, &peers).unwrap(), peers[0]);
+        assert_eq!(resolve(short(&id), &peers).unwrap(), peers[0]);
+        assert_eq!(resolve(&id, &[]).unwrap(), peers[0]);
+        assert!(resolve("brave-otter-0000", &[]).is_err());
+        assert!(resolve("", &peers).is_err());
+
+        let text = format!("Dialing {} at /ip4/127.0.0.1/tcp/4001/p2p/{}", id, id);
+        assert_eq!(rewrite_as(&text, Format::Full), text);
+        assert_eq!(
+            rewrite_as(&text, Format::Words),
+            format!("Dialing {} at /ip4/127.0.0.1/tcp/4001/p2p/{}", name, id)
+        );
+        assert_eq!(
+            rewrite_as(&format!("({})", id), Format::Short),
+            format!("({})", short(&id))
+        );
+        assert_eq!(rewrite_as("No peers here", Format::Words), "No peers here");
+    }
+}
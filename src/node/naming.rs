@@ -0,0 +1,261 @@
+//! Signed name records in the DHT.
+//!
+//! [`NodeHandle::publish_name`] stores a record under a human-readable name
+//! in the Kademlia DHT, mapping it to our peer id, the addresses we listen on
+//! and some metadata. The record is signed with our identity key and carries
+//! a sequence number, the time it was signed, so publishing again updates it
+//! and [`NodeHandle::resolve_name`] returns the latest one.
+//!
+//! Names are not exclusive: peers store the last record put under a name,
+//! whoever signed it. Resolving a name claimed by more than one peer fails
+//! rather than pick one, so a node can not silently take over another's
+//! name. Records live for 36 hours and Kademlia republishes them daily, with
+//! the addresses of the last publish. A node whose identity was rotated
+//! adds its [`Migration`] to its records, so they win over the older ones
+//! of its previous peer id, see [`super::rotation`].
+//!
+//! [`NodeHandle::publish_name`]: crate::node::NodeHandle::publish_name
+//! [`NodeHandle::resolve_name`]: crate::node::NodeHandle::resolve_name
+
+use super::{
+    rotation::Migration,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::{identity, Multiaddr, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Prefix of the DHT keys of name records.
+pub const KEY_PREFIX: &str = "/mesh-rs/name/";
+
+/// Longest name.
+pub const MAX_NAME: usize = 64;
+
+/// Where a name points.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NameRecord {
+    pub name:      String,
+    pub peer_id:   PeerId,
+    pub addresses: Vec<Multiaddr>,
+    pub metadata:  BTreeMap<String, String>,
+    /// Milliseconds since the Unix epoch when the record was signed.
+    pub sequence:  u64,
+    /// The peer id the signer rotated from.
+    pub previous:  Option<PeerId>,
+}
+
+/// A [`NameRecord`] as stored in the DHT.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Signed {
+    name:       String,
+    addresses:  Vec<String>,
+    metadata:   BTreeMap<String, String>,
+    sequence:   u64,
+    /// Protobuf encoding of the signer's public key.
+    public_key: ByteBuf,
+    signature:  ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    migration:  Option<Migration>,
+}
+
+impl Signed {
+    fn signed_bytes(
+        name: &str,
+        addresses: &[String],
+        metadata: &BTreeMap<String, String>,
+        sequence: u64,
+    ) -> Vec<u8> {
+        let addresses = serde_cbor::to_vec(&addresses).expect("Addresses always encode");
+        let metadata = serde_cbor::to_vec(&metadata).expect("Metadata always encodes");
+        Layout::new(Domain::Name)
+            .timestamp(sequence)
+            .header("name", name.as_bytes())
+            .header("addresses", addresses)
+            .header("metadata", metadata)
+            .to_bytes()
+    }
+}
+
+/// The DHT key of `name`.
+pub fn key(name: &str) -> Vec<u8> {
+    format!("{}{}", KEY_PREFIX, name).into_bytes()
+}
+
+/// Check that `name` is lowercase letters, digits, `-` and `.`, at most
+/// [`MAX_NAME`] long.
+pub fn validate(name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "Empty name");
+    ensure!(name.len() <= MAX_NAME, "Name {} is longer than {}", name, MAX_NAME);
+    if let Some(c) = name
+        .chars()
+        .find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '-' | '.'))
+    {
+        bail!("Invalid character {:?} in name {}", c, name);
+    }
+    Ok(())
+}
+
+/// Sign a record of `name` for the holder of `keypair`, who rotated from
+/// another identity if there is a `migration`.
+pub fn sign(
+    keypair: &identity::Keypair,
+    name: &str,
+    addresses: &[Multiaddr],
+    metadata: BTreeMap<String, String>,
+    migration: Option<&Migration>,
+    now: SystemTime,
+) -> Result<Vec<u8>> {
+    validate(name)?;
+    let addresses: Vec<String> = addresses.iter().map(ToString::to_string).collect();
+    let sequence = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64);
+    let signature = keypair
+        .sign(&Signed::signed_bytes(name, &addresses, &metadata, sequence))
+        .map_err(|err| anyhow!("Signing name record: {:?}", err))?;
+    let signed = Signed {
+        name: name.to_owned(),
+        addresses,
+        metadata,
+        sequence,
+        public_key: ByteBuf::from(keypair.public().into_protobuf_encoding()),
+        signature: ByteBuf::from(signature),
+        migration: migration.cloned(),
+    };
+    Ok(serde_cbor::to_vec(&signed)?)
+}
+
+/// Decode a record found under `name` and check its signature.
+pub fn verify(name: &str, value: &[u8]) -> Result<NameRecord> {
+    let signed: Signed = serde_cbor::from_slice(value).context("Decoding name record")?;
+    ensure!(signed.name == name, "Record of {} stored under {}", signed.name, name);
+    let public = identity::PublicKey::from_protobuf_encoding(&signed.public_key)
+        .map_err(|err| anyhow!("Invalid public key: {:?}", err))?;
+    let bytes = Signed::signed_bytes(
+        &signed.name,
+        &signed.addresses,
+        &signed.metadata,
+        signed.sequence,
+    );
+    ensure!(public.verify(&bytes, &signed.signature), "Invalid signature");
+    let peer_id = PeerId::from(public);
+    let previous = match &signed.migration {
+        Some(migration) => {
+            let (previous, current) = migration.verify().context("Invalid migration")?;
+            ensure!(current == peer_id, "Migration to {} in record of {}", current, peer_id);
+            Some(previous)
+        }
+        None => None,
+    };
+    let addresses = signed
+        .addresses
+        .iter()
+        .map(|address| address.parse())
+        .collect::<std::result::Result<_, _>>()
+        .context("Invalid address in name record")?;
+    Ok(NameRecord {
+        name: signed.name,
+        peer_id,
+        addresses,
+        metadata: signed.metadata,
+        sequence: signed.sequence,
+        previous,
+    })
+}
+
+/// The latest valid record among the `values` found under `name`.
+pub fn latest(name: &str, values: &[Vec<u8>]) -> Result<NameRecord> {
+    let mut records = Vec::new();
+    for value in values {
+        match verify(name, value) {
+            Ok(record) => records.push(record),
+            Err(err) => debug!("Ignoring record of {}: {:#}", name, err),
+        }
+    }
+    let latest = records
+        .iter()
+        .max_by_key(|record| record.sequence)
+        .ok_or_else(|| anyhow!("No valid record of {} found", name))?;
+    // Older records of the identity the latest one rotated from do not count
+    if let Some(other) = records.iter().find(|record| {
+        record.peer_id != latest.peer_id
+            && !(latest.previous.as_ref() == Some(&record.peer_id)
+                && record.sequence < latest.sequence)
+    }) {
+        bail!(
+            "Name {} is claimed by both {} and {}",
+            name,
+            latest.peer_id,
+            other.peer_id
+        );
+    }
+    Ok(latest.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_resolves_latest_signed_record() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let mut metadata = BTreeMap::new();
+        metadata.insert("role".to_owned(), "gateway".to_owned());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let old = sign(&keypair, "gateway.lab", &[], BTreeMap::new(), None, at(1)).unwrap();
+        let new = sign(
+            &keypair,
+            "gateway.lab",
+            &[address.clone()],
+            metadata.clone(),
+            None,
+            at(2),
+        )
+        .unwrap();
+
+        let record = latest("gateway.lab", &[old.clone(), new.clone()]).unwrap();
+        assert_eq!(record, NameRecord {
+            name: "gateway.lab".into(),
+            peer_id: PeerId::from(keypair.public()),
+            addresses: vec![address],
+            metadata,
+            sequence: 2000,
+            previous: None,
+        });
+
+        // Not under another name, nor tampered with
+        assert!(verify("other", &new).is_err());
+        let mut signed: Signed = serde_cbor::from_slice(&new).unwrap();
+        signed.sequence += 1;
+        let tampered = serde_cbor::to_vec(&signed).unwrap();
+        assert!(verify("gateway.lab", &tampered).is_err());
+        assert_eq!(latest("gateway.lab", &[tampered, old]).unwrap().sequence, 1000);
+
+        // Claimed by someone else too
+        let other = identity::Keypair::generate_ed25519();
+        let claim = sign(&other, "gateway.lab", &[], BTreeMap::new(), None, at(3)).unwrap();
+        assert!(latest("gateway.lab", &[new.clone(), claim]).is_err());
+
+        // Unless it rotated from the older claimant
+        let peer_id = PeerId::from(other.public());
+        let migration = Migration::sign(&keypair, &peer_id, at(3600)).unwrap();
+        let rotated =
+            sign(&other, "gateway.lab", &[], BTreeMap::new(), Some(&migration), at(3)).unwrap();
+        let record = latest("gateway.lab", &[new, rotated]).unwrap();
+        assert_eq!(record.peer_id, peer_id);
+        assert_eq!(record.previous, Some(PeerId::from(keypair.public())));
+        let foreign = sign(&keypair, "gateway.lab", &[], BTreeMap::new(), Some(&migration), at(4));
+        assert!(verify("gateway.lab", &foreign.unwrap()).is_err());
+
+        assert!(validate("Gateway").is_err());
+        assert!(validate(&"a".repeat(MAX_NAME + 1)).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Why connection upgrades fail.
+//!
+//! A connection is upgraded in stages: the security protocol is negotiated
+//! and its handshake run, then the stream multiplexer is negotiated, and
+//! later each behaviour negotiates its own protocol on every substream. A
+//! failure at any of these stages used to show up as an opaque I/O error, if
+//! at all. The transport now tags its errors with a [`Reason`], which the
+//! node logs, counts in [`Failures`], pushes to statsd as
+//! `negotiation.<reason>` and emits as [`Event::NegotiationFailed`].
+//!
+//! [`Event::NegotiationFailed`]: crate::node::Event::NegotiationFailed
+
+use crate::prelude::*;
+use libp2p::core::{
+    connection::PendingConnectionError,
+    either::EitherError,
+    transport::{timeout::TransportTimeoutError, TransportError},
+    upgrade::{NegotiationError, UpgradeError},
+};
+use std::{collections::BTreeMap, error::Error as StdError, fmt, io};
+
+/// The stage at which a connection or substream upgrade failed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Reason {
+    /// The connection could not be established.
+    Transport,
+    /// The upgrade did not finish in time.
+    Timeout,
+    /// The peer supports none of our security protocols.
+    SecurityMismatch,
+    /// The security handshake failed.
+    Security,
+    /// The peer supports none of our stream multiplexers.
+    MuxerMismatch,
+    /// The multiplexer could not be set up.
+    Muxer,
+    /// The peer does not speak a protocol we opened a substream for.
+    UnsupportedProtocol,
+    Other,
+}
+
+impl Reason {
+    pub const ALL: [Self; 8] = [
+        Self::Transport,
+        Self::Timeout,
+        Self::SecurityMismatch,
+        Self::Security,
+        Self::MuxerMismatch,
+        Self::Muxer,
+        Self::UnsupportedProtocol,
+        Self::Other,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Transport => "transport",
+            Self::Timeout => "timeout",
+            Self::SecurityMismatch => "security_mismatch",
+            Self::Security => "security",
+            Self::MuxerMismatch => "muxer_mismatch",
+            Self::Muxer => "muxer",
+            Self::UnsupportedProtocol => "unsupported_protocol",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A transport error tagged with its [`Reason`].
+#[derive(Debug, Error)]
+#[error("{reason}: {error}")]
+pub struct Failed {
+    pub reason: Reason,
+    #[source]
+    error:      Box<dyn StdError + Send + Sync>,
+}
+
+fn stage<E>(error: &UpgradeError<E>, mismatch: Reason, failed: Reason) -> Reason {
+    match error {
+        UpgradeError::Select(NegotiationError::Failed) => mismatch,
+        _ => failed,
+    }
+}
+
+/// Tag an error of the upgraded transport, see
+/// [`crate::node::transport::make_transport`]. Errors of the transport itself
+/// are kept as they are, so [`crate::node::dial`] can tell why a dial failed.
+pub fn classify<A, M>(
+    error: TransportTimeoutError<
+        EitherError<EitherError<io::Error, UpgradeError<A>>, UpgradeError<M>>,
+    >,
+) -> Failed
+where
+    A: StdError + Send + Sync + 'static,
+    M: StdError + Send + Sync + 'static,
+{
+    use EitherError::{A as Transport, B as Upgrade};
+    let reason = match &error {
+        TransportTimeoutError::Timeout => Reason::Timeout,
+        TransportTimeoutError::TimerError(_) => Reason::Other,
+        TransportTimeoutError::Other(Upgrade(error)) => {
+            stage(error, Reason::MuxerMismatch, Reason::Muxer)
+        }
+        TransportTimeoutError::Other(Transport(Upgrade(error))) => {
+            stage(error, Reason::SecurityMismatch, Reason::Security)
+        }
+        TransportTimeoutError::Other(Transport(Transport(_))) => Reason::Transport,
+    };
+    let error: Box<dyn StdError + Send + Sync> = match error {
+        TransportTimeoutError::Other(Transport(Transport(error))) => Box::new(error),
+        error => Box::new(error),
+    };
+    Failed { reason, error }
+}
+
+/// The reason of a connection error, if it is an upgrade failure.
+pub fn reason(error: &(dyn StdError + 'static)) -> Option<Reason> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(failed) = error.downcast_ref::<Failed>() {
+            return Some(failed.reason);
+        }
+        if let Some(NegotiationError::Failed) = error.downcast_ref::<NegotiationError>() {
+            return Some(Reason::UnsupportedProtocol);
+        }
+        // The boxed transport hides our error in an I/O error
+        source = match error.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+            Some(inner) => Some(inner),
+            None => error.source(),
+        };
+    }
+    None
+}
+
+/// The reason a connection could not be established, if it failed to
+/// upgrade.
+pub fn pending(error: &PendingConnectionError<io::Error>) -> Option<Reason> {
+    match error {
+        PendingConnectionError::InvalidPeerId => Some(Reason::Security),
+        PendingConnectionError::ConnectionLimit(_)
+        | PendingConnectionError::Transport(TransportError::MultiaddrNotSupported(_)) => None,
+        error => reason(error),
+    }
+}
+
+/// Number of failures by reason.
+#[derive(Clone, Default, Debug)]
+pub struct Failures(BTreeMap<Reason, u64>);
+
+impl Failures {
+    pub fn record(&mut self, reason: Reason) {
+        *self.0.entry(reason).or_default() += 1;
+    }
+
+    pub fn count(&self, reason: Reason) -> u64 {
+        self.0.get(&reason).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    type Upgraded = TransportTimeoutError<
+        EitherError<EitherError<io::Error, UpgradeError<io::Error>>, UpgradeError<io::Error>>,
+    >;
+
+    fn boxed(error: Upgraded) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, classify(error))
+    }
+
+    #[test]
+    fn test_classifies_upgrade_failures() {
+        use EitherError::{A as Transport, B as Upgrade};
+        let io = || io::Error::new(io::ErrorKind::Other, "broken");
+        let cases: Vec<(Upgraded, Reason)> = vec![
+            (TransportTimeoutError::Timeout, Reason::Timeout),
+            (
+                TransportTimeoutError::Other(Transport(Transport(io()))),
+                Reason::Transport,
+            ),
+            (
+                TransportTimeoutError::Other(Transport(Upgrade(UpgradeError::Select(
+                    NegotiationError::Failed,
+                )))),
+                Reason::SecurityMismatch,
+            ),
+            (
+                TransportTimeoutError::Other(Transport(Upgrade(UpgradeError::Apply(io())))),
+                Reason::Security,
+            ),
+            (
+                TransportTimeoutError::Other(Upgrade(UpgradeError::Select(
+                    NegotiationError::Failed,
+                ))),
+                Reason::MuxerMismatch,
+            ),
+            (
+                TransportTimeoutError::Other(Upgrade(UpgradeError::Apply(io()))),
+                Reason::Muxer,
+            ),
+        ];
+        let mut failures = Failures::default();
+        for (error, expected) in cases {
+            let error = PendingConnectionError::Transport(TransportError::Other(boxed(error)));
+            assert_eq!(pending(&error), Some(expected));
+            failures.record(expected);
+        }
+        assert_eq!(pending(&PendingConnectionError::InvalidPeerId), Some(Reason::Security));
+        assert_eq!(pending(&PendingConnectionError::IO(io())), None);
+        assert_eq!(reason(&NegotiationError::Failed), Some(Reason::UnsupportedProtocol));
+        assert_eq!(reason(&io()), None);
+        assert_eq!(failures.count(Reason::Muxer), 1);
+        assert_eq!(failures.count(Reason::Other), 0);
+    }
+}
@@ -0,0 +1,415 @@
+//! Signed nickname profiles of peers.
+//!
+//! With `--nickname "name=alice avatar=<sha256 hex> meta=team:ops"` the node
+//! publishes its [`Profile`] on [`TOPIC`] once it has peers and again every
+//! `interval`, default ten minutes: a nickname, the hash of an avatar image
+//! to fetch out of band, and `meta` fields, signed with its identity key.
+//! Every node keeps the profiles of the peers it heard from in a
+//! [`Registry`], after dropping those
+//!
+//! * not signed by the peer that published them,
+//! * not newer than the last profile of the peer, or
+//! * with a nickname, avatar or fields over the limits.
+//!
+//! Nicknames are not exclusive, like [`super::naming`] records: a nickname
+//! claimed by more than one peer does not resolve. Resolved nicknames are
+//! accepted wherever the node maps names to peers, see
+//! [`NodeHandle::resolve_peer`], so the interactive console shows message
+//! sources by nickname and takes `/msg alice hello`. The registry keeps
+//! [`MAX_PEERS`] profiles and forgets the oldest beyond that.
+//!
+//! [`NodeHandle::resolve_peer`]: crate::node::NodeHandle::resolve_peer
+
+use super::{
+    keyring, options,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Topic on which profiles are published.
+pub const TOPIC: &str = "/mesh-rs/nickname/version/1";
+
+/// Longest nickname.
+pub const MAX_NAME: usize = 32;
+
+/// Most `meta` fields in a profile.
+pub const MAX_FIELDS: usize = 16;
+
+/// Longest `meta` key or value.
+pub const MAX_FIELD: usize = 256;
+
+/// Most profiles kept in the registry.
+pub const MAX_PEERS: usize = 4096;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    pub nickname: String,
+    /// Hex SHA-256 of the avatar image.
+    pub avatar:   Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    /// Time between publishing the profile.
+    pub interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nickname: String::new(),
+            avatar:   None,
+            metadata: BTreeMap::new(),
+            interval: Duration::from_secs(600),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    /// `meta` takes `<key>:<value>` and may be repeated.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "name" => config.nickname = value.to_owned(),
+                "avatar" => config.avatar = Some(value.to_ascii_lowercase()),
+                "meta" => {
+                    let (field, content) = match value.find(':') {
+                        Some(index) => (&value[..index], &value[index + 1..]),
+                        None => bail!("Expected meta=<key>:<value>, got {}", value),
+                    };
+                    config.metadata.insert(field.to_owned(), content.to_owned());
+                }
+                "interval" => {
+                    config.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid interval {}", value))?;
+                }
+                _ => bail!("Unknown nickname option {}", key),
+            }
+        }
+        ensure!(
+            config.interval >= Duration::from_secs(1),
+            "Nickname interval must be at least a second"
+        );
+        validate(&config.nickname, config.avatar.as_deref(), &config.metadata)?;
+        Ok(config)
+    }
+}
+
+/// Check the fields of a profile against the limits.
+fn validate(
+    nickname: &str,
+    avatar: Option<&str>,
+    metadata: &BTreeMap<String, String>,
+) -> Result<()> {
+    ensure!(!nickname.is_empty(), "Empty nickname");
+    ensure!(nickname.len() <= MAX_NAME, "Nickname over {} bytes", MAX_NAME);
+    ensure!(
+        !nickname.contains(|c: char| c.is_whitespace() || c == '/'),
+        "Nickname {} contains a space or a slash",
+        nickname
+    );
+    if let Some(avatar) = avatar {
+        ensure!(
+            avatar.len() == 64 && avatar.chars().all(|c| c.is_ascii_hexdigit()),
+            "Avatar {} is not a hex SHA-256",
+            avatar
+        );
+    }
+    ensure!(metadata.len() <= MAX_FIELDS, "Over {} meta fields", MAX_FIELDS);
+    ensure!(
+        metadata
+            .iter()
+            .all(|(key, value)| key.len() <= MAX_FIELD && value.len() <= MAX_FIELD),
+        "Meta field over {} bytes",
+        MAX_FIELD
+    );
+    Ok(())
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// What a peer says about itself, signed with its identity key.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    pub nickname:  String,
+    pub avatar:    Option<String>,
+    pub metadata:  BTreeMap<String, String>,
+    /// Milliseconds since the Unix epoch when it was signed, later profiles
+    /// of a peer replace earlier ones.
+    pub at_ms:     u64,
+    pub signature: ByteBuf,
+}
+
+impl Profile {
+    fn signed_bytes(
+        nickname: &str,
+        avatar: Option<&str>,
+        metadata: &BTreeMap<String, String>,
+        at_ms: u64,
+    ) -> Vec<u8> {
+        let fields = serde_cbor::to_vec(metadata).expect("Meta fields always encode");
+        Layout::new(Domain::Profile)
+            .timestamp(at_ms)
+            .header("nickname", nickname.as_bytes())
+            .header("avatar", avatar.unwrap_or_default().as_bytes())
+            .payload(&fields)
+            .to_bytes()
+    }
+
+    /// Sign the profile of `config` with `keypair`.
+    pub fn sign(keypair: &identity::Keypair, config: &Config, at: SystemTime) -> Result<Self> {
+        let at_ms = epoch_ms(at);
+        let avatar = config.avatar.as_deref();
+        let signature = keypair
+            .sign(&Self::signed_bytes(&config.nickname, avatar, &config.metadata, at_ms))
+            .map_err(|err| anyhow!("Signing profile: {:?}", err))?;
+        Ok(Self {
+            nickname: config.nickname.clone(),
+            avatar: config.avatar.clone(),
+            metadata: config.metadata.clone(),
+            at_ms,
+            signature: ByteBuf::from(signature),
+        })
+    }
+
+    /// Whether the profile is signed by `peer_id`.
+    pub fn verify(&self, peer_id: &PeerId) -> bool {
+        let signed = Self::signed_bytes(
+            &self.nickname,
+            self.avatar.as_deref(),
+            &self.metadata,
+            self.at_ms,
+        );
+        match keyring::public_key(peer_id) {
+            Some(public) => public.verify(&signed, &self.signature),
+            None => false,
+        }
+    }
+}
+
+/// The verified profiles of peers.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    profiles: HashMap<PeerId, Profile>,
+}
+
+impl Registry {
+    /// Keep `profile` published by `source`. Returns false if it was not
+    /// newer than the one we have.
+    pub fn receive(&mut self, source: &PeerId, profile: Profile) -> Result<bool> {
+        ensure!(profile.verify(source), "Profile not signed by {}", source);
+        validate(&profile.nickname, profile.avatar.as_deref(), &profile.metadata)?;
+        if let Some(known) = self.profiles.get(source) {
+            if known.at_ms >= profile.at_ms {
+                return Ok(false);
+            }
+        } else if self.profiles.len() >= MAX_PEERS {
+            let oldest = self
+                .profiles
+                .iter()
+                .min_by_key(|(_, profile)| profile.at_ms)
+                .map(|(peer_id, _)| peer_id.clone());
+            if let Some(oldest) = oldest {
+                self.profiles.remove(&oldest);
+            }
+        }
+        self.profiles.insert(source.clone(), profile);
+        Ok(true)
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&Profile> {
+        self.profiles.get(peer_id)
+    }
+
+    /// The peer whose nickname is `nickname`, if any. Fails for a nickname
+    /// claimed by more than one peer.
+    pub fn resolve(&self, nickname: &str) -> Result<Option<PeerId>> {
+        let matches = self
+            .profiles
+            .iter()
+            .filter(|(_, profile)| profile.nickname == nickname)
+            .map(|(peer_id, _)| peer_id)
+            .collect::<Vec<_>>();
+        match matches.as_slice() {
+            [] => Ok(None),
+            [peer_id] => Ok(Some((*peer_id).clone())),
+            _ => bail!("Nickname {} is claimed by {} peers", nickname, matches.len()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+}
+
+/// Our profile and the registry of peers.
+pub struct Nicknames {
+    keypair:  identity::Keypair,
+    local:    PeerId,
+    config:   Option<Config>,
+    /// When our profile is due next.
+    next:     Option<Instant>,
+    registry: Registry,
+}
+
+impl Nicknames {
+    pub fn new(keypair: identity::Keypair) -> Self {
+        let local = PeerId::from(keypair.public());
+        Self {
+            keypair,
+            local,
+            config: None,
+            next: None,
+            registry: Registry::default(),
+        }
+    }
+
+    /// Publish the profile of `config`.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = Some(config);
+        self.next = None;
+    }
+
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_ref()
+    }
+
+    /// Our profile signed now, if due at `now`.
+    pub fn due(&self, now: Instant) -> Option<Result<Profile>> {
+        let config = self.config.as_ref()?;
+        if matches!(self.next, Some(next) if now < next) {
+            return None;
+        }
+        Some(Profile::sign(&self.keypair, config, SystemTime::now()))
+    }
+
+    /// Our profile went out at `now`.
+    pub fn published(&mut self, now: Instant) {
+        if let Some(config) = &self.config {
+            self.next = Some(now + config.interval);
+        }
+    }
+
+    /// Accept a profile published by `source`.
+    pub fn receive(&mut self, source: &PeerId, profile: Profile) -> Result<bool> {
+        self.registry.receive(source, profile)
+    }
+
+    /// The nickname of `peer_id`, ours included.
+    pub fn nickname(&self, peer_id: &PeerId) -> Option<&str> {
+        if *peer_id == self.local {
+            return self.config.as_ref().map(|config| config.nickname.as_str());
+        }
+        self.registry.get(peer_id).map(|profile| profile.nickname.as_str())
+    }
+
+    pub fn profile(&self, peer_id: &PeerId) -> Option<&Profile> {
+        self.registry.get(peer_id)
+    }
+
+    /// The peer named `nickname`, ours included, see [`Registry::resolve`].
+    pub fn resolve(&self, nickname: &str) -> Result<Option<PeerId>> {
+        let peer = self.registry.resolve(nickname)?;
+        let local = self
+            .config
+            .as_ref()
+            .filter(|config| config.nickname == nickname);
+        match (peer, local) {
+            (Some(_), Some(_)) => bail!("Nickname {} is claimed by 2 peers", nickname),
+            (Some(peer_id), None) => Ok(Some(peer_id)),
+            (None, Some(_)) => Ok(Some(self.local.clone())),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let avatar = "ab".repeat(32);
+        let config: Config = format!("name=alice avatar={} meta=team:ops meta=tz:CET", avatar)
+            .parse()
+            .unwrap();
+        assert_eq!(config.nickname, "alice");
+        assert_eq!(config.avatar, Some(avatar));
+        assert_eq!(config.metadata.get("team").map(String::as_str), Some("ops"));
+        assert_eq!(config.metadata.len(), 2);
+        assert_eq!(config.interval, Duration::from_secs(600));
+        assert!("avatar=cafe".parse::<Config>().is_err());
+        assert!("name=a/b".parse::<Config>().is_err());
+        assert!("name=alice meta=team".parse::<Config>().is_err());
+        assert!("name=alice colour=red".parse::<Config>().is_err());
+        assert!("".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_keeps_verified_profiles() {
+        let (alice_key, bob_key) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let (alice, bob) = (PeerId::from(alice_key.public()), PeerId::from(bob_key.public()));
+        let config: Config = "name=alice".parse().unwrap();
+        let now = SystemTime::now();
+        let profile = Profile::sign(&alice_key, &config, now).unwrap();
+
+        let mut registry = Registry::default();
+        assert!(registry.receive(&bob, profile.clone()).is_err());
+        let mut forged = profile.clone();
+        forged.nickname = "mallory".into();
+        assert!(registry.receive(&alice, forged).is_err());
+        assert!(registry.receive(&alice, profile.clone()).unwrap());
+        assert!(!registry.receive(&alice, profile).unwrap());
+        assert_eq!(registry.resolve("alice").unwrap(), Some(alice.clone()));
+        assert_eq!(registry.resolve("bob").unwrap(), None);
+
+        let renamed: Config = "name=al".parse().unwrap();
+        let later = now + Duration::from_secs(1);
+        let profile = Profile::sign(&alice_key, &renamed, later).unwrap();
+        assert!(registry.receive(&alice, profile).unwrap());
+        assert_eq!(registry.get(&alice).unwrap().nickname, "al");
+        assert_eq!(registry.resolve("alice").unwrap(), None);
+
+        // Nicknames claimed twice do not resolve
+        let impostor = Profile::sign(&bob_key, &renamed, now).unwrap();
+        assert!(registry.receive(&bob, impostor).unwrap());
+        assert!(registry.resolve("al").is_err());
+    }
+
+    #[test]
+    fn test_publishes_own_profile() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let local = PeerId::from(keypair.public());
+        let mut nicknames = Nicknames::new(keypair);
+        let now = Instant::now();
+        assert!(nicknames.due(now).is_none());
+        nicknames.set_config("name=carol interval=1m".parse().unwrap());
+        let profile = nicknames.due(now).unwrap().unwrap();
+        assert!(profile.verify(&local));
+        nicknames.published(now);
+        assert!(nicknames.due(now + Duration::from_secs(30)).is_none());
+        assert!(nicknames.due(now + Duration::from_secs(60)).is_some());
+        assert_eq!(nicknames.nickname(&local), Some("carol"));
+        assert_eq!(nicknames.resolve("carol").unwrap(), Some(local));
+    }
+}
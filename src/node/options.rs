@@ -0,0 +1,35 @@
+//! Parsing of option strings like `--soak "rate=100 size=1KiB"`.
+
+use crate::prelude::*;
+use anyhow::anyhow;
+
+/// The `key=value` pairs of `s`, separated by spaces or commas.
+pub fn pairs(s: &str) -> impl Iterator<Item = Result<(&str, &str)>> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|pair| !pair.is_empty())
+        .map(pair)
+}
+
+/// Split `pair` at its first `=`.
+pub fn pair(pair: &str) -> Result<(&str, &str)> {
+    match pair.find('=') {
+        Some(index) => Ok((&pair[..index], &pair[index + 1..])),
+        None => Err(anyhow!("Expected key=value, got {}", pair)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_splits_pairs() {
+        let parsed: Vec<_> = pairs(" rate=100,size=1KiB  dir=a=b ")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(parsed, vec![("rate", "100"), ("size", "1KiB"), ("dir", "a=b")]);
+        assert_eq!(pairs("").count(), 0);
+        assert!(pairs("rate=1 size").nth(1).unwrap().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Backpressure for outbound publishes.
+//!
+//! [`super::NodeHandle::publish`] does not hand its message to pubsub while
+//! handling the command. Messages wait in a queue of `--publish-queue`
+//! messages (1024 by default) that the node drains between swarm events,
+//! [`BATCH`] at a time, so a burst of publishes can not keep the swarm from
+//! sending them. A publish returns once its message was handed to the
+//! transport, or with the error pubsub gave. When the queue is full it fails
+//! at once with [`Full`], for the application to slow down or drop, rather
+//! than growing without bound.
+//!
+//! The StatsD gauge `publish.queued` and the Prometheus gauge
+//! `mesh_publish_queue_depth` show the depth of the queue, the counters
+//! `publish.rejected` and `mesh_publish_rejected_total` the publishes
+//! refused as full.
+
+use crate::prelude::*;
+use futures::channel::oneshot;
+use std::{collections::VecDeque, time::Instant};
+
+/// Messages waiting at most by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Messages handed to pubsub between two swarm events at most.
+pub const BATCH: usize = 64;
+
+/// Why a publish was refused.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Outbound queue full with {0} messages")]
+pub struct Full(pub usize);
+
+/// A message waiting to be published, and its caller.
+#[derive(Debug)]
+pub struct Pending {
+    pub topic:  String,
+    pub data:   Vec<u8>,
+    /// Whether the message went to pubsub right away, see
+    /// [`super::Node::publish_now`].
+    pub sender: oneshot::Sender<Result<bool>>,
+    pub queued: Instant,
+}
+
+/// Counts of the queue since the node started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    pub published: u64,
+    pub rejected:  u64,
+    /// Deepest the queue was.
+    pub max_depth: usize,
+}
+
+/// The bounded queue of outbound publishes.
+#[derive(Debug)]
+pub struct Queue {
+    capacity: usize,
+    pending:  VecDeque<Pending>,
+    stats:    Stats,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Queue {
+    /// Keep at most `capacity` messages, at least one.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pending:  VecDeque::new(),
+            stats:    Stats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub const fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Queue `data` on `topic`, or answer `sender` with [`Full`] if
+    /// `capacity` messages wait already.
+    pub fn push(
+        &mut self,
+        topic: String,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<bool>>,
+        now: Instant,
+    ) {
+        if self.pending.len() >= self.capacity {
+            self.stats.rejected += 1;
+            let _ = sender.send(Err(Full(self.capacity).into()));
+            return;
+        }
+        self.pending.push_back(Pending {
+            topic,
+            data,
+            sender,
+            queued: now,
+        });
+        self.stats.max_depth = self.stats.max_depth.max(self.pending.len());
+    }
+
+    /// The next [`BATCH`] messages to publish, oldest first. Messages whose
+    /// caller gave up waiting are dropped.
+    pub fn batch(&mut self) -> Vec<Pending> {
+        let mut batch = Vec::new();
+        while batch.len() < BATCH {
+            match self.pending.pop_front() {
+                Some(pending) if pending.sender.is_canceled() => {
+                    trace!("Dropping publish on {}, caller gone", pending.topic);
+                }
+                Some(pending) => batch.push(pending),
+                None => break,
+            }
+        }
+        self.stats.published += batch.len() as u64;
+        batch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_refuses_publishes_when_full() {
+        let mut queue = Queue::new(BATCH + 1);
+        let now = Instant::now();
+        let mut receivers = Vec::new();
+        for i in 0..=BATCH + 1 {
+            let (sender, receiver) = oneshot::channel();
+            queue.push("chat".into(), vec![i as u8], sender, now);
+            receivers.push(receiver);
+        }
+        assert_eq!(queue.len(), BATCH + 1);
+        let mut last = receivers.pop().unwrap();
+        let err = last.try_recv().unwrap().unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref::<Full>(), Some(&Full(BATCH + 1)));
+
+        // Oldest first, skipping callers that gave up
+        drop(receivers.remove(1));
+        let batch = queue.batch();
+        assert_eq!(batch.len(), BATCH);
+        assert_eq!((batch[0].data[0], batch[1].data[0]), (0, 2));
+        assert_eq!(queue.batch().len(), 0);
+        assert!(queue.is_empty());
+        assert_eq!(queue.stats(), Stats {
+            published: BATCH as u64,
+            rejected:  1,
+            max_depth: BATCH + 1,
+        });
+    }
+}
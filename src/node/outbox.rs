@@ -0,0 +1,144 @@
+//! Durable outbox for critical topics.
+//!
+//! Publishes on the topics given with `--outbox` are written to the
+//! [`storage`] of the data directory before they are handed to pubsub,
+//! and removed once pubsub took them, which it only does with at least one
+//! peer to send them to. Entries the node could not send, because it had no
+//! peers, was in power-save mode or quiet hours, or crashed, are retried
+//! every tick, including after a restart. Delivery is thus at least once:
+//! receivers may see a message twice if the node crashed right after sending
+//! it, and should deduplicate if that matters.
+//!
+//! The outbox holds at most [`MAX_ENTRIES`]; publishing on a topic with a
+//! full outbox fails rather than dropping messages.
+//!
+//! [`storage`]: super::storage
+
+use super::storage::Slot;
+use crate::prelude::*;
+use anyhow::ensure;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashSet};
+
+/// Storage namespace of the outbox.
+pub const NAMESPACE: &str = "outbox";
+
+/// Storage key of the outbox, once its file name.
+pub const KEY: &str = "outbox.cbor";
+
+/// Most messages waiting in the outbox.
+pub const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Entry {
+    topic: String,
+    data:  ByteBuf,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Outbox {
+    slot:    Option<Slot>,
+    topics:  HashSet<String>,
+    next_id: u64,
+    entries: BTreeMap<u64, Entry>,
+}
+
+impl Outbox {
+    /// Keep publishes on `topics` in `slot`, resuming the entries left
+    /// there.
+    pub fn load(slot: Slot, topics: impl IntoIterator<Item = String>) -> Result<Self> {
+        let entries: BTreeMap<u64, Entry> = match slot.read()? {
+            Some(data) => serde_cbor::from_slice(&data)
+                .with_context(|| format!("Parsing outbox {}", slot))?,
+            None => BTreeMap::new(),
+        };
+        if !entries.is_empty() {
+            info!("Resending {} messages from the outbox", entries.len());
+        }
+        Ok(Self {
+            slot: Some(slot),
+            topics: topics.into_iter().collect(),
+            next_id: entries.keys().next_back().map_or(0, |id| id + 1),
+            entries,
+        })
+    }
+
+    /// Whether publishes on `topic` go through the outbox.
+    pub fn is_durable(&self, topic: &str) -> bool {
+        self.topics.contains(topic)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Store `data` for `topic` until it is [`Self::confirm`]ed.
+    pub fn push(&mut self, topic: &str, data: &[u8]) -> Result<u64> {
+        ensure!(
+            self.entries.len() < MAX_ENTRIES,
+            "Outbox full with {} messages",
+            MAX_ENTRIES
+        );
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(id, Entry {
+            topic: topic.to_owned(),
+            data:  ByteBuf::from(data.to_vec()),
+        });
+        if let Err(err) = self.save() {
+            self.entries.remove(&id);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// Forget entry `id`, which was sent.
+    pub fn confirm(&mut self, id: u64) -> Result<()> {
+        if self.entries.remove(&id).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The entries waiting to be sent, oldest first.
+    pub fn pending(&self) -> Vec<(u64, String, Vec<u8>)> {
+        self.entries
+            .iter()
+            .map(|(id, entry)| (*id, entry.topic.clone(), entry.data.to_vec()))
+            .collect()
+    }
+
+    fn save(&self) -> Result<()> {
+        let slot = match &self.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        // Flush, so the entry survives a power loss.
+        slot.write(&serde_cbor::to_vec(&self.entries)?)?;
+        slot.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_keeps_unconfirmed_across_loads() {
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+
+        let mut outbox = Outbox::load(slot.clone(), vec!["orders".to_owned()]).unwrap();
+        assert!(outbox.is_durable("orders"));
+        assert!(!outbox.is_durable("chat"));
+        let sent = outbox.push("orders", b"first").unwrap();
+        let unsent = outbox.push("orders", b"second").unwrap();
+        outbox.confirm(sent).unwrap();
+
+        let mut reloaded = Outbox::load(slot, Vec::new()).unwrap();
+        assert_eq!(reloaded.pending(), vec![(unsent, "orders".to_owned(), b"second".to_vec())]);
+        assert!(reloaded.push("orders", b"third").unwrap() > unsent);
+    }
+}
@@ -0,0 +1,84 @@
+//! Private networks of the nodes holding a shared secret.
+//!
+//! Started with `--swarm-key <path>`, the node encrypts every connection
+//! with the pre-shared key in that file before anything else is sent, so
+//! nodes without the same key can not negotiate a security protocol with it
+//! and are refused. Nodes with a key and nodes without one do not connect
+//! either. Keys are in the `swarm.key` format of go-ipfs, and
+//! `mesh-rs keygen` prints a new one. Logs show the key's fingerprint only.
+
+use crate::prelude::*;
+use anyhow::anyhow;
+use libp2p::pnet::{PnetConfig, PreSharedKey};
+use std::{fmt, path::Path, str::FromStr};
+
+/// The pre-shared key of a private network.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SwarmKey(PreSharedKey);
+
+impl SwarmKey {
+    /// A new random key.
+    pub fn generate() -> Self {
+        Self(PreSharedKey::new(rand::random()))
+    }
+
+    /// Read the key file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading swarm key {}", path.display()))?;
+        let key: Self = text
+            .parse()
+            .with_context(|| format!("Parsing swarm key {}", path.display()))?;
+        info!("Joining the private network of swarm key {}", key.0.fingerprint());
+        Ok(key)
+    }
+
+    pub(crate) fn config(self) -> PnetConfig {
+        PnetConfig::new(self.0)
+    }
+}
+
+impl FromStr for SwarmKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse()
+            .map(Self)
+            .map_err(|err| anyhow!("Invalid swarm key: {:?}", err))
+    }
+}
+
+/// The key file.
+impl fmt::Display for SwarmKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Just the fingerprint, keeping the key itself out of logs.
+impl fmt::Debug for SwarmKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SwarmKey({})", self.0.fingerprint())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_key_file_round_trips() {
+        let key = SwarmKey::generate();
+        let file = key.to_string();
+        assert!(file.starts_with("/key/swarm/psk/1.0.0/\n/base16/\n"));
+        assert_eq!(file.parse::<SwarmKey>().unwrap(), key);
+        assert_ne!(SwarmKey::generate(), key);
+        assert!(!format!("{:?}", key).contains(file.lines().nth(2).unwrap()));
+
+        assert!("/key/swarm/psk/1.0.0/\n/base64/\nAAAA\n"
+            .parse::<SwarmKey>()
+            .is_err());
+        assert!("".parse::<SwarmKey>().is_err());
+    }
+}
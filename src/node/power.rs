@@ -0,0 +1,111 @@
+//! Power-save mode for battery powered and edge nodes.
+//!
+//! Entered with `--power-save`, [`super::NodeHandle::set_power_save`] or, in
+//! the binary, `SIGUSR1` (and left with `SIGUSR2`), so a battery monitor can
+//! switch modes. In power-save mode the node
+//!
+//! * ticks every [`TICK_INTERVAL`] instead of every second, which spaces out
+//!   election heartbeats and aggregate publishing. It stays below the default
+//!   election lease, so we keep our group memberships.
+//! * stops mDNS queries. Peers already known stay reachable through the DHT.
+//! * keeps at most [`MAX_PEERS`] connections besides critical peers, closing
+//!   those with the slowest pings.
+//! * queues messages published through the handle and sends them together
+//!   on the next tick, so the radio wakes up less often.
+//!
+//! The gossipsub heartbeat is fixed when the node is created and is not
+//! affected.
+
+use libp2p::PeerId;
+use std::{mem, time::Duration};
+
+/// Interval between ticks in power-save mode.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most non-critical peers to stay connected to.
+pub const MAX_PEERS: usize = 4;
+
+/// Most messages queued before they are sent early.
+pub const MAX_BATCH: usize = 256;
+
+/// Messages waiting for the next tick.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    messages: Vec<(String, Vec<u8>)>,
+}
+
+impl Batch {
+    /// Queue a message. Returns true if the batch is full and should be sent.
+    pub fn push(&mut self, topic: String, data: Vec<u8>) -> bool {
+        self.messages.push((topic, data));
+        self.messages.len() >= MAX_BATCH
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn take(&mut self) -> Vec<(String, Vec<u8>)> {
+        mem::take(&mut self.messages)
+    }
+}
+
+/// A connected peer, as far as trimming connections is concerned.
+#[derive(Clone, Debug)]
+pub struct Connected {
+    pub peer_id:  PeerId,
+    pub ping:     Option<Duration>,
+    pub critical: bool,
+}
+
+/// Peers to disconnect from to keep `keep` connections, preferring critical
+/// peers and then those with the fastest pings.
+pub fn excess_peers(mut peers: Vec<Connected>, keep: usize) -> Vec<PeerId> {
+    // Peers never pinged sort last
+    peers.sort_by_key(|peer| {
+        (
+            !peer.critical,
+            peer.ping.unwrap_or(Duration::from_secs(u64::MAX)),
+        )
+    });
+    peers
+        .into_iter()
+        .skip(keep)
+        .map(|peer| peer.peer_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_keeps_critical_and_fastest_peers() {
+        let peer = |ping: Option<u64>, critical: bool| {
+            Connected {
+                peer_id: PeerId::random(),
+                ping: ping.map(Duration::from_millis),
+                critical,
+            }
+        };
+        let mut peers = (0..MAX_PEERS as u64)
+            .map(|ping| peer(Some(ping * 10), false))
+            .collect::<Vec<_>>();
+        let slow = peer(Some(1000), false);
+        let unknown = peer(None, false);
+        let critical = peer(None, true);
+        peers.extend(vec![unknown.clone(), critical.clone(), slow.clone()]);
+        assert_eq!(excess_peers(peers.clone(), MAX_PEERS + 1), vec![
+            slow.peer_id,
+            unknown.peer_id
+        ]);
+        let kept = peers.len() - excess_peers(peers.clone(), 1).len();
+        assert_eq!(kept, 1);
+        assert!(!excess_peers(peers, 1).contains(&critical.peer_id));
+    }
+}
@@ -0,0 +1,404 @@
+//! Who is online, from gossiped heartbeats.
+//!
+//! With `--presence "nickname=alice capability=chat interval=30s timeout=90s"`
+//! the node publishes a [`Heartbeat`] on [`TOPIC`] every `interval`: its
+//! nickname, capabilities and the topics it is subscribed to through the
+//! handle. It keeps a roster of the peers doing the same, with the time each
+//! was last seen on each of its topics, counting its messages there too.
+//! [`Event::PresenceJoined`] is emitted when a peer shows up on a topic, and
+//! [`Event::PresenceLeft`] when it stops listing the topic, announces it is
+//! leaving through [`super::lifecycle`] or goes silent for `timeout`.
+//!
+//! Only nodes with `--presence` take part. Heartbeats are as authentic as
+//! other messages, see [`super::verification`].
+//!
+//! [`Event::PresenceJoined`]: crate::node::Event::PresenceJoined
+//! [`Event::PresenceLeft`]: crate::node::Event::PresenceLeft
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Topic on which heartbeats are published.
+pub const TOPIC: &str = "/mesh-rs/presence/version/1";
+
+/// Longest nickname or capability accepted.
+pub const MAX_NAME: usize = 64;
+
+/// Most capabilities in a heartbeat.
+pub const MAX_CAPABILITIES: usize = 16;
+
+/// Most topics in a heartbeat.
+pub const MAX_TOPICS: usize = 64;
+
+/// Most peers kept in the roster.
+const MAX_PEERS: usize = 4096;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Shown to peers instead of our peer id, if not empty.
+    pub nickname:     String,
+    pub capabilities: Vec<String>,
+    /// Time between heartbeats.
+    pub interval:     Duration,
+    /// Silence after which a peer counts as gone.
+    pub timeout:      Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nickname:     String::new(),
+            capabilities: Vec::new(),
+            interval:     Duration::from_secs(30),
+            timeout:      Duration::from_secs(90),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    /// `capability` may be repeated.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "nickname" => config.nickname = value.to_owned(),
+                "capability" => config.capabilities.push(value.to_owned()),
+                "interval" => {
+                    config.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid interval {}", value))?;
+                }
+                "timeout" => {
+                    config.timeout = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid timeout {}", value))?;
+                }
+                _ => bail!("Unknown presence option {}", key),
+            }
+        }
+        ensure!(
+            config.interval > Duration::from_secs(0),
+            "Presence interval must be positive"
+        );
+        ensure!(
+            config.timeout > config.interval,
+            "Presence timeout must exceed the interval"
+        );
+        ensure!(
+            config.nickname.len() <= MAX_NAME,
+            "Nickname over {} bytes",
+            MAX_NAME
+        );
+        ensure!(
+            config.capabilities.len() <= MAX_CAPABILITIES,
+            "Over {} capabilities",
+            MAX_CAPABILITIES
+        );
+        ensure!(
+            config.capabilities.iter().all(|name| name.len() <= MAX_NAME),
+            "Capability over {} bytes",
+            MAX_NAME
+        );
+        Ok(config)
+    }
+}
+
+/// What a peer says about itself every interval.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub nickname:     String,
+    pub capabilities: Vec<String>,
+    pub topics:       Vec<String>,
+}
+
+/// A peer on a topic, as listed by [`super::NodeHandle::roster`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Present {
+    pub peer_id:      PeerId,
+    pub nickname:     String,
+    pub capabilities: Vec<String>,
+    pub last_seen:    SystemTime,
+}
+
+/// A peer showing up on or leaving a topic.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+    Joined {
+        peer_id:  PeerId,
+        topic:    String,
+        nickname: String,
+    },
+    Left {
+        peer_id: PeerId,
+        topic:   String,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Peer {
+    nickname:     String,
+    capabilities: Vec<String>,
+    /// Last heartbeat.
+    heartbeat:    Instant,
+    /// When the peer was last seen on each of its topics.
+    topics:       HashMap<String, Instant>,
+}
+
+/// Our heartbeats and the roster of peers.
+#[derive(Clone, Debug)]
+pub struct Presence {
+    config: Config,
+    /// When our next heartbeat is due.
+    next:   Option<Instant>,
+    peers:  HashMap<PeerId, Peer>,
+}
+
+impl Presence {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            next: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Our heartbeat listing `topics`, if due at `now`.
+    pub fn due(&self, now: Instant, mut topics: Vec<String>) -> Option<Heartbeat> {
+        if matches!(self.next, Some(next) if now < next) {
+            return None;
+        }
+        topics.truncate(MAX_TOPICS);
+        Some(Heartbeat {
+            nickname: self.config.nickname.clone(),
+            capabilities: self.config.capabilities.clone(),
+            topics,
+        })
+    }
+
+    /// Our heartbeat went out at `now`.
+    pub fn published(&mut self, now: Instant) {
+        self.next = Some(now + self.config.interval);
+    }
+
+    /// Take the heartbeat `source` published at `now`.
+    pub fn receive(
+        &mut self,
+        source: &PeerId,
+        heartbeat: Heartbeat,
+        now: Instant,
+    ) -> Result<Vec<Change>> {
+        ensure!(heartbeat.nickname.len() <= MAX_NAME, "Nickname over {} bytes", MAX_NAME);
+        ensure!(
+            heartbeat.capabilities.len() <= MAX_CAPABILITIES
+                && heartbeat.capabilities.iter().all(|name| name.len() <= MAX_NAME),
+            "Over {} capabilities or longer than {} bytes",
+            MAX_CAPABILITIES,
+            MAX_NAME
+        );
+        ensure!(heartbeat.topics.len() <= MAX_TOPICS, "Over {} topics", MAX_TOPICS);
+        if !self.peers.contains_key(source) {
+            ensure!(self.peers.len() < MAX_PEERS, "Over {} peers in the roster", MAX_PEERS);
+        }
+        let peer = self.peers.entry(source.clone()).or_insert_with(|| {
+            Peer {
+                nickname:     String::new(),
+                capabilities: Vec::new(),
+                heartbeat:    now,
+                topics:       HashMap::new(),
+            }
+        });
+        peer.nickname = heartbeat.nickname;
+        peer.capabilities = heartbeat.capabilities;
+        peer.heartbeat = now;
+        let topics = heartbeat.topics;
+        let mut changes = Vec::new();
+        peer.topics.retain(|topic, _| {
+            let kept = topics.contains(topic);
+            if !kept {
+                changes.push(Change::Left {
+                    peer_id: source.clone(),
+                    topic:   topic.clone(),
+                });
+            }
+            kept
+        });
+        for topic in topics {
+            if peer.topics.insert(topic.clone(), now).is_none() {
+                changes.push(Change::Joined {
+                    peer_id: source.clone(),
+                    topic,
+                    nickname: peer.nickname.clone(),
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    /// `source` published a message on `topic` at `now`.
+    pub fn seen(&mut self, source: &PeerId, topic: &str, now: Instant) {
+        if let Some(last_seen) = self
+            .peers
+            .get_mut(source)
+            .and_then(|peer| peer.topics.get_mut(topic))
+        {
+            *last_seen = now;
+        }
+    }
+
+    /// Drop `peer_id`, which left.
+    pub fn remove(&mut self, peer_id: &PeerId) -> Vec<Change> {
+        self.peers
+            .remove(peer_id)
+            .map_or_else(Vec::new, |peer| left(peer_id, peer))
+    }
+
+    /// Drop the peers silent for the timeout by `now`.
+    pub fn tick(&mut self, now: Instant) -> Vec<Change> {
+        let timeout = self.config.timeout;
+        let silent: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| now.saturating_duration_since(peer.heartbeat) >= timeout)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        silent
+            .iter()
+            .flat_map(|peer_id| self.remove(peer_id))
+            .collect()
+    }
+
+    /// The peers on `topic` with their last seen time, as of `now`, by
+    /// nickname.
+    pub fn roster(&self, topic: &str, now: Instant) -> Vec<Present> {
+        let wall = SystemTime::now();
+        let mut present: Vec<Present> = self
+            .peers
+            .iter()
+            .filter_map(|(peer_id, peer)| {
+                let last_seen = peer.topics.get(topic)?;
+                Some(Present {
+                    peer_id:      peer_id.clone(),
+                    nickname:     peer.nickname.clone(),
+                    capabilities: peer.capabilities.clone(),
+                    last_seen:    wall - now.saturating_duration_since(*last_seen),
+                })
+            })
+            .collect();
+        present.sort_by(|a, b| {
+            (&a.nickname, a.peer_id.to_base58()).cmp(&(&b.nickname, b.peer_id.to_base58()))
+        });
+        present
+    }
+}
+
+fn left(peer_id: &PeerId, peer: Peer) -> Vec<Change> {
+    let mut topics: Vec<String> = peer.topics.into_iter().map(|(topic, _)| topic).collect();
+    topics.sort();
+    topics
+        .into_iter()
+        .map(|topic| {
+            Change::Left {
+                peer_id: peer_id.clone(),
+                topic,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn heartbeat(nickname: &str, topics: &[&str]) -> Heartbeat {
+        Heartbeat {
+            nickname:     nickname.to_owned(),
+            capabilities: vec!["chat".to_owned()],
+            topics:       topics.iter().map(|topic| (*topic).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "nickname=alice capability=chat capability=files interval=10s"
+            .parse()
+            .unwrap();
+        assert_eq!(config.nickname, "alice");
+        assert_eq!(config.capabilities, vec!["chat", "files"]);
+        assert_eq!(config.interval, Duration::from_secs(10));
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("interval=2m".parse::<Config>().is_err());
+        assert!("colour=red".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_tracks_peers_per_topic() {
+        let mut presence = Presence::new(Config::default());
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+
+        let changes = presence
+            .receive(&alice, heartbeat("alice", &["chat"]), start)
+            .unwrap();
+        assert_eq!(changes, vec![Change::Joined {
+            peer_id:  alice.clone(),
+            topic:    "chat".into(),
+            nickname: "alice".into(),
+        }]);
+        assert!(presence
+            .receive(&alice, heartbeat("alice", &["chat"]), start)
+            .unwrap()
+            .is_empty());
+        presence
+            .receive(&bob, heartbeat("bob", &["chat", "news"]), start)
+            .unwrap();
+        let roster = presence.roster("chat", start);
+        assert_eq!(roster.len(), 2);
+        assert_eq!(roster[0].nickname, "alice");
+        assert_eq!(presence.roster("news", start)[0].peer_id, bob);
+
+        // Topics no longer listed are left
+        let later = start + Duration::from_secs(60);
+        let changes = presence.receive(&bob, heartbeat("bob", &["chat"]), later).unwrap();
+        assert_eq!(changes, vec![Change::Left {
+            peer_id: bob.clone(),
+            topic:   "news".into(),
+        }]);
+
+        // Silent peers leave
+        let changes = presence.tick(start + Duration::from_secs(90));
+        assert_eq!(changes, vec![Change::Left {
+            peer_id: alice.clone(),
+            topic:   "chat".into(),
+        }]);
+        assert_eq!(presence.roster("chat", later).len(), 1);
+        assert_eq!(presence.remove(&bob).len(), 1);
+        assert!(presence.remove(&bob).is_empty());
+
+        let long = heartbeat(&"a".repeat(MAX_NAME + 1), &[]);
+        assert!(presence.receive(&alice, long, later).is_err());
+    }
+
+    #[test]
+    fn test_publishes_every_interval() {
+        let mut presence = Presence::new(Config::default());
+        let now = Instant::now();
+        let topics = vec!["chat".to_owned()];
+        assert_eq!(presence.due(now, topics.clone()).unwrap().topics, topics);
+        presence.published(now);
+        assert!(presence.due(now, topics.clone()).is_none());
+        assert!(presence.due(now + Duration::from_secs(30), topics).is_some());
+    }
+}
@@ -0,0 +1,265 @@
+//! Liveness and readiness probes.
+//!
+//! Started with `--probes "address=0.0.0.0:8081 peers=2 stall=30s"` the node
+//! answers plain HTTP probes on that address, for an orchestrator like
+//! Kubernetes to restart it or hold traffic back:
+//!
+//! * `GET /healthz` answers `200 OK` while the event loop is alive, and
+//!   `503 Service Unavailable` when it took no step for `stall`.
+//! * `GET /readyz` answers `200 OK` once the node is alive, listens on an
+//!   address and has at least `peers` connected peers or completed its
+//!   [`bootstrap`], and `503 Service Unavailable` with the reason before
+//!   that and while shutting down.
+//!
+//! The event loop records its [`Status`] after every step, and the probes
+//! are answered from that record on a task of their own, so they get an
+//! answer even when the loop is stuck. The loop steps at least on every
+//! tick, in power-save mode every [`power::TICK_INTERVAL`], so `stall` must
+//! be longer than that. A watchdog looks at the record every half `stall`
+//! and logs an error when the loop stalled, and again when it recovers.
+//! `peers` defaults to 1 and `stall` to 30 seconds.
+//!
+//! [`bootstrap`]: crate::node::bootstrap
+//! [`power::TICK_INTERVAL`]: crate::node::power::TICK_INTERVAL
+
+use super::{http, options, power};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub address: SocketAddr,
+    /// Connected peers that make the node ready without a bootstrap.
+    pub peers:   usize,
+    /// How long the event loop may take no step before it is not alive.
+    pub stall:   Duration,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut address = None;
+        let mut peers = 1;
+        let mut stall = Duration::from_secs(30);
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "address" => {
+                    address = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid address {}", value))?,
+                    );
+                }
+                "peers" => {
+                    peers = value
+                        .parse()
+                        .with_context(|| format!("Invalid peers {}", value))?;
+                }
+                "stall" => {
+                    stall = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid stall {}", value))?;
+                }
+                _ => bail!("Unknown probe option {}", key),
+            }
+        }
+        let config = Self {
+            address: address.ok_or_else(|| anyhow!("The probes need an address"))?,
+            peers,
+            stall,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.stall > power::TICK_INTERVAL,
+            "The stall timeout must be longer than {:?}",
+            power::TICK_INTERVAL
+        );
+        Ok(())
+    }
+
+    /// Whether the event loop that recorded `status` is alive at `now`.
+    pub fn live(&self, status: &Status, now: Instant) -> Result<()> {
+        let stalled = now.saturating_duration_since(status.stepped);
+        ensure!(stalled < self.stall, "Event loop stalled for {:?}", stalled);
+        Ok(())
+    }
+
+    /// Whether the node that recorded `status` is ready at `now`.
+    pub fn ready(&self, status: &Status, now: Instant) -> Result<()> {
+        self.live(status, now)?;
+        ensure!(!status.stopping, "Shutting down");
+        ensure!(status.listening, "Not listening");
+        ensure!(
+            status.peers >= self.peers || status.bootstrapped,
+            "{} of {} peers connected and bootstrap incomplete",
+            status.peers,
+            self.peers
+        );
+        Ok(())
+    }
+}
+
+/// The state of the node the probes are answered from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Status {
+    /// When the event loop last took a step.
+    pub stepped:      Instant,
+    pub listening:    bool,
+    pub peers:        usize,
+    pub bootstrapped: bool,
+    pub stopping:     bool,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            stepped:      Instant::now(),
+            listening:    false,
+            peers:        0,
+            bootstrapped: false,
+            stopping:     false,
+        }
+    }
+}
+
+/// The last [`Status`] the event loop recorded, shared with the probes.
+#[derive(Clone, Debug, Default)]
+pub struct Probes(Arc<Mutex<Status>>);
+
+impl Probes {
+    pub fn record(&self, status: Status) {
+        *self.0.lock().unwrap() = status;
+    }
+
+    pub fn status(&self) -> Status {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Answer one HTTP request on `stream`.
+async fn serve_client(stream: TcpStream, probes: &Probes, config: &Config) -> Result<()> {
+    http::answer(stream, "text/plain", |request| {
+        async move {
+            let path = request.path.as_str();
+            Ok(match request.method.as_str() {
+                "GET" if path == "/healthz" || path == "/readyz" => {
+                    let checked = if path == "/healthz" {
+                        config.live(&probes.status(), Instant::now())
+                    } else {
+                        config.ready(&probes.status(), Instant::now())
+                    };
+                    match checked {
+                        Ok(()) => ("200 OK", "ok\n".to_owned()),
+                        Err(err) => ("503 Service Unavailable", format!("{:#}\n", err)),
+                    }
+                }
+                "GET" => ("404 Not Found", "Not found, try /healthz or /readyz\n".to_owned()),
+                _ => ("405 Method Not Allowed", "Only GET is supported\n".to_owned()),
+            })
+        }
+    })
+    .await
+}
+
+/// Listen for probes on the address of `config`.
+pub async fn bind(config: &Config) -> Result<TcpListener> {
+    TcpListener::bind(config.address)
+        .await
+        .with_context(|| format!("Listening for probes on {}", config.address))
+}
+
+/// Accept probes on `listener`, answering them from `probes`.
+pub async fn serve(listener: TcpListener, probes: Probes, config: Config) -> Result<()> {
+    info!("Serving probes on http://{}/healthz", listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await.context("Accepting probe")?;
+        let probes = probes.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, &probes, &config).await {
+                debug!("Probe failed: {:#}", err);
+            }
+        });
+    }
+}
+
+/// Log an error when the event loop recording to `probes` stalls.
+pub async fn watch(probes: Probes, config: Config) {
+    let mut stalled = false;
+    loop {
+        sleep(config.stall / 2).await;
+        match config.live(&probes.status(), Instant::now()) {
+            Err(err) if !stalled => {
+                error!("{:#}", err);
+                stalled = true;
+            }
+            Ok(()) if stalled => {
+                info!("Event loop recovered");
+                stalled = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "address=127.0.0.1:8081 peers=3 stall=1m".parse().unwrap();
+        assert_eq!(config.address, "127.0.0.1:8081".parse().unwrap());
+        assert_eq!((config.peers, config.stall), (3, Duration::from_secs(60)));
+        let config: Config = "address=[::]:8081".parse().unwrap();
+        assert_eq!((config.peers, config.stall), (1, Duration::from_secs(30)));
+        assert!("peers=3".parse::<Config>().is_err());
+        assert!("address=127.0.0.1:8081 stall=1s".parse::<Config>().is_err());
+        assert!("address=127.0.0.1:8081 ready=yes".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_ready_once_listening_with_peers() {
+        let config: Config = "address=127.0.0.1:0 peers=2".parse().unwrap();
+        let now = Instant::now();
+        let mut status = Status {
+            stepped: now,
+            ..Status::default()
+        };
+        assert!(config.live(&status, now).is_ok());
+        assert!(config.ready(&status, now).is_err());
+        status.listening = true;
+        status.peers = 1;
+        let err = config.ready(&status, now).unwrap_err();
+        assert_eq!(err.to_string(), "1 of 2 peers connected and bootstrap incomplete");
+        status.bootstrapped = true;
+        assert!(config.ready(&status, now).is_ok());
+        status.bootstrapped = false;
+        status.peers = 2;
+        assert!(config.ready(&status, now).is_ok());
+
+        let later = now + config.stall;
+        assert!(config.live(&status, later).is_err());
+        assert!(config.ready(&status, later).is_err());
+        status.stopping = true;
+        assert_eq!(config.ready(&status, now).unwrap_err().to_string(), "Shutting down");
+    }
+}
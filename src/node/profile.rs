@@ -0,0 +1,134 @@
+//! Bounds on what the node keeps in memory.
+//!
+//! Every collection that grows with the network or with traffic is bounded,
+//! either by a fixed limit of its module or by [`Limits`]:
+//!
+//! * connections, by [`Limits::max_peers`] beyond critical peers, and
+//!   [`super::power::MAX_PEERS`] when saving power;
+//! * the known peers table, by [`Limits::known_peers`], forgetting
+//!   disconnected peers first;
+//! * the blob store, by [`Limits::blob_capacity`] bytes;
+//! * live messages held while backfilling a topic, by
+//!   [`Limits::backfill_held`];
+//! * actions waiting for their time, by [`Limits::scheduled`];
+//! * the outbox, the power-save and quiet hours batches, unacked direct
+//!   messages, routes, event streams and the archive, by their own limits.
+//!
+//! `--profile constrained` shrinks the limits for 64 MB-class devices, see
+//! the Readme for memory measured in both profiles. `--max-peers` overrides
+//! the connection limit of either profile.
+
+use crate::prelude::*;
+use anyhow::bail;
+use libp2p::PeerId;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum Profile {
+    Default,
+    /// Small limits for edge devices.
+    Constrained,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "default" => Self::Default,
+            "constrained" => Self::Constrained,
+            _ => bail!("Unknown profile {}, expected default or constrained", s),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    /// Connected peers to keep, besides critical peers, if limited.
+    pub max_peers:     Option<usize>,
+    /// Entries of the known peers table.
+    pub known_peers:   usize,
+    /// Bytes of large message payloads kept to serve them.
+    pub blob_capacity: usize,
+    /// Live messages held per topic while it is backfilled.
+    pub backfill_held: usize,
+    /// Scheduled actions waiting for their time.
+    pub scheduled:     usize,
+}
+
+impl Profile {
+    pub const fn limits(self) -> Limits {
+        match self {
+            Self::Default => {
+                Limits {
+                    max_peers:     None,
+                    known_peers:   4096,
+                    blob_capacity: 64 * 1024 * 1024,
+                    backfill_held: 4096,
+                    scheduled:     1024,
+                }
+            }
+            Self::Constrained => {
+                Limits {
+                    max_peers:     Some(8),
+                    known_peers:   64,
+                    blob_capacity: 2 * 1024 * 1024,
+                    backfill_held: 256,
+                    scheduled:     32,
+                }
+            }
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Profile::Default.limits()
+    }
+}
+
+/// Known peers to forget to keep `limit` entries: the disconnected peers of
+/// `peers`, given with whether they are connected, in order.
+pub fn excess_known_peers(
+    peers: impl IntoIterator<Item = (PeerId, bool)>,
+    limit: usize,
+) -> Vec<PeerId> {
+    let peers: Vec<_> = peers.into_iter().collect();
+    let excess = peers.len().saturating_sub(limit);
+    peers
+        .into_iter()
+        .filter(|(_, connected)| !connected)
+        .map(|(peer_id, _)| peer_id)
+        .take(excess)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_forgets_disconnected_peers() {
+        assert_eq!("constrained".parse::<Profile>().unwrap(), Profile::Constrained);
+        assert!("tiny".parse::<Profile>().is_err());
+        assert_eq!(Limits::default(), Profile::Default.limits());
+
+        let peers: Vec<_> = (0..5).map(|_| PeerId::random()).collect();
+        let known = || peers.iter().cloned().zip(vec![true, false, true, false, false]);
+        assert_eq!(excess_known_peers(known(), 3), vec![
+            peers[1].clone(),
+            peers[3].clone()
+        ]);
+        assert!(excess_known_peers(known(), 5).is_empty());
+        // Connected peers stay even beyond the limit
+        assert_eq!(excess_known_peers(known(), 0).len(), 3);
+    }
+}
@@ -0,0 +1,208 @@
+//! Publishing from code, with receipts.
+//!
+//! [`Node::publisher`] and [`NodeHandle::publisher`] return a [`Publisher`]
+//! for one topic, which a sensor or an automation keeps to publish on a
+//! timer. [`Publisher::send`] resolves with a [`Receipt`] once pubsub
+//! handed the message to at least one peer. While no peer takes it, or the
+//! outbound queue is full, it tries again with a backoff from [`RETRY_MIN`]
+//! up to [`RETRY_MAX`] between attempts and fails after the timeout of the
+//! publisher, [`DEFAULT_TIMEOUT`] unless set. A message the node holds back
+//! for the outbox, power-save mode or quiet hours has not left the node
+//! yet, which its receipt tells.
+//!
+//! `--heartbeat "topic=sensors/kitchen interval=10s data=alive"`, or a
+//! `[[heartbeat]]` table of the config file, publishes `data` on `topic`
+//! every `interval` through a publisher that times out after the interval.
+//! Each send that fails is logged, and the next one tries again. May be
+//! repeated.
+//!
+//! [`Node::publisher`]: crate::node::Node::publisher
+
+use super::{options, outbound, NodeHandle};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::{interval_at, sleep, timeout};
+
+/// How long a send tries by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The first wait before trying a send again.
+pub const RETRY_MIN: Duration = Duration::from_millis(100);
+
+/// The longest wait between two attempts of a send.
+pub const RETRY_MAX: Duration = Duration::from_secs(2);
+
+/// Why pubsub did not take a message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Publish failed: InsufficientPeers")]
+pub struct NoPeers;
+
+/// How a message left the node.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Receipt {
+    /// Whether pubsub sent the message, rather than the node holding it
+    /// back to send later.
+    pub sent:     bool,
+    /// Publishes it took, the first included.
+    pub attempts: u32,
+    /// From the send to the receipt.
+    pub waited:   Duration,
+}
+
+/// Publishes on one topic, see the [module docs](self).
+#[derive(Clone)]
+pub struct Publisher {
+    handle:  NodeHandle,
+    topic:   String,
+    timeout: Duration,
+}
+
+impl Publisher {
+    pub fn new(handle: NodeHandle, topic: &str) -> Self {
+        Self {
+            handle,
+            topic: topic.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Give up sends after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Publish `data`, returning once a peer took it.
+    pub async fn send(&mut self, data: &[u8]) -> Result<Receipt> {
+        let started = Instant::now();
+        let deadline = started + self.timeout;
+        let mut delay = RETRY_MIN;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let left = deadline.saturating_duration_since(Instant::now());
+            let result = match timeout(left, self.handle.publish_now(&self.topic, data)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("Node did not take the message")),
+            };
+            let err = match result {
+                Ok(sent) => {
+                    return Ok(Receipt {
+                        sent,
+                        attempts,
+                        waited: started.elapsed(),
+                    })
+                }
+                Err(err) => err,
+            };
+            let retry = err.downcast_ref::<NoPeers>().is_some()
+                || err.downcast_ref::<outbound::Full>().is_some();
+            if !retry || Instant::now() + delay > deadline {
+                return Err(err.context(format!(
+                    "Publishing on {} failed after {} attempts in {:?}",
+                    self.topic,
+                    attempts,
+                    started.elapsed()
+                )));
+            }
+            trace!("{}, trying again in {:?}", err, delay);
+            sleep(delay).await;
+            delay = (delay * 2).min(RETRY_MAX);
+        }
+    }
+}
+
+/// Data to publish on a timer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Heartbeat {
+    pub topic:    String,
+    pub interval: Duration,
+    pub data:     String,
+}
+
+impl FromStr for Heartbeat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut topic = None;
+        let mut interval = None;
+        let mut data = String::new();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "topic" => topic = Some(value.to_owned()),
+                "interval" => {
+                    interval = Some(
+                        humantime::parse_duration(value)
+                            .with_context(|| format!("Invalid interval {}", value))?,
+                    );
+                }
+                "data" => data = value.to_owned(),
+                _ => bail!("Unknown heartbeat option {}", key),
+            }
+        }
+        let heartbeat = Self {
+            topic: topic.ok_or_else(|| anyhow!("A heartbeat needs a topic"))?,
+            interval: interval.ok_or_else(|| anyhow!("A heartbeat needs an interval"))?,
+            data,
+        };
+        heartbeat.validate()?;
+        Ok(heartbeat)
+    }
+}
+
+impl Heartbeat {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.topic.is_empty(), "The heartbeat topic is empty");
+        ensure!(
+            self.interval >= Duration::from_millis(100),
+            "A heartbeat interval must be at least 100ms"
+        );
+        Ok(())
+    }
+
+    /// Publish through `handle` every interval, until the node stops.
+    pub async fn run(self, handle: NodeHandle) {
+        let mut publisher = Publisher::new(handle, &self.topic).with_timeout(self.interval);
+        let mut ticks = interval_at((Instant::now() + self.interval).into(), self.interval);
+        loop {
+            ticks.tick().await;
+            match publisher.send(self.data.as_bytes()).await {
+                Ok(receipt) => trace!("Heartbeat on {}: {:?}", self.topic, receipt),
+                Err(_) if publisher.handle.is_stopped() => return,
+                Err(err) => warn!("Heartbeat not sent: {:#}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_heartbeat() {
+        let heartbeat: Heartbeat = "topic=sensors interval=10s data=alive".parse().unwrap();
+        assert_eq!(heartbeat, Heartbeat {
+            topic:    "sensors".into(),
+            interval: Duration::from_secs(10),
+            data:     "alive".into(),
+        });
+        assert_eq!("topic=a interval=1s".parse::<Heartbeat>().unwrap().data, "");
+        assert!("interval=1s".parse::<Heartbeat>().is_err());
+        assert!("topic=a".parse::<Heartbeat>().is_err());
+        assert!("topic=a interval=1ms".parse::<Heartbeat>().is_err());
+        assert!("topic=a interval=1s qos=high".parse::<Heartbeat>().is_err());
+        let err = anyhow::Error::new(NoPeers).context("Publishing on a failed");
+        assert_eq!(err.downcast_ref::<NoPeers>(), Some(&NoPeers));
+    }
+}
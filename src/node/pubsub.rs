@@ -0,0 +1,162 @@
+//! Choice and tuning of the pubsub protocol.
+//!
+//! Gossipsub, the default, keeps a mesh of `mesh` peers per topic, between
+//! `mesh-low` and `mesh-high`, forwards full messages only along the mesh and
+//! gossips about the rest every `heartbeat`. Messages are signed by their
+//! source. Floodsub sends every message to every connected peer, which is
+//! simpler and fine for a handful of nodes on a LAN, but neither scales nor
+//! signs messages. Nodes only exchange messages with peers speaking the same
+//! protocol, so all nodes of a deployment should use the same
+//! `--pubsub "protocol=floodsub"` or `--pubsub "mesh=8 heartbeat=700ms"`.
+//! `seen-ttl` and `seen-capacity` bound the cache of delivered messages,
+//! see [`seen`]. [`compat`] pins all of it to what go-libp2p peers expect.
+//!
+//! [`seen`]: crate::node::seen
+//! [`compat`]: crate::node::compat
+
+use crate::{
+    node::{options, seen},
+    prelude::*,
+};
+use anyhow::{bail, ensure};
+use std::{str::FromStr, time::Duration};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Protocol {
+    #[default]
+    Gossipsub,
+    Floodsub,
+    /// No pubsub at all, for [`super::mode::Mode::Bootstrap`].
+    Off,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub protocol:      Protocol,
+    /// Number of mesh peers per topic gossipsub aims for.
+    pub mesh:          usize,
+    /// Fewer mesh peers than this are topped up at the next heartbeat.
+    pub mesh_low:      usize,
+    /// More mesh peers than this are pruned at the next heartbeat.
+    pub mesh_high:     usize,
+    /// Time between gossipsub heartbeats, which maintain the mesh and
+    /// gossip about recent messages.
+    pub heartbeat:     Duration,
+    /// How long delivered messages are remembered to drop duplicates.
+    pub seen_ttl:      Duration,
+    /// Delivered messages remembered at most.
+    pub seen_capacity: usize,
+    /// Largest message gossipsub sends or takes, in bytes.
+    pub max_transmit:  usize,
+    /// Publish bare payloads, with message ids as go-libp2p makes them,
+    /// see [`crate::node::compat`].
+    pub bare:          bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            protocol:      Protocol::Gossipsub,
+            mesh:          6,
+            mesh_low:      5,
+            mesh_high:     12,
+            heartbeat:     Duration::from_secs(1),
+            seen_ttl:      seen::DEFAULT_TTL,
+            seen_capacity: seen::DEFAULT_CAPACITY,
+            max_transmit:  262_144,
+            bare:          false,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let degree = || {
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid {} {}", key, value))
+            };
+            match key {
+                "protocol" => {
+                    config.protocol = match value {
+                        "gossipsub" => Protocol::Gossipsub,
+                        "floodsub" => Protocol::Floodsub,
+                        _ => bail!("Unknown pubsub protocol {}", value),
+                    };
+                }
+                "mesh" => config.mesh = degree()?,
+                "mesh-low" => config.mesh_low = degree()?,
+                "mesh-high" => config.mesh_high = degree()?,
+                "heartbeat" => {
+                    config.heartbeat = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid heartbeat {}", value))?;
+                }
+                "seen-ttl" => {
+                    config.seen_ttl = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid seen-ttl {}", value))?;
+                }
+                "seen-capacity" => config.seen_capacity = degree()?,
+                _ => bail!("Unknown pubsub option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.mesh_low <= self.mesh && self.mesh <= self.mesh_high,
+            "Pubsub mesh degrees must be mesh-low <= mesh <= mesh-high"
+        );
+        ensure!(self.mesh > 0, "Pubsub mesh degree must be positive");
+        ensure!(
+            self.heartbeat > Duration::from_secs(0),
+            "Pubsub heartbeat must be positive"
+        );
+        ensure!(self.seen_capacity > 0, "Pubsub seen-capacity must be positive");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert_eq!(
+            "protocol=floodsub".parse::<Config>().unwrap().protocol,
+            Protocol::Floodsub
+        );
+        assert_eq!(
+            "mesh=8 mesh-low=6,mesh-high=16 heartbeat=700ms"
+                .parse::<Config>()
+                .unwrap(),
+            Config {
+                protocol:      Protocol::Gossipsub,
+                mesh:          8,
+                mesh_low:      6,
+                mesh_high:     16,
+                heartbeat:     Duration::from_millis(700),
+                ..Config::default()
+            }
+        );
+        let config: Config = "seen-ttl=10s seen-capacity=100".parse().unwrap();
+        assert_eq!((config.seen_ttl, config.seen_capacity), (Duration::from_secs(10), 100));
+        assert!("seen-capacity=0".parse::<Config>().is_err());
+        assert!("mesh=20".parse::<Config>().is_err());
+        assert!("protocol=randomsub".parse::<Config>().is_err());
+        assert!("heartbeat=0s".parse::<Config>().is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Classes of service of topics.
+//!
+//! Subscribing with `TopicOptions { qos: Class::Realtime, .. }` marks the
+//! messages we publish on the topic as urgent, and `Class::Bulk` as able to
+//! wait. Neither the yamux nor the mplex multiplexer of libp2p 0.32 has
+//! stream priorities and there is no QUIC transport, so classes map to our
+//! own queues only: realtime messages skip the batch of power-save mode
+//! and go out at once, and whenever queued messages are sent, from the
+//! batch or the [outbox], those of higher classes go first. Within a class
+//! messages keep the order they were published in.
+//!
+//! [outbox]: super::outbox
+
+use crate::prelude::*;
+
+/// How urgently messages of a topic are sent, highest first.
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Class {
+    Realtime,
+    #[default]
+    Normal,
+    Bulk,
+}
+
+/// Put the `queue` of messages in the order to send them: higher classes
+/// first, in the order they were queued within a class.
+pub fn prioritize<T>(queue: &mut [T], class: impl Fn(&T) -> Class) {
+    // Stable, so messages of a class keep their order
+    queue.sort_by_key(class);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_sends_higher_classes_first() {
+        assert!(Class::Realtime < Class::Bulk);
+        assert_eq!(serde_json::to_string(&Class::Realtime).unwrap(), "\"realtime\"");
+
+        let mut queue = vec![
+            (Class::Bulk, 1),
+            (Class::Normal, 2),
+            (Class::Realtime, 3),
+            (Class::Bulk, 4),
+            (Class::Realtime, 5),
+        ];
+        prioritize(&mut queue, |(class, _)| *class);
+        let order = queue.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        assert_eq!(order, vec![3, 5, 2, 1, 4]);
+    }
+}
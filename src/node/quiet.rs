@@ -0,0 +1,111 @@
+//! Scheduled quiet hours.
+//!
+//! Started with `--quiet-hours "22:00-06:00,12:00-12:30"` the node goes
+//! dormant during those windows (in UTC): on top of [`super::power`] save
+//! mode it keeps a single connection, preferring a critical peer, and holds
+//! all messages published through the handle until the window ends. At most
+//! [`MAX_BUFFERED`] messages are held; publishing more fails.
+
+use crate::prelude::*;
+use anyhow::bail;
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Most messages held while dormant.
+pub const MAX_BUFFERED: usize = 4096;
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Daily windows in seconds since midnight UTC, ends exclusive. A window
+/// ending before it starts wraps around midnight.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Schedule {
+    windows: Vec<(u32, u32)>,
+}
+
+fn parse_time(time: &str) -> Result<u32> {
+    let (hours, minutes) = match time.find(':') {
+        Some(index) => (&time[..index], &time[index + 1..]),
+        None => bail!("Expected HH:MM, got {}", time),
+    };
+    let hours: u32 = hours.parse().with_context(|| format!("Invalid hour in {}", time))?;
+    let minutes: u32 = minutes
+        .parse()
+        .with_context(|| format!("Invalid minute in {}", time))?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        bail!("Time of day {} out of range", time);
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    /// Parse `HH:MM-HH:MM` windows separated by spaces or commas.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut windows = Vec::new();
+        for window in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if window.is_empty() {
+                continue;
+            }
+            let (start, end) = match window.find('-') {
+                Some(index) => (&window[..index], &window[index + 1..]),
+                None => bail!("Expected HH:MM-HH:MM, got {}", window),
+            };
+            let (start, end) = (parse_time(start)?, parse_time(end)?);
+            if start == end {
+                bail!("Quiet window {} is empty", window);
+            }
+            windows.push((start, end));
+        }
+        Ok(Self { windows })
+    }
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Whether `time`, in seconds since midnight UTC, falls in a window.
+    pub fn contains(&self, time: u32) -> bool {
+        self.windows.iter().any(|&(start, end)| {
+            if start < end {
+                start <= time && time < end
+            } else {
+                time >= start || time < end
+            }
+        })
+    }
+
+    /// Whether the current time falls in a window.
+    pub fn is_quiet(&self) -> bool {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.contains((since_epoch.as_secs() % u64::from(SECS_PER_DAY)) as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_windows_wrap_around_midnight() {
+        let schedule: Schedule = "22:00-06:00, 12:00-12:30".parse().unwrap();
+        assert_eq!(schedule.windows, vec![(79200, 21600), (43200, 45000)]);
+        assert!(schedule.contains(23 * 3600));
+        assert!(schedule.contains(0));
+        assert!(!schedule.contains(6 * 3600));
+        assert!(schedule.contains(12 * 3600 + 29 * 60));
+        assert!(!schedule.contains(12 * 3600 + 30 * 60));
+        assert!("25:00-01:00".parse::<Schedule>().is_err());
+        assert!("10:00".parse::<Schedule>().is_err());
+        assert!("10:00-10:00".parse::<Schedule>().is_err());
+        assert!("".parse::<Schedule>().unwrap().is_empty());
+    }
+}
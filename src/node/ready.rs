@@ -0,0 +1,96 @@
+//! Waiting until the mesh can deliver.
+//!
+//! A freshly started node has no peers, and messages published before it
+//! joined a topic mesh are lost. [`NodeHandle::wait_ready`] resolves once
+//! all [`Criteria`] hold: enough connected peers, enough mesh peers on each
+//! given topic, the [`bootstrap`] complete and an external address known.
+//! The last is what stands in for NAT status: an address peers observed us
+//! at, reported through identify, means someone reached us or we reached
+//! out through the NAT and learned our address behind it. Wrap the call in a
+//! timeout to give up.
+//!
+//! [`NodeHandle::wait_ready`]: crate::node::NodeHandle::wait_ready
+//! [`bootstrap`]: crate::node::bootstrap
+
+use std::collections::BTreeMap;
+
+/// What must hold for the mesh to be ready. The default holds right away.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Criteria {
+    /// Connected peers.
+    pub peers:            usize,
+    /// Mesh peers by topic.
+    pub topics:           BTreeMap<String, usize>,
+    pub bootstrapped:     bool,
+    pub external_address: bool,
+}
+
+impl Criteria {
+    /// Wait for `count` connected peers.
+    pub fn peers(mut self, count: usize) -> Self {
+        self.peers = count;
+        self
+    }
+
+    /// Wait for `count` peers in our mesh for `topic`. May be repeated.
+    pub fn topic(mut self, topic: &str, count: usize) -> Self {
+        self.topics.insert(topic.to_owned(), count);
+        self
+    }
+
+    /// Wait for the bootstrap to complete.
+    pub fn bootstrapped(mut self) -> Self {
+        self.bootstrapped = true;
+        self
+    }
+
+    /// Wait for an address peers observed us at.
+    pub fn external_address(mut self) -> Self {
+        self.external_address = true;
+        self
+    }
+
+    pub fn is_met(&self, status: &Status) -> bool {
+        status.peers >= self.peers
+            && self.topics.iter().all(|(topic, count)| {
+                status.topics.get(topic).copied().unwrap_or_default() >= *count
+            })
+            && (status.bootstrapped || !self.bootstrapped)
+            && (status.external_address || !self.external_address)
+    }
+}
+
+/// The state of the node the criteria are checked against.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Status {
+    pub peers:            usize,
+    /// Mesh peers on the topics of the criteria.
+    pub topics:           BTreeMap<String, usize>,
+    pub bootstrapped:     bool,
+    pub external_address: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_holds_once_all_criteria_hold() {
+        let criteria = Criteria::default()
+            .peers(2)
+            .topic("chat", 1)
+            .bootstrapped();
+        let mut status = Status {
+            peers: 3,
+            bootstrapped: true,
+            ..Status::default()
+        };
+        assert!(Criteria::default().is_met(&status));
+        assert!(!criteria.is_met(&status));
+        status.topics.insert("chat".into(), 1);
+        assert!(criteria.is_met(&status));
+        assert!(!criteria.clone().external_address().is_met(&status));
+        status.bootstrapped = false;
+        assert!(!criteria.is_met(&status));
+    }
+}
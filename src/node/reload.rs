@@ -0,0 +1,141 @@
+//! Applying a changed config without restarting.
+//!
+//! On `SIGHUP` the node reads `--config` again, merged with the environment
+//! and command line as on start, and applies what changed live, without
+//! dropping connections:
+//!
+//! * topics added to `topic` are subscribed, removed ones unsubscribed,
+//! * new `listen` addresses are listened on,
+//! * new `bootstrap` peers are dialed, new `critical` and `pin` peers kept
+//!   connected and new `rendezvous` points registered at,
+//! * `verbose` sets the log levels.
+//!
+//! Peers and addresses removed from the config stay until the next
+//! restart, which is logged, as are invalid configs, which change nothing.
+
+use crate::prelude::*;
+use libp2p::Multiaddr;
+use std::fmt;
+
+/// The options that can change while running.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Config {
+    pub topics:     Vec<String>,
+    pub listen:     Vec<Multiaddr>,
+    pub bootstrap:  Vec<Multiaddr>,
+    pub critical:   Vec<Multiaddr>,
+    pub pinned:     Vec<Multiaddr>,
+    pub rendezvous: Vec<Multiaddr>,
+}
+
+/// What applying a config changes, see [`Config::diff`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Changes {
+    pub subscribe:   Vec<String>,
+    pub unsubscribe: Vec<String>,
+    pub listen:      Vec<Multiaddr>,
+    pub bootstrap:   Vec<Multiaddr>,
+    pub critical:    Vec<Multiaddr>,
+    pub pinned:      Vec<Multiaddr>,
+    pub rendezvous:  Vec<Multiaddr>,
+    /// Options with removals that only apply on restart.
+    pub restart:     Vec<&'static str>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The items of `new` not in `old`, and whether any of `old` is missing
+/// from `new`.
+fn added<T: Clone + PartialEq>(old: &[T], new: &[T]) -> (Vec<T>, bool) {
+    let added = new
+        .iter()
+        .filter(|item| !old.contains(item))
+        .cloned()
+        .collect();
+    (added, old.iter().any(|item| !new.contains(item)))
+}
+
+impl Config {
+    /// The changes from `self` to `new`.
+    pub fn diff(&self, new: &Self) -> Changes {
+        let mut changes = Changes::default();
+        let (subscribe, _) = added(&self.topics, &new.topics);
+        let (unsubscribe, _) = added(&new.topics, &self.topics);
+        changes.subscribe = subscribe;
+        changes.unsubscribe = unsubscribe;
+        let peers = [
+            ("listen", &self.listen, &new.listen, &mut changes.listen),
+            ("bootstrap", &self.bootstrap, &new.bootstrap, &mut changes.bootstrap),
+            ("critical", &self.critical, &new.critical, &mut changes.critical),
+            ("pin", &self.pinned, &new.pinned, &mut changes.pinned),
+            ("rendezvous", &self.rendezvous, &new.rendezvous, &mut changes.rendezvous),
+        ];
+        for (option, old, new, changed) in peers {
+            let (addresses, removed) = added(old, new);
+            *changed = addresses;
+            if removed {
+                changes.restart.push(option);
+            }
+        }
+        changes
+    }
+}
+
+/// Reads the config again, set up by the binary that parsed it.
+pub struct Source(Box<dyn FnMut() -> Result<Config> + Send>);
+
+impl Source {
+    pub fn new(load: impl FnMut() -> Result<Config> + Send + 'static) -> Self {
+        Self(Box::new(load))
+    }
+
+    pub fn load(&mut self) -> Result<Config> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Source")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_diffs_configs() {
+        let address = |port: u16| -> Multiaddr {
+            format!("/ip4/10.0.0.1/tcp/{}", port).parse().unwrap()
+        };
+        let old = Config {
+            topics:    vec!["chat".into(), "orders".into()],
+            listen:    vec![address(4001)],
+            bootstrap: vec![address(5001)],
+            ..Config::default()
+        };
+        assert!(old.diff(&old).is_empty());
+        let new = Config {
+            topics:   vec!["orders".into(), "news".into()],
+            listen:   vec![address(4001), address(4002)],
+            critical: vec![address(6001)],
+            ..Config::default()
+        };
+        assert_eq!(old.diff(&new), Changes {
+            subscribe:   vec!["news".into()],
+            unsubscribe: vec!["chat".into()],
+            listen:      vec![address(4002)],
+            bootstrap:   vec![],
+            critical:    vec![address(6001)],
+            pinned:      vec![],
+            rendezvous:  vec![],
+            restart:     vec!["bootstrap"],
+        });
+    }
+}
@@ -0,0 +1,330 @@
+//! Finding the peers of a topic through rendezvous points.
+//!
+//! mDNS only reaches the local network and the DHT only finds providers of
+//! content, so nodes of a dynamic WAN swarm meet at rendezvous points
+//! instead. A node started with `--rendezvous-server` keeps a [`Registry`]
+//! of the peers that registered under a namespace and answers discoveries
+//! with their addresses. Nodes given the point with `--rendezvous
+//! <address>/p2p/<peer id>` register under the names of their subscribed
+//! topics for [`TTL`], again every half of it, and every
+//! [`DISCOVER_INTERVAL`] ask the point for the other registrants of each
+//! topic, whom they dial. Unsubscribing unregisters.
+//!
+//! A registration always holds the peer id of the connection it came in
+//! on, so peers can only register themselves. Points keep at most
+//! [`MAX_REGISTRATIONS`] per namespace and [`MAX_NAMESPACES`] per peer.
+
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// How long a registration lasts.
+pub const TTL: Duration = Duration::from_secs(2 * 3600);
+
+/// Time between discoveries of the registrants of a topic.
+pub const DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Registrations a point keeps per namespace.
+pub const MAX_REGISTRATIONS: usize = 1000;
+
+/// Namespaces a peer may register under at one point.
+pub const MAX_NAMESPACES: usize = 256;
+
+/// Longest namespace, in bytes.
+pub const MAX_NAMESPACE: usize = 255;
+
+/// Addresses kept per registration.
+pub const MAX_ADDRESSES: usize = 16;
+
+/// Registrations returned by one discovery at most.
+pub const MAX_DISCOVERED: usize = 100;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Register {
+        namespace: String,
+        addresses: Vec<Multiaddr>,
+        ttl_secs:  u64,
+    },
+    Unregister {
+        namespace: String,
+    },
+    Discover {
+        namespace: String,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// Registered for this many seconds.
+    Registered(u64),
+    Unregistered,
+    Registrations(Vec<Registration>),
+    Refused(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Registration {
+    #[serde(with = "serde_bytes")]
+    pub peer:      Vec<u8>,
+    pub addresses: Vec<Multiaddr>,
+}
+
+impl Registration {
+    pub fn peer_id(&self) -> Result<PeerId> {
+        PeerId::from_bytes(self.peer.clone()).map_err(|_| anyhow!("Invalid peer id"))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    addresses: Vec<Multiaddr>,
+    expires:   Instant,
+}
+
+/// The registrations of a rendezvous point.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    namespaces: HashMap<String, HashMap<PeerId, Entry>>,
+}
+
+impl Registry {
+    /// Answer `request` from `peer`.
+    pub fn handle(&mut self, peer: &PeerId, request: Request, now: Instant) -> Response {
+        self.expire(now);
+        match request {
+            Request::Register {
+                namespace,
+                addresses,
+                ttl_secs,
+            } => {
+                match self.register(peer, namespace, addresses, ttl_secs, now) {
+                    Ok(ttl) => Response::Registered(ttl.as_secs()),
+                    Err(err) => Response::Refused(err.to_string()),
+                }
+            }
+            Request::Unregister { namespace } => {
+                if let Some(peers) = self.namespaces.get_mut(&namespace) {
+                    peers.remove(peer);
+                    if peers.is_empty() {
+                        self.namespaces.remove(&namespace);
+                    }
+                }
+                Response::Unregistered
+            }
+            Request::Discover { namespace } => {
+                let registrations = self
+                    .namespaces
+                    .get(&namespace)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(registered, _)| *registered != peer)
+                    .take(MAX_DISCOVERED)
+                    .map(|(registered, entry)| {
+                        Registration {
+                            peer:      registered.as_bytes().to_vec(),
+                            addresses: entry.addresses.clone(),
+                        }
+                    })
+                    .collect();
+                Response::Registrations(registrations)
+            }
+        }
+    }
+
+    fn register(
+        &mut self,
+        peer: &PeerId,
+        namespace: String,
+        mut addresses: Vec<Multiaddr>,
+        ttl_secs: u64,
+        now: Instant,
+    ) -> Result<Duration> {
+        ensure!(!namespace.is_empty(), "Empty namespace");
+        ensure!(namespace.len() <= MAX_NAMESPACE, "Namespace over {} bytes", MAX_NAMESPACE);
+        ensure!(!addresses.is_empty(), "No addresses");
+        let registered = self
+            .namespaces
+            .values()
+            .filter(|peers| peers.contains_key(peer))
+            .count();
+        let peers = self.namespaces.get(&namespace);
+        let renewal = peers.map_or(false, |peers| peers.contains_key(peer));
+        if !renewal {
+            if registered >= MAX_NAMESPACES {
+                bail!("Registered under {} namespaces already", MAX_NAMESPACES);
+            }
+            if peers.map_or(0, HashMap::len) >= MAX_REGISTRATIONS {
+                bail!("Namespace {} is full", namespace);
+            }
+        }
+        addresses.truncate(MAX_ADDRESSES);
+        let ttl = Duration::from_secs(ttl_secs).min(TTL);
+        self.namespaces
+            .entry(namespace)
+            .or_default()
+            .insert(peer.clone(), Entry {
+                addresses,
+                expires: now + ttl,
+            });
+        Ok(ttl)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.namespaces.retain(|_, peers| {
+            peers.retain(|_, entry| entry.expires > now);
+            !peers.is_empty()
+        });
+    }
+}
+
+/// What a node asks its rendezvous points when.
+#[derive(Clone, Debug, Default)]
+pub struct Client {
+    points:        Vec<PeerId>,
+    /// When to register again under each namespace at each point.
+    registered:    HashMap<(PeerId, String), Instant>,
+    next_discover: Option<Instant>,
+    /// When to dial each point again while not connected.
+    next_dial:     HashMap<PeerId, Instant>,
+}
+
+impl Client {
+    pub fn add_point(&mut self, peer_id: PeerId) {
+        if !self.points.contains(&peer_id) {
+            self.points.push(peer_id);
+        }
+    }
+
+    pub fn points(&self) -> &[PeerId] {
+        &self.points
+    }
+
+    /// The points to dial at `now`, once every [`DISCOVER_INTERVAL`] while
+    /// not `connected`.
+    pub fn dials(&mut self, connected: impl Fn(&PeerId) -> bool, now: Instant) -> Vec<PeerId> {
+        let mut dials = Vec::new();
+        for point in self.points.iter().filter(|point| !connected(point)) {
+            let next = self.next_dial.entry(point.clone()).or_insert(now);
+            if now >= *next {
+                *next = now + DISCOVER_INTERVAL;
+                dials.push(point.clone());
+            }
+        }
+        dials
+    }
+
+    /// The requests due at `now` for the `connected` points, to be
+    /// registered under `namespaces` there.
+    pub fn due(
+        &mut self,
+        namespaces: &BTreeSet<String>,
+        addresses: &[Multiaddr],
+        connected: impl Fn(&PeerId) -> bool,
+        now: Instant,
+    ) -> Vec<(PeerId, Request)> {
+        let mut requests = Vec::new();
+        let points = self
+            .points
+            .iter()
+            .filter(|point| connected(point))
+            .collect::<Vec<_>>();
+        let discover = !points.is_empty() && self.next_discover.map_or(true, |next| now >= next);
+        if discover {
+            self.next_discover = Some(now + DISCOVER_INTERVAL);
+        }
+        for point in points {
+            for namespace in namespaces {
+                let key = (point.clone(), namespace.clone());
+                let due = self.registered.get(&key).map_or(true, |next| now >= *next);
+                if due && !addresses.is_empty() {
+                    self.registered.insert(key, now + TTL / 2);
+                    requests.push((point.clone(), Request::Register {
+                        namespace: namespace.clone(),
+                        addresses: addresses.to_vec(),
+                        ttl_secs:  TTL.as_secs(),
+                    }));
+                }
+                if discover {
+                    requests.push((point.clone(), Request::Discover {
+                        namespace: namespace.clone(),
+                    }));
+                }
+            }
+        }
+        let registered = &mut self.registered;
+        let unregister = registered
+            .keys()
+            .filter(|(_, namespace)| !namespaces.contains(namespace))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (point, namespace) in unregister {
+            registered.remove(&(point.clone(), namespace.clone()));
+            requests.push((point, Request::Unregister { namespace }));
+        }
+        requests
+    }
+
+    /// Register again at `point` soon, as a request to it failed.
+    pub fn failed(&mut self, point: &PeerId) {
+        self.registered.retain(|(registered, _), _| registered != point);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_registers_and_discovers() {
+        let (point, alice, bob) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/198.51.100.1/tcp/4001".parse().unwrap();
+        let now = Instant::now();
+        let namespaces = vec!["chat".to_owned()].into_iter().collect::<BTreeSet<_>>();
+        let mut client = Client::default();
+        client.add_point(point.clone());
+        assert_eq!(client.dials(|_| false, now), vec![point.clone()]);
+        assert!(client.dials(|_| false, now).is_empty());
+        assert_eq!(client.due(&namespaces, &[address.clone()], |_| false, now), vec![]);
+        let requests = client.due(&namespaces, &[address.clone()], |_| true, now);
+        assert_eq!(requests.len(), 2);
+        assert!(client.due(&namespaces, &[address.clone()], |_| true, now).is_empty());
+
+        let mut registry = Registry::default();
+        for (_, request) in requests {
+            registry.handle(&alice, request, now);
+        }
+        let register = Request::Register {
+            namespace: "chat".into(),
+            addresses: vec![address.clone()],
+            ttl_secs:  60,
+        };
+        assert_eq!(registry.handle(&bob, register, now), Response::Registered(60));
+        let discover = Request::Discover {
+            namespace: "chat".into(),
+        };
+        let found = match registry.handle(&bob, discover.clone(), now) {
+            Response::Registrations(found) => found,
+            response => panic!("Unexpected {:?}", response),
+        };
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].peer_id().unwrap(), alice);
+        assert_eq!(found[0].addresses, vec![address.clone()]);
+
+        // Registrations expire, and end when the topic is left
+        let later = now + Duration::from_secs(61);
+        match registry.handle(&alice, discover, later) {
+            Response::Registrations(found) => assert!(found.is_empty()),
+            response => panic!("Unexpected {:?}", response),
+        }
+        let requests = client.due(&BTreeSet::new(), &[address], |_| true, later);
+        assert_eq!(requests, vec![(point, Request::Unregister {
+            namespace: "chat".into(),
+        })]);
+    }
+}
@@ -0,0 +1,487 @@
+//! Replicated key-value maps.
+//!
+//! [`super::NodeHandle::state`] gives a handle to the map of a name, shared
+//! by the nodes that use it. The map is a last-writer-wins CRDT: each entry
+//! carries the hybrid logical [`Timestamp`] of its write and the writer's
+//! peer id, which breaks ties, and removing a key leaves a tombstone so an
+//! older write arriving late does not bring it back. Merging is commutative,
+//! associative and idempotent, so replicas that saw the same writes hold the
+//! same map whatever the order they saw them in.
+//!
+//! Local writes go out as a [`Message::Delta`] on the [`topic`] of the map.
+//! For anti-entropy, the node publishes a [`Message::Digest`] of the versions
+//! it holds when it starts using a map and when a peer subscribes to it, at
+//! most every [`DIGEST_INTERVAL`]. Receivers answer with a delta of the
+//! entries the digest lacks or holds older, and with a digest of their own
+//! if it names entries they lack, so a joining node and the mesh converge.
+//! Deltas and digests are split to stay below the pubsub message size.
+//!
+//! Entries timestamped over [`MAX_DRIFT`] ahead of our clock are refused,
+//! so one peer can not claim a key forever. Tombstones are kept.
+
+use super::{hlc::Timestamp, NodeHandle};
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    time::{Duration, Instant},
+};
+
+/// Least time between our digests of a map.
+pub const DIGEST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far ahead of our clock an entry may be.
+pub const MAX_DRIFT: Duration = Duration::from_secs(60);
+
+/// Longest map name.
+pub const MAX_NAME: usize = 64;
+
+/// Longest key.
+pub const MAX_KEY: usize = 256;
+
+/// Largest value.
+pub const MAX_VALUE: usize = 64 * 1024;
+
+/// Most entries of a map, tombstones included.
+pub const MAX_ENTRIES: usize = 10_000;
+
+/// Most bytes of keys and values in one message.
+const CHUNK: usize = 128 * 1024;
+
+/// Topic on which `map` is replicated.
+pub fn topic(map: &str) -> String {
+    format!("/mesh-rs/state/{}/version/1", map)
+}
+
+/// The map replicated on `topic`, if it is a state topic.
+fn map_of(topic: &str) -> Option<&str> {
+    topic
+        .strip_prefix("/mesh-rs/state/")
+        .and_then(|rest| rest.strip_suffix("/version/1"))
+}
+
+pub fn validate(map: &str) -> Result<()> {
+    ensure!(!map.is_empty(), "Empty map name");
+    ensure!(map.len() <= MAX_NAME, "Map name over {} bytes", MAX_NAME);
+    ensure!(!map.contains('/'), "Map name {} contains a slash", map);
+    Ok(())
+}
+
+/// When and by whom an entry was written. Later versions win.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub struct Version {
+    pub timestamp: Timestamp,
+    /// Peer id of the writer.
+    pub writer:    ByteBuf,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub version: Version,
+    /// `None` for a removed key.
+    pub value:   Option<ByteBuf>,
+}
+
+impl Entry {
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.value.as_ref().map_or(0, |value| value.len())
+    }
+}
+
+/// What replicas publish on the topic of a map.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Message {
+    /// Entries to merge.
+    Delta(BTreeMap<String, Entry>),
+    /// The versions held of the keys after `after` through `through`, each
+    /// unbounded if `None`.
+    Digest {
+        after:    Option<String>,
+        through:  Option<String>,
+        versions: BTreeMap<String, Version>,
+    },
+}
+
+/// Split `items` into runs of at most [`CHUNK`] bytes, or one item.
+fn chunks<T: Clone>(
+    items: impl Iterator<Item = (String, T)>,
+    size: impl Fn(&str, &T) -> usize,
+) -> Vec<BTreeMap<String, T>> {
+    let mut chunks = Vec::new();
+    let mut chunk = BTreeMap::new();
+    let mut bytes = 0;
+    for (key, item) in items {
+        let item_size = size(&key, &item);
+        if !chunk.is_empty() && bytes + item_size > CHUNK {
+            chunks.push(std::mem::take(&mut chunk));
+            bytes = 0;
+        }
+        bytes += item_size;
+        chunk.insert(key, item);
+    }
+    chunks.push(chunk);
+    chunks
+}
+
+/// One replicated map.
+#[derive(Clone, Debug, Default)]
+pub struct Replica {
+    entries:       BTreeMap<String, Entry>,
+    /// A digest should go out.
+    digest_wanted: bool,
+    last_digest:   Option<Instant>,
+}
+
+impl Replica {
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .get(key)
+            .and_then(|entry| entry.value.as_deref())
+            .map(Vec::as_slice)
+    }
+
+    /// The keys and values, without removed keys.
+    pub fn entries(&self) -> BTreeMap<String, Vec<u8>> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| Some((key.clone(), entry.value.as_ref()?.to_vec())))
+            .collect()
+    }
+
+    /// Write `value`, or remove `key` without one, as `version`. Returns the
+    /// delta to publish.
+    pub fn write(
+        &mut self,
+        key: &str,
+        value: Option<Vec<u8>>,
+        version: Version,
+    ) -> Result<Message> {
+        let entry = Entry {
+            version,
+            value: value.map(ByteBuf::from),
+        };
+        let mut delta = BTreeMap::new();
+        delta.insert(key.to_owned(), entry);
+        self.merge(delta.clone(), Timestamp::default())?;
+        Ok(Message::Delta(delta))
+    }
+
+    /// Merge a received delta, refusing entries over [`MAX_DRIFT`] ahead of
+    /// `now`, a timestamp of ours. Returns the keys that changed and their
+    /// new values. The zero timestamp skips the drift check for our own
+    /// writes.
+    pub fn merge(
+        &mut self,
+        delta: BTreeMap<String, Entry>,
+        now: Timestamp,
+    ) -> Result<Vec<(String, Option<Vec<u8>>)>> {
+        for (key, entry) in &delta {
+            ensure!(key.len() <= MAX_KEY, "Key over {} bytes", MAX_KEY);
+            if let Some(value) = &entry.value {
+                ensure!(value.len() <= MAX_VALUE, "Value of {} over {} bytes", key, MAX_VALUE);
+            }
+            if now != Timestamp::default() {
+                let limit_ms = now.wall_ms.saturating_add(MAX_DRIFT.as_millis() as u64);
+                ensure!(
+                    entry.version.timestamp.wall_ms <= limit_ms,
+                    "Entry {} is {}ms ahead of our clock",
+                    key,
+                    entry.version.timestamp.wall_ms - now.wall_ms
+                );
+            }
+        }
+        let mut changed = Vec::new();
+        for (key, entry) in delta {
+            match self.entries.get(&key) {
+                Some(current) if current.version >= entry.version => continue,
+                Some(_) => {}
+                None if self.entries.len() >= MAX_ENTRIES => {
+                    bail!("Over {} entries", MAX_ENTRIES);
+                }
+                None => {}
+            }
+            changed.push((key.clone(), entry.value.as_ref().map(|value| value.to_vec())));
+            self.entries.insert(key, entry);
+        }
+        Ok(changed)
+    }
+
+    /// The deltas answering a digest: our entries in its range that it lacks
+    /// or holds older. Wants a digest of ours if it holds entries newer than
+    /// ours or that we lack.
+    pub fn answer(
+        &mut self,
+        after: Option<&str>,
+        through: Option<&str>,
+        versions: &BTreeMap<String, Version>,
+    ) -> Vec<Message> {
+        if matches!((after, through), (Some(after), Some(through)) if after > through) {
+            return Vec::new();
+        }
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let upper = through.map_or(Bound::Unbounded, Bound::Included);
+        if versions.iter().any(|(key, version)| {
+            !matches!(self.entries.get(key), Some(entry) if entry.version >= *version)
+        }) {
+            self.digest_wanted = true;
+        }
+        let newer = self
+            .entries
+            .range::<str, _>((lower, upper))
+            .filter(|(key, entry)| {
+                !matches!(versions.get(*key), Some(version) if *version >= entry.version)
+            })
+            .map(|(key, entry)| (key.clone(), entry.clone()));
+        chunks(newer, |key, entry| entry.size(key))
+            .into_iter()
+            .filter(|delta| !delta.is_empty())
+            .map(Message::Delta)
+            .collect()
+    }
+
+    /// Send a digest once [`DIGEST_INTERVAL`] passed since the last one.
+    pub fn want_digest(&mut self) {
+        self.digest_wanted = true;
+    }
+
+    /// Our digest, if wanted and due at `now`, split in key ranges.
+    pub fn digest(&mut self, now: Instant) -> Vec<Message> {
+        if !self.digest_wanted
+            || matches!(self.last_digest, Some(last) if now < last + DIGEST_INTERVAL)
+        {
+            return Vec::new();
+        }
+        self.digest_wanted = false;
+        self.last_digest = Some(now);
+        let versions = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.version.clone()));
+        let chunks = chunks(versions, |key, version| key.len() + version.writer.len() + 16);
+        let count = chunks.len();
+        let mut after = None;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, versions)| {
+                let through = if index + 1 == count {
+                    None
+                } else {
+                    versions.keys().next_back().cloned()
+                };
+                let digest = Message::Digest {
+                    after: after.clone(),
+                    through: through.clone(),
+                    versions,
+                };
+                after = through;
+                digest
+            })
+            .collect()
+    }
+}
+
+/// The maps in use.
+#[derive(Clone, Debug, Default)]
+pub struct Replicas {
+    maps: HashMap<String, Replica>,
+}
+
+impl Replicas {
+    pub fn get(&self, map: &str) -> Option<&Replica> {
+        self.maps.get(map)
+    }
+
+    /// The replica of `map`, created if new. Returns whether it is.
+    pub fn join(&mut self, map: &str) -> Result<(&mut Replica, bool)> {
+        validate(map)?;
+        let new = !self.maps.contains_key(map);
+        let replica = self.maps.entry(map.to_owned()).or_default();
+        if new {
+            replica.want_digest();
+        }
+        Ok((replica, new))
+    }
+
+    /// The map replicated on `topic`, if we use it.
+    pub fn on_topic(&mut self, topic: &str) -> Option<(String, &mut Replica)> {
+        let map = map_of(topic)?;
+        let replica = self.maps.get_mut(map)?;
+        Some((map.to_owned(), replica))
+    }
+
+    /// Our digests due at `now`, with the topics to publish them on.
+    pub fn digests(&mut self, now: Instant) -> Vec<(String, Message)> {
+        self.maps
+            .iter_mut()
+            .flat_map(|(map, replica)| {
+                let topic = topic(map);
+                replica
+                    .digest(now)
+                    .into_iter()
+                    .map(move |message| (topic.clone(), message))
+            })
+            .collect()
+    }
+}
+
+/// A replicated map, see [`NodeHandle::state`].
+#[derive(Clone)]
+pub struct SharedState {
+    handle: NodeHandle,
+    map:    String,
+}
+
+impl SharedState {
+    pub(crate) fn new(handle: NodeHandle, map: &str) -> Self {
+        Self {
+            handle,
+            map: map.to_owned(),
+        }
+    }
+
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.handle.get_state(&self.map, key).await
+    }
+
+    pub async fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.handle
+            .set_state(&self.map, key, Some(value.to_vec()))
+            .await
+    }
+
+    pub async fn remove(&mut self, key: &str) -> Result<()> {
+        self.handle.set_state(&self.map, key, None).await
+    }
+
+    /// The keys and values held now.
+    pub async fn entries(&mut self) -> Result<BTreeMap<String, Vec<u8>>> {
+        self.handle.state_entries(&self.map).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn version(wall_ms: u64, writer: u8) -> Version {
+        Version {
+            timestamp: Timestamp {
+                wall_ms,
+                logical: 0,
+            },
+            writer:    ByteBuf::from(vec![writer]),
+        }
+    }
+
+    fn delta(message: Message) -> BTreeMap<String, Entry> {
+        match message {
+            Message::Delta(delta) => delta,
+            Message::Digest { .. } => panic!("Expected a delta"),
+        }
+    }
+
+    #[test]
+    fn test_converges_whatever_the_order() {
+        let (mut a, mut b) = (Replica::default(), Replica::default());
+        let set = a.write("color", Some(b"red".to_vec()), version(1, 1)).unwrap();
+        let later = b.write("color", Some(b"blue".to_vec()), version(2, 2)).unwrap();
+        let tie = b.write("size", Some(b"small".to_vec()), version(3, 2)).unwrap();
+        let removed = a.write("size", None, version(3, 3)).unwrap();
+
+        let now = version(10, 0).timestamp;
+        a.merge(delta(later.clone()), now).unwrap();
+        a.merge(delta(tie.clone()), now).unwrap();
+        b.merge(delta(removed.clone()), now).unwrap();
+        b.merge(delta(set.clone()), now).unwrap();
+        assert_eq!(a.entries(), b.entries());
+        assert_eq!(a.get("color"), Some(&b"blue"[..]));
+        assert_eq!(a.get("size"), None);
+
+        // Idempotent, and stale writes change nothing
+        assert!(a.merge(delta(later), now).unwrap().is_empty());
+        assert!(b.merge(delta(set), now).unwrap().is_empty());
+
+        // Not from the future
+        let future = delta(a.write("x", None, version(100_000, 1)).unwrap());
+        assert!(b.merge(future, now).is_err());
+        let long = "k".repeat(MAX_KEY + 1);
+        assert!(a.write(&long, None, version(4, 1)).is_err());
+    }
+
+    #[test]
+    fn test_anti_entropy_fills_in_both_sides() {
+        let (mut old, mut new) = (Replica::default(), Replica::default());
+        old.write("a", Some(b"1".to_vec()), version(1, 1)).unwrap();
+        old.write("b", Some(b"2".to_vec()), version(2, 1)).unwrap();
+        new.write("b", Some(b"3".to_vec()), version(5, 2)).unwrap();
+        new.write("c", Some(b"4".to_vec()), version(6, 2)).unwrap();
+        let now = version(10, 0).timestamp;
+        let start = Instant::now();
+
+        // The mesh sends its digest, the new node fills it in
+        old.want_digest();
+        let digest = old.digest(start);
+        assert_eq!(digest.len(), 1);
+        assert!(old.digest(start).is_empty());
+        for message in digest {
+            if let Message::Digest {
+                after,
+                through,
+                versions,
+            } = message
+            {
+                for reply in new.answer(after.as_deref(), through.as_deref(), &versions) {
+                    old.merge(delta(reply), now).unwrap();
+                }
+            }
+        }
+        // ... and asks for what it lacks with its own digest
+        for message in new.digest(start) {
+            if let Message::Digest {
+                after,
+                through,
+                versions,
+            } = message
+            {
+                for reply in old.answer(after.as_deref(), through.as_deref(), &versions) {
+                    new.merge(delta(reply), now).unwrap();
+                }
+            }
+        }
+        assert_eq!(old.entries(), new.entries());
+        assert_eq!(new.get("a"), Some(&b"1"[..]));
+        assert_eq!(old.get("b"), Some(&b"3"[..]));
+    }
+
+    #[test]
+    fn test_splits_large_digests_by_range() {
+        let mut replica = Replica::default();
+        let value = vec![0; MAX_VALUE / 2];
+        for index in 0..4 {
+            let key = format!("key-{}", index);
+            replica
+                .write(&key, Some(value.clone()), version(index + 1, 1))
+                .unwrap();
+        }
+        let answer = replica.answer(None, None, &BTreeMap::new());
+        assert_eq!(answer.len(), 2);
+        let answer = replica.answer(Some("key-1"), Some("key-2"), &BTreeMap::new());
+        assert_eq!(delta(answer[0].clone()).keys().collect::<Vec<_>>(), vec!["key-2"]);
+
+        let mut replicas = Replicas::default();
+        assert!(replicas.join("a/b").is_err());
+        assert!(replicas.join("settings").unwrap().1);
+        assert!(!replicas.join("settings").unwrap().1);
+        assert!(replicas.on_topic(&topic("settings")).is_some());
+        assert!(replicas.on_topic(&topic("other")).is_none());
+        assert_eq!(replicas.digests(Instant::now()).len(), 1);
+    }
+}
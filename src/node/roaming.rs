@@ -0,0 +1,118 @@
+//! Surviving changes of our own IP addresses.
+//!
+//! A mobile node roaming between networks keeps its process and peer id but
+//! loses its addresses, and with them every connection, which only time out
+//! much later. Every tick the node lists its interface addresses and, when
+//! they [changed](Change), it
+//!
+//! * redials its connected peers, critical ones included, from the new
+//!   address, so they learn our addresses and subscriptions again,
+//! * advertises the new addresses on the ports we listen on, and stops
+//!   advertising those it added for addresses that went away. The TCP
+//!   transport only lists interfaces once, when it starts listening.
+//! * resends direct messages that were not acknowledged before the old
+//!   connections died, see [`super::behaviour::direct`].
+//!
+//! Loopback and IPv6 link-local addresses are ignored, they do not take us
+//! anywhere new.
+
+use crate::prelude::*;
+use std::{collections::BTreeSet, net::IpAddr};
+
+/// Addresses that appeared and disappeared since the previous check.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Change {
+    pub added:   Vec<IpAddr>,
+    pub removed: Vec<IpAddr>,
+}
+
+fn is_routable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_link_local(),
+        IpAddr::V6(ip) => !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80,
+    }
+}
+
+/// Our routable interface addresses, if they can be listed.
+fn local_addresses() -> Option<BTreeSet<IpAddr>> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            Some(
+                interfaces
+                    .into_iter()
+                    .map(|interface| interface.ip())
+                    .filter(is_routable)
+                    .collect(),
+            )
+        }
+        Err(err) => {
+            warn!("Could not list interface addresses: {}", err);
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Watcher {
+    addresses: BTreeSet<IpAddr>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self {
+            addresses: local_addresses().unwrap_or_default(),
+        }
+    }
+
+    /// Compare our addresses with those of the previous check.
+    pub fn check(&mut self) -> Option<Change> {
+        self.update(local_addresses()?)
+    }
+
+    fn update(&mut self, addresses: BTreeSet<IpAddr>) -> Option<Change> {
+        if addresses == self.addresses {
+            return None;
+        }
+        let change = Change {
+            added:   addresses.difference(&self.addresses).cloned().collect(),
+            removed: self.addresses.difference(&addresses).cloned().collect(),
+        };
+        self.addresses = addresses;
+        Some(change)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_reports_changes() {
+        let wifi: IpAddr = "192.168.1.20".parse().unwrap();
+        let cellular: IpAddr = "10.64.3.7".parse().unwrap();
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        assert!(!is_routable(&"127.0.0.1".parse().unwrap()));
+        assert!(!is_routable(&"fe80::1".parse().unwrap()));
+        assert!(is_routable(&v6));
+
+        let mut watcher = Watcher::default();
+        let addresses = |ips: &[IpAddr]| ips.iter().cloned().collect::<BTreeSet<_>>();
+        assert_eq!(
+            watcher.update(addresses(&[wifi, v6])),
+            Some(Change {
+                added:   vec![wifi, v6],
+                removed: vec![],
+            })
+        );
+        assert_eq!(watcher.update(addresses(&[wifi, v6])), None);
+        // Leaving the wifi for the cellular network
+        assert_eq!(
+            watcher.update(addresses(&[cellular, v6])),
+            Some(Change {
+                added:   vec![cellular],
+                removed: vec![wifi],
+            })
+        );
+    }
+}
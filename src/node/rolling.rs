@@ -0,0 +1,205 @@
+//! Files rotated by size and age.
+//!
+//! Used for the log file and the event [`super::journal`], so nodes on
+//! devices without journald keep bounded output. Once the current file
+//! exceeds its size or age, it is renamed to `<path>.1` (compressed to
+//! `<path>.1.gz` by default), older files shift up by one, and all but the
+//! `keep` most recent are deleted.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use ubyte::{ByteUnit, ToByteUnit};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    pub path:     PathBuf,
+    /// Rotate once the file would grow beyond this.
+    pub size:     ByteUnit,
+    /// Rotate files older than this.
+    pub age:      Option<Duration>,
+    /// Number of rotated files to keep.
+    pub keep:     usize,
+    /// Compress rotated files with gzip.
+    pub compress: bool,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    /// `path` is required.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut path = None;
+        let mut config = Self {
+            path:     PathBuf::new(),
+            size:     10.mebibytes(),
+            age:      None,
+            keep:     5,
+            compress: true,
+        };
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "path" => path = Some(PathBuf::from(value)),
+                "size" => {
+                    config.size = value
+                        .parse()
+                        .map_err(|err| anyhow!("Invalid size {}: {}", value, err))?;
+                }
+                "age" => {
+                    config.age = Some(
+                        humantime::parse_duration(value)
+                            .with_context(|| format!("Invalid age {}", value))?,
+                    );
+                }
+                "keep" => {
+                    config.keep = value
+                        .parse()
+                        .with_context(|| format!("Invalid keep {}", value))?;
+                }
+                "compress" => {
+                    config.compress = value
+                        .parse()
+                        .with_context(|| format!("Invalid compress {}", value))?;
+                }
+                _ => bail!("Unknown rotation option {}", key),
+            }
+        }
+        config.path = path.ok_or_else(|| anyhow!("Missing path=<file>"))?;
+        if config.size.as_u64() == 0 {
+            bail!("Rotation size must be positive");
+        }
+        Ok(config)
+    }
+}
+
+pub struct RollingFile {
+    config: Config,
+    file:   File,
+    size:   u64,
+    opened: Instant,
+}
+
+impl RollingFile {
+    /// Append to the file at `config.path`, creating it and its directory if
+    /// needed.
+    pub fn open(config: Config) -> Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Creating directory {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("Opening {}", config.path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    /// Path of the `index`-th most recent rotated file.
+    pub fn rotated_path(&self, index: usize) -> PathBuf {
+        let suffix = if self.config.compress { ".gz" } else { "" };
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}{}", index, suffix));
+        path.into()
+    }
+
+    fn is_due(&self, len: usize) -> bool {
+        self.size > 0
+            && (self.size + len as u64 > self.config.size.as_u64()
+                || matches!(self.config.age, Some(age) if self.opened.elapsed() >= age))
+    }
+
+    /// Move the current file aside and start a new one.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.config.keep > 0 {
+            let oldest = self.rotated_path(self.config.keep);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for index in (1..self.config.keep).rev() {
+                let path = self.rotated_path(index);
+                if path.exists() {
+                    fs::rename(path, self.rotated_path(index + 1))?;
+                }
+            }
+            if self.config.compress {
+                let mut encoder =
+                    GzEncoder::new(File::create(self.rotated_path(1))?, Compression::default());
+                io::copy(&mut File::open(&self.config.path)?, &mut encoder)?;
+                encoder.finish()?;
+            } else {
+                fs::copy(&self.config.path, self.rotated_path(1))?;
+            }
+        }
+        self.file = File::create(&self.config.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    /// Writes all of `buf` to one file, so records written in one call are
+    /// never split across a rotation.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_due(buf.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("mesh-rolling-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("mesh.log");
+        let config: Config = format!("path={} size=10B keep=2", path.display())
+            .parse()
+            .unwrap();
+        assert!("size=10B".parse::<Config>().is_err());
+        let mut file = RollingFile::open(config).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        let mut rotated = String::new();
+        GzDecoder::new(File::open(file.rotated_path(1)).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "third\n");
+        assert!(file.rotated_path(2).exists());
+        assert!(!file.rotated_path(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
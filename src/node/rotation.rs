@@ -0,0 +1,215 @@
+//! Moving peers over to a rotated identity.
+//!
+//! `mesh-rs identity rotate` gives the node a new key and keeps the old one
+//! for a grace period, see [`super::keystore`]. Until it ends the node
+//! publishes a [`Migration`] on [`TOPIC`] once it has peers and every
+//! [`REPUBLISH`] after: the new peer id, signed with the old key. The name
+//! records it publishes carry the migration too, so resolving a name the
+//! old peer id claimed before does not fail as claimed by two peers.
+//!
+//! Receivers check the signature against the old key, move the address
+//! book and DHT entries of the old peer id to the new one, dial it and emit
+//! [`Event::IdentityRotated`]. Until the grace period ends, an address of
+//! the old peer id answering with the new one is accepted whatever the
+//! `--identity-mismatch` policy.
+//!
+//! [`Event::IdentityRotated`]: crate::node::Event::IdentityRotated
+
+use super::signing::{Domain, Layout};
+use crate::prelude::*;
+use anyhow::{anyhow, ensure};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Topic on which migrations are published.
+pub const TOPIC: &str = "/mesh-rs/rotation/version/1";
+
+/// Time between publishing our migration.
+pub const REPUBLISH: Duration = Duration::from_secs(3600);
+
+/// Most rotations of peers remembered.
+const REMEMBERED: usize = 1024;
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// An old identity vouching for the new one.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Migration {
+    /// Protobuf encoding of the old public key.
+    pub previous:  ByteBuf,
+    /// The new peer id.
+    pub current:   ByteBuf,
+    /// Milliseconds since the Unix epoch when the grace period ends.
+    pub until_ms:  u64,
+    pub signature: ByteBuf,
+}
+
+impl Migration {
+    fn signed_bytes(current: &[u8], until_ms: u64) -> Vec<u8> {
+        Layout::new(Domain::Rotation)
+            .timestamp(until_ms)
+            .header("current", current)
+            .to_bytes()
+    }
+
+    /// Sign over to `current` with the `previous` key, for the grace period
+    /// ending at `until`.
+    pub fn sign(previous: &identity::Keypair, current: &PeerId, until: SystemTime) -> Result<Self> {
+        let until_ms = epoch_ms(until);
+        let signature = previous
+            .sign(&Self::signed_bytes(current.as_bytes(), until_ms))
+            .map_err(|err| anyhow!("Signing migration: {:?}", err))?;
+        Ok(Self {
+            previous: ByteBuf::from(previous.public().into_protobuf_encoding()),
+            current: ByteBuf::from(current.as_bytes().to_vec()),
+            until_ms,
+            signature: ByteBuf::from(signature),
+        })
+    }
+
+    /// The old and the new peer id, if validly signed.
+    pub fn verify(&self) -> Result<(PeerId, PeerId)> {
+        let public = identity::PublicKey::from_protobuf_encoding(&self.previous)
+            .map_err(|err| anyhow!("Invalid public key: {:?}", err))?;
+        let current = PeerId::from_bytes(self.current.to_vec())
+            .map_err(|_| anyhow!("Invalid peer id in migration"))?;
+        ensure!(
+            public.verify(&Self::signed_bytes(&self.current, self.until_ms), &self.signature),
+            "Invalid signature"
+        );
+        let previous = PeerId::from(public);
+        ensure!(previous != current, "Migration to the same peer id");
+        Ok((previous, current))
+    }
+
+    /// When the grace period ends.
+    pub fn until(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.until_ms)
+    }
+}
+
+/// Our migration, and the rotations peers announced.
+#[derive(Clone, Debug, Default)]
+pub struct Rotations {
+    own:     Option<Migration>,
+    /// When to publish ours next.
+    next:    Option<Instant>,
+    /// The new peer id of each peer that rotated, and the end of its grace
+    /// period.
+    rotated: HashMap<PeerId, (PeerId, SystemTime)>,
+}
+
+impl Rotations {
+    /// Publish `migration` until its grace period ends.
+    pub fn set_own(&mut self, migration: Migration) {
+        self.own = Some(migration);
+        self.next = None;
+    }
+
+    /// Our migration, while its grace period lasts.
+    pub fn own(&self) -> Option<&Migration> {
+        self.own.as_ref()
+    }
+
+    /// Our migration to publish at `now`, if due.
+    pub fn due(&mut self, now: Instant, wall: SystemTime) -> Option<Result<Vec<u8>>> {
+        if matches!(&self.own, Some(own) if own.until() <= wall) {
+            info!("Grace period of the previous identity ended");
+            self.own = None;
+        }
+        let own = self.own.as_ref()?;
+        if matches!(self.next, Some(next) if now < next) {
+            return None;
+        }
+        Some(serde_cbor::to_vec(own).map_err(anyhow::Error::from))
+    }
+
+    /// Our migration went out at `now`.
+    pub fn published(&mut self, now: Instant) {
+        self.next = Some(now + REPUBLISH);
+    }
+
+    /// Accept a migration `now`. Returns the old and the new peer id if we
+    /// did not know about it.
+    pub fn receive(
+        &mut self,
+        migration: &Migration,
+        now: SystemTime,
+    ) -> Result<Option<(PeerId, PeerId)>> {
+        ensure!(migration.until() > now, "Grace period ended");
+        if let Some(own) = &self.own {
+            if own == migration {
+                return Ok(None);
+            }
+        }
+        let (previous, current) = migration.verify()?;
+        if matches!(self.rotated.get(&previous), Some((known, _)) if *known == current) {
+            return Ok(None);
+        }
+        self.rotated.retain(|_, (_, until)| *until > now);
+        ensure!(self.rotated.len() < REMEMBERED, "Over {} rotations remembered", REMEMBERED);
+        self.rotated
+            .insert(previous.clone(), (current.clone(), migration.until()));
+        Ok(Some((previous, current)))
+    }
+
+    /// The peer id `peer` rotated to, while its grace period lasts.
+    pub fn successor(&self, peer: &PeerId, now: SystemTime) -> Option<&PeerId> {
+        match self.rotated.get(peer) {
+            Some((current, until)) if *until > now => Some(current),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_accepts_migrations_signed_by_the_old_key() {
+        let old = identity::Keypair::generate_ed25519();
+        let new = identity::Keypair::generate_secp256k1();
+        let (old_id, new_id) = (PeerId::from(old.public()), PeerId::from(new.public()));
+        let now = SystemTime::now();
+        let until = now + Duration::from_secs(3600);
+        let migration = Migration::sign(&old, &new_id, until).unwrap();
+        assert_eq!(migration.verify().unwrap(), (old_id.clone(), new_id.clone()));
+
+        let mut rotation = Rotations::default();
+        assert_eq!(
+            rotation.receive(&migration, now).unwrap(),
+            Some((old_id.clone(), new_id.clone()))
+        );
+        assert_eq!(rotation.receive(&migration, now).unwrap(), None);
+        assert_eq!(rotation.successor(&old_id, now), Some(&new_id));
+        assert_eq!(rotation.successor(&old_id, until), None);
+        assert!(rotation.receive(&migration, until).is_err());
+
+        // Not signed by the old key, or pointing elsewhere
+        let forged = Migration::sign(&new, &new_id, until).unwrap();
+        assert!(forged.verify().is_err());
+        let mut tampered = migration.clone();
+        tampered.current = ByteBuf::from(PeerId::random().as_bytes().to_vec());
+        assert!(rotation.receive(&tampered, now).is_err());
+
+        // Published until the grace period ends
+        let start = Instant::now();
+        let mut own = Rotations::default();
+        own.set_own(migration);
+        assert!(own.due(start, now).unwrap().is_ok());
+        own.published(start);
+        assert!(own.due(start, now).is_none());
+        assert!(own.due(start + REPUBLISH, now).is_some());
+        assert!(own.due(start + REPUBLISH, until).is_none());
+        assert!(own.own().is_none());
+    }
+}
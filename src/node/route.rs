@@ -0,0 +1,147 @@
+//! Handlers by topic.
+//!
+//! Instead of every application looping over [`NodeHandle::events`] and
+//! matching topics, [`NodeHandle::route`] registers an async handler for a
+//! topic. The node passes each message on the topic to the route's task,
+//! which runs up to `limit` handler calls at once and takes the next message
+//! when one finishes. Messages still appear on the event stream.
+//!
+//! A route buffers [`BUFFER`] messages; beyond that the node drops messages
+//! for it with a warning, like for slow event consumers. Routing a topic
+//! again replaces its handler, and the old one finishes the messages it has.
+//! Routes do not subscribe, so subscribe to the topic as well.
+//!
+//! [`NodeHandle::events`]: crate::node::NodeHandle::events
+//! [`NodeHandle::route`]: crate::node::NodeHandle::route
+
+use super::{
+    behaviour::{envelope::Provenance, Event},
+    hlc::Timestamp,
+};
+use crate::prelude::*;
+use futures::channel::mpsc;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// Messages a route holds while its handlers are busy.
+pub const BUFFER: usize = 64;
+
+/// A message on a routed topic, as in
+/// [`Event::Message`](crate::node::Event::Message).
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub source:     PeerId,
+    pub topic:      String,
+    pub data:       Vec<u8>,
+    pub direct:     bool,
+    pub timestamp:  Option<Timestamp>,
+    pub provenance: Provenance,
+    pub signed:     bool,
+}
+
+impl From<Message> for Event {
+    fn from(message: Message) -> Self {
+        Self::Message {
+            source:     message.source,
+            topic:      message.topic,
+            data:       message.data,
+            direct:     message.direct,
+            timestamp:  message.timestamp,
+            provenance: message.provenance,
+            signed:     message.signed,
+        }
+    }
+}
+
+/// Start a task calling `handler` on the messages sent to the returned
+/// sender, at most `limit` at once.
+pub fn spawn<F, Fut>(limit: usize, handler: F) -> mpsc::Sender<Message>
+where
+    F: FnMut(Message) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    tokio::spawn(receiver.for_each_concurrent(limit.max(1), handler));
+    sender
+}
+
+#[derive(Default)]
+pub struct Routes {
+    senders: HashMap<String, mpsc::Sender<Message>>,
+}
+
+impl Routes {
+    pub fn insert(&mut self, topic: &str, sender: mpsc::Sender<Message>) {
+        self.senders.insert(topic.to_owned(), sender);
+    }
+
+    pub fn remove(&mut self, topic: &str) {
+        self.senders.remove(topic);
+    }
+
+    /// Hand `message` to the route of its topic, if any.
+    pub fn dispatch(&mut self, message: &Message) {
+        let sender = match self.senders.get_mut(&message.topic) {
+            Some(sender) => sender,
+            None => return,
+        };
+        if let Err(err) = sender.try_send(message.clone()) {
+            if err.is_disconnected() {
+                debug!("Route for {} stopped", message.topic);
+                self.senders.remove(&message.topic);
+            } else {
+                warn!("Route for {} is falling behind, dropping message", message.topic);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::time::{sleep, Duration};
+
+    #[tokio::test]
+    async fn test_limits_concurrent_handlers() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut routes = Routes::default();
+        routes.insert("jobs", {
+            let (running, most, handled) = (running.clone(), most.clone(), handled.clone());
+            spawn(2, move |_| {
+                let (running, most, handled) = (running.clone(), most.clone(), handled.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    handled.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        });
+        let message = |topic: &str| {
+            Message {
+                source:     PeerId::random(),
+                topic:      topic.to_owned(),
+                data:       Vec::new(),
+                direct:     false,
+                timestamp:  None,
+                provenance: Provenance::default(),
+                signed:     false,
+            }
+        };
+        for _ in 0..6 {
+            routes.dispatch(&message("jobs"));
+        }
+        routes.dispatch(&message("chat"));
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 6);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,304 @@
+//! Actions at the same moment on every node.
+//!
+//! [`super::NodeHandle::schedule`] publishes a signed [`Action`] for a wall
+//! clock time, and every node that accepts the issuer with
+//! [`super::NodeHandle::trust_scheduler`] emits
+//! [`Event::Scheduled`](crate::node::Event::Scheduled) when it is due. The
+//! action carries the issuer's hybrid logical clock, so a receiver whose
+//! clock is more than [`MAX_SKEW`] off the issuer's runs the action at the
+//! issuer's time rather than its own; smaller offsets are taken to be
+//! transit time. Deadlines are kept on the monotonic clock, so once an action
+//! is scheduled, jumps of the local wall clock do not move it.
+//!
+//! Actions are sent once. Nodes that join later do not learn about them, and
+//! actions arriving more than [`MAX_LATE`] after they were due are dropped.
+
+use super::{
+    hlc::Timestamp,
+    keyring, profile,
+    signing::{Domain, Layout},
+};
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::{identity, PeerId};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Topic on which actions are published.
+pub const TOPIC: &str = "/mesh-rs/schedule/version/1";
+
+/// Clock offsets to the issuer up to this are not corrected.
+pub const MAX_SKEW: Duration = Duration::from_millis(500);
+
+/// How long after it was due an action is still run.
+pub const MAX_LATE: Duration = Duration::from_secs(5);
+
+/// A named action at a time, signed by its issuer.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Action {
+    pub name:      String,
+    /// Milliseconds since the Unix epoch on the issuer's clock.
+    pub at_ms:     u64,
+    pub data:      ByteBuf,
+    /// The issuer's clock when scheduling.
+    pub issued:    Timestamp,
+    pub signature: ByteBuf,
+}
+
+impl Action {
+    fn signed_bytes(name: &str, at_ms: u64, data: &[u8], issued: Timestamp) -> Vec<u8> {
+        let issued = serde_cbor::to_vec(&issued).expect("Timestamps always encode");
+        Layout::new(Domain::Schedule)
+            .timestamp(at_ms)
+            .header("name", name.as_bytes())
+            .header("issued", issued)
+            .payload(data)
+            .to_bytes()
+    }
+
+    /// Whether the action is signed by `issuer`.
+    pub fn verify(&self, issuer: &PeerId) -> bool {
+        match keyring::public_key(issuer) {
+            Some(public) => {
+                public.verify(
+                    &Self::signed_bytes(&self.name, self.at_ms, &self.data, self.issued),
+                    &self.signature,
+                )
+            }
+            None => false,
+        }
+    }
+}
+
+/// An action that is due, with how late we are running it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Due {
+    pub issuer: PeerId,
+    pub action: Action,
+    pub late:   Duration,
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    deadline: Instant,
+    issuer:   PeerId,
+    action:   Action,
+}
+
+pub struct Schedule {
+    keypair:     identity::Keypair,
+    trusted:     HashSet<PeerId>,
+    /// Ordered by deadline.
+    pending:     Vec<Pending>,
+    max_pending: usize,
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+impl Schedule {
+    pub fn new(keypair: identity::Keypair) -> Self {
+        Self {
+            keypair,
+            trusted: HashSet::new(),
+            pending: Vec::new(),
+            max_pending: profile::Limits::default().scheduled,
+        }
+    }
+
+    /// Refuse actions beyond `max_pending` waiting ones.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    /// Run the actions `issuer` schedules. Returns false if we already did.
+    pub fn trust(&mut self, issuer: PeerId) -> bool {
+        self.trusted.insert(issuer)
+    }
+
+    /// Sign an action `name` at `at` and schedule it locally. `issued` is our
+    /// hybrid logical clock.
+    pub fn schedule(
+        &mut self,
+        name: &str,
+        at: SystemTime,
+        data: &[u8],
+        issued: Timestamp,
+    ) -> Result<Action> {
+        let action = self.sign(name, at, data, issued)?;
+        let issuer = PeerId::from(self.keypair.public());
+        let wall_ms = epoch_ms(SystemTime::now());
+        self.insert(issuer, action.clone(), wall_ms, wall_ms, Instant::now())?;
+        Ok(action)
+    }
+
+    fn sign(&self, name: &str, at: SystemTime, data: &[u8], issued: Timestamp) -> Result<Action> {
+        let at_ms = epoch_ms(at);
+        let signature = self
+            .keypair
+            .sign(&Action::signed_bytes(name, at_ms, data, issued))
+            .map_err(|err| anyhow!("Signing action: {:?}", err))?;
+        Ok(Action {
+            name: name.to_owned(),
+            at_ms,
+            data: ByteBuf::from(data),
+            issued,
+            signature: ByteBuf::from(signature),
+        })
+    }
+
+    /// Accept an action from `issuer`.
+    pub fn receive(&mut self, issuer: PeerId, action: Action) -> Result<()> {
+        self.receive_at(issuer, action, epoch_ms(SystemTime::now()), Instant::now())
+    }
+
+    fn receive_at(
+        &mut self,
+        issuer: PeerId,
+        action: Action,
+        wall_ms: u64,
+        now: Instant,
+    ) -> Result<()> {
+        if !self.trusted.contains(&issuer) {
+            bail!("{} is not a trusted scheduler", issuer);
+        }
+        if !action.verify(&issuer) {
+            bail!("Action {} not signed by {}", action.name, issuer);
+        }
+        let issued_ms = action.issued.wall_ms;
+        self.insert(issuer, action, issued_ms, wall_ms, now)
+    }
+
+    /// Schedule `action`, issued at `issued_ms` on the issuer's clock, which
+    /// we received at `wall_ms` on ours.
+    fn insert(
+        &mut self,
+        issuer: PeerId,
+        action: Action,
+        issued_ms: u64,
+        wall_ms: u64,
+        now: Instant,
+    ) -> Result<()> {
+        let skew_ms = issued_ms as i64 - wall_ms as i64;
+        let correction_ms = if skew_ms.unsigned_abs() > MAX_SKEW.as_millis() as u64 {
+            debug!("Correcting action {} for {}ms clock skew", action.name, skew_ms);
+            skew_ms
+        } else {
+            0
+        };
+        let delay_ms = action.at_ms as i64 - correction_ms - wall_ms as i64;
+        if delay_ms < -(MAX_LATE.as_millis() as i64) {
+            bail!("Action {} was due {}ms ago", action.name, -delay_ms);
+        }
+        if self.pending.len() >= self.max_pending {
+            bail!("{} actions are waiting already", self.pending.len());
+        }
+        let deadline = if delay_ms > 0 {
+            now + Duration::from_millis(delay_ms as u64)
+        } else {
+            now.checked_sub(Duration::from_millis(delay_ms.unsigned_abs()))
+                .unwrap_or(now)
+        };
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.deadline > deadline)
+            .unwrap_or_else(|| self.pending.len());
+        self.pending.insert(index, Pending {
+            deadline,
+            issuer,
+            action,
+        });
+        Ok(())
+    }
+
+    /// When the next action is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.first().map(|pending| pending.deadline)
+    }
+
+    /// Take the actions due at `now`, in order.
+    pub fn due(&mut self, now: Instant) -> Vec<Due> {
+        let count = self
+            .pending
+            .iter()
+            .take_while(|pending| pending.deadline <= now)
+            .count();
+        self.pending
+            .drain(..count)
+            .map(|pending| {
+                Due {
+                    issuer: pending.issuer,
+                    action: pending.action,
+                    late:   now.saturating_duration_since(pending.deadline),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_corrects_issuer_skew() {
+        let issuer_keys = identity::Keypair::generate_ed25519();
+        let issuer = PeerId::from(issuer_keys.public());
+        let issuer_side = Schedule::new(issuer_keys);
+        let mut node = Schedule::new(identity::Keypair::generate_ed25519());
+        let now = Instant::now();
+        let issued = Timestamp {
+            wall_ms: 1_000_000,
+            logical: 0,
+        };
+        let at = UNIX_EPOCH + Duration::from_millis(1_010_000);
+        let sample = issuer_side.sign("sample", at, b"x", issued).unwrap();
+
+        // Not run before trusting the issuer, nor if forged
+        assert!(node
+            .receive_at(issuer.clone(), sample.clone(), 1_000_000, now)
+            .is_err());
+        assert!(node.trust(issuer.clone()));
+        let mut forged = sample.clone();
+        forged.at_ms += 1;
+        assert!(node.receive_at(issuer.clone(), forged, 1_000_000, now).is_err());
+
+        // Our clock runs 3s behind the issuer's, so we wait 10s, not 13s
+        node.receive_at(issuer.clone(), sample.clone(), 997_000, now)
+            .unwrap();
+        // A small offset is transit time
+        let report = issuer_side.sign("report", at, b"x", issued).unwrap();
+        node.receive_at(issuer.clone(), report.clone(), 999_800, now)
+            .unwrap();
+        assert_eq!(node.next_deadline(), Some(now + Duration::from_millis(10_000)));
+        assert!(node.due(now + Duration::from_millis(9_999)).is_empty());
+        assert_eq!(node.due(now + Duration::from_millis(10_300)), vec![
+            Due {
+                issuer: issuer.clone(),
+                action: sample.clone(),
+                late:   Duration::from_millis(300),
+            },
+            Due {
+                issuer: issuer.clone(),
+                action: report,
+                late:   Duration::from_millis(100),
+            }
+        ]);
+        assert_eq!(node.next_deadline(), None);
+
+        // Long overdue when issued
+        let issued = Timestamp {
+            wall_ms: 1_020_000,
+            logical: 0,
+        };
+        let overdue = issuer_side.sign("sample", at, b"x", issued).unwrap();
+        assert!(node.receive_at(issuer, overdue, 1_020_000, now).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Registry of JSON Schemas that topic payloads must conform to.
+//!
+//! The registry is a JSON file mapping topic names to schemas:
+//!
+//! ```json
+//! { "chat": { "type": "object", "required": ["text"] } }
+//! ```
+//!
+//! Payloads published on a registered topic are validated before sending and
+//! rejected with the list of violations. Received payloads that do not
+//! validate are dropped.
+//!
+//! Supported keywords are `type`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems`, `maxItems`, `minLength`,
+//! `maxLength`, `minimum` and `maximum`. Other keywords are ignored.
+
+use crate::prelude::*;
+use serde_json::Value;
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+/// File name of the schema registry inside the data directory.
+pub const FILE_NAME: &str = "schemas.json";
+
+/// A single schema violation, located by a JSON pointer into the payload.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Violation {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() {
+            "/"
+        } else {
+            &self.pointer
+        };
+        write!(f, "{}: {}", pointer, self.message)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum Error {
+    #[error("Payload is not JSON: {0}")]
+    NotJson(String),
+    #[error("Payload does not match schema: {}", display_violations(.0))]
+    Invalid(Vec<Violation>),
+}
+
+fn display_violations(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Value>,
+}
+
+impl SchemaRegistry {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json =
+            fs::read(path).with_context(|| format!("Reading schemas from {}", path.display()))?;
+        let schemas = serde_json::from_slice(&json)
+            .with_context(|| format!("Parsing schemas from {}", path.display()))?;
+        Ok(Self { schemas })
+    }
+
+    pub fn insert(&mut self, topic: &str, schema: Value) {
+        self.schemas.insert(topic.into(), schema);
+    }
+
+    /// Validate a payload for `topic`. Topics without a schema accept
+    /// anything.
+    pub fn validate(&self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let schema = match self.schemas.get(topic) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let value: Value =
+            serde_json::from_slice(data).map_err(|err| Error::NotJson(err.to_string()))?;
+        let mut violations = Vec::new();
+        validate(schema, &value, "", &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(violations))
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    let actual = type_name(value);
+    actual == expected || (expected == "number" && actual == "integer")
+}
+
+fn push(violations: &mut Vec<Violation>, pointer: &str, message: String) {
+    violations.push(Violation {
+        pointer: pointer.into(),
+        message,
+    });
+}
+
+fn validate(schema: &Value, value: &Value, pointer: &str, violations: &mut Vec<Violation>) {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        // `true` and `{}` accept everything, `false` nothing.
+        Value::Bool(false) => return push(violations, pointer, "No value is allowed here".into()),
+        _ => return,
+    };
+
+    match schema.get("type") {
+        Some(Value::String(expected)) if !has_type(value, expected) => {
+            return push(
+                violations,
+                pointer,
+                format!("Expected {}, got {}", expected, type_name(value)),
+            );
+        }
+        Some(Value::Array(expected))
+            if !expected
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|expected| has_type(value, expected)) =>
+        {
+            return push(
+                violations,
+                pointer,
+                format!("Unexpected type {}", type_name(value)),
+            );
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            push(
+                violations,
+                pointer,
+                format!("{} is not one of the allowed values", value),
+            );
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            push(violations, pointer, format!("Expected {}", constant));
+        }
+    }
+
+    match value {
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                if number < minimum {
+                    push(
+                        violations,
+                        pointer,
+                        format!("{} is less than {}", number, minimum),
+                    );
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                if number > maximum {
+                    push(
+                        violations,
+                        pointer,
+                        format!("{} is more than {}", number, maximum),
+                    );
+                }
+            }
+        }
+        Value::String(string) => {
+            let length = string.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if length < min {
+                    push(
+                        violations,
+                        pointer,
+                        format!("Shorter than {} characters", min),
+                    );
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if length > max {
+                    push(
+                        violations,
+                        pointer,
+                        format!("Longer than {} characters", max),
+                    );
+                }
+            }
+        }
+        Value::Array(items) => {
+            let length = items.len() as u64;
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                if length < min {
+                    push(violations, pointer, format!("Fewer than {} items", min));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                if length > max {
+                    push(violations, pointer, format!("More than {} items", max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let pointer = format!("{}/{}", pointer, index);
+                    validate(item_schema, item, &pointer, violations);
+                }
+            }
+        }
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(key) {
+                        push(
+                            violations,
+                            pointer,
+                            format!("Missing required property {}", key),
+                        );
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let additional = schema.get("additionalProperties");
+            for (key, item) in object {
+                let pointer = format!("{}/{}", pointer, key);
+                match properties.and_then(|properties| properties.get(key)) {
+                    Some(item_schema) => validate(item_schema, item, &pointer, violations),
+                    None => {
+                        if let Some(additional) = additional {
+                            validate(additional, item, &pointer, violations);
+                        }
+                    }
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use serde_json::json;
+
+    fn registry() -> SchemaRegistry {
+        let mut registry = SchemaRegistry::default();
+        registry.insert(
+            "chat",
+            json!({
+                "type": "object",
+                "required": ["text"],
+                "properties": {
+                    "text": { "type": "string", "maxLength": 5 },
+                    "tags": { "type": "array", "items": { "enum": ["a", "b"] } }
+                },
+                "additionalProperties": false
+            }),
+        );
+        registry
+    }
+
+    #[test]
+    fn test_valid_and_unregistered() {
+        let registry = registry();
+        assert_eq!(
+            registry.validate("chat", br#"{"text":"hi","tags":["a"]}"#),
+            Ok(())
+        );
+        assert_eq!(registry.validate("other", b"not json"), Ok(()));
+    }
+
+    #[test]
+    fn test_violations_are_located() {
+        let registry = registry();
+        let error = registry
+            .validate("chat", br#"{"text":"hello!","tags":["c"],"x":1}"#)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Payload does not match schema: /tags/0: \"c\" is not one of the allowed values, \
+             /text: Longer than 5 characters, /x: No value is allowed here"
+        );
+        assert!(matches!(
+            registry.validate("chat", b"[]"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            registry.validate("chat", b"{"),
+            Err(Error::NotJson(_))
+        ));
+    }
+}
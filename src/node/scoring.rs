@@ -0,0 +1,300 @@
+//! Peer scores and flood protection.
+//!
+//! With `--scoring "rate=100 burst=200"` the node takes at most `rate`
+//! pubsub messages a second from each neighbour, in bursts of up to `burst`,
+//! and drops the rest. Every peer has a [`Score`] that starts at zero and
+//! goes down for dropped messages, for invalid ones (bad signatures,
+//! provenance, blob hashes or schemas) and for replaying messages seen
+//! before more than `duplicates` of the time. Penalties fade, halving every
+//! `decay`.
+//!
+//! Peers scoring below `throttle` get a quarter of the rate, below
+//! `disconnect` their connections are closed, and below `ban` they are
+//! banned for `ban_for`. Critical peers are throttled but kept. Scores are
+//! shown in [`super::NodeHandle::peer_info`].
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Taken off for each message dropped over the rate.
+pub const RATE_PENALTY: f64 = 0.1;
+
+/// Taken off for each invalid message.
+pub const INVALID_PENALTY: f64 = 10.0;
+
+/// Taken off for each duplicate beyond the `duplicates` ratio.
+pub const DUPLICATE_PENALTY: f64 = 1.0;
+
+/// Messages a peer sends before its duplicate ratio counts.
+pub const MIN_MESSAGES: u64 = 20;
+
+/// Peers back above this score and idle for an hour are forgotten.
+const FORGOTTEN: f64 = -1.0;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Config {
+    /// Messages a second taken from each peer.
+    pub rate:       f64,
+    pub burst:      u32,
+    /// Share of duplicates above which a peer is penalized.
+    pub duplicates: f64,
+    pub throttle:   f64,
+    pub disconnect: f64,
+    pub ban:        f64,
+    pub ban_for:    Duration,
+    /// Half-life of penalties.
+    pub decay:      Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rate:       100.0,
+            burst:      200,
+            duplicates: 0.5,
+            throttle:   -20.0,
+            disconnect: -50.0,
+            ban:        -100.0,
+            ban_for:    Duration::from_secs(3600),
+            decay:      Duration::from_secs(600),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("Invalid {} {}", key, value))
+            };
+            match key {
+                "rate" => config.rate = number()?,
+                "burst" => {
+                    config.burst = value
+                        .parse()
+                        .with_context(|| format!("Invalid burst {}", value))?;
+                }
+                "duplicates" => config.duplicates = number()?,
+                "throttle" => config.throttle = number()?,
+                "disconnect" => config.disconnect = number()?,
+                "ban" => config.ban = number()?,
+                "ban_for" => {
+                    config.ban_for = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid ban_for {}", value))?;
+                }
+                "decay" => {
+                    config.decay = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid decay {}", value))?;
+                }
+                _ => bail!("Unknown scoring option {}", key),
+            }
+        }
+        ensure!(config.rate > 0.0 && config.burst > 0, "Scoring rate must be positive");
+        ensure!(
+            (0.0..=1.0).contains(&config.duplicates),
+            "Scoring duplicates must be between 0 and 1"
+        );
+        ensure!(
+            config.ban <= config.disconnect
+                && config.disconnect <= config.throttle
+                && config.throttle <= 0.0,
+            "Expected ban <= disconnect <= throttle <= 0"
+        );
+        ensure!(config.decay > Duration::from_secs(0), "Scoring decay must be positive");
+        Ok(config)
+    }
+}
+
+/// How a peer behaved.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Score {
+    /// Zero for well-behaved peers, negative after penalties.
+    pub value:      f64,
+    /// Pubsub messages received, including dropped ones.
+    pub messages:   u64,
+    /// Messages dropped over the rate.
+    pub throttled:  u64,
+    pub invalid:    u64,
+    pub duplicates: u64,
+}
+
+/// What to do with a peer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Disconnect,
+    Ban(Duration),
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    score:        Score,
+    tokens:       f64,
+    updated:      Instant,
+    disconnected: bool,
+}
+
+/// The scores of the peers we heard from.
+#[derive(Clone, Debug)]
+pub struct Scores {
+    config: Config,
+    peers:  HashMap<PeerId, Entry>,
+}
+
+impl Scores {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn score(&self, peer: &PeerId) -> Option<Score> {
+        self.peers.get(peer).map(|entry| entry.score.clone())
+    }
+
+    /// The entry of `peer`, with penalties faded and tokens refilled until
+    /// `now`.
+    fn entry(&mut self, peer: &PeerId, now: Instant) -> &mut Entry {
+        let config = &self.config;
+        let entry = self.peers.entry(peer.clone()).or_insert_with(|| {
+            Entry {
+                score:        Score::default(),
+                tokens:       f64::from(config.burst),
+                updated:      now,
+                disconnected: false,
+            }
+        });
+        let elapsed = now.saturating_duration_since(entry.updated).as_secs_f64();
+        entry.updated = now;
+        entry.score.value *= 0.5_f64.powf(elapsed / config.decay.as_secs_f64());
+        let (rate, burst) = if entry.score.value < config.throttle {
+            (config.rate / 4.0, f64::from(config.burst) / 4.0)
+        } else {
+            (config.rate, f64::from(config.burst))
+        };
+        entry.tokens = (entry.tokens + elapsed * rate).min(burst);
+        entry
+    }
+
+    /// Count a pubsub message from `peer`. Returns whether to take it, or
+    /// drop it as over the rate.
+    pub fn message(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let entry = self.entry(peer, now);
+        entry.score.messages += 1;
+        if entry.tokens >= 1.0 {
+            entry.tokens -= 1.0;
+            return true;
+        }
+        entry.score.throttled += 1;
+        entry.score.value -= RATE_PENALTY;
+        false
+    }
+
+    /// `peer` sent a message seen before.
+    pub fn duplicate(&mut self, peer: &PeerId, now: Instant) {
+        let ratio = self.config.duplicates;
+        let score = &mut self.entry(peer, now).score;
+        score.duplicates += 1;
+        let share = score.duplicates as f64 / score.messages.max(1) as f64;
+        if score.messages >= MIN_MESSAGES && share > ratio {
+            score.value -= DUPLICATE_PENALTY;
+        }
+    }
+
+    /// `peer` sent an invalid message.
+    pub fn invalid(&mut self, peer: &PeerId, now: Instant) {
+        let score = &mut self.entry(peer, now).score;
+        score.invalid += 1;
+        score.value -= INVALID_PENALTY;
+    }
+
+    /// The peers to disconnect or ban at `now`. Banned peers start over.
+    pub fn verdicts(&mut self, now: Instant) -> Vec<(PeerId, Verdict)> {
+        let mut verdicts = Vec::new();
+        let peers = self.peers.keys().cloned().collect::<Vec<_>>();
+        for peer in peers {
+            let config = self.config.clone();
+            let entry = self.entry(&peer, now);
+            if entry.score.value < config.ban {
+                verdicts.push((peer, Verdict::Ban(config.ban_for)));
+            } else if entry.score.value < config.disconnect {
+                if !entry.disconnected {
+                    entry.disconnected = true;
+                    verdicts.push((peer, Verdict::Disconnect));
+                }
+            } else {
+                entry.disconnected = false;
+            }
+        }
+        for (peer, verdict) in &verdicts {
+            if matches!(verdict, Verdict::Ban(_)) {
+                self.peers.remove(peer);
+            }
+        }
+        let idle = Duration::from_secs(3600);
+        self.peers.retain(|_, entry| {
+            entry.score.value < FORGOTTEN || now.saturating_duration_since(entry.updated) < idle
+        });
+        verdicts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_throttles_then_bans_floods() {
+        let config = "rate=10 burst=20 decay=1m".parse::<Config>().unwrap();
+        assert!("ban=-10 disconnect=-50".parse::<Config>().is_err());
+        let mut scores = Scores::new(config);
+        let (flooder, honest) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        let taken = (0..100).filter(|_| scores.message(&flooder, now)).count();
+        assert_eq!(taken, 20);
+        assert!(scores.message(&honest, now));
+        let score = scores.score(&flooder).unwrap();
+        assert_eq!((score.messages, score.throttled), (100, 80));
+        assert!(scores.verdicts(now).is_empty());
+
+        // Refilled at the rate, a quarter of it once throttled
+        let later = now + Duration::from_secs(1);
+        assert_eq!((0..20).filter(|_| scores.message(&flooder, later)).count(), 10);
+        for _ in 0..2 {
+            scores.invalid(&flooder, later);
+        }
+        let later = later + Duration::from_secs(1);
+        assert_eq!((0..20).filter(|_| scores.message(&flooder, later)).count(), 2);
+
+        // Replays count once the peer sent enough messages
+        for _ in 0..20 {
+            scores.duplicate(&honest, later);
+        }
+        assert!(scores.score(&honest).unwrap().value == 0.0);
+        for _ in 0..3 {
+            scores.invalid(&flooder, later);
+        }
+        assert_eq!(scores.verdicts(later), vec![(flooder.clone(), Verdict::Disconnect)]);
+        assert!(scores.verdicts(later).is_empty());
+        for _ in 0..5 {
+            scores.invalid(&flooder, later);
+        }
+        let ban = Verdict::Ban(Duration::from_secs(3600));
+        assert_eq!(scores.verdicts(later), vec![(flooder.clone(), ban)]);
+        assert!(scores.score(&flooder).is_none());
+    }
+}
@@ -0,0 +1,291 @@
+//! Full-text search over archived messages.
+//!
+//! In builds with the `search` feature, an archiver started with
+//! `--persist-archive` also indexes the messages it keeps that are UTF-8
+//! text, in an SQLite FTS5 table in `<data dir>/search.sqlite`. The index
+//! holds the messages of the [`archive`]: older ones leave both together,
+//! and it is rebuilt from the stored archive on start.
+//!
+//! `mesh --data-dir <dir> search <query>` searches the index of the node on
+//! the data directory, running or not, and prints the matching messages,
+//! newest first, optionally only those on a `--topic` and between `--since`
+//! and `--until`. Queries are in the FTS5 syntax: words match messages
+//! containing all of them, `"a phrase"` the phrase, `pre*` words starting
+//! with `pre`, and `a OR b` either word. Embedding applications open the
+//! file with [`Index::open`] and call [`Index::search`].
+//!
+//! [`archive`]: super::archive
+
+use super::archive::epoch_ms;
+use crate::prelude::*;
+use libp2p::PeerId;
+use rusqlite::{params, Connection, NO_PARAMS};
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the index in the data directory.
+pub const FILE_NAME: &str = "search.sqlite";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id      INTEGER PRIMARY KEY,
+        topic   TEXT NOT NULL,
+        source  TEXT NOT NULL,
+        time_ms INTEGER NOT NULL,
+        text    TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_topic ON messages (topic, id);
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_text
+        USING fts5 (text, content = 'messages', content_rowid = 'id');
+    CREATE TRIGGER IF NOT EXISTS messages_inserted AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_text (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_deleted AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_text (messages_text, rowid, text)
+            VALUES ('delete', old.id, old.text);
+    END;
+";
+
+/// What to search for.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Query {
+    /// An FTS5 query of the text.
+    pub text:  String,
+    pub topic: Option<String>,
+    /// Only messages from this time on.
+    pub since: Option<SystemTime>,
+    /// Only messages before this time.
+    pub until: Option<SystemTime>,
+    /// Most messages found.
+    pub limit: usize,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text:  text.to_owned(),
+            topic: None,
+            since: None,
+            until: None,
+            limit: 20,
+        }
+    }
+}
+
+/// A message found.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hit {
+    pub topic:  String,
+    pub source: PeerId,
+    /// When it was sent, or received if it has no timestamp.
+    pub time:   SystemTime,
+    pub text:   String,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            humantime::format_rfc3339_millis(self.time),
+            self.topic,
+            self.source,
+            self.text
+        )
+    }
+}
+
+/// The text of archived messages.
+#[derive(Debug)]
+pub struct Index(Connection);
+
+impl Index {
+    /// Open the index at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection =
+            Connection::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        // Searches from other processes read while the node writes
+        connection.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("Creating the index in {}", path.display()))?;
+        Ok(Self(connection))
+    }
+
+    /// An index in memory, for tests.
+    pub fn memory() -> Result<Self> {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self(connection))
+    }
+
+    /// Index `data` from `source` on `topic` at `time_ms` milliseconds since
+    /// the Unix epoch, if it is text, keeping the last `keep` messages of
+    /// `topic`.
+    pub fn insert(
+        &self,
+        topic: &str,
+        source: &str,
+        time_ms: u64,
+        data: &[u8],
+        keep: usize,
+    ) -> Result<()> {
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return Ok(()),
+        };
+        self.0.execute(
+            "INSERT INTO messages (topic, source, time_ms, text) VALUES (?1, ?2, ?3, ?4)",
+            params![topic, source, time_ms as i64, text],
+        )?;
+        self.0.execute(
+            "DELETE FROM messages WHERE topic = ?1 AND id <= (SELECT id FROM messages WHERE topic \
+             = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2)",
+            params![topic, keep as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Forget the messages of `topic`.
+    pub fn remove(&self, topic: &str) -> Result<()> {
+        self.0
+            .execute("DELETE FROM messages WHERE topic = ?1", params![topic])?;
+        Ok(())
+    }
+
+    /// Replace the indexed messages with those `fill` inserts.
+    pub fn rebuild(&mut self, fill: impl FnOnce(&Self) -> Result<()>) -> Result<()> {
+        self.0.execute_batch("BEGIN; DELETE FROM messages;")?;
+        match fill(self) {
+            Ok(()) => Ok(self.0.execute_batch("COMMIT")?),
+            Err(err) => {
+                let _ = self.0.execute_batch("ROLLBACK");
+                Err(err)
+            }
+        }
+    }
+
+    /// The messages matching `query`, newest first.
+    pub fn search(&self, query: &Query) -> Result<Vec<Hit>> {
+        let mut statement = self.0.prepare(
+            "SELECT messages.topic, messages.source, messages.time_ms, messages.text FROM \
+             messages_text JOIN messages ON messages.id = messages_text.rowid WHERE messages_text \
+             MATCH ?1 AND (?2 IS NULL OR messages.topic = ?2) AND messages.time_ms >= ?3 AND \
+             messages.time_ms < ?4 ORDER BY messages.time_ms DESC, messages.id DESC LIMIT ?5",
+        )?;
+        let rows = statement
+            .query_map(
+                params![
+                    query.text,
+                    query.topic,
+                    query.since.map_or(0, epoch_ms) as i64,
+                    query.until.map_or(i64::MAX, |until| epoch_ms(until) as i64),
+                    query.limit as i64,
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .with_context(|| format!("Searching for {}", query.text))?;
+        let mut hits = Vec::new();
+        for row in rows {
+            let (topic, source, time_ms, text): (String, String, i64, String) =
+                row.with_context(|| format!("Searching for {}", query.text))?;
+            hits.push(Hit {
+                topic,
+                source: source
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid indexed source {}", source))?,
+                time: UNIX_EPOCH + Duration::from_millis(time_ms as u64),
+                text,
+            });
+        }
+        Ok(hits)
+    }
+}
+
+/// Print the messages matching `query` in the index at `path`.
+pub fn print(path: &Path, query: &Query) -> Result<()> {
+    anyhow::ensure!(path.exists(), "No search index at {}", path.display());
+    for hit in Index::open(path)?.search(query)? {
+        println!("{}", hit);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_finds_text_by_topic_and_time() {
+        let index = Index::memory().unwrap();
+        let source = PeerId::random().to_base58();
+        index
+            .insert("chat", &source, 1000, b"hello world", 2)
+            .unwrap();
+        index
+            .insert("chat", &source, 2000, b"hello again", 2)
+            .unwrap();
+        index
+            .insert("news", &source, 3000, b"hello news", 2)
+            .unwrap();
+        index
+            .insert("news", &source, 4000, &[0xff, 0xfe], 2)
+            .unwrap();
+
+        let texts = |query: &Query| {
+            let hits = index.search(query).unwrap();
+            hits.into_iter().map(|hit| hit.text).collect::<Vec<_>>()
+        };
+        let mut query = Query::new("hello");
+        assert_eq!(texts(&query), vec![
+            "hello news",
+            "hello again",
+            "hello world"
+        ]);
+        query.topic = Some("chat".into());
+        assert_eq!(texts(&query), vec!["hello again", "hello world"]);
+        query.since = Some(UNIX_EPOCH + Duration::from_millis(1500));
+        assert_eq!(texts(&query), vec!["hello again"]);
+        query.until = Some(UNIX_EPOCH + Duration::from_millis(2000));
+        assert!(texts(&query).is_empty());
+        assert_eq!(texts(&Query::new("wor*")), vec!["hello world"]);
+        assert!(index.search(&Query::new("\"open")).is_err());
+
+        // Older messages leave as they leave the archive
+        index.insert("chat", &source, 5000, b"third", 2).unwrap();
+        assert!(texts(&Query::new("world")).is_empty());
+        index.remove("news").unwrap();
+        assert!(texts(&Query::new("news")).is_empty());
+        let hit = &index.search(&Query::new("third")).unwrap()[0];
+        assert_eq!(hit.source.to_base58(), source);
+        assert_eq!(hit.time, UNIX_EPOCH + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_reopens_index_file() {
+        let dir = std::env::temp_dir().join(format!("mesh-search-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE_NAME);
+        let source = PeerId::random().to_base58();
+        Index::open(&path)
+            .unwrap()
+            .insert("chat", &source, 1000, b"kept", 10)
+            .unwrap();
+        assert_eq!(
+            Index::open(&path)
+                .unwrap()
+                .search(&Query::new("kept"))
+                .unwrap()
+                .len(),
+            1
+        );
+        print(&path, &Query::new("kept")).unwrap();
+        assert!(print(&dir.join("missing.sqlite"), &Query::new("kept")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
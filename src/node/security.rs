@@ -0,0 +1,87 @@
+//! Which protocols authenticate and encrypt connections.
+//!
+//! Connections negotiate Noise XX, or secio if the other side does not
+//! speak Noise. secio is deprecated upstream and the only protocol the Go
+//! version of 0x Mesh supports, so it stays on by default. Rolling out
+//! `--security noise` once every node is upgraded stops accepting secio;
+//! upgraded nodes keep talking to each other over Noise throughout, and the
+//! nodes still on secio show up in the debug log.
+//!
+//! A [`SwarmKey`] wraps all of it in the encryption of a private network,
+//! see [`super::pnet`].
+
+use super::pnet::SwarmKey;
+use crate::prelude::*;
+use anyhow::bail;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub noise:     bool,
+    /// Whether to fall back to secio.
+    pub secio:     bool,
+    /// Only connect to the nodes with this key.
+    pub swarm_key: Option<SwarmKey>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            noise:     true,
+            secio:     true,
+            swarm_key: None,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    /// Parse protocol names separated by spaces or commas, like
+    /// `noise,secio`. Noise is preferred whatever the order.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self {
+            noise:     false,
+            secio:     false,
+            swarm_key: None,
+        };
+        for protocol in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            match protocol {
+                "" => {}
+                "noise" => config.noise = true,
+                "secio" => config.secio = true,
+                _ => bail!("Unknown security protocol {}, expected noise or secio", protocol),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if !self.noise && !self.secio {
+            bail!("Expected at least one of noise and secio");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        assert_eq!("secio, noise".parse::<Config>().unwrap(), Config::default());
+        assert_eq!("noise".parse::<Config>().unwrap(), Config {
+            noise:     true,
+            secio:     false,
+            swarm_key: None,
+        });
+        assert!("".parse::<Config>().is_err());
+        assert!("tls".parse::<Config>().is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Dropping pubsub messages that arrive more than once.
+//!
+//! Floodsub sends every message to every connected peer, so with peers
+//! connected to each other the same message arrives over several paths.
+//! The pubsub behaviour remembers the [`MessageId`] of each message it
+//! delivers, a hash of its source and sequence number, and drops messages
+//! it has seen before, whichever protocol runs. Ids are kept for
+//! `seen-ttl=2m` and at most `seen-capacity=10000` of them, set through
+//! `--pubsub`, and the StatsD counter `pubsub.duplicates` counts the
+//! messages dropped.
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long ids are kept by default.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(120);
+
+/// Ids kept at most by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A message, by its source and sequence number.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MessageId([u8; 16]);
+
+impl MessageId {
+    pub fn new(source: &PeerId, sequence_number: &[u8]) -> Self {
+        let digest = Sha256::new()
+            .chain(source.as_bytes())
+            .chain(sequence_number)
+            .finalize();
+        let mut id = [0; 16];
+        id.copy_from_slice(&digest[..16]);
+        Self(id)
+    }
+}
+
+/// The ids of recent messages.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    ttl:        Duration,
+    capacity:   usize,
+    ids:        HashSet<MessageId>,
+    /// The ids with when they were first seen, oldest first.
+    order:      VecDeque<(Instant, MessageId)>,
+    duplicates: u64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL, DEFAULT_CAPACITY)
+    }
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            duplicates: 0,
+        }
+    }
+
+    /// Remember `id`, returning false if it was seen before.
+    pub fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        while let Some((seen, oldest)) = self.order.front() {
+            if now.saturating_duration_since(*seen) < self.ttl {
+                break;
+            }
+            self.ids.remove(oldest);
+            self.order.pop_front();
+        }
+        if self.ids.contains(&id) {
+            self.duplicates += 1;
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id);
+        self.order.push_back((now, id));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Messages dropped since the node started.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_drops_recent_duplicates() {
+        let source = PeerId::random();
+        let id = |seqno: u64| MessageId::new(&source, &seqno.to_be_bytes());
+        assert_ne!(id(1), MessageId::new(&PeerId::random(), &1_u64.to_be_bytes()));
+
+        let now = Instant::now();
+        let mut cache = Cache::new(Duration::from_secs(60), 2);
+        assert!(cache.insert(id(1), now));
+        assert!(!cache.insert(id(1), now));
+        assert!(cache.insert(id(2), now));
+        assert_eq!((cache.len(), cache.duplicates()), (2, 1));
+
+        // The oldest id makes room
+        assert!(cache.insert(id(3), now));
+        assert!(cache.insert(id(1), now));
+        assert!(!cache.insert(id(3), now));
+
+        // Ids expire after the ttl
+        assert!(cache.insert(id(3), now + Duration::from_secs(60)));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,377 @@
+//! Serial link, for gateways wired to a microcontroller or another board.
+//!
+//! `--link serial:ttyUSB0@115200` listens on `/dev/ttyUSB0`, set to raw mode
+//! at 115200 baud. A serial line joins exactly two devices: one listens, the
+//! other dials `/unix/serial:<device>@<baud>/p2p/<peer id>`, for example as a
+//! `--critical` peer. The device name defaults to a baud rate of
+//! [`DEFAULT_BAUD`].
+//!
+//! Bytes travel in SLIP frames (RFC 1055) of at most [`MAX_FRAME`] bytes,
+//! each followed by the CRC-32 of its content. A UART has no error
+//! correction, so a frame that fails its check ends the connection, which is
+//! then reopened and redialed, instead of corrupting the encrypted stream.
+//! The other end can be anything speaking the same framing.
+
+use super::link::{check, Fd, FdStream, Incoming, LinkTransport};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use futures::{
+    channel::oneshot,
+    future::BoxFuture,
+    io::{AsyncRead, AsyncWrite},
+};
+use std::{
+    ffi::CString,
+    fmt, io, mem,
+    os::unix::io::AsRawFd,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Baud rate of a device given without one.
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+/// Largest payload of a frame.
+pub const MAX_FRAME: usize = 1024;
+
+/// Wait before reopening a device that could not be opened.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+const SCHEME: &str = "serial";
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+const CRC_LEN: usize = 4;
+
+/// A serial device and its speed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Address {
+    /// Name of the device in `/dev`.
+    pub device: String,
+    pub baud:   u32,
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (device, baud) = match s.find('@') {
+            Some(index) => {
+                let baud = &s[index + 1..];
+                let baud = baud
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid baud rate {}", baud))?;
+                (&s[..index], baud)
+            }
+            None => (s, DEFAULT_BAUD),
+        };
+        ensure!(
+            !device.is_empty() && !device.contains('/') && device != "..",
+            "Expected the name of a device in /dev, got {}",
+            device
+        );
+        speed(baud)?;
+        Ok(Self {
+            device: device.into(),
+            baud,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.device, self.baud)
+    }
+}
+
+fn speed(baud: u32) -> Result<libc::speed_t> {
+    Ok(match baud {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        460_800 => libc::B460800,
+        921_600 => libc::B921600,
+        _ => bail!("Unsupported baud rate {}", baud),
+    })
+}
+
+/// Open `address` in raw mode, dropping whatever was waiting in its buffers.
+fn open(address: &Address) -> io::Result<Fd> {
+    let path = CString::new(format!("/dev/{}", address.device))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK;
+    let fd = Fd::new(check(unsafe { libc::open(path.as_ptr(), flags) })?)?;
+    let speed = speed(address.baud)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", err)))?;
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    check(unsafe { libc::tcgetattr(fd.as_raw_fd(), &mut termios) })?;
+    unsafe { libc::cfmakeraw(&mut termios) };
+    check(unsafe { libc::cfsetspeed(&mut termios, speed) })?;
+    termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+    check(unsafe { libc::tcsetattr(fd.as_raw_fd(), libc::TCSANOW, &termios) })?;
+    check(unsafe { libc::tcflush(fd.as_raw_fd(), libc::TCIOFLUSH) })?;
+    Ok(fd)
+}
+
+fn crc(payload: &[u8]) -> [u8; CRC_LEN] {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.finalize().to_be_bytes()
+}
+
+/// Append the frame of `payload` to `out`.
+pub fn encode(payload: &[u8], out: &mut Vec<u8>) {
+    for &byte in payload.iter().chain(&crc(payload)) {
+        match byte {
+            END => out.extend_from_slice(&[ESC, ESC_END]),
+            ESC => out.extend_from_slice(&[ESC, ESC_ESC]),
+            byte => out.push(byte),
+        }
+    }
+    out.push(END);
+}
+
+/// Reassembles frames from the bytes received.
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    frame:   Vec<u8>,
+    escaped: bool,
+}
+
+impl Decoder {
+    /// Take in `byte`, appending the payload to `out` if it completed a
+    /// frame. Fails on frames that are corrupted or too long.
+    pub fn push(&mut self, byte: u8, out: &mut Vec<u8>) -> io::Result<()> {
+        let byte = match (self.escaped, byte) {
+            (false, END) => return self.finish(out),
+            (false, ESC) => {
+                self.escaped = true;
+                return Ok(());
+            }
+            (true, ESC_END) => END,
+            (true, ESC_ESC) => ESC,
+            (false, byte) => byte,
+            (true, byte) => return Err(corrupted(&format!("invalid escape {:#04x}", byte))),
+        };
+        self.escaped = false;
+        if self.frame.len() >= MAX_FRAME + CRC_LEN {
+            return Err(corrupted("frame too long"));
+        }
+        self.frame.push(byte);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let frame = mem::take(&mut self.frame);
+        // Empty frames only flush line noise
+        if frame.is_empty() {
+            return Ok(());
+        }
+        if frame.len() < CRC_LEN {
+            return Err(corrupted("frame too short"));
+        }
+        let (payload, checksum) = frame.split_at(frame.len() - CRC_LEN);
+        if crc(payload) != checksum {
+            return Err(corrupted("checksum mismatch"));
+        }
+        out.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
+fn corrupted(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Corrupted serial frame, {}", message))
+}
+
+/// A connection over a serial line.
+#[derive(Debug)]
+pub struct Stream {
+    port:     FdStream,
+    decoder:  Decoder,
+    /// Payload received and not yet read.
+    received: Vec<u8>,
+    /// Frames not yet sent.
+    sending:  Vec<u8>,
+    /// Tells the listener when the connection is gone.
+    _closed:  Option<oneshot::Sender<()>>,
+}
+
+impl Stream {
+    fn new(port: Fd, closed: Option<oneshot::Sender<()>>) -> io::Result<Self> {
+        Ok(Self {
+            port:     FdStream::new(port)?,
+            decoder:  Decoder::default(),
+            received: Vec::new(),
+            sending:  Vec::new(),
+            _closed:  closed,
+        })
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.sending.is_empty() {
+            let written = futures::ready!(Pin::new(&mut self.port).poll_write(cx, &self.sending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut raw = [0_u8; 512];
+        while this.received.is_empty() {
+            let read = futures::ready!(Pin::new(&mut this.port).poll_read(cx, &mut raw))?;
+            if read == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            for &byte in &raw[..read] {
+                this.decoder.push(byte, &mut this.received)?;
+            }
+        }
+        let len = buf.len().min(this.received.len());
+        buf[..len].copy_from_slice(&this.received[..len]);
+        this.received.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_send(cx))?;
+        let len = buf.len().min(MAX_FRAME);
+        encode(&buf[..len], &mut this.sending);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+}
+
+fn invalid(err: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:#}", err))
+}
+
+/// The serial [`LinkTransport`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Serial;
+
+impl LinkTransport for Serial {
+    type Stream = Stream;
+
+    fn scheme(&self) -> &'static str {
+        SCHEME
+    }
+
+    /// Open the device, and open it again whenever the connection over it
+    /// ended.
+    fn listen(&self, address: &str) -> io::Result<Incoming<Self::Stream>> {
+        let address: Address = address.parse().map_err(invalid)?;
+        let port = open(&address)?;
+        info!("Listening on serial device {}", address);
+        let first = Some(port);
+        let state = (address, first, None::<oneshot::Receiver<()>>);
+        Ok(stream::unfold(state, |(address, mut port, closed)| {
+            async move {
+                if let Some(closed) = closed {
+                    let _ = closed.await;
+                    debug!("Connection over {} ended, reopening", address);
+                }
+                let port = match port.take() {
+                    Some(port) => Ok(port),
+                    None => {
+                        open(&address).map_err(|err| {
+                            io::Error::new(err.kind(), format!("Opening {}: {}", address, err))
+                        })
+                    }
+                };
+                let (sender, receiver) = oneshot::channel();
+                let accepted = port
+                    .and_then(|port| Stream::new(port, Some(sender)))
+                    .map(|stream| (stream, address.to_string()));
+                if accepted.is_err() {
+                    sleep(REOPEN_DELAY).await;
+                }
+                let closed = accepted.as_ref().ok().map(|_| receiver);
+                Some((accepted, (address, None, closed)))
+            }
+        })
+        .boxed())
+    }
+
+    fn dial(&self, address: &str) -> BoxFuture<'static, io::Result<Self::Stream>> {
+        let address = address.parse::<Address>().map_err(invalid);
+        async move {
+            let address = address?;
+            let stream = Stream::new(open(&address)?, None)?;
+            debug!("Opened serial device {}", address);
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_frames_bytes() {
+        let address: Address = "ttyUSB0".parse().unwrap();
+        assert_eq!(address.to_string(), "ttyUSB0@115200");
+        assert!("ttyS0@1234".parse::<Address>().is_err());
+        assert!("../etc/passwd".parse::<Address>().is_err());
+
+        // Special bytes are escaped and the checksum is verified
+        let payload = [1, END, 2, ESC, 3];
+        let mut frame = Vec::new();
+        encode(&payload, &mut frame);
+        let mut decoder = Decoder::default();
+        let mut received = Vec::new();
+        for &byte in [END].iter().chain(&frame) {
+            decoder.push(byte, &mut received).unwrap();
+        }
+        assert_eq!(received, payload.to_vec());
+        frame[0] ^= 1;
+        let result = frame.iter().try_for_each(|&byte| decoder.push(byte, &mut received));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Over a pair of connected descriptors standing in for the line
+        let mut fds = [0; 2];
+        check(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) })
+            .unwrap();
+        let mut left = Stream::new(Fd::new(fds[0]).unwrap(), None).unwrap();
+        let mut right = Stream::new(Fd::new(fds[1]).unwrap(), None).unwrap();
+        let message = vec![ESC; 3000];
+        left.write_all(&message).await.unwrap();
+        left.flush().await.unwrap();
+        let mut read = vec![0; message.len()];
+        right.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, message);
+    }
+}
@@ -0,0 +1,389 @@
+//! Bandwidth caps.
+//!
+//! Started with `--bandwidth "upload=1MiB/s download=4MiB/s peer-upload=256KiB/s"`
+//! the node limits its traffic with token buckets: one per direction shared
+//! by all connections, and one per direction for every peer, shared by the
+//! connections to that peer. A connection reads or writes only as many bytes
+//! as all of its buckets allow and waits for them to refill otherwise, so TCP
+//! flow control pushes back on the remote.
+//!
+//! The caps apply to the multiplexed stream of a connection, after
+//! encryption is set up, so they do not count the encryption overhead.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{anyhow, bail};
+use libp2p::{
+    core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, Sleep};
+use ubyte::ByteUnit;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Bandwidth caps in bytes per second. `None` is unlimited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub struct Config {
+    pub upload:        Option<ByteUnit>,
+    pub download:      Option<ByteUnit>,
+    pub peer_upload:   Option<ByteUnit>,
+    pub peer_download: Option<ByteUnit>,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let rate = value
+                .strip_suffix("/s")
+                .unwrap_or(value)
+                .parse::<ByteUnit>()
+                .map_err(|err| anyhow!("Invalid {} {}: {}", key, value, err))?;
+            match key {
+                "upload" => config.upload = Some(rate),
+                "download" => config.download = Some(rate),
+                "peer-upload" => config.peer_upload = Some(rate),
+                "peer-download" => config.peer_download = Some(rate),
+                _ => bail!("Unknown bandwidth option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        for (key, cap) in &[
+            ("upload", self.upload),
+            ("download", self.download),
+            ("peer-upload", self.peer_upload),
+            ("peer-download", self.peer_download),
+        ] {
+            if *cap == Some(ByteUnit::from(0)) {
+                bail!("Bandwidth cap {} must be positive", key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket holding up to one second worth of traffic.
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    /// Bytes per second.
+    rate:    u64,
+    tokens:  u64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: ByteUnit, now: Instant) -> Self {
+        let rate = rate.as_u64().max(1);
+        Self {
+            rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_nanos();
+        let added = elapsed * u128::from(self.rate) / NANOS_PER_SEC;
+        if u128::from(self.tokens) + added >= u128::from(self.rate) {
+            self.tokens = self.rate;
+            self.updated = now;
+        } else if added > 0 {
+            self.tokens += added as u64;
+            // Keep the fraction of a token not yet added
+            let used = added * NANOS_PER_SEC / u128::from(self.rate);
+            self.updated += Duration::from_nanos(used as u64);
+        }
+    }
+
+    /// Time until `want` bytes, or a full bucket, are available.
+    fn wait(&self, want: usize) -> Duration {
+        let needed = (want as u64).min(self.rate).saturating_sub(self.tokens);
+        let nanos = (u128::from(needed) * NANOS_PER_SEC + u128::from(self.rate) - 1)
+            / u128::from(self.rate);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// The buckets traffic in one direction of a connection draws from.
+#[derive(Clone, Debug, Default)]
+struct Buckets(Vec<Arc<Mutex<TokenBucket>>>);
+
+impl Buckets {
+    /// How many of `want` bytes may pass now, or how long to wait first.
+    fn available(&self, want: usize, now: Instant) -> Result<usize, Duration> {
+        let mut grant = want;
+        let mut wait = Duration::default();
+        for bucket in &self.0 {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.refill(now);
+            if bucket.tokens == 0 {
+                wait = wait.max(bucket.wait(want));
+            }
+            grant = grant.min(bucket.tokens as usize);
+        }
+        if grant == 0 && want > 0 {
+            Err(wait)
+        } else {
+            Ok(grant)
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        for bucket in &self.0 {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.tokens = bucket.tokens.saturating_sub(bytes as u64);
+        }
+    }
+
+    /// Wait until some of `want` bytes may pass.
+    fn poll_grant(
+        &self,
+        delay: &mut Option<Pin<Box<Sleep>>>,
+        want: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = delay {
+                futures::ready!(sleep.as_mut().poll(cx));
+                *delay = None;
+            }
+            match self.available(want, Instant::now()) {
+                Ok(grant) => return Poll::Ready(grant),
+                Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+            }
+        }
+    }
+}
+
+/// Connection stream limited by bandwidth caps.
+pub struct Shaped<C> {
+    inner:       C,
+    upload:      Buckets,
+    download:    Buckets,
+    read_delay:  Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<C> Shaped<C> {
+    fn new(inner: C, upload: Buckets, download: Buckets) -> Self {
+        Self {
+            inner,
+            upload,
+            download,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Shaped<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let grant = futures::ready!(this.download.poll_grant(&mut this.read_delay, buf.len(), cx));
+        let read = futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..grant]))?;
+        this.download.consume(read);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Shaped<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let grant = futures::ready!(this.upload.poll_grant(&mut this.write_delay, buf.len(), cx));
+        let written = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..grant]))?;
+        this.upload.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Upgrade that runs `inner` over a [`Shaped`] stream.
+#[derive(Clone, Debug)]
+pub struct Shaping<U> {
+    inner:    U,
+    upload:   Buckets,
+    download: Buckets,
+}
+
+impl<U: UpgradeInfo> UpgradeInfo for Shaping<U> {
+    type Info = U::Info;
+    type InfoIter = U::InfoIter;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info()
+    }
+}
+
+impl<C, U: InboundUpgrade<Shaped<C>>> InboundUpgrade<C> for Shaping<U> {
+    type Error = U::Error;
+    type Future = U::Future;
+    type Output = U::Output;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = Shaped::new(socket, self.upload, self.download);
+        self.inner.upgrade_inbound(socket, info)
+    }
+}
+
+impl<C, U: OutboundUpgrade<Shaped<C>>> OutboundUpgrade<C> for Shaping<U> {
+    type Error = U::Error;
+    type Future = U::Future;
+    type Output = U::Output;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = Shaped::new(socket, self.upload, self.download);
+        self.inner.upgrade_outbound(socket, info)
+    }
+}
+
+type PeerBuckets = (Weak<Mutex<TokenBucket>>, Weak<Mutex<TokenBucket>>);
+
+/// Hands out the buckets for new connections.
+#[derive(Clone, Debug)]
+pub struct Shaper {
+    config:   Config,
+    upload:   Buckets,
+    download: Buckets,
+    /// Buckets of peers with open connections.
+    peers:    Arc<Mutex<HashMap<PeerId, PeerBuckets>>>,
+}
+
+/// The bucket in `slot`, or a new one if no connection uses it any more.
+fn shared(
+    slot: &mut Weak<Mutex<TokenBucket>>,
+    rate: ByteUnit,
+    now: Instant,
+) -> Arc<Mutex<TokenBucket>> {
+    slot.upgrade().unwrap_or_else(|| {
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(rate, now)));
+        *slot = Arc::downgrade(&bucket);
+        bucket
+    })
+}
+
+impl Shaper {
+    pub fn new(config: Config) -> Self {
+        let now = Instant::now();
+        let global = |rate: Option<ByteUnit>| {
+            Buckets(
+                rate.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, now))))
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        Self {
+            config,
+            upload: global(config.upload),
+            download: global(config.download),
+            peers: Arc::default(),
+        }
+    }
+
+    /// Limit the connection to `peer_id` upgraded by `inner`.
+    pub fn apply<U>(&self, peer_id: &PeerId, inner: U) -> Shaping<U> {
+        let now = Instant::now();
+        let mut upload = self.upload.clone();
+        let mut download = self.download.clone();
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, (up, down)| up.strong_count() > 0 || down.strong_count() > 0);
+        let (up, down) = peers.entry(peer_id.clone()).or_default();
+        if let Some(rate) = self.config.peer_upload {
+            upload.0.push(shared(up, rate, now));
+        }
+        if let Some(rate) = self.config.peer_download {
+            download.0.push(shared(down, rate, now));
+        }
+        Shaping {
+            inner,
+            upload,
+            download,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use futures::AsyncWriteExt;
+    use ubyte::ToByteUnit;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = "upload=1MiB/s peer-download=256KiB".parse().unwrap();
+        assert_eq!(config, Config {
+            upload: Some(1.mebibytes()),
+            peer_download: Some(256.kibibytes()),
+            ..Config::default()
+        });
+        assert!("upload=0".parse::<Config>().is_err());
+        assert!("sideways=1MiB".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_bucket_limits_rate() {
+        let now = Instant::now();
+        let peer = Arc::new(Mutex::new(TokenBucket::new(1000.bytes(), now)));
+        let global = Arc::new(Mutex::new(TokenBucket::new(4000.bytes(), now)));
+        let buckets = Buckets(vec![peer, global.clone()]);
+        assert_eq!(buckets.available(1500, now), Ok(1000));
+        buckets.consume(1000);
+        assert_eq!(buckets.available(1500, now), Err(Duration::from_secs(1)));
+        assert_eq!(buckets.available(1500, now + Duration::from_millis(250)), Ok(250));
+        buckets.consume(250);
+        assert_eq!(global.lock().unwrap().tokens, 3750);
+        assert_eq!(buckets.available(0, now), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_shaped_stream_is_throttled() {
+        let config = Config {
+            peer_upload: Some(1000.bytes()),
+            ..Config::default()
+        };
+        let shaper = Shaper::new(config);
+        let shaping = shaper.apply(&PeerId::random(), ());
+        let mut stream = Shaped::new(Vec::new(), shaping.upload, shaping.download);
+        let start = Instant::now();
+        AsyncWriteExt::write_all(&mut stream, &[0_u8; 1500])
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(stream.inner.len(), 1500);
+    }
+}
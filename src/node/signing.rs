@@ -0,0 +1,206 @@
+//! The bytes the node signs and authenticates.
+//!
+//! Every signature the node makes, and the associated data of every payload
+//! or key it encrypts, covers a [`Layout`]: a versioned byte string that
+//! starts with the [`Domain`] of the message type and names the topic, so a
+//! signature or ciphertext made for one message type or topic does not
+//! verify as another. All integers are big endian:
+//!
+//! ```text
+//! "mesh-rs\0" VERSION:u8
+//! domain:     u8 length, bytes
+//! topic:      u32 length, bytes, empty for messages of no topic
+//! timestamp:  u64, milliseconds since the Unix epoch, 0 where there is none
+//! headers:    u16 count, then for each, sorted by name:
+//!             u8 name length, name, u32 value length, value
+//! payload:    u32 length, bytes
+//! ```
+//!
+//! Nodes only accept signatures over the layout of their own [`VERSION`].
+//! Records, actions and bundles signed, and payloads sealed, in another
+//! version fail to verify, including those kept in stores from before.
+//! Gossipsub signs its messages in its own format, which covers the topic.
+//! Provenance hops have no topic, as bridges may republish on another one.
+
+use std::{borrow::Cow, collections::BTreeMap, convert::TryFrom};
+
+/// Version of the layout.
+pub const VERSION: u8 = 1;
+
+/// Start of every layout.
+const MAGIC: &[u8] = b"mesh-rs\0";
+
+/// The message types that are signed or encrypted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Domain {
+    /// A [`super::naming`] record.
+    Name,
+    /// A [`super::moderation`] blocklist.
+    Blocklist,
+    /// A [`super::membership`] grant.
+    Grant,
+    /// The envelope of a published or direct message.
+    Message,
+    /// A LAN multicast datagram.
+    Multicast,
+    /// A relay hop in the provenance of a republished message.
+    Provenance,
+    /// A [`super::schedule`] action.
+    Schedule,
+    /// A [`super::dtn`] bundle.
+    Bundle,
+    /// A payload sealed with a topic key, see [`super::keyring`].
+    Payload,
+    /// A topic key wrapped to a member.
+    KeyWrap,
+    /// A [`super::lifecycle`] announcement.
+    Announcement,
+    /// An old identity vouching for its successor, see [`super::rotation`].
+    Rotation,
+    /// A [`super::capability`] to publish on a restricted topic.
+    Capability,
+    /// A [`super::nickname`] profile.
+    Profile,
+}
+
+impl Domain {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Name => "mesh-rs/name",
+            Self::Blocklist => "mesh-rs/blocklist",
+            Self::Grant => "mesh-rs/grant",
+            Self::Message => "mesh-rs/message",
+            Self::Multicast => "mesh-rs/multicast",
+            Self::Provenance => "mesh-rs/provenance",
+            Self::Schedule => "mesh-rs/schedule",
+            Self::Bundle => "mesh-rs/bundle",
+            Self::Payload => "mesh-rs/payload",
+            Self::KeyWrap => "mesh-rs/key-wrap",
+            Self::Announcement => "mesh-rs/announcement",
+            Self::Rotation => "mesh-rs/rotation",
+            Self::Capability => "mesh-rs/capability",
+            Self::Profile => "mesh-rs/profile",
+        }
+    }
+}
+
+/// What is signed or authenticated, see the [module docs](self).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Layout<'a> {
+    domain:    Domain,
+    topic:     &'a str,
+    timestamp: u64,
+    headers:   BTreeMap<&'static str, Cow<'a, [u8]>>,
+    payload:   &'a [u8],
+}
+
+impl<'a> Layout<'a> {
+    pub fn new(domain: Domain) -> Self {
+        Self {
+            domain,
+            topic: "",
+            timestamp: 0,
+            headers: BTreeMap::new(),
+            payload: &[],
+        }
+    }
+
+    pub fn topic(mut self, topic: &'a str) -> Self {
+        self.topic = topic;
+        self
+    }
+
+    /// Milliseconds since the Unix epoch.
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Add a header, replacing one of the same `name`.
+    pub fn header(mut self, name: &'static str, value: impl Into<Cow<'a, [u8]>>) -> Self {
+        self.headers.insert(name, value.into());
+        self
+    }
+
+    /// Add a number as an 8 byte header.
+    pub fn number(self, name: &'static str, value: u64) -> Self {
+        self.header(name, value.to_be_bytes().to_vec())
+    }
+
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64 + self.topic.len() + self.payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        let tag = self.domain.tag().as_bytes();
+        bytes.push(u8::try_from(tag.len()).expect("Domain tags are short"));
+        bytes.extend_from_slice(tag);
+        put(&mut bytes, self.topic.as_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        let count = u16::try_from(self.headers.len()).expect("Layouts have few headers");
+        bytes.extend_from_slice(&count.to_be_bytes());
+        for (name, value) in &self.headers {
+            bytes.push(u8::try_from(name.len()).expect("Header names are short"));
+            bytes.extend_from_slice(name.as_bytes());
+            put(&mut bytes, value);
+        }
+        put(&mut bytes, self.payload);
+        bytes
+    }
+}
+
+/// Append `value` with its u32 length.
+fn put(bytes: &mut Vec<u8>, value: &[u8]) {
+    let length = u32::try_from(value.len()).expect("Signed fields are below 4 GiB");
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_separates_domains_and_topics() {
+        let grant = Layout::new(Domain::Grant)
+            .topic("chat")
+            .header("member", &b"peer"[..])
+            .to_bytes();
+        assert_eq!(
+            grant,
+            [
+                &b"mesh-rs\0\x01\x0dmesh-rs/grant"[..],
+                &[0, 0, 0, 4],
+                b"chat",
+                &[0; 8],
+                &[0, 1, 6],
+                b"member",
+                &[0, 0, 0, 4],
+                b"peer",
+                &[0; 4],
+            ]
+            .concat()
+        );
+        let other_topic = Layout::new(Domain::Grant)
+            .topic("news")
+            .header("member", &b"peer"[..]);
+        assert_ne!(other_topic.to_bytes(), grant);
+        let other_domain = Layout::new(Domain::Blocklist)
+            .topic("chat")
+            .header("member", &b"peer"[..]);
+        assert_ne!(other_domain.to_bytes(), grant);
+
+        // Headers are sorted, fields length prefixed
+        let a = Layout::new(Domain::Name).number("b", 1).header("a", vec![2]);
+        let b = Layout::new(Domain::Name).header("a", vec![2]).number("b", 1);
+        assert_eq!(a.to_bytes(), b.to_bytes());
+        let split = Layout::new(Domain::Payload).topic("ab").payload(b"c");
+        let moved = Layout::new(Domain::Payload).topic("a").payload(b"bc");
+        assert_ne!(split.to_bytes(), moved.to_bytes());
+    }
+}
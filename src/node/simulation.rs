@@ -0,0 +1,363 @@
+//! Simulating large meshes in one process.
+//!
+//! Built with the `harness` feature. [`run`] starts `nodes` nodes of a
+//! [`Harness`] over the memory transport, each dialing `degree` of the nodes
+//! started before it, subscribes all to one topic and publishes `messages`
+//! numbered messages of `size` bytes in a traffic [`Pattern`], paced at
+//! `rate` per second. It then waits until every node received every message,
+//! or nothing arrived for [`QUIET`], and returns a [`Report`] of the delivery
+//! latency percentiles, the share of nodes each message reached and the
+//! duplicates pubsub dropped per delivery. Configure it the same way as the
+//! other subsystems:
+//!
+//! ```ignore
+//! # async fn example() -> anyhow::Result<()> {
+//! use mesh::node::simulation;
+//!
+//! let config = "nodes=100 degree=4 messages=200 pattern=random".parse()?;
+//! let report = tokio::task::LocalSet::new()
+//!     .run_until(simulation::run(&config))
+//!     .await?;
+//! println!("{}", report);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The criterion benchmarks of [`super::bench::group`] run the simulation of
+//! `MESH_SIMULATION`, or [`BENCH`] if unset. All nodes share the threads of
+//! the benchmark, so latencies include the time spent waiting for the other
+//! nodes and grow with their number. Deliveries beyond the route buffers of
+//! a node are dropped, see [`super::route`], which bursts can run into.
+
+use super::{harness::Harness, options, route, statsd::Sample};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use futures::channel::mpsc;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// Topic the simulated nodes publish on.
+pub const TOPIC: &str = "/mesh-rs/simulation/version/1";
+
+/// Stop waiting for deliveries after this long without one.
+pub const QUIET: Duration = Duration::from_secs(5);
+
+/// Simulation benchmarked when `MESH_SIMULATION` is not set.
+pub const BENCH: &str = "nodes=32 degree=4 messages=50 rate=200/s";
+
+/// Which nodes publish the messages, and when.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pattern {
+    /// The first node publishes every message, paced at the rate.
+    Single,
+    /// A random node publishes each message, paced at the rate.
+    Random,
+    /// Random nodes publish all messages at once.
+    Burst,
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "single" => Self::Single,
+            "random" => Self::Random,
+            "burst" => Self::Burst,
+            _ => bail!("Unknown traffic pattern {}, expected single, random or burst", s),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Nodes in the mesh.
+    pub nodes:    usize,
+    /// Nodes each node dials when it starts.
+    pub degree:   usize,
+    /// Messages published in total.
+    pub messages: usize,
+    /// Size of each message, at least the 8 byte message number.
+    pub size:     ByteUnit,
+    /// Messages published per second, unless bursting.
+    pub rate:     u64,
+    pub pattern:  Pattern,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nodes:    100,
+            degree:   4,
+            messages: 100,
+            size:     256.bytes(),
+            rate:     100,
+            pattern:  Pattern::Random,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let parse_count = |value: &str| {
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid {} {}", key, value))
+            };
+            match key {
+                "nodes" => config.nodes = parse_count(value)?,
+                "degree" => config.degree = parse_count(value)?,
+                "messages" => config.messages = parse_count(value)?,
+                "size" => {
+                    config.size = value
+                        .parse::<ByteUnit>()
+                        .map_err(|err| anyhow!("Invalid size {}: {}", value, err))?;
+                }
+                "rate" => {
+                    let rate = value.strip_suffix("/s").unwrap_or(value);
+                    config.rate = rate
+                        .parse()
+                        .with_context(|| format!("Invalid rate {}", value))?;
+                }
+                "pattern" => config.pattern = value.parse()?,
+                _ => bail!("Unknown simulation option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.nodes >= 2, "Simulation needs at least two nodes");
+        ensure!(self.degree > 0, "Simulation degree must be positive");
+        ensure!(self.messages > 0, "Simulation needs at least one message");
+        ensure!(self.rate > 0, "Simulation rate must be positive");
+        ensure!(
+            self.size.as_u64() >= 8,
+            "Simulation messages must be at least 8 bytes"
+        );
+        Ok(())
+    }
+}
+
+/// What a simulation measured.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    pub nodes:      usize,
+    pub messages:   usize,
+    /// Messages received by nodes other than their publisher.
+    pub deliveries: usize,
+    /// Deliveries if every message reached every other node.
+    pub expected:   usize,
+    /// Messages pubsub dropped as seen before, per delivery.
+    pub duplicates: f64,
+    /// Fraction of the other nodes the least and the average message
+    /// reached.
+    pub coverage:   (f64, f64),
+    /// Delivery latency percentiles 50, 90 and 99, and the slowest.
+    pub latency:    [Duration; 4],
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [p50, p90, p99, max] = self.latency;
+        writeln!(
+            f,
+            "{} nodes, {} messages: {} of {} deliveries",
+            self.nodes, self.messages, self.deliveries, self.expected
+        )?;
+        writeln!(
+            f,
+            "coverage {:.1}% average, {:.1}% least",
+            self.coverage.1 * 100.0,
+            self.coverage.0 * 100.0
+        )?;
+        writeln!(f, "duplicates {:.3} per delivery", self.duplicates)?;
+        write!(
+            f,
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            p50, p90, p99, max
+        )
+    }
+}
+
+/// Latency percentile `percent` of the `sorted` latencies.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let rank = (sorted.len() * percent + 99) / 100;
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+/// Sum of the counter `name` over all nodes.
+async fn counter(harness: &Harness, name: &str) -> Result<u64> {
+    let mut total = 0;
+    for index in 0..harness.nodes().len() {
+        for sample in harness.handle(index).samples().await? {
+            if let Sample::Counter(sample, value) = sample {
+                if sample == name {
+                    total += value;
+                }
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// A message received by node `node`, when it arrived.
+struct Delivery {
+    node:    usize,
+    message: u64,
+    at:      Instant,
+}
+
+/// Run the simulation of `config` on the current tokio `LocalSet`.
+pub async fn run(config: &Config) -> Result<Report> {
+    config.validate()?;
+    let mut harness = Harness::spawn_sparse(config.nodes, config.degree).await?;
+    harness.subscribe(TOPIC).await?;
+    let (sender, mut deliveries) = mpsc::unbounded();
+    for index in 0..config.nodes {
+        let sender = sender.clone();
+        harness
+            .handle(index)
+            .route(TOPIC, 1, move |message: route::Message| {
+                let at = Instant::now();
+                let message = message.data.get(..8).and_then(|bytes| bytes.try_into().ok());
+                if let Some(message) = message.map(u64::from_be_bytes) {
+                    let _ = sender.unbounded_send(Delivery {
+                        node: index,
+                        message,
+                        at,
+                    });
+                }
+                future::ready(())
+            })
+            .await?;
+    }
+    drop(sender);
+    let duplicates = counter(&harness, "pubsub.duplicates").await?;
+
+    let mut rng = StdRng::seed_from_u64(config.messages as u64);
+    let mut published = HashMap::with_capacity(config.messages);
+    let interval = Duration::from_secs(1) / config.rate.try_into().unwrap_or(u32::MAX);
+    let size = config.size.as_u64().try_into().unwrap_or(usize::MAX);
+    for message in 0..config.messages {
+        let publisher = match config.pattern {
+            Pattern::Single => 0,
+            Pattern::Random | Pattern::Burst => rng.gen_range(0, config.nodes),
+        };
+        let mut data = vec![0_u8; size];
+        data[..8].copy_from_slice(&(message as u64).to_be_bytes());
+        published.insert(message as u64, (publisher, Instant::now()));
+        harness.handle(publisher).publish(TOPIC, &data).await?;
+        if config.pattern != Pattern::Burst {
+            sleep(interval).await;
+        }
+    }
+
+    let expected = config.messages * (config.nodes - 1);
+    let mut received = HashSet::with_capacity(expected);
+    let mut latencies = Vec::with_capacity(expected);
+    while received.len() < expected {
+        let delivery = match timeout(QUIET, deliveries.next()).await {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) | Err(_) => break,
+        };
+        let (publisher, sent) = match published.get(&delivery.message) {
+            Some(published) => *published,
+            None => continue,
+        };
+        if delivery.node != publisher && received.insert((delivery.node, delivery.message)) {
+            latencies.push(delivery.at.saturating_duration_since(sent));
+        }
+    }
+    let duplicates = counter(&harness, "pubsub.duplicates")
+        .await?
+        .saturating_sub(duplicates);
+    harness.shutdown().await?;
+
+    let mut reached = vec![0_usize; config.messages];
+    for (_, message) in &received {
+        reached[*message as usize] += 1;
+    }
+    let others = (config.nodes - 1) as f64;
+    let least = reached.iter().min().copied().unwrap_or_default();
+    latencies.sort();
+    Ok(Report {
+        nodes: config.nodes,
+        messages: config.messages,
+        deliveries: received.len(),
+        expected,
+        duplicates: duplicates as f64 / received.len().max(1) as f64,
+        coverage: (
+            least as f64 / others,
+            received.len() as f64 / (others * config.messages as f64),
+        ),
+        latency: [
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+            latencies.last().copied().unwrap_or_default(),
+        ],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use tokio::task::LocalSet;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "nodes=10 degree=2 messages=5 size=1KiB rate=50/s pattern=burst"
+            .parse()
+            .unwrap();
+        assert_eq!(config.nodes, 10);
+        assert_eq!(config.degree, 2);
+        assert_eq!(config.size, 1.kibibytes());
+        assert_eq!(config.rate, 50);
+        assert_eq!(config.pattern, Pattern::Burst);
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!(BENCH.parse::<Config>().is_ok());
+        assert!("nodes=1".parse::<Config>().is_err());
+        assert!("size=4B".parse::<Config>().is_err());
+        assert!("pattern=flood".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let latencies = (1..=10).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&latencies, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&[], 50), Duration::default());
+    }
+
+    #[tokio::test]
+    async fn test_reaches_every_node() {
+        let config: Config = "nodes=6 degree=2 messages=4 rate=20/s".parse().unwrap();
+        let report = LocalSet::new().run_until(run(&config)).await.unwrap();
+        assert_eq!(report.expected, 20);
+        assert_eq!(report.deliveries, 20);
+        assert_eq!(report.coverage, (1.0, 1.0));
+        assert!(report.latency[0] <= report.latency[3]);
+    }
+}
@@ -0,0 +1,268 @@
+//! Soak testing with synthetic traffic.
+//!
+//! Every node started with `--soak rate=1000/s size=512B topics=10` publishes
+//! numbered messages round-robin over the soak topics and checks what it
+//! receives from the other soak nodes. Two invariants are checked every
+//! [`REPORT_INTERVAL`]:
+//!
+//! * No message loss: every sequence number of a sender arrives within
+//!   [`LOSS_GRACE`] of a higher one.
+//! * Bounded memory: resident memory does not grow more than `memory` (default
+//!   64MiB) past its size after the first report.
+//!
+//! A violation stops the node with an error, so a soak run can gate a release.
+
+use super::{options, subscriptions::TopicOptions, Event, NodeHandle};
+use crate::prelude::*;
+use anyhow::{anyhow, bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use ubyte::{ByteUnit, ToByteUnit};
+
+/// How often to report statistics and check invariants.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a missing message may lag behind later ones before it is lost.
+pub const LOSS_GRACE: Duration = Duration::from_secs(5);
+
+const SEND_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Topic `index` of the soak test.
+pub fn topic(index: usize) -> String {
+    format!("/mesh-rs/soak/{}/version/1", index)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Messages published per second.
+    pub rate:   u64,
+    /// Size of each message, at least the 8 byte sequence number.
+    pub size:   ByteUnit,
+    /// Number of topics to spread the messages over.
+    pub topics: usize,
+    /// Allowed growth of resident memory after the first report.
+    pub memory: ByteUnit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rate:   100,
+            size:   512.bytes(),
+            topics: 1,
+            memory: 64.mebibytes(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let parse_size = |value: &str| {
+                value
+                    .parse::<ByteUnit>()
+                    .map_err(|err| anyhow!("Invalid {} {}: {}", key, value, err))
+            };
+            match key {
+                "rate" => {
+                    let rate = value.strip_suffix("/s").unwrap_or(value);
+                    config.rate = rate
+                        .parse()
+                        .with_context(|| format!("Invalid rate {}", value))?;
+                }
+                "size" => config.size = parse_size(value)?,
+                "memory" => config.memory = parse_size(value)?,
+                "topics" => {
+                    config.topics = value
+                        .parse()
+                        .with_context(|| format!("Invalid topics {}", value))?;
+                }
+                _ => bail!("Unknown soak option {}", key),
+            }
+        }
+        ensure!(config.rate > 0, "Soak rate must be positive");
+        ensure!(config.topics > 0, "Soak needs at least one topic");
+        ensure!(
+            config.size.as_u64() >= 8,
+            "Soak messages must be at least 8 bytes"
+        );
+        Ok(config)
+    }
+}
+
+/// Sequence numbers received from one sender.
+#[derive(Clone, Debug, Default)]
+struct Sender {
+    highest:  Option<u64>,
+    /// Skipped sequence numbers, with the time we noticed.
+    missing:  BTreeMap<u64, Instant>,
+    received: u64,
+}
+
+/// Loss accounting over all senders.
+#[derive(Clone, Debug, Default)]
+struct Tracker {
+    senders: HashMap<PeerId, Sender>,
+    lost:    u64,
+}
+
+impl Tracker {
+    fn receive(&mut self, source: PeerId, sequence: u64, now: Instant) {
+        let sender = self.senders.entry(source).or_default();
+        sender.received += 1;
+        match sender.highest {
+            Some(highest) if sequence <= highest => {
+                sender.missing.remove(&sequence);
+            }
+            Some(highest) => {
+                for skipped in highest + 1..sequence {
+                    sender.missing.insert(skipped, now);
+                }
+                sender.highest = Some(sequence);
+            }
+            // Whatever was sent before we joined does not count
+            None => sender.highest = Some(sequence),
+        }
+    }
+
+    /// Count messages missing for longer than [`LOSS_GRACE`] as lost.
+    fn expire(&mut self, now: Instant) -> u64 {
+        let mut lost = 0;
+        for sender in self.senders.values_mut() {
+            let before = sender.missing.len();
+            sender
+                .missing
+                .retain(|_, noticed| now.duration_since(*noticed) < LOSS_GRACE);
+            lost += (before - sender.missing.len()) as u64;
+        }
+        self.lost += lost;
+        lost
+    }
+
+    fn received(&self) -> u64 {
+        self.senders.values().map(|sender| sender.received).sum()
+    }
+}
+
+/// Resident memory of this process, where the platform tells us.
+fn resident_memory() -> Option<ByteUnit> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some((pages * 4096).bytes())
+}
+
+/// Generate traffic and check invariants until one is violated.
+pub async fn run(mut handle: NodeHandle, config: Config) -> Result<()> {
+    info!(
+        "Soak test: {}/s of {} over {} topics",
+        config.rate, config.size, config.topics
+    );
+    let topics = (0..config.topics).map(topic).collect::<Vec<_>>();
+    for topic in &topics {
+        handle.subscribe(topic, TopicOptions::default()).await?;
+    }
+    let mut events = handle.events().await?;
+
+    let start = Instant::now();
+    let mut send = interval(SEND_INTERVAL);
+    let mut report = interval(REPORT_INTERVAL);
+    // The first tick completes immediately
+    report.tick().await;
+    let mut tracker = Tracker::default();
+    let mut attempted = 0_u64;
+    let mut sequence = 0_u64;
+    let mut baseline = None;
+    let mut payload = vec![0_u8; config.size.as_u64() as usize];
+    loop {
+        tokio::select! {
+            _ = send.tick() => {
+                let due = (start.elapsed().as_secs_f64() * config.rate as f64) as u64;
+                while attempted < due {
+                    attempted += 1;
+                    payload[..8].copy_from_slice(&sequence.to_be_bytes());
+                    let topic = &topics[(attempted % topics.len() as u64) as usize];
+                    match handle.publish(topic, &payload).await {
+                        Ok(()) => sequence += 1,
+                        Err(err) => trace!("Soak message not published: {}", err),
+                    }
+                }
+            }
+            Some(Event::Message { source, topic, data, .. }) = events.next() => {
+                if topics.contains(&topic) {
+                    if let Some(bytes) = data.get(..8) {
+                        let sequence = u64::from_be_bytes(bytes.try_into().unwrap());
+                        tracker.receive(source, sequence, Instant::now());
+                    }
+                }
+            }
+            _ = report.tick() => {
+                let lost = tracker.expire(Instant::now());
+                let memory = resident_memory();
+                info!(
+                    "Soak: sent {}/{}, received {} from {} senders, lost {}, memory {}",
+                    sequence,
+                    attempted,
+                    tracker.received(),
+                    tracker.senders.len(),
+                    tracker.lost,
+                    memory.map_or_else(|| "unknown".into(), |memory| memory.to_string())
+                );
+                ensure!(lost == 0, "Soak test lost {} messages", lost);
+                if let Some(memory) = memory {
+                    let baseline = *baseline.get_or_insert(memory);
+                    ensure!(
+                        memory <= baseline + config.memory,
+                        "Soak test memory grew from {} to {}",
+                        baseline,
+                        memory
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = "rate=1000/s size=512B topics=10".parse().unwrap();
+        assert_eq!(config, Config {
+            rate: 1000,
+            size: 512.bytes(),
+            topics: 10,
+            ..Config::default()
+        });
+        assert!("rate=0".parse::<Config>().is_err());
+        assert!("size=4B".parse::<Config>().is_err());
+        assert!("speed=1".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_reordering_is_not_loss() {
+        let now = Instant::now();
+        let source = PeerId::random();
+        let mut tracker = Tracker::default();
+        for sequence in &[10, 11, 13, 12, 16] {
+            tracker.receive(source.clone(), *sequence, now);
+        }
+        assert_eq!(tracker.expire(now + LOSS_GRACE / 2), 0);
+        tracker.receive(source, 15, now);
+        assert_eq!(tracker.expire(now + LOSS_GRACE), 1);
+        assert_eq!(tracker.received(), 6);
+    }
+}
@@ -0,0 +1,87 @@
+//! Spans giving log records their context, see [`crate::logging`].
+//!
+//! A node runs in a `swarm` span with its `peer_id`. Each connected peer
+//! gets a `connection` span under it with the `peer` and the `address` of
+//! the first connection, open until its last connection closes. Messages
+//! are handled in a `publish` or `receive` span with their `topic` and
+//! `message_id`, a receive span under the connection of the sender when it
+//! is connected.
+//!
+//! The message id is the SHA-256 of the peer that sent the envelope and its
+//! timestamp, so the sender and every receiver log the same id. Bare
+//! messages, see [`super::compat`], carry no timestamp and have none.
+
+use super::hlc::Timestamp;
+use libp2p::{Multiaddr, PeerId};
+use sha2::{Digest, Sha256};
+use tracing::{debug_span, field, info_span, Span};
+
+/// Id of the message `sender` sent at `timestamp`, 32 hex digits.
+pub fn message_id(sender: &PeerId, timestamp: Timestamp) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(sender.as_bytes());
+    hasher.update(timestamp.wall_ms.to_be_bytes());
+    hasher.update(timestamp.logical.to_be_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+/// The span of the node with `peer_id`.
+pub fn swarm(peer_id: &PeerId) -> Span {
+    info_span!("swarm", peer_id = %peer_id)
+}
+
+/// The span of the connections to `peer`, the first at `address`, under
+/// the `swarm` span.
+pub fn connection(swarm: &Span, peer: &PeerId, address: &Multiaddr) -> Span {
+    info_span!(parent: swarm, "connection", peer = %peer, address = %address)
+}
+
+/// The span of publishing the message we send at `timestamp` on `topic`.
+pub fn publish(local: &PeerId, timestamp: Timestamp, topic: &str) -> Span {
+    debug_span!(
+        "publish",
+        message_id = %message_id(local, timestamp),
+        topic = %topic,
+    )
+}
+
+/// The span of handling the message with `message_id` on `topic`, under
+/// `parent`.
+pub fn receive(parent: &Span, message_id: Option<&str>, topic: &str) -> Span {
+    let span = debug_span!(
+        parent: parent,
+        "receive",
+        message_id = field::Empty,
+        topic = %topic,
+    );
+    if let Some(message_id) = message_id {
+        span.record("message_id", &message_id);
+    }
+    span
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_message_ids_match_sender_and_time() {
+        let (sender, other) = (PeerId::random(), PeerId::random());
+        let timestamp = Timestamp {
+            wall_ms: 1000,
+            logical: 1,
+        };
+        let id = message_id(&sender, timestamp);
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, message_id(&sender, timestamp));
+        assert_ne!(id, message_id(&other, timestamp));
+        assert_ne!(
+            id,
+            message_id(&sender, Timestamp {
+                logical: 2,
+                ..timestamp
+            })
+        );
+    }
+}
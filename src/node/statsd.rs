@@ -0,0 +1,203 @@
+//! Pushing metrics to StatsD.
+//!
+//! Started with `--statsd "address=127.0.0.1:8125 interval=10s prefix=mesh"`
+//! the node sends its metrics over UDP every `interval`:
+//!
+//! * `<prefix>.peers.connected`, `<prefix>.peers.known` and
+//!   `<prefix>.subscriptions` gauges.
+//! * `<prefix>.bandwidth.inbound` and `<prefix>.bandwidth.outbound` counters,
+//!   in bytes.
+//! * A `<prefix>.ping` timer sample per connected peer.
+//! * A `<prefix>.aggregate.<name>` gauge per mesh-wide [`super::aggregate`].
+//!
+//! Graphite users can point this at a StatsD server with a Graphite backend.
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, time::Duration};
+use tokio::net::UdpSocket;
+
+/// Largest datagram sent, to stay below the Ethernet MTU.
+const MAX_PACKET: usize = 1432;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// Where the StatsD server listens.
+    pub address:  SocketAddr,
+    /// How often to push.
+    pub interval: Duration,
+    /// Prepended to every metric name.
+    pub prefix:   String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address:  SocketAddr::from(([127, 0, 0, 1], 8125)),
+            interval: Duration::from_secs(10),
+            prefix:   "mesh".into(),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "address" => {
+                    config.address = value
+                        .parse()
+                        .with_context(|| format!("Invalid address {}", value))?;
+                }
+                "interval" => {
+                    config.interval = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid interval {}", value))?;
+                }
+                "prefix" => config.prefix = value.into(),
+                _ => bail!("Unknown statsd option {}", key),
+            }
+        }
+        ensure!(
+            config.interval > Duration::from_secs(0),
+            "StatsD interval must be positive"
+        );
+        Ok(config)
+    }
+}
+
+/// A measurement, named without the prefix.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Sample {
+    /// A running total. The increase since the previous push is sent.
+    Counter(String, u64),
+    Gauge(String, i64),
+    Timer(String, Duration),
+}
+
+/// Sends samples to a StatsD server.
+#[derive(Debug)]
+pub struct Exporter {
+    config: Config,
+    socket: UdpSocket,
+    /// Counter totals at the previous push.
+    totals: HashMap<String, u64>,
+}
+
+impl Exporter {
+    pub async fn new(config: Config) -> Result<Self> {
+        let local: SocketAddr = if config.address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0_u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .context("Binding StatsD socket")?;
+        info!("Pushing metrics to StatsD at {}", config.address);
+        Ok(Self {
+            config,
+            socket,
+            totals: HashMap::new(),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Render `samples` as StatsD lines.
+    fn lines(&mut self, samples: Vec<Sample>) -> Vec<String> {
+        let prefix = &self.config.prefix;
+        let mut lines = Vec::new();
+        for sample in samples {
+            match sample {
+                Sample::Counter(name, total) => {
+                    let previous = self.totals.insert(name.clone(), total).unwrap_or(0);
+                    // A counter that went down was reset, count from zero
+                    let delta = total.checked_sub(previous).unwrap_or(total);
+                    lines.push(format!("{}.{}:{}|c", prefix, name, delta));
+                }
+                Sample::Gauge(name, value) => {
+                    // A leading sign would make it a relative change
+                    if value < 0 {
+                        lines.push(format!("{}.{}:0|g", prefix, name));
+                    }
+                    lines.push(format!("{}.{}:{}|g", prefix, name, value));
+                }
+                Sample::Timer(name, duration) => {
+                    let millis = duration.as_secs_f64() * 1000.0;
+                    lines.push(format!("{}.{}:{:.3}|ms", prefix, name, millis));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Send `samples`, packing as many lines per datagram as fit.
+    pub async fn push(&mut self, samples: Vec<Sample>) -> Result<()> {
+        let mut packet = String::new();
+        for line in self.lines(samples) {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+                self.send(&packet).await?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            self.send(&packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, packet: &str) -> Result<()> {
+        self.socket
+            .send_to(packet.as_bytes(), self.config.address)
+            .await
+            .with_context(|| format!("Sending metrics to {}", self.config.address))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_pushes_counter_increases() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config: Config = format!("address={} prefix=node", server.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        assert_eq!(config.interval, Duration::from_secs(10));
+        assert!("interval=0s".parse::<Config>().is_err());
+        assert!("port=1".parse::<Config>().is_err());
+
+        let mut exporter = Exporter::new(config).await.unwrap();
+        let samples = |total| {
+            vec![
+                Sample::Counter("bytes".into(), total),
+                Sample::Gauge("level".into(), -2),
+                Sample::Timer("ping".into(), Duration::from_micros(1500)),
+            ]
+        };
+        exporter.push(samples(100)).await.unwrap();
+        exporter.push(samples(130)).await.unwrap();
+        let mut buffer = [0; MAX_PACKET];
+        let (len, _) = server.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(
+            &buffer[..len],
+            &b"node.bytes:100|c\nnode.level:0|g\nnode.level:-2|g\nnode.ping:1.500|ms"[..]
+        );
+        let (len, _) = server.recv_from(&mut buffer).await.unwrap();
+        assert!(buffer[..len].starts_with(b"node.bytes:30|c\n"));
+    }
+}
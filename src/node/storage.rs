@@ -0,0 +1,565 @@
+//! Where the persistent subsystems keep their state.
+//!
+//! The address book, the bans of the [`gate`], the subscriptions, the
+//! [`archive`], the [`outbox`], the bundles of [`dtn`] mode and the
+//! [`keystore`] read and write their state through a [`Storage`]: values
+//! by key in a namespace per subsystem, each subsystem with a [`Slot`] of
+//! its own. Keys and namespaces are short names of ASCII letters, digits,
+//! `.`, `_` and `-`, not starting with `.`, so every backend can store them
+//! as they are.
+//!
+//! `--storage files`, the default, keeps each value in a file of its own,
+//! `<data dir>/storage/<namespace>/<key>`, replaced atomically, synced and
+//! readable by its owner only. `--storage sled` keeps them in a [sled]
+//! database in `<data dir>/storage.sled`, in builds with the `sled`
+//! feature. Opening a data directory moves the files of the subsystems
+//! written before there was storage, like `peers.json`, into it, and a new
+//! sled database starts with the values of the files backend. Embedders may
+//! supply their own backend, say on SQLite, by implementing [`Storage`] and
+//! handing it to [`NodeBuilder::with_storage`]; [`Memory`] keeps values for
+//! tests.
+//!
+//! [`gate`]: super::gate
+//! [`archive`]: super::archive
+//! [`outbox`]: super::outbox
+//! [`dtn`]: super::dtn
+//! [`keystore`]: super::keystore
+//! [sled]: https://docs.rs/sled
+//! [`NodeBuilder::with_storage`]: super::NodeBuilder::with_storage
+
+use super::{addressbook, archive, dtn, gate, keystore, outbox, subscriptions};
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Directory of the files backend inside the data directory.
+pub const FILES_DIR: &str = "storage";
+
+/// Directory of the sled backend inside the data directory.
+pub const SLED_DIR: &str = "storage.sled";
+
+/// Values of the subsystems that were files of the data directory, by
+/// namespace. The key is the old file name.
+const LEGACY: &[(&str, &str)] = &[
+    (addressbook::NAMESPACE, addressbook::KEY),
+    (archive::NAMESPACE, archive::KEY),
+    (dtn::NAMESPACE, dtn::KEY),
+    (gate::NAMESPACE, gate::KEY),
+    (keystore::NAMESPACE, keystore::KEY),
+    (outbox::NAMESPACE, outbox::KEY),
+    (subscriptions::NAMESPACE, subscriptions::KEY),
+];
+
+/// A key-value store of namespaces.
+pub trait Storage: Send + Sync {
+    /// The value of `key` in `namespace`, if any.
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Set `key` in `namespace` to `value`. Readers see the old value or the
+    /// new one, never a part.
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Remove `key` from `namespace`, if there.
+    fn remove(&self, namespace: &str, key: &str) -> Result<()>;
+
+    /// The keys of `namespace` with their values, ordered by key.
+    fn iterate(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>>;
+
+    /// The namespaces holding values, ordered.
+    fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// Make what was put so far survive a crash of the host.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A storage shared by the subsystems.
+pub type Shared = Arc<dyn Storage>;
+
+/// Fail for names backends may not store as they are.
+pub fn check_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'),
+        "Invalid storage name {:?}",
+        name
+    );
+    Ok(())
+}
+
+/// The built-in backends.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Files,
+    Sled,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Files
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "files" => Self::Files,
+            "sled" => Self::Sled,
+            _ => bail!("Unknown storage {}, expected files or sled", s),
+        })
+    }
+}
+
+/// Open the storage of `backend` in `data_dir`, taking over the files of
+/// subsystems written before there was storage.
+pub fn open(backend: Backend, data_dir: &Path) -> Result<Shared> {
+    let files = Files::new(&data_dir.join(FILES_DIR));
+    let storage: Shared = match backend {
+        Backend::Files => Arc::new(files),
+        #[cfg(feature = "sled")]
+        Backend::Sled => {
+            let sled = Sled::open(&data_dir.join(SLED_DIR))?;
+            if sled.namespaces()?.is_empty() {
+                copy(&files, &sled)?;
+            }
+            Arc::new(sled)
+        }
+        #[cfg(not(feature = "sled"))]
+        Backend::Sled => bail!("Built without sled storage, enable the sled feature"),
+    };
+    for (namespace, key) in LEGACY {
+        let path = data_dir.join(key);
+        if !path.exists() || storage.get(namespace, key)?.is_some() {
+            continue;
+        }
+        let value = fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+        storage.put(namespace, key, &value)?;
+        fs::remove_file(&path).with_context(|| format!("Removing {}", path.display()))?;
+        info!("Moved {} into storage", path.display());
+    }
+    Ok(storage)
+}
+
+/// Put every value of `from` into `to`.
+pub fn copy(from: &dyn Storage, to: &dyn Storage) -> Result<()> {
+    for namespace in from.namespaces()? {
+        for (key, value) in from.iterate(&namespace)? {
+            to.put(&namespace, &key, &value)?;
+        }
+    }
+    to.flush()
+}
+
+/// Write `value` to a temporary file readable by its owner only, sync it
+/// and rename it to `path`, so a crash can not leave a truncated file
+/// behind.
+fn write_file(path: &Path, temp: &Path, value: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(temp)
+        .and_then(|mut file| {
+            file.write_all(value)?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Writing {}", temp.display()))?;
+    fs::rename(temp, path).with_context(|| format!("Writing {}", path.display()))
+}
+
+/// Values in files of their own, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Files {
+    root: PathBuf,
+}
+
+impl Files {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_owned(),
+        }
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> Result<PathBuf> {
+        check_name(namespace)?;
+        check_name(key)?;
+        Ok(self.root.join(namespace).join(key))
+    }
+
+    /// The names in `dir` that are not temporary files, ordered.
+    fn names(dir: &Path) -> Result<Vec<(String, fs::FileType)>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).with_context(|| format!("Reading {}", dir.display())),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Reading {}", dir.display()))?;
+            if let Some(name) = entry.file_name().to_str() {
+                if check_name(name).is_ok() {
+                    names.push((name.to_owned(), entry.file_type()?));
+                }
+            }
+        }
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(names)
+    }
+}
+
+impl Storage for Files {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(namespace, key)?;
+        match fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Reading {}", path.display())),
+        }
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        let path = self.path(namespace, key)?;
+        write_file(&path, &self.root.join(namespace).join(format!(".{}.tmp", key)), value)
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<()> {
+        let path = self.path(namespace, key)?;
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn iterate(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        check_name(namespace)?;
+        let mut values = Vec::new();
+        for (key, kind) in Self::names(&self.root.join(namespace))? {
+            if kind.is_file() {
+                if let Some(value) = self.get(namespace, &key)? {
+                    values.push((key, value));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut namespaces = Vec::new();
+        for (namespace, kind) in Self::names(&self.root)? {
+            if kind.is_dir() && !Self::names(&self.root.join(&namespace))?.is_empty() {
+                namespaces.push(namespace);
+            }
+        }
+        Ok(namespaces)
+    }
+}
+
+/// Values in a sled database, a tree per namespace.
+#[cfg(feature = "sled")]
+pub struct Sled(sled::Db);
+
+#[cfg(feature = "sled")]
+impl Sled {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        Ok(Self(db))
+    }
+}
+
+#[cfg(feature = "sled")]
+impl Storage for Sled {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let tree = self.0.open_tree(namespace)?;
+        Ok(tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        check_name(namespace)?;
+        check_name(key)?;
+        self.0.open_tree(namespace)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<()> {
+        check_name(namespace)?;
+        check_name(key)?;
+        self.0.open_tree(namespace)?.remove(key)?;
+        Ok(())
+    }
+
+    fn iterate(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        check_name(namespace)?;
+        let mut values = Vec::new();
+        for entry in self.0.open_tree(namespace)?.iter() {
+            let (key, value) = entry?;
+            values.push((String::from_utf8_lossy(&key).into_owned(), value.to_vec()));
+        }
+        Ok(values)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut namespaces = Vec::new();
+        for name in self.0.tree_names() {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            if check_name(&name).is_ok() && !self.0.open_tree(&name)?.is_empty() {
+                namespaces.push(name);
+            }
+        }
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Values in memory, lost with the process.
+#[derive(Debug, Default)]
+pub struct Memory(Mutex<BTreeMap<String, BTreeMap<String, Vec<u8>>>>);
+
+impl Storage for Memory {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let namespaces = self.0.lock().unwrap();
+        Ok(namespaces
+            .get(namespace)
+            .and_then(|values| values.get(key))
+            .cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let mut namespaces = self.0.lock().unwrap();
+        namespaces
+            .entry(namespace.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<()> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let mut namespaces = self.0.lock().unwrap();
+        if let Some(values) = namespaces.get_mut(namespace) {
+            values.remove(key);
+            if values.is_empty() {
+                namespaces.remove(namespace);
+            }
+        }
+        Ok(())
+    }
+
+    fn iterate(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        check_name(namespace)?;
+        let namespaces = self.0.lock().unwrap();
+        Ok(namespaces.get(namespace).map_or_else(Vec::new, |values| {
+            values
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }))
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.0.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Where a subsystem keeps its state.
+#[derive(Clone)]
+pub enum Slot {
+    /// A file of its own, like an `--identity` given.
+    File(PathBuf),
+    /// A key of a storage.
+    Stored {
+        storage:   Shared,
+        namespace: &'static str,
+        key:       &'static str,
+    },
+}
+
+impl Slot {
+    pub fn new(storage: &Shared, namespace: &'static str, key: &'static str) -> Self {
+        Self::Stored {
+            storage: storage.clone(),
+            namespace,
+            key,
+        }
+    }
+
+    /// The value kept, if any.
+    pub fn read(&self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::File(path) => match fs::read(path) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err).with_context(|| format!("Reading {}", path.display())),
+            },
+            Self::Stored {
+                storage,
+                namespace,
+                key,
+            } => storage
+                .get(namespace, key)
+                .with_context(|| format!("Reading {}", self)),
+        }
+    }
+
+    /// Keep `value`, replacing what was kept.
+    pub fn write(&self, value: &[u8]) -> Result<()> {
+        match self {
+            Self::File(path) => {
+                let mut temp = path.clone().into_os_string();
+                temp.push(".tmp");
+                write_file(path, Path::new(&temp), value)
+            }
+            Self::Stored {
+                storage,
+                namespace,
+                key,
+            } => storage
+                .put(namespace, key, value)
+                .with_context(|| format!("Writing {}", self)),
+        }
+    }
+
+    /// Make what was written survive a crash of the host. Files are synced
+    /// as they are written.
+    pub fn flush(&self) -> Result<()> {
+        match self {
+            Self::File(_) => Ok(()),
+            Self::Stored { storage, .. } => storage.flush(),
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Stored { namespace, key, .. } => write!(f, "{}/{} in storage", namespace, key),
+        }
+    }
+}
+
+/// Slots are the same key of the same storage, or the same file.
+impl PartialEq for Slot {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::File(path), Self::File(other)) => path == other,
+            (
+                Self::Stored {
+                    storage,
+                    namespace,
+                    key,
+                },
+                Self::Stored {
+                    storage: other,
+                    namespace: other_namespace,
+                    key: other_key,
+                },
+            ) => {
+                Arc::as_ptr(storage) as *const u8 == Arc::as_ptr(other) as *const u8
+                    && namespace == other_namespace
+                    && key == other_key
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Slot {}
+
+impl fmt::Debug for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn exercise(storage: &dyn Storage) {
+        assert_eq!(storage.get("peers", "a").unwrap(), None);
+        storage.put("peers", "b", b"2").unwrap();
+        storage.put("peers", "a", b"1").unwrap();
+        storage.put("peers", "a", b"one").unwrap();
+        storage.put("outbox", "1", b"").unwrap();
+        assert_eq!(storage.get("peers", "a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(storage.iterate("peers").unwrap(), vec![
+            ("a".to_owned(), b"one".to_vec()),
+            ("b".to_owned(), b"2".to_vec()),
+        ]);
+        assert_eq!(storage.namespaces().unwrap(), vec!["outbox", "peers"]);
+        storage.remove("outbox", "1").unwrap();
+        storage.remove("outbox", "1").unwrap();
+        assert_eq!(storage.namespaces().unwrap(), vec!["peers"]);
+        assert!(storage.put("peers", "../a", b"").is_err());
+        assert!(storage.put("", "a", b"").is_err());
+        assert!(storage.get("peers", "../a").is_err());
+        assert!(storage.remove(".peers", "a").is_err());
+        assert!(storage.iterate("peers/a").is_err());
+        storage.flush().unwrap();
+    }
+
+    #[test]
+    fn test_backends_store_by_namespace() {
+        exercise(&Memory::default());
+        let dir = std::env::temp_dir().join(format!("mesh-storage-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        exercise(&Files::new(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+        #[cfg(feature = "sled")]
+        {
+            exercise(&Sled::open(&dir).unwrap());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_takes_over_legacy_files() {
+        let dir = std::env::temp_dir().join(format!("mesh-legacy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(addressbook::KEY), b"{}").unwrap();
+        let storage = open(Backend::Files, &dir).unwrap();
+        assert!(!dir.join(addressbook::KEY).exists());
+        let slot = Slot::new(&storage, addressbook::NAMESPACE, addressbook::KEY);
+        assert_eq!(slot.read().unwrap(), Some(b"{}".to_vec()));
+        assert_eq!(slot.to_string(), "addressbook/peers.json in storage");
+
+        let memory = Memory::default();
+        copy(&*storage, &memory).unwrap();
+        assert_eq!(memory.namespaces().unwrap(), vec![addressbook::NAMESPACE]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Persistent set of subscribed topics.
+//!
+//! Subscriptions made through the node handle are recorded together with their
+//! options as JSON in the [`storage`], so a restarted node resumes them
+//! automatically.
+//!
+//! [`storage`]: super::storage
+
+use super::{qos::Class, storage::Slot};
+use crate::prelude::*;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+/// Storage namespace of the subscriptions.
+pub const NAMESPACE: &str = "subscriptions";
+
+/// Storage key of the subscriptions, once their file name.
+pub const KEY: &str = "subscriptions.json";
+
+/// Per-topic delivery options.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicOptions {
+    /// Receivers acknowledge messages, which are sent again until they do,
+    /// see [`super::reliable`].
+    pub acked:          bool,
+    /// Payloads are encrypted.
+    pub encrypted:      bool,
+    /// Only the latest message per key is retained.
+    pub compacted:      bool,
+    /// Payloads are delta-encoded state updates, see [`super::delta`].
+    pub delta:          bool,
+    /// Messages are sent to the local network by UDP multicast instead of
+    /// through the gossip mesh, see [`super::behaviour::multicast`].
+    pub multicast:      bool,
+    /// Unsubscribe once the topic saw no messages and no subscribers for
+    /// this long.
+    pub expire_after:   Option<Duration>,
+    /// Drop messages republished by more bridges or relays than this.
+    pub max_relays:     Option<usize>,
+    /// Ask a connected archiver for this many past messages when
+    /// subscribing, see [`super::archive`].
+    pub backfill:       Option<usize>,
+    /// Ask for the past messages from this time on, all or the last
+    /// `backfill` of them.
+    pub backfill_since: Option<SystemTime>,
+    /// How urgently our messages are sent, see [`super::qos`].
+    pub qos:            Class,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Subscriptions {
+    slot:   Option<Slot>,
+    topics: BTreeMap<String, TopicOptions>,
+}
+
+impl Subscriptions {
+    /// Load the subscriptions kept in `slot`, or start empty if there are
+    /// none. Changes are written back to `slot`.
+    pub fn load(slot: Slot) -> Result<Self> {
+        let topics = match slot.read()? {
+            Some(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("Parsing subscriptions from {}", slot))?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            slot: Some(slot),
+            topics,
+        })
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = (&String, &TopicOptions)> {
+        self.topics.iter()
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&TopicOptions> {
+        self.topics.get(topic)
+    }
+
+    /// Record a subscription, replacing its options if it already exists.
+    pub fn insert(&mut self, topic: &str, options: TopicOptions) -> Result<()> {
+        if self.topics.get(topic) != Some(&options) {
+            self.topics.insert(topic.into(), options);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Forget a subscription. Returns false if there was none.
+    pub fn remove(&mut self, topic: &str) -> Result<bool> {
+        if self.topics.remove(topic).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.slot {
+            Some(slot) => slot.write(&serde_json::to_vec_pretty(&self.topics)?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::storage, test::prelude::assert_eq};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_persists_across_loads() {
+        let storage: storage::Shared = Arc::new(storage::Memory::default());
+        let slot = Slot::new(&storage, NAMESPACE, KEY);
+
+        let mut subscriptions = Subscriptions::load(slot.clone()).unwrap();
+        let options = TopicOptions {
+            acked: true,
+            ..TopicOptions::default()
+        };
+        subscriptions.insert("chat", options).unwrap();
+        subscriptions.insert("news", TopicOptions::default()).unwrap();
+        assert!(subscriptions.remove("news").unwrap());
+
+        let reloaded = Subscriptions::load(slot).unwrap();
+        assert_eq!(reloaded, subscriptions);
+        assert_eq!(reloaded.get("chat"), Some(&options));
+    }
+}
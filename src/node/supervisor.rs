@@ -0,0 +1,305 @@
+//! Reconnecting to important peers after a connection drops.
+//!
+//! When the last connection to a bootstrap peer, or a peer passed to
+//! [`Node::supervise`], closes without us asking for it, the node dials the
+//! peer again after `first`, and after each retry that did not connect
+//! twice as long, up to `max`, each wait spread by up to `jitter` of itself
+//! either way so peers that lost the same link do not redial in step. After
+//! `retries` dials it gives up until the peer connects again. Closes the
+//! node asks for itself, like evicting a peer for its score, banning it or
+//! trimming connections over `--max-peers`, are intentional and not
+//! retried. Tune it with
+//! `--reconnect "first=1s max=5m jitter=0.2 retries=10"`, `retries=forever`
+//! or `retries=0` to turn it off.
+//!
+//! [`Node::supervise`]: crate::node::Node::supervise
+
+use super::options;
+use crate::prelude::*;
+use anyhow::{bail, ensure};
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Config {
+    /// Wait before the first redial.
+    pub first:   Duration,
+    /// Longest wait between redials.
+    pub max:     Duration,
+    /// Fraction of each wait it is randomly spread by.
+    pub jitter:  f64,
+    /// Redials before giving up, `None` to keep trying.
+    pub retries: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            first:   Duration::from_secs(1),
+            max:     Duration::from_secs(5 * 60),
+            jitter:  0.2,
+            retries: Some(10),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            match key {
+                "first" => {
+                    config.first = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid first {}", value))?;
+                }
+                "max" => {
+                    config.max = humantime::parse_duration(value)
+                        .with_context(|| format!("Invalid max {}", value))?;
+                }
+                "jitter" => {
+                    config.jitter = value
+                        .parse()
+                        .with_context(|| format!("Invalid jitter {}", value))?;
+                }
+                "retries" if value == "forever" => config.retries = None,
+                "retries" => {
+                    config.retries = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid retries {}", value))?,
+                    );
+                }
+                _ => bail!("Unknown reconnect option {}", key),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.first > Duration::from_secs(0), "Reconnect first must be positive");
+        ensure!(self.max >= self.first, "Reconnect max must be at least first");
+        ensure!(
+            (0.0..=1.0).contains(&self.jitter),
+            "Reconnect jitter must be between 0 and 1"
+        );
+        Ok(())
+    }
+
+    /// The wait before redial `attempt`, counting from zero, spread by
+    /// `sample` between 0 and 1.
+    pub fn backoff(&self, attempt: u32, sample: f64) -> Duration {
+        let backoff = self
+            .first
+            .checked_mul(1 << attempt.min(16))
+            .map_or(self.max, |backoff| backoff.min(self.max));
+        backoff.mul_f64(1.0 + self.jitter * (2.0 * sample - 1.0))
+    }
+
+    fn is_exhausted(&self, attempts: u32) -> bool {
+        self.retries.map_or(false, |retries| attempts >= retries)
+    }
+}
+
+/// What to do about a peer that dropped.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Dial it, for the `attempt`th time since it dropped.
+    Redial { peer_id: PeerId, attempt: u32 },
+    /// Stop, after `attempts` dials did not connect.
+    GiveUp { peer_id: PeerId, attempts: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Retry {
+    attempts: u32,
+    /// No dial before then.
+    retry:    Instant,
+}
+
+/// Peers to reconnect to, and which of them are being reconnected.
+#[derive(Clone, Debug, Default)]
+pub struct Supervisor {
+    config:      Config,
+    /// Peers supervised besides the bootstrap peers.
+    supervised:  HashSet<PeerId>,
+    /// Peers whose next disconnect we asked for.
+    intentional: HashSet<PeerId>,
+    retries:     HashMap<PeerId, Retry>,
+}
+
+impl Supervisor {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Reconnect to `peer_id` when it drops, returning false if it was
+    /// supervised already.
+    pub fn supervise(&mut self, peer_id: PeerId) -> bool {
+        self.supervised.insert(peer_id)
+    }
+
+    /// Stop reconnecting to `peer_id`, returning false if it was not
+    /// supervised.
+    pub fn unsupervise(&mut self, peer_id: &PeerId) -> bool {
+        self.retries.remove(peer_id);
+        self.supervised.remove(peer_id)
+    }
+
+    pub fn is_supervised(&self, peer_id: &PeerId) -> bool {
+        self.supervised.contains(peer_id)
+    }
+
+    /// We are closing the connections to `peer_id`, so do not reconnect.
+    pub fn intend(&mut self, peer_id: &PeerId) {
+        self.intentional.insert(peer_id.clone());
+        self.retries.remove(peer_id);
+    }
+
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        self.intentional.remove(peer_id);
+        self.retries.remove(peer_id);
+    }
+
+    /// The last connection to the important `peer_id` closed. Returns when
+    /// it will be dialed again, unless we closed it or reconnecting is off.
+    pub fn disconnected(&mut self, peer_id: &PeerId, now: Instant) -> Option<Duration> {
+        if self.intentional.remove(peer_id) || self.config.is_exhausted(0) {
+            return None;
+        }
+        let backoff = self.config.backoff(0, rand::random());
+        self.retries.insert(peer_id.clone(), Retry {
+            attempts: 0,
+            retry:    now + backoff,
+        });
+        Some(backoff)
+    }
+
+    /// The redials due `now`, and the peers to give up on. Peers that
+    /// `connected` some other way are no longer retried.
+    pub fn due(&mut self, now: Instant, connected: impl Fn(&PeerId) -> bool) -> Vec<Action> {
+        self.retries.retain(|peer_id, _| !connected(peer_id));
+        let mut actions = Vec::new();
+        let mut exhausted = Vec::new();
+        for (peer_id, retry) in &mut self.retries {
+            if now < retry.retry {
+                continue;
+            }
+            if self.config.is_exhausted(retry.attempts) {
+                exhausted.push(peer_id.clone());
+                actions.push(Action::GiveUp {
+                    peer_id:  peer_id.clone(),
+                    attempts: retry.attempts,
+                });
+                continue;
+            }
+            retry.attempts += 1;
+            retry.retry = now + self.config.backoff(retry.attempts, rand::random());
+            actions.push(Action::Redial {
+                peer_id: peer_id.clone(),
+                attempt: retry.attempts,
+            });
+        }
+        for peer_id in exhausted {
+            self.retries.remove(&peer_id);
+        }
+        actions
+    }
+
+    /// The peers being reconnected, with the dials since they dropped.
+    pub fn reconnecting(&self) -> Vec<(PeerId, u32)> {
+        let mut peers = self
+            .retries
+            .iter()
+            .map(|(peer_id, retry)| (peer_id.clone(), retry.attempts))
+            .collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_config() {
+        let config: Config = "first=2s max=1m jitter=0 retries=forever".parse().unwrap();
+        assert_eq!(config.first, Duration::from_secs(2));
+        assert_eq!(config.max, Duration::from_secs(60));
+        assert_eq!(config.retries, None);
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("first=1m max=1s".parse::<Config>().is_err());
+        assert!("jitter=2".parse::<Config>().is_err());
+        assert!("delay=1s".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_backs_off_with_jitter() {
+        let config = Config::default();
+        assert_eq!(config.backoff(0, 0.5), Duration::from_secs(1));
+        assert_eq!(config.backoff(3, 0.5), Duration::from_secs(8));
+        assert_eq!(config.backoff(40, 0.5), config.max);
+        let config = Config {
+            jitter: 0.5,
+            ..config
+        };
+        assert_eq!(config.backoff(1, 0.0), Duration::from_secs(1));
+        assert_eq!(config.backoff(1, 1.0), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_retries_accidental_disconnects() {
+        let config: Config = "first=1s max=4s jitter=0 retries=3".parse().unwrap();
+        let mut supervisor = Supervisor::new(config);
+        let (peer_id, now) = (PeerId::random(), Instant::now());
+        let at = |secs| now + Duration::from_secs(secs);
+        let redial = |attempt| {
+            Action::Redial {
+                peer_id: peer_id.clone(),
+                attempt,
+            }
+        };
+
+        supervisor.intend(&peer_id);
+        assert_eq!(supervisor.disconnected(&peer_id, now), None);
+        assert_eq!(supervisor.disconnected(&peer_id, now), Some(Duration::from_secs(1)));
+        assert_eq!(supervisor.due(now, |_| false), vec![]);
+        assert_eq!(supervisor.due(at(1), |_| false), vec![redial(1)]);
+        assert_eq!(supervisor.due(at(2), |_| false), vec![]);
+        assert_eq!(supervisor.due(at(3), |_| false), vec![redial(2)]);
+        assert_eq!(supervisor.due(at(7), |_| false), vec![redial(3)]);
+        assert_eq!(supervisor.reconnecting(), vec![(peer_id.clone(), 3)]);
+        assert_eq!(supervisor.due(at(11), |_| false), vec![Action::GiveUp {
+            peer_id:  peer_id.clone(),
+            attempts: 3,
+        }]);
+        assert_eq!(supervisor.reconnecting(), vec![]);
+
+        // Connecting some other way stops the retries
+        supervisor.disconnected(&peer_id, now);
+        assert_eq!(supervisor.due(at(1), |_| true), vec![]);
+        assert_eq!(supervisor.reconnecting(), vec![]);
+    }
+}
@@ -1,55 +1,190 @@
 //! Compose the transport stack for LibP2P
-//!
-//! TODO: Testnet memory transport
-//! TODO: pnet private network for testing
 
+use super::{
+    accounting::{Meter, Metered}, activation::Activated, admission::Admission, ble::Ble,
+    dial::Backoffs, family::Families, gate::Gate, link::Link, mismatch::Identities, negotiation,
+    proxy::Proxies, security, serial::Serial, shaping::Shaper, udp::Udp,
+};
 use crate::prelude::*;
 use libp2p::{
     bandwidth::BandwidthSinks,
     core::{
-        either::EitherOutput, muxing::StreamMuxerBox, upgrade, upgrade::SelectUpgrade, UpgradeInfo,
+        either::{EitherError, EitherOutput, EitherTransport},
+        muxing::StreamMuxerBox,
+        transport::{MemoryTransport, memory::MemoryTransportError},
+        ConnectedPoint, upgrade, upgrade::{OptionalUpgrade, SelectUpgrade}, UpgradeInfo,
     },
-    dns::DnsConfig,
+    dns::{DnsConfig, DnsErr},
     identity, mplex, noise,
+    pnet::PnetError,
     tcp::TokioTcpConfig,
-    websocket::WsConfig,
+    websocket::{self, WsConfig},
     yamux, PeerId, Transport, TransportExt,
 };
 use libp2p_secio as secio;
-use std::{sync::Arc, time::Duration};
+use std::{io, sync::Arc, time::{Duration, SystemTime}};
 
 use upgrade::{MapInboundUpgrade, MapOutboundUpgrade};
 
 pub type Libp2pTransport = libp2p::core::transport::Boxed<(PeerId, StreamMuxerBox)>;
 
-/// Create a transport for TCP/IP and WebSockets over TCP/IP with Secio
-/// encryption and either yamux or else mplex multiplexing.
+/// Errors of the combined transports, flattened to the I/O error they carry
+/// so [`negotiation`] keeps its kind.
+trait IntoIo {
+    fn into_io(self) -> io::Error;
+}
+
+impl IntoIo for io::Error {
+    fn into_io(self) -> io::Error {
+        self
+    }
+}
+
+impl<A: IntoIo, B: IntoIo> IntoIo for EitherError<A, B> {
+    fn into_io(self) -> io::Error {
+        match self {
+            EitherError::A(error) => error.into_io(),
+            EitherError::B(error) => error.into_io(),
+        }
+    }
+}
+
+impl IntoIo for MemoryTransportError {
+    fn into_io(self) -> io::Error {
+        let kind = match self {
+            Self::Unreachable => io::ErrorKind::ConnectionRefused,
+            Self::AlreadyInUse => io::ErrorKind::AddrInUse,
+        };
+        io::Error::new(kind, self)
+    }
+}
+
+impl IntoIo for PnetError {
+    fn into_io(self) -> io::Error {
+        match self {
+            PnetError::HandshakeError(error) | PnetError::IoError(error) => error,
+        }
+    }
+}
+
+impl<E> IntoIo for DnsErr<E>
+where
+    E: IntoIo + std::error::Error + Send + Sync + 'static,
+{
+    fn into_io(self) -> io::Error {
+        match self {
+            DnsErr::Underlying(error) => error.into_io(),
+            error @ (DnsErr::ResolveFail(_) | DnsErr::ResolveError { .. }) => {
+                io::Error::new(io::ErrorKind::NotFound, error)
+            }
+            error => io::Error::new(io::ErrorKind::Other, error),
+        }
+    }
+}
+
+impl<E> IntoIo for websocket::error::Error<E>
+where
+    E: IntoIo + std::error::Error + Send + Sync + 'static,
+{
+    fn into_io(self) -> io::Error {
+        match self {
+            websocket::error::Error::Transport(error) => error.into_io(),
+            error => io::Error::new(io::ErrorKind::Other, error),
+        }
+    }
+}
+
+/// Create a transport for TCP/IP, WebSockets over TCP/IP, `udp`, Bluetooth LE
+/// and serial links, and `/memory/` addresses within the process, with the
+/// Noise or Secio encryption of `security` and either yamux or else mplex
+/// multiplexing, inside the private network of the swarm key of `security`
+/// if any. Listening on the address of an `activated`
+/// socket uses that socket. Connections are limited by the bandwidth caps of
+/// `shaper`. Upgrade errors are tagged with their [`negotiation::Reason`], and
+/// the peer ids that dialed addresses answered with are kept in `identities`.
+/// TCP addresses with a SOCKS5 proxy in `proxies` are dialed through it.
+/// Addresses of a family `families` does not dial and those backing off in
+/// `backoffs` are not dialed, and connections the
+/// [`gate`](super::gate) refuses are closed before the handshake or right
+/// after authentication, like those over the per-IP limit of `admission`.
+/// The traffic of their substreams is counted by peer and protocol in
+/// `meter`. Connections not upgraded within `timeout` fail.
 pub fn make_transport(
     peer_id_keys: identity::Keypair,
+    activated: Activated,
+    shaper: Shaper,
+    udp: Udp,
+    identities: Identities,
+    backoffs: Backoffs,
+    families: Families,
+    proxies: Proxies,
+    gate: Gate,
+    admission: Admission,
+    security: security::Config,
+    meter: Meter,
+    timeout: Duration,
 ) -> Result<(Libp2pTransport, Arc<BandwidthSinks>)> {
     // Create transport with TCP, DNS and WS
     // TODO: WASM support
     // TODO: Circuit-relay (waiting for upstream PR)
     let transport = {
-        // TCP/IP transport using Tokio
-        let tcp_transport = TokioTcpConfig::new().nodelay(true);
+        // TCP/IP transport using Tokio, after sockets inherited from systemd
+        let tcp_transport = activated.or_transport(TokioTcpConfig::new().nodelay(true));
 
-        // Add DNS support to the TCP transport (to resolve /dns*/ addresses)
-        let tcp_dns_transport =
-            DnsConfig::new(tcp_transport).context("Creating /dns/ transport")?;
+        // Add DNS support to the TCP transport (to resolve /dns*/ addresses),
+        // after the proxies that may resolve names themselves
+        let dns_transport = DnsConfig::new(tcp_transport).context("Creating /dns/ transport")?;
+        let tcp_dns_transport = proxies.or_transport(dns_transport);
 
         // Websocket transport over TCP/IP
         // TODO: Secure websocket.
         let ws_transport = WsConfig::new(tcp_dns_transport.clone());
 
-        // Combine transports
-        tcp_dns_transport.or_transport(ws_transport)
+        // Combine transports, other link transports go last. Addresses of
+        // the wrong family or backing off are refused before reaching any
+        // of them.
+        families
+            .or_transport(backoffs)
+            .or_transport(tcp_dns_transport)
+            .or_transport(ws_transport)
+            .or_transport(udp)
+            .or_transport(Link(Ble))
+            .or_transport(Link(Serial))
+            // For nodes of one process, like the test harness
+            .or_transport(MemoryTransport)
     };
 
     // Add bandwidth monitoring
-    let (transport, bandwidth_logger) = transport.with_bandwidth_logging();
+    let (transport, bandwidth_logger) = transport
+        .map_err(IntoIo::into_io)
+        .with_bandwidth_logging();
 
-    // Create authenticator with Noise and Secio
+    // Refuse denied addresses before spending a handshake on them
+    let address_gate = gate.clone();
+    let transport = transport
+        .and_then(move |socket, endpoint| {
+            let address = endpoint.get_remote_address();
+            let result = address_gate.check_address(address).map_err(io::Error::from);
+            let result = result.and_then(|()| admission.check(&endpoint).map_err(io::Error::from));
+            if let Err(denied) = &result {
+                debug!("Refusing connection with {}: {}", address, denied);
+            }
+            future::ready(result.map(|()| socket))
+        })
+        .map_err(IntoIo::into_io);
+
+    // Encrypt everything with the swarm key of a private network
+    let transport = match security.swarm_key {
+        Some(key) => {
+            EitherTransport::Left(
+                transport.and_then(move |socket, _| key.config().handshake(socket)),
+            )
+        }
+        None => EitherTransport::Right(transport),
+    }
+    .map_err(IntoIo::into_io);
+
+    // Create authenticator with Noise and Secio, as enabled
     let authenticator = {
         // Noise legacy
         let mut noise_legacy = noise::LegacyConfig::default();
@@ -63,24 +198,40 @@ pub fn make_transport(
         let mut noise_xx_config = noise::NoiseConfig::xx(noise_keys);
         noise_xx_config.set_legacy_config(noise_legacy);
         let noise = noise_xx_config.into_authenticated();
+        let noise = if security.noise {
+            OptionalUpgrade::some(noise)
+        } else {
+            OptionalUpgrade::none()
+        };
 
         // Secio
         // The Go version of 0x-mesh only supports Secio.
-        let secio = secio::SecioConfig::new(peer_id_keys);
+        let secio = if security.secio {
+            OptionalUpgrade::some(secio::SecioConfig::new(peer_id_keys))
+        } else {
+            OptionalUpgrade::none()
+        };
 
         // We need to do some monad stack shuffling:
         // `Either<(A, B), (A, C)>` to `(A, Either<B, C>)`
+        // and note who still needs Secio.
         let upgrade = SelectUpgrade::new(noise, secio);
         let upgrade = MapInboundUpgrade::new(upgrade, |out| {
             match out {
                 EitherOutput::First((peer_id, out)) => (peer_id, EitherOutput::First(out)),
-                EitherOutput::Second((peer_id, out)) => (peer_id, EitherOutput::Second(out)),
+                EitherOutput::Second((peer_id, out)) => {
+                    debug!("Peer {} connected with deprecated secio", peer_id);
+                    (peer_id, EitherOutput::Second(out))
+                }
             }
         });
         let upgrade = MapOutboundUpgrade::new(upgrade, |out| {
             match out {
                 EitherOutput::First((peer_id, out)) => (peer_id, EitherOutput::First(out)),
-                EitherOutput::Second((peer_id, out)) => (peer_id, EitherOutput::Second(out)),
+                EitherOutput::Second((peer_id, out)) => {
+                    debug!("Peer {} connected with deprecated secio", peer_id);
+                    (peer_id, EitherOutput::Second(out))
+                }
             }
         });
         upgrade
@@ -105,8 +256,29 @@ pub fn make_transport(
     let transport = transport
         .upgrade(upgrade::Version::V1)
         .authenticate(authenticator)
-        .multiplex(multiplexer)
-        .timeout(Duration::from_secs(20))
+        .multiplex_ext(move |peer_id, _| shaper.apply(peer_id, multiplexer))
+        .timeout(timeout)
+        .map_err(negotiation::classify)
+        .and_then(move |(peer_id, muxer), _| {
+            let result = gate.check_peer(&peer_id, SystemTime::now());
+            if let Err(denied) = &result {
+                debug!("Refusing connection: {}", denied);
+            }
+            future::ready(result.map(|()| (peer_id, muxer)).map_err(io::Error::from))
+        })
+        .map_err(|error| {
+            match error {
+                EitherError::A(failed) => io::Error::new(io::ErrorKind::Other, failed),
+                EitherError::B(denied) => denied,
+            }
+        })
+        .map(move |(peer_id, muxer), endpoint| {
+            if let ConnectedPoint::Dialer { address } = endpoint {
+                identities.record(&address, &peer_id);
+            }
+            let muxer = Metered::new(muxer, peer_id.clone(), meter.clone());
+            (peer_id, StreamMuxerBox::new(muxer))
+        })
         .boxed();
 
     Ok((transport, bandwidth_logger))
@@ -0,0 +1,505 @@
+//! Topics carrying one payload type.
+//!
+//! [`NodeHandle::typed_topic`] subscribes to a topic and returns a
+//! [`TypedSender`] publishing values of `T` and a [`TypedReceiver`]
+//! streaming the [`MeshMessage`]s others publish, framed as described in
+//! [`message`](super::message). Messages that do not decode as `T` are
+//! dropped with a warning.
+//! The receiver is a [`route`], so it buffers like one, and dropping it
+//! stops the routing but not the subscription.
+//!
+//! [`NodeHandle::on_topic`] subscribes and calls an async handler on each
+//! value of `T` instead, on the tasks of a [`route`], at most `limit` at
+//! once. Messages that do not decode go to the stream of [`Malformed`]
+//! messages it returns, and are dropped with a warning when nobody takes
+//! them from there.
+//!
+//! [`NodeHandle::echo_topic`] does the same with local echo, for UIs that
+//! should not wait for the mesh: an [`EchoSender`] hands each value to the
+//! [`EchoReceiver`] right away as [`Echo::Local`], pending, before
+//! publishing it, then follows up with its [`Delivery`] status: sent once
+//! pubsub took it, or failed, and delivered for every peer that sends a
+//! receipt. Messages waiting in the outbox or a power-save batch stay
+//! pending until the first receipt. Typed receivers acknowledge messages
+//! asking for receipts with a direct message to their sender, on
+//! [`RECEIPT_PREFIX`] and the topic, as long as the node's command queue has
+//! room.
+//!
+//! [`NodeHandle::typed_topic`]: crate::node::NodeHandle::typed_topic
+//! [`NodeHandle::echo_topic`]: crate::node::NodeHandle::echo_topic
+//! [`NodeHandle::on_topic`]: crate::node::NodeHandle::on_topic
+//! [`route`]: crate::node::route
+
+use super::{
+    message::{self, Format, MeshMessage},
+    route, NodeHandle, Provenance,
+};
+use crate::prelude::*;
+use futures::{channel::mpsc, task::Context as TaskContext};
+use libp2p::PeerId;
+use serde::de::DeserializeOwned;
+use std::{collections::VecDeque, marker::PhantomData, pin::Pin, task::Poll};
+
+/// Prefix of the topics receipts go to, followed by the acknowledged topic.
+pub const RECEIPT_PREFIX: &str = "/mesh-rs/receipt/";
+
+/// Most sent messages an [`EchoReceiver`] accepts receipts for.
+pub const MAX_TRACKED: usize = 1024;
+
+/// The topic receipts of messages on `topic` go to.
+pub fn receipt_topic(topic: &str) -> String {
+    format!("{}{}", RECEIPT_PREFIX, topic)
+}
+
+/// Acknowledge the message `id` from `sender` on `topic`, unless the node
+/// is busy.
+fn acknowledge(handle: &mut NodeHandle, sender: &PeerId, topic: &str, id: u64) {
+    let data = serde_cbor::to_vec(&id).expect("Receipts always encode");
+    if !handle.try_publish_to(sender, &receipt_topic(topic), data) {
+        debug!("Dropping receipt to {}, node busy", sender);
+    }
+}
+
+/// How far a message we sent got.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Delivery {
+    /// Not handed to pubsub yet.
+    Pending,
+    /// Pubsub took it, so it went to at least one peer.
+    Sent,
+    /// The peer received it, reported once per receipt.
+    Delivered(PeerId),
+    Failed(String),
+}
+
+/// What an [`EchoReceiver`] streams.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Echo<T> {
+    /// A message of another peer.
+    Received(MeshMessage<T>),
+    /// A message we are sending, [`Delivery::Pending`] until a status
+    /// follows.
+    Local { id: u64, message: MeshMessage<T> },
+    /// Our message `id` got further.
+    Status { id: u64, status: Delivery },
+}
+
+/// Publishes values of `T` on a topic.
+#[derive(Clone)]
+pub struct TypedSender<T> {
+    handle:  NodeHandle,
+    topic:   String,
+    format:  Format,
+    payload: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> TypedSender<T> {
+    pub(crate) fn new(handle: NodeHandle, topic: &str) -> Self {
+        Self {
+            handle,
+            topic: topic.to_owned(),
+            format: Format::default(),
+            payload: PhantomData,
+        }
+    }
+
+    /// Send frames in `format`, like [`Format::Json`] to debug.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub async fn send(&mut self, value: &T) -> Result<()> {
+        let data = message::encode(value, self.format)?;
+        self.handle.publish(&self.topic, &data).await
+    }
+}
+
+/// Streams the messages with values of `T` received on a topic.
+pub struct TypedReceiver<T> {
+    messages: mpsc::Receiver<route::Message>,
+    /// Where receipts are sent through.
+    handle:   Option<NodeHandle>,
+    payload:  PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedReceiver<T> {
+    pub(crate) fn new(
+        messages: mpsc::Receiver<route::Message>,
+        handle: Option<NodeHandle>,
+    ) -> Self {
+        Self {
+            messages,
+            handle,
+            payload: PhantomData,
+        }
+    }
+
+    fn acknowledge(&mut self, message: &MeshMessage<T>, id: u64) {
+        if let Some(handle) = &mut self.handle {
+            acknowledge(handle, &message.sender, &message.topic, id);
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Stream for TypedReceiver<T> {
+    type Item = MeshMessage<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match self.messages.poll_next_unpin(cx) {
+                Poll::Ready(Some(message)) => message,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let (topic, source) = (message.topic.clone(), message.source.clone());
+            match message::decode_with_receipt(message) {
+                Ok((message, receipt)) => {
+                    if let Some(id) = receipt {
+                        self.acknowledge(&message, id);
+                    }
+                    return Poll::Ready(Some(message));
+                }
+                Err(err) => warn!("Dropping message on {} from {}: {}", topic, source, err),
+            }
+        }
+    }
+}
+
+/// A message on a topic of [`NodeHandle::on_topic`] that did not decode.
+///
+/// [`NodeHandle::on_topic`]: crate::node::NodeHandle::on_topic
+#[derive(Clone, Debug)]
+pub struct Malformed {
+    pub message: route::Message,
+    pub error:   String,
+}
+
+/// Start a route calling `handler` on the values of `T` sent to the returned
+/// sender, at most `limit` at once, and passing those that do not decode to
+/// `errors`. Receipts are sent through `handle`.
+pub(crate) fn spawn<T, F, Fut>(
+    limit: usize,
+    mut handle: Option<NodeHandle>,
+    mut handler: F,
+    mut errors: mpsc::Sender<Malformed>,
+) -> mpsc::Sender<route::Message>
+where
+    T: DeserializeOwned + 'static,
+    F: FnMut(MeshMessage<T>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    route::spawn(limit, move |routed: route::Message| {
+        match message::decode_with_receipt(routed.clone()) {
+            Ok((message, receipt)) => {
+                if let (Some(handle), Some(id)) = (&mut handle, receipt) {
+                    acknowledge(handle, &message.sender, &message.topic, id);
+                }
+                future::Either::Left(handler(message))
+            }
+            Err(err) => {
+                let (topic, source) = (routed.topic.clone(), routed.source.clone());
+                let malformed = Malformed {
+                    message: routed,
+                    error:   format!("{:#}", err),
+                };
+                if errors.try_send(malformed).is_err() {
+                    warn!("Dropping message on {} from {}: {}", topic, source, err);
+                }
+                future::Either::Right(future::ready(()))
+            }
+        }
+    })
+}
+
+/// Publishes values of `T` on a topic, echoing them to an
+/// [`EchoReceiver`].
+#[derive(Clone)]
+pub struct EchoSender<T> {
+    sender: TypedSender<T>,
+    echoes: mpsc::UnboundedSender<Echo<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned> EchoSender<T> {
+    pub(crate) fn new(sender: TypedSender<T>, echoes: mpsc::UnboundedSender<Echo<T>>) -> Self {
+        Self { sender, echoes }
+    }
+
+    /// Send frames in `format`, like [`TypedSender::with_format`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.sender = self.sender.with_format(format);
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        self.sender.topic()
+    }
+
+    /// Echo `value` and publish it, returning the id of its statuses.
+    /// Failing to publish is a [`Delivery::Failed`] status, not an error.
+    pub async fn send(&mut self, value: &T) -> Result<u64> {
+        let id = rand::random();
+        let data = message::encode_with_receipt(value, self.sender.format, Some(id))?;
+        let timestamp = self.sender.handle.timestamp().await?;
+        let message = message::decode(route::Message {
+            source:     self.sender.handle.local_peer_id().clone(),
+            topic:      self.sender.topic.clone(),
+            data:       data.clone(),
+            direct:     false,
+            timestamp:  Some(timestamp),
+            provenance: Provenance::default(),
+            signed:     true,
+        })?;
+        self.echo(Echo::Local { id, message });
+        let topic = &self.sender.topic;
+        let status = match self.sender.handle.publish_now(topic, &data).await {
+            Ok(true) => Delivery::Sent,
+            Ok(false) => return Ok(id),
+            Err(err) => Delivery::Failed(format!("{:#}", err)),
+        };
+        self.echo(Echo::Status { id, status });
+        Ok(id)
+    }
+
+    fn echo(&self, echo: Echo<T>) {
+        if self.echoes.unbounded_send(echo).is_err() {
+            trace!("Echo receiver of {} dropped", self.sender.topic);
+        }
+    }
+}
+
+/// Streams the messages received on a topic, our own as they are sent and
+/// their [`Delivery`] statuses.
+pub struct EchoReceiver<T> {
+    messages: TypedReceiver<T>,
+    echoes:   mpsc::UnboundedReceiver<Echo<T>>,
+    receipts: mpsc::Receiver<route::Message>,
+    /// Ids of the messages echoed, oldest first.
+    tracked:  VecDeque<u64>,
+}
+
+impl<T: DeserializeOwned> EchoReceiver<T> {
+    pub(crate) fn new(
+        messages: TypedReceiver<T>,
+        echoes: mpsc::UnboundedReceiver<Echo<T>>,
+        receipts: mpsc::Receiver<route::Message>,
+    ) -> Self {
+        Self {
+            messages,
+            echoes,
+            receipts,
+            tracked: VecDeque::new(),
+        }
+    }
+
+    /// The status a receipt reports, if it is for a message we track.
+    fn receipt(&self, receipt: &route::Message) -> Option<Echo<T>> {
+        let id = match serde_cbor::from_slice::<u64>(&receipt.data) {
+            Ok(id) => id,
+            Err(err) => {
+                warn!("Invalid receipt from {}: {}", receipt.source, err);
+                return None;
+            }
+        };
+        if !self.tracked.contains(&id) {
+            return None;
+        }
+        Some(Echo::Status {
+            id,
+            status: Delivery::Delivered(receipt.source.clone()),
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Stream for EchoReceiver<T> {
+    type Item = Echo<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(Some(echo)) = self.echoes.poll_next_unpin(cx) {
+            if let Echo::Local { id, .. } = &echo {
+                if self.tracked.len() >= MAX_TRACKED {
+                    self.tracked.pop_front();
+                }
+                let id = *id;
+                self.tracked.push_back(id);
+            }
+            return Poll::Ready(Some(echo));
+        }
+        while let Poll::Ready(Some(receipt)) = self.receipts.poll_next_unpin(cx) {
+            if let Some(status) = self.receipt(&receipt) {
+                return Poll::Ready(Some(status));
+            }
+        }
+        self.messages
+            .poll_next_unpin(cx)
+            .map(|message| message.map(Echo::Received))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::behaviour::envelope::Provenance, test::prelude::assert_eq};
+    use libp2p::PeerId;
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value:  i64,
+    }
+
+    #[tokio::test]
+    async fn test_decodes_payloads() {
+        let (mut sender, receiver) = mpsc::channel(4);
+        let mut receiver = TypedReceiver::<Reading>::new(receiver, None);
+        let source = PeerId::random();
+        let reading = Reading {
+            sensor: "t1".into(),
+            value:  21,
+        };
+        for data in vec![
+            b"garbage".to_vec(),
+            // Without a frame
+            serde_cbor::to_vec(&reading).unwrap(),
+            message::encode(&reading, Format::Json).unwrap(),
+        ] {
+            sender
+                .try_send(route::Message {
+                    source: source.clone(),
+                    topic: "readings".into(),
+                    data,
+                    direct: false,
+                    timestamp: None,
+                    provenance: Provenance::default(),
+                    signed: false,
+                })
+                .unwrap();
+        }
+        drop(sender);
+        assert_eq!(
+            receiver.next().await,
+            Some(MeshMessage {
+                sender:       source,
+                timestamp:    None,
+                topic:        "readings".into(),
+                content_type: "application/json".into(),
+                payload:      reading,
+            })
+        );
+        assert_eq!(receiver.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handles_payloads() {
+        let (handled, mut values) = mpsc::unbounded();
+        let (errors, mut malformed) = mpsc::channel(4);
+        let mut sender = spawn(
+            2,
+            None,
+            move |message: MeshMessage<Reading>| {
+                let handled = handled.clone();
+                async move {
+                    handled.unbounded_send(message.payload).unwrap();
+                }
+            },
+            errors,
+        );
+        let reading = Reading {
+            sensor: "t1".into(),
+            value:  21,
+        };
+        let source = PeerId::random();
+        for data in vec![b"garbage".to_vec(), message::encode(&reading, Format::Cbor).unwrap()] {
+            sender
+                .try_send(route::Message {
+                    source: source.clone(),
+                    topic: "readings".into(),
+                    data,
+                    direct: false,
+                    timestamp: None,
+                    provenance: Provenance::default(),
+                    signed: false,
+                })
+                .unwrap();
+        }
+        assert_eq!(values.next().await, Some(reading));
+        let malformed = malformed.next().await.unwrap();
+        assert_eq!(malformed.message.data, b"garbage".to_vec());
+        assert_eq!(malformed.message.source, source);
+    }
+
+    #[tokio::test]
+    async fn test_echoes_with_receipts() {
+        let (mut messages, routed) = mpsc::channel(4);
+        let (echo_sender, echoes) = mpsc::unbounded();
+        let (mut receipt_sender, receipts) = mpsc::channel(4);
+        let mut receiver = EchoReceiver::<String>::new(
+            TypedReceiver::new(routed, None),
+            echoes,
+            receipts,
+        );
+        let (local, remote) = (PeerId::random(), PeerId::random());
+        let routed = |source: &PeerId, topic: &str, data| {
+            route::Message {
+                source: source.clone(),
+                topic: topic.into(),
+                data,
+                direct: false,
+                timestamp: None,
+                provenance: Provenance::default(),
+                signed: false,
+            }
+        };
+        let message = |sender: &PeerId| {
+            MeshMessage {
+                sender:       sender.clone(),
+                timestamp:    None,
+                topic:        "chat".into(),
+                content_type: "application/cbor".into(),
+                payload:      "hi".to_owned(),
+            }
+        };
+
+        let local_echo = Echo::Local {
+            id:      7,
+            message: message(&local),
+        };
+        echo_sender.unbounded_send(local_echo.clone()).unwrap();
+        echo_sender
+            .unbounded_send(Echo::Status {
+                id:     7,
+                status: Delivery::Sent,
+            })
+            .unwrap();
+        assert_eq!(receiver.next().await, Some(local_echo));
+        assert_eq!(
+            receiver.next().await,
+            Some(Echo::Status {
+                id:     7,
+                status: Delivery::Sent,
+            })
+        );
+
+        // Receipts of messages we did not send are ignored
+        let topic = receipt_topic("chat");
+        for id in &[8_u64, 7] {
+            let data = serde_cbor::to_vec(id).unwrap();
+            receipt_sender.try_send(routed(&remote, &topic, data)).unwrap();
+        }
+        assert_eq!(
+            receiver.next().await,
+            Some(Echo::Status {
+                id:     7,
+                status: Delivery::Delivered(remote.clone()),
+            })
+        );
+
+        let data = message::encode_with_receipt(&"hi", Format::Cbor, Some(3)).unwrap();
+        messages.try_send(routed(&remote, "chat", data)).unwrap();
+        assert_eq!(receiver.next().await, Some(Echo::Received(message(&remote))));
+    }
+}
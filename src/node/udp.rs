@@ -0,0 +1,957 @@
+//! UDP transport with retransmissions for lossy networks.
+//!
+//! On wireless links that lose a few percent of their packets TCP mistakes
+//! loss for congestion and slows to a crawl. This transport carries
+//! connections over UDP instead, with a lightweight ARQ: data is split in
+//! numbered packets of at most [`MAX_PAYLOAD`] bytes, up to [`WINDOW`] of them
+//! in flight, and the receiver acknowledges the next packet it expects
+//! together with a bitmap of the 64 packets after it that already arrived.
+//! Packets not acknowledged within the retransmission timeout, derived from
+//! the measured round trip time, are sent again, backing off exponentially.
+//! Loss is never taken as a sign of congestion, so this is meant for local
+//! networks and not for the internet.
+//!
+//! The transport is selected per listener, as with
+//! `--listen /ip4/0.0.0.0/udp/4002`, and used to dial peers that announced
+//! `/udp/` addresses. Connections are encrypted and multiplexed like TCP
+//! connections. [`Stats`] counts sent, retransmitted and duplicate packets,
+//! so retransmitted over sent packets estimates the loss on the network.
+
+use crate::prelude::*;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{BoxFuture, Ready},
+    io::{AsyncRead, AsyncWrite},
+    stream::BoxStream,
+};
+use libp2p::{
+    core::{
+        multiaddr::Protocol,
+        transport::{ListenerEvent, TransportError},
+    },
+    Multiaddr, Transport,
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::interval};
+
+/// Largest payload of a packet, so packets fit the minimum IPv6 MTU.
+pub const MAX_PAYLOAD: usize = 1200;
+
+/// Packets sent but not acknowledged yet, per connection.
+pub const WINDOW: usize = 128;
+
+const HEADER_LEN: usize = 21;
+const TICK: Duration = Duration::from_millis(20);
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MIN_RTO: Duration = Duration::from_millis(100);
+const MAX_RTO: Duration = Duration::from_secs(5);
+/// Transmissions of a packet after which the connection is considered dead.
+const MAX_ATTEMPTS: u32 = 12;
+/// Time after which an idle connection sends an empty acknowledgement.
+const HEARTBEAT: Duration = Duration::from_secs(5);
+/// Time without hearing from the remote after which a connection is dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_BACKLOG: usize = 16;
+/// Writes of a stream waiting for room in the window of its connection.
+const QUEUED_WRITES: usize = 16;
+
+/// Packets sent and received by the transport, over all connections.
+#[derive(Debug, Default)]
+pub struct Stats {
+    sent:          AtomicU64,
+    received:      AtomicU64,
+    retransmitted: AtomicU64,
+    duplicates:    AtomicU64,
+}
+
+impl Stats {
+    /// Data packets sent for the first time.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Data packets received, including duplicates.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Data packets sent again because they were not acknowledged in time.
+    pub fn retransmitted(&self) -> u64 {
+        self.retransmitted.load(Ordering::Relaxed)
+    }
+
+    /// Data packets received that were already received before.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Data,
+    /// Acknowledgement without data.
+    Ack,
+    /// End of the data, numbered like data.
+    Fin,
+    /// The connection is unknown to the remote.
+    Reset,
+}
+
+impl Kind {
+    const fn is_numbered(self) -> bool {
+        matches!(self, Self::Data | Self::Fin)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Packet {
+    kind:    Kind,
+    /// Chosen by the dialer, to tell connections from the same address apart.
+    id:      u32,
+    seq:     u32,
+    /// The next packet the sender expects.
+    ack:     u32,
+    /// Bit `i` is set if the sender received packet `ack + 1 + i`.
+    sack:    u64,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buffer.push(match self.kind {
+            Kind::Data => 0,
+            Kind::Ack => 1,
+            Kind::Fin => 2,
+            Kind::Reset => 3,
+        });
+        buffer.extend_from_slice(&self.id.to_be_bytes());
+        buffer.extend_from_slice(&self.seq.to_be_bytes());
+        buffer.extend_from_slice(&self.ack.to_be_bytes());
+        buffer.extend_from_slice(&self.sack.to_be_bytes());
+        buffer.extend_from_slice(&self.payload);
+        buffer
+    }
+
+    fn decode(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < HEADER_LEN {
+            return None;
+        }
+        let kind = match buffer[0] {
+            0 => Kind::Data,
+            1 => Kind::Ack,
+            2 => Kind::Fin,
+            3 => Kind::Reset,
+            _ => return None,
+        };
+        let u32_at =
+            |index: usize| u32::from_be_bytes(buffer[index..index + 4].try_into().unwrap());
+        Some(Self {
+            kind,
+            id: u32_at(1),
+            seq: u32_at(5),
+            ack: u32_at(9),
+            sack: u64::from_be_bytes(buffer[13..HEADER_LEN].try_into().unwrap()),
+            payload: buffer[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Sent {
+    kind:     Kind,
+    seq:      u32,
+    payload:  Vec<u8>,
+    sent:     Option<Instant>,
+    attempts: u32,
+    /// Received by the remote, but not acknowledged in order yet.
+    sacked:   bool,
+}
+
+/// The state of one end of a connection.
+#[derive(Debug)]
+struct Connection {
+    id:           u32,
+    next_seq:     u32,
+    unacked:      VecDeque<Sent>,
+    expected:     u32,
+    out_of_order: BTreeMap<u32, (Kind, Vec<u8>)>,
+    received:     VecDeque<u8>,
+    /// The remote finished sending.
+    eof:          bool,
+    /// We finished sending.
+    closed:       bool,
+    /// The stream was dropped, the connection lingers to deliver its data.
+    dropped:      bool,
+    error:        Option<io::ErrorKind>,
+    srtt:         Option<Duration>,
+    rttvar:       Duration,
+    rto:          Duration,
+    last_heard:   Instant,
+    last_sent:    Instant,
+}
+
+impl Connection {
+    fn new(id: u32, now: Instant) -> Self {
+        Self {
+            id,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            expected: 0,
+            out_of_order: BTreeMap::new(),
+            received: VecDeque::new(),
+            eof: false,
+            closed: false,
+            dropped: false,
+            error: None,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+            last_heard: now,
+            last_sent: now,
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(kind);
+        }
+    }
+
+    /// Whether the connection can be forgotten.
+    fn finished(&self) -> bool {
+        self.error.is_some() || (self.dropped && self.unacked.is_empty())
+    }
+
+    fn has_room(&self) -> bool {
+        self.unacked.len() < WINDOW
+    }
+
+    /// Queue a numbered packet for sending.
+    fn queue(&mut self, kind: Kind, payload: Vec<u8>) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq = seq
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "sequence numbers exhausted"))?;
+        self.unacked.push_back(Sent {
+            kind,
+            seq,
+            payload,
+            sent: None,
+            attempts: 0,
+            sacked: false,
+        });
+        Ok(())
+    }
+
+    /// Finish sending, unless we already did.
+    fn close(&mut self) {
+        if !self.closed && self.error.is_none() {
+            self.closed = true;
+            // Cannot fail before the sequence numbers do
+            let _ = self.queue(Kind::Fin, Vec::new());
+        }
+    }
+
+    fn sack(&self) -> u64 {
+        self.out_of_order
+            .range(self.expected.saturating_add(1)..)
+            .map(|(seq, _)| seq - self.expected - 1)
+            .take_while(|offset| *offset < 64)
+            .fold(0, |sack, offset| sack | 1 << offset)
+    }
+
+    fn packet(&self, kind: Kind, seq: u32, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            id: self.id,
+            seq,
+            ack: self.expected,
+            sack: self.sack(),
+            payload,
+        }
+    }
+
+    fn measured(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or(INITIAL_RTO) + (self.rttvar * 4).max(TICK);
+        self.rto = rto.max(MIN_RTO).min(MAX_RTO);
+    }
+
+    fn acknowledged(&mut self, ack: u32, sack: u64, now: Instant) {
+        let mut rtt = None;
+        while self.unacked.front().map_or(false, |sent| sent.seq < ack) {
+            let sent = self.unacked.pop_front().unwrap();
+            // Only packets sent once tell the round trip time
+            if sent.attempts == 1 {
+                rtt = sent.sent.map(|sent| now.saturating_duration_since(sent));
+            }
+        }
+        if let Some(rtt) = rtt {
+            self.measured(rtt);
+        }
+        for sent in &mut self.unacked {
+            let offset = match u64::from(sent.seq).checked_sub(u64::from(ack) + 1) {
+                Some(offset) if offset < 64 => offset,
+                Some(_) => break,
+                None => continue,
+            };
+            sent.sacked |= sack & 1 << offset != 0;
+        }
+    }
+
+    /// Handle `packet` from the remote. Returns the acknowledgement to send,
+    /// if any.
+    fn on_packet(&mut self, packet: Packet, now: Instant, stats: &Stats) -> Option<Packet> {
+        self.last_heard = now;
+        if packet.kind == Kind::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return None;
+        }
+        self.acknowledged(packet.ack, packet.sack, now);
+        if !packet.kind.is_numbered() {
+            return None;
+        }
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        if packet.seq < self.expected || self.out_of_order.contains_key(&packet.seq) {
+            stats.duplicates.fetch_add(1, Ordering::Relaxed);
+        } else if (packet.seq - self.expected) as usize >= WINDOW {
+            // Beyond what the remote may send, retransmitted later
+            return None;
+        } else {
+            self.out_of_order
+                .insert(packet.seq, (packet.kind, packet.payload));
+        }
+        while let Some((kind, payload)) = self.out_of_order.remove(&self.expected) {
+            self.expected += 1;
+            match kind {
+                Kind::Fin => self.eof = true,
+                _ => self.received.extend(payload),
+            }
+        }
+        self.last_sent = now;
+        Some(self.packet(Kind::Ack, 0, Vec::new()))
+    }
+
+    /// The packets to send at `now`: new ones, those that were not
+    /// acknowledged in time and heartbeats.
+    fn due(&mut self, now: Instant, stats: &Stats) -> Vec<Packet> {
+        if self.error.is_some() {
+            return Vec::new();
+        }
+        if now.saturating_duration_since(self.last_heard) >= IDLE_TIMEOUT {
+            debug!("UDP connection {:08x} timed out", self.id);
+            self.fail(io::ErrorKind::TimedOut);
+            return Vec::new();
+        }
+        let mut due = Vec::new();
+        let rto = self.rto;
+        let mut failed = false;
+        for sent in self.unacked.iter_mut().take(WINDOW) {
+            if sent.sacked {
+                continue;
+            }
+            if let Some(last) = sent.sent {
+                let backoff = rto
+                    .checked_mul(1 << (sent.attempts - 1).min(16))
+                    .map_or(MAX_RTO, |backoff| backoff.min(MAX_RTO));
+                if now.saturating_duration_since(last) < backoff {
+                    continue;
+                }
+                if sent.attempts >= MAX_ATTEMPTS {
+                    failed = true;
+                    break;
+                }
+                stats.retransmitted.fetch_add(1, Ordering::Relaxed);
+            } else {
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            sent.sent = Some(now);
+            sent.attempts += 1;
+            due.push((sent.kind, sent.seq, sent.payload.clone()));
+        }
+        if failed {
+            debug!("UDP connection {:08x} stopped acknowledging", self.id);
+            self.fail(io::ErrorKind::TimedOut);
+            return Vec::new();
+        }
+        let mut due = due
+            .into_iter()
+            .map(|(kind, seq, payload)| self.packet(kind, seq, payload))
+            .collect::<Vec<_>>();
+        if due.is_empty() && now.saturating_duration_since(self.last_sent) >= HEARTBEAT {
+            due.push(self.packet(Kind::Ack, 0, Vec::new()));
+        }
+        if !due.is_empty() {
+            self.last_sent = now;
+        }
+        due
+    }
+}
+
+fn error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, match kind {
+        io::ErrorKind::ConnectionReset => "connection reset by remote",
+        io::ErrorKind::TimedOut => "connection timed out",
+        _ => "connection closed",
+    })
+}
+
+/// The sending half of a socket. Sends go straight to the non-blocking socket,
+/// and packets that do not fit its buffer are lost and resent later.
+type Sender = Arc<std::net::UdpSocket>;
+
+fn send(socket: &Sender, remote: SocketAddr, packets: Vec<Packet>) {
+    for packet in packets {
+        if let Err(err) = socket.send_to(&packet.encode(), remote) {
+            trace!("Could not send UDP packet to {}: {}", remote, err);
+        }
+    }
+}
+
+/// What a stream asks of its connection.
+#[derive(Debug)]
+enum Command {
+    Write(Vec<u8>),
+    Close,
+}
+
+/// What a connection hands to its stream.
+#[derive(Debug)]
+enum Delivery {
+    Data(Vec<u8>),
+    Eof,
+    Failed(io::ErrorKind),
+}
+
+/// A connection, owned by the task serving its socket, and the channels to
+/// its [`Stream`].
+#[derive(Debug)]
+struct Link {
+    connection:  Connection,
+    commands:    mpsc::Receiver<Command>,
+    deliveries:  mpsc::UnboundedSender<Delivery>,
+    /// Told when the first packet is acknowledged, when dialing.
+    established: Option<oneshot::Sender<io::Result<()>>>,
+    eof:         bool,
+    failed:      bool,
+}
+
+impl Link {
+    fn new(connection: Connection) -> (Self, Stream) {
+        let (commands, receiver) = mpsc::channel(QUEUED_WRITES);
+        let (sender, deliveries) = mpsc::unbounded();
+        let link = Self {
+            connection,
+            commands: receiver,
+            deliveries: sender,
+            established: None,
+            eof: false,
+            failed: false,
+        };
+        let stream = Stream {
+            commands,
+            deliveries,
+            received: Vec::new(),
+            eof: false,
+            closed: false,
+            error: None,
+        };
+        (link, stream)
+    }
+
+    /// Whether to take the next command of the stream.
+    fn takes_commands(&self) -> bool {
+        !self.connection.dropped && self.connection.has_room()
+    }
+
+    /// Carry out `command` of the stream, `None` once the stream is dropped.
+    fn on_command(&mut self, command: Option<Command>) {
+        match command {
+            Some(Command::Write(data)) => {
+                if let Err(err) = self.connection.queue(Kind::Data, data) {
+                    debug!("UDP connection {:08x} failed: {}", self.connection.id, err);
+                    self.connection.fail(err.kind());
+                }
+            }
+            Some(Command::Close) => self.connection.close(),
+            None => {
+                self.connection.dropped = true;
+                self.connection.close();
+            }
+        }
+    }
+
+    /// Hand what arrived to the stream, and tell a dialer it connected.
+    fn deliver(&mut self) {
+        let connection = &mut self.connection;
+        if !connection.received.is_empty() {
+            let data = connection.received.drain(..).collect();
+            // The stream may be dropped
+            let _ = self.deliveries.unbounded_send(Delivery::Data(data));
+        }
+        if connection.eof && !self.eof {
+            self.eof = true;
+            let _ = self.deliveries.unbounded_send(Delivery::Eof);
+        }
+        if let Some(kind) = connection.error {
+            if !self.failed {
+                self.failed = true;
+                let _ = self.deliveries.unbounded_send(Delivery::Failed(kind));
+            }
+            if let Some(established) = self.established.take() {
+                let _ = established.send(Err(error(kind)));
+            }
+        } else if connection.unacked.front().map_or(true, |sent| sent.seq > 0) {
+            if let Some(established) = self.established.take() {
+                let _ = established.send(Ok(()));
+            }
+        }
+    }
+}
+
+/// A connection over the transport. Its state is kept by the task serving
+/// the socket, which it talks to over channels.
+#[derive(Debug)]
+pub struct Stream {
+    commands:   mpsc::Sender<Command>,
+    deliveries: mpsc::UnboundedReceiver<Delivery>,
+    /// Data received and not yet read.
+    received:   Vec<u8>,
+    /// The remote finished sending.
+    eof:        bool,
+    /// We finished sending.
+    closed:     bool,
+    error:      Option<io::ErrorKind>,
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.received.is_empty() {
+            if this.eof {
+                return Poll::Ready(Ok(0));
+            }
+            if let Some(kind) = this.error {
+                return Poll::Ready(Err(error(kind)));
+            }
+            match futures::ready!(this.deliveries.poll_next_unpin(cx)) {
+                Some(Delivery::Data(data)) => this.received = data,
+                Some(Delivery::Eof) => this.eof = true,
+                Some(Delivery::Failed(kind)) => this.error = Some(kind),
+                None => this.error = Some(io::ErrorKind::ConnectionAborted),
+            }
+        }
+        let len = buf.len().min(this.received.len());
+        buf[..len].copy_from_slice(&this.received[..len]);
+        this.received.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(kind) = this.error {
+            return Poll::Ready(Err(error(kind)));
+        }
+        if this.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // The task drops the commands of a connection that failed
+        let aborted = |_| error(io::ErrorKind::ConnectionAborted);
+        futures::ready!(this.commands.poll_ready(cx)).map_err(aborted)?;
+        let len = buf.len().min(MAX_PAYLOAD);
+        let command = Command::Write(buf[..len].to_vec());
+        this.commands.start_send(command).map_err(aborted)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed {
+            // Nothing to close once the task dropped the connection
+            if futures::ready!(this.commands.poll_ready(cx)).is_ok() {
+                let _ = this.commands.start_send(Command::Close);
+            }
+            this.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+type Key = (SocketAddr, u32);
+type Accepted = (Stream, SocketAddr);
+
+/// The commands ready on links that take them.
+fn commands(
+    links: &mut HashMap<Key, Link>,
+) -> impl Future<Output = Vec<(Key, Option<Command>)>> + '_ {
+    future::poll_fn(move |cx| {
+        let ready = links
+            .iter_mut()
+            .filter(|(_, link)| link.takes_commands())
+            .filter_map(|(key, link)| {
+                match link.commands.poll_next_unpin(cx) {
+                    Poll::Ready(command) => Some((*key, command)),
+                    Poll::Pending => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(ready)
+        }
+    })
+}
+
+/// Serve the connections on `receiver` and `socket`, accepting new ones if
+/// `accept` is given, until there are no connections left and nothing to
+/// accept. The connections live on this task, and their streams reach them
+/// over channels.
+async fn drive(
+    receiver: UdpSocket,
+    socket: Sender,
+    mut links: HashMap<Key, Link>,
+    mut accept: Option<mpsc::Sender<io::Result<Accepted>>>,
+    stats: Arc<Stats>,
+) {
+    let mut buffer = vec![0; HEADER_LEN + MAX_PAYLOAD];
+    let mut ticker = interval(TICK);
+    loop {
+        tokio::select! {
+            received = receiver.recv_from(&mut buffer) => {
+                let (len, remote) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        debug!("Could not receive UDP packet: {}", err);
+                        continue;
+                    }
+                };
+                let packet = match Packet::decode(&buffer[..len]) {
+                    Some(packet) => packet,
+                    None => {
+                        trace!("Ignoring invalid UDP packet from {}", remote);
+                        continue;
+                    }
+                };
+                let key = (remote, packet.id);
+                let now = Instant::now();
+                if let Some(link) = links.get_mut(&key) {
+                    let ack = link.connection.on_packet(packet, now, &stats);
+                    send(&socket, remote, ack.into_iter().collect());
+                    link.deliver();
+                    continue;
+                }
+                let opens = packet.kind == Kind::Data && packet.seq == 0;
+                let sender = match &mut accept {
+                    Some(sender) if opens => sender,
+                    _ => {
+                        if packet.kind != Kind::Reset {
+                            let reset = Packet {
+                                kind:    Kind::Reset,
+                                id:      packet.id,
+                                seq:     0,
+                                ack:     0,
+                                sack:    0,
+                                payload: Vec::new(),
+                            };
+                            send(&socket, remote, vec![reset]);
+                        }
+                        continue;
+                    }
+                };
+                let mut connection = Connection::new(packet.id, now);
+                let ack = connection.on_packet(packet, now, &stats);
+                let (link, stream) = Link::new(connection);
+                if sender.try_send(Ok((stream, remote))).is_ok() {
+                    trace!("Accepted UDP connection from {}", remote);
+                    links.insert(key, link);
+                    send(&socket, remote, ack.into_iter().collect());
+                }
+            }
+            ready = commands(&mut links) => {
+                let now = Instant::now();
+                for (key, command) in ready {
+                    let link = links.get_mut(&key).expect("Commands come from links");
+                    link.on_command(command);
+                    // Send new data right away instead of at the next tick
+                    send(&socket, key.0, link.connection.due(now, &stats));
+                    link.deliver();
+                }
+            }
+            _ = ticker.tick() => {
+                let now = Instant::now();
+                links.retain(|(remote, _), link| {
+                    send(&socket, *remote, link.connection.due(now, &stats));
+                    link.deliver();
+                    !link.connection.finished()
+                });
+                if accept.as_ref().map_or(false, mpsc::Sender::is_closed) {
+                    accept = None;
+                }
+                if accept.is_none() && links.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The socket address in `address`, if it is `/ip4/<ip>/udp/<port>` or
+/// `/ip6/<ip>/udp/<port>`.
+fn socket_address(address: &Multiaddr) -> Option<SocketAddr> {
+    let mut protocols = address.iter();
+    let ip = match protocols.next()? {
+        Protocol::Ip4(ip) => IpAddr::V4(ip),
+        Protocol::Ip6(ip) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    match (protocols.next()?, protocols.next()) {
+        (Protocol::Udp(port), None) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
+fn to_multiaddr(address: SocketAddr) -> Multiaddr {
+    Multiaddr::from(address.ip()).with(Protocol::Udp(address.port()))
+}
+
+fn bind(address: SocketAddr) -> io::Result<(UdpSocket, Sender)> {
+    let socket = std::net::UdpSocket::bind(address)?;
+    socket.set_nonblocking(true)?;
+    let sender = Arc::new(socket.try_clone()?);
+    Ok((UdpSocket::from_std(socket)?, sender))
+}
+
+/// The addresses to announce for a socket bound to `address`.
+fn listen_addresses(address: SocketAddr) -> Vec<Multiaddr> {
+    if !address.ip().is_unspecified() {
+        return vec![to_multiaddr(address)];
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            interfaces
+                .into_iter()
+                .map(|interface| interface.ip())
+                .filter(|ip| ip.is_ipv4() == address.is_ipv4())
+                .map(|ip| to_multiaddr(SocketAddr::new(ip, address.port())))
+                .collect()
+        }
+        Err(err) => {
+            warn!("Could not list interface addresses: {}", err);
+            vec![to_multiaddr(address)]
+        }
+    }
+}
+
+type Upgrade = Ready<io::Result<Stream>>;
+type Listener = BoxStream<'static, io::Result<ListenerEvent<Upgrade, io::Error>>>;
+
+/// The UDP transport, sharing its [`Stats`] between clones.
+#[derive(Clone, Debug, Default)]
+pub struct Udp {
+    stats: Arc<Stats>,
+}
+
+impl Udp {
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Transport for Udp {
+    type Dial = BoxFuture<'static, io::Result<Stream>>;
+    type Error = io::Error;
+    type Listener = Listener;
+    type ListenerUpgrade = Upgrade;
+    type Output = Stream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let local = match socket_address(&address) {
+            Some(local) => local,
+            None => return Err(TransportError::MultiaddrNotSupported(address)),
+        };
+        let (receiver, socket) = bind(local).map_err(TransportError::Other)?;
+        let local = socket.local_addr().map_err(TransportError::Other)?;
+        info!("Listening for UDP connections on {}", local);
+        let (sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let driver = drive(receiver, socket, HashMap::new(), Some(sender), self.stats);
+        tokio::spawn(driver);
+        let local_addr = to_multiaddr(local);
+        let incoming = accepted.map(move |accepted| {
+            Ok(match accepted {
+                Ok((stream, remote)) => {
+                    ListenerEvent::Upgrade {
+                        upgrade:     future::ok(stream),
+                        local_addr:  local_addr.clone(),
+                        remote_addr: to_multiaddr(remote),
+                    }
+                }
+                Err(err) => ListenerEvent::Error(err),
+            })
+        });
+        let announced = listen_addresses(local)
+            .into_iter()
+            .map(|address| Ok(ListenerEvent::NewAddress(address)));
+        Ok(stream::iter(announced).chain(incoming).boxed())
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let remote = match socket_address(&address) {
+            Some(remote) => remote,
+            None => return Err(TransportError::MultiaddrNotSupported(address)),
+        };
+        let stats = self.stats;
+        Ok(async move {
+            let unspecified = match remote {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let (receiver, socket) = bind(SocketAddr::new(unspecified, 0))?;
+            let id = rand::random();
+            let now = Instant::now();
+            let mut connection = Connection::new(id, now);
+            // An empty first packet opens the connection
+            connection.queue(Kind::Data, Vec::new())?;
+            send(&socket, remote, connection.due(now, &stats));
+            let (mut link, stream) = Link::new(connection);
+            let (established, connected) = oneshot::channel();
+            link.established = Some(established);
+            let mut links = HashMap::new();
+            links.insert((remote, id), link);
+            tokio::spawn(drive(receiver, socket, links, None, stats));
+            connected
+                .await
+                .map_err(|_| error(io::ErrorKind::ConnectionAborted))??;
+            debug!("Connected over UDP to {}", remote);
+            Ok(stream)
+        }
+        .boxed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_delivers_over_lossy_link() {
+        let packet = Packet {
+            kind:    Kind::Fin,
+            id:      7,
+            seq:     3,
+            ack:     2,
+            sack:    5,
+            payload: b"data".to_vec(),
+        };
+        assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+        assert_eq!(Packet::decode(&[9; HEADER_LEN]), None);
+        let address: Multiaddr = "/ip4/10.0.0.1/udp/4002".parse().unwrap();
+        assert_eq!(socket_address(&address), Some("10.0.0.1:4002".parse().unwrap()));
+        assert_eq!(to_multiaddr("10.0.0.1:4002".parse().unwrap()), address);
+        assert_eq!(socket_address(&"/ip4/10.0.0.1/tcp/4002".parse().unwrap()), None);
+
+        // Every third packet on the way there is lost
+        let stats = Stats::default();
+        let mut now = Instant::now();
+        let mut sender = Connection::new(1, now);
+        let mut receiver = Connection::new(1, now);
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            sender.queue(Kind::Data, chunk.to_vec()).unwrap();
+        }
+        sender.close();
+        let mut sent = 0;
+        while !receiver.eof {
+            assert!(now.saturating_duration_since(sender.last_heard) < IDLE_TIMEOUT);
+            for packet in sender.due(now, &stats) {
+                sent += 1;
+                if sent % 3 == 0 {
+                    continue;
+                }
+                if let Some(ack) = receiver.on_packet(packet, now, &stats) {
+                    sender.on_packet(ack, now, &stats);
+                }
+            }
+            now += TICK;
+        }
+        assert_eq!(receiver.received.iter().copied().collect::<Vec<_>>(), data);
+        assert!(sender.unacked.is_empty());
+        assert!(stats.retransmitted() > 0);
+        assert_eq!(stats.sent(), (data.len() / MAX_PAYLOAD + 2) as u64);
+        assert!(sender.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_streams_over_sockets() {
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+        let udp = Udp::default();
+        let mut listener = udp
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0".parse().unwrap())
+            .unwrap();
+        let address = match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(address))) => address,
+            other => panic!("Expected an address, got {:?}", other.map(|_| ())),
+        };
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let sent = data.clone();
+        let dialer = tokio::spawn(async move {
+            let mut stream = udp.dial(address).unwrap().await.unwrap();
+            stream.write_all(&sent).await.unwrap();
+            stream.close().await.unwrap();
+            assert!(stream.write_all(b"more").await.is_err());
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        });
+        let mut stream = match listener.next().await {
+            Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) => upgrade.await.unwrap(),
+            other => panic!("Expected a connection, got {:?}", other.map(|_| ())),
+        };
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        stream.write_all(&received).await.unwrap();
+        stream.close().await.unwrap();
+        assert_eq!(dialer.await.unwrap(), data);
+    }
+}
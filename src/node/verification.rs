@@ -0,0 +1,106 @@
+//! Checking who sent a message.
+//!
+//! Gossipsub signs its messages, but floodsub, direct messages and the
+//! `source` claimed in them are not bound to the sender's key. So the node
+//! signs the envelope of every message it sends, over the topic, its
+//! timestamp and payload in the [`signing`] layout, and receivers check the
+//! signature against the source. Messages relayed by a bridge are signed
+//! by the last relay, whose provenance hop names where it got them.
+//!
+//! `--verification "unsigned=flag invalid=drop"` picks the [`Policy`]:
+//! messages without a signature, like those of the Go 0x Mesh nodes and of
+//! older versions, and messages whose signature does not match their
+//! source, are either dropped or delivered with `signed: false` on
+//! [`Event::Message`].
+//!
+//! [`signing`]: super::signing
+//! [`Event::Message`]: crate::node::Event::Message
+
+use super::options;
+use crate::prelude::*;
+use anyhow::bail;
+use std::str::FromStr;
+
+/// What the signature of a received message showed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verification {
+    Valid,
+    Unsigned,
+    /// Signed by someone else, or not matching the message.
+    Invalid,
+}
+
+/// What to do with a message that is not validly signed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Deliver it with `signed: false`.
+    Flag,
+    Drop,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Policy {
+    pub unsigned: Action,
+    pub invalid:  Action,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            unsigned: Action::Flag,
+            invalid:  Action::Drop,
+        }
+    }
+}
+
+impl Policy {
+    /// Whether to deliver a message that verified as `verification`.
+    pub fn delivers(&self, verification: Verification) -> bool {
+        match verification {
+            Verification::Valid => true,
+            Verification::Unsigned => self.unsigned == Action::Flag,
+            Verification::Invalid => self.invalid == Action::Flag,
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = Self::default();
+        for pair in options::pairs(s) {
+            let (key, value) = pair?;
+            let action = match value {
+                "flag" => Action::Flag,
+                "drop" => Action::Drop,
+                _ => bail!("Unknown action {} for {}, expected flag or drop", value, key),
+            };
+            match key {
+                "unsigned" => policy.unsigned = action,
+                "invalid" => policy.invalid = action,
+                _ => bail!("Unknown verification option {}", key),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parses_policy() {
+        assert_eq!("".parse::<Policy>().unwrap(), Policy::default());
+        let strict: Policy = "unsigned=drop".parse().unwrap();
+        assert_eq!(strict.invalid, Action::Drop);
+        assert!(strict.delivers(Verification::Valid));
+        assert!(!strict.delivers(Verification::Unsigned));
+        assert!(Policy::default().delivers(Verification::Unsigned));
+        assert!(!Policy::default().delivers(Verification::Invalid));
+        assert!("invalid=accept".parse::<Policy>().is_err());
+        assert!("forged=drop".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Connections kept open to peers the application expects to talk to.
+//!
+//! A first message to a peer we are not connected to waits for the dial,
+//! the security and multiplexing handshakes and the exchange of pubsub
+//! subscriptions. [`NodeHandle::warm_peer`] marks the peer at an address
+//! ending in `/p2p/<peer id>` hot: the node dials it right away and again
+//! whenever the connection is lost, waiting twice as long after each dial
+//! that did not connect, up to [`MAX_BACKOFF`]. Once connected the peers
+//! know each other's subscriptions, so publishes on topics it subscribed to
+//! go to it at once, and gossipsub grafts it into the topic mesh at the
+//! next heartbeat. Hot peers are not disconnected to stay under
+//! `--max-peers`. Unlike [critical peers], one connection is kept.
+//! [`NodeHandle::cool_peer`] lets the connection go again.
+//!
+//! Pinned peers, from `--pin` or [`Node::pin`], are kept connected the same
+//! way, for infrastructure nodes that should always be. They do not count
+//! towards `--max-peers` at all, stay connected while the node is dormant
+//! and are not evicted for their score.
+//!
+//! [`NodeHandle::warm_peer`]: crate::node::NodeHandle::warm_peer
+//! [`NodeHandle::cool_peer`]: crate::node::NodeHandle::cool_peer
+//! [critical peers]: crate::node::Node::add_critical_peer
+//! [`Node::pin`]: crate::node::Node::pin
+
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Wait before the second dial of a peer that did not connect.
+pub const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between dials.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A hot peer, as [`NodeHandle::hot_peers`] returns it.
+///
+/// [`NodeHandle::hot_peers`]: crate::node::NodeHandle::hot_peers
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HotPeer {
+    pub peer_id:   PeerId,
+    pub address:   Multiaddr,
+    pub connected: bool,
+    /// Dials since the peer was last connected.
+    pub dials:     u32,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    address: Multiaddr,
+    dials:   u32,
+    /// No dial before then.
+    retry:   Option<Instant>,
+}
+
+/// The hot peers, by peer id.
+#[derive(Clone, Debug, Default)]
+pub struct Peers {
+    peers: HashMap<PeerId, Entry>,
+}
+
+impl Peers {
+    /// Keep a connection to `peer_id` at `address`, returning false if it
+    /// was hot already.
+    pub fn warm(&mut self, peer_id: PeerId, address: Multiaddr) -> bool {
+        let new = !self.peers.contains_key(&peer_id);
+        self.peers.insert(peer_id, Entry {
+            address,
+            dials: 0,
+            retry: None,
+        });
+        new
+    }
+
+    /// Stop keeping a connection to `peer_id`, returning false if it was
+    /// not hot.
+    pub fn cool(&mut self, peer_id: &PeerId) -> bool {
+        self.peers.remove(peer_id).is_some()
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    /// `peer_id` connected, dial it at once when the connection is lost.
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.dials = 0;
+            entry.retry = None;
+        }
+    }
+
+    /// The peers not `connected` to dial `now`, with their address.
+    pub fn due(
+        &mut self,
+        now: Instant,
+        connected: impl Fn(&PeerId) -> bool,
+    ) -> Vec<(PeerId, Multiaddr)> {
+        let mut due = Vec::new();
+        for (peer_id, entry) in &mut self.peers {
+            if connected(peer_id) || entry.retry.map_or(false, |retry| now < retry) {
+                continue;
+            }
+            let backoff = FIRST_BACKOFF
+                .checked_mul(1 << entry.dials.min(16))
+                .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+            entry.dials += 1;
+            entry.retry = Some(now + backoff);
+            due.push((peer_id.clone(), entry.address.clone()));
+        }
+        due
+    }
+
+    pub fn list(&self, connected: impl Fn(&PeerId) -> bool) -> Vec<HotPeer> {
+        let mut peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, entry)| {
+                HotPeer {
+                    peer_id:   peer_id.clone(),
+                    address:   entry.address.clone(),
+                    connected: connected(peer_id),
+                    dials:     entry.dials,
+                }
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        peers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_redials_with_backoff() {
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let mut peers = Peers::default();
+        assert!(peers.warm(peer_id.clone(), address.clone()));
+        assert!(!peers.warm(peer_id.clone(), address.clone()));
+
+        let now = Instant::now();
+        let due = |peers: &mut Peers, after: u64| {
+            peers
+                .due(now + Duration::from_secs(after), |_| false)
+                .len()
+        };
+        assert_eq!(due(&mut peers, 0), 1);
+        assert_eq!(due(&mut peers, 0), 0);
+        assert_eq!(due(&mut peers, 1), 1);
+        // The second failed dial doubles the wait
+        assert_eq!(due(&mut peers, 2), 0);
+        assert_eq!(due(&mut peers, 3), 1);
+        assert_eq!(peers.list(|_| false)[0].dials, 3);
+
+        // Connected peers are not dialed, and dialed at once when lost
+        peers.connected(&peer_id);
+        assert_eq!(peers.due(now, |_| true), vec![]);
+        assert_eq!(peers.due(now, |_| false), vec![(peer_id.clone(), address)]);
+
+        assert!(peers.cool(&peer_id));
+        assert!(!peers.contains(&peer_id));
+        assert_eq!(due(&mut peers, 100), 0);
+    }
+}
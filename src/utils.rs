@@ -1,6 +1,13 @@
 use crate::prelude::*;
 use std::io::{Error, ErrorKind, Result};
 
+/// 64 bit FNV-1a, used because it is stable across builds and platforms.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 /// Read a Serde Serialize from an futures::io::AsyncRead.
 ///
 /// This is difficult because there is no framing other than JSON succeeding to
@@ -0,0 +1,8 @@
+module github.com/0xProject/mesh-rs/test/interop/go-peer
+
+go 1.19
+
+require (
+	github.com/libp2p/go-libp2p v0.23.4
+	github.com/libp2p/go-libp2p-pubsub v0.8.2
+)
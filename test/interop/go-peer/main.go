@@ -0,0 +1,64 @@
+// Reference go-libp2p peer for the interop test of mesh-rs, see
+// src/node/interop.rs. It runs with the go-libp2p defaults and Noise, joins
+// a gossipsub topic and publishes on it every second. On stdout it prints
+// the addresses it listens on as "LISTEN <address>/p2p/<id>", and the
+// messages of other peers as "RECEIVED <source> <data>".
+package main
+
+import (
+	"context"
+	"flag"
+	"fmt"
+	"os"
+	"time"
+
+	"github.com/libp2p/go-libp2p"
+	pubsub "github.com/libp2p/go-libp2p-pubsub"
+	noise "github.com/libp2p/go-libp2p/p2p/security/noise"
+)
+
+func main() {
+	topicName := flag.String("topic", "mesh-rs/interop", "topic to join")
+	listen := flag.String("listen", "/ip4/127.0.0.1/tcp/0", "address to listen on")
+	message := flag.String("message", "hello from go-libp2p", "data to publish every second")
+	flag.Parse()
+
+	ctx := context.Background()
+	host, err := libp2p.New(
+		libp2p.ListenAddrStrings(*listen),
+		libp2p.Security(noise.ID, noise.New),
+	)
+	check(err)
+	gossip, err := pubsub.NewGossipSub(ctx, host)
+	check(err)
+	topic, err := gossip.Join(*topicName)
+	check(err)
+	subscription, err := topic.Subscribe()
+	check(err)
+	for _, address := range host.Addrs() {
+		fmt.Printf("LISTEN %s/p2p/%s\n", address, host.ID())
+	}
+
+	go func() {
+		for range time.Tick(time.Second) {
+			if err := topic.Publish(ctx, []byte(*message)); err != nil {
+				fmt.Fprintln(os.Stderr, "publish:", err)
+			}
+		}
+	}()
+	for {
+		received, err := subscription.Next(ctx)
+		check(err)
+		if received.ReceivedFrom == host.ID() {
+			continue
+		}
+		fmt.Printf("RECEIVED %s %s\n", received.GetFrom(), received.Data)
+	}
+}
+
+func check(err error) {
+	if err != nil {
+		fmt.Fprintln(os.Stderr, err)
+		os.Exit(1)
+	}
+}
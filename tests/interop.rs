@@ -0,0 +1,10 @@
+use mesh::node::interop;
+use std::path::Path;
+
+#[tokio::test]
+async fn test_go_libp2p_pubsub() {
+    let package = Path::new(env!("CARGO_MANIFEST_DIR"));
+    if let Err(err) = interop::go_pubsub(package).await {
+        panic!("{:#}", err);
+    }
+}